@@ -2,6 +2,16 @@ import { browser } from '$app/environment';
 
 const API_BASE_URL = 'http://127.0.0.1:8000/api';
 
+function pageParams(limit: number, cursor?: string): string
+{
+	const params = new URLSearchParams({ limit: String(limit) });
+	if (cursor)
+	{
+		params.set('cursor', cursor);
+	}
+	return params.toString();
+}
+
 export interface LoginRequest
 {
 	username: string;
@@ -19,9 +29,55 @@ export interface RegisterRequest
 export interface AuthResponseData
 {
 	token: string;
+	expires_in: number;
+	refresh_token: string;
+	role: string;
+	permissions: string[];
 	is_admin: boolean;
 }
 
+// What login returns instead of a session when the account uses two-factor
+// authentication; finish with completeTwoFactorLogin
+export interface TwoFactorChallengeData
+{
+	two_factor_required: true;
+	two_factor_token: string;
+	expires_in: number;
+	enrollment_required: boolean;
+}
+
+export interface TwoFactorSetupData
+{
+	secret: string;
+	otpauth_uri: string;
+}
+
+export interface PasskeyInfo
+{
+	id: string;
+	name: string;
+	created_at: string;
+	last_used_at: string | null;
+}
+
+export type ApiKeyScope = 'read-only' | 'stream' | 'playlist-write';
+
+export interface ApiKeyInfo
+{
+	id: string;
+	name: string;
+	prefix: string;
+	scopes: ApiKeyScope[];
+	created_at: string;
+	last_used_at: string | null;
+}
+
+// The key's value is only in the response that creates it
+export interface CreatedApiKey extends ApiKeyInfo
+{
+	key: string;
+}
+
 export interface UserInfo
 {
 	username: string;
@@ -33,6 +89,8 @@ export interface ApiResponse<T>
 	success: boolean;
 	message: string;
 	data?: T;
+	next_cursor?: string;
+	total?: number;
 	error?: string;
 	errors?: Record<string, string>;
 }
@@ -64,31 +122,38 @@ export interface Artist
 class ApiService
 {
 	private token: string | null = null;
+	private refreshToken: string | null = null;
+	private refreshing: Promise<boolean> | null = null;
 
 	constructor()
 	{
 		// Only access localStorage in the browser
 		this.token = browser ? localStorage.getItem('auth_token') : null;
+		this.refreshToken = browser ? localStorage.getItem('refresh_token') : null;
 	}
 
-	setToken(token: string | null)
+	setToken(token: string | null, refreshToken: string | null = null)
 	{
 		this.token = token;
+		this.refreshToken = token ? refreshToken : null;
 		// Only access localStorage in the browser
 		if (browser) {
 			if (token) localStorage.setItem('auth_token', token);
 			else localStorage.removeItem('auth_token');
+			if (this.refreshToken) localStorage.setItem('refresh_token', this.refreshToken);
+			else localStorage.removeItem('refresh_token');
 		}
 	}
 
-	private async request<T>(endpoint: string, options: RequestInit = {}): Promise<T>
+	private async request<T>(endpoint: string, options: RequestInit = {}, retry = true): Promise<T>
 	{
 		const url = `${API_BASE_URL}${endpoint}`;
 		const headers: Record<string, string> = {
 			'Content-Type': 'application/json',
 		};
 
-		if (this.token && !endpoint.includes('/login') && !endpoint.includes('/register'))
+		const isAuthEndpoint = ['/login', '/register', '/refresh', '/oidc/callback'].some((path) => endpoint.includes(path));
+		if (this.token && !isAuthEndpoint)
 			headers['Authorization'] = `Bearer ${this.token}`;
 
 		const response = await fetch(url, {
@@ -100,6 +165,9 @@ class ApiService
 		{
 			if (response.status === 401)
 			{
+				// Access tokens are short-lived; get a new one and try once more
+				if (retry && !isAuthEndpoint && await this.refresh())
+					return this.request<T>(endpoint, options, false);
 				this.setToken(null);
 				throw new Error('Unauthorized');
 			}
@@ -110,14 +178,53 @@ class ApiService
 		return response.json();
 	}
 
-	async login(credentials: LoginRequest): Promise<ApiResponse<AuthResponseData>>
+	// Refresh tokens work once, so concurrent requests share one refresh
+	private refresh(): Promise<boolean>
+	{
+		if (!this.refreshToken) return Promise.resolve(false);
+		this.refreshing ??= this.request<ApiResponse<AuthResponseData>>('/refresh', {
+			method: 'POST',
+			body: JSON.stringify({ refresh_token: this.refreshToken }),
+		}, false)
+			.then((response) => {
+				if (!response.success || !response.data) return false;
+				this.setToken(response.data.token, response.data.refresh_token);
+				return true;
+			})
+			.catch(() => false)
+			.finally(() => { this.refreshing = null; });
+		return this.refreshing;
+	}
+
+	async login(credentials: LoginRequest): Promise<ApiResponse<AuthResponseData | TwoFactorChallengeData>>
 	{
-		const response = await this.request<ApiResponse<AuthResponseData>>('/login', {
+		const response = await this.request<ApiResponse<AuthResponseData | TwoFactorChallengeData>>('/login', {
 			method: 'POST',
 			body: JSON.stringify(credentials),
 		});
 
-		if (response.success && response.data?.token) this.setToken(response.data.token);
+		if (response.success && response.data && 'token' in response.data) this.setToken(response.data.token, response.data.refresh_token);
+		return response;
+	}
+
+	// For accounts that have to set up two-factor authentication before logging in
+	async setupTwoFactorLogin(twoFactorToken: string): Promise<ApiResponse<TwoFactorSetupData>>
+	{
+		return this.request<ApiResponse<TwoFactorSetupData>>('/login/2fa/setup', {
+			method: 'POST',
+			body: JSON.stringify({ two_factor_token: twoFactorToken }),
+		});
+	}
+
+	// Code is from the authenticator app or a recovery code
+	async completeTwoFactorLogin(twoFactorToken: string, code: string): Promise<ApiResponse<AuthResponseData & { recovery_codes?: string[] }>>
+	{
+		const response = await this.request<ApiResponse<AuthResponseData & { recovery_codes?: string[] }>>('/login/2fa', {
+			method: 'POST',
+			body: JSON.stringify({ two_factor_token: twoFactorToken, code }),
+		});
+
+		if (response.success && response.data?.token) this.setToken(response.data.token, response.data.refresh_token);
 		return response;
 	}
 
@@ -128,17 +235,105 @@ class ApiService
 			body: JSON.stringify(userData),
 		});
 
-		if (response.success && response.data?.token) this.setToken(response.data.token);
+		if (response.success && response.data?.token) this.setToken(response.data.token, response.data.refresh_token);
+		return response;
+	}
+
+	// Single sign-on: send the browser to the returned URL, then pass the code
+	// and state it comes back with to completeOidcLogin
+	async getOidcLoginUrl(): Promise<ApiResponse<{ url: string }>>
+	{
+		return this.request<ApiResponse<{ url: string }>>('/oidc/authorize');
+	}
+
+	async completeOidcLogin(code: string, state: string): Promise<ApiResponse<AuthResponseData>>
+	{
+		const response = await this.request<ApiResponse<AuthResponseData>>('/oidc/callback', {
+			method: 'POST',
+			body: JSON.stringify({ code, state }),
+		});
+
+		if (response.success && response.data?.token) this.setToken(response.data.token, response.data.refresh_token);
 		return response;
 	}
 
+	// Passkeys: pass the options to PublicKeyCredential.parseRequestOptionsFromJSON
+	// (or parseCreationOptionsFromJSON), then send back credential.toJSON()
+	async startPasskeyLogin(username?: string): Promise<ApiResponse<Record<string, unknown>>>
+	{
+		return this.request<ApiResponse<Record<string, unknown>>>('/passkeys/login/start', {
+			method: 'POST',
+			body: JSON.stringify({ username }),
+		});
+	}
+
+	async finishPasskeyLogin(credential: unknown): Promise<ApiResponse<AuthResponseData | TwoFactorChallengeData>>
+	{
+		const response = await this.request<ApiResponse<AuthResponseData | TwoFactorChallengeData>>('/passkeys/login/finish', {
+			method: 'POST',
+			body: JSON.stringify({ credential }),
+		});
+
+		if (response.success && response.data && 'token' in response.data) this.setToken(response.data.token, response.data.refresh_token);
+		return response;
+	}
+
+	async startPasskeyRegistration(): Promise<ApiResponse<Record<string, unknown>>>
+	{
+		return this.request<ApiResponse<Record<string, unknown>>>('/user/passkeys/register/start', { method: 'POST' });
+	}
+
+	async finishPasskeyRegistration(name: string, credential: unknown): Promise<ApiResponse<PasskeyInfo>>
+	{
+		return this.request<ApiResponse<PasskeyInfo>>('/user/passkeys/register/finish', {
+			method: 'POST',
+			body: JSON.stringify({ name, credential }),
+		});
+	}
+
+	async getPasskeys(): Promise<ApiResponse<PasskeyInfo[]>>
+	{
+		return this.request<ApiResponse<PasskeyInfo[]>>('/user/passkeys');
+	}
+
+	async deletePasskey(id: string): Promise<ApiResponse<null>>
+	{
+		return this.request<ApiResponse<null>>('/user/passkeys', {
+			method: 'DELETE',
+			body: JSON.stringify({ id }),
+		});
+	}
+
+	async getApiKeys(): Promise<ApiResponse<ApiKeyInfo[]>>
+	{
+		return this.request<ApiResponse<ApiKeyInfo[]>>('/user/api-keys');
+	}
+
+	async createApiKey(name: string, scopes: ApiKeyScope[]): Promise<ApiResponse<CreatedApiKey>>
+	{
+		return this.request<ApiResponse<CreatedApiKey>>('/user/api-keys', {
+			method: 'POST',
+			body: JSON.stringify({ name, scopes }),
+		});
+	}
+
+	async deleteApiKey(id: string): Promise<ApiResponse<null>>
+	{
+		return this.request<ApiResponse<null>>('/user/api-keys', {
+			method: 'DELETE',
+			body: JSON.stringify({ id }),
+		});
+	}
+
 	async getUserInfo(): Promise<ApiResponse<UserInfo>>
 	{
 		return this.request<ApiResponse<UserInfo>>('/user');
 	}
 
-	logout()
+	async logout()
 	{
+		// End the login on the server too, so its refresh token stops working
+		if (this.token) await this.request('/logout', { method: 'POST' }, false).catch(() => {});
 		this.setToken(null);
 	}
 
@@ -147,14 +342,14 @@ class ApiService
 		return this.token !== null;
 	}
 
-	async getPrivatePlaylists(indexStart: number, indexEnd: number): Promise<ApiResponse<Playlist[]>>
+	async getPrivatePlaylists(limit: number, cursor?: string): Promise<ApiResponse<Playlist[]>>
 	{
-		return this.request<ApiResponse<Playlist[]>>(`/playlists/private?index_start=${indexStart}&index_end=${indexEnd}`);
+		return this.request<ApiResponse<Playlist[]>>(`/playlists/private?${pageParams(limit, cursor)}`);
 	}
 
-	async getPublicPlaylists(indexStart: number, indexEnd: number): Promise<ApiResponse<Playlist[]>>
+	async getPublicPlaylists(limit: number, cursor?: string): Promise<ApiResponse<Playlist[]>>
 	{
-		return this.request<ApiResponse<Playlist[]>>(`/playlists/public?index_start=${indexStart}&index_end=${indexEnd}`);
+		return this.request<ApiResponse<Playlist[]>>(`/playlists/public?${pageParams(limit, cursor)}`);
 	}
 
 	async getPrivatePlaylistCount(): Promise<ApiResponse<PlaylistTotal>>
@@ -1,21 +1,57 @@
-use axum::{extract::{Json, Query, State}};
+use axum::{extract::{Json, Query, State}, http::StatusCode};
 use serde::{Deserialize, Serialize};
+use crate::api::audit::{self, Changes, ClientAddress};
+use crate::api::libraries::library_filter;
+use crate::api::pagination::PageQuery;
 use crate::api::response::{ApiResponse, ApiResult, ApiResultNoData, ApiError};
 use crate::api::users::UserInfo;
 use crate::api::auth::AppState;
+use crate::auth::{scope, Authorized, Permission};
+use crate::auth::permissions::is_built_in_role;
+use crate::auth::rate_limit::Lockouts;
+use crate::db::DbError;
+use crate::db::models::{AuditAction, Playlist, Role};
+use crate::db::paging::{Page, PageRequest, SortField, SortOrder};
 use crate::music::MusicScanner;
 
-#[derive(Debug, Deserialize)]
-pub struct PaginationQuery {
-    pub index_start: usize,
-    pub index_end: usize,
-}
-
 #[derive(Debug, Deserialize)]
 pub struct EditUserRequest {
     pub username: String,
     pub new_email: Option<String>,
-    pub is_admin: Option<bool>,
+    pub role: Option<String>,
+}
+
+/// An account or address to let log in again
+#[derive(Debug, Deserialize)]
+pub struct UnlockRequest {
+    pub account: Option<String>,
+    pub address: Option<std::net::IpAddr>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveRoleRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScanQuery {
+    /// ID of the library to scan, all of them when left out
+    pub library: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleNameQuery {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoleInfo {
+    #[serde(flatten)]
+    pub role: Role,
+    pub user_count: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,37 +91,40 @@ pub struct AdminPlaylistInfo {
     pub name: String,
     pub owner: String,
     pub is_public: bool,
+    pub is_smart: bool,
+    /// Stored entries, always 0 for smart playlists
     pub song_count: usize,
 }
 
 pub async fn get_all_users(
     State(state): State<AppState>,
-    Query(params): Query<PaginationQuery>
+    _: Authorized<scope::ManageUsers>,
+    Query(params): Query<PageQuery>
 ) -> ApiResult<Vec<UserInfo>> {
-    // Calculate offset and limit from index_start and index_end
-    let offset = params.index_start;
-    let limit = params.index_end.saturating_sub(params.index_start).max(1);
+    let request = params.to_request(&state.page_limits, &[SortField::Name, SortField::Added], SortField::Added)?;
     
-    let users = state.db.get_all_users(offset, limit)
+    let users = state.db.get_all_users(&request)
         .await
         .map_err(|e| {
             tracing::error!("Failed to get users: {}", e);
             ApiError::internal_server_error(format!("Failed to retrieve users: {}", e))
         })?;
     
-    let user_infos: Vec<UserInfo> = users.into_iter().map(|user| UserInfo {
+    let user_infos = users.map(|user| UserInfo {
         username: user.username,
         email: user.email,
-        is_admin: user.is_admin,
+        role: user.role,
         created_at: user.created_at.format(&time::format_description::well_known::Rfc3339)
             .unwrap_or_else(|_| "Invalid date".to_string()),
-    }).collect();
+    });
     
-    Ok(Json(ApiResponse::success("users", user_infos)))
+    Ok(Json(ApiResponse::page("users", user_infos)))
 }
 
 pub async fn edit_user(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageUsers>,
+    address: ClientAddress,
     Json(payload): Json<EditUserRequest>
 ) -> ApiResultNoData {
     // Get the user first to ensure they exist
@@ -96,6 +135,19 @@ pub async fn edit_user(
             ApiError::not_found(format!("User not found: {}", e))
         })?;
     
+    // Check the role before changing anything
+    if let Some(role) = &payload.role {
+        if user.id == claims.sub && *role != user.role {
+            return Err(ApiError::forbidden("You can't change your own role"));
+        }
+        state.db.get_role(role).await.map_err(|e| match e {
+            DbError::RoleNotFound => ApiError::bad_request(format!("No such role: {}", role)),
+            e => ApiError::internal_server_error(format!("Failed to get role: {}", e)),
+        })?;
+    }
+    
+    let mut changes = Changes::default();
+    
     // Update email if provided
     if let Some(new_email) = payload.new_email {
         state.db.update_user_email(&payload.username, &new_email)
@@ -104,16 +156,23 @@ pub async fn edit_user(
                 tracing::error!("Failed to update user email: {}", e);
                 ApiError::bad_request(format!("Failed to update email: {}", e))
             })?;
+        changes.set("email", user.email, new_email);
     }
     
-    // Update admin status if provided
-    if let Some(is_admin) = payload.is_admin {
-        state.db.update_user_admin_status(&user.id, is_admin)
+    // Update role if provided; it applies from the user's next login or refresh
+    if let Some(role) = payload.role {
+        state.db.update_user_role(&user.id, &role)
             .await
             .map_err(|e| {
-                tracing::error!("Failed to update user admin status: {}", e);
-                ApiError::internal_server_error(format!("Failed to update admin status: {}", e))
+                tracing::error!("Failed to update user role: {}", e);
+                ApiError::internal_server_error(format!("Failed to update role: {}", e))
             })?;
+        changes.set("role", user.role, role);
+    }
+    
+    if !changes.is_empty() {
+        let event = audit::event(&claims, &address, AuditAction::UserEdit).with_target(&user.username);
+        audit::record(&state.db, changes.apply(event)).await;
     }
     
     Ok(Json(ApiResponse::no_data("User updated successfully")))
@@ -121,8 +180,13 @@ pub async fn edit_user(
 
 pub async fn delete_user(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageUsers>,
+    address: ClientAddress,
     Json(payload): Json<DeleteUserRequest>
 ) -> ApiResultNoData {
+    // Kept for the audit log, which outlives the account
+    let user = state.db.get_user_by_username(&payload.username).await.ok();
+    
     state.db.delete_user_by_username(&payload.username)
         .await
         .map_err(|e| {
@@ -130,10 +194,85 @@ pub async fn delete_user(
             ApiError::internal_server_error(format!("Failed to delete user: {}", e))
         })?;
     
+    let before = user.map(|user| serde_json::json!({ "id": user.id, "email": user.email, "role": user.role }));
+    let event = audit::event(&claims, &address, AuditAction::UserDelete)
+        .with_target(&payload.username)
+        .with_change(before, None);
+    audit::record(&state.db, event).await;
+    
     Ok(Json(ApiResponse::no_data("User deleted successfully")))
 }
 
-pub async fn add_song(Json(_payload): Json<serde_json::Value>) -> ApiResultNoData {
+/// Turn off a user's two-factor authentication, for when they've lost their
+/// authenticator and recovery codes
+pub async fn reset_two_factor(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageUsers>,
+    address: ClientAddress,
+    Json(payload): Json<DeleteUserRequest>
+) -> ApiResultNoData {
+    let user = state.db.get_user_by_username(&payload.username)
+        .await
+        .map_err(|e| match e {
+            DbError::UserNotFound => ApiError::not_found("User not found"),
+            e => ApiError::internal_server_error(format!("Failed to get user: {}", e)),
+        })?;
+    
+    state.db.delete_two_factor(&user.id)
+        .await
+        .map_err(|e| match e {
+            DbError::TwoFactorNotFound => ApiError::not_found("The user doesn't use two-factor authentication"),
+            e => ApiError::internal_server_error(format!("Failed to reset two-factor authentication: {}", e)),
+        })?;
+    tracing::info!("{} reset two-factor authentication for {}", claims.username, user.username);
+    audit::record(&state.db, audit::event(&claims, &address, AuditAction::TwoFactorReset).with_target(&user.username)).await;
+    
+    Ok(Json(ApiResponse::no_data("Two-factor authentication reset")))
+}
+
+/// Get the accounts and addresses whose logins are backing off or locked out
+pub async fn get_lockouts(
+    State(state): State<AppState>,
+    _: Authorized<scope::ManageUsers>,
+) -> ApiResult<Lockouts> {
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    Ok(Json(ApiResponse::success("Lockouts retrieved successfully", state.rate_limiter.lockouts(now))))
+}
+
+/// Forget the failed logins of an account or address
+pub async fn unlock(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageUsers>,
+    client: ClientAddress,
+    Json(payload): Json<UnlockRequest>
+) -> ApiResultNoData {
+    let (unlocked, target) = match (payload.account, payload.address) {
+        (Some(account), None) => {
+            let unlocked = state.rate_limiter.unlock_account(&account);
+            if unlocked {
+                tracing::info!("{} unlocked logins to {}", claims.username, account);
+            }
+            (unlocked, account)
+        }
+        (None, Some(address)) => {
+            let unlocked = state.rate_limiter.unlock_address(address);
+            if unlocked {
+                tracing::info!("{} unlocked logins from {}", claims.username, address);
+            }
+            (unlocked, address.to_string())
+        }
+        _ => return Err(ApiError::bad_request("Give either an account or an address")),
+    };
+    if !unlocked {
+        return Err(ApiError::not_found("No failed logins to forget"));
+    }
+    audit::record(&state.db, audit::event(&claims, &client, AuditAction::LoginUnlock).with_target(target)).await;
+    
+    Ok(Json(ApiResponse::no_data("Unlocked")))
+}
+
+pub async fn add_song(_: Authorized<scope::Upload>, Json(_payload): Json<serde_json::Value>) -> ApiResultNoData {
     // Songs are added automatically via the music scanner
     // This endpoint is not implemented as manual song addition is not supported
     Err(ApiError::bad_request("Manual song addition is not supported. Songs are automatically added via the music scanner. Use POST /api/admin/songs/scan to scan for new songs."))
@@ -141,6 +280,8 @@ pub async fn add_song(Json(_payload): Json<serde_json::Value>) -> ApiResultNoDat
 
 pub async fn edit_song(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::EditSongs>,
+    address: ClientAddress,
     Json(payload): Json<EditSongRequest>
 ) -> ApiResultNoData {
     // Get artist by name
@@ -151,8 +292,9 @@ pub async fn edit_song(
             ApiError::not_found(format!("Artist not found: {}", e))
         })?;
     
-    // Get songs by this artist
-    let songs = state.db.get_songs_by_artist(&artist.id)
+    // Get songs by this artist in the libraries the caller can see
+    let libraries = library_filter(&state, &claims).await?;
+    let songs = state.db.get_songs_by_artist(&artist.id, &libraries)
         .await
         .map_err(|e| {
             tracing::error!("Failed to get songs: {}", e);
@@ -185,11 +327,21 @@ pub async fn edit_song(
         tracing::warn!("Genre update requested but not supported in current schema");
     }
     
+    if let Some(new_album) = payload.new_album {
+        let mut changes = Changes::default();
+        changes.set("album", song.album.clone(), new_album);
+        let event = audit::event(&claims, &address, AuditAction::SongEdit)
+            .with_target(format!("{} - {}", song.artist_name, song.title));
+        audit::record(&state.db, changes.apply(event)).await;
+    }
+    
     Ok(Json(ApiResponse::no_data("Song metadata updated successfully")))
 }
 
 pub async fn delete_song(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageLibrary>,
+    address: ClientAddress,
     Json(payload): Json<DeleteSongRequest>
 ) -> ApiResultNoData {
     // Get artist by name
@@ -200,8 +352,9 @@ pub async fn delete_song(
             ApiError::not_found(format!("Artist not found: {}", e))
         })?;
     
-    // Get songs by this artist
-    let songs = state.db.get_songs_by_artist(&artist.id)
+    // Get songs by this artist in the libraries the caller can see
+    let libraries = library_filter(&state, &claims).await?;
+    let songs = state.db.get_songs_by_artist(&artist.id, &libraries)
         .await
         .map_err(|e| {
             tracing::error!("Failed to get songs: {}", e);
@@ -224,19 +377,24 @@ pub async fn delete_song(
             ApiError::internal_server_error(format!("Failed to delete song: {}", e))
         })?;
     
+    let before = serde_json::json!({ "id": song.id, "album": song.album, "file_path": song.file_path });
+    let event = audit::event(&claims, &address, AuditAction::SongDelete)
+        .with_target(format!("{} - {}", song.artist_name, song.title))
+        .with_change(Some(before), None);
+    audit::record(&state.db, event).await;
+    
     Ok(Json(ApiResponse::no_data("Song deleted successfully")))
 }
 
 pub async fn get_all_playlists(
     State(state): State<AppState>,
-    Query(params): Query<PaginationQuery>
+    _: Authorized<scope::CuratePlaylists>,
+    Query(params): Query<PageQuery>
 ) -> ApiResult<Vec<AdminPlaylistInfo>> {
-    // Calculate offset and limit from index_start and index_end
-    let offset = params.index_start;
-    let limit = params.index_end.saturating_sub(params.index_start).max(1);
+    let request = params.to_request(&state.page_limits, &[SortField::Name, SortField::Added], SortField::Added)?;
     
-    // Get all playlists from database
-    let playlists = state.db.get_all_playlists(offset, limit)
+    // Get the requested page of playlists from database
+    let page = state.db.get_all_playlists(&request)
         .await
         .map_err(|e| {
             tracing::error!("Failed to get playlists: {}", e);
@@ -245,7 +403,9 @@ pub async fn get_all_playlists(
     
     // Convert to AdminPlaylistInfo with song count
     let mut admin_playlists = Vec::new();
-    for playlist in playlists {
+    let next_cursor = page.next_cursor.clone();
+    let total = page.total;
+    for playlist in page.items {
         // Get song count for this playlist
         let songs = state.db.get_playlist_songs(&playlist.id)
             .await
@@ -258,33 +418,52 @@ pub async fn get_all_playlists(
             name: playlist.name,
             owner: playlist.owner_username,
             is_public: playlist.is_public,
+            is_smart: playlist.is_smart,
             song_count: songs.len(),
         });
     }
     
-    Ok(Json(ApiResponse::success("playlists", admin_playlists)))
+    Ok(Json(ApiResponse::page("playlists", Page {
+        items: admin_playlists,
+        next_cursor,
+        total,
+    })))
+}
+
+/// Walk every page of playlists looking for one with a matching name
+async fn find_playlist_by_name(state: &AppState, name: &str) -> Result<Playlist, ApiError> {
+    let mut request = PageRequest::new(SortField::Name, SortOrder::Asc, state.page_limits.max_size);
+    loop {
+        let page = state.db.get_all_playlists(&request)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get playlists: {}", e);
+                ApiError::internal_server_error(format!("Failed to retrieve playlists: {}", e))
+            })?;
+        
+        if let Some(playlist) = page.items.into_iter().find(|p| p.name == name) {
+            return Ok(playlist);
+        }
+        
+        match page.next_cursor {
+            Some(cursor) => request = request.next(cursor),
+            None => {
+                tracing::error!("Playlist '{}' not found", name);
+                return Err(ApiError::not_found(format!("Playlist '{}' not found", name)));
+            }
+        }
+    }
 }
 
 pub async fn edit_playlist(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::CuratePlaylists>,
+    address: ClientAddress,
     Json(payload): Json<EditPlaylistRequest>
 ) -> ApiResultNoData {
     // Find playlist by name (admin can see all playlists)
-    // We need to get all playlists and find the one with matching name
-    // This is inefficient but works for now - could add a db method later
-    let all_playlists = state.db.get_all_playlists(0, 10000)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to get playlists: {}", e);
-            ApiError::internal_server_error(format!("Failed to retrieve playlists: {}", e))
-        })?;
-    
-    let playlist = all_playlists.iter()
-        .find(|p| p.name == payload.name)
-        .ok_or_else(|| {
-            tracing::error!("Playlist '{}' not found", payload.name);
-            ApiError::not_found(format!("Playlist '{}' not found", payload.name))
-        })?;
+    let playlist = find_playlist_by_name(&state, &payload.name).await?;
+    let mut changes = Changes::default();
     
     // Update name if provided
     if let Some(new_name) = payload.new_name {
@@ -294,6 +473,7 @@ pub async fn edit_playlist(
                 tracing::error!("Failed to update playlist name: {}", e);
                 ApiError::internal_server_error(format!("Failed to update playlist name: {}", e))
             })?;
+        changes.set("name", playlist.name.clone(), new_name);
     }
     
     // Update visibility if provided
@@ -305,6 +485,12 @@ pub async fn edit_playlist(
                 tracing::error!("Failed to update playlist visibility: {}", e);
                 ApiError::internal_server_error(format!("Failed to update playlist visibility: {}", e))
             })?;
+        changes.set("is_public", playlist.is_public, is_public);
+    }
+    
+    if !changes.is_empty() {
+        let event = audit::event(&claims, &address, AuditAction::PlaylistEdit).with_target(&playlist.name);
+        audit::record(&state.db, changes.apply(event)).await;
     }
     
     Ok(Json(ApiResponse::no_data("Playlist updated successfully")))
@@ -312,22 +498,12 @@ pub async fn edit_playlist(
 
 pub async fn delete_playlist(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::CuratePlaylists>,
+    address: ClientAddress,
     Json(payload): Json<DeletePlaylistRequest>
 ) -> ApiResultNoData {
     // Find playlist by name (admin can delete any playlist)
-    let all_playlists = state.db.get_all_playlists(0, 10000)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to get playlists: {}", e);
-            ApiError::internal_server_error(format!("Failed to retrieve playlists: {}", e))
-        })?;
-    
-    let playlist = all_playlists.iter()
-        .find(|p| p.name == payload.name)
-        .ok_or_else(|| {
-            tracing::error!("Playlist '{}' not found", payload.name);
-            ApiError::not_found(format!("Playlist '{}' not found", payload.name))
-        })?;
+    let playlist = find_playlist_by_name(&state, &payload.name).await?;
     
     // Delete the playlist (admin version bypasses owner check)
     state.db.delete_playlist_by_id(&playlist.id)
@@ -337,6 +513,12 @@ pub async fn delete_playlist(
             ApiError::internal_server_error(format!("Failed to delete playlist: {}", e))
         })?;
     
+    let before = serde_json::json!({ "id": playlist.id, "owner": playlist.owner_username, "is_public": playlist.is_public });
+    let event = audit::event(&claims, &address, AuditAction::PlaylistDelete)
+        .with_target(&playlist.name)
+        .with_change(Some(before), None);
+    audit::record(&state.db, event).await;
+    
     Ok(Json(ApiResponse::no_data("Playlist deleted successfully")))
 }
 
@@ -350,32 +532,138 @@ pub struct ScanMusicResult {
     pub errors: usize,
 }
 
-/// POST /api/admin/songs/scan
-/// Scan the runtime/music directory and register all audio files
+/// POST /api/admin/songs/scan?library=X
+/// Scan one library's folder, or every library's, and register all audio files
 /// Also removes songs whose files no longer exist
 pub async fn scan_music_directory(
     State(state): State<AppState>,
+    _: Authorized<scope::Upload>,
+    Query(params): Query<ScanQuery>,
 ) -> ApiResult<ScanMusicResult> {
-    tracing::info!("Starting music directory scan");
+    let libraries = match &params.library {
+        Some(id) => vec![state.db.get_library(id).await.map_err(|e| match e {
+            DbError::LibraryNotFound => ApiError::not_found("Library not found"),
+            e => ApiError::internal_server_error(format!("Failed to retrieve library: {}", e)),
+        })?],
+        None => state.db.get_libraries().await
+            .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve libraries: {}", e)))?,
+    };
     
-    // Create the music scanner
-    let scanner = MusicScanner::new(state.db.clone(), "runtime/music");
+    let mut scan_result = ScanMusicResult {
+        total_files: 0,
+        registered: 0,
+        updated: 0,
+        skipped: 0,
+        removed: 0,
+        errors: 0,
+    };
     
-    // Perform the scan
-    let result = scanner.scan_and_register().await
-        .map_err(|e| {
-            tracing::error!("Failed to scan music directory: {}", e);
-            ApiError::internal_server_error(format!("Failed to scan music directory: {}", e))
-        })?;
+    for library in libraries {
+        tracing::info!("Starting scan of library '{}'", library.name);
+        let scanner = MusicScanner::new(state.db.clone(), library);
+        
+        let result = scanner.scan_and_register().await
+            .map_err(|e| {
+                tracing::error!("Failed to scan music directory: {}", e);
+                ApiError::internal_server_error(format!("Failed to scan music directory: {}", e))
+            })?;
+        
+        scan_result.total_files += result.total_files;
+        scan_result.registered += result.registered;
+        scan_result.updated += result.updated;
+        scan_result.skipped += result.skipped;
+        scan_result.removed += result.removed;
+        scan_result.errors += result.errors;
+    }
+    
+    Ok(Json(ApiResponse::success("Music scan completed", scan_result)))
+}
+
+/// GET /api/admin/roles
+/// List every role with its permissions and how many users have it
+pub async fn get_roles(
+    State(state): State<AppState>,
+    _: Authorized<scope::ManageUsers>,
+) -> ApiResult<Vec<RoleInfo>> {
+    let roles = state.db.get_roles().await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve roles: {}", e)))?;
     
-    let scan_result = ScanMusicResult {
-        total_files: result.total_files,
-        registered: result.registered,
-        updated: result.updated,
-        skipped: result.skipped,
-        removed: result.removed,
-        errors: result.errors,
+    let mut infos = Vec::with_capacity(roles.len());
+    for role in roles {
+        let user_count = state.db.count_users_with_role(&role.name).await
+            .map_err(|e| ApiError::internal_server_error(format!("Failed to count users: {}", e)))?;
+        infos.push(RoleInfo { role, user_count });
+    }
+    
+    Ok(Json(ApiResponse::success("roles", infos)))
+}
+
+/// POST /api/admin/roles
+/// Create a custom role, or change the permissions of one
+pub async fn save_role(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageUsers>,
+    address: ClientAddress,
+    Json(payload): Json<SaveRoleRequest>,
+) -> ApiResult<Role> {
+    let valid_name = (1..=32).contains(&payload.name.len())
+        && payload.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid_name {
+        return Err(ApiError::bad_request("Role names are 1 to 32 lowercase letters, digits, '-' or '_'"));
+    }
+    if is_built_in_role(&payload.name) {
+        return Err(ApiError::forbidden(format!("Built-in role can't be changed: {}", payload.name)));
+    }
+    
+    let mut permissions = payload.permissions;
+    permissions.sort();
+    permissions.dedup();
+    let role = Role {
+        name: payload.name,
+        description: payload.description,
+        permissions,
+        built_in: false,
+    };
+    let existing = match state.db.get_role(&role.name).await {
+        Ok(existing) => Some(existing),
+        Err(DbError::RoleNotFound) => None,
+        Err(e) => return Err(ApiError::internal_server_error(format!("Failed to get role: {}", e))),
     };
+    state.db.save_role(&role).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save role: {}", e)))?;
     
-    Ok(Json(ApiResponse::success("Music scan completed", scan_result)))
+    let to_value = |role: &Role| serde_json::json!({ "description": role.description, "permissions": role.permissions });
+    let event = audit::event(&claims, &address, AuditAction::RoleSave)
+        .with_target(&role.name)
+        .with_change(existing.as_ref().map(to_value), Some(to_value(&role)));
+    audit::record(&state.db, event).await;
+    
+    Ok(Json(ApiResponse::success("Role saved", role)))
+}
+
+/// DELETE /api/admin/roles?name=X
+/// Delete a custom role no user has
+pub async fn delete_role(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageUsers>,
+    address: ClientAddress,
+    Query(params): Query<RoleNameQuery>,
+) -> ApiResultNoData {
+    if is_built_in_role(&params.name) {
+        return Err(ApiError::forbidden(format!("Built-in role can't be deleted: {}", params.name)));
+    }
+    
+    let user_count = state.db.count_users_with_role(&params.name).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to count users: {}", e)))?;
+    if user_count > 0 {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Role is given to {} users", user_count)));
+    }
+    
+    state.db.delete_role(&params.name).await.map_err(|e| match e {
+        DbError::RoleNotFound => ApiError::not_found(format!("Role not found: {}", params.name)),
+        e => ApiError::internal_server_error(format!("Failed to delete role: {}", e)),
+    })?;
+    audit::record(&state.db, audit::event(&claims, &address, AuditAction::RoleDelete).with_target(&params.name)).await;
+    
+    Ok(Json(ApiResponse::no_data("Role deleted")))
 }
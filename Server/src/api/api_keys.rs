@@ -0,0 +1,154 @@
+use axum::{extract::{Json, State}, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::api::audit::{self, ClientAddress};
+use crate::api::auth::AppState;
+use crate::api::response::{ApiError, ApiResponse, ApiResult, ApiResultNoData};
+use crate::auth::Authorized;
+use crate::auth::api_keys::{display_prefix, generate_key, hash_key, ApiKeyScope};
+use crate::db::DbError;
+use crate::db::models::{ApiKey, AuditAction};
+
+/// Longest name a key can have
+const MAX_NAME_LENGTH: usize = 64;
+/// Most keys a user can have at once
+const MAX_KEYS: usize = 25;
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    /// Start of the key, to tell which one a script has
+    pub prefix: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(api_key: ApiKey) -> Self {
+        Self {
+            id: api_key.id,
+            name: api_key.name,
+            prefix: api_key.prefix,
+            scopes: api_key.scopes,
+            created_at: api_key.created_at,
+            last_used_at: api_key.last_used_at,
+        }
+    }
+}
+
+/// A key just made, the only time its value is shown
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteApiKeyRequest {
+    pub id: String,
+}
+
+/// GET /api/user/api-keys
+/// Get the caller's API keys, without their values
+pub async fn get_api_keys(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> ApiResult<Vec<ApiKeyInfo>> {
+    let api_keys = state.db.get_user_api_keys(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get API keys: {}", e)))?;
+
+    Ok(Json(ApiResponse::success(
+        "API keys retrieved successfully",
+        api_keys.into_iter().map(ApiKeyInfo::from).collect(),
+    )))
+}
+
+/// POST /api/user/api-keys
+/// Make an API key for scripts to send in the `X-Api-Key` header. Its value
+/// is only in this response.
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> ApiResult<CreatedApiKey> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::bad_request(format!("API key names must be 1 to {} characters", MAX_NAME_LENGTH)));
+    }
+
+    let mut scopes = payload.scopes;
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(ApiError::bad_request("API keys need at least one scope"));
+    }
+    if let Some(scope) = scopes.iter().find(|scope| !scope.permissions().iter().all(|&p| claims.can(p))) {
+        return Err(ApiError::forbidden(format!("Your role doesn't allow the {} scope", scope)));
+    }
+
+    let existing = state.db.get_user_api_keys(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get API keys: {}", e)))?;
+    if existing.len() >= MAX_KEYS {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("You can have at most {} API keys", MAX_KEYS)));
+    }
+
+    let key = generate_key();
+    let api_key = ApiKey {
+        id: Uuid::new_v4().to_string(),
+        user_id: claims.sub.clone(),
+        name: name.to_string(),
+        prefix: display_prefix(&key),
+        key_hash: hash_key(&key),
+        scopes,
+        created_at: OffsetDateTime::now_utc(),
+        last_used_at: None,
+    };
+    state.db.create_api_key(&api_key).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save API key: {}", e)))?;
+    let after = serde_json::json!({ "id": api_key.id, "name": api_key.name, "prefix": api_key.prefix, "scopes": api_key.scopes });
+    let event = audit::event(&claims, &address, AuditAction::ApiKeyCreate)
+        .with_target(&claims.username)
+        .with_change(None, Some(after));
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::success(
+        "API key created. Copy it now, as it won't be shown again.",
+        CreatedApiKey { info: api_key.into(), key },
+    )))
+}
+
+/// DELETE /api/user/api-keys
+/// Revoke one of the caller's API keys
+pub async fn delete_api_key(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+    Json(payload): Json<DeleteApiKeyRequest>,
+) -> ApiResultNoData {
+    state.db.delete_api_key(&claims.sub, &payload.id).await
+        .map_err(|e| match e {
+            DbError::ApiKeyNotFound => ApiError::not_found("API key not found"),
+            e => ApiError::internal_server_error(format!("Failed to delete API key: {}", e)),
+        })?;
+    let before = serde_json::json!({ "id": payload.id });
+    let event = audit::event(&claims, &address, AuditAction::ApiKeyRevoke)
+        .with_target(&claims.username)
+        .with_change(Some(before), None);
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::no_data("API key revoked")))
+}
@@ -7,13 +7,13 @@ use serde::{Deserialize, Serialize};
 use tokio::fs;
 
 use crate::api::auth::AppState;
+use crate::api::favorites::{rating_state, ratings_by_id};
+use crate::api::libraries::library_filter;
+use crate::api::pagination::PageQuery;
 use crate::api::response::{ApiError, ApiResponse, ApiResult};
-
-#[derive(Debug, Deserialize)]
-pub struct PaginationQuery {
-    pub index_start: Option<usize>,
-    pub index_end: Option<usize>,
-}
+use crate::auth::{scope, Authorized};
+use crate::db::models::{Artist, LibraryFilter, Song};
+use crate::db::paging::SortField;
 
 #[derive(Debug, Deserialize)]
 pub struct ArtistNameQuery {
@@ -24,6 +24,8 @@ pub struct ArtistNameQuery {
 pub struct ArtistBasic {
     pub id: String,
     pub name: String,
+    pub favorite: bool,
+    pub rating: Option<u8>,
 }
 
 #[derive(Debug, Serialize)]
@@ -31,42 +33,54 @@ pub struct SongBasic {
     pub id: String,
     pub title: String,
     pub artist_name: String,
+    pub favorite: bool,
+    pub rating: Option<u8>,
 }
 
-/// GET /api/artists?index_start=0&index_end=50
-/// Get paginated list of artists
+/// GET /api/artists?limit=50&sort=name&order=asc&cursor=X
+/// Get a page of artists, sortable by name or added
 pub async fn get_artists(
     State(state): State<AppState>,
-    Query(params): Query<PaginationQuery>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<PageQuery>,
 ) -> ApiResult<Vec<ArtistBasic>> {
-    // Calculate offset and limit from the pagination query
-    let offset = params.index_start.unwrap_or(0);
-    let total_songs = state.db.get_total_artists().await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to get total artists: {}", e)))?;
-    let limit = params.index_end.unwrap_or(total_songs).saturating_sub(offset);
+    let request = params.to_request(
+        &state.page_limits,
+        &[SortField::Name, SortField::Added],
+        SortField::Name,
+    )?;
     
-    // Get artists from database
-    let artists = state.db.get_artists(offset, limit).await
+    // Get artists with songs in the libraries the user can see
+    let libraries = library_filter(&state, &claims).await?;
+    let page = state.db.get_artists(&request, &libraries).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Database error: {}", e)))?;
     
     // Convert to response format
-    let artist_list: Vec<ArtistBasic> = artists.into_iter().map(|artist| ArtistBasic {
-        id: artist.id,
-        name: artist.name,
-    }).collect();
+    let artist_ids = page.items.iter().map(|artist| artist.id.clone()).collect();
+    let ratings = ratings_by_id(&state, &claims.sub, "artist", artist_ids).await?;
+    let page = page.map(|artist| {
+        let (favorite, rating) = rating_state(&ratings, &artist.id);
+        ArtistBasic {
+            favorite,
+            rating,
+            id: artist.id,
+            name: artist.name,
+        }
+    });
     
-    Ok(Json(ApiResponse::success("artists", artist_list)))
+    Ok(Json(ApiResponse::page("artists", page)))
 }
 
 /// GET /api/artists/cover?name=ArtistName
 /// Get artist cover image
 pub async fn get_artist_cover(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
     Query(params): Query<ArtistNameQuery>,
 ) -> Result<Response, ApiError> {
     // Get artist from database
-    let artist = state.db.get_artist_by_name(&params.name).await
-        .map_err(|_| ApiError::not_found("Artist not found"))?;
+    let libraries = library_filter(&state, &claims).await?;
+    let (artist, _) = find_artist(&state, &params.name, &libraries).await?;
     
     // Check if artist has a cover image
     let cover_path = artist.cover_image_path
@@ -98,22 +112,64 @@ pub async fn get_artist_cover(
 /// Get all songs by a specific artist
 pub async fn get_artist_songs(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
     Query(params): Query<ArtistNameQuery>,
 ) -> ApiResult<Vec<SongBasic>> {
-    // Get artist from database
-    let artist = state.db.get_artist_by_name(&params.name).await
-        .map_err(|_| ApiError::not_found("Artist not found"))?;
-    
-    // Get songs by this artist
-    let songs = state.db.get_songs_by_artist(&artist.id).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Database error: {}", e)))?;
+    // Get the artist and their songs
+    let libraries = library_filter(&state, &claims).await?;
+    let (_, songs) = find_artist(&state, &params.name, &libraries).await?;
     
     // Convert to response format
-    let song_list: Vec<SongBasic> = songs.into_iter().map(|song| SongBasic {
-        id: song.id,
-        title: song.title,
-        artist_name: song.artist_name,
+    let song_ids = songs.iter().map(|song| song.id.clone()).collect();
+    let ratings = ratings_by_id(&state, &claims.sub, "song", song_ids).await?;
+    let song_list: Vec<SongBasic> = songs.into_iter().map(|song| {
+        let (favorite, rating) = rating_state(&ratings, &song.id);
+        SongBasic {
+            favorite,
+            rating,
+            id: song.id,
+            title: song.title,
+            artist_name: song.artist_name,
+        }
     }).collect();
     
     Ok(Json(ApiResponse::success("artist songs", song_list)))
 }
+
+/// Look up an artist and their songs in `libraries`. Artists with no songs
+/// there are hidden from users who can't see every library.
+pub(crate) async fn find_artist(state: &AppState, name: &str, libraries: &LibraryFilter) -> Result<(Artist, Vec<Song>), ApiError> {
+    let artist = state.db.get_artist_by_name(name).await
+        .map_err(|_| ApiError::not_found("Artist not found"))?;
+
+    let songs = state.db.get_songs_by_artist(&artist.id, libraries).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Database error: {}", e)))?;
+    if songs.is_empty() && *libraries != LibraryFilter::All {
+        return Err(ApiError::not_found("Artist not found"));
+    }
+
+    Ok((artist, songs))
+}
+
+/// Songs on one of an artist's albums in `libraries`, matching the album name case-insensitively
+pub(crate) async fn album_songs(state: &AppState, artist_id: &str, album: &str, libraries: &LibraryFilter) -> Result<Vec<Song>, ApiError> {
+    let songs = state.db.get_songs_by_artist(artist_id, libraries).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch songs: {}", e)))?;
+
+    Ok(songs.into_iter()
+        .filter(|song| song.album.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(album)))
+        .collect())
+}
+
+/// Look up an album in `libraries` by artist and album name
+pub(crate) async fn find_album(state: &AppState, artist_name: &str, album: &str, libraries: &LibraryFilter) -> Result<(Artist, Vec<Song>), ApiError> {
+    let artist = state.db.get_artist_by_name(artist_name).await
+        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, format!("Artist not found: {}", e)))?;
+
+    let songs = album_songs(state, &artist.id, album, libraries).await?;
+    if songs.is_empty() {
+        return Err(ApiError::not_found(format!("Album not found: {}", album)));
+    }
+
+    Ok((artist, songs))
+}
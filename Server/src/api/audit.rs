@@ -0,0 +1,192 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts, Query, State},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::api::auth::AppState;
+use crate::api::pagination::PageQuery;
+use crate::api::response::{ApiError, ApiResponse, ApiResult};
+use crate::auth::{scope, Authorized, Claims};
+use crate::db::Database;
+use crate::db::models::{AuditAction, AuditEvent, AuditFilter, User};
+use crate::db::paging::{PageRequest, SortField, SortOrder};
+
+/// Bytes of an export written ahead of the client
+const EXPORT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Address a request came from, when the server knows it
+pub struct ClientAddress(pub Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddress {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientAddress(parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip())))
+    }
+}
+
+/// Append to the audit log. A failure is logged rather than failing the
+/// action being recorded, which has already happened.
+pub(crate) async fn record(db: &Arc<dyn Database>, event: AuditEvent) {
+    if let Err(e) = db.record_audit_event(&event).await {
+        tracing::warn!("Failed to record audit event {}: {}", event.action.as_str(), e);
+    }
+}
+
+/// An entry for an action the holder of `claims` took
+pub(crate) fn event(claims: &Claims, address: &ClientAddress, action: AuditAction) -> AuditEvent {
+    AuditEvent::new(Some(&claims.sub), &claims.username, action).with_ip(address.0)
+}
+
+/// An entry for a login to `user`, noting how they logged in
+pub(crate) fn login_event(user: &User, address: &ClientAddress, method: &str) -> AuditEvent {
+    AuditEvent::new(Some(&user.id), &user.username, AuditAction::Login)
+        .with_target(&user.username)
+        .with_change(None, Some(serde_json::json!({ "method": method })))
+        .with_ip(address.0)
+}
+
+/// The old and new values of the fields an edit changed
+#[derive(Default)]
+pub(crate) struct Changes {
+    before: Map<String, Value>,
+    after: Map<String, Value>,
+}
+
+impl Changes {
+    /// Note a field's change, if its value is different
+    pub fn set(&mut self, field: &str, before: impl Into<Value>, after: impl Into<Value>) {
+        let (before, after) = (before.into(), after.into());
+        if before != after {
+            self.before.insert(field.to_string(), before);
+            self.after.insert(field.to_string(), after);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.after.is_empty()
+    }
+
+    pub fn apply(self, event: AuditEvent) -> AuditEvent {
+        event.with_change(Some(Value::Object(self.before)), Some(Value::Object(self.after)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    /// RFC 3339 time of the earliest entry to include
+    pub since: Option<String>,
+    /// RFC 3339 time entries have to be before
+    pub until: Option<String>,
+}
+
+fn parse_time(name: &str, value: Option<&str>) -> Result<Option<OffsetDateTime>, ApiError> {
+    value.map(|value| OffsetDateTime::parse(value, &Rfc3339)
+        .map_err(|_| ApiError::bad_request(format!("{} must be an RFC 3339 time", name))))
+        .transpose()
+}
+
+impl AuditQuery {
+    fn to_filter(&self) -> Result<AuditFilter, ApiError> {
+        let action = match &self.action {
+            Some(action) => Some(AuditAction::from_string(action)
+                .ok_or_else(|| ApiError::bad_request(format!("Unknown audit action: {}", action)))?),
+            None => None,
+        };
+
+        Ok(AuditFilter {
+            actor: self.actor.clone(),
+            action,
+            target: self.target.clone(),
+            since: parse_time("since", self.since.as_deref())?,
+            until: parse_time("until", self.until.as_deref())?,
+        })
+    }
+}
+
+/// GET /api/admin/audit?actor=X&action=Y&target=Z&since=T&until=T
+/// List the audit log, newest first unless `order=asc`
+pub async fn get_audit_log(
+    State(state): State<AppState>,
+    _: Authorized<scope::ManageUsers>,
+    Query(query): Query<AuditQuery>,
+    Query(params): Query<PageQuery>,
+) -> ApiResult<Vec<AuditEvent>> {
+    let filter = query.to_filter()?;
+    let request = params.to_request(&state.page_limits, &[SortField::Added], SortField::Added)?;
+
+    let page = state.db.get_audit_events(&filter, &request).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve audit log: {}", e)))?;
+
+    Ok(Json(ApiResponse::page("audit events", page)))
+}
+
+/// GET /api/admin/audit/export?actor=X&action=Y&target=Z&since=T&until=T
+/// Download every matching audit log entry as JSON lines, oldest first. Pages
+/// are read as the client takes them, so the log is never held in memory.
+pub async fn export_audit_log(
+    State(state): State<AppState>,
+    _: Authorized<scope::ManageUsers>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, ApiError> {
+    let filter = query.to_filter()?;
+
+    // The first page is read before answering, so a failing query is still an error response
+    let mut request = PageRequest::new(SortField::Added, SortOrder::Asc, state.page_limits.max_size);
+    let mut page = state.db.get_audit_events(&filter, &request).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve audit log: {}", e)))?;
+
+    let (mut writer, reader) = tokio::io::duplex(EXPORT_BUFFER_SIZE);
+    let db = state.db.clone();
+    tokio::spawn(async move {
+        loop {
+            for event in &page.items {
+                let mut line = match serde_json::to_vec(event) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        tracing::warn!("Failed to write audit event {}: {}", event.id, e);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                // The client went away
+                if writer.write_all(&line).await.is_err() {
+                    return;
+                }
+            }
+
+            let Some(cursor) = page.next_cursor.take() else { break };
+            request = request.next(cursor);
+            page = match db.get_audit_events(&filter, &request).await {
+                Ok(page) => page,
+                Err(e) => {
+                    tracing::warn!("Audit log export ended early: {}", e);
+                    return;
+                }
+            };
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"audit.jsonl\""),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    ).into_response())
+}
@@ -1,15 +1,32 @@
 use axum::{
-    extract::{Json, State, Extension},
+    extract::{FromRef, Json, State},
     http::StatusCode,
+    response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use uuid::Uuid;
 use validator::Validate;
 
+use crate::api::audit::{self, ClientAddress};
+use crate::api::pagination::PageLimits;
 use crate::api::response::{ApiError, ApiResponse, ApiResultNoData};
-use crate::auth::{JwtService, PasswordService, Claims};
-use crate::db::Database;
+use crate::api::two_factor::{self, TwoFactorChallenge};
+use crate::auth::{Authorized, JwtService, PasswordService, Permission};
+use crate::auth::middleware::{AuthState, LoginSucceeded};
+use crate::auth::oidc::OidcClient;
+use crate::auth::permissions::ADMIN_ROLE;
+use crate::auth::rate_limit::RateLimiter;
+use crate::auth::stream_url::StreamUrlSigner;
+use crate::auth::webauthn::WebAuthn;
+use crate::connect::ConnectHub;
+use crate::rooms::RoomHub;
+use crate::downloads::DownloadHub;
+use crate::music::hls::HlsPackager;
+use crate::music::lyrics_provider::LyricsProvider;
+use crate::db::{Database, DbError};
+use crate::db::models::{AuditAction, AuditEvent, User};
 
 // ============================================================================
 // Request/Response Types
@@ -35,12 +52,35 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
+    /// Short-lived access token, sent as a bearer token
     pub token: String,
+    /// Seconds until `token` expires
+    pub expires_in: i64,
+    /// Single-use token for `/api/refresh`, which returns a new pair
+    pub refresh_token: String,
+    pub role: String,
+    /// What the token allows, as in its `scope` claim
+    pub permissions: Vec<Permission>,
+    /// Whether the role is `admin`, for clients from before roles
     pub is_admin: bool,
 }
 
+/// What a login gets: a session, or a partial token when the account uses
+/// two-factor authentication
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Session(AuthResponse),
+    TwoFactor(TwoFactorChallenge),
+}
+
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
     pub status: String,
@@ -77,7 +117,78 @@ pub async fn health_check() -> Result<Json<HealthResponse>, ApiError> {
 pub struct AppState {
     pub db: Arc<dyn Database>,
     pub jwt_service: Arc<JwtService>,
+    /// Signs stream URLs for players that can't send headers
+    pub stream_signer: Arc<StreamUrlSigner>,
     pub password_service: Arc<PasswordService>,
+    pub page_limits: PageLimits,
+    /// Base URL clients reach the server at, used in exported links
+    pub website_url: String,
+    /// Devices connected for remote control
+    pub connect: Arc<ConnectHub>,
+    /// Open listening rooms
+    pub rooms: Arc<RoomHub>,
+    /// Where to look up lyrics for songs without any, when configured
+    pub lyrics_provider: Option<Arc<dyn LyricsProvider>>,
+    /// Encodes songs for HLS and DASH
+    pub hls: Arc<HlsPackager>,
+    /// Offline download bundles being built or ready to fetch
+    pub downloads: Arc<DownloadHub>,
+    /// Identity provider for single sign-on, when configured
+    pub oidc: Option<Arc<OidcClient>>,
+    /// Whether accounts that can manage users must use two-factor authentication
+    pub require_admin_two_factor: bool,
+    /// Passkey registrations and logins in progress
+    pub webauthn: Arc<WebAuthn>,
+    /// Failed logins, for holding back password guessing
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
+impl FromRef<AppState> for AuthState {
+    fn from_ref(state: &AppState) -> Self {
+        AuthState {
+            jwt_service: state.jwt_service.clone(),
+            db: state.db.clone(),
+            stream_signer: state.stream_signer.clone(),
+            rate_limiter: state.rate_limiter.clone(),
+        }
+    }
+}
+
+/// Issue an access token for a user with the permissions their role has now,
+/// and the next refresh token in their login's `family`
+async fn issue_token(state: &AppState, user: &User, family: &str) -> Result<AuthResponse, ApiError> {
+    // Roles can't be deleted while in use, but a user left without one can do nothing
+    let permissions = match state.db.get_role(&user.role).await {
+        Ok(role) => role.permissions,
+        Err(DbError::RoleNotFound) => Vec::new(),
+        Err(e) => return Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+    };
+
+    let token = state.jwt_service.generate_access_token(&user.id, &user.username, &user.role, &permissions, family)
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to generate token: {}", e)))?;
+
+    let (refresh_token, expires_at) = state.jwt_service.generate_refresh_token();
+    state.db.create_refresh_token(&JwtService::hash_refresh_token(&refresh_token), family, &user.id, expires_at).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to store refresh token: {}", e)))?;
+
+    Ok(AuthResponse {
+        token,
+        expires_in: state.jwt_service.access_token_lifetime(),
+        refresh_token,
+        role: user.role.clone(),
+        permissions,
+        is_admin: user.role == ADMIN_ROLE,
+    })
+}
+
+/// Start a login with a new family of refresh tokens
+pub(crate) async fn start_session(state: &AppState, user: &User) -> Result<AuthResponse, ApiError> {
+    // Expired tokens are only kept until the next login of anyone
+    if let Err(e) = state.db.delete_expired_refresh_tokens().await {
+        tracing::warn!("Failed to delete expired refresh tokens: {}", e);
+    }
+
+    issue_token(state, user, &Uuid::new_v4().to_string()).await
 }
 
 /// POST /api/register
@@ -145,22 +256,29 @@ pub async fn register(
     let user = state.db.create_user(&payload.username, &payload.email, &password_hash).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to create user: {}", e)))?;
 
-    // Generate JWT token
-    let token = state.jwt_service.generate_token(&user.id, &user.username, user.is_admin)
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to generate token: {}", e)))?;
-
+    // Log the new user in
     Ok(Json(ApiResponse::success(
         "Registration successful",
-        AuthResponse { token, is_admin: user.is_admin },
+        start_session(&state, &user).await?,
     )))
 }
 
+/// Answer a login that started a session, marked so the login rate limit
+/// forgets the account's failures
+pub(crate) fn session_response<T: Serialize>(data: T) -> Response {
+    let mut response = Json(ApiResponse::success("Login successful", data)).into_response();
+    response.extensions_mut().insert(LoginSucceeded);
+    response
+}
+
 /// POST /api/login
-/// Authenticate user and return JWT token
+/// Authenticate user and return JWT token, or a partial token to pass to
+/// `/api/login/2fa` with their second factor
 pub async fn login(
     State(state): State<AppState>,
+    address: ClientAddress,
     Json(payload): Json<LoginRequest>,
-) -> Result<Json<ApiResponse<AuthResponse>>, ApiError> {
+) -> Result<Response, ApiError> {
     // Get user from database
     let user = state.db.get_user_by_username(&payload.username).await
         .map_err(|_| ApiError::new(StatusCode::UNAUTHORIZED, "Invalid username or password"))?;
@@ -173,40 +291,83 @@ pub async fn login(
         return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid username or password"));
     }
 
-    // Generate JWT token
-    let token = state.jwt_service.generate_token(&user.id, &user.username, user.is_admin)
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to generate token: {}", e)))?;
+    if let Some(challenge) = two_factor::login_challenge(&state, &user).await? {
+        let message = if challenge.enrollment_required {
+            "Set up two-factor authentication to continue"
+        } else {
+            "Enter your two-factor code"
+        };
+        return Ok(Json(ApiResponse::success(message, LoginResponse::TwoFactor(challenge))).into_response());
+    }
 
-    Ok(Json(ApiResponse::success(
-        "Login successful",
-        AuthResponse { token, is_admin: user.is_admin },
-    )))
+    let session = start_session(&state, &user).await?;
+    audit::record(&state.db, audit::login_event(&user, &address, "password")).await;
+
+    Ok(session_response(LoginResponse::Session(session)))
 }
 
 /// POST /api/logout
-/// Invalidate the current JWT token (requires authentication)
+/// End the login the token was issued for (requires authentication)
 pub async fn logout(
-    Extension(_claims): Extension<Claims>,
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
 ) -> ApiResultNoData {
-    // Note: With JWT, logout is typically handled client-side by removing the token
-    // For server-side invalidation, you would need to implement a token blacklist
-    // This endpoint confirms the client has authenticated before clearing their token
+    // Its refresh tokens stop working now, and the access token at its expiry
+    if let Some(family) = &claims.session {
+        state.db.delete_refresh_token_family(family).await
+            .map_err(|e| ApiError::internal_server_error(format!("Failed to end login: {}", e)))?;
+    }
     
     Ok(Json(ApiResponse::no_data("Logged out successfully")))
 }
 
 /// POST /api/refresh
-/// Refresh JWT token (requires authentication)
+/// Exchange a refresh token for a new access token and refresh token. Each
+/// refresh token works once; presenting a used one ends its whole login.
 pub async fn refresh_token(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    address: ClientAddress,
+    Json(payload): Json<RefreshRequest>,
 ) -> Result<Json<ApiResponse<AuthResponse>>, ApiError> {
-    // Generate new token with same claims but renewed expiry
-    let new_token = state.jwt_service.generate_token(&claims.sub, &claims.username, claims.is_admin)
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to generate token: {}", e)))?;
+    let token_hash = JwtService::hash_refresh_token(&payload.refresh_token);
+    let refresh_token = state.db.get_refresh_token(&token_hash).await
+        .map_err(|e| match e {
+            DbError::RefreshTokenNotFound => ApiError::unauthorized("Invalid or expired refresh token"),
+            e => ApiError::internal_server_error(format!("Database error: {}", e)),
+        })?;
+
+    if refresh_token.is_expired() {
+        return Err(ApiError::unauthorized("Invalid or expired refresh token"));
+    }
+
+    // A token used twice has been copied, and there's no telling which use was
+    // the thief's, so neither gets to continue
+    let first_use = state.db.use_refresh_token(&token_hash).await
+        .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))?;
+    if !first_use {
+        tracing::warn!("Refresh token reused for user {}; ending that login", refresh_token.user_id);
+        state.db.delete_refresh_token_family(&refresh_token.family_id).await
+            .map_err(|e| ApiError::internal_server_error(format!("Failed to end login: {}", e)))?;
+
+        // Whoever presented it may not be the user, so like a failed login it has no actor
+        let target = match state.db.get_user_by_id(&refresh_token.user_id).await {
+            Ok(user) => user.username,
+            Err(_) => refresh_token.user_id.clone(),
+        };
+        let event = AuditEvent::new(None, "anonymous", AuditAction::RefreshTokenReuse)
+            .with_target(target)
+            .with_change(Some(serde_json::json!({ "family": refresh_token.family_id })), None)
+            .with_ip(address.0);
+        audit::record(&state.db, event).await;
+        return Err(ApiError::unauthorized("Refresh token already used"));
+    }
+
+    // The new token picks up any change to the user's role
+    let user = state.db.get_user_by_id(&refresh_token.user_id).await
+        .map_err(|_| ApiError::unauthorized("Invalid or expired refresh token"))?;
 
     Ok(Json(ApiResponse::success(
         "Token refreshed",
-        AuthResponse { token: new_token, is_admin: claims.is_admin },
+        issue_token(&state, &user, &refresh_token.family_id).await?,
     )))
 }
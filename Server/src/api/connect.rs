@@ -0,0 +1,145 @@
+use axum::{
+    extract::{Query, State, ws::{Message, WebSocket, WebSocketUpgrade}},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::time::Instant;
+use uuid::Uuid;
+use crate::api::response::ApiError;
+use crate::api::auth::AppState;
+use crate::auth::{Claims, Permission};
+use crate::auth::authorized::require;
+use crate::connect::protocol::{ClientMessage, ServerMessage};
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectQuery {
+    /// The JWT, for clients that can't set headers on a WebSocket
+    pub token: Option<String>,
+    /// Stable id of this device, so a reconnect replaces its old session
+    pub device_id: Option<String>,
+    /// Name other devices see
+    pub name: Option<String>,
+}
+
+/// GET /api/connect?token=X&device_id=Y&name=Z
+/// Register as one of the caller's devices over a WebSocket. The token may
+/// also be sent as `Authorization: Bearer`.
+pub async fn connect(
+    State(state): State<AppState>,
+    Query(params): Query<ConnectQuery>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let claims = authenticate(&state, &headers, params.token.as_deref())?;
+    let device_id = params.device_id
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let name = params.name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| device_id.clone());
+
+    Ok(upgrade.on_upgrade(move |socket| run_device(state, claims, device_id, name, socket)))
+}
+
+/// Verify a user token from the query string or Authorization header, for
+/// a user who may listen
+pub(crate) fn authenticate(state: &AppState, headers: &HeaderMap, query_token: Option<&str>) -> Result<Claims, ApiError> {
+    let token = query_token
+        .or_else(|| headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer ")))
+        .ok_or_else(|| ApiError::unauthorized("Missing token"))?;
+
+    let claims = state.jwt_service.verify_token(token)
+        .map_err(|_| ApiError::unauthorized("Invalid or expired token"))?;
+
+    if claims.share_link.is_some() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Share link tokens can only stream"));
+    }
+    require(&claims, Permission::Listen)?;
+
+    Ok(claims)
+}
+
+/// Check a token a connected client sent with `reauth`, which has to be
+/// for the same user as the one it connected with. Returns the new claims
+/// and when they expire.
+pub(crate) fn reauthenticate(state: &AppState, claims: &Claims, token: &str) -> Result<(Claims, OffsetDateTime), ApiError> {
+    let renewed = authenticate(state, &HeaderMap::new(), Some(token))?;
+    if renewed.sub != claims.sub {
+        return Err(ApiError::forbidden("Token is for another user"));
+    }
+    let expires_at = OffsetDateTime::from_unix_timestamp(renewed.exp)
+        .map_err(|_| ApiError::unauthorized("Invalid or expired token"))?;
+    Ok((renewed, expires_at))
+}
+
+/// When a socket opened or renewed with `claims` has to close
+pub(crate) fn socket_deadline(claims: &Claims) -> Instant {
+    let expires_in = (claims.exp - OffsetDateTime::now_utc().unix_timestamp()).max(0) as u64;
+    Instant::now() + Duration::from_secs(expires_in)
+}
+
+/// Relay messages between the socket and the hub until either side closes
+/// or the token expires without the device sending a `reauth`
+async fn run_device(state: AppState, mut claims: Claims, device_id: String, name: String, mut socket: WebSocket) {
+    let hub = state.connect.clone();
+    let mut session = hub.connect(&claims.sub, &device_id, &name);
+    let expiry = tokio::time::sleep_until(socket_deadline(&claims));
+    tokio::pin!(expiry);
+
+    loop {
+        tokio::select! {
+            outgoing = session.messages.recv() => {
+                // The channel only closes when a newer connection took this device id
+                let Some(message) = outgoing else { break };
+                if send(&mut socket, &message).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                match incoming {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(ClientMessage::Reauth { token }) => {
+                            let reply = match reauthenticate(&state, &claims, &token) {
+                                Ok((renewed, expires_at)) => {
+                                    expiry.as_mut().reset(socket_deadline(&renewed));
+                                    claims = renewed;
+                                    ServerMessage::Reauthenticated { expires_at }
+                                }
+                                Err(e) => ServerMessage::Error { message: e.message },
+                            };
+                            if send(&mut socket, &reply).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(message) => hub.handle(&claims.sub, &device_id, message),
+                        Err(e) => {
+                            let error = ServerMessage::Error { message: format!("Invalid message: {}", e) };
+                            if send(&mut socket, &error).await.is_err() {
+                                break;
+                            }
+                        }
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+            _ = &mut expiry => {
+                let _ = send(&mut socket, &ServerMessage::Error { message: "Token expired".to_string() }).await;
+                break;
+            }
+        }
+    }
+
+    hub.disconnect(&claims.sub, &session);
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).unwrap_or_default();
+    socket.send(Message::Text(text.into())).await
+}
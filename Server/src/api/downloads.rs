@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::path::Path;
+use axum::{
+    body::Body,
+    extract::{Json, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+use crate::api::response::{ApiError, ApiResponse};
+use crate::api::auth::AppState;
+use crate::api::artists::find_album;
+use crate::api::libraries::library_filter;
+use crate::api::playlists::{find_playlist, playlist_songs};
+use crate::api::ranges::{parse_range_header, ByteRanges};
+use crate::api::streaming::{multipart_ranges, open_slice};
+use crate::auth::{scope, Authorized, Claims};
+use crate::db::models::{SharePermission, Song};
+use crate::downloads::archive::ArchiveFormat;
+use crate::downloads::hub::{DownloadError, DownloadInfo, DownloadRequest};
+use crate::downloads::manifest::{compute_delta, song_revision, Delta};
+use crate::downloads::transcode::TranscodeProfile;
+use crate::music::seek::file_stamp;
+use crate::music::slicing::Slice;
+
+/// Most songs one download can hold
+const MAX_DOWNLOAD_SONGS: usize = 2000;
+
+/// The songs a download or delta is for: a `playlist` by `name` (and
+/// `owner`), an `album` by `artist` and `name`, or `songs` by `song_ids`
+#[derive(Debug, Deserialize)]
+pub struct DownloadSource {
+    #[serde(rename = "type")]
+    pub source_type: String,
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub song_ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateDownloadRequest {
+    #[serde(flatten)]
+    pub source: DownloadSource,
+    /// `original`, or a codec and bitrate such as `mp3-320`
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// `zip` or `tar`
+    #[serde(default = "default_archive_format")]
+    pub format: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeltaRequest {
+    #[serde(flatten)]
+    pub source: DownloadSource,
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// Revision of each song the client has, from the manifests it got
+    #[serde(default)]
+    pub have: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeltaResponse {
+    #[serde(flatten)]
+    pub delta: Delta,
+    /// Current revision of every song to add or update
+    pub revisions: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadIdQuery {
+    pub id: String,
+}
+
+fn default_profile() -> String {
+    "original".to_string()
+}
+
+fn default_archive_format() -> String {
+    "zip".to_string()
+}
+
+impl From<DownloadError> for ApiError {
+    fn from(error: DownloadError) -> Self {
+        let status = match error {
+            DownloadError::NotFound => StatusCode::NOT_FOUND,
+            DownloadError::NotReady => StatusCode::CONFLICT,
+            DownloadError::TooMany => StatusCode::TOO_MANY_REQUESTS,
+        };
+        ApiError::new(status, error.to_string())
+    }
+}
+
+/// POST /api/downloads
+/// Start building a ZIP or tar of a playlist, album or set of songs in a
+/// transcode profile, with their covers and a manifest. Poll its status
+/// and fetch it from `/api/downloads/archive` once ready.
+pub async fn create_download(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Download>,
+    Json(payload): Json<CreateDownloadRequest>,
+) -> Result<Json<ApiResponse<DownloadInfo>>, ApiError> {
+    let profile = parse_profile(&payload.profile)?;
+    let format = ArchiveFormat::parse(&payload.format)
+        .ok_or_else(|| ApiError::bad_request(format!("Unsupported archive format: {}", payload.format)))?;
+    let (name, songs) = resolve_source(&state, &claims, &payload.source).await?;
+
+    let download = state.downloads.create(&claims.sub, DownloadRequest {
+        source: payload.source.source_type.to_lowercase(),
+        name,
+        songs,
+        profile,
+        format,
+    })?;
+    Ok(Json(ApiResponse::success("Download started", download)))
+}
+
+/// GET /api/downloads
+/// The caller's downloads, newest first
+pub async fn get_downloads(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Download>,
+) -> Result<Json<ApiResponse<Vec<DownloadInfo>>>, ApiError> {
+    Ok(Json(ApiResponse::success("downloads", state.downloads.list(&claims.sub))))
+}
+
+/// GET /api/downloads/status?id=X
+/// How far along a download is
+pub async fn get_download_status(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Download>,
+    Query(params): Query<DownloadIdQuery>,
+) -> Result<Json<ApiResponse<DownloadInfo>>, ApiError> {
+    let download = state.downloads.get(&claims.sub, &params.id)?;
+    Ok(Json(ApiResponse::success("download", download)))
+}
+
+/// DELETE /api/downloads?id=X
+/// Delete a download, cancelling it if it's still building
+pub async fn delete_download(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Download>,
+    Query(params): Query<DownloadIdQuery>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    state.downloads.remove(&claims.sub, &params.id)?;
+    Ok(Json(ApiResponse::no_data("Download deleted")))
+}
+
+/// GET /api/downloads/archive?id=X
+/// Fetch a finished download. An archive never changes once built, so an
+/// interrupted download resumes with a `Range` request, guarded by `If-Range`
+/// with its ETag.
+pub async fn get_download_archive(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Download>,
+    Query(params): Query<DownloadIdQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let (path, download) = state.downloads.archive(&claims.sub, &params.id)?;
+    let path = path.to_string_lossy().to_string();
+    let size = tokio::fs::metadata(&path).await
+        .map_err(|_| ApiError::not_found("Download not found"))?
+        .len();
+    let etag = format!("\"{}\"", download.id);
+    let content_type = ArchiveFormat::parse(&download.format)
+        .map_or("application/octet-stream", |format| format.content_type());
+
+    // A stale If-Range means the client has part of something else
+    let if_range_matches = headers.get(header::IF_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .is_none_or(|value| value == etag);
+    let ranges = headers.get(header::RANGE)
+        .filter(|_| if_range_matches)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_range_header(value, size));
+
+    let slice = Slice::whole(size);
+    let mut response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "private, no-cache")
+        .header(header::CONTENT_DISPOSITION, content_disposition(&download));
+    let (content_length, reader): (u64, Box<dyn AsyncRead + Send + Unpin>) = match ranges {
+        Some(ByteRanges::Unsatisfiable) => {
+            return response.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .map_err(|e| ApiError::internal_server_error(format!("Failed to build response: {}", e)));
+        }
+        None => {
+            let reader = open_slice(&path, &slice, 0, size).await.map_err(open_error)?;
+            response = response.status(StatusCode::OK).header(header::CONTENT_TYPE, content_type);
+            (size, Box::new(reader))
+        }
+        Some(ByteRanges::Satisfiable(ranges)) => match ranges.as_slice() {
+            &[(start, end)] => {
+                let reader = open_slice(&path, &slice, start, end - start + 1).await.map_err(open_error)?;
+                response = response.status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size));
+                (end - start + 1, Box::new(reader))
+            }
+            ranges => {
+                let boundary = uuid::Uuid::new_v4().simple().to_string();
+                let (length, reader) = multipart_ranges(&path, &slice, ranges, content_type, &boundary).await
+                    .map_err(open_error)?;
+                response = response.status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary));
+                (length, reader)
+            }
+        },
+    };
+
+    response.header(header::CONTENT_LENGTH, content_length)
+        .body(Body::from_stream(ReaderStream::new(reader)))
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to build response: {}", e)))
+}
+
+/// POST /api/downloads/delta
+/// Which songs a client synced with a playlist, album or set of songs needs
+/// to add, update or remove to match it now, given the revisions from the
+/// manifests it has. Songs to add or update can then be downloaded by ID.
+pub async fn get_download_delta(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Download>,
+    Json(payload): Json<DeltaRequest>,
+) -> Result<Json<ApiResponse<DeltaResponse>>, ApiError> {
+    let profile = parse_profile(&payload.profile)?;
+    let (_, songs) = resolve_source(&state, &claims, &payload.source).await?;
+
+    let mut current = Vec::with_capacity(songs.len());
+    for song in &songs {
+        // A missing file gets a revision of its own, so it's offered again once back
+        let stamp = file_stamp(Path::new(&song.file_path)).unwrap_or((0, 0));
+        current.push((song.id.clone(), song_revision(song, stamp, profile)));
+    }
+
+    let delta = compute_delta(&current, &payload.have);
+    let revisions = current.into_iter()
+        .filter(|(id, _)| delta.add.contains(id) || delta.update.contains(id))
+        .collect();
+    Ok(Json(ApiResponse::success("delta", DeltaResponse { delta, revisions })))
+}
+
+/// The name and songs of what a download is for
+async fn resolve_source(state: &AppState, claims: &Claims, source: &DownloadSource) -> Result<(String, Vec<Song>), ApiError> {
+    let required = |value: &Option<String>, field: &str| {
+        value.as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| ApiError::bad_request(format!("{} is required", field)))
+    };
+
+    let libraries = library_filter(state, claims).await?;
+    let (name, songs) = match source.source_type.to_lowercase().as_str() {
+        "playlist" => {
+            let name = required(&source.name, "name")?;
+            let playlist = find_playlist(state, claims, &name, source.owner.as_deref(), SharePermission::View).await?;
+            let songs = playlist_songs(state, &playlist, &libraries).await?;
+            (playlist.name, songs)
+        }
+        "album" => {
+            let artist = required(&source.artist, "artist")?;
+            let album = required(&source.name, "name")?;
+            let (artist, songs) = find_album(state, &artist, &album, &libraries).await?;
+            let name = songs.first().and_then(|song| song.album.clone()).unwrap_or(album);
+            (format!("{} - {}", artist.name, name), songs)
+        }
+        "songs" => {
+            let ids = source.song_ids.as_deref().unwrap_or_default();
+            if ids.is_empty() {
+                return Err(ApiError::bad_request("song_ids is required"));
+            }
+            if ids.len() > MAX_DOWNLOAD_SONGS {
+                return Err(ApiError::bad_request(format!("A download can hold at most {} songs", MAX_DOWNLOAD_SONGS)));
+            }
+            let mut songs = Vec::with_capacity(ids.len());
+            for id in ids {
+                let song = state.db.get_song_by_id(id).await.ok()
+                    .filter(|song| libraries.allows(&song.library_id))
+                    .ok_or_else(|| ApiError::not_found(format!("Song not found: {}", id)))?;
+                songs.push(song);
+            }
+            let name = source.name.clone()
+                .filter(|name| !name.trim().is_empty())
+                .unwrap_or_else(|| format!("{} songs", songs.len()));
+            (name, songs)
+        }
+        other => return Err(ApiError::bad_request(format!("Unsupported download type: {}", other))),
+    };
+
+    if songs.len() > MAX_DOWNLOAD_SONGS {
+        return Err(ApiError::bad_request(format!("A download can hold at most {} songs", MAX_DOWNLOAD_SONGS)));
+    }
+    Ok((name, songs))
+}
+
+fn parse_profile(profile: &str) -> Result<TranscodeProfile, ApiError> {
+    TranscodeProfile::parse(profile)
+        .ok_or_else(|| ApiError::bad_request(format!("Unsupported transcode profile: {}", profile)))
+}
+
+/// `attachment` with an ASCII file name, and the real one for clients that
+/// understand RFC 6266 `filename*`
+fn content_disposition(download: &DownloadInfo) -> String {
+    let file_name = download.file_name();
+    let ascii: String = file_name.chars()
+        .map(|c| if c.is_ascii_graphic() || c == ' ' { c } else { '_' })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii, urlencoding::encode(&file_name))
+}
+
+fn open_error(e: std::io::Error) -> ApiError {
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open file: {}", e))
+}
@@ -0,0 +1,221 @@
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+use crate::api::response::{ApiResponse, ApiResult, ApiResultNoData, ApiError};
+use crate::api::auth::AppState;
+use crate::api::artists::{find_album, find_artist};
+use crate::api::libraries::library_filter;
+use crate::api::playlists::find_song;
+use crate::auth::{scope, Authorized};
+use crate::db::models::{LibraryFilter, LibraryItem, UserRating};
+
+#[derive(Debug, Deserialize)]
+pub struct FavoritesQuery {
+    /// `song`, `album` or `artist`, everything when omitted
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// Only favourites, leaving out items that are just rated
+    #[serde(default)]
+    pub favorite: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LibraryItemQuery {
+    /// `song`, `album` or `artist`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Song title, album name or artist name
+    pub name: String,
+    /// Artist of the song or album
+    pub artist: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateFavoriteRequest {
+    #[serde(flatten)]
+    pub item: LibraryItemQuery,
+    pub favorite: Option<bool>,
+    /// 1 to 5 stars, 0 clears the rating
+    pub rating: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FavoriteInfo {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub name: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub favorite: bool,
+    pub rating: Option<u8>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+/// GET /api/user/favorites?type=song&favorite=true
+/// The caller's favourites and ratings, most recently changed first.
+/// `type=song&favorite=true` is the "Liked songs" list.
+pub async fn get_favorites(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<FavoritesQuery>,
+) -> ApiResult<Vec<FavoriteInfo>> {
+    if let Some(kind) = params.kind.as_deref() {
+        check_kind(kind)?;
+    }
+
+    let ratings = state.db.get_user_ratings(&claims.sub, params.kind.as_deref()).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch favorites: {}", e)))?;
+
+    let libraries = library_filter(&state, &claims).await?;
+    let mut favorites = Vec::with_capacity(ratings.len());
+    for rating in ratings.into_iter().filter(|rating| rating.favorite || !params.favorite) {
+        // Songs or artists removed from the library since, or no longer visible, are left out
+        if let Some(info) = favorite_info(&state, rating, &libraries).await {
+            favorites.push(info);
+        }
+    }
+
+    Ok(Json(ApiResponse::success("favorites", favorites)))
+}
+
+/// PUT /api/user/favorites
+/// Favourite or rate a song, album or artist. Fields left out keep their
+/// current value.
+pub async fn update_favorite(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Json(payload): Json<UpdateFavoriteRequest>,
+) -> ApiResult<FavoriteInfo> {
+    if payload.rating.is_some_and(|rating| rating > 5) {
+        return Err(ApiError::bad_request("Ratings go from 1 to 5 stars, or 0 to clear"));
+    }
+
+    let libraries = library_filter(&state, &claims).await?;
+    let item = find_item(&state, &payload.item, &libraries).await?;
+    let mut rating = get_rating(&state, &claims.sub, &item).await?
+        .unwrap_or_else(|| UserRating::new(&claims.sub, item));
+
+    if let Some(favorite) = payload.favorite {
+        rating.favorite = favorite;
+    }
+    if let Some(stars) = payload.rating {
+        rating.rating = Some(stars).filter(|stars| *stars > 0);
+    }
+    rating.updated_at = OffsetDateTime::now_utc();
+
+    state.db.save_user_rating(&rating).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save favorite: {}", e)))?;
+
+    let info = favorite_info(&state, rating, &libraries).await
+        .ok_or_else(|| ApiError::not_found("Item no longer exists"))?;
+
+    Ok(Json(ApiResponse::success("Favorite updated", info)))
+}
+
+/// DELETE /api/user/favorites?type=song&name=X&artist=Y
+/// Unfavourite an item and clear its rating
+pub async fn delete_favorite(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<LibraryItemQuery>,
+) -> ApiResultNoData {
+    let libraries = library_filter(&state, &claims).await?;
+    let item = find_item(&state, &params, &libraries).await?;
+
+    state.db.save_user_rating(&UserRating::new(&claims.sub, item)).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to remove favorite: {}", e)))?;
+
+    Ok(Json(ApiResponse::no_data("Favorite removed")))
+}
+
+/// The caller's favourites and ratings for a page of songs or artists, by id
+pub(crate) async fn ratings_by_id(
+    state: &AppState,
+    user_id: &str,
+    kind: &str,
+    item_ids: Vec<String>,
+) -> Result<HashMap<String, UserRating>, ApiError> {
+    let ratings = state.db.get_user_ratings_for(user_id, kind, &item_ids).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch favorites: {}", e)))?;
+
+    Ok(ratings.into_iter()
+        .map(|rating| (rating.item.item_id().to_string(), rating))
+        .collect())
+}
+
+/// Favourite flag and stars for one id of a `ratings_by_id` lookup
+pub(crate) fn rating_state(ratings: &HashMap<String, UserRating>, item_id: &str) -> (bool, Option<u8>) {
+    ratings.get(item_id)
+        .map(|rating| (rating.favorite, rating.rating))
+        .unwrap_or_default()
+}
+
+async fn get_rating(state: &AppState, user_id: &str, item: &LibraryItem) -> Result<Option<UserRating>, ApiError> {
+    let ratings = state.db.get_user_ratings_for(user_id, item.kind(), &[item.item_id().to_string()]).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch favorites: {}", e)))?;
+
+    Ok(ratings.into_iter().find(|rating| &rating.item == item))
+}
+
+fn check_kind(kind: &str) -> Result<(), ApiError> {
+    match kind {
+        "song" | "album" | "artist" => Ok(()),
+        other => Err(ApiError::bad_request(format!("Unknown item type: {}", other))),
+    }
+}
+
+/// Resolve a song, album or artist from its names
+async fn find_item(state: &AppState, query: &LibraryItemQuery, libraries: &LibraryFilter) -> Result<LibraryItem, ApiError> {
+    check_kind(&query.kind)?;
+
+    if query.kind == "artist" {
+        let (artist, _) = find_artist(state, &query.name, libraries).await?;
+        return Ok(LibraryItem::Artist { artist_id: artist.id });
+    }
+
+    let artist = query.artist.as_deref()
+        .ok_or_else(|| ApiError::bad_request(format!("An artist is required for a {}", query.kind)))?;
+
+    if query.kind == "song" {
+        let song = find_song(state, artist, &query.name, libraries).await?;
+        Ok(LibraryItem::Song { song_id: song.id })
+    } else {
+        // Stored under the album's own spelling so any casing finds it again
+        let (artist, songs) = find_album(state, artist, &query.name, libraries).await?;
+        let album = songs.into_iter().find_map(|song| song.album).unwrap_or_else(|| query.name.clone());
+        Ok(LibraryItem::Album { artist_id: artist.id, album })
+    }
+}
+
+async fn favorite_info(state: &AppState, rating: UserRating, libraries: &LibraryFilter) -> Option<FavoriteInfo> {
+    let (name, artist, album) = match &rating.item {
+        LibraryItem::Song { song_id } => {
+            let song = state.db.get_song_by_id(song_id).await.ok()
+                .filter(|song| libraries.allows(&song.library_id))?;
+            (song.title, Some(song.artist_name), song.album)
+        }
+        LibraryItem::Album { artist_id, album } => {
+            let artist = state.db.get_artist_by_id(artist_id).await.ok()?;
+            (album.clone(), Some(artist.name), None)
+        }
+        LibraryItem::Artist { artist_id } => {
+            let artist = state.db.get_artist_by_id(artist_id).await.ok()?;
+            (artist.name, None, None)
+        }
+    };
+
+    Some(FavoriteInfo {
+        kind: rating.item.kind(),
+        name,
+        artist,
+        album,
+        favorite: rating.favorite,
+        rating: rating.rating,
+        updated_at: rating.updated_at,
+    })
+}
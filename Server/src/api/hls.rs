@@ -0,0 +1,136 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use crate::api::response::ApiError;
+use crate::api::auth::AppState;
+use crate::api::libraries::{check_song_access, library_filter};
+use crate::api::share_links::ensure_share_scope;
+use crate::auth::{Claims, Permission};
+use crate::auth::authorized::require;
+use crate::db::models::Song;
+use crate::music::hls::{segment_path, HlsError};
+use crate::music::hls_playlist::{dash_manifest, master_playlist, media_playlist, parse_rendition_name, Rendition};
+
+const HLS_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+const DASH_CONTENT_TYPE: &str = "application/dash+xml";
+const SEGMENT_CONTENT_TYPE: &str = "audio/mp4";
+
+#[derive(Debug, Deserialize)]
+pub struct PlaylistQuery {
+    /// A user token, or the playlist token from the URLs of a playlist
+    pub token: Option<String>,
+}
+
+/// GET /api/stream/hls/{song_id}/master.m3u8 and /api/stream/hls/{song_id}/manifest.mpd
+/// The HLS master playlist or DASH manifest of a song. URLs in them carry a
+/// token for the song, so players that can't send headers can follow them.
+pub async fn get_manifest(
+    State(state): State<AppState>,
+    Path((song_id, file)): Path<(String, String)>,
+    Query(params): Query<PlaylistQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let (song, claims) = authorize(&state, &headers, params.token.as_deref(), &song_id).await?;
+    let token = playlist_token(&state, &claims, &song)?;
+
+    match file.as_str() {
+        "master.m3u8" => text_response(HLS_CONTENT_TYPE, master_playlist(state.hls.bitrates(), Some(&token))),
+        "manifest.mpd" => {
+            // Every representation's segment durations are listed up front
+            let mut encoded = Vec::new();
+            for &kbps in state.hls.bitrates() {
+                encoded.push((kbps, state.hls.rendition(&song, kbps).await.map_err(hls_error)?));
+            }
+            let renditions: Vec<Rendition> = encoded.iter()
+                .map(|(kbps, segments)| Rendition { kbps: *kbps, segments })
+                .collect();
+            text_response(DASH_CONTENT_TYPE, dash_manifest(&renditions, Some(&token)))
+        }
+        _ => Err(ApiError::not_found("Playlist not found")),
+    }
+}
+
+/// GET /api/stream/hls/{song_id}/{rendition}/{file}
+/// A rendition's media playlist (`index.m3u8`), init segment (`init.mp4`) or
+/// media segments. The rendition is encoded the first time it's asked for.
+pub async fn get_rendition_file(
+    State(state): State<AppState>,
+    Path((song_id, rendition, file)): Path<(String, String, String)>,
+    Query(params): Query<PlaylistQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let kbps = parse_rendition_name(&rendition)
+        .ok_or_else(|| ApiError::not_found("Rendition not found"))?;
+    let (song, claims) = authorize(&state, &headers, params.token.as_deref(), &song_id).await?;
+    let segments = state.hls.rendition(&song, kbps).await.map_err(hls_error)?;
+
+    if file == "index.m3u8" {
+        let token = playlist_token(&state, &claims, &song)?;
+        return text_response(HLS_CONTENT_TYPE, media_playlist(&segments, Some(&token)));
+    }
+
+    let path = segment_path(&song.id, kbps, &file)
+        .ok_or_else(|| ApiError::not_found("Segment not found"))?;
+    let segment = File::open(&path).await
+        .map_err(|_| ApiError::not_found("Segment not found"))?;
+    let len = segment.metadata().await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to read segment: {}", e)))?
+        .len();
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, SEGMENT_CONTENT_TYPE)
+        .header(header::CONTENT_LENGTH, len)
+        .header(header::CACHE_CONTROL, "private, max-age=86400")
+        .body(Body::from_stream(ReaderStream::new(segment)))
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to build response: {}", e)))
+}
+
+/// Verify a token from the query string or Authorization header, which may
+/// be a user token, a share link token or a playlist token for this song
+async fn authorize(state: &AppState, headers: &HeaderMap, query_token: Option<&str>, song_id: &str) -> Result<(Song, Claims), ApiError> {
+    let token = query_token
+        .or_else(|| headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer ")))
+        .ok_or_else(|| ApiError::unauthorized("Missing token"))?;
+
+    let claims = state.jwt_service.verify_playlist_token(token, song_id)
+        .map_err(|_| ApiError::unauthorized("Invalid or expired token"))?;
+    require(&claims, Permission::Listen)?;
+
+    let song = state.db.get_song_by_id(song_id).await
+        .map_err(|_| ApiError::not_found("Song not found"))?;
+    check_song_access(&library_filter(state, &claims).await?, &song)?;
+    ensure_share_scope(state, &claims, &song).await?;
+
+    Ok((song, claims))
+}
+
+fn playlist_token(state: &AppState, claims: &Claims, song: &Song) -> Result<String, ApiError> {
+    state.jwt_service.generate_playlist_token(claims, &song.id)
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to generate token: {}", e)))
+}
+
+fn text_response(content_type: &str, body: String) -> Result<Response, ApiError> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        // Playlists carry a token, so they're never shared or reused
+        .header(header::CACHE_CONTROL, "private, no-store")
+        .body(Body::from(body))
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to build response: {}", e)))
+}
+
+fn hls_error(e: HlsError) -> ApiError {
+    match e {
+        HlsError::UnknownBitrate(_) => ApiError::not_found(e.to_string()),
+        HlsError::Encoder(_) | HlsError::Io(_) => {
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to encode the song: {}", e))
+        }
+    }
+}
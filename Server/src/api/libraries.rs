@@ -0,0 +1,401 @@
+use axum::{extract::{Json, Query, State}, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::api::audit::{self, Changes, ClientAddress};
+use crate::api::response::{ApiError, ApiResponse, ApiResult, ApiResultNoData};
+use crate::api::auth::AppState;
+use crate::auth::{scope, Authorized, Claims, Permission};
+use crate::db::DbError;
+use crate::db::models::{AuditAction, GrantKind, Library, LibraryFilter, LibraryGrant, Song};
+use crate::music::MusicScanner;
+
+// ============================================================================
+// Access checks
+// ============================================================================
+
+/// Libraries the caller may see songs from. Users who manage the library see
+/// all of them, everyone else what they or their role were granted. Share
+/// link tokens see what the link's owner can.
+pub(crate) async fn library_filter(state: &AppState, claims: &Claims) -> Result<LibraryFilter, ApiError> {
+    match &claims.share_link {
+        // The middleware has already turned away links that are gone
+        Some(link_id) => match state.db.get_share_link(link_id).await {
+            Ok(link) => user_library_filter(state, &link.owner_id).await,
+            Err(_) => Ok(LibraryFilter::Only(Vec::new())),
+        },
+        // Signed URLs don't carry the role, so it comes from the account
+        None if claims.role.is_empty() => user_library_filter(state, &claims.sub).await,
+        None if claims.can(Permission::ManageLibrary) => Ok(LibraryFilter::All),
+        None => accessible_libraries(state, &claims.sub, &claims.role).await,
+    }
+}
+
+/// Libraries a user may see songs from, going by the role they have now
+pub(crate) async fn user_library_filter(state: &AppState, user_id: &str) -> Result<LibraryFilter, ApiError> {
+    let user = match state.db.get_user_by_id(user_id).await {
+        Ok(user) => user,
+        Err(DbError::UserNotFound) => return Ok(LibraryFilter::Only(Vec::new())),
+        Err(e) => return Err(ApiError::internal_server_error(format!("Failed to load user: {}", e))),
+    };
+
+    match state.db.get_role(&user.role).await {
+        Ok(role) if role.permissions.contains(&Permission::ManageLibrary) => Ok(LibraryFilter::All),
+        Ok(_) | Err(DbError::RoleNotFound) => accessible_libraries(state, &user.id, &user.role).await,
+        Err(e) => Err(ApiError::internal_server_error(format!("Failed to load role: {}", e))),
+    }
+}
+
+async fn accessible_libraries(state: &AppState, user_id: &str, role: &str) -> Result<LibraryFilter, ApiError> {
+    let libraries = state.db.get_accessible_libraries(user_id, role).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to load libraries: {}", e)))?;
+    Ok(LibraryFilter::Only(libraries))
+}
+
+/// Songs in libraries the caller can't see are treated as missing
+pub(crate) fn check_song_access(libraries: &LibraryFilter, song: &Song) -> Result<(), ApiError> {
+    if libraries.allows(&song.library_id) {
+        Ok(())
+    } else {
+        Err(ApiError::not_found("Song not found"))
+    }
+}
+
+/// Whether one folder is inside the other, which would scan files into both
+fn roots_overlap(a: &Path, b: &Path) -> bool {
+    let resolve = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (a, b) = (resolve(a), resolve(b));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+// ============================================================================
+// Request/Response Types
+// ============================================================================
+
+#[derive(Debug, Serialize)]
+pub struct LibraryBasic {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LibraryInfo {
+    #[serde(flatten)]
+    pub library: Library,
+    pub grants: Vec<GrantInfo>,
+}
+
+/// A grant, naming the user by username rather than ID
+#[derive(Debug, Serialize)]
+pub struct GrantInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateLibraryRequest {
+    pub name: String,
+    pub root_path: String,
+    #[serde(default = "default_scan_on_startup")]
+    pub scan_on_startup: bool,
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+fn default_scan_on_startup() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateLibraryRequest {
+    pub name: Option<String>,
+    pub root_path: Option<String>,
+    pub scan_on_startup: Option<bool>,
+    pub recursive: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LibraryIdQuery {
+    pub id: String,
+}
+
+/// Grant to one user, by username, or to a role, by name
+#[derive(Debug, Deserialize)]
+pub struct GrantRequest {
+    pub library_id: String,
+    pub user: Option<String>,
+    pub role: Option<String>,
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// GET /api/libraries
+/// List the libraries the user can see songs from
+pub async fn get_libraries(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+) -> ApiResult<Vec<LibraryBasic>> {
+    let filter = library_filter(&state, &claims).await?;
+    let libraries = state.db.get_libraries().await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve libraries: {}", e)))?;
+
+    let libraries = libraries.into_iter()
+        .filter(|library| filter.allows(&library.id))
+        .map(|library| LibraryBasic { id: library.id, name: library.name })
+        .collect();
+
+    Ok(Json(ApiResponse::success("libraries", libraries)))
+}
+
+/// GET /api/admin/libraries
+/// List every library with who it is granted to
+pub async fn get_all_libraries(
+    State(state): State<AppState>,
+    _: Authorized<scope::ManageLibrary>,
+) -> ApiResult<Vec<LibraryInfo>> {
+    let libraries = state.db.get_libraries().await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve libraries: {}", e)))?;
+
+    let mut infos = Vec::with_capacity(libraries.len());
+    for library in libraries {
+        let grants = state.db.get_library_grants(&library.id).await
+            .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve grants: {}", e)))?;
+
+        let mut grant_infos = Vec::with_capacity(grants.len());
+        for grant in grants {
+            grant_infos.push(match grant.kind {
+                GrantKind::User => {
+                    // Grants are removed with their user, so a missing one is mid-delete
+                    let Ok(user) = state.db.get_user_by_id(&grant.subject).await else { continue };
+                    GrantInfo { user: Some(user.username), role: None }
+                }
+                GrantKind::Role => GrantInfo { user: None, role: Some(grant.subject) },
+            });
+        }
+        infos.push(LibraryInfo { library, grants: grant_infos });
+    }
+
+    Ok(Json(ApiResponse::success("libraries", infos)))
+}
+
+/// POST /api/admin/libraries
+/// Add a library for a music folder. It is empty until scanned, and only
+/// users who manage the library see it until it is granted.
+pub async fn create_library(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageLibrary>,
+    address: ClientAddress,
+    Json(payload): Json<CreateLibraryRequest>,
+) -> ApiResult<Library> {
+    let library = Library {
+        id: Uuid::new_v4().to_string(),
+        name: payload.name.trim().to_string(),
+        root_path: payload.root_path.trim().to_string(),
+        scan_on_startup: payload.scan_on_startup,
+        recursive: payload.recursive,
+        created_at: OffsetDateTime::now_utc(),
+    };
+    check_library(&state, &library).await?;
+
+    state.db.save_library(&library).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save library: {}", e)))?;
+    let after = serde_json::json!({ "id": library.id, "root_path": library.root_path, "scan_on_startup": library.scan_on_startup, "recursive": library.recursive });
+    let event = audit::event(&claims, &address, AuditAction::LibraryCreate)
+        .with_target(&library.name)
+        .with_change(None, Some(after));
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::success("Library created", library)))
+}
+
+/// PUT /api/admin/libraries?id=X
+/// Rename a library, move its folder or change how it is scanned. Songs
+/// outside a moved folder are removed by its next scan.
+pub async fn update_library(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageLibrary>,
+    address: ClientAddress,
+    Query(params): Query<LibraryIdQuery>,
+    Json(payload): Json<UpdateLibraryRequest>,
+) -> ApiResult<Library> {
+    let mut library = get_library(&state, &params.id).await?;
+    let before = library.clone();
+
+    if let Some(name) = payload.name {
+        library.name = name.trim().to_string();
+    }
+    if let Some(root_path) = payload.root_path {
+        library.root_path = root_path.trim().to_string();
+    }
+    if let Some(scan_on_startup) = payload.scan_on_startup {
+        library.scan_on_startup = scan_on_startup;
+    }
+    if let Some(recursive) = payload.recursive {
+        library.recursive = recursive;
+    }
+    check_library(&state, &library).await?;
+
+    state.db.save_library(&library).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save library: {}", e)))?;
+
+    let mut changes = Changes::default();
+    changes.set("name", before.name.as_str(), library.name.as_str());
+    changes.set("root_path", before.root_path.as_str(), library.root_path.as_str());
+    changes.set("scan_on_startup", before.scan_on_startup, library.scan_on_startup);
+    changes.set("recursive", before.recursive, library.recursive);
+    if !changes.is_empty() {
+        let event = audit::event(&claims, &address, AuditAction::LibraryEdit).with_target(&before.name);
+        audit::record(&state.db, changes.apply(event)).await;
+    }
+
+    Ok(Json(ApiResponse::success("Library updated", library)))
+}
+
+/// DELETE /api/admin/libraries?id=X
+/// Remove a library, its songs and its grants. The files are left alone.
+pub async fn delete_library(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageLibrary>,
+    address: ClientAddress,
+    Query(params): Query<LibraryIdQuery>,
+) -> ApiResultNoData {
+    let library = get_library(&state, &params.id).await?;
+    let (id, name, root_path) = (library.id.clone(), library.name.clone(), library.root_path.clone());
+
+    let scanner = MusicScanner::new(state.db.clone(), library);
+    let removed = scanner.remove_all_songs().await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to remove songs: {}", e)))?;
+    tracing::info!("Removed {} songs of deleted library {}", removed, params.id);
+
+    state.db.delete_library(&params.id).await.map_err(|e| match e {
+        DbError::LibraryNotFound => ApiError::not_found("Library not found"),
+        e => ApiError::internal_server_error(format!("Failed to delete library: {}", e)),
+    })?;
+    let event = audit::event(&claims, &address, AuditAction::LibraryDelete)
+        .with_target(name)
+        .with_change(Some(serde_json::json!({ "id": id, "root_path": root_path, "songs": removed })), None);
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::no_data("Library deleted")))
+}
+
+/// POST /api/admin/libraries/grants
+/// Let a user or everyone with a role see a library
+pub async fn grant_library(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageLibrary>,
+    address: ClientAddress,
+    Json(payload): Json<GrantRequest>,
+) -> ApiResultNoData {
+    let grantee = grantee(&payload);
+    let (library, grant) = to_grant(&state, payload).await?;
+
+    state.db.grant_library(&grant).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to grant library: {}", e)))?;
+    let event = audit::event(&claims, &address, AuditAction::LibraryGrant)
+        .with_target(&library.name)
+        .with_change(None, Some(grantee));
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::no_data("Library granted")))
+}
+
+/// DELETE /api/admin/libraries/grants
+/// Take a grant back. Users who can still see the library through their
+/// role, or who manage the library, keep seeing it.
+pub async fn revoke_library(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ManageLibrary>,
+    address: ClientAddress,
+    Json(payload): Json<GrantRequest>,
+) -> ApiResultNoData {
+    let grantee = grantee(&payload);
+    let (library, grant) = to_grant(&state, payload).await?;
+
+    state.db.revoke_library(&grant).await.map_err(|e| match e {
+        DbError::GrantNotFound => ApiError::not_found("Library grant not found"),
+        e => ApiError::internal_server_error(format!("Failed to revoke library: {}", e)),
+    })?;
+    let event = audit::event(&claims, &address, AuditAction::LibraryRevoke)
+        .with_target(&library.name)
+        .with_change(Some(grantee), None);
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::no_data("Library grant revoked")))
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+async fn get_library(state: &AppState, id: &str) -> Result<Library, ApiError> {
+    state.db.get_library(id).await.map_err(|e| match e {
+        DbError::LibraryNotFound => ApiError::not_found("Library not found"),
+        e => ApiError::internal_server_error(format!("Failed to retrieve library: {}", e)),
+    })
+}
+
+/// A library needs a unique name and a folder of its own
+async fn check_library(state: &AppState, library: &Library) -> Result<(), ApiError> {
+    if library.name.is_empty() || library.name.chars().count() > 64 {
+        return Err(ApiError::bad_request("Library names are 1 to 64 characters"));
+    }
+
+    let root = PathBuf::from(&library.root_path);
+    if !root.is_dir() {
+        return Err(ApiError::bad_request(format!("Not a folder: {}", library.root_path)));
+    }
+
+    let libraries = state.db.get_libraries().await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to retrieve libraries: {}", e)))?;
+    for other in libraries.iter().filter(|other| other.id != library.id) {
+        if other.name.eq_ignore_ascii_case(&library.name) {
+            return Err(ApiError::new(StatusCode::CONFLICT, format!("Library name is taken: {}", library.name)));
+        }
+        if roots_overlap(&root, Path::new(&other.root_path)) {
+            return Err(ApiError::new(StatusCode::CONFLICT, format!("Folder overlaps library '{}'", other.name)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a grant request names an existing library and exactly one existing
+/// user or role, returning the library with the grant
+async fn to_grant(state: &AppState, payload: GrantRequest) -> Result<(Library, LibraryGrant), ApiError> {
+    let library = get_library(state, &payload.library_id).await?;
+
+    let (kind, subject) = match (payload.user, payload.role) {
+        (Some(username), None) => {
+            let user = state.db.get_user_by_username(&username).await.map_err(|e| match e {
+                DbError::UserNotFound => ApiError::not_found(format!("User not found: {}", username)),
+                e => ApiError::internal_server_error(format!("Failed to retrieve user: {}", e)),
+            })?;
+            (GrantKind::User, user.id)
+        }
+        (None, Some(role)) => {
+            state.db.get_role(&role).await.map_err(|e| match e {
+                DbError::RoleNotFound => ApiError::not_found(format!("Role not found: {}", role)),
+                e => ApiError::internal_server_error(format!("Failed to retrieve role: {}", e)),
+            })?;
+            (GrantKind::Role, role)
+        }
+        _ => return Err(ApiError::bad_request("Give either a user or a role")),
+    };
+
+    Ok((library, LibraryGrant { library_id: payload.library_id, kind, subject }))
+}
+
+/// Who a grant request is for, as the audit log shows it
+fn grantee(payload: &GrantRequest) -> serde_json::Value {
+    match &payload.user {
+        Some(user) => serde_json::json!({ "user": user }),
+        None => serde_json::json!({ "role": payload.role }),
+    }
+}
@@ -0,0 +1,196 @@
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use crate::api::audit::{self, ClientAddress};
+use crate::api::response::{ApiResponse, ApiError, ApiResult, ApiResultNoData};
+use crate::api::auth::AppState;
+use crate::auth::{scope, Authorized, Claims};
+use crate::api::libraries::library_filter;
+use crate::api::playlists::find_song;
+use crate::db::models::{AuditAction, LyricsSource, Song, SongLyrics};
+use crate::music::lyrics::{parse_lrc, plain_text, FoundLyrics, LyricLine};
+
+#[derive(Debug, Deserialize)]
+pub struct LyricsQuery {
+    pub artist_name: String,
+    pub name: String,
+    /// `plain` to leave out the timed lines
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditLyricsRequest {
+    pub artist_name: String,
+    pub song_name: String,
+    /// Plain text or LRC
+    pub lyrics: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LyricsSongRequest {
+    pub artist_name: String,
+    pub song_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LyricsInfo {
+    pub name: String,
+    pub artist_name: String,
+    pub source: LyricsSource,
+    pub synced: bool,
+    /// The lyrics without timestamps
+    pub text: String,
+    /// Timed lines, for synced lyrics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<Vec<LyricLine>>,
+}
+
+impl LyricsInfo {
+    fn new(song: Song, lyrics: SongLyrics, with_lines: bool) -> Self {
+        LyricsInfo {
+            name: song.title,
+            artist_name: song.artist_name,
+            source: lyrics.source,
+            synced: lyrics.synced,
+            text: plain_text(&lyrics.text, lyrics.synced),
+            lines: (with_lines && lyrics.synced).then(|| parse_lrc(&lyrics.text)),
+        }
+    }
+}
+
+/// GET /api/songs/lyrics?artist_name=X&name=Y&format=plain
+/// Get a song's lyrics, looking them up from the lyrics provider when the
+/// song has none and one is configured
+pub async fn get_lyrics(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<LyricsQuery>,
+) -> ApiResult<LyricsInfo> {
+    let with_lines = match params.format.as_deref() {
+        None | Some("synced") => true,
+        Some("plain") => false,
+        Some(format) => return Err(ApiError::bad_request(format!("Unknown lyrics format: {}", format))),
+    };
+
+    let libraries = library_filter(&state, &claims).await?;
+    let song = find_song(&state, &params.artist_name, &params.name, &libraries).await?;
+    let lyrics = match stored_lyrics(&state, &song).await? {
+        Some(lyrics) => Some(lyrics),
+        None if state.lyrics_provider.is_some() => match lookup_lyrics(&state, &song).await {
+            Ok(lyrics) => lyrics,
+            Err(e) => {
+                tracing::warn!("Lyrics lookup for '{}' failed: {}", song.title, e.message);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let lyrics = lyrics.ok_or_else(|| ApiError::not_found("No lyrics for this song"))?;
+    Ok(Json(ApiResponse::success("Lyrics", LyricsInfo::new(song, lyrics, with_lines))))
+}
+
+/// PUT /api/admin/songs/lyrics
+/// Set a song's lyrics, as plain text or LRC. Library scans leave them be.
+pub async fn edit_lyrics(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::EditSongs>,
+    address: ClientAddress,
+    Json(payload): Json<EditLyricsRequest>,
+) -> ApiResult<LyricsInfo> {
+    let libraries = library_filter(&state, &claims).await?;
+    let song = find_song(&state, &payload.artist_name, &payload.song_name, &libraries).await?;
+    let found = FoundLyrics::from_text(&payload.lyrics, LyricsSource::Manual)
+        .ok_or_else(|| ApiError::bad_request("Lyrics are empty. Use DELETE to remove them."))?;
+
+    let before = stored_lyrics(&state, &song).await?;
+    let lyrics = save_lyrics(&state, &song, found).await?;
+    record_lyrics_change(&state, &claims, &address, &song, before, Some(&lyrics)).await;
+    Ok(Json(ApiResponse::success("Lyrics updated", LyricsInfo::new(song, lyrics, true))))
+}
+
+/// DELETE /api/admin/songs/lyrics
+/// Remove a song's lyrics. The next scan picks up any in its file again.
+pub async fn delete_lyrics(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::EditSongs>,
+    address: ClientAddress,
+    Json(payload): Json<LyricsSongRequest>,
+) -> ApiResultNoData {
+    let libraries = library_filter(&state, &claims).await?;
+    let song = find_song(&state, &payload.artist_name, &payload.song_name, &libraries).await?;
+    let before = stored_lyrics(&state, &song).await?;
+    state.db.delete_song_lyrics(&song.id).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to delete lyrics: {}", e)))?;
+    record_lyrics_change(&state, &claims, &address, &song, before, None).await;
+
+    Ok(Json(ApiResponse::no_data("Lyrics deleted")))
+}
+
+/// POST /api/admin/songs/lyrics/lookup
+/// Replace a song's lyrics with those from the lyrics provider
+pub async fn lookup_song_lyrics(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::EditSongs>,
+    address: ClientAddress,
+    Json(payload): Json<LyricsSongRequest>,
+) -> ApiResult<LyricsInfo> {
+    if state.lyrics_provider.is_none() {
+        return Err(ApiError::bad_request("No lyrics provider is configured"));
+    }
+
+    let libraries = library_filter(&state, &claims).await?;
+    let song = find_song(&state, &payload.artist_name, &payload.song_name, &libraries).await?;
+    let before = stored_lyrics(&state, &song).await?;
+    let lyrics = lookup_lyrics(&state, &song).await?
+        .ok_or_else(|| ApiError::not_found("The lyrics provider has no lyrics for this song"))?;
+    record_lyrics_change(&state, &claims, &address, &song, before, Some(&lyrics)).await;
+
+    Ok(Json(ApiResponse::success("Lyrics updated", LyricsInfo::new(song, lyrics, true))))
+}
+
+/// Note an admin's change to a song's lyrics in the audit log
+async fn record_lyrics_change(state: &AppState, claims: &Claims, address: &ClientAddress, song: &Song, before: Option<SongLyrics>, after: Option<&SongLyrics>) {
+    let to_value = |lyrics: &SongLyrics| serde_json::json!({ "text": lyrics.text, "source": lyrics.source });
+    let action = if after.is_some() { AuditAction::LyricsEdit } else { AuditAction::LyricsDelete };
+    let event = audit::event(claims, address, action)
+        .with_target(format!("{} - {}", song.artist_name, song.title))
+        .with_change(before.as_ref().map(to_value), after.map(to_value));
+    audit::record(&state.db, event).await;
+}
+
+async fn stored_lyrics(state: &AppState, song: &Song) -> Result<Option<SongLyrics>, ApiError> {
+    state.db.get_song_lyrics(&song.id).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to fetch lyrics: {}", e)))
+}
+
+/// Ask the lyrics provider, keeping what it finds
+async fn lookup_lyrics(state: &AppState, song: &Song) -> Result<Option<SongLyrics>, ApiError> {
+    let Some(provider) = &state.lyrics_provider else {
+        return Ok(None);
+    };
+
+    let text = provider.lookup(song).await
+        .map_err(|e| ApiError::new(StatusCode::BAD_GATEWAY, e.to_string()))?;
+    match text.and_then(|text| FoundLyrics::from_text(&text, LyricsSource::Provider)) {
+        Some(found) => save_lyrics(state, song, found).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+async fn save_lyrics(state: &AppState, song: &Song, found: FoundLyrics) -> Result<SongLyrics, ApiError> {
+    let lyrics = SongLyrics {
+        song_id: song.id.clone(),
+        text: found.text,
+        synced: found.synced,
+        source: found.source,
+        updated_at: OffsetDateTime::now_utc(),
+    };
+
+    state.db.save_song_lyrics(&lyrics).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save lyrics: {}", e)))?;
+    Ok(lyrics)
+}
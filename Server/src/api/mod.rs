@@ -4,25 +4,39 @@
 //! Requires JWT authentication except for public endpoints.
 
 pub mod response;
+pub mod pagination;
 pub mod auth;
 pub mod songs;
 pub mod artists;
 pub mod playlists;
 pub mod users;
 pub mod streaming;
+pub mod ranges;
+pub mod hls;
 pub mod admin;
+pub mod share_links;
+pub mod favorites;
+pub mod play_queue;
+pub mod connect;
+pub mod rooms;
+pub mod lyrics;
+pub mod downloads;
+pub mod libraries;
+pub mod oidc;
+pub mod two_factor;
+pub mod passkeys;
+pub mod api_keys;
+pub mod audit;
 
-use axum::{Router, routing::{get, post, put, delete}, middleware};
+use axum::{Router, extract::FromRef, routing::{get, post, put, delete}, middleware, http::{header, HeaderName}};
 use tower_http::cors::{CorsLayer, Any};
 use crate::api::auth::AppState;
-use crate::auth::middleware::{require_auth, require_admin, AuthState};
+use crate::auth::middleware::{rate_limit_auth, signed_stream_auth, AuthState};
 
 /// Create the main API router with all endpoints
 pub fn create_router(state: AppState) -> Router {
     // Create auth state for middleware
-    let auth_state = AuthState {
-        jwt_service: state.jwt_service.clone(),
-    };
+    let auth_state = AuthState::from_ref(&state);
     
     Router::new()
         // Public health check
@@ -30,31 +44,68 @@ pub fn create_router(state: AppState) -> Router {
         
         // Public auth routes
         .merge(public_auth_routes())
+
+        // Logins and other password or code checks, which are rate limited
+        .merge(login_attempt_routes(auth_state.clone()))
+
+        // Remote control and listening rooms, which check the token itself as browsers can't set WebSocket headers
+        .route("/api/connect", get(connect::connect))
+        .route("/api/rooms/join", get(rooms::join_room))
+
+        // Adaptive streaming, which also takes the token in the query string for players that can't set headers
+        .route("/api/stream/hls/{song_id}/{file}", get(hls::get_manifest))
+        .route("/api/stream/hls/{song_id}/{rendition}/{file}", get(hls::get_rendition_file))
         
-        // Protected routes (require authentication)
-        .merge(protected_routes(auth_state.clone()))
+        // Protected routes, whose handlers each require a permission
+        .merge(protected_routes())
+
+        // Streaming, which also takes signed URLs
+        .merge(streaming_routes(auth_state))
         
-        // Admin routes (require admin role)
-        .merge(admin_routes_protected(auth_state.clone()))
+        // Admin routes, for roles with the permissions to manage users, songs or playlists
+        .nest("/api/admin", admin_routes())
         
         // Add CORS
-        .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
+        .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any).expose_headers([
+            header::ETAG,
+            header::CONTENT_DISPOSITION,
+            header::CONTENT_RANGE,
+            HeaderName::from_static(streaming::TRACK_START_HEADER),
+            HeaderName::from_static(streaming::TRACK_END_HEADER),
+            HeaderName::from_static(streaming::ENCODER_DELAY_HEADER),
+            HeaderName::from_static(streaming::ENCODER_PADDING_HEADER),
+            HeaderName::from_static(streaming::STREAM_START_HEADER),
+        ]))
         
         // Add application state
         .with_state(state)
 }
 
 fn public_auth_routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/passkeys/login/start", post(passkeys::login_start))
+        .route("/api/refresh", post(auth::refresh_token))
+        .route("/api/oidc/authorize", get(oidc::authorize))
+        .route("/api/oidc/callback", post(oidc::callback))
+        .route("/api/public/shares/{token}", get(share_links::get_public_share))
+}
+
+fn login_attempt_routes(auth_state: AuthState) -> Router<AppState> {
     Router::new()
         .route("/api/register", post(auth::register))
         .route("/api/login", post(auth::login))
+        .route("/api/login/2fa", post(two_factor::login))
+        .route("/api/login/2fa/setup", post(two_factor::login_setup))
+        .route("/api/passkeys/login/finish", post(passkeys::login_finish))
+        .route("/api/user/reset", post(users::reset_password))
+        .route("/api/public/shares/{token}/access", post(share_links::access_public_share))
+        .route_layer(middleware::from_fn_with_state(auth_state, rate_limit_auth))
 }
 
-fn protected_routes(auth_state: AuthState) -> Router<AppState> {
+fn protected_routes() -> Router<AppState> {
     Router::new()
         // Auth routes
         .route("/api/logout", post(auth::logout))
-        .route("/api/refresh", post(auth::refresh_token))
         
         // Song routes
         .nest("/api/songs", songs_routes())
@@ -68,18 +119,27 @@ fn protected_routes(auth_state: AuthState) -> Router<AppState> {
         // User routes
         .nest("/api/user", user_routes())
         
-        // Streaming routes
-        .nest("/api/stream", streaming_routes())
+        // Libraries the user can see
+        .route("/api/libraries", get(libraries::get_libraries))
         
-        // Apply authentication middleware to all these routes
-        .route_layer(middleware::from_fn_with_state(auth_state, require_auth))
-}
+        // Share link routes
+        .route("/api/share-links", get(share_links::get_share_links))
+        .route("/api/share-links", post(share_links::create_share_link))
+        .route("/api/share-links", delete(share_links::revoke_share_link))
+        .route("/api/rooms", get(rooms::get_rooms))
+        .route("/api/rooms", post(rooms::create_room))
+        .route("/api/rooms", delete(rooms::close_room))
 
-fn admin_routes_protected(auth_state: AuthState) -> Router<AppState> {
-    Router::new()
-        .nest("/api/admin", admin_routes())
-        // Apply admin middleware
-        .route_layer(middleware::from_fn_with_state(auth_state, require_admin))
+        // Signed streaming URLs
+        .route("/api/stream/sign", post(streaming::sign_stream_url))
+
+        // Offline download bundles
+        .route("/api/downloads", get(downloads::get_downloads))
+        .route("/api/downloads", post(downloads::create_download))
+        .route("/api/downloads", delete(downloads::delete_download))
+        .route("/api/downloads/status", get(downloads::get_download_status))
+        .route("/api/downloads/archive", get(downloads::get_download_archive))
+        .route("/api/downloads/delta", post(downloads::get_download_delta))
 }
 
 
@@ -88,6 +148,7 @@ fn songs_routes() -> Router<AppState> {
         .route("/", get(songs::get_songs))
         .route("/info", get(songs::get_song_info))
         .route("/cover", get(songs::get_song_cover))
+        .route("/lyrics", get(lyrics::get_lyrics))
 }
 
 fn artists_routes() -> Router<AppState> {
@@ -104,10 +165,19 @@ fn playlists_routes() -> Router<AppState> {
         .route("/shared", get(playlists::get_shared_playlists))
         .route("/", post(playlists::create_playlist))
         .route("/", delete(playlists::delete_playlist))
+        .route("/songs", get(playlists::get_playlist_songs))
         .route("/song/add", post(playlists::add_song_to_playlist))
+        .route("/song/move", post(playlists::move_song_in_playlist))
         .route("/song/remove", post(playlists::remove_song_from_playlist))
+        .route("/songs/add", post(playlists::add_songs_to_playlist))
+        .route("/songs/remove", post(playlists::remove_songs_from_playlist))
+        .route("/rules", put(playlists::update_playlist_rules))
+        .route("/export", get(playlists::export_playlist))
+        .route("/import", post(playlists::import_playlist))
         .route("/share", post(playlists::share_playlist))
         .route("/share", delete(playlists::revoke_playlist_share))
+        .route("/shares", get(playlists::get_playlist_shares))
+        .route("/activity", get(playlists::get_playlist_activity))
 }
 
 fn user_routes() -> Router<AppState> {
@@ -115,25 +185,62 @@ fn user_routes() -> Router<AppState> {
         .route("/", get(users::get_user_info))
         .route("/", put(users::update_user_info))
         .route("/password", put(users::change_password))
-        .route("/reset", post(users::reset_password))
         .route("/delete", post(users::delete_account))
+        .route("/oidc", post(oidc::link))
+        .route("/oidc", delete(oidc::unlink))
+        .route("/2fa", get(two_factor::get_status))
+        .route("/2fa", delete(two_factor::disable))
+        .route("/2fa/setup", post(two_factor::setup))
+        .route("/2fa/enable", post(two_factor::enable_two_factor))
+        .route("/2fa/recovery-codes", post(two_factor::regenerate_recovery_codes))
+        .route("/passkeys", get(passkeys::get_passkeys))
+        .route("/passkeys", delete(passkeys::delete_passkey))
+        .route("/passkeys/register/start", post(passkeys::register_start))
+        .route("/passkeys/register/finish", post(passkeys::register_finish))
+        .route("/api-keys", get(api_keys::get_api_keys))
+        .route("/api-keys", post(api_keys::create_api_key))
+        .route("/api-keys", delete(api_keys::delete_api_key))
+        .route("/favorites", get(favorites::get_favorites))
+        .route("/favorites", put(favorites::update_favorite))
+        .route("/favorites", delete(favorites::delete_favorite))
+        .route("/queue", get(play_queue::get_queue))
+        .route("/queue", put(play_queue::save_queue))
 }
 
-fn streaming_routes() -> Router<AppState> {
-    Router::new().route("/", get(streaming::stream_song))
+fn streaming_routes(auth_state: AuthState) -> Router<AppState> {
+    Router::new()
+        .route("/api/stream", get(streaming::stream_song))
+        .route_layer(middleware::from_fn_with_state(auth_state, signed_stream_auth))
 }
 
 fn admin_routes() -> Router<AppState> {
     Router::new()
-        // Admin users authenticate via the regular /login endpoint
-        // Access is controlled by the is_admin flag in their JWT token
+        // Users authenticate via the regular /login endpoint
+        // Access is controlled by the permissions in their JWT token's scope
         .route("/users", get(admin::get_all_users))
         .route("/users/edit", put(admin::edit_user))
         .route("/users/delete", delete(admin::delete_user))
+        .route("/users/2fa", delete(admin::reset_two_factor))
+        .route("/lockouts", get(admin::get_lockouts))
+        .route("/lockouts", delete(admin::unlock))
+        .route("/audit", get(audit::get_audit_log))
+        .route("/audit/export", get(audit::export_audit_log))
+        .route("/roles", get(admin::get_roles))
+        .route("/roles", post(admin::save_role))
+        .route("/roles", delete(admin::delete_role))
         .route("/songs/add", post(admin::add_song))
         .route("/songs/edit", put(admin::edit_song))
         .route("/songs/delete", delete(admin::delete_song))
         .route("/songs/scan", post(admin::scan_music_directory))
+        .route("/libraries", get(libraries::get_all_libraries))
+        .route("/libraries", post(libraries::create_library))
+        .route("/libraries", put(libraries::update_library))
+        .route("/libraries", delete(libraries::delete_library))
+        .route("/libraries/grants", post(libraries::grant_library))
+        .route("/libraries/grants", delete(libraries::revoke_library))
+        .route("/songs/lyrics", put(lyrics::edit_lyrics))
+        .route("/songs/lyrics", delete(lyrics::delete_lyrics))
+        .route("/songs/lyrics/lookup", post(lyrics::lookup_song_lyrics))
         .route("/playlists", get(admin::get_all_playlists))
         .route("/playlists/edit", put(admin::edit_playlist))
         .route("/playlists/delete", delete(admin::delete_playlist))
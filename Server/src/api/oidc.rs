@@ -0,0 +1,316 @@
+use axum::{extract::{Json, State}, http::StatusCode};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::api::audit::{self, ClientAddress};
+use crate::api::auth::{start_session, AppState, LoginResponse};
+use crate::api::two_factor;
+use crate::api::response::{ApiError, ApiResponse, ApiResult, ApiResultNoData};
+use crate::auth::Authorized;
+use crate::auth::oidc::{Identity, OidcClient, OidcError};
+use crate::db::DbError;
+use crate::db::models::{AuditAction, AuditEvent, User};
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationResponse {
+    /// Identity provider page to send the browser to
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackRequest {
+    pub code: String,
+    pub state: String,
+}
+
+fn oidc_client(state: &AppState) -> Result<&Arc<OidcClient>, ApiError> {
+    state.oidc.as_ref()
+        .ok_or_else(|| ApiError::not_found("Single sign-on isn't set up on this server"))
+}
+
+fn oidc_error(e: OidcError) -> ApiError {
+    match e {
+        OidcError::UnknownLogin => ApiError::bad_request(e.to_string()),
+        OidcError::InvalidToken(_) => ApiError::unauthorized(e.to_string()),
+        OidcError::Status(status) if status.is_client_error() => ApiError::unauthorized("The identity provider refused the login"),
+        OidcError::TooManyLogins => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+        e => {
+            tracing::warn!("Single sign-on failed: {}", e);
+            ApiError::new(StatusCode::BAD_GATEWAY, e.to_string())
+        }
+    }
+}
+
+/// GET /api/oidc/authorize
+/// Start a single sign-on login, returning the identity provider page to send
+/// the browser to
+pub async fn authorize(State(state): State<AppState>) -> ApiResult<AuthorizationResponse> {
+    let url = oidc_client(&state)?.authorization_url(None).await.map_err(oidc_error)?;
+
+    Ok(Json(ApiResponse::success("Continue at the identity provider", AuthorizationResponse { url })))
+}
+
+/// POST /api/user/oidc
+/// Start a single sign-on login that links the identity to the caller's account
+pub async fn link(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> ApiResult<AuthorizationResponse> {
+    let url = oidc_client(&state)?.authorization_url(Some(claims.sub)).await.map_err(oidc_error)?;
+
+    Ok(Json(ApiResponse::success("Continue at the identity provider", AuthorizationResponse { url })))
+}
+
+/// DELETE /api/user/oidc
+/// Unlink the caller's account from the identity provider
+pub async fn unlink(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+) -> ApiResultNoData {
+    let oidc = oidc_client(&state)?;
+    state.db.unlink_identity(&oidc.config().issuer, &claims.sub).await
+        .map_err(|e| match e {
+            DbError::IdentityNotFound => ApiError::not_found("Your account isn't linked"),
+            e => ApiError::internal_server_error(format!("Failed to unlink account: {}", e)),
+        })?;
+    let event = audit::event(&claims, &address, AuditAction::IdentityUnlink)
+        .with_target(&claims.username)
+        .with_change(Some(serde_json::json!({ "issuer": oidc.config().issuer })), None);
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::no_data("Account unlinked")))
+}
+
+/// POST /api/oidc/callback
+/// Finish a single sign-on login with the code and state the identity provider
+/// sent the browser back with, logging in as the linked account. Accounts
+/// that use two-factor authentication still need their second factor.
+pub async fn callback(
+    State(state): State<AppState>,
+    address: ClientAddress,
+    Json(payload): Json<CallbackRequest>,
+) -> ApiResult<LoginResponse> {
+    let oidc = oidc_client(&state)?;
+    let login = oidc.complete(&payload.code, &payload.state).await.map_err(oidc_error)?;
+    let identity = login.identity;
+    let issuer = &oidc.config().issuer;
+
+    let linked = match state.db.get_user_by_identity(issuer, &identity.sub).await {
+        Ok(user) => Some(user),
+        Err(DbError::UserNotFound) => None,
+        Err(e) => return Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+    };
+
+    let mut user = match (login.link_user, linked) {
+        (Some(user_id), Some(user)) if user.id != user_id => {
+            return Err(ApiError::new(StatusCode::CONFLICT, "That identity is linked to another account"));
+        }
+        (_, Some(user)) => user,
+        (Some(user_id), None) => {
+            let user = state.db.get_user_by_id(&user_id).await
+                .map_err(|_| ApiError::unauthorized("The account to link no longer exists"))?;
+            link_identity(&state, &address, issuer, &identity, &user, "link").await?;
+            user
+        }
+        (None, None) => {
+            let (user, how) = match linkable_by_email(&state, oidc, &identity).await? {
+                Some(user) => (user, "email"),
+                None if oidc.config().auto_provision => (provision(&state, &identity).await?, "new_account"),
+                None => return Err(ApiError::forbidden("No account is linked to that identity")),
+            };
+            link_identity(&state, &address, issuer, &identity, &user, how).await?;
+            user
+        }
+    };
+
+    if let Some(roles) = &oidc.config().roles {
+        let role = roles.role_for(&identity.claims);
+        if role != user.role {
+            match state.db.get_role(role).await {
+                Ok(_) => {
+                    state.db.update_user_role(&user.id, role).await
+                        .map_err(|e| ApiError::internal_server_error(format!("Failed to update role: {}", e)))?;
+                    user.role = role.to_string();
+                }
+                Err(DbError::RoleNotFound) => tracing::warn!("Single sign-on maps to role '{}', which doesn't exist", role),
+                Err(e) => return Err(ApiError::internal_server_error(format!("Failed to get role: {}", e))),
+            }
+        }
+    }
+
+    if let Some(challenge) = two_factor::login_challenge(&state, &user).await? {
+        let message = if challenge.enrollment_required {
+            "Set up two-factor authentication to continue"
+        } else {
+            "Enter your two-factor code"
+        };
+        return Ok(Json(ApiResponse::success(message, LoginResponse::TwoFactor(challenge))));
+    }
+
+    let session = start_session(&state, &user).await?;
+    audit::record(&state.db, audit::login_event(&user, &address, "single_sign_on")).await;
+
+    Ok(Json(ApiResponse::success("Login successful", LoginResponse::Session(session))))
+}
+
+/// Link the identity to `user`, noting `how` it came to be linked: asked
+/// for by the user, matched by email, or for an account made for it
+async fn link_identity(state: &AppState, address: &ClientAddress, issuer: &str, identity: &Identity, user: &User, how: &str) -> Result<(), ApiError> {
+    state.db.link_identity(issuer, &identity.sub, &user.id).await
+        .map_err(|e| match e {
+            DbError::IdentityAlreadyLinked => ApiError::new(StatusCode::CONFLICT, "Your account is already linked to another identity"),
+            e => ApiError::internal_server_error(format!("Failed to link account: {}", e)),
+        })?;
+
+    let event = AuditEvent::new(Some(&user.id), &user.username, AuditAction::IdentityLink)
+        .with_target(&user.username)
+        .with_change(None, Some(serde_json::json!({ "issuer": issuer, "subject": identity.sub, "how": how })))
+        .with_ip(address.0);
+    audit::record(&state.db, event).await;
+    Ok(())
+}
+
+/// The account with the identity's email, when linking by email is on and the
+/// provider has verified the address
+async fn linkable_by_email(state: &AppState, oidc: &OidcClient, identity: &Identity) -> Result<Option<User>, ApiError> {
+    let Some(email) = identity.email.as_deref().filter(|_| identity.email_verified) else {
+        return Ok(None);
+    };
+    if !oidc.config().link_by_email {
+        return Ok(None);
+    }
+
+    match state.db.get_user_by_email(email).await {
+        Ok(user) => Ok(Some(user)),
+        Err(DbError::UserNotFound) => Ok(None),
+        Err(e) => Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+    }
+}
+
+/// Create an account for someone logging in for the first time
+async fn provision(state: &AppState, identity: &Identity) -> Result<User, ApiError> {
+    let email = identity.email.as_deref()
+        .filter(|email| !email.is_empty())
+        .ok_or_else(|| ApiError::forbidden("The identity provider didn't share an email address, which new accounts need"))?;
+    if state.db.email_exists(email).await
+        .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))? {
+        return Err(ApiError::new(StatusCode::CONFLICT, "An account with this email already exists; log in to it and link single sign-on from there"));
+    }
+
+    let username = available_username(state, identity).await?;
+
+    // They log in through the provider, so the password is random and never shown
+    let password = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let password_hash = state.password_service.hash_password(&password)
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to hash password: {}", e)))?;
+
+    let user = state.db.create_user(&username, email, &password_hash).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to create user: {}", e)))?;
+    tracing::info!("Created account '{}' for single sign-on user {}", user.username, identity.sub);
+
+    Ok(user)
+}
+
+/// The identity's preferred username, or its email's local part, made to fit
+/// the rules for usernames and numbered when it's taken
+async fn available_username(state: &AppState, identity: &Identity) -> Result<String, ApiError> {
+    let wanted = identity.preferred_username.as_deref()
+        .or_else(|| identity.email.as_deref().and_then(|email| email.split('@').next()))
+        .unwrap_or_default();
+    let mut base: String = wanted.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .take(20)
+        .collect();
+    if base.len() < 3 {
+        base = "user".to_string();
+    }
+
+    for n in 1..=100 {
+        let candidate = match n {
+            1 => base.clone(),
+            n => {
+                let suffix = n.to_string();
+                format!("{}{}", &base[..base.len().min(20 - suffix.len())], suffix)
+            }
+        };
+        if !state.db.username_exists(&candidate).await
+            .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))? {
+            return Ok(candidate);
+        }
+    }
+
+    Err(ApiError::new(StatusCode::CONFLICT, "Couldn't find a free username"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::pagination::PageLimits;
+    use crate::auth::oidc::tests::{client, mock_provider, start_login};
+    use crate::auth::rate_limit::{RateLimitConfig, RateLimiter};
+    use crate::auth::stream_url::StreamUrlSigner;
+    use crate::auth::webauthn::{WebAuthn, WebAuthnConfig};
+    use crate::auth::{JwtService, PasswordService};
+    use crate::connect::ConnectHub;
+    use crate::db::{create_database, DbBackend};
+    use crate::downloads::DownloadHub;
+    use crate::music::hls::HlsPackager;
+    use crate::rooms::RoomHub;
+
+    async fn app_state(path: &std::path::Path, oidc: OidcClient) -> AppState {
+        let db = create_database(DbBackend::SQLite, &format!("sqlite:{}?mode=rwc", path.display())).await.unwrap();
+
+        AppState {
+            db,
+            jwt_service: Arc::new(JwtService::new("test_secret_key_for_testing", 15, 30)),
+            stream_signer: Arc::new(StreamUrlSigner::new("test_secret_key_for_testing")),
+            password_service: Arc::new(PasswordService::new()),
+            page_limits: PageLimits { default_size: 50, max_size: 500 },
+            website_url: "http://localhost:5173".to_string(),
+            connect: Arc::new(ConnectHub::new()),
+            rooms: Arc::new(RoomHub::new()),
+            lyrics_provider: None,
+            hls: Arc::new(HlsPackager::new("ffmpeg", vec![128], 6)),
+            downloads: Arc::new(DownloadHub::new("ffmpeg")),
+            oidc: Some(Arc::new(oidc)),
+            require_admin_two_factor: false,
+            webauthn: Arc::new(WebAuthn::new(WebAuthnConfig {
+                rp_id: "localhost".to_string(),
+                rp_name: "Muse".to_string(),
+                origin: "http://localhost:5173".to_string(),
+            })),
+            rate_limiter: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_two_factor_user_gets_partial_token() {
+        let (issuer, provider) = mock_provider().await;
+        let path = std::env::temp_dir().join(format!("muse-oidc-{}.db", uuid::Uuid::new_v4()));
+        let state = app_state(&path, client(&issuer)).await;
+
+        let user = state.db.create_user("ada", "ada@example.com", "unused").await.unwrap();
+        state.db.link_identity(&issuer, "user-1", &user.id).await.unwrap();
+        state.db.save_two_factor(&user.id, "JBSWY3DPEHPK3PXP", true).await.unwrap();
+
+        let oidc = state.oidc.clone().unwrap();
+        let login_state = start_login(&oidc, &provider, None).await;
+        let Json(response) = callback(
+            State(state.clone()),
+            ClientAddress(None),
+            Json(CallbackRequest { code: "mock-code".to_string(), state: login_state }),
+        ).await.unwrap();
+
+        let Some(LoginResponse::TwoFactor(challenge)) = response.data else {
+            panic!("expected a two-factor challenge");
+        };
+        assert!(!challenge.enrollment_required);
+        let claims = state.jwt_service.verify_two_factor_token(&challenge.two_factor_token).unwrap();
+        assert_eq!(claims.sub, user.id);
+        assert!(state.jwt_service.verify_token(&challenge.two_factor_token).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
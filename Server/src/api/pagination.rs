@@ -0,0 +1,69 @@
+use serde::Deserialize;
+
+use crate::api::response::ApiError;
+use crate::db::paging::{Cursor, PageRequest, SortField, SortOrder};
+
+/// Page size limits applied to every listing endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct PageLimits {
+    pub default_size: usize,
+    pub max_size: usize,
+}
+
+/// Query parameters shared by all listing endpoints
+/// e.g. `?limit=50&sort=title&order=asc&cursor=<next_cursor>`
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    pub sort: Option<String>,
+    pub order: Option<String>,
+}
+
+impl PageQuery {
+    /// Validate the query against the sort fields a listing supports.
+    /// When only a cursor is given, its sort field and order are reused.
+    pub fn to_request(
+        &self,
+        limits: &PageLimits,
+        allowed: &[SortField],
+        default_sort: SortField,
+    ) -> Result<PageRequest, ApiError> {
+        let cursor = match &self.cursor {
+            Some(token) => Some(Cursor::decode(token)
+                .ok_or_else(|| ApiError::bad_request("Invalid cursor"))?),
+            None => None,
+        };
+
+        let sort = match &self.sort {
+            Some(sort) => SortField::from_string(sort)
+                .filter(|field| allowed.contains(field))
+                .ok_or_else(|| ApiError::bad_request(format!("Unsupported sort field: {}", sort)))?,
+            None => cursor.as_ref().map(|c| c.sort).unwrap_or(default_sort),
+        };
+
+        let order = match &self.order {
+            Some(order) => SortOrder::from_string(order)
+                .ok_or_else(|| ApiError::bad_request(format!("Unsupported sort order: {}", order)))?,
+            None => cursor.as_ref()
+                .filter(|c| c.sort == sort)
+                .map(|c| c.order)
+                .unwrap_or_else(|| sort.default_order()),
+        };
+
+        let limit = match self.limit {
+            Some(0) => return Err(ApiError::bad_request("limit must be greater than 0")),
+            Some(limit) => limit.min(limits.max_size),
+            None => limits.default_size,
+        };
+
+        let request = PageRequest::new(sort, order, limit);
+        match cursor {
+            Some(cursor) if cursor.sort != sort || cursor.order != order => {
+                Err(ApiError::bad_request("Cursor does not match the requested sort order"))
+            }
+            Some(cursor) => Ok(request.next(cursor)),
+            None => Ok(request),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use axum::{extract::{Json, State}, http::StatusCode, response::{IntoResponse, Response}};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::api::audit::{self, ClientAddress};
+use crate::api::auth::{session_response, start_session, AppState, LoginResponse};
+use crate::api::response::{ApiError, ApiResponse, ApiResult, ApiResultNoData};
+use crate::api::two_factor;
+use crate::auth::Authorized;
+use crate::auth::webauthn::{LoginCredential, RegistrationCredential, WebAuthnError};
+use crate::db::DbError;
+use crate::db::models::{AuditAction, Passkey};
+
+/// Longest name a passkey can have
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+impl From<Passkey> for PasskeyInfo {
+    fn from(passkey: Passkey) -> Self {
+        Self {
+            id: passkey.id,
+            name: passkey.name,
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginStartRequest {
+    /// Limits the login to this user's passkeys, for authenticators that
+    /// can't offer them on their own
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginFinishRequest {
+    pub credential: LoginCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterFinishRequest {
+    pub name: String,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeletePasskeyRequest {
+    pub id: String,
+}
+
+fn webauthn_error(e: WebAuthnError) -> ApiError {
+    match e {
+        WebAuthnError::UnknownChallenge => ApiError::bad_request(e.to_string()),
+        WebAuthnError::TooManyCeremonies => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+        WebAuthnError::UnsupportedKey(_) | WebAuthnError::InvalidClientData(_) | WebAuthnError::InvalidAuthenticatorData(_) => {
+            ApiError::bad_request(e.to_string())
+        }
+        WebAuthnError::InvalidSignature | WebAuthnError::CounterRegression => ApiError::unauthorized(e.to_string()),
+    }
+}
+
+/// POST /api/passkeys/login/start
+/// Start a passkey login, returning the options for `navigator.credentials.get()`
+pub async fn login_start(
+    State(state): State<AppState>,
+    Json(payload): Json<LoginStartRequest>,
+) -> ApiResult<Value> {
+    // Unknown users get the same options as users without passkeys, so this
+    // doesn't tell who has an account
+    let allow = match payload.username {
+        Some(username) => match state.db.get_user_by_username(&username).await {
+            Ok(user) => state.db.get_user_passkeys(&user.id).await
+                .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))?
+                .into_iter()
+                .map(|passkey| passkey.id)
+                .collect(),
+            Err(DbError::UserNotFound) => Vec::new(),
+            Err(e) => return Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+        },
+        None => Vec::new(),
+    };
+
+    let options = state.webauthn.start_login(&allow).map_err(webauthn_error)?;
+
+    Ok(Json(ApiResponse::success("Sign the challenge with a passkey", options)))
+}
+
+/// POST /api/passkeys/login/finish
+/// Log in with a signed passkey challenge. Passkeys that didn't verify the
+/// user with a PIN or biometric still need the account's two-factor code.
+pub async fn login_finish(
+    State(state): State<AppState>,
+    address: ClientAddress,
+    Json(payload): Json<LoginFinishRequest>,
+) -> Result<Response, ApiError> {
+    let credential = payload.credential;
+    let passkey = state.db.get_passkey(credential.id.trim_end_matches('=')).await
+        .map_err(|e| match e {
+            DbError::PasskeyNotFound => ApiError::unauthorized("Unknown passkey"),
+            e => ApiError::internal_server_error(format!("Database error: {}", e)),
+        })?;
+    if credential.response.user_handle.as_deref()
+        .is_some_and(|handle| handle.trim_end_matches('=') != URL_SAFE_NO_PAD.encode(&passkey.user_id)) {
+        return Err(ApiError::unauthorized("Unknown passkey"));
+    }
+
+    let public_key = URL_SAFE_NO_PAD.decode(&passkey.public_key)
+        .map_err(|e| ApiError::internal_server_error(format!("Stored passkey is corrupt: {}", e)))?;
+    let verified = state.webauthn.finish_login(&credential, &public_key, passkey.sign_count as u32)
+        .map_err(|e| {
+            tracing::warn!("Passkey login for user {} failed: {}", passkey.user_id, e);
+            webauthn_error(e)
+        })?;
+    state.db.use_passkey(&passkey.id, verified.sign_count as i64).await
+        .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))?;
+
+    let user = state.db.get_user_by_id(&passkey.user_id).await
+        .map_err(|_| ApiError::unauthorized("Unknown passkey"))?;
+
+    if !verified.user_verified && let Some(challenge) = two_factor::login_challenge(&state, &user).await? {
+        return Ok(Json(ApiResponse::success("Enter your two-factor code", LoginResponse::TwoFactor(challenge))).into_response());
+    }
+
+    let session = start_session(&state, &user).await?;
+    audit::record(&state.db, audit::login_event(&user, &address, "passkey")).await;
+
+    Ok(session_response(LoginResponse::Session(session)))
+}
+
+/// GET /api/user/passkeys
+/// Get the caller's passkeys
+pub async fn get_passkeys(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> ApiResult<Vec<PasskeyInfo>> {
+    let passkeys = state.db.get_user_passkeys(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get passkeys: {}", e)))?;
+
+    Ok(Json(ApiResponse::success(
+        "Passkeys retrieved successfully",
+        passkeys.into_iter().map(PasskeyInfo::from).collect(),
+    )))
+}
+
+/// POST /api/user/passkeys/register/start
+/// Start registering a passkey, returning the options for
+/// `navigator.credentials.create()`
+pub async fn register_start(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> ApiResult<Value> {
+    let existing: Vec<String> = state.db.get_user_passkeys(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get passkeys: {}", e)))?
+        .into_iter()
+        .map(|passkey| passkey.id)
+        .collect();
+
+    let options = state.webauthn.start_registration(&claims.sub, &claims.username, &existing)
+        .map_err(webauthn_error)?;
+
+    Ok(Json(ApiResponse::success("Create a passkey with these options", options)))
+}
+
+/// POST /api/user/passkeys/register/finish
+/// Save the passkey the browser created
+pub async fn register_finish(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+    Json(payload): Json<RegisterFinishRequest>,
+) -> ApiResult<PasskeyInfo> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::bad_request(format!("Passkey names must be 1 to {} characters", MAX_NAME_LENGTH)));
+    }
+
+    let credential = state.webauthn.finish_registration(&claims.sub, &payload.credential)
+        .map_err(webauthn_error)?;
+
+    match state.db.get_passkey(&credential.id).await {
+        Ok(_) => return Err(ApiError::new(StatusCode::CONFLICT, "That passkey is already registered")),
+        Err(DbError::PasskeyNotFound) => {}
+        Err(e) => return Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+    }
+
+    let passkey = Passkey {
+        id: credential.id,
+        user_id: claims.sub.clone(),
+        name: name.to_string(),
+        public_key: URL_SAFE_NO_PAD.encode(&credential.public_key),
+        algorithm: credential.algorithm,
+        sign_count: credential.sign_count as i64,
+        created_at: OffsetDateTime::now_utc(),
+        last_used_at: None,
+    };
+    state.db.create_passkey(&passkey).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save passkey: {}", e)))?;
+    let event = audit::event(&claims, &address, AuditAction::PasskeyRegister)
+        .with_target(&claims.username)
+        .with_change(None, Some(serde_json::json!({ "id": passkey.id, "name": passkey.name })));
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::success("Passkey registered", passkey.into())))
+}
+
+/// DELETE /api/user/passkeys
+/// Delete one of the caller's passkeys
+pub async fn delete_passkey(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+    Json(payload): Json<DeletePasskeyRequest>,
+) -> ApiResultNoData {
+    state.db.delete_passkey(&claims.sub, &payload.id).await
+        .map_err(|e| match e {
+            DbError::PasskeyNotFound => ApiError::not_found("Passkey not found"),
+            e => ApiError::internal_server_error(format!("Failed to delete passkey: {}", e)),
+        })?;
+    let event = audit::event(&claims, &address, AuditAction::PasskeyDelete)
+        .with_target(&claims.username)
+        .with_change(Some(serde_json::json!({ "id": payload.id })), None);
+    audit::record(&state.db, event).await;
+
+    Ok(Json(ApiResponse::no_data("Passkey deleted")))
+}
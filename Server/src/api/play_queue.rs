@@ -0,0 +1,147 @@
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use time::OffsetDateTime;
+use crate::api::response::{ApiResponse, ApiError};
+use crate::api::auth::AppState;
+use crate::api::libraries::library_filter;
+use crate::api::playlists::{if_match_version, with_etag};
+use crate::auth::{scope, Authorized};
+use crate::db::DbError;
+use crate::db::models::{LibraryFilter, PlayQueue};
+
+#[derive(Debug, Deserialize)]
+pub struct SaveQueueRequest {
+    pub song_ids: Option<Vec<String>>,
+    pub current_index: Option<usize>,
+    pub position_ms: Option<i64>,
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueSong {
+    pub id: String,
+    pub name: String,
+    pub artist_name: String,
+    pub album: Option<String>,
+    pub duration: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueInfo {
+    pub songs: Vec<QueueSong>,
+    pub current_index: usize,
+    pub position_ms: i64,
+    pub device: Option<String>,
+    pub version: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+/// GET /api/user/queue
+/// Restore the caller's play queue. The ETag carries its version for If-Match.
+pub async fn get_queue(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+) -> Result<Response, ApiError> {
+    let queue = state.db.get_play_queue(&claims.sub).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch play queue: {}", e)))?;
+
+    let libraries = library_filter(&state, &claims).await?;
+    let version = queue.version;
+    Ok(with_etag(version, ApiResponse::success("play queue", queue_info(&state, queue, &libraries).await)))
+}
+
+/// PUT /api/user/queue
+/// Save the caller's play queue. Fields left out keep their value, so a
+/// client can report just its position. Send the restored version in
+/// If-Match to fail with 412 rather than overwrite another device's save.
+pub async fn save_queue(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    headers: HeaderMap,
+    Json(payload): Json<SaveQueueRequest>,
+) -> Result<Response, ApiError> {
+    let expected_version = if_match_version(&headers)?;
+    let libraries = library_filter(&state, &claims).await?;
+    let mut queue = state.db.get_play_queue(&claims.sub).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch play queue: {}", e)))?;
+
+    if let Some(song_ids) = payload.song_ids {
+        check_songs(&state, &song_ids, &libraries).await?;
+        queue.song_ids = song_ids;
+        // A new list starts from its first song unless told otherwise
+        queue.current_index = 0;
+        queue.position_ms = 0;
+    }
+    if let Some(current_index) = payload.current_index {
+        queue.current_index = current_index;
+    }
+    if let Some(position_ms) = payload.position_ms {
+        queue.position_ms = position_ms;
+    }
+    if payload.device.is_some() {
+        queue.device = payload.device;
+    }
+
+    if queue.current_index >= queue.song_ids.len().max(1) {
+        return Err(ApiError::bad_request(format!("current_index {} is past the end of the queue", queue.current_index)));
+    }
+    if queue.position_ms < 0 {
+        return Err(ApiError::bad_request("position_ms can't be negative"));
+    }
+
+    let queue = state.db.save_play_queue(&queue, expected_version).await
+        .map_err(|e| match e {
+            DbError::VersionConflict => ApiError::new(StatusCode::PRECONDITION_FAILED, "The queue was saved by another device since"),
+            e => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save play queue: {}", e)),
+        })?;
+
+    let version = queue.version;
+    Ok(with_etag(version, ApiResponse::success("Play queue saved", queue_info(&state, queue, &libraries).await)))
+}
+
+async fn check_songs(state: &AppState, song_ids: &[String], libraries: &LibraryFilter) -> Result<(), ApiError> {
+    let unique: HashSet<&String> = song_ids.iter().collect();
+    for song_id in unique {
+        match state.db.get_song_by_id(song_id).await {
+            Ok(song) if libraries.allows(&song.library_id) => {}
+            _ => return Err(ApiError::bad_request(format!("Unknown song id: {}", song_id))),
+        }
+    }
+    Ok(())
+}
+
+/// Songs deleted since the queue was saved, or no longer visible, are
+/// dropped, keeping the index on the same song
+async fn queue_info(state: &AppState, queue: PlayQueue, libraries: &LibraryFilter) -> QueueInfo {
+    let mut songs = Vec::with_capacity(queue.song_ids.len());
+    let mut current_index = queue.current_index;
+
+    for (index, song_id) in queue.song_ids.iter().enumerate() {
+        match state.db.get_song_by_id(song_id).await {
+            Ok(song) if libraries.allows(&song.library_id) => songs.push(QueueSong {
+                id: song.id,
+                name: song.title,
+                artist_name: song.artist_name,
+                album: song.album,
+                duration: song.duration,
+            }),
+            _ if index < queue.current_index => current_index -= 1,
+            _ => {}
+        }
+    }
+
+    QueueInfo {
+        current_index: current_index.min(songs.len().saturating_sub(1)),
+        songs,
+        position_ms: queue.position_ms,
+        device: queue.device,
+        version: queue.version,
+        updated_at: queue.updated_at,
+    }
+}
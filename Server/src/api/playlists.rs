@@ -1,21 +1,35 @@
 use axum::{
-    extract::{Json, Query, State, Extension},
-    http::StatusCode,
+    extract::{Json, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use crate::api::libraries::library_filter;
+use crate::api::pagination::PageQuery;
 use crate::api::response::{ApiResponse, ApiError};
 use crate::api::auth::AppState;
-use crate::auth::Claims;
+use crate::auth::{scope, Authorized, Claims, Permission};
+use crate::db::DbError;
+use crate::db::models::{LibraryFilter, Playlist, PlaylistAction, PlaylistActivity, PlaylistEntry, SharePermission, Song};
+use crate::db::paging::SortField;
+use crate::db::playlist_edit::PlaylistEdit;
+use crate::db::smart_rules::SmartRules;
+use crate::music::matching::{LibraryMatcher, MatchMethod};
+use crate::music::playlist_file::{PlaylistFile, PlaylistFormat, PlaylistTrack};
 
 #[derive(Debug, Deserialize)]
-pub struct PaginationQuery {
-    pub index_start: usize,
-    pub index_end: usize,
+pub struct PlaylistNameQuery {
+    pub name: String,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct PlaylistNameQuery {
+pub struct PlaylistOwnerQuery {
     pub name: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,81 +37,246 @@ pub struct CreatePlaylistRequest {
     pub name: String,
     #[serde(rename = "isPublic")]
     pub is_public: bool,
+    /// Rule query, e.g. `genre = Jazz AND year < 1970 ORDER BY random LIMIT 50`.
+    /// Creates a smart playlist whose songs are evaluated on read.
+    pub rules: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePlaylistRulesRequest {
+    pub playlist: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub rules: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AddSongToPlaylistRequest {
     pub playlist: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub song: String,
+    pub artist: String,
+    /// Position to insert at, appends when omitted
+    pub position: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SongRef {
     pub song: String,
     pub artist: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct AddSongsToPlaylistRequest {
+    pub playlist: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub songs: Vec<SongRef>,
+    pub position: Option<usize>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RemoveSongFromPlaylistRequest {
     pub playlist: String,
-    pub song: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    /// Removes the first entry with this title when no position is given
+    pub song: Option<String>,
+    pub position: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveSongsFromPlaylistRequest {
+    pub playlist: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub positions: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveSongRequest {
+    pub playlist: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub from: usize,
+    pub to: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportPlaylistQuery {
+    pub name: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    #[serde(default = "default_export_format")]
+    pub format: String,
+    /// `url` for stream URLs or `relative` for paths relative to the music directory
+    #[serde(default = "default_export_paths")]
+    pub paths: String,
+}
+
+fn default_export_format() -> String {
+    "m3u8".to_string()
+}
+
+fn default_export_paths() -> String {
+    "url".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportPlaylistQuery {
+    /// Name of the new playlist, defaults to the title in the file
+    pub name: Option<String>,
+    /// Detected from the file when omitted
+    pub format: Option<String>,
+    #[serde(rename = "isPublic", default)]
+    pub is_public: bool,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SharePlaylistRequest {
     pub playlist_name: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
     pub target_user: String,
+    /// `view` (default), `add`, `edit` or `admin`; ignored when revoking
+    pub permission: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlaylistActivityQuery {
+    pub name: String,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    /// Most recent entries to return, 50 by default
+    pub limit: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct PlaylistBasic {
     pub name: String,
     pub is_public: bool,
+    pub is_smart: bool,
     pub owner: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct PlaylistEntryInfo {
+    pub position: i64,
+    pub name: String,
+    pub artist_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistContents {
+    pub name: String,
+    pub version: i64,
+    /// Rule query of a smart playlist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rules: Option<String>,
+    pub songs: Vec<PlaylistEntryInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistVersion {
+    pub version: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchedEntry {
+    /// Index of the entry in the imported file
+    pub index: usize,
+    pub name: String,
+    pub artist_name: String,
+    pub method: MatchMethod,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnmatchedEntry {
+    pub index: usize,
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportResult {
+    pub name: String,
+    pub version: i64,
+    pub matched: Vec<MatchedEntry>,
+    pub unmatched: Vec<UnmatchedEntry>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct SharedPlaylistInfo {
     pub name: String,
     pub owner: String,
+    pub is_smart: bool,
+    pub shared_by: String,
+    pub permission: SharePermission,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistShareInfo {
+    pub username: String,
+    pub permission: SharePermission,
     pub shared_by: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub shared_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistActivityInfo {
+    pub username: String,
+    pub action: PlaylistAction,
+    pub song: Option<String>,
+    pub artist_name: Option<String>,
+    pub position: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
 }
 
 pub async fn get_private_playlists(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
-    Query(params): Query<PaginationQuery>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<PageQuery>,
 ) -> Result<Json<ApiResponse<Vec<PlaylistBasic>>>, ApiError> {
-    let playlists = state.db.get_user_playlists(&claims.sub, params.index_start, params.index_end - params.index_start).await
+    let request = params.to_request(&state.page_limits, &[SortField::Name, SortField::Added], SortField::Added)?;
+    let playlists = state.db.get_user_playlists(&claims.sub, &request).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch playlists: {}", e)))?;
     
-    let playlist_basics: Vec<PlaylistBasic> = playlists.into_iter()
-        .map(|p| PlaylistBasic {
-            name: p.name,
-            is_public: p.is_public,
-            owner: p.owner_username,
-        })
-        .collect();
+    let playlist_basics = playlists.map(|p| PlaylistBasic {
+        name: p.name,
+        is_public: p.is_public,
+        is_smart: p.is_smart,
+        owner: p.owner_username,
+    });
     
-    Ok(Json(ApiResponse::success("private playlists", playlist_basics)))
+    Ok(Json(ApiResponse::page("private playlists", playlist_basics)))
 }
 
 pub async fn get_public_playlists(
     State(state): State<AppState>,
-    Query(params): Query<PaginationQuery>,
+    _: Authorized<scope::Listen>,
+    Query(params): Query<PageQuery>,
 ) -> Result<Json<ApiResponse<Vec<PlaylistBasic>>>, ApiError> {
-    let playlists = state.db.get_public_playlists(params.index_start, params.index_end - params.index_start).await
+    let request = params.to_request(&state.page_limits, &[SortField::Name, SortField::Added], SortField::Added)?;
+    let playlists = state.db.get_public_playlists(&request).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch playlists: {}", e)))?;
     
-    let playlist_basics: Vec<PlaylistBasic> = playlists.into_iter()
-        .map(|p| PlaylistBasic {
-            name: p.name,
-            is_public: p.is_public,
-            owner: p.owner_username,
-        })
-        .collect();
+    let playlist_basics = playlists.map(|p| PlaylistBasic {
+        name: p.name,
+        is_public: p.is_public,
+        is_smart: p.is_smart,
+        owner: p.owner_username,
+    });
     
-    Ok(Json(ApiResponse::success("public playlists", playlist_basics)))
+    Ok(Json(ApiResponse::page("public playlists", playlist_basics)))
 }
 
 pub async fn get_shared_playlists(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::Listen>,
 ) -> Result<Json<ApiResponse<Vec<SharedPlaylistInfo>>>, ApiError> {
     let shared_playlists = state.db.get_shared_playlists(&claims.sub).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch shared playlists: {}", e)))?;
@@ -110,7 +289,9 @@ pub async fn get_shared_playlists(
         shared_info.push(SharedPlaylistInfo {
             name: playlist.name,
             owner: playlist.owner_username,
+            is_smart: playlist.is_smart,
             shared_by: shared_by_user.username,
+            permission: share.permission,
         });
     }
     
@@ -119,67 +300,481 @@ pub async fn get_shared_playlists(
 
 pub async fn create_playlist(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
     Json(payload): Json<CreatePlaylistRequest>,
 ) -> Result<Json<ApiResponse<()>>, ApiError> {
-    state.db.create_playlist(&payload.name, &claims.sub, payload.is_public).await
+    let rules = payload.rules.as_deref().map(parse_rules).transpose()?;
+    
+    state.db.create_playlist(&payload.name, &claims.sub, payload.is_public, rules.as_ref()).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to create playlist: {}", e)))?;
     
     Ok(Json(ApiResponse::no_data("Playlist created successfully")))
 }
 
+/// PUT /api/playlists/rules
+/// Replace the rule query of a smart playlist
+pub async fn update_playlist_rules(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    Json(payload): Json<UpdatePlaylistRulesRequest>,
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &payload.playlist, payload.owner.as_deref(), SharePermission::Edit).await?;
+    
+    if !playlist.is_smart {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Only smart playlists have rules"));
+    }
+    
+    let rules = parse_rules(&payload.rules)?;
+    let version = state.db.update_playlist_rules(&playlist.id, &rules).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update playlist rules: {}", e)))?;
+    
+    record_activity(&state, vec![
+        PlaylistActivity::new(&playlist.id, &claims.sub, &claims.username, PlaylistAction::RulesUpdated),
+    ]).await;
+    
+    Ok(with_etag(version, ApiResponse::success("Playlist rules updated", PlaylistVersion { version })))
+}
+
+fn parse_rules(rules: &str) -> Result<SmartRules, ApiError> {
+    SmartRules::parse(rules)
+        .map_err(|e| ApiError::bad_request(format!("Invalid playlist rules: {}", e)))
+}
+
+/// GET /api/playlists/songs?name=X&owner=Y
+/// Get a playlist's songs in order. The ETag carries the playlist version for If-Match.
+/// Smart playlists are evaluated against the library on every read.
+/// Songs in libraries the caller can't see are left out, keeping the positions of the rest.
+pub async fn get_playlist_songs(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<PlaylistOwnerQuery>,
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &params.name, params.owner.as_deref(), SharePermission::View).await?;
+    let libraries = library_filter(&state, &claims).await?;
+    let songs = positioned_songs(&state, &playlist, &libraries).await?;
+    
+    let contents = PlaylistContents {
+        name: playlist.name,
+        version: playlist.version,
+        rules: playlist.rules.map(|rules| rules.to_string()),
+        songs: songs.into_iter().map(|(position, song)| PlaylistEntryInfo {
+            position: position as i64,
+            name: song.title,
+            artist_name: song.artist_name,
+        }).collect(),
+    };
+    
+    Ok(with_etag(playlist.version, ApiResponse::success("playlist songs", contents)))
+}
+
+/// POST /api/playlists/song/add
+/// Insert a song at a position, or append it. A song may be added more than once.
 pub async fn add_song_to_playlist(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    headers: HeaderMap,
     Json(payload): Json<AddSongToPlaylistRequest>,
-) -> Result<Json<ApiResponse<()>>, ApiError> {
-    // Get playlist by name and owner
-    let playlist = state.db.get_playlist_by_name_and_owner(&payload.playlist, &claims.sub).await
-        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Playlist not found: {}", e)))?;
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &payload.playlist, payload.owner.as_deref(), SharePermission::Add).await?;
     
-    // Get song by title and artist
-    let artist = state.db.get_artist_by_name(&payload.artist).await
-        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Artist not found: {}", e)))?;
+    let libraries = library_filter(&state, &claims).await?;
+    let song = find_song(&state, &payload.artist, &payload.song, &libraries).await?;
     
-    let songs = state.db.get_songs_by_artist(&artist.id).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch songs: {}", e)))?;
+    let edit = PlaylistEdit::Insert { song_ids: vec![song.id], position: payload.position };
+    apply_edit(&state, &claims, &playlist, &edit, if_match_version(&headers)?, "Song added to playlist").await
+}
+
+/// POST /api/playlists/songs/add
+/// Insert several songs in one request, keeping their order
+pub async fn add_songs_to_playlist(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    headers: HeaderMap,
+    Json(payload): Json<AddSongsToPlaylistRequest>,
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &payload.playlist, payload.owner.as_deref(), SharePermission::Add).await?;
     
-    let song = songs.iter().find(|s| s.title == payload.song)
-        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Song not found"))?;
+    let libraries = library_filter(&state, &claims).await?;
+    let mut song_ids = Vec::with_capacity(payload.songs.len());
+    for song_ref in &payload.songs {
+        song_ids.push(find_song(&state, &song_ref.artist, &song_ref.song, &libraries).await?.id);
+    }
     
-    // Add song to playlist
-    state.db.add_song_to_playlist(&playlist.id, &song.id).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to add song to playlist: {}", e)))?;
+    let edit = PlaylistEdit::Insert { song_ids, position: payload.position };
+    apply_edit(&state, &claims, &playlist, &edit, if_match_version(&headers)?, "Songs added to playlist").await
+}
+
+/// POST /api/playlists/song/move
+/// Move the entry at `from` to `to`
+pub async fn move_song_in_playlist(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    headers: HeaderMap,
+    Json(payload): Json<MoveSongRequest>,
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &payload.playlist, payload.owner.as_deref(), SharePermission::Edit).await?;
     
-    Ok(Json(ApiResponse::no_data("Song added to playlist")))
+    let edit = PlaylistEdit::Move { from: payload.from, to: payload.to };
+    apply_edit(&state, &claims, &playlist, &edit, if_match_version(&headers)?, "Song moved").await
 }
 
+/// POST /api/playlists/song/remove
+/// Remove the entry at a position, or the first entry with a matching title
 pub async fn remove_song_from_playlist(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    headers: HeaderMap,
     Json(payload): Json<RemoveSongFromPlaylistRequest>,
-) -> Result<Json<ApiResponse<()>>, ApiError> {
-    // Get playlist by name and owner
-    let playlist = state.db.get_playlist_by_name_and_owner(&payload.playlist, &claims.sub).await
-        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Playlist not found: {}", e)))?;
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &payload.playlist, payload.owner.as_deref(), SharePermission::Edit).await?;
+    ensure_editable(&playlist)?;
+    
+    let expected_version = if_match_version(&headers)?;
+    let (position, expected_version) = match (payload.position, &payload.song) {
+        (Some(position), _) => (position, expected_version),
+        (None, Some(title)) => {
+            let entries = playlist_entries(&state, &playlist).await?;
+            
+            let position = entries.iter()
+                .find(|entry| &entry.song.title == title)
+                .map(|entry| entry.position as usize)
+                .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Song not found in playlist"))?;
+            // The position is only that song's while the playlist is as it was read
+            (position, expected_version.or(Some(playlist.version)))
+        }
+        (None, None) => return Err(ApiError::bad_request("Either song or position is required")),
+    };
+    
+    let edit = PlaylistEdit::Remove { positions: vec![position] };
+    apply_edit(&state, &claims, &playlist, &edit, expected_version, "Song removed from playlist").await
+}
+
+/// POST /api/playlists/songs/remove
+/// Remove the entries at several positions in one request
+pub async fn remove_songs_from_playlist(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    headers: HeaderMap,
+    Json(payload): Json<RemoveSongsFromPlaylistRequest>,
+) -> Result<Response, ApiError> {
+    let playlist = find_playlist(&state, &claims, &payload.playlist, payload.owner.as_deref(), SharePermission::Edit).await?;
+    
+    let edit = PlaylistEdit::Remove { positions: payload.positions };
+    apply_edit(&state, &claims, &playlist, &edit, if_match_version(&headers)?, "Songs removed from playlist").await
+}
+
+/// Look up a song in `libraries` by artist name and title
+pub(crate) async fn find_song(state: &AppState, artist: &str, title: &str, libraries: &LibraryFilter) -> Result<Song, ApiError> {
+    let artist = state.db.get_artist_by_name(artist).await
+        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Artist not found: {}", e)))?;
+    
+    let songs = state.db.get_songs_by_artist(&artist.id, libraries).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch songs: {}", e)))?;
+    
+    songs.into_iter().find(|s| s.title == title)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("Song not found: {}", title)))
+}
+
+/// Songs of a playlist in order that are in `libraries`, evaluating the rules of a smart playlist
+pub(crate) async fn playlist_songs(state: &AppState, playlist: &Playlist, libraries: &LibraryFilter) -> Result<Vec<Song>, ApiError> {
+    let songs = positioned_songs(state, playlist, libraries).await?;
+    Ok(songs.into_iter().map(|(_, song)| song).collect())
+}
+
+/// Playlist songs in `libraries` with their positions, which the songs left out still take up
+async fn positioned_songs(state: &AppState, playlist: &Playlist, libraries: &LibraryFilter) -> Result<Vec<(usize, Song)>, ApiError> {
+    let songs = match &playlist.rules {
+        Some(rules) => state.db.get_smart_playlist_songs(rules, libraries).await,
+        None => state.db.get_playlist_songs(&playlist.id).await,
+    };
+    
+    let songs = songs.map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch playlist songs: {}", e)))?;
+    Ok(songs.into_iter()
+        .enumerate()
+        .filter(|(_, song)| libraries.allows(&song.library_id))
+        .collect())
+}
+
+async fn playlist_entries(state: &AppState, playlist: &Playlist) -> Result<Vec<PlaylistEntry>, ApiError> {
+    state.db.get_playlist_entries(&playlist.id).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch playlist songs: {}", e)))
+}
+
+/// Append to the activity log. The change itself has already been made,
+/// so a failure here is logged rather than failing the request.
+async fn record_activity(state: &AppState, activity: Vec<PlaylistActivity>) {
+    if let Err(e) = state.db.record_playlist_activity(&activity).await {
+        tracing::warn!("Failed to record playlist activity: {}", e);
+    }
+}
+
+/// Smart playlist entries come from their rules and can't be edited directly
+fn ensure_editable(playlist: &Playlist) -> Result<(), ApiError> {
+    if playlist.is_smart {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Smart playlists are edited through their rules"));
+    }
+    Ok(())
+}
+
+/// Apply an edit, honouring the version in an If-Match header,
+/// and respond with the new version
+async fn apply_edit(
+    state: &AppState,
+    claims: &Claims,
+    playlist: &Playlist,
+    edit: &PlaylistEdit,
+    expected_version: Option<i64>,
+    message: &str,
+) -> Result<Response, ApiError> {
+    ensure_editable(playlist)?;
+    
+    let (version, edited) = state.db.edit_playlist_entries(&playlist.id, edit, expected_version).await
+        .map_err(|e| match e {
+            DbError::VersionConflict => ApiError::new(StatusCode::PRECONDITION_FAILED, e.to_string()),
+            DbError::InvalidPosition(_) => ApiError::bad_request(e.to_string()),
+            e => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update playlist: {}", e)),
+        })?;
+    
+    let action = match edit {
+        PlaylistEdit::Insert { .. } => PlaylistAction::Added,
+        PlaylistEdit::Move { .. } => PlaylistAction::Moved,
+        PlaylistEdit::Remove { .. } => PlaylistAction::Removed,
+    };
+    let mut activity = Vec::new();
+    for entry in edited {
+        // A song deleted since can't be named in the log
+        let Ok(song) = state.db.get_song_by_id(&entry.song_id).await else {
+            continue;
+        };
+        activity.push(PlaylistActivity::new(&playlist.id, &claims.sub, &claims.username, action)
+            .with_song(&song, entry.position as i64));
+    }
+    record_activity(state, activity).await;
+    
+    Ok(with_etag(version, ApiResponse::success(message, PlaylistVersion { version })))
+}
+
+/// Parse the version from an If-Match header (`"3"`, `W/"3"` or `*`)
+pub(crate) fn if_match_version(headers: &HeaderMap) -> Result<Option<i64>, ApiError> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    
+    let value = value.to_str().unwrap_or_default().trim();
+    if value == "*" {
+        return Ok(None);
+    }
     
-    // Find song by title (simplified - in production you'd want more specific song identification)
-    let songs = state.db.get_playlist_songs(&playlist.id).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch playlist songs: {}", e)))?;
+    value.trim_start_matches("W/")
+        .trim_matches('"')
+        .parse::<i64>()
+        .map(Some)
+        .map_err(|_| ApiError::new(StatusCode::PRECONDITION_FAILED, "If-Match does not match the current version"))
+}
+
+pub(crate) fn with_etag<T: Serialize>(version: i64, body: ApiResponse<T>) -> Response {
+    ([(header::ETAG, format!("\"{}\"", version))], Json(body)).into_response()
+}
+
+/// GET /api/playlists/export?name=X&owner=Y&format=m3u8|xspf|jspf&paths=url|relative
+/// Download a playlist the caller owns, or that is public or shared with them
+pub async fn export_playlist(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<ExportPlaylistQuery>,
+) -> Result<Response, ApiError> {
+    let format = PlaylistFormat::from_string(&params.format)
+        .ok_or_else(|| ApiError::bad_request(format!("Unsupported playlist format: {}", params.format)))?;
+    let relative_paths = match params.paths.as_str() {
+        "url" => false,
+        "relative" => true,
+        other => return Err(ApiError::bad_request(format!("Unsupported paths option: {}", other))),
+    };
     
-    let song = songs.iter().find(|s| s.title == payload.song)
-        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Song not found in playlist"))?;
+    let playlist = find_playlist(&state, &claims, &params.name, params.owner.as_deref(), SharePermission::View).await?;
+    let libraries = library_filter(&state, &claims).await?;
+    let songs = playlist_songs(&state, &playlist, &libraries).await?;
+    let roots: HashMap<String, PathBuf> = library_roots(&state).await?.into_iter().collect();
     
-    // Remove song from playlist
-    state.db.remove_song_from_playlist(&playlist.id, &song.id).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to remove song from playlist: {}", e)))?;
+    let file = PlaylistFile {
+        title: Some(playlist.name.clone()),
+        tracks: songs.into_iter().map(|song| PlaylistTrack {
+            location: Some(if relative_paths {
+                relative_song_path(&song, roots.get(&song.library_id).map(PathBuf::as_path))
+            } else {
+                stream_url(&state, &song)
+            }),
+            musicbrainz_id: song.musicbrainz_id,
+            title: Some(song.title),
+            artist: Some(song.artist_name),
+            album: song.album,
+            duration: song.duration,
+        }).collect(),
+    };
     
-    Ok(Json(ApiResponse::no_data("Song removed from playlist")))
+    let file_name = format!("{}.{}", playlist.name.replace(['"', '/', '\\'], "_"), format.extension());
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)),
+        ],
+        file.write(format),
+    ).into_response())
+}
+
+/// POST /api/playlists/import?name=X&format=m3u8|xspf|jspf
+/// Create a playlist from an uploaded playlist file. Entries are matched
+/// against the library by path, MusicBrainz ID or artist and title;
+/// entries that can't be matched are skipped and reported back.
+pub async fn import_playlist(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
+    Query(params): Query<ImportPlaylistQuery>,
+    body: String,
+) -> Result<Json<ApiResponse<ImportResult>>, ApiError> {
+    let format = match &params.format {
+        Some(format) => PlaylistFormat::from_string(format)
+            .ok_or_else(|| ApiError::bad_request(format!("Unsupported playlist format: {}", format)))?,
+        None => PlaylistFormat::detect(&body),
+    };
+    
+    let file = PlaylistFile::parse(format, &body)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    
+    let name = params.name.or(file.title)
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| ApiError::bad_request("A playlist name is required"))?;
+    
+    if state.db.get_playlist_by_name_and_owner(&name, &claims.sub).await.is_ok() {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Playlist already exists: {}", name)));
+    }
+    
+    let libraries = library_filter(&state, &claims).await?;
+    let roots = library_roots(&state).await?.into_iter()
+        .filter(|(id, _)| libraries.allows(id))
+        .map(|(_, root)| root)
+        .collect();
+    let matcher = LibraryMatcher::new(state.db.clone(), roots, libraries);
+    let mut song_ids = Vec::new();
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    
+    for (index, track) in file.tracks.into_iter().enumerate() {
+        match matcher.resolve(&track).await {
+            Some((song, method)) => {
+                song_ids.push(song.id);
+                matched.push(MatchedEntry {
+                    index,
+                    name: song.title,
+                    artist_name: song.artist_name,
+                    method,
+                });
+            }
+            None => unmatched.push(UnmatchedEntry {
+                index,
+                location: track.location,
+                title: track.title,
+                artist: track.artist,
+            }),
+        }
+    }
+    
+    let playlist = state.db.create_playlist(&name, &claims.sub, params.is_public, None).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create playlist: {}", e)))?;
+    
+    let version = if song_ids.is_empty() {
+        playlist.version
+    } else {
+        let edit = PlaylistEdit::Insert { song_ids, position: None };
+        state.db.edit_playlist_entries(&playlist.id, &edit, None).await
+            .map(|(version, _)| version)
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add songs to playlist: {}", e)))?
+    };
+    
+    let message = format!("Imported {} of {} entries", matched.len(), matched.len() + unmatched.len());
+    Ok(Json(ApiResponse::success(message, ImportResult {
+        name,
+        version,
+        matched,
+        unmatched,
+    })))
+}
+
+/// Find a playlist by name that the caller may use with `permission`: their own,
+/// one shared with them at that permission or above, or a public one to view.
+/// Admins can use any playlist.
+pub(crate) async fn find_playlist(
+    state: &AppState,
+    claims: &Claims,
+    name: &str,
+    owner: Option<&str>,
+    permission: SharePermission,
+) -> Result<Playlist, ApiError> {
+    let owner_id = match owner {
+        Some(owner) if owner != claims.username => {
+            state.db.get_user_by_username(owner).await
+                .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, format!("User not found: {}", e)))?
+                .id
+        }
+        _ => claims.sub.clone(),
+    };
+    
+    let playlist = state.db.get_playlist_by_name_and_owner(name, &owner_id).await
+        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Playlist not found: {}", e)))?;
+    
+    if playlist.owner_id == claims.sub || claims.can(Permission::CuratePlaylists) {
+        return Ok(playlist);
+    }
+    
+    let share = state.db.get_playlist_share(&playlist.id, &claims.sub).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to check playlist access: {}", e)))?;
+    
+    match share {
+        Some(share) if share.permission >= permission => Ok(playlist),
+        None if playlist.is_public && permission == SharePermission::View => Ok(playlist),
+        None if !playlist.is_public => Err(ApiError::not_found("Playlist not found")),
+        _ => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("This playlist needs {} permission", permission.as_str()),
+        )),
+    }
+}
+
+/// Stream URL for a song, with the format taken from its file extension
+pub(crate) fn stream_url(state: &AppState, song: &Song) -> String {
+    let mut url = format!(
+        "{}/api/stream?artist={}&name={}",
+        state.website_url,
+        urlencoding::encode(&song.artist_name),
+        urlencoding::encode(&song.title),
+    );
+    if let Some(extension) = std::path::Path::new(&song.file_path).extension().and_then(|ext| ext.to_str()) {
+        url.push_str(&format!("&format={}", extension.to_lowercase()));
+    }
+    url
+}
+
+/// Folder of every library by its ID
+async fn library_roots(state: &AppState) -> Result<Vec<(String, PathBuf)>, ApiError> {
+    let libraries = state.db.get_libraries().await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch libraries: {}", e)))?;
+    Ok(libraries.into_iter().map(|library| (library.id, PathBuf::from(library.root_path))).collect())
+}
+
+/// Song file path relative to its library's folder
+fn relative_song_path(song: &Song, root: Option<&Path>) -> String {
+    let path = Path::new(&song.file_path);
+    root.and_then(|root| path.strip_prefix(root).ok())
+        .or_else(|| path.file_name().map(Path::new))
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
 }
 
 pub async fn delete_playlist(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
     Query(params): Query<PlaylistNameQuery>,
 ) -> Result<Json<ApiResponse<()>>, ApiError> {
     // Get playlist to get its ID
@@ -192,34 +787,45 @@ pub async fn delete_playlist(
     Ok(Json(ApiResponse::no_data("Playlist deleted successfully")))
 }
 
+/// POST /api/playlists/share
+/// Share a playlist, or change the permission of an existing share.
+/// Needs admin permission on the playlist.
 pub async fn share_playlist(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
     Json(payload): Json<SharePlaylistRequest>,
 ) -> Result<Json<ApiResponse<()>>, ApiError> {
-    // Get playlist by name and owner
-    let playlist = state.db.get_playlist_by_name_and_owner(&payload.playlist_name, &claims.sub).await
-        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Playlist not found: {}", e)))?;
+    let permission = match &payload.permission {
+        Some(permission) => SharePermission::from_string(permission)
+            .ok_or_else(|| ApiError::bad_request(format!("Unknown share permission: {}", permission)))?,
+        None => SharePermission::View,
+    };
+    
+    let playlist = find_playlist(&state, &claims, &payload.playlist_name, payload.owner.as_deref(), SharePermission::Admin).await?;
     
     // Get target user
     let target_user = state.db.get_user_by_username(&payload.target_user).await
         .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("User not found: {}", e)))?;
     
+    if target_user.id == playlist.owner_id {
+        return Err(ApiError::bad_request("A playlist can't be shared with its owner"));
+    }
+    
     // Share playlist
-    state.db.share_playlist(&playlist.id, &target_user.id, &claims.sub).await
+    state.db.share_playlist(&playlist.id, &target_user.id, &claims.sub, permission).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to share playlist: {}", e)))?;
     
     Ok(Json(ApiResponse::no_data("Playlist shared successfully")))
 }
 
+/// DELETE /api/playlists/share
+/// Needs admin permission on the playlist
 pub async fn revoke_playlist_share(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized<scope::WritePlaylists>,
     Json(payload): Json<SharePlaylistRequest>,
 ) -> Result<Json<ApiResponse<()>>, ApiError> {
-    // Get playlist by name and owner
-    let playlist = state.db.get_playlist_by_name_and_owner(&payload.playlist_name, &claims.sub).await
-        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, &format!("Playlist not found: {}", e)))?;
+    let playlist = find_playlist(&state, &claims, &payload.playlist_name, payload.owner.as_deref(), SharePermission::Admin).await?;
     
     // Get target user
     let target_user = state.db.get_user_by_username(&payload.target_user).await
@@ -231,3 +837,58 @@ pub async fn revoke_playlist_share(
     
     Ok(Json(ApiResponse::no_data("Playlist share revoked")))
 }
+
+/// GET /api/playlists/shares?name=X&owner=Y
+/// List who a playlist is shared with. Needs admin permission on the playlist.
+pub async fn get_playlist_shares(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<PlaylistOwnerQuery>,
+) -> Result<Json<ApiResponse<Vec<PlaylistShareInfo>>>, ApiError> {
+    let playlist = find_playlist(&state, &claims, &params.name, params.owner.as_deref(), SharePermission::Admin).await?;
+    
+    let shares = state.db.get_playlist_shares(&playlist.id).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch playlist shares: {}", e)))?;
+    
+    let mut share_info = Vec::with_capacity(shares.len());
+    for share in shares {
+        let user = state.db.get_user_by_id(&share.shared_with_user_id).await
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch user: {}", e)))?;
+        let shared_by = state.db.get_user_by_id(&share.shared_by_user_id).await
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch user: {}", e)))?;
+        
+        share_info.push(PlaylistShareInfo {
+            username: user.username,
+            permission: share.permission,
+            shared_by: shared_by.username,
+            shared_at: share.shared_at,
+        });
+    }
+    
+    Ok(Json(ApiResponse::success("playlist shares", share_info)))
+}
+
+/// GET /api/playlists/activity?name=X&owner=Y&limit=50
+/// Who added, removed or moved what, newest first
+pub async fn get_playlist_activity(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<PlaylistActivityQuery>,
+) -> Result<Json<ApiResponse<Vec<PlaylistActivityInfo>>>, ApiError> {
+    let limit = params.limit.unwrap_or(50).clamp(1, 500);
+    let playlist = find_playlist(&state, &claims, &params.name, params.owner.as_deref(), SharePermission::View).await?;
+    
+    let activity = state.db.get_playlist_activity(&playlist.id, limit).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch playlist activity: {}", e)))?;
+    
+    let activity_info = activity.into_iter().map(|item| PlaylistActivityInfo {
+        username: item.username,
+        action: item.action,
+        song: item.song_title,
+        artist_name: item.artist_name,
+        position: item.position,
+        at: item.created_at,
+    }).collect();
+    
+    Ok(Json(ApiResponse::success("playlist activity", activity_info)))
+}
@@ -0,0 +1,119 @@
+//! HTTP `Range` header parsing (RFC 9110 §14)
+
+/// More ranges than this in one request are ignored and the whole body is
+/// sent, so a client can't make the server build a huge multipart response
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRanges {
+    /// Inclusive `(first, last)` byte positions, sorted, with overlapping and
+    /// adjacent ranges merged
+    Satisfiable(Vec<(u64, u64)>),
+    /// None of the ranges overlap the body, which is a 416
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header such as `bytes=0-499`, `bytes=500-`, `bytes=-500`
+/// or `bytes=0-99,200-299` for a body of `size` bytes. Returns `None` when
+/// the header should be ignored: it's malformed, not in bytes, asks for too
+/// many ranges, or the body is empty.
+pub fn parse_range_header(header: &str, size: u64) -> Option<ByteRanges> {
+    let (unit, specs) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || size == 0 {
+        return None;
+    }
+
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            ("", "") => return None,
+            // The last `n` bytes
+            ("", suffix) => match suffix.parse::<u64>().ok()? {
+                0 => None,
+                n => Some((size.saturating_sub(n), size - 1)),
+            },
+            (first, last) => {
+                let first = first.parse::<u64>().ok()?;
+                let last = match last {
+                    "" => u64::MAX,
+                    last => last.parse::<u64>().ok()?,
+                };
+                if last < first {
+                    return None;
+                }
+                (first < size).then(|| (first, last.min(size - 1)))
+            }
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return Some(ByteRanges::Unsatisfiable);
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1 + 1 => previous.1 = previous.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    Some(ByteRanges::Satisfiable(merged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfiable(header: &str, size: u64) -> Vec<(u64, u64)> {
+        match parse_range_header(header, size) {
+            Some(ByteRanges::Satisfiable(ranges)) => ranges,
+            other => panic!("{} gave {:?}", header, other),
+        }
+    }
+
+    #[test]
+    fn test_single_ranges() {
+        assert_eq!(satisfiable("bytes=0-499", 1000), vec![(0, 499)]);
+        assert_eq!(satisfiable("bytes=500-", 1000), vec![(500, 999)]);
+        assert_eq!(satisfiable("bytes=900-5000", 1000), vec![(900, 999)]);
+    }
+
+    #[test]
+    fn test_suffix_ranges() {
+        assert_eq!(satisfiable("bytes=-500", 1000), vec![(500, 999)]);
+        assert_eq!(satisfiable("bytes=-5000", 1000), vec![(0, 999)]);
+        assert_eq!(parse_range_header("bytes=-0", 1000), Some(ByteRanges::Unsatisfiable));
+    }
+
+    #[test]
+    fn test_multiple_ranges_are_sorted_and_merged() {
+        assert_eq!(satisfiable("bytes=500-599, 0-99", 1000), vec![(0, 99), (500, 599)]);
+        assert_eq!(satisfiable("bytes=0-99,100-199,150-300", 1000), vec![(0, 300)]);
+        assert_eq!(satisfiable("bytes=0-9,-10", 1000), vec![(0, 9), (990, 999)]);
+        // Unsatisfiable ranges are dropped as long as one is satisfiable
+        assert_eq!(satisfiable("bytes=0-9,2000-", 1000), vec![(0, 9)]);
+    }
+
+    #[test]
+    fn test_unsatisfiable() {
+        assert_eq!(parse_range_header("bytes=1000-", 1000), Some(ByteRanges::Unsatisfiable));
+        assert_eq!(parse_range_header("bytes=1000-1100,2000-", 1000), Some(ByteRanges::Unsatisfiable));
+    }
+
+    #[test]
+    fn test_ignored_headers() {
+        for header in ["bytes=", "bytes=abc", "bytes=5-1", "bytes=-", "items=0-1", "0-499"] {
+            assert_eq!(parse_range_header(header, 1000), None, "{}", header);
+        }
+        assert_eq!(parse_range_header("bytes=0-1", 0), None);
+        let many = format!("bytes={}", vec!["0-1"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_range_header(&many, 1000), None);
+    }
+}
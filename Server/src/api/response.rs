@@ -7,6 +7,8 @@ use axum::{
 use std::collections::HashMap;
 use time::OffsetDateTime;
 
+use crate::db::paging::Page;
+
 /// Standard API success response envelope
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T> {
@@ -14,6 +16,12 @@ pub struct ApiResponse<T> {
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<T>,
+    /// Cursor for the next page of a listing, absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// Total number of items in a listing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
     #[serde(with = "time::serde::iso8601")]
     pub timestamp: OffsetDateTime,
 }
@@ -24,6 +32,8 @@ impl<T: Serialize> ApiResponse<T> {
             success: true,
             message: message.into(),
             data: Some(data),
+            next_cursor: None,
+            total: None,
             timestamp: OffsetDateTime::now_utc(),
         }
     }
@@ -34,6 +44,21 @@ impl<T: Serialize> ApiResponse<T> {
             success: true,
             message: message.into(),
             data: None,
+            next_cursor: None,
+            total: None,
+            timestamp: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+impl<T: Serialize> ApiResponse<Vec<T>> {
+    pub fn page(message: impl Into<String>, page: Page<T>) -> Self {
+        Self {
+            success: true,
+            message: message.into(),
+            data: Some(page.items),
+            next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+            total: Some(page.total),
             timestamp: OffsetDateTime::now_utc(),
         }
     }
@@ -45,6 +70,8 @@ impl ApiResponse<()> {
             success: true,
             message: message.into(),
             data: None,
+            next_cursor: None,
+            total: None,
             timestamp: OffsetDateTime::now_utc(),
         }
     }
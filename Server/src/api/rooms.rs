@@ -0,0 +1,192 @@
+use axum::{
+    extract::{Json, Query, State, ws::{Message, WebSocket, WebSocketUpgrade}},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use crate::api::response::{ApiResponse, ApiError};
+use crate::api::auth::AppState;
+use crate::api::connect::{authenticate, reauthenticate, socket_deadline};
+use crate::api::libraries::library_filter;
+use crate::api::playlists::{find_playlist, playlist_songs};
+use crate::auth::{scope, Authorized, Claims, Permission};
+use crate::db::models::{LibraryFilter, SharePermission};
+use crate::rooms::hub::{RoomError, RoomSummary};
+use crate::rooms::protocol::{ClientMessage, RoomState, ServerMessage};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoomRequest {
+    pub name: String,
+    /// Playlist to start the queue with
+    pub playlist: Option<String>,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomIdQuery {
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JoinRoomQuery {
+    pub room: String,
+    /// The JWT, for clients that can't set headers on a WebSocket
+    pub token: Option<String>,
+}
+
+impl From<RoomError> for ApiError {
+    fn from(error: RoomError) -> Self {
+        let status = match error {
+            RoomError::NotFound => StatusCode::NOT_FOUND,
+            RoomError::NotHost => StatusCode::FORBIDDEN,
+            RoomError::Invalid(_) => StatusCode::BAD_REQUEST,
+        };
+        ApiError::new(status, error.to_string())
+    }
+}
+
+/// POST /api/rooms
+/// Open a listening room hosted by the caller, optionally queueing a playlist
+pub async fn create_room(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Json(payload): Json<CreateRoomRequest>,
+) -> Result<Json<ApiResponse<RoomState>>, ApiError> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("Room name is required"));
+    }
+
+    let queue = match &payload.playlist {
+        Some(playlist) => {
+            let playlist = find_playlist(&state, &claims, playlist, payload.owner.as_deref(), SharePermission::View).await?;
+            let libraries = library_filter(&state, &claims).await?;
+            playlist_songs(&state, &playlist, &libraries).await?
+        }
+        None => Vec::new(),
+    };
+
+    let room = state.rooms.create(name, &claims.sub, &claims.username, queue);
+    Ok(Json(ApiResponse::success("Room created", room)))
+}
+
+/// GET /api/rooms
+/// List open listening rooms
+pub async fn get_rooms(
+    State(state): State<AppState>,
+    _: Authorized<scope::Listen>,
+) -> Result<Json<ApiResponse<Vec<RoomSummary>>>, ApiError> {
+    Ok(Json(ApiResponse::success("rooms", state.rooms.list())))
+}
+
+/// DELETE /api/rooms?id=X
+/// Close a room, for its host or an admin
+pub async fn close_room(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<RoomIdQuery>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    state.rooms.close(&params.id, &claims.sub, claims.can(Permission::ManageUsers))?;
+    Ok(Json(ApiResponse::no_data("Room closed")))
+}
+
+/// GET /api/rooms/join?room=X&token=Y
+/// Join a room over a WebSocket. The token may also be sent as
+/// `Authorization: Bearer`.
+pub async fn join_room(
+    State(state): State<AppState>,
+    Query(params): Query<JoinRoomQuery>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let claims = authenticate(&state, &headers, params.token.as_deref())?;
+    // Checked before upgrading, so a missing room is a plain 404
+    if !state.rooms.contains(&params.room) {
+        return Err(RoomError::NotFound.into());
+    }
+    let libraries = library_filter(&state, &claims).await?;
+
+    Ok(upgrade.on_upgrade(move |socket| run_member(state, claims, libraries, params.room, socket)))
+}
+
+/// Relay messages between the socket and the room until either side closes,
+/// the member leaves or the token expires without them sending a `reauth`.
+/// Members can only suggest songs from libraries they can see.
+async fn run_member(state: AppState, mut claims: Claims, libraries: LibraryFilter, room_id: String, mut socket: WebSocket) {
+    let hub = state.rooms.clone();
+    let mut session = match hub.join(&room_id, &claims.sub, &claims.username) {
+        Ok(session) => session,
+        // Closed in the meantime
+        Err(e) => {
+            let _ = send(&mut socket, &ServerMessage::Error { message: e.to_string() }).await;
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    };
+    let expiry = tokio::time::sleep_until(socket_deadline(&claims));
+    tokio::pin!(expiry);
+
+    loop {
+        tokio::select! {
+            outgoing = session.messages.recv() => {
+                // The channel closes when the room does, or a newer connection replaced this one
+                let Some(message) = outgoing else { break };
+                if send(&mut socket, &message).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                let message = match incoming {
+                    Some(Ok(Message::Text(text))) => serde_json::from_str::<ClientMessage>(&text),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                match message {
+                    Ok(ClientMessage::Leave) => break,
+                    Ok(ClientMessage::Reauth { token }) => {
+                        let reply = match reauthenticate(&state, &claims, &token) {
+                            Ok((renewed, expires_at)) => {
+                                expiry.as_mut().reset(socket_deadline(&renewed));
+                                claims = renewed;
+                                ServerMessage::Reauthenticated { expires_at }
+                            }
+                            Err(e) => ServerMessage::Error { message: e.message },
+                        };
+                        if send(&mut socket, &reply).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(ClientMessage::Suggest { song_id }) => match state.db.get_song_by_id(&song_id).await {
+                        Ok(song) if libraries.allows(&song.library_id) => hub.suggest(&claims.sub, &session, song),
+                        _ => {
+                            let error = ServerMessage::Error { message: "Song not found".to_string() };
+                            if send(&mut socket, &error).await.is_err() {
+                                break;
+                            }
+                        }
+                    },
+                    Ok(message) => hub.handle(&claims.sub, &session, message),
+                    Err(e) => {
+                        let error = ServerMessage::Error { message: format!("Invalid message: {}", e) };
+                        if send(&mut socket, &error).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = &mut expiry => {
+                let _ = send(&mut socket, &ServerMessage::Error { message: "Token expired".to_string() }).await;
+                break;
+            }
+        }
+    }
+
+    hub.leave(&claims.sub, &session);
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).unwrap_or_default();
+    socket.send(Message::Text(text.into())).await
+}
@@ -0,0 +1,321 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+use crate::api::response::{ApiResponse, ApiError};
+use crate::api::auth::AppState;
+use crate::api::artists::{album_songs, find_album};
+use crate::api::libraries::{library_filter, user_library_filter};
+use crate::api::playlists::{find_playlist, find_song, playlist_songs, stream_url};
+use crate::auth::{scope, Authorized, Claims};
+use crate::db::models::{SharePermission, ShareLink, ShareTarget, Song};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareLinkRequest {
+    /// `playlist`, `album` or `song`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Playlist name, album name or song title
+    pub name: String,
+    /// Artist of the album or song
+    pub artist: Option<String>,
+    /// Username of the playlist's owner, defaults to the caller
+    pub owner: Option<String>,
+    pub password: Option<String>,
+    /// Seconds until the link expires, never when omitted
+    pub expires_in: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareLinkTokenQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ShareLinkAccessRequest {
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareLinkInfo {
+    pub token: String,
+    pub url: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub name: String,
+    pub artist: Option<String>,
+    pub has_password: bool,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+    pub expired: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublicShareInfo {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub name: String,
+    pub artist: Option<String>,
+    pub shared_by: String,
+    pub requires_password: bool,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SharedSong {
+    pub name: String,
+    pub artist_name: String,
+    pub album: Option<String>,
+    pub duration: Option<i32>,
+    pub stream_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareLinkAccess {
+    /// Stream-only token, sent as `Authorization: Bearer <token>`
+    pub token: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+    pub songs: Vec<SharedSong>,
+}
+
+/// POST /api/share-links
+/// Create a public link to a playlist, album or song. Sharing a playlist
+/// needs admin permission on it; any song or album in the library can be shared.
+pub async fn create_share_link(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::ShareLinks>,
+    Json(payload): Json<CreateShareLinkRequest>,
+) -> Result<Json<ApiResponse<ShareLinkInfo>>, ApiError> {
+    let libraries = library_filter(&state, &claims).await?;
+    let target = match payload.kind.as_str() {
+        "playlist" => {
+            let playlist = find_playlist(&state, &claims, &payload.name, payload.owner.as_deref(), SharePermission::Admin).await?;
+            ShareTarget::Playlist { playlist_id: playlist.id }
+        }
+        "album" => {
+            let artist = payload.artist.as_deref()
+                .ok_or_else(|| ApiError::bad_request("An artist is required to share an album"))?;
+            let (artist, _) = find_album(&state, artist, &payload.name, &libraries).await?;
+            ShareTarget::Album { artist_id: artist.id, album: payload.name.clone() }
+        }
+        "song" => {
+            let artist = payload.artist.as_deref()
+                .ok_or_else(|| ApiError::bad_request("An artist is required to share a song"))?;
+            let song = find_song(&state, artist, &payload.name, &libraries).await?;
+            ShareTarget::Song { song_id: song.id }
+        }
+        other => return Err(ApiError::bad_request(format!("Unknown share link type: {}", other))),
+    };
+
+    let expires_at = match payload.expires_in {
+        Some(seconds) if seconds <= 0 => return Err(ApiError::bad_request("expires_in must be positive")),
+        Some(seconds) => Some(OffsetDateTime::now_utc().checked_add(Duration::seconds(seconds))
+            .ok_or_else(|| ApiError::bad_request("expires_in is too far in the future"))?),
+        None => None,
+    };
+
+    let password_hash = match payload.password.as_deref().filter(|password| !password.is_empty()) {
+        Some(password) => Some(state.password_service.hash_password(password)
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to hash password: {}", e)))?),
+        None => None,
+    };
+
+    let link = state.db.create_share_link(&generate_token(), &claims.sub, &target, password_hash.as_deref(), expires_at).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create share link: {}", e)))?;
+
+    let info = link_info(&state, link).await?;
+    Ok(Json(ApiResponse::success("Share link created", info)))
+}
+
+/// GET /api/share-links
+/// The caller's share links, newest first, including expired ones
+pub async fn get_share_links(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> Result<Json<ApiResponse<Vec<ShareLinkInfo>>>, ApiError> {
+    let links = state.db.get_user_share_links(&claims.sub).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch share links: {}", e)))?;
+
+    let mut link_infos = Vec::with_capacity(links.len());
+    for link in links {
+        link_infos.push(link_info(&state, link).await?);
+    }
+
+    Ok(Json(ApiResponse::success("share links", link_infos)))
+}
+
+/// DELETE /api/share-links?token=X
+/// Revoke a link. Tokens already handed out through it stop working at once.
+pub async fn revoke_share_link(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    Query(params): Query<ShareLinkTokenQuery>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    state.db.delete_share_link(&params.token, &claims.sub).await
+        .map_err(|e| ApiError::new(StatusCode::NOT_FOUND, format!("Share link not found: {}", e)))?;
+
+    Ok(Json(ApiResponse::no_data("Share link revoked")))
+}
+
+/// GET /api/public/shares/{token}
+/// What a link shares, without needing an account or the password
+pub async fn get_public_share(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<ApiResponse<PublicShareInfo>>, ApiError> {
+    let link = live_link(&state, &token).await?;
+    let (name, artist) = target_name(&state, &link.target).await?;
+    let owner = state.db.get_user_by_id(&link.owner_id).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch user: {}", e)))?;
+
+    Ok(Json(ApiResponse::success("shared content", PublicShareInfo {
+        kind: link.target.kind(),
+        name,
+        artist,
+        shared_by: owner.username,
+        requires_password: link.password_hash.is_some(),
+        expires_at: link.expires_at,
+    })))
+}
+
+/// POST /api/public/shares/{token}/access
+/// Exchange a link, and its password if it has one, for a stream-only token
+/// and the shared songs
+pub async fn access_public_share(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    payload: Option<Json<ShareLinkAccessRequest>>,
+) -> Result<Json<ApiResponse<ShareLinkAccess>>, ApiError> {
+    let link = live_link(&state, &token).await?;
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+
+    if let Some(password_hash) = &link.password_hash {
+        let password = payload.password.as_deref().unwrap_or_default();
+        let is_valid = state.password_service.verify_password(password, password_hash)
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Password verification failed: {}", e)))?;
+
+        if !is_valid {
+            return Err(ApiError::unauthorized("Invalid share link password"));
+        }
+    }
+
+    let songs = target_songs(&state, &link).await?;
+    let (token, expires_at) = state.jwt_service
+        .generate_share_token(&link.id, link.expires_at.map(|expires_at| expires_at.unix_timestamp()))
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate token: {}", e)))?;
+
+    Ok(Json(ApiResponse::success("share link access granted", ShareLinkAccess {
+        token,
+        expires_at: OffsetDateTime::from_unix_timestamp(expires_at)
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid token expiry: {}", e)))?,
+        songs: songs.iter().map(|song| SharedSong {
+            name: song.title.clone(),
+            artist_name: song.artist_name.clone(),
+            album: song.album.clone(),
+            duration: song.duration,
+            stream_url: stream_url(&state, song),
+        }).collect(),
+    })))
+}
+
+/// Reject songs outside the link a share token was issued for.
+/// User tokens pass through unchanged.
+pub async fn ensure_share_scope(state: &AppState, claims: &Claims, song: &Song) -> Result<(), ApiError> {
+    let Some(link_id) = &claims.share_link else {
+        return Ok(());
+    };
+
+    let link = live_link(state, link_id).await?;
+    let allowed = match &link.target {
+        ShareTarget::Song { song_id } => song_id == &song.id,
+        _ => target_songs(state, &link).await?.iter().any(|shared| shared.id == song.id),
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(ApiError::forbidden("This song is not part of the share link"))
+    }
+}
+
+/// Look up a link that has not expired
+async fn live_link(state: &AppState, token: &str) -> Result<ShareLink, ApiError> {
+    let link = state.db.get_share_link(token).await
+        .map_err(|_| ApiError::not_found("Share link not found"))?;
+
+    if link.is_expired() {
+        return Err(ApiError::new(StatusCode::GONE, "Share link has expired"));
+    }
+
+    Ok(link)
+}
+
+/// Songs a link gives access to, from the libraries its owner can see now
+async fn target_songs(state: &AppState, link: &ShareLink) -> Result<Vec<Song>, ApiError> {
+    let libraries = user_library_filter(state, &link.owner_id).await?;
+    match &link.target {
+        ShareTarget::Playlist { playlist_id } => {
+            let playlist = state.db.get_playlist_by_id(playlist_id).await
+                .map_err(|_| ApiError::not_found("Shared playlist no longer exists"))?;
+            playlist_songs(state, &playlist, &libraries).await
+        }
+        ShareTarget::Album { artist_id, album } => album_songs(state, artist_id, album, &libraries).await,
+        ShareTarget::Song { song_id } => {
+            let song = state.db.get_song_by_id(song_id).await.ok()
+                .filter(|song| libraries.allows(&song.library_id))
+                .ok_or_else(|| ApiError::not_found("Shared song no longer exists"))?;
+            Ok(vec![song])
+        }
+    }
+}
+
+/// Display name of what a link shares, and its artist for albums and songs
+async fn target_name(state: &AppState, target: &ShareTarget) -> Result<(String, Option<String>), ApiError> {
+    match target {
+        ShareTarget::Playlist { playlist_id } => {
+            let playlist = state.db.get_playlist_by_id(playlist_id).await
+                .map_err(|_| ApiError::not_found("Shared playlist no longer exists"))?;
+            Ok((playlist.name, None))
+        }
+        ShareTarget::Album { artist_id, album } => {
+            let artist = state.db.get_artist_by_id(artist_id).await
+                .map_err(|_| ApiError::not_found("Shared album no longer exists"))?;
+            Ok((album.clone(), Some(artist.name)))
+        }
+        ShareTarget::Song { song_id } => {
+            let song = state.db.get_song_by_id(song_id).await
+                .map_err(|_| ApiError::not_found("Shared song no longer exists"))?;
+            Ok((song.title, Some(song.artist_name)))
+        }
+    }
+}
+
+async fn link_info(state: &AppState, link: ShareLink) -> Result<ShareLinkInfo, ApiError> {
+    let (name, artist) = target_name(state, &link.target).await
+        .unwrap_or_else(|_| ("(deleted)".to_string(), None));
+
+    Ok(ShareLinkInfo {
+        url: format!("{}/api/public/shares/{}", state.website_url, link.id),
+        kind: link.target.kind(),
+        name,
+        artist,
+        has_password: link.password_hash.is_some(),
+        expires_at: link.expires_at,
+        expired: link.is_expired(),
+        created_at: link.created_at,
+        token: link.id,
+    })
+}
+
+/// 256 random bits, URL-safe
+fn generate_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
@@ -4,35 +4,34 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
+use crate::api::favorites::{rating_state, ratings_by_id};
+use crate::api::libraries::library_filter;
+use crate::api::pagination::PageQuery;
 use crate::api::response::{ApiError, ApiResponse, ApiResult};
 use crate::api::auth::AppState;
+use crate::auth::{scope, Authorized};
+use crate::db::models::{Song, UserRating};
+use crate::db::paging::SortField;
+use crate::music::gapless::GaplessInfo;
 
 // ============================================================================
 // Request/Response Types
 // ============================================================================
 
-#[derive(Debug, Deserialize)]
-pub struct PaginationQuery {
-    pub index_start: Option<usize>,
-    pub index_end: Option<usize>,
-}
-
 #[derive(Debug, Deserialize)]
 pub struct SongInfoQuery {
     pub artist_name: String,
     pub name: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct SearchQuery {
-    pub query: String,
-}
-
 #[derive(Debug, Serialize)]
 pub struct SongBasic {
     pub name: String,
     pub artist_name: String,
+    pub favorite: bool,
+    pub rating: Option<u8>,
 }
 
 #[derive(Debug, Serialize)]
@@ -43,47 +42,73 @@ pub struct SongInfo {
     pub duration: u32,
     pub bitrate: u32,
     pub genre: String,
+    pub favorite: bool,
+    pub rating: Option<u8>,
+    /// Where the song is in its file, for a track of a cue sheet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<TrackRange>,
+    pub gapless: GaplessInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackRange {
+    pub start_ms: i64,
+    /// `None` when the track runs to the end of the file
+    pub end_ms: Option<i64>,
+}
+
+impl SongBasic {
+    fn new(song: Song, ratings: &HashMap<String, UserRating>) -> Self {
+        let (favorite, rating) = rating_state(ratings, &song.id);
+        SongBasic {
+            name: song.title,
+            artist_name: song.artist_name,
+            favorite,
+            rating,
+        }
+    }
 }
 
 // ============================================================================
 // Handlers
 // ============================================================================
 
-/// GET /api/songs?index_start=X&index_end=Y
-/// Get paginated list of songs
+/// GET /api/songs?limit=50&sort=title&order=asc&cursor=X
+/// Get a page of songs, sortable by title, artist, added or play_count
 pub async fn get_songs(
     State(state): State<AppState>,
-    Query(params): Query<PaginationQuery>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    Query(params): Query<PageQuery>,
 ) -> ApiResult<Vec<SongBasic>> {
-    // Calculate offset and limit from the pagination query
-    let offset = params.index_start.unwrap_or(0);
-    let total_songs = state.db.get_total_songs().await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to get total songs: {}", e)))?;
-    let limit = params.index_end.unwrap_or(total_songs).saturating_sub(offset);
+    let request = params.to_request(
+        &state.page_limits,
+        &[SortField::Title, SortField::Artist, SortField::Added, SortField::PlayCount],
+        SortField::Added,
+    )?;
     
-    // Query database for songs in the specified range
-    let songs = state.db.get_songs(offset, limit).await
+    // Query database for the requested page of songs, from the libraries the user can see
+    let libraries = library_filter(&state, &claims).await?;
+    let page = state.db.get_songs(&request, &libraries).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to fetch songs: {}", e)))?;
     
     // Convert database Song models to SongBasic response type
-    let song_basics: Vec<SongBasic> = songs.into_iter()
-        .map(|song| SongBasic {
-            name: song.title,
-            artist_name: song.artist_name,
-        })
-        .collect();
+    let song_ids = page.items.iter().map(|song| song.id.clone()).collect();
+    let ratings = ratings_by_id(&state, &claims.sub, "song", song_ids).await?;
+    let page = page.map(|song| SongBasic::new(song, &ratings));
 
-    Ok(Json(ApiResponse::success("songs", song_basics)))
+    Ok(Json(ApiResponse::page("songs", page)))
 }
 
 /// GET /api/songs/info?artist_name=X&name=Y
 /// Get detailed information about a specific song
 pub async fn get_song_info(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
     Query(params): Query<SongInfoQuery>,
 ) -> ApiResult<SongInfo> {
     // Search for the song by artist name and title
-    let songs = state.db.search_songs(&params.name, 0, 100).await
+    let libraries = library_filter(&state, &claims).await?;
+    let songs = state.db.search_songs(&params.name, &libraries, 0, 100).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to search songs: {}", e)))?;
     
     // Find the song matching both artist name and title
@@ -92,7 +117,14 @@ pub async fn get_song_info(
                    s.title.eq_ignore_ascii_case(&params.name))
         .ok_or_else(|| ApiError::not_found("Song not found"))?;
     
+    let ratings = ratings_by_id(&state, &claims.sub, "song", vec![song.id.clone()]).await?;
+    let (favorite, rating) = rating_state(&ratings, &song.id);
+    
     let song_info = SongInfo {
+        favorite,
+        rating,
+        track: song.start_ms.map(|start_ms| TrackRange { start_ms, end_ms: song.end_ms }),
+        gapless: GaplessInfo::of(&song),
         name: song.title,
         artist_name: song.artist_name,
         album: song.album.unwrap_or_else(|| "Unknown Album".to_string()),
@@ -108,11 +140,13 @@ pub async fn get_song_info(
 /// Get cover image for a specific song
 pub async fn get_song_cover(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
     Query(params): Query<SongInfoQuery>,
 ) -> Result<Response, ApiError> {
     // Search for the song by artist name and title
-    let songs = state.db.search_songs(&params.name, 0, 100).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to search songs: {}", e)))?;
+    let libraries = library_filter(&state, &claims).await?;
+    let songs = state.db.search_songs(&params.name, &libraries, 0, 100).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to search songs: {}", e)))?;
     
     // Find the song matching both artist name and title
     let song = songs.into_iter()
@@ -1,14 +1,38 @@
+use std::io::{Cursor, SeekFrom};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use axum::{
     body::Body,
-    extract::{Query, State},
+    extract::{ConnectInfo, Json, Query, State},
     http::{header, HeaderMap, StatusCode},
-    response::{IntoResponse, Response},
+    response::Response,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
-use crate::api::response::ApiError;
+use crate::api::response::{ApiError, ApiResponse};
 use crate::api::auth::AppState;
+use crate::api::ranges::{parse_range_header, ByteRanges};
+use crate::api::libraries::library_filter;
+use crate::api::share_links::ensure_share_scope;
+use crate::auth::{scope, Authorized, Claims};
+use crate::auth::stream_url::StreamGrant;
+use crate::db::models::Song;
+use crate::music::seek::load_seek_table;
+use crate::music::slicing::{slice_file, Slice, SliceError};
+
+/// Headers telling players where an uncut cue sheet track is in the file
+/// they're sent, and how much priming and padding to drop for gapless playback
+pub const TRACK_START_HEADER: &str = "x-track-start-ms";
+pub const TRACK_END_HEADER: &str = "x-track-end-ms";
+pub const ENCODER_DELAY_HEADER: &str = "x-encoder-delay";
+pub const ENCODER_PADDING_HEADER: &str = "x-encoder-padding";
+
+/// Where in the song a stream asked to start at a time actually starts. Cuts
+/// fall on frame boundaries, so this can be slightly before the time asked for.
+pub const STREAM_START_HEADER: &str = "x-stream-start-ms";
 
 #[derive(Debug, Deserialize)]
 pub struct StreamQuery {
@@ -16,34 +40,154 @@ pub struct StreamQuery {
     pub name: String,
     #[serde(default = "default_format")]
     pub format: String,
+    /// Seconds into the song to start from
+    pub start: Option<f64>,
+    /// Seconds into the song to stop at
+    pub end: Option<f64>,
 }
 
 fn default_format() -> String {
     "mp3".to_string()
 }
 
+/// How long a signed URL lasts when the request doesn't say
+const DEFAULT_SIGNED_URL_SECONDS: i64 = 15 * 60;
+const MAX_SIGNED_URL_SECONDS: i64 = 12 * 3600;
+
+#[derive(Debug, Deserialize)]
+pub struct SignStreamRequest {
+    pub artist: String,
+    pub name: String,
+    #[serde(default = "default_format")]
+    pub format: String,
+    /// Seconds until the URL stops working
+    pub expires_in: Option<i64>,
+    /// Only let the URL be used from the caller's address
+    #[serde(default)]
+    pub bind_ip: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignedStreamUrl {
+    pub url: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+    /// The address the URL is bound to
+    pub ip: Option<String>,
+}
+
+/// POST /api/stream/sign
+/// A signed `/api/stream` URL for one song and format, for players that
+/// can't send an Authorization header. It works without a token until it
+/// expires, and only from the caller's address when bound.
+pub async fn sign_stream_url(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Json(payload): Json<SignStreamRequest>,
+) -> Result<Json<ApiResponse<SignedStreamUrl>>, ApiError> {
+    let expires_in = payload.expires_in.unwrap_or(DEFAULT_SIGNED_URL_SECONDS);
+    if !(1..=MAX_SIGNED_URL_SECONDS).contains(&expires_in) {
+        return Err(ApiError::bad_request(format!("expires_in must be between 1 and {} seconds", MAX_SIGNED_URL_SECONDS)));
+    }
+
+    let song = find_stream_song(&state, &claims, &payload.artist, &payload.name).await?;
+    ensure_share_scope(&state, &claims, &song).await?;
+
+    // A user's URL can outlive their access token, which is refreshed every
+    // few minutes, but a share link's URL never outlives the link's token
+    let mut expires = OffsetDateTime::now_utc().unix_timestamp() + expires_in;
+    if claims.share_link.is_some() {
+        expires = expires.min(claims.exp);
+    }
+    let grant = StreamGrant {
+        artist: song.artist_name,
+        name: song.title,
+        format: payload.format,
+        user_id: claims.sub,
+        share_link: claims.share_link,
+        expires,
+        ip: payload.bind_ip.then(|| peer.ip()),
+    };
+    let signature = state.stream_signer.sign(&grant);
+
+    let info = SignedStreamUrl {
+        url: format!("{}/api/stream?{}", state.website_url, grant.query_string(&signature)),
+        expires_at: OffsetDateTime::from_unix_timestamp(expires)
+            .map_err(|e| ApiError::internal_server_error(format!("Invalid expiry: {}", e)))?,
+        ip: grant.ip.map(|ip| ip.to_string()),
+    };
+    Ok(Json(ApiResponse::success("Signed stream URL created", info)))
+}
+
+/// The song `/api/stream` streams for an artist and title, ignoring case,
+/// from the libraries the caller can see
+async fn find_stream_song(state: &AppState, claims: &Claims, artist: &str, name: &str) -> Result<Song, ApiError> {
+    // Search for the song by artist name and title
+    let libraries = library_filter(state, claims).await?;
+    let songs = state.db.search_songs(name, &libraries, 0, 100).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to search songs: {}", e)))?;
+
+    // Find the song matching both artist name and title
+    songs.into_iter()
+        .find(|s| s.artist_name.eq_ignore_ascii_case(artist) &&
+                   s.title.eq_ignore_ascii_case(name))
+        .ok_or_else(|| ApiError::not_found("Song not found"))
+}
+
 pub async fn stream_song(
     State(state): State<AppState>,
+    Authorized(claims, _): Authorized<scope::Listen>,
     headers: HeaderMap,
     Query(params): Query<StreamQuery>,
 ) -> Result<Response, ApiError> {
-    // Search for the song by artist name and title
-    let songs = state.db.search_songs(&params.name, 0, 100).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to search songs: {}", e)))?;
-    
-    // Find the song matching both artist name and title
-    let song = songs.into_iter()
-        .find(|s| s.artist_name.eq_ignore_ascii_case(&params.artist) && 
-                   s.title.eq_ignore_ascii_case(&params.name))
-        .ok_or_else(|| ApiError::not_found("Song not found"))?;
-    
+    let start_secs = parse_seconds(params.start, "start")?;
+    let end_secs = parse_seconds(params.end, "end")?;
+    if let (Some(start), Some(end)) = (start_secs, end_secs) && end <= start {
+        return Err(ApiError::bad_request("end must be after start"));
+    }
+    let seeking = start_secs.is_some_and(|start| start > 0) || end_secs.is_some();
+
+    let song = find_stream_song(&state, &claims, &params.artist, &params.name).await?;
+    ensure_share_scope(&state, &claims, &song).await?;
+
     // Get file metadata
     let file_path = &song.file_path;
     let metadata = tokio::fs::metadata(file_path).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to read file metadata: {}", e)))?;
-    
+
     let file_size = metadata.len();
-    
+
+    // Cue sheet tracks are cut out of their file where the format allows it,
+    // as are streams asked to start or end at a time. Otherwise the whole
+    // file is sent, with a cue sheet track's times in headers.
+    let song_start_ms = song.start_ms.unwrap_or(0);
+    let cut = if seeking || song.start_ms.is_some() {
+        let path = PathBuf::from(file_path);
+        let start_ms = song_start_ms + start_secs.unwrap_or(0);
+        let end_ms = match end_secs.map(|end| song_start_ms + end) {
+            Some(end) => Some(song.end_ms.map_or(end, |song_end| song_end.min(end))),
+            None => song.end_ms,
+        };
+        tokio::task::spawn_blocking(move || {
+            let table = load_seek_table(&path);
+            slice_file(&path, start_ms, end_ms, table.as_ref())
+        }).await
+            .map_err(|e| ApiError::internal_server_error(format!("Failed to cut track: {}", e)))?
+            .map_err(|e| match e {
+                SliceError::Empty => ApiError::new(StatusCode::RANGE_NOT_SATISFIABLE, "The song has no audio at that time"),
+                e => ApiError::internal_server_error(format!("Failed to cut track: {}", e)),
+            })?
+    } else {
+        None
+    };
+    if seeking && cut.is_none() {
+        return Err(ApiError::bad_request("Seeking isn't supported for this song's format"));
+    }
+    let is_cut = cut.is_some();
+    let slice = cut.unwrap_or_else(|| Slice::whole(file_size));
+    let total_size = slice.total_len();
+
     // Determine content type based on format
     let content_type = match params.format.to_lowercase().as_str() {
         "mp3" => "audio/mpeg",
@@ -53,79 +197,126 @@ pub async fn stream_song(
         "ogg" => "audio/ogg",
         _ => "application/octet-stream",
     };
-    
-    // Check for Range header to support partial content requests
-    if let Some(range_header) = headers.get(header::RANGE) {
-        if let Ok(range_str) = range_header.to_str() {
-            // Parse range header (e.g., "bytes=0-1023")
-            if let Some(range) = parse_range_header(range_str, file_size) {
-                let (start, end) = range;
-                let content_length = end - start + 1;
-                
-                // Open file and seek to start position
-                let file = File::open(file_path).await
-                    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to open file: {}", e)))?;
-                
-                let mut file = tokio::io::BufReader::new(file);
-                tokio::io::AsyncSeekExt::seek(&mut file, std::io::SeekFrom::Start(start)).await
-                    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to seek file: {}", e)))?;
-                
-                // Create a stream that reads only the requested range
-                let limited_stream = tokio::io::AsyncReadExt::take(file, content_length);
-                let stream = ReaderStream::new(limited_stream);
-                
-                return Ok((
-                    StatusCode::PARTIAL_CONTENT,
-                    [
-                        (header::CONTENT_TYPE, content_type),
-                        (header::CONTENT_LENGTH, &content_length.to_string()),
-                        (header::CONTENT_RANGE, &format!("bytes {}-{}/{}", start, end, file_size)),
-                        (header::ACCEPT_RANGES, "bytes"),
-                    ],
-                    Body::from_stream(stream),
-                ).into_response());
-            }
+
+    // Check for Range header to support partial content requests (e.g., "bytes=0-1023")
+    let ranges = headers.get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_range_header(value, total_size));
+    let ranges = match ranges {
+        Some(ByteRanges::Satisfiable(ranges)) => Some(ranges),
+        Some(ByteRanges::Unsatisfiable) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", total_size))
+                .body(Body::empty())
+                .map_err(|e| ApiError::internal_server_error(format!("Failed to build response: {}", e)));
         }
+        None => None,
+    };
+
+    // Count a play when playback starts from the beginning of the song,
+    // not for every range request a player makes while seeking
+    if start_secs.is_none_or(|start| start == 0)
+        && ranges.as_ref().is_none_or(|ranges| ranges[0].0 == 0)
+        && let Err(e) = state.db.increment_song_play_count(&song.id).await
+    {
+        tracing::warn!("Failed to update play count for {}: {}", song.id, e);
     }
-    
-    // No range request or invalid range - stream entire file
-    let file = File::open(file_path).await
-        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to open file: {}", e)))?;
-    
-    let stream = ReaderStream::new(file);
-    
-    Ok((
-        StatusCode::OK,
-        [
-            (header::CONTENT_TYPE, content_type),
-            (header::CONTENT_LENGTH, &file_size.to_string()),
-            (header::ACCEPT_RANGES, "bytes"),
-        ],
-        Body::from_stream(stream),
-    ).into_response())
+
+    let mut response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes");
+    let (content_length, reader): (u64, Box<dyn AsyncRead + Send + Unpin>) = match ranges.as_deref() {
+        // No range request or an ignored one - stream everything
+        None => {
+            let reader = open_slice(file_path, &slice, 0, total_size).await
+                .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open file: {}", e)))?;
+            response = response.status(StatusCode::OK).header(header::CONTENT_TYPE, content_type);
+            (total_size, Box::new(reader))
+        }
+        Some(&[(start, end)]) => {
+            let reader = open_slice(file_path, &slice, start, end - start + 1).await
+                .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open file: {}", e)))?;
+            response = response.status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, total_size));
+            (end - start + 1, Box::new(reader))
+        }
+        Some(ranges) => {
+            let boundary = uuid::Uuid::new_v4().simple().to_string();
+            let (length, reader) = multipart_ranges(file_path, &slice, ranges, content_type, &boundary).await
+                .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open file: {}", e)))?;
+            response = response.status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary));
+            (length, reader)
+        }
+    };
+    response = response.header(header::CONTENT_LENGTH, content_length);
+
+    if seeking {
+        response = response.header(STREAM_START_HEADER, slice.start_ms.saturating_sub(song_start_ms as u64));
+    }
+    if let (Some(start_ms), false) = (song.start_ms, is_cut) {
+        response = response.header(TRACK_START_HEADER, start_ms);
+        if let Some(end_ms) = song.end_ms {
+            response = response.header(TRACK_END_HEADER, end_ms);
+        }
+    }
+    if let Some(delay) = song.encoder_delay {
+        response = response.header(ENCODER_DELAY_HEADER, delay);
+    }
+    if let Some(padding) = song.encoder_padding {
+        response = response.header(ENCODER_PADDING_HEADER, padding);
+    }
+
+    response.body(Body::from_stream(ReaderStream::new(reader)))
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to build response: {}", e)))
 }
 
-/// Parse HTTP Range header
-/// Returns (start, end) byte positions, or None if invalid
-fn parse_range_header(range: &str, file_size: u64) -> Option<(u64, u64)> {
-    // Expected format: "bytes=start-end" or "bytes=start-"
-    let range = range.strip_prefix("bytes=")?;
-    
-    let parts: Vec<&str> = range.split('-').collect();
-    if parts.len() != 2 {
-        return None;
+/// A time in seconds from the query, in milliseconds
+fn parse_seconds(seconds: Option<f64>, name: &str) -> Result<Option<i64>, ApiError> {
+    match seconds {
+        Some(seconds) if !seconds.is_finite() || seconds < 0.0 => {
+            Err(ApiError::bad_request(format!("{} must be a non-negative number of seconds", name)))
+        }
+        seconds => Ok(seconds.map(|seconds| (seconds * 1000.0) as i64)),
     }
-    
-    let start = parts[0].parse::<u64>().ok()?;
-    let end = if parts[1].is_empty() {
-        file_size - 1
-    } else {
-        parts[1].parse::<u64>().ok()?.min(file_size - 1)
-    };
-    
-    if start > end || start >= file_size {
-        return None;
+}
+
+/// Read `len` bytes of a slice from `start`, running from its header into
+/// the file region after it
+pub(super) async fn open_slice(path: &str, slice: &Slice, start: u64, len: u64) -> std::io::Result<impl AsyncRead + use<>> {
+    let header_len = slice.header.len() as u64;
+    let end = start + len;
+    let header = slice.header[start.min(header_len) as usize..end.min(header_len) as usize].to_vec();
+    let file_start = start.max(header_len) - header_len;
+
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(slice.offset + file_start)).await?;
+    let file_len = end.saturating_sub(start.max(header_len));
+    Ok(Cursor::new(header).chain(file.take(file_len)))
+}
+
+/// A `multipart/byteranges` body with a part for each range, and its length
+pub(super) async fn multipart_ranges(
+    path: &str,
+    slice: &Slice,
+    ranges: &[(u64, u64)],
+    content_type: &str,
+    boundary: &str,
+) -> std::io::Result<(u64, Box<dyn AsyncRead + Send + Unpin>)> {
+    let total_size = slice.total_len();
+    let mut length = 0;
+    let mut body: Box<dyn AsyncRead + Send + Unpin> = Box::new(tokio::io::empty());
+    for &(start, end) in ranges {
+        let part_header = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, total_size,
+        );
+        length += part_header.len() as u64 + end - start + 1;
+        let part = open_slice(path, slice, start, end - start + 1).await?;
+        body = Box::new(body.chain(Cursor::new(part_header.into_bytes())).chain(part));
     }
-    
-    Some((start, end))
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    length += closing.len() as u64;
+    Ok((length, Box::new(body.chain(Cursor::new(closing.into_bytes())))))
 }
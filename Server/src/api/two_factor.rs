@@ -0,0 +1,330 @@
+use axum::{extract::{Json, State}, http::StatusCode, response::Response};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::api::audit::{self, ClientAddress};
+use crate::api::auth::{session_response, start_session, AppState, AuthResponse};
+use crate::api::response::{ApiError, ApiResponse, ApiResult, ApiResultNoData};
+use crate::auth::{totp, Authorized, Permission};
+use crate::auth::jwt::TWO_FACTOR_TOKEN_MINUTES;
+use crate::db::DbError;
+use crate::db::models::{AuditAction, TwoFactor, User};
+
+/// Name authenticator apps list accounts under
+const ISSUER: &str = "Muse";
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallenge {
+    /// Always true, so clients can tell this from a completed login
+    pub two_factor_required: bool,
+    /// Partial token for `/api/login/2fa`
+    pub two_factor_token: String,
+    /// Seconds until `two_factor_token` expires
+    pub expires_in: i64,
+    /// Whether the account has to set up an authenticator first, through
+    /// `/api/login/2fa/setup`
+    pub enrollment_required: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorLoginResponse {
+    #[serde(flatten)]
+    pub session: AuthResponse,
+    /// Set when the login also enabled two-factor authentication. They're
+    /// only shown this once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorSetup {
+    /// Base32 secret, for typing into an authenticator app
+    pub secret: String,
+    /// `otpauth://` URI, for showing as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    /// Whether the account isn't allowed to turn it off
+    pub required: bool,
+    pub recovery_codes_left: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    /// One-time codes for logging in without the authenticator. They're only
+    /// shown this once.
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub two_factor_token: String,
+    /// Code from the authenticator, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorSetupRequest {
+    pub two_factor_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTwoFactorRequest {
+    pub password: String,
+}
+
+/// Whether a user has to use two-factor authentication, which the server can
+/// require of accounts whose role can manage users
+async fn is_required(state: &AppState, user: &User) -> Result<bool, ApiError> {
+    if !state.require_admin_two_factor {
+        return Ok(false);
+    }
+
+    match state.db.get_role(&user.role).await {
+        Ok(role) => Ok(role.permissions.contains(&Permission::ManageUsers)),
+        Err(DbError::RoleNotFound) => Ok(false),
+        Err(e) => Err(ApiError::internal_server_error(format!("Failed to get role: {}", e))),
+    }
+}
+
+/// A user's authenticator, enabled or not, if they've set one up
+async fn get_two_factor(state: &AppState, user_id: &str) -> Result<Option<TwoFactor>, ApiError> {
+    match state.db.get_two_factor(user_id).await {
+        Ok(two_factor) => Ok(Some(two_factor)),
+        Err(DbError::TwoFactorNotFound) => Ok(None),
+        Err(e) => Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+    }
+}
+
+/// Check a code from the user's authenticator. Each code works only once.
+async fn check_code(state: &AppState, two_factor: &TwoFactor, code: &str) -> Result<bool, ApiError> {
+    let Some(step) = totp::verify(&two_factor.secret, code, OffsetDateTime::now_utc().unix_timestamp()) else {
+        return Ok(false);
+    };
+
+    state.db.use_totp_step(&two_factor.user_id, step).await
+        .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))
+}
+
+/// Give a user new recovery codes, replacing any they had
+async fn new_recovery_codes(state: &AppState, user_id: &str) -> Result<Vec<String>, ApiError> {
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+    state.db.replace_recovery_codes(user_id, &hashes).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save recovery codes: {}", e)))?;
+
+    Ok(codes)
+}
+
+/// Start setting up an authenticator with a new secret, which isn't used
+/// until a code from it is entered
+async fn start_setup(state: &AppState, user: &User) -> Result<TwoFactorSetup, ApiError> {
+    if get_two_factor(state, &user.id).await?.is_some_and(|two_factor| two_factor.enabled) {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Two-factor authentication is already on"));
+    }
+
+    let secret = totp::generate_secret();
+    state.db.save_two_factor(&user.id, &secret, false).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save two-factor authentication: {}", e)))?;
+
+    Ok(TwoFactorSetup {
+        otpauth_uri: totp::provisioning_uri(&secret, ISSUER, &user.username),
+        secret,
+    })
+}
+
+/// Turn on the authenticator the user set up, if the code is from it,
+/// returning their first recovery codes
+async fn enable(state: &AppState, user_id: &str, code: &str) -> Result<Vec<String>, ApiError> {
+    let two_factor = get_two_factor(state, user_id).await?
+        .ok_or_else(|| ApiError::bad_request("Set up an authenticator first"))?;
+    if two_factor.enabled {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Two-factor authentication is already on"));
+    }
+    if !check_code(state, &two_factor, code).await? {
+        return Err(ApiError::unauthorized("Invalid code"));
+    }
+
+    state.db.save_two_factor(user_id, &two_factor.secret, true).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to save two-factor authentication: {}", e)))?;
+    tracing::info!("Two-factor authentication turned on for user {}", user_id);
+
+    new_recovery_codes(state, user_id).await
+}
+
+/// The second step a password login needs before the user gets a session, if
+/// any: a code when they use two-factor authentication, or setting it up when
+/// they have to and haven't
+pub(crate) async fn login_challenge(state: &AppState, user: &User) -> Result<Option<TwoFactorChallenge>, ApiError> {
+    let enabled = get_two_factor(state, &user.id).await?.is_some_and(|two_factor| two_factor.enabled);
+    if !enabled && !is_required(state, user).await? {
+        return Ok(None);
+    }
+
+    let two_factor_token = state.jwt_service.generate_two_factor_token(&user.id, &user.username)
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to generate token: {}", e)))?;
+
+    Ok(Some(TwoFactorChallenge {
+        two_factor_required: true,
+        two_factor_token,
+        expires_in: TWO_FACTOR_TOKEN_MINUTES * 60,
+        enrollment_required: !enabled,
+    }))
+}
+
+/// The user a partial token from a password login is for
+async fn two_factor_user(state: &AppState, token: &str) -> Result<User, ApiError> {
+    let claims = state.jwt_service.verify_two_factor_token(token)
+        .map_err(|_| ApiError::unauthorized("Invalid or expired login; log in again"))?;
+
+    state.db.get_user_by_id(&claims.sub).await
+        .map_err(|_| ApiError::unauthorized("Invalid or expired login; log in again"))
+}
+
+/// POST /api/login/2fa/setup
+/// Start setting up an authenticator during a login to an account that has to
+/// use one
+pub async fn login_setup(
+    State(state): State<AppState>,
+    Json(payload): Json<TwoFactorSetupRequest>,
+) -> ApiResult<TwoFactorSetup> {
+    let user = two_factor_user(&state, &payload.two_factor_token).await?;
+
+    Ok(Json(ApiResponse::success("Add the account to your authenticator, then log in with a code from it", start_setup(&state, &user).await?)))
+}
+
+/// POST /api/login/2fa
+/// Finish a password login with a code from the user's authenticator or one of
+/// their recovery codes. When the account had to set up an authenticator, this
+/// turns it on and returns the first recovery codes.
+pub async fn login(
+    State(state): State<AppState>,
+    address: ClientAddress,
+    Json(payload): Json<TwoFactorLoginRequest>,
+) -> Result<Response, ApiError> {
+    let user = two_factor_user(&state, &payload.two_factor_token).await?;
+    let two_factor = get_two_factor(&state, &user.id).await?;
+
+    let recovery_codes = match two_factor {
+        Some(two_factor) if two_factor.enabled => {
+            let valid = check_code(&state, &two_factor, &payload.code).await?
+                || state.db.use_recovery_code(&user.id, &totp::hash_recovery_code(&payload.code)).await
+                    .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))?;
+            if !valid {
+                return Err(ApiError::unauthorized("Invalid code"));
+            }
+            None
+        }
+        _ if is_required(&state, &user).await? => Some(enable(&state, &user.id, &payload.code).await?),
+        // Turned off since the password was checked, by the user or an admin
+        _ => None,
+    };
+
+    let session = start_session(&state, &user).await?;
+    audit::record(&state.db, audit::login_event(&user, &address, "two_factor")).await;
+
+    Ok(session_response(TwoFactorLoginResponse { session, recovery_codes }))
+}
+
+/// GET /api/user/2fa
+/// Get whether the caller uses two-factor authentication
+pub async fn get_status(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> ApiResult<TwoFactorStatus> {
+    let user = state.db.get_user_by_id(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get user: {}", e)))?;
+    let enabled = get_two_factor(&state, &user.id).await?.is_some_and(|two_factor| two_factor.enabled);
+    let recovery_codes_left = state.db.count_recovery_codes(&user.id).await
+        .map_err(|e| ApiError::internal_server_error(format!("Database error: {}", e)))?;
+
+    Ok(Json(ApiResponse::success("Two-factor authentication status", TwoFactorStatus {
+        enabled,
+        required: is_required(&state, &user).await?,
+        recovery_codes_left,
+    })))
+}
+
+/// POST /api/user/2fa/setup
+/// Start setting up an authenticator, replacing one set up but not turned on
+pub async fn setup(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+) -> ApiResult<TwoFactorSetup> {
+    let user = state.db.get_user_by_id(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get user: {}", e)))?;
+
+    Ok(Json(ApiResponse::success("Add the account to your authenticator, then enter a code from it", start_setup(&state, &user).await?)))
+}
+
+/// POST /api/user/2fa/enable
+/// Turn on two-factor authentication with a code from the authenticator just
+/// set up, returning recovery codes
+pub async fn enable_two_factor(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+    Json(payload): Json<CodeRequest>,
+) -> ApiResult<RecoveryCodes> {
+    let recovery_codes = enable(&state, &claims.sub, &payload.code).await?;
+    audit::record(&state.db, audit::event(&claims, &address, AuditAction::TwoFactorEnable).with_target(&claims.username)).await;
+
+    Ok(Json(ApiResponse::success("Two-factor authentication turned on", RecoveryCodes { recovery_codes })))
+}
+
+/// POST /api/user/2fa/recovery-codes
+/// Replace the caller's recovery codes, given a code from their authenticator
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    Json(payload): Json<CodeRequest>,
+) -> ApiResult<RecoveryCodes> {
+    let two_factor = get_two_factor(&state, &claims.sub).await?
+        .filter(|two_factor| two_factor.enabled)
+        .ok_or_else(|| ApiError::bad_request("Two-factor authentication is off"))?;
+    if !check_code(&state, &two_factor, &payload.code).await? {
+        return Err(ApiError::unauthorized("Invalid code"));
+    }
+
+    let recovery_codes = new_recovery_codes(&state, &claims.sub).await?;
+
+    Ok(Json(ApiResponse::success("Recovery codes replaced", RecoveryCodes { recovery_codes })))
+}
+
+/// DELETE /api/user/2fa
+/// Turn off two-factor authentication, given the caller's password
+pub async fn disable(
+    State(state): State<AppState>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
+    Json(payload): Json<DisableTwoFactorRequest>,
+) -> ApiResultNoData {
+    let user = state.db.get_user_by_id(&claims.sub).await
+        .map_err(|e| ApiError::internal_server_error(format!("Failed to get user: {}", e)))?;
+    if is_required(&state, &user).await? {
+        return Err(ApiError::forbidden("Your account has to use two-factor authentication"));
+    }
+
+    let is_valid = state.password_service.verify_password(&payload.password, &user.password_hash)
+        .map_err(|e| ApiError::internal_server_error(format!("Password verification failed: {}", e)))?;
+    if !is_valid {
+        return Err(ApiError::unauthorized("Incorrect password"));
+    }
+
+    state.db.delete_two_factor(&user.id).await
+        .map_err(|e| match e {
+            DbError::TwoFactorNotFound => ApiError::not_found("Two-factor authentication is off"),
+            e => ApiError::internal_server_error(format!("Failed to turn off two-factor authentication: {}", e)),
+        })?;
+    audit::record(&state.db, audit::event(&claims, &address, AuditAction::TwoFactorDisable).with_target(&claims.username)).await;
+
+    Ok(Json(ApiResponse::no_data("Two-factor authentication turned off")))
+}
@@ -1,9 +1,11 @@
-use axum::{extract::{Json, State, Extension}, http::StatusCode};
+use axum::{extract::{Json, State}, http::StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use crate::api::audit::{self, ClientAddress};
 use crate::api::response::{ApiResponse, ApiResult, ApiResultNoData, ApiError};
 use crate::api::auth::AppState;
-use crate::auth::jwt::Claims;
+use crate::auth::Authorized;
+use crate::db::models::AuditAction;
 
 #[derive(Debug, Deserialize)]
 pub struct UpdateUserRequest {
@@ -32,12 +34,12 @@ pub struct UserInfo {
     pub username: String,
     pub email: String,
     pub created_at: String,
-    pub is_admin: bool,
+    pub role: String,
 }
 
 pub async fn get_user_info(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized,
 ) -> ApiResult<UserInfo> {
     // Get user from database using ID from claims
     let user = state.db.get_user_by_id(&claims.sub).await
@@ -48,7 +50,7 @@ pub async fn get_user_info(
         email: user.email,
         created_at: user.created_at.format(&time::format_description::well_known::Rfc3339)
             .unwrap_or_else(|_| "2025-01-01T00:00:00Z".to_string()),
-        is_admin: user.is_admin,
+        role: user.role,
     };
     
     Ok(Json(ApiResponse::success("User info retrieved successfully", user_info)))
@@ -56,7 +58,7 @@ pub async fn get_user_info(
 
 pub async fn update_user_info(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized,
     Json(payload): Json<UpdateUserRequest>,
 ) -> ApiResultNoData {
     // Check if at least one field is being updated
@@ -78,8 +80,8 @@ pub async fn update_user_info(
         }
         
         // Check if username is already taken (but not by this user)
-        if new_username != &claims.username {
-            if state.db.username_exists(new_username).await
+        if new_username != &claims.username
+            && state.db.username_exists(new_username).await
                 .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Database error: {}", e)))? {
                 let mut errors = HashMap::new();
                 errors.insert("username".to_string(), "Username already exists".to_string());
@@ -89,7 +91,6 @@ pub async fn update_user_info(
                     errors,
                 ));
             }
-        }
         
         // Update username in database
         state.db.update_username(&claims.sub, new_username).await
@@ -137,7 +138,8 @@ pub async fn update_user_info(
 
 pub async fn change_password(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
     Json(payload): Json<ChangePasswordRequest>,
 ) -> ApiResultNoData {
     // Validate new password length
@@ -177,11 +179,17 @@ pub async fn change_password(
     state.db.update_user_password(&claims.sub, &new_password_hash).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to update password: {}", e)))?;
     
+    // Anyone else logged in with the old password is logged out
+    state.db.delete_user_refresh_tokens(&claims.sub, claims.session.as_deref()).await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to end other logins: {}", e)))?;
+    audit::record(&state.db, audit::event(&claims, &address, AuditAction::PasswordChange).with_target(&claims.username)).await;
+    
     Ok(Json(ApiResponse::no_data("Password changed successfully")))
 }
 
 pub async fn reset_password(
     State(state): State<AppState>,
+    _: Authorized,
     Json(payload): Json<ResetPasswordRequest>,
 ) -> ApiResultNoData {
     // Verify that the email exists in the database
@@ -200,7 +208,8 @@ pub async fn reset_password(
 
 pub async fn delete_account(
     State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
+    Authorized(claims, _): Authorized,
+    address: ClientAddress,
     Json(payload): Json<DeleteAccountRequest>,
 ) -> ApiResultNoData {
     // Get user from database
@@ -225,5 +234,11 @@ pub async fn delete_account(
     state.db.delete_user_by_id(&claims.sub).await
         .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to delete account: {}", e)))?;
     
+    let before = serde_json::json!({ "email": user.email, "role": user.role });
+    let event = audit::event(&claims, &address, AuditAction::AccountDelete)
+        .with_target(&claims.username)
+        .with_change(Some(before), None);
+    audit::record(&state.db, event).await;
+    
     Ok(Json(ApiResponse::no_data("Account deleted successfully")))
 }
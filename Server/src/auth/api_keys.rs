@@ -0,0 +1,197 @@
+use std::fmt;
+
+use axum::http::Method;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::auth::permissions::Permission;
+
+/// Start of every key, so they're easy to spot in scripts and secret scanners
+pub const KEY_PREFIX: &str = "muse_";
+/// Characters of a key kept in the clear, for telling keys apart
+const DISPLAY_PREFIX_LENGTH: usize = 12;
+/// Routes that stream or download songs
+const STREAM_ROUTES: &[&str] = &["/api/stream", "/api/downloads"];
+/// Routes playlist-write keys may change things on
+const PLAYLIST_ROUTES: &[&str] = &["/api/playlists"];
+
+/// What an API key may be used for. A key never gets more than its owner's
+/// role allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ApiKeyScope {
+    /// Browse the library and playlists
+    ReadOnly,
+    /// Stream songs and make download bundles
+    Stream,
+    /// Make and change the owner's playlists
+    PlaylistWrite,
+}
+
+impl ApiKeyScope {
+    pub const ALL: [ApiKeyScope; 3] = [ApiKeyScope::ReadOnly, ApiKeyScope::Stream, ApiKeyScope::PlaylistWrite];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::ReadOnly => "read-only",
+            ApiKeyScope::Stream => "stream",
+            ApiKeyScope::PlaylistWrite => "playlist-write",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        ApiKeyScope::ALL.into_iter().find(|s| s.as_str() == scope)
+    }
+
+    /// Permissions the scope asks for
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            ApiKeyScope::ReadOnly => &[Permission::Listen],
+            ApiKeyScope::Stream => &[Permission::Listen, Permission::Download],
+            ApiKeyScope::PlaylistWrite => &[Permission::Listen, Permission::WritePlaylists],
+        }
+    }
+}
+
+impl fmt::Display for ApiKeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ApiKeyScope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ApiKeyScope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let scope = String::deserialize(deserializer)?;
+        ApiKeyScope::parse(&scope)
+            .ok_or_else(|| serde::de::Error::custom(format!("Unknown API key scope: {}", scope)))
+    }
+}
+
+/// Read space-separated key scopes, sorted and without duplicates
+pub fn parse_scopes(scopes: &str) -> Vec<ApiKeyScope> {
+    let mut scopes: Vec<ApiKeyScope> = scopes.split_whitespace()
+        .filter_map(ApiKeyScope::parse)
+        .collect();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+/// Write key scopes space-separated
+pub fn format_scopes(scopes: &[ApiKeyScope]) -> String {
+    scopes.iter().map(ApiKeyScope::as_str).collect::<Vec<_>>().join(" ")
+}
+
+/// A new key, 256 random bits URL-safe after [`KEY_PREFIX`]. Only its
+/// [hash](hash_key) is stored.
+pub fn generate_key() -> String {
+    format!("{}{}", KEY_PREFIX, URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()))
+}
+
+/// SHA-256 of a key in hex. The keys are random enough that they don't need
+/// a slow hash.
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The start of a key, shown so its owner can tell which one a script has
+pub fn display_prefix(key: &str) -> String {
+    key.chars().take(DISPLAY_PREFIX_LENGTH).collect()
+}
+
+/// What a key with `scopes` may do for an owner whose role allows `role_permissions`
+pub fn key_permissions(scopes: &[ApiKeyScope], role_permissions: &[Permission]) -> Vec<Permission> {
+    let mut permissions: Vec<Permission> = scopes.iter()
+        .flat_map(|scope| scope.permissions().iter().copied())
+        .filter(|permission| role_permissions.contains(permission))
+        .collect();
+    permissions.sort();
+    permissions.dedup();
+    permissions
+}
+
+fn under(routes: &[&str], path: &str) -> bool {
+    routes.iter().any(|route| path == *route || path.strip_prefix(route).is_some_and(|rest| rest.starts_with('/')))
+}
+
+/// Whether a key with `scopes` may make a request, on top of the permission
+/// the handler checks. Only stream keys reach the streaming routes, and the
+/// only changes keys can make are to playlists and downloads.
+pub fn allows_request(scopes: &[ApiKeyScope], method: &Method, path: &str) -> bool {
+    let stream = scopes.contains(&ApiKeyScope::Stream);
+    if under(STREAM_ROUTES, path) {
+        return stream;
+    }
+    if method == Method::GET || method == Method::HEAD {
+        return true;
+    }
+    scopes.contains(&ApiKeyScope::PlaylistWrite) && under(PLAYLIST_ROUTES, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_round_trip() {
+        for scope in ApiKeyScope::ALL {
+            assert_eq!(ApiKeyScope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(parse_scopes(" stream read-only stream admin "),
+            vec![ApiKeyScope::ReadOnly, ApiKeyScope::Stream]);
+        assert_eq!(parse_scopes(&format_scopes(&ApiKeyScope::ALL)), ApiKeyScope::ALL.to_vec());
+        assert!(serde_json::from_str::<ApiKeyScope>("\"users:manage\"").is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        let key = generate_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(key.len(), KEY_PREFIX.len() + 43);
+        assert_ne!(key, generate_key());
+        assert_eq!(hash_key(&key), hash_key(&key));
+        assert_eq!(hash_key(&key).len(), 64);
+        assert_eq!(display_prefix(&key).len(), DISPLAY_PREFIX_LENGTH);
+        assert!(key.starts_with(&display_prefix(&key)));
+    }
+
+    #[test]
+    fn test_key_permissions_are_limited_by_role() {
+        use Permission::*;
+        let listener = [Listen, Download, WritePlaylists, ShareLinks];
+        assert_eq!(key_permissions(&[ApiKeyScope::ReadOnly], &listener), vec![Listen]);
+        assert_eq!(key_permissions(&ApiKeyScope::ALL, &Permission::ALL), vec![Listen, Download, WritePlaylists]);
+        assert_eq!(key_permissions(&[ApiKeyScope::Stream, ApiKeyScope::PlaylistWrite], &[Listen]), vec![Listen]);
+        assert!(key_permissions(&ApiKeyScope::ALL, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_allowed_requests() {
+        let read = [ApiKeyScope::ReadOnly];
+        let stream = [ApiKeyScope::Stream];
+        let playlists = [ApiKeyScope::PlaylistWrite];
+
+        assert!(allows_request(&read, &Method::GET, "/api/songs"));
+        assert!(!allows_request(&read, &Method::GET, "/api/stream"));
+        assert!(!allows_request(&read, &Method::POST, "/api/stream/sign"));
+        assert!(!allows_request(&read, &Method::POST, "/api/playlists/song/add"));
+        assert!(!allows_request(&read, &Method::PUT, "/api/user/password"));
+
+        assert!(allows_request(&stream, &Method::GET, "/api/stream"));
+        assert!(allows_request(&stream, &Method::POST, "/api/stream/sign"));
+        assert!(allows_request(&stream, &Method::POST, "/api/downloads"));
+        assert!(!allows_request(&stream, &Method::DELETE, "/api/playlists"));
+        assert!(allows_request(&read, &Method::GET, "/api/streams"));
+
+        assert!(allows_request(&playlists, &Method::POST, "/api/playlists/song/add"));
+        assert!(allows_request(&playlists, &Method::DELETE, "/api/playlists"));
+        assert!(!allows_request(&playlists, &Method::POST, "/api/user/api-keys"));
+        assert!(!allows_request(&playlists, &Method::GET, "/api/downloads"));
+    }
+}
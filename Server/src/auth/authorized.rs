@@ -0,0 +1,100 @@
+use std::marker::PhantomData;
+
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+};
+
+use crate::api::response::ApiError;
+use crate::auth::jwt::Claims;
+use crate::auth::middleware::{authenticate, AuthState};
+use crate::auth::permissions::Permission;
+
+/// Refuse claims without `permission` with 403, for handlers that check
+/// tokens themselves
+pub fn require(claims: &Claims, permission: Permission) -> Result<(), ApiError> {
+    if claims.can(permission) {
+        Ok(())
+    } else {
+        Err(ApiError::forbidden(format!("Requires the {} permission", permission)))
+    }
+}
+
+/// A permission a handler needs, named by a type from [`scope`]
+pub trait RequiredPermission: Send + Sync + 'static {
+    /// `None` when any signed-in user will do
+    const PERMISSION: Option<Permission>;
+}
+
+/// Marker types for [`Authorized`], one for each [`Permission`]
+pub mod scope {
+    use super::*;
+
+    /// Any signed-in user
+    pub struct Authenticated;
+
+    impl RequiredPermission for Authenticated {
+        const PERMISSION: Option<Permission> = None;
+    }
+
+    macro_rules! permission_scopes {
+        ($($name:ident),* $(,)?) => {
+            $(
+                #[doc = concat!("Users whose token has [`Permission::", stringify!($name), "`]")]
+                pub struct $name;
+
+                impl RequiredPermission for $name {
+                    const PERMISSION: Option<Permission> = Some(Permission::$name);
+                }
+            )*
+        };
+    }
+
+    permission_scopes!(
+        Listen,
+        Download,
+        WritePlaylists,
+        ShareLinks,
+        Upload,
+        EditSongs,
+        CuratePlaylists,
+        ManageLibrary,
+        ManageUsers,
+    );
+}
+
+/// The claims of a request whose token allows `P`, rejecting it with 401
+/// without a valid token and 403 without the permission:
+///
+/// ```ignore
+/// pub async fn edit_song(Authorized(claims, _): Authorized<scope::EditSongs>, ...)
+/// ```
+///
+/// Claims a middleware already put in the request, such as those of a
+/// signed stream URL, are used in place of a token.
+pub struct Authorized<P: RequiredPermission = scope::Authenticated>(pub Claims, pub PhantomData<P>);
+
+impl<S, P> FromRequestParts<S> for Authorized<P>
+where
+    AuthState: FromRef<S>,
+    S: Send + Sync,
+    P: RequiredPermission,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = match parts.extensions.get::<Claims>() {
+            Some(claims) => claims.clone(),
+            None => {
+                let claims = authenticate(&AuthState::from_ref(state), parts).await?;
+                parts.extensions.insert(claims.clone());
+                claims
+            }
+        };
+
+        if let Some(permission) = P::PERMISSION {
+            require(&claims, permission)?;
+        }
+        Ok(Authorized(claims, PhantomData))
+    }
+}
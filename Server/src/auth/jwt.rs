@@ -1,48 +1,218 @@
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+use crate::auth::permissions::{scope_string, Permission};
+
+/// Share link tokens can't be refreshed, so they keep the lifetime user
+/// tokens had before refresh tokens
+const SHARE_TOKEN_HOURS: i64 = 24;
+/// Playlist tokens outlive the access token they're made from, so a long
+/// song or film keeps playing after the player's login has been refreshed
+const PLAYLIST_TOKEN_HOURS: i64 = 12;
+/// How long a user has to enter their second factor after their password
+pub const TWO_FACTOR_TOKEN_MINUTES: i64 = 5;
 
 #[derive(Clone)]
 pub struct JwtService {
     encoding_key: EncodingKey,
     decoding_key: DecodingKey,
-    expiration_hours: i64,
+    access_token_minutes: i64,
+    /// How long a refresh token can go unused before its login ends
+    refresh_token_days: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,      // Subject (user ID)
     pub username: String, // Username
-    pub is_admin: bool,   // Admin status
+    /// Role the user had when the token was issued
+    #[serde(default)]
+    pub role: String,
+    /// What the role allowed when the token was issued, as a space-separated
+    /// `scope`. Role changes apply from the next login or refresh.
+    #[serde(rename = "scope", with = "scope_string", default)]
+    pub permissions: Vec<Permission>,
     pub exp: i64,         // Expiration time
     pub iat: i64,         // Issued at
+    /// Set on stream-only tokens issued for a public share link,
+    /// which are only accepted for the link's songs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_link: Option<String>,
+    /// Set on tokens put in the URLs of HLS and DASH playlists, which only
+    /// fetch that song's playlists and segments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist_song: Option<String>,
+    /// Refresh token family of the login the token was issued for, which
+    /// logging out ends
+    #[serde(rename = "sid", default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Set on the partial tokens a password gets accounts with two-factor
+    /// authentication, which are only good for passing the second factor
+    #[serde(rename = "2fa", default, skip_serializing_if = "std::ops::Not::not")]
+    pub two_factor_pending: bool,
+}
+
+impl Claims {
+    /// Whether the token allows `permission`
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
 }
 
 impl JwtService {
-    pub fn new(secret: &str, expiration_hours: i64) -> Self {
+    pub fn new(secret: &str, access_token_minutes: i64, refresh_token_days: i64) -> Self {
         Self {
             encoding_key: EncodingKey::from_secret(secret.as_bytes()),
             decoding_key: DecodingKey::from_secret(secret.as_bytes()),
-            expiration_hours,
+            access_token_minutes,
+            refresh_token_days,
         }
     }
 
-    pub fn generate_token(&self, user_id: &str, username: &str, is_admin: bool) -> Result<String, jsonwebtoken::errors::Error> {
+    /// Seconds an access token lasts
+    pub fn access_token_lifetime(&self) -> i64 {
+        self.access_token_minutes * 60
+    }
+
+    /// Generate a short-lived access token for a user, in the login `session`
+    /// whose refresh tokens renew it
+    pub fn generate_access_token(&self, user_id: &str, username: &str, role: &str, permissions: &[Permission], session: &str) -> Result<String, jsonwebtoken::errors::Error> {
         let now = OffsetDateTime::now_utc().unix_timestamp();
-        let expiration = now + (self.expiration_hours * 3600);
+        let expiration = now + self.access_token_lifetime();
 
         let claims = Claims {
             sub: user_id.to_string(),
             username: username.to_string(),
-            is_admin,
+            role: role.to_string(),
+            permissions: permissions.to_vec(),
             exp: expiration,
             iat: now,
+            share_link: None,
+            playlist_song: None,
+            session: Some(session.to_string()),
+            two_factor_pending: false,
         };
 
         encode(&Header::default(), &claims, &self.encoding_key)
     }
 
+    /// Generate an opaque refresh token, 256 random bits URL-safe, and when it
+    /// expires. Only its [hash](Self::hash_refresh_token) is stored.
+    pub fn generate_refresh_token(&self) -> (String, OffsetDateTime) {
+        let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        (token, OffsetDateTime::now_utc() + Duration::days(self.refresh_token_days))
+    }
+
+    /// SHA-256 of a refresh token, in hex. The tokens are random enough that
+    /// they don't need a slow hash.
+    pub fn hash_refresh_token(token: &str) -> String {
+        Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Generate a partial token for a user who has given their password but
+    /// not yet their second factor. It carries no permissions.
+    pub fn generate_two_factor_token(&self, user_id: &str, username: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        let claims = Claims {
+            sub: user_id.to_string(),
+            username: username.to_string(),
+            role: String::new(),
+            permissions: Vec::new(),
+            exp: now + TWO_FACTOR_TOKEN_MINUTES * 60,
+            iat: now,
+            share_link: None,
+            playlist_song: None,
+            session: None,
+            two_factor_pending: true,
+        };
+
+        encode(&Header::default(), &claims, &self.encoding_key)
+    }
+
+    /// Generate a stream-only token for a public share link, expiring with
+    /// the link when that comes before the usual token lifetime
+    pub fn generate_share_token(&self, link_id: &str, link_expires_at: Option<i64>) -> Result<(String, i64), jsonwebtoken::errors::Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let expiration = link_expires_at
+            .map_or(now + SHARE_TOKEN_HOURS * 3600, |link_expiry| link_expiry.min(now + SHARE_TOKEN_HOURS * 3600));
+
+        let claims = Claims {
+            sub: format!("share:{}", link_id),
+            username: String::new(),
+            role: String::new(),
+            permissions: vec![Permission::Listen],
+            exp: expiration,
+            iat: now,
+            share_link: Some(link_id.to_string()),
+            playlist_song: None,
+            session: None,
+            two_factor_pending: false,
+        };
+
+        encode(&Header::default(), &claims, &self.encoding_key).map(|token| (token, expiration))
+    }
+
+    /// Generate a token for the URLs in a song's HLS and DASH playlists, for
+    /// players that can't send headers. It lasts `PLAYLIST_TOKEN_HOURS`, but
+    /// never longer than a share link or playlist token it's made from, and
+    /// keeps its share link scope while only letting its holder listen.
+    pub fn generate_playlist_token(&self, claims: &Claims, song_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut expiration = now + PLAYLIST_TOKEN_HOURS * 3600;
+        if claims.share_link.is_some() || claims.playlist_song.is_some() {
+            expiration = expiration.min(claims.exp);
+        }
+
+        let claims = Claims {
+            sub: claims.sub.clone(),
+            username: claims.username.clone(),
+            role: claims.role.clone(),
+            permissions: claims.permissions.iter().copied().filter(|&p| p == Permission::Listen).collect(),
+            exp: expiration,
+            iat: now,
+            share_link: claims.share_link.clone(),
+            playlist_song: Some(song_id.to_string()),
+            session: None,
+            two_factor_pending: false,
+        };
+
+        encode(&Header::default(), &claims, &self.encoding_key)
+    }
+
+    /// Verify a token for general use. Playlist and partial two-factor tokens
+    /// are refused.
     pub fn verify_token(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let claims = self.decode(token)?;
+        if claims.playlist_song.is_some() || claims.two_factor_pending {
+            return Err(ErrorKind::InvalidToken.into());
+        }
+        Ok(claims)
+    }
+
+    /// Verify a partial token from a password login, refusing any other
+    pub fn verify_two_factor_token(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let claims = self.decode(token)?;
+        if !claims.two_factor_pending {
+            return Err(ErrorKind::InvalidToken.into());
+        }
+        Ok(claims)
+    }
+
+    /// Verify a token for a song's playlists: any token [`verify_token`](Self::verify_token)
+    /// accepts, or a playlist token for that song
+    pub fn verify_playlist_token(&self, token: &str, song_id: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let claims = self.decode(token)?;
+        if claims.two_factor_pending || claims.playlist_song.as_deref().is_some_and(|song| song != song_id) {
+            return Err(ErrorKind::InvalidToken.into());
+        }
+        Ok(claims)
+    }
+
+    fn decode(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
         let token_data = decode::<Claims>(
             token,
             &self.decoding_key,
@@ -51,39 +221,115 @@ impl JwtService {
 
         Ok(token_data.claims)
     }
-
-    #[allow(dead_code)]
-    pub fn refresh_token(&self, old_token: &str) -> Result<String, jsonwebtoken::errors::Error> {
-        let claims = self.verify_token(old_token)?;
-        self.generate_token(&claims.sub, &claims.username, claims.is_admin)
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::permissions::{ADMIN_ROLE, DEFAULT_ROLE};
 
     #[test]
     fn test_jwt_generation_and_verification() {
-        let jwt_service = JwtService::new("test_secret_key_for_testing", 24);
-        let token = jwt_service.generate_token("user123", "testuser", false).unwrap();
+        let jwt_service = JwtService::new("test_secret_key_for_testing", 15, 30);
+        let token = jwt_service.generate_access_token("user123", "testuser", DEFAULT_ROLE, &[Permission::Listen, Permission::Download], "family1").unwrap();
         
         let claims = jwt_service.verify_token(&token).unwrap();
         assert_eq!(claims.sub, "user123");
         assert_eq!(claims.username, "testuser");
-        assert_eq!(claims.is_admin, false);
+        assert_eq!(claims.role, DEFAULT_ROLE);
+        assert!(claims.can(Permission::Download));
+        assert!(!claims.can(Permission::ManageUsers));
+        assert_eq!(claims.session.as_deref(), Some("family1"));
+        assert_eq!(claims.exp - claims.iat, 15 * 60);
     }
 
     #[test]
-    fn test_jwt_refresh() {
-        let jwt_service = JwtService::new("test_secret_key_for_testing", 24);
-        let original_token = jwt_service.generate_token("user123", "testuser", true).unwrap();
-        
-        let new_token = jwt_service.refresh_token(&original_token).unwrap();
-        let claims = jwt_service.verify_token(&new_token).unwrap();
-        
+    fn test_refresh_tokens() {
+        let jwt_service = JwtService::new("test_secret_key_for_testing", 15, 30);
+        let (token, expires_at) = jwt_service.generate_refresh_token();
+        let (other, _) = jwt_service.generate_refresh_token();
+        assert_ne!(token, other);
+        assert!(jwt_service.verify_token(&token).is_err());
+
+        let lifetime = expires_at - OffsetDateTime::now_utc();
+        assert!(lifetime > Duration::days(30) - Duration::minutes(1) && lifetime <= Duration::days(30));
+
+        let hash = JwtService::hash_refresh_token(&token);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, JwtService::hash_refresh_token(&token));
+        assert_ne!(hash, JwtService::hash_refresh_token(&other));
+    }
+
+    #[test]
+    fn test_share_token_scope() {
+        let jwt_service = JwtService::new("test_secret_key_for_testing", 15, 30);
+        let link_expiry = OffsetDateTime::now_utc().unix_timestamp() + 60;
+        let (token, expires_at) = jwt_service.generate_share_token("link123", Some(link_expiry)).unwrap();
+
+        let claims = jwt_service.verify_token(&token).unwrap();
+        assert_eq!(claims.share_link.as_deref(), Some("link123"));
+        assert_eq!(claims.exp, link_expiry);
+        assert_eq!(expires_at, link_expiry);
+        assert_eq!(claims.permissions, vec![Permission::Listen]);
+
+        let user_token = jwt_service.generate_access_token("user123", "testuser", DEFAULT_ROLE, &[Permission::Listen], "family1").unwrap();
+        assert!(jwt_service.verify_token(&user_token).unwrap().share_link.is_none());
+    }
+
+    #[test]
+    fn test_playlist_token_scope() {
+        let jwt_service = JwtService::new("test_secret_key_for_testing", 15, 30);
+        let user_token = jwt_service.generate_access_token("user123", "testuser", ADMIN_ROLE, &Permission::ALL, "family1").unwrap();
+        let claims = jwt_service.verify_token(&user_token).unwrap();
+        let token = jwt_service.generate_playlist_token(&claims, "song1").unwrap();
+
+        let playlist_claims = jwt_service.verify_playlist_token(&token, "song1").unwrap();
+        assert_eq!(playlist_claims.sub, "user123");
+        assert_eq!(playlist_claims.exp - playlist_claims.iat, PLAYLIST_TOKEN_HOURS * 3600);
+        assert_eq!(playlist_claims.permissions, vec![Permission::Listen]);
+        assert!(jwt_service.verify_playlist_token(&token, "song2").is_err());
+        assert!(jwt_service.verify_token(&token).is_err());
+        assert!(jwt_service.verify_playlist_token(&user_token, "song2").is_ok());
+
+        // A token made from a playlist token can't extend it
+        let renewed = jwt_service.generate_playlist_token(&playlist_claims, "song1").unwrap();
+        let renewed_claims = jwt_service.verify_playlist_token(&renewed, "song1").unwrap();
+        assert!(renewed_claims.exp <= playlist_claims.exp);
+    }
+
+    #[test]
+    fn test_two_factor_token_scope() {
+        let jwt_service = JwtService::new("test_secret_key_for_testing", 15, 30);
+        let token = jwt_service.generate_two_factor_token("user123", "testuser").unwrap();
+
+        let claims = jwt_service.verify_two_factor_token(&token).unwrap();
         assert_eq!(claims.sub, "user123");
-        assert_eq!(claims.username, "testuser");
-        assert_eq!(claims.is_admin, true);
+        assert!(claims.permissions.is_empty());
+        assert_eq!(claims.exp - claims.iat, TWO_FACTOR_TOKEN_MINUTES * 60);
+        assert!(jwt_service.verify_token(&token).is_err());
+        assert!(jwt_service.verify_playlist_token(&token, "song1").is_err());
+
+        let user_token = jwt_service.generate_access_token("user123", "testuser", DEFAULT_ROLE, &[Permission::Listen], "family1").unwrap();
+        assert!(jwt_service.verify_two_factor_token(&user_token).is_err());
+    }
+
+    #[test]
+    fn test_tokens_without_scope_allow_nothing() {
+        #[derive(Serialize)]
+        struct OldClaims {
+            sub: String,
+            username: String,
+            is_admin: bool,
+            exp: i64,
+            iat: i64,
+        }
+        let jwt_service = JwtService::new("test_secret_key_for_testing", 15, 30);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let old = OldClaims { sub: "user123".to_string(), username: "testuser".to_string(), is_admin: true, exp: now + 60, iat: now };
+        let token = encode(&Header::default(), &old, &jwt_service.encoding_key).unwrap();
+
+        let claims = jwt_service.verify_token(&token).unwrap();
+        assert!(claims.permissions.is_empty());
+        assert!(!claims.can(Permission::Listen));
     }
 }
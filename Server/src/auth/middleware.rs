@@ -1,37 +1,54 @@
 use axum::{
-    extract::{Request, State},
-    http::{header, StatusCode},
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, FromRequestParts, OriginalUri, Query, RawPathParams, Request, State},
+    http::{header, request::Parts, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use time::OffsetDateTime;
 
-use crate::auth::jwt::JwtService;
+use crate::auth::api_keys::{allows_request, hash_key, key_permissions};
+use crate::auth::jwt::{Claims, JwtService};
+use crate::auth::permissions::Permission;
+use crate::auth::rate_limit::RateLimiter;
+use crate::auth::stream_url::{SignatureError, SignedStreamQuery, StreamUrlSigner};
+use crate::api::audit;
 use crate::api::response::ApiError;
+use crate::db::{Database, DbError};
+use crate::db::models::{AuditAction, AuditEvent};
 
 #[derive(Clone)]
 pub struct AuthState {
     pub jwt_service: Arc<JwtService>,
+    pub db: Arc<dyn Database>,
+    pub stream_signer: Arc<StreamUrlSigner>,
+    pub rate_limiter: Arc<RateLimiter>,
 }
 
-/// Middleware to require authentication for a route
-pub async fn require_auth(
-    State(state): State<AuthState>,
-    mut request: Request,
-    next: Next,
-) -> Result<Response, ApiError> {
-    // Extract token from Authorization header
-    let token = request
-        .headers()
+/// Routes a share link token may be used on
+const SHARE_LINK_ROUTES: &[&str] = &["/api/stream"];
+/// Header scripts send their API key in, in place of a token
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Seconds between writes of a key's last use, so busy scripts don't write
+/// on every request
+const API_KEY_TOUCH_SECONDS: i64 = 60;
+
+/// Verify the Bearer token or API key of a request. Share link tokens are
+/// only let through to the routes they may be used on.
+pub async fn authenticate(state: &AuthState, parts: &Parts) -> Result<Claims, ApiError> {
+    if let Some(key) = parts.headers.get(API_KEY_HEADER) {
+        let key = key.to_str().map_err(|_| ApiError::unauthorized("Invalid API key"))?;
+        return authenticate_api_key(state, parts, key.trim()).await;
+    }
+
+    let token = parts
+        .headers
         .get(header::AUTHORIZATION)
         .and_then(|value| value.to_str().ok())
-        .and_then(|value| {
-            if value.starts_with("Bearer ") {
-                Some(&value[7..])
-            } else {
-                None
-            }
-        })
+        .and_then(|value| value.strip_prefix("Bearer "))
         .ok_or_else(|| {
             ApiError::new(
                 StatusCode::UNAUTHORIZED,
@@ -39,7 +56,6 @@ pub async fn require_auth(
             )
         })?;
 
-    // Verify token
     let claims = state
         .jwt_service
         .verify_token(token)
@@ -47,55 +63,208 @@ pub async fn require_auth(
             ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired token")
         })?;
 
-    // Add claims to request extensions
-    request.extensions_mut().insert(claims);
+    if claims.share_link.is_some() {
+        let path = parts.extensions.get::<OriginalUri>()
+            .map(|uri| uri.path().to_string())
+            .unwrap_or_else(|| parts.uri.path().to_string());
+        check_share_link_token(state, &claims, &path).await?;
+    }
 
-    Ok(next.run(request).await)
+    Ok(claims)
+}
+
+/// Claims for a request made with an API key, which has the permissions its
+/// scopes ask for that the owner's role still allows
+async fn authenticate_api_key(state: &AuthState, parts: &Parts, key: &str) -> Result<Claims, ApiError> {
+    let api_key = state.db.get_api_key_by_hash(&hash_key(key)).await
+        .map_err(|e| match e {
+            DbError::ApiKeyNotFound => ApiError::unauthorized("Invalid API key"),
+            e => ApiError::internal_server_error(format!("Database error: {}", e)),
+        })?;
+
+    let path = parts.extensions.get::<OriginalUri>()
+        .map(|uri| uri.path().to_string())
+        .unwrap_or_else(|| parts.uri.path().to_string());
+    if !allows_request(&api_key.scopes, &parts.method, &path) {
+        return Err(ApiError::forbidden("This API key's scopes don't allow this request"));
+    }
+
+    let user = state.db.get_user_by_id(&api_key.user_id).await
+        .map_err(|_| ApiError::unauthorized("Invalid API key"))?;
+    let role_permissions = match state.db.get_role(&user.role).await {
+        Ok(role) => role.permissions,
+        Err(DbError::RoleNotFound) => Vec::new(),
+        Err(e) => return Err(ApiError::internal_server_error(format!("Database error: {}", e))),
+    };
+
+    let now = OffsetDateTime::now_utc();
+    if api_key.last_used_at.is_none_or(|last_used_at| (now - last_used_at).whole_seconds() >= API_KEY_TOUCH_SECONDS)
+        && let Err(e) = state.db.touch_api_key(&api_key.id).await {
+        tracing::warn!("Failed to record use of API key {}: {}", api_key.id, e);
+    }
+
+    Ok(Claims {
+        sub: user.id,
+        username: user.username,
+        role: user.role,
+        permissions: key_permissions(&api_key.scopes, &role_permissions),
+        exp: now.unix_timestamp() + state.jwt_service.access_token_lifetime(),
+        iat: now.unix_timestamp(),
+        share_link: None,
+        playlist_song: None,
+        session: None,
+        two_factor_pending: false,
+    })
 }
 
-/// Middleware to require admin authentication for a route
-pub async fn require_admin(
+#[derive(Deserialize)]
+struct SignatureQuery {
+    signature: Option<String>,
+}
+
+/// Middleware for the streaming route, which takes a signed URL from
+/// `POST /api/stream/sign` in place of a token, for media elements and
+/// receivers that can't send headers. Requests without a signature are left
+/// for the handler's [`Authorized`](crate::auth::Authorized) extractor.
+pub async fn signed_stream_auth(
     State(state): State<AuthState>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, ApiError> {
-    // Extract token from Authorization header
-    let token = request
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|value| value.to_str().ok())
-        .and_then(|value| {
-            if value.starts_with("Bearer ") {
-                Some(&value[7..])
-            } else {
-                None
-            }
-        })
-        .ok_or_else(|| {
-            ApiError::new(
-                StatusCode::UNAUTHORIZED,
-                "Missing or invalid Authorization header",
-            )
-        })?;
+    let signed = Query::<SignatureQuery>::try_from_uri(request.uri())
+        .is_ok_and(|Query(query)| query.signature.is_some());
+    if !signed {
+        return Ok(next.run(request).await);
+    }
 
-    // Verify token
-    let claims = state
-        .jwt_service
-        .verify_token(token)
-        .map_err(|_| {
-            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired token")
+    let Query(query) = Query::<SignedStreamQuery>::try_from_uri(request.uri())
+        .map_err(|_| ApiError::unauthorized("Invalid signed URL"))?;
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    if query.bound && peer.is_none() {
+        return Err(ApiError::unauthorized("Invalid signed URL"));
+    }
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    state.stream_signer.verify(&query.grant(peer), &query.signature, now)
+        .map_err(|e| match e {
+            SignatureError::Expired => ApiError::unauthorized("Signed URL has expired"),
+            SignatureError::Invalid => ApiError::unauthorized("Invalid signed URL"),
         })?;
 
-    // Check if user is admin
-    if !claims.is_admin {
-        return Err(ApiError::new(
-            StatusCode::FORBIDDEN,
-            "Admin access required",
-        ));
+    // The handler sees the user who signed the URL, scoped like their token was
+    let claims = Claims {
+        sub: query.user,
+        username: String::new(),
+        role: String::new(),
+        permissions: vec![Permission::Listen],
+        exp: query.expires,
+        iat: now,
+        share_link: query.share,
+        playlist_song: None,
+        session: None,
+        two_factor_pending: false,
+    };
+    if claims.share_link.is_some() {
+        check_share_link_token(&state, &claims, request.uri().path()).await?;
     }
-
-    // Add claims to request extensions
     request.extensions_mut().insert(claims);
 
     Ok(next.run(request).await)
 }
+
+/// Largest body the rate limited routes read
+const MAX_ATTEMPT_BODY: usize = 64 * 1024;
+
+/// Put on the response to a login that started a session, so the login
+/// rate limit forgets the account's failures. A login that still needs its
+/// second factor doesn't get it, or a known password would reset the count of
+/// wrong codes.
+#[derive(Clone, Copy)]
+pub struct LoginSucceeded;
+
+/// The account a share link password attempt counts against, named so it
+/// can't be taken for a username
+fn share_link_account(token: &str) -> String {
+    format!("share:{}", token)
+}
+
+/// The parts of a login attempt that name the account it's on
+#[derive(Deserialize)]
+struct AttemptBody {
+    username: Option<String>,
+    two_factor_token: Option<String>,
+}
+
+/// Middleware for logins and the other routes that check a password or code.
+/// Attempts are refused with 429 and `Retry-After` while their address or
+/// account has to wait, and those answered with 401 or 404 count as failures.
+/// A completed login clears its account's failures. Share link passwords count against the link, as `share:<token>`.
+pub async fn rate_limit_auth(
+    State(state): State<AuthState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let address = peer.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    let (mut parts, body) = request.into_parts();
+    let path = parts.uri.path().to_string();
+    let share_link = RawPathParams::from_request_parts(&mut parts, &state).await.ok()
+        .and_then(|params| params.iter().find(|(name, _)| *name == "token").map(|(_, token)| share_link_account(token)));
+    let body = match to_bytes(body, MAX_ATTEMPT_BODY).await {
+        Ok(body) => body,
+        Err(_) => return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large").into_response(),
+    };
+    let account = serde_json::from_slice::<AttemptBody>(&body).ok()
+        .and_then(|attempt| match attempt.two_factor_token {
+            Some(token) => state.jwt_service.verify_two_factor_token(&token).ok().map(|claims| claims.username),
+            None => attempt.username.map(|username| username.trim().to_string()),
+        })
+        .filter(|account| !account.is_empty())
+        .or(share_link);
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    if let Err(wait) = state.rate_limiter.check(address, account.as_deref(), now) {
+        tracing::warn!("Refused login attempt from {} on {:?}, {} seconds to wait", address, account, wait);
+        let mut response = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Too many attempts; try again in {} seconds", wait),
+        ).into_response();
+        response.headers_mut().insert(header::RETRY_AFTER, wait.into());
+        return response;
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if response.status().is_success() && response.extensions().get::<LoginSucceeded>().is_some() {
+        if let Some(account) = &account {
+            state.rate_limiter.record_success(account);
+        }
+        return response;
+    }
+    if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::NOT_FOUND) {
+        state.rate_limiter.record_failure(address, account.as_deref(), now);
+
+        let mut event = AuditEvent::new(None, "anonymous", AuditAction::LoginFailed)
+            .with_change(None, Some(serde_json::json!({ "path": path, "status": response.status().as_u16() })))
+            .with_ip(peer);
+        event.target = account;
+        audit::record(&state.db, event).await;
+    }
+    response
+}
+
+/// Share link tokens only reach the streaming routes, and stop working as
+/// soon as their link is revoked or expires. Handlers check the song itself.
+async fn check_share_link_token(state: &AuthState, claims: &Claims, path: &str) -> Result<(), ApiError> {
+    let allowed = SHARE_LINK_ROUTES.iter()
+        .any(|route| path == *route || path.strip_prefix(route).is_some_and(|rest| rest.starts_with('/')));
+    if !allowed {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Share link tokens can only stream"));
+    }
+
+    let link_id = claims.share_link.as_deref().unwrap_or_default();
+    match state.db.get_share_link(link_id).await {
+        Ok(link) if !link.is_expired() => Ok(()),
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "Share link has been revoked or has expired")),
+    }
+}
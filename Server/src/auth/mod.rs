@@ -1,6 +1,16 @@
 pub mod jwt;
 pub mod password;
 pub mod middleware;
+pub mod permissions;
+pub mod authorized;
+pub mod stream_url;
+pub mod oidc;
+pub mod totp;
+pub mod webauthn;
+pub mod api_keys;
+pub mod rate_limit;
 
 pub use jwt::{JwtService, Claims};
 pub use password::PasswordService;
+pub use authorized::{scope, Authorized};
+pub use permissions::Permission;
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+/// How long a user has to finish logging in at the provider
+const LOGIN_TTL: Duration = Duration::minutes(10);
+/// Logins in progress at once, as anyone can start one
+const MAX_PENDING_LOGINS: usize = 10_000;
+
+#[derive(Debug, Error)]
+pub enum OidcError {
+    #[error("Identity provider request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Identity provider returned HTTP {0}")]
+    Status(StatusCode),
+
+    #[error("Identity provider says its issuer is {0}")]
+    IssuerMismatch(String),
+
+    #[error("Invalid identity provider metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Unknown or expired login")]
+    UnknownLogin,
+
+    #[error("Too many logins in progress, try again later")]
+    TooManyLogins,
+
+    #[error("Invalid ID token: {0}")]
+    InvalidToken(String),
+}
+
+/// An OpenID Connect provider users can log in with
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer URL, which the discovery document is found under
+    pub issuer: String,
+    pub client_id: String,
+    /// Sent to the token endpoint, and the key of HS256 ID tokens.
+    /// Public clients have none and rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends the browser back to, a page of the client
+    /// that passes the code and state on to `/api/oidc/callback`
+    pub redirect_url: String,
+    /// Space-separated scopes to ask for
+    pub scopes: String,
+    /// Roles to give users from their claims, when the provider manages them
+    pub roles: Option<RoleMapping>,
+    /// Create accounts for people who log in for the first time
+    pub auto_provision: bool,
+    /// Link to the account with the same email when the provider has verified it
+    pub link_by_email: bool,
+}
+
+/// Picks a role from the values of a claim, e.g. the `groups` claim with
+/// `muse-admins=admin,muse-curators=curator`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoleMapping {
+    /// Claim name, with dots for nested claims like `realm_access.roles`
+    pub claim: String,
+    /// Claim value and role, in order of preference
+    pub rules: Vec<(String, String)>,
+    /// Role for users none of the rules match
+    pub default_role: String,
+}
+
+impl RoleMapping {
+    /// Parse `value=role` pairs separated by commas, skipping malformed ones
+    pub fn parse(claim: &str, rules: &str, default_role: &str) -> Self {
+        Self {
+            claim: claim.to_string(),
+            rules: rules.split(',')
+                .filter_map(|rule| rule.split_once('='))
+                .map(|(value, role)| (value.trim().to_string(), role.trim().to_string()))
+                .filter(|(value, role)| !value.is_empty() && !role.is_empty())
+                .collect(),
+            default_role: default_role.to_string(),
+        }
+    }
+
+    /// The role of the first rule whose value the claim has, or the default.
+    /// The claim can be a string or an array of strings.
+    pub fn role_for(&self, claims: &Map<String, Value>) -> &str {
+        let mut path = self.claim.split('.');
+        let mut value = path.next().and_then(|name| claims.get(name));
+        for name in path {
+            value = value.and_then(|value| value.get(name));
+        }
+        let values: Vec<&str> = match value {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        self.rules.iter()
+            .find(|(value, _)| values.contains(&value.as_str()))
+            .map_or(&self.default_role, |(_, role)| role)
+    }
+}
+
+/// Who the provider says logged in, from a verified ID token
+#[derive(Debug, Clone, Deserialize)]
+pub struct Identity {
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    /// The other claims, for role mapping
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+/// A login that came back from the provider
+#[derive(Debug)]
+pub struct CompletedLogin {
+    pub identity: Identity,
+    /// The user who started it to link their account, if one did
+    pub link_user: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    link_user: Option<String>,
+    expires_at: OffsetDateTime,
+}
+
+/// Runs authorization code logins with PKCE against an OpenID Connect provider.
+/// The provider's metadata is discovered on first use and its signing keys are
+/// fetched again when a token names one it hasn't seen.
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    metadata: Mutex<Option<Arc<ProviderMetadata>>>,
+    keys: Mutex<Option<Arc<JwkSet>>>,
+    /// Logins sent to the provider, by state
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::builder()
+                .user_agent("Muse-Server/0.1.0")
+                .build()
+                .unwrap_or_default(),
+            metadata: Mutex::new(None),
+            keys: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    /// Start a login, returning the provider URL to send the browser to.
+    /// `link_user` is the user to link the identity to when it comes back.
+    pub async fn authorization_url(&self, link_user: Option<String>) -> Result<String, OidcError> {
+        let metadata = self.metadata().await?;
+        let state = random_token();
+        let nonce = random_token();
+        let code_verifier = random_token();
+
+        {
+            let now = OffsetDateTime::now_utc();
+            let mut pending = self.pending.lock().unwrap();
+            pending.retain(|_, login| login.expires_at > now);
+            if pending.len() >= MAX_PENDING_LOGINS {
+                return Err(OidcError::TooManyLogins);
+            }
+            pending.insert(state.clone(), PendingLogin {
+                code_verifier: code_verifier.clone(),
+                nonce: nonce.clone(),
+                link_user,
+                expires_at: now + LOGIN_TTL,
+            });
+        }
+
+        let url = Url::parse_with_params(&metadata.authorization_endpoint, [
+            ("response_type", "code"),
+            ("client_id", self.config.client_id.as_str()),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("scope", self.config.scopes.as_str()),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+            ("code_challenge", pkce_challenge(&code_verifier).as_str()),
+            ("code_challenge_method", "S256"),
+        ]).map_err(|e| OidcError::InvalidMetadata(format!("authorization endpoint: {}", e)))?;
+
+        Ok(url.into())
+    }
+
+    /// Finish a login with the code and state the provider sent back. Each
+    /// state works once.
+    pub async fn complete(&self, code: &str, state: &str) -> Result<CompletedLogin, OidcError> {
+        let login = self.pending.lock().unwrap().remove(state)
+            .filter(|login| login.expires_at > OffsetDateTime::now_utc())
+            .ok_or(OidcError::UnknownLogin)?;
+
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", login.code_verifier.as_str()),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let response = self.http.post(&metadata.token_endpoint).form(&form).send().await?;
+        if !response.status().is_success() {
+            return Err(OidcError::Status(response.status()));
+        }
+        let tokens: TokenResponse = response.json().await?;
+
+        let identity = self.verify_id_token(&tokens.id_token, &metadata).await?;
+        if identity.nonce.as_deref() != Some(login.nonce.as_str()) {
+            return Err(OidcError::InvalidToken("nonce doesn't match".to_string()));
+        }
+
+        Ok(CompletedLogin { identity, link_user: login.link_user })
+    }
+
+    async fn verify_id_token(&self, token: &str, metadata: &ProviderMetadata) -> Result<Identity, OidcError> {
+        let header = decode_header(token).map_err(|e| OidcError::InvalidToken(e.to_string()))?;
+
+        // HMAC ID tokens are signed with the client secret, the rest with a
+        // key the provider publishes
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = self.config.client_secret.as_ref()
+                    .ok_or_else(|| OidcError::InvalidToken("HMAC-signed token without a client secret".to_string()))?;
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            _ => self.signing_key(header.kid.as_deref(), metadata).await?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        decode::<Identity>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| OidcError::InvalidToken(e.to_string()))
+    }
+
+    /// The provider key a token names, fetching the keys again when it's new
+    async fn signing_key(&self, kid: Option<&str>, metadata: &ProviderMetadata) -> Result<DecodingKey, OidcError> {
+        let cached = self.keys.lock().unwrap().clone();
+        for keys in [cached, None] {
+            let keys = match keys {
+                Some(keys) => keys,
+                None => {
+                    let keys: Arc<JwkSet> = Arc::new(self.get_json(&metadata.jwks_uri).await?);
+                    *self.keys.lock().unwrap() = Some(keys.clone());
+                    keys
+                }
+            };
+            // Without a key id, a provider with a single key means that one
+            let jwk = match kid {
+                Some(kid) => keys.find(kid),
+                None if keys.keys.len() == 1 => keys.keys.first(),
+                None => None,
+            };
+            if let Some(jwk) = jwk {
+                return DecodingKey::from_jwk(jwk).map_err(|e| OidcError::InvalidToken(e.to_string()));
+            }
+        }
+
+        Err(OidcError::InvalidToken("signed with an unknown key".to_string()))
+    }
+
+    async fn metadata(&self) -> Result<Arc<ProviderMetadata>, OidcError> {
+        if let Some(metadata) = self.metadata.lock().unwrap().clone() {
+            return Ok(metadata);
+        }
+
+        let issuer = self.config.issuer.trim_end_matches('/');
+        let metadata: ProviderMetadata = self.get_json(&format!("{}/.well-known/openid-configuration", issuer)).await?;
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(OidcError::IssuerMismatch(metadata.issuer));
+        }
+
+        let metadata = Arc::new(metadata);
+        *self.metadata.lock().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, OidcError> {
+        let response = self.http.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(OidcError::Status(response.status()));
+        }
+        Ok(response.json().await?)
+    }
+}
+
+/// 256 random bits, URL-safe, which is also a valid PKCE code verifier
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// The S256 code challenge for a PKCE code verifier
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::extract::{Form, State};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const CLIENT_ID: &str = "muse";
+    const CLIENT_SECRET: &str = "mock-client-secret";
+
+    #[test]
+    fn test_pkce_challenge() {
+        // RFC 7636, appendix B
+        assert_eq!(pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn test_role_mapping() {
+        let mapping = RoleMapping::parse("groups", "muse-admins=admin, muse-curators = curator,broken,=x", "listener");
+        assert_eq!(mapping.rules.len(), 2);
+
+        let claims = |value: Value| json!({ "groups": value }).as_object().unwrap().clone();
+        assert_eq!(mapping.role_for(&claims(json!(["staff", "muse-curators", "muse-admins"]))), "admin");
+        assert_eq!(mapping.role_for(&claims(json!("muse-curators"))), "curator");
+        assert_eq!(mapping.role_for(&claims(json!(["staff"]))), "listener");
+        assert_eq!(mapping.role_for(&Map::new()), "listener");
+
+        let nested = RoleMapping::parse("realm_access.roles", "muse-admin=admin", "listener");
+        let claims = json!({ "realm_access": { "roles": ["muse-admin"] } }).as_object().unwrap().clone();
+        assert_eq!(nested.role_for(&claims), "admin");
+    }
+
+    /// What the mock provider's login page would have been given
+    #[derive(Default)]
+    pub(crate) struct MockLogin {
+        code_challenge: String,
+        nonce: String,
+        audience: String,
+    }
+
+    pub(crate) type MockState = Arc<Mutex<(String, MockLogin)>>;
+
+    /// A provider with discovery and a token endpoint that checks PKCE, and
+    /// signs ID tokens with the client secret
+    pub(crate) async fn mock_provider() -> (String, MockState) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let state: MockState = Arc::new(Mutex::new((issuer.clone(), MockLogin::default())));
+
+        let discovery = {
+            let issuer = issuer.clone();
+            move || async move {
+                Json(json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/authorize", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "jwks_uri": format!("{}/jwks", issuer),
+                }))
+            }
+        };
+        let token = |State(state): State<MockState>, Form(form): Form<HashMap<String, String>>| async move {
+            let (issuer, login) = &*state.lock().unwrap();
+            if form["code"] != "mock-code" || pkce_challenge(&form["code_verifier"]) != login.code_challenge {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let claims = json!({
+                "iss": issuer, "aud": login.audience, "sub": "user-1", "exp": now + 60, "iat": now,
+                "nonce": login.nonce, "email": "ada@example.com", "email_verified": true,
+                "preferred_username": "ada", "groups": ["muse-admins"],
+            });
+            let id_token = encode(&Header::default(), &claims, &EncodingKey::from_secret(CLIENT_SECRET.as_bytes())).unwrap();
+            Ok(Json(json!({ "access_token": "unused", "token_type": "Bearer", "id_token": id_token })))
+        };
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(|| async { Json(json!({ "keys": [] })) }))
+            .route("/token", post(token))
+            .with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (issuer, state)
+    }
+
+    pub(crate) fn client(issuer: &str) -> OidcClient {
+        OidcClient::new(OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            redirect_url: "http://localhost:5173/login/oidc".to_string(),
+            scopes: "openid profile email".to_string(),
+            roles: None,
+            auto_provision: true,
+            link_by_email: false,
+        })
+    }
+
+    /// Play the provider's login page: note what the authorization URL asked
+    /// for, returning the state to complete it with
+    pub(crate) async fn start_login(client: &OidcClient, provider: &MockState, link_user: Option<String>) -> String {
+        let url = Url::parse(&client.authorization_url(link_user).await.unwrap()).unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["client_id"], CLIENT_ID);
+
+        provider.lock().unwrap().1 = MockLogin {
+            code_challenge: params["code_challenge"].clone(),
+            nonce: params["nonce"].clone(),
+            audience: CLIENT_ID.to_string(),
+        };
+        params["state"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_login_against_mock_provider() {
+        let (issuer, provider) = mock_provider().await;
+        let client = client(&issuer);
+
+        let state = start_login(&client, &provider, Some("local-user".to_string())).await;
+        let login = client.complete("mock-code", &state).await.unwrap();
+        assert_eq!(login.identity.sub, "user-1");
+        assert_eq!(login.identity.email.as_deref(), Some("ada@example.com"));
+        assert!(login.identity.email_verified);
+        assert_eq!(login.identity.preferred_username.as_deref(), Some("ada"));
+        assert_eq!(login.identity.claims["groups"], json!(["muse-admins"]));
+        assert_eq!(login.link_user.as_deref(), Some("local-user"));
+
+        // A state works once
+        assert!(matches!(client.complete("mock-code", &state).await, Err(OidcError::UnknownLogin)));
+        assert!(matches!(client.complete("mock-code", "made-up").await, Err(OidcError::UnknownLogin)));
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_tokens_are_checked() {
+        let (issuer, provider) = mock_provider().await;
+        let client = client(&issuer);
+
+        // The provider refuses a code without the right verifier
+        let state = start_login(&client, &provider, None).await;
+        provider.lock().unwrap().1.code_challenge = pkce_challenge("someone else's verifier");
+        assert!(matches!(client.complete("mock-code", &state).await, Err(OidcError::Status(StatusCode::BAD_REQUEST))));
+
+        let state = start_login(&client, &provider, None).await;
+        provider.lock().unwrap().1.nonce = "replayed".to_string();
+        assert!(matches!(client.complete("mock-code", &state).await, Err(OidcError::InvalidToken(_))));
+
+        let state = start_login(&client, &provider, None).await;
+        provider.lock().unwrap().1.audience = "another-client".to_string();
+        assert!(matches!(client.complete("mock-code", &state).await, Err(OidcError::InvalidToken(_))));
+
+        // A public client has no secret to check an HMAC-signed token with
+        let mut other = OidcClient::new(client.config().clone());
+        other.config.client_secret = None;
+        let state = start_login(&other, &provider, None).await;
+        assert!(matches!(other.complete("mock-code", &state).await, Err(OidcError::InvalidToken(_))));
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Role every account gets when it's made
+pub const DEFAULT_ROLE: &str = "listener";
+/// Role with every permission, which can't be edited or removed
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Something a role lets its users do. Tokens carry them as OAuth-style
+/// scopes, such as `library:listen songs:edit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Permission {
+    /// Browse the library and stream songs
+    Listen,
+    /// Make offline download bundles
+    Download,
+    /// Make and change their own playlists
+    WritePlaylists,
+    /// Make public share links
+    ShareLinks,
+    /// Add songs and scan the music directory
+    Upload,
+    /// Change the tags and lyrics of songs
+    EditSongs,
+    /// Change and delete anyone's playlists
+    CuratePlaylists,
+    /// Delete songs, manage libraries and who can see them, and see every library
+    ManageLibrary,
+    /// Manage accounts and roles, read the audit log, and close anyone's room
+    ManageUsers,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 9] = [
+        Permission::Listen,
+        Permission::Download,
+        Permission::WritePlaylists,
+        Permission::ShareLinks,
+        Permission::Upload,
+        Permission::EditSongs,
+        Permission::CuratePlaylists,
+        Permission::ManageLibrary,
+        Permission::ManageUsers,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Listen => "library:listen",
+            Permission::Download => "library:download",
+            Permission::WritePlaylists => "playlists:write",
+            Permission::ShareLinks => "share:create",
+            Permission::Upload => "songs:upload",
+            Permission::EditSongs => "songs:edit",
+            Permission::CuratePlaylists => "playlists:curate",
+            Permission::ManageLibrary => "library:manage",
+            Permission::ManageUsers => "users:manage",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        Permission::ALL.into_iter().find(|permission| permission.as_str() == scope)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Permission {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Permission {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let scope = String::deserialize(deserializer)?;
+        Permission::parse(&scope)
+            .ok_or_else(|| serde::de::Error::custom(format!("Unknown permission: {}", scope)))
+    }
+}
+
+/// Read a space-separated scope string, sorted and without duplicates.
+/// Scopes this server doesn't know, say from a newer version, are dropped.
+pub fn parse_scope(scope: &str) -> Vec<Permission> {
+    let mut permissions: Vec<Permission> = scope.split_whitespace()
+        .filter_map(Permission::parse)
+        .collect();
+    permissions.sort();
+    permissions.dedup();
+    permissions
+}
+
+/// Write permissions as a space-separated scope string
+pub fn format_scope(permissions: &[Permission]) -> String {
+    permissions.iter().map(Permission::as_str).collect::<Vec<_>>().join(" ")
+}
+
+/// (De)serializes permissions as a scope string, for the `scope` claim
+pub mod scope_string {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(permissions: &[Permission], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_scope(permissions))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Permission>, D::Error> {
+        Ok(parse_scope(&String::deserialize(deserializer)?))
+    }
+}
+
+/// The roles every server has: name, description and permissions
+pub fn built_in_roles() -> Vec<(&'static str, &'static str, Vec<Permission>)> {
+    use Permission::*;
+    let listener = vec![Listen, Download, WritePlaylists, ShareLinks];
+    vec![
+        (ADMIN_ROLE, "Everything, including accounts and roles", Permission::ALL.to_vec()),
+        ("curator", "Listener who can also edit tags, lyrics and anyone's playlists",
+            [listener.as_slice(), &[EditSongs, CuratePlaylists]].concat()),
+        ("uploader", "Listener who can also add songs", [listener.as_slice(), &[Upload]].concat()),
+        (DEFAULT_ROLE, "Streams, downloads, playlists and share links", listener.clone()),
+        ("guest", "Browse and stream only", vec![Listen]),
+    ]
+}
+
+/// Whether `name` is one of the [`built_in_roles`]
+pub fn is_built_in_role(name: &str) -> bool {
+    built_in_roles().iter().any(|(role, _, _)| *role == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_round_trip() {
+        for permission in Permission::ALL {
+            assert_eq!(Permission::parse(permission.as_str()), Some(permission));
+        }
+        let scope = format_scope(&Permission::ALL);
+        assert_eq!(parse_scope(&scope), Permission::ALL.to_vec());
+        assert_eq!(parse_scope("  songs:edit unknown:thing library:listen songs:edit "),
+            vec![Permission::Listen, Permission::EditSongs]);
+        assert!(parse_scope("").is_empty());
+    }
+
+    #[test]
+    fn test_built_in_roles() {
+        let roles = built_in_roles();
+        let permissions = |name: &str| roles.iter().find(|(role, _, _)| *role == name).unwrap().2.clone();
+        assert_eq!(permissions(ADMIN_ROLE), Permission::ALL.to_vec());
+        assert_eq!(permissions("guest"), vec![Permission::Listen]);
+        assert!(permissions("curator").contains(&Permission::CuratePlaylists));
+        assert!(!permissions("uploader").contains(&Permission::EditSongs));
+        assert!(permissions(DEFAULT_ROLE).iter().all(|p| permissions("uploader").contains(p)));
+        assert!(is_built_in_role("guest"));
+        assert!(!is_built_in_role("dj"));
+    }
+}
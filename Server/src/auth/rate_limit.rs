@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+/// Seconds the first backoff lasts, doubling with each failure after it
+const BACKOFF_BASE_SECONDS: i64 = 2;
+/// Seconds requests are counted over
+const WINDOW_SECONDS: i64 = 60;
+
+/// How failed attempts on one account or from one address are held back
+#[derive(Debug, Clone, Copy)]
+pub struct FailurePolicy {
+    /// Failures in a row allowed before any backoff
+    pub free_failures: u32,
+    /// Failures in a row that lock it out
+    pub lockout_failures: u32,
+    /// Seconds a lockout lasts, and how long failures are remembered
+    pub lockout_seconds: i64,
+}
+
+impl FailurePolicy {
+    /// Seconds to hold off after the `failures`th failure in a row
+    fn delay(&self, failures: u32) -> i64 {
+        if failures >= self.lockout_failures {
+            self.lockout_seconds
+        } else if failures <= self.free_failures {
+            0
+        } else {
+            let doublings = (failures - self.free_failures - 1).min(30);
+            (BACKOFF_BASE_SECONDS << doublings).min(self.lockout_seconds)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Attempts an address can make a minute, or 0 for no limit
+    pub requests_per_minute: u32,
+    pub account: FailurePolicy,
+    pub address: FailurePolicy,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 20,
+            account: FailurePolicy { free_failures: 5, lockout_failures: 10, lockout_seconds: 15 * 60 },
+            address: FailurePolicy { free_failures: 20, lockout_failures: 50, lockout_seconds: 15 * 60 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Failures {
+    count: u32,
+    last_failure: i64,
+    blocked_until: i64,
+}
+
+/// An account or address that is being held back
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockout {
+    /// Username or IP address
+    pub subject: String,
+    /// Failures in a row
+    pub failures: u32,
+    /// Whether it reached the lockout rather than backing off
+    pub locked_out: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub until: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockouts {
+    pub accounts: Vec<Lockout>,
+    pub addresses: Vec<Lockout>,
+}
+
+#[derive(Default)]
+struct Attempts {
+    /// Start of each address's window and the attempts made in it
+    requests: HashMap<IpAddr, (i64, u32)>,
+    addresses: HashMap<IpAddr, Failures>,
+    accounts: HashMap<String, Failures>,
+    last_pruned: i64,
+}
+
+/// Counts attempts on the login endpoints to slow down password guessing.
+/// Failures back off exponentially and then lock out, both per account and
+/// per address. It's kept in memory, so a restart forgets it.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    attempts: Mutex<Attempts>,
+}
+
+fn blocked<K: Eq + Hash>(failures: &HashMap<K, Failures>, key: &K, now: i64) -> Option<i64> {
+    failures.get(key)
+        .map(|failures| failures.blocked_until - now)
+        .filter(|&wait| wait > 0)
+}
+
+fn fail<K: Eq + Hash>(failures: &mut HashMap<K, Failures>, key: K, policy: &FailurePolicy, now: i64) {
+    let entry = failures.entry(key).or_default();
+    if now - entry.last_failure >= policy.lockout_seconds && entry.blocked_until <= now {
+        *entry = Failures::default();
+    }
+    entry.count += 1;
+    entry.last_failure = now;
+    entry.blocked_until = now + policy.delay(entry.count);
+}
+
+fn lockouts<K: ToString>(failures: &HashMap<K, Failures>, policy: &FailurePolicy, now: i64) -> Vec<Lockout> {
+    let mut lockouts: Vec<Lockout> = failures.iter()
+        .filter(|(_, failures)| failures.blocked_until > now)
+        .map(|(subject, failures)| Lockout {
+            subject: subject.to_string(),
+            failures: failures.count,
+            locked_out: failures.count >= policy.lockout_failures,
+            until: OffsetDateTime::from_unix_timestamp(failures.blocked_until).unwrap_or(OffsetDateTime::UNIX_EPOCH),
+        })
+        .collect();
+    lockouts.sort_by(|a, b| a.subject.cmp(&b.subject));
+    lockouts
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config, attempts: Mutex::new(Attempts::default()) }
+    }
+
+    fn attempts(&self) -> std::sync::MutexGuard<'_, Attempts> {
+        self.attempts.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Count an attempt from `address`, on `account` when it names one. When
+    /// it has to wait, the seconds until it can try again.
+    pub fn check(&self, address: IpAddr, account: Option<&str>, now: i64) -> Result<(), i64> {
+        let mut attempts = self.attempts();
+        self.prune(&mut attempts, now);
+
+        let wait = blocked(&attempts.addresses, &address, now)
+            .into_iter()
+            .chain(account.and_then(|account| blocked(&attempts.accounts, &account.to_string(), now)))
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        if self.config.requests_per_minute > 0 {
+            let (start, count) = attempts.requests.entry(address).or_insert((now, 0));
+            if now - *start >= WINDOW_SECONDS {
+                *start = now;
+                *count = 0;
+            }
+            if *count >= self.config.requests_per_minute {
+                return Err(*start + WINDOW_SECONDS - now);
+            }
+            *count += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Record a failed attempt, such as a wrong password or code
+    pub fn record_failure(&self, address: IpAddr, account: Option<&str>, now: i64) {
+        let mut attempts = self.attempts();
+        fail(&mut attempts.addresses, address, &self.config.address, now);
+        if let Some(account) = account {
+            fail(&mut attempts.accounts, account.to_string(), &self.config.account, now);
+        }
+    }
+
+    /// Record a successful login, which forgets the account's failures. The
+    /// address's are kept, as one address may be trying many accounts.
+    pub fn record_success(&self, account: &str) {
+        self.attempts().accounts.remove(account);
+    }
+
+    /// Accounts and addresses being held back now
+    pub fn lockouts(&self, now: i64) -> Lockouts {
+        let attempts = self.attempts();
+        Lockouts {
+            accounts: lockouts(&attempts.accounts, &self.config.account, now),
+            addresses: lockouts(&attempts.addresses, &self.config.address, now),
+        }
+    }
+
+    /// Forget an account's failures, returning false if it had none
+    pub fn unlock_account(&self, account: &str) -> bool {
+        self.attempts().accounts.remove(account).is_some()
+    }
+
+    /// Forget an address's failures and attempts, returning false if it had none
+    pub fn unlock_address(&self, address: IpAddr) -> bool {
+        let mut attempts = self.attempts();
+        let requests = attempts.requests.remove(&address).is_some();
+        attempts.addresses.remove(&address).is_some() || requests
+    }
+
+    /// Drop windows that have passed and failures old enough to be forgotten
+    fn prune(&self, attempts: &mut Attempts, now: i64) {
+        if now - attempts.last_pruned < WINDOW_SECONDS {
+            return;
+        }
+        attempts.last_pruned = now;
+        attempts.requests.retain(|_, (start, _)| now - *start < WINDOW_SECONDS);
+        let keep = |policy: FailurePolicy| move |failures: &Failures| {
+            failures.blocked_until > now || now - failures.last_failure < policy.lockout_seconds
+        };
+        let (account, address) = (keep(self.config.account), keep(self.config.address));
+        attempts.accounts.retain(|_, failures| account(failures));
+        attempts.addresses.retain(|_, failures| address(failures));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_limiter(requests_per_minute: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            requests_per_minute,
+            account: FailurePolicy { free_failures: 2, lockout_failures: 5, lockout_seconds: 600 },
+            address: FailurePolicy { free_failures: 10, lockout_failures: 20, lockout_seconds: 600 },
+        })
+    }
+
+    const ADDRESS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn test_backoff_doubles_then_locks_out() {
+        let policy = FailurePolicy { free_failures: 2, lockout_failures: 6, lockout_seconds: 600 };
+        let delays: Vec<i64> = (1..=7).map(|failures| policy.delay(failures)).collect();
+        assert_eq!(delays, vec![0, 0, 2, 4, 8, 600, 600]);
+
+        let short = FailurePolicy { free_failures: 0, lockout_failures: 100, lockout_seconds: 10 };
+        assert_eq!(short.delay(99), 10);
+    }
+
+    #[test]
+    fn test_account_failures() {
+        let limiter = new_limiter(0);
+        let now = 1_000_000;
+        for _ in 0..2 {
+            limiter.record_failure(ADDRESS, Some("alice"), now);
+            assert!(limiter.check(ADDRESS, Some("alice"), now).is_ok());
+        }
+        limiter.record_failure(ADDRESS, Some("alice"), now);
+        assert_eq!(limiter.check(ADDRESS, Some("alice"), now), Err(2));
+        assert_eq!(limiter.check(OTHER, Some("alice"), now + 1), Err(1));
+        assert!(limiter.check(OTHER, Some("bob"), now).is_ok());
+        assert!(limiter.check(ADDRESS, Some("alice"), now + 2).is_ok());
+
+        for _ in 0..2 {
+            limiter.record_failure(OTHER, Some("alice"), now + 2);
+        }
+        assert_eq!(limiter.check(OTHER, Some("alice"), now + 2), Err(600));
+        let lockouts = limiter.lockouts(now + 2);
+        assert_eq!(lockouts.accounts.len(), 1);
+        assert_eq!(lockouts.accounts[0].subject, "alice");
+        assert!(lockouts.accounts[0].locked_out);
+        assert!(lockouts.addresses.is_empty());
+
+        assert!(limiter.unlock_account("alice"));
+        assert!(!limiter.unlock_account("alice"));
+        assert!(limiter.check(OTHER, Some("alice"), now + 3).is_ok());
+    }
+
+    #[test]
+    fn test_success_clears_account_failures() {
+        let limiter = new_limiter(0);
+        let now = 1_000_000;
+        for _ in 0..4 {
+            limiter.record_failure(ADDRESS, Some("alice"), now);
+        }
+        assert!(limiter.check(ADDRESS, Some("alice"), now).is_err());
+
+        limiter.record_success("alice");
+        assert!(limiter.check(ADDRESS, Some("alice"), now).is_ok());
+        assert!(limiter.lockouts(now).accounts.is_empty());
+
+        // It starts over from the first failure
+        limiter.record_failure(ADDRESS, Some("alice"), now);
+        assert!(limiter.check(ADDRESS, Some("alice"), now).is_ok());
+    }
+
+    #[test]
+    fn test_failures_are_forgotten() {
+        let limiter = new_limiter(0);
+        let now = 1_000_000;
+        for _ in 0..3 {
+            limiter.record_failure(ADDRESS, Some("alice"), now);
+        }
+        assert!(limiter.check(ADDRESS, Some("alice"), now).is_err());
+
+        // Long after the last failure, the next one starts the count over
+        limiter.record_failure(ADDRESS, Some("alice"), now + 600);
+        assert!(limiter.check(ADDRESS, Some("alice"), now + 600).is_ok());
+        assert!(limiter.lockouts(now + 600).accounts.is_empty());
+    }
+
+    #[test]
+    fn test_address_failures() {
+        let limiter = new_limiter(0);
+        let now = 1_000_000;
+        for i in 0..11 {
+            limiter.record_failure(ADDRESS, Some(&format!("user{}", i)), now);
+        }
+        assert_eq!(limiter.check(ADDRESS, Some("someone"), now), Err(2));
+        assert_eq!(limiter.check(ADDRESS, None, now), Err(2));
+        assert!(limiter.check(OTHER, None, now).is_ok());
+        assert_eq!(limiter.lockouts(now).addresses[0].subject, "192.0.2.1");
+
+        assert!(limiter.unlock_address(ADDRESS));
+        assert!(limiter.check(ADDRESS, None, now).is_ok());
+    }
+
+    #[test]
+    fn test_requests_per_minute() {
+        let limiter = new_limiter(3);
+        let now = 1_000_000;
+        for _ in 0..3 {
+            assert!(limiter.check(ADDRESS, None, now).is_ok());
+        }
+        assert_eq!(limiter.check(ADDRESS, Some("alice"), now + 20), Err(40));
+        assert!(limiter.check(OTHER, None, now + 20).is_ok());
+        assert!(limiter.check(ADDRESS, None, now + 60).is_ok());
+
+        let unlimited = new_limiter(0);
+        for _ in 0..100 {
+            assert!(unlimited.check(ADDRESS, None, now).is_ok());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::net::IpAddr;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keeps stream URL signatures from being valid as anything else signed
+/// with the same secret
+const SIGNATURE_CONTEXT: &[u8] = b"muse-stream-url-v1";
+
+/// What a signed stream URL lets its holder stream. The song is named the
+/// way `/api/stream` names it, so the URL is checked without a lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamGrant {
+    pub artist: String,
+    pub name: String,
+    pub format: String,
+    /// The user who asked for the URL
+    pub user_id: String,
+    /// Set when it was asked for with a share link token
+    pub share_link: Option<String>,
+    /// Unix timestamp
+    pub expires: i64,
+    /// The only address the URL works from, when bound
+    pub ip: Option<IpAddr>,
+}
+
+/// The query string of a signed `/api/stream` URL
+#[derive(Debug, Deserialize)]
+pub struct SignedStreamQuery {
+    pub artist: String,
+    pub name: String,
+    #[serde(default = "default_format")]
+    pub format: String,
+    pub user: String,
+    pub share: Option<String>,
+    pub expires: i64,
+    /// Whether the URL only works from the address it was asked for from
+    #[serde(default)]
+    pub bound: bool,
+    pub signature: String,
+}
+
+fn default_format() -> String {
+    "mp3".to_string()
+}
+
+impl SignedStreamQuery {
+    /// The grant the URL claims, for a request coming from `peer`
+    pub fn grant(&self, peer: Option<IpAddr>) -> StreamGrant {
+        StreamGrant {
+            artist: self.artist.clone(),
+            name: self.name.clone(),
+            format: self.format.clone(),
+            user_id: self.user.clone(),
+            share_link: self.share.clone(),
+            expires: self.expires,
+            ip: if self.bound { peer } else { None },
+        }
+    }
+}
+
+impl StreamGrant {
+    /// The query string of a signed URL for this grant
+    pub fn query_string(&self, signature: &str) -> String {
+        let mut query = format!(
+            "artist={}&name={}&format={}&user={}&expires={}",
+            urlencoding::encode(&self.artist),
+            urlencoding::encode(&self.name),
+            urlencoding::encode(&self.format),
+            urlencoding::encode(&self.user_id),
+            self.expires,
+        );
+        if let Some(share_link) = &self.share_link {
+            query.push_str(&format!("&share={}", urlencoding::encode(share_link)));
+        }
+        if self.ip.is_some() {
+            query.push_str("&bound=true");
+        }
+        query.push_str(&format!("&signature={}", signature));
+        query
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Signed URL has expired")]
+    Expired,
+
+    #[error("Invalid signature")]
+    Invalid,
+}
+
+/// Signs and checks stream URLs with HMAC-SHA256
+pub struct StreamUrlSigner {
+    key: Vec<u8>,
+}
+
+impl StreamUrlSigner {
+    pub fn new(secret: &str) -> Self {
+        Self { key: secret.as_bytes().to_vec() }
+    }
+
+    pub fn sign(&self, grant: &StreamGrant) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(grant).finalize().into_bytes())
+    }
+
+    /// Check a signature at the time `now`, in constant time
+    pub fn verify(&self, grant: &StreamGrant, signature: &str, now: i64) -> Result<(), SignatureError> {
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| SignatureError::Invalid)?;
+        self.mac(grant).verify_slice(&signature).map_err(|_| SignatureError::Invalid)?;
+        if grant.expires <= now {
+            return Err(SignatureError::Expired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, grant: &StreamGrant) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(SIGNATURE_CONTEXT);
+        let ip = grant.ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let expires = grant.expires.to_string();
+        let fields = [
+            grant.artist.as_str(),
+            &grant.name,
+            &grant.format,
+            &grant.user_id,
+            grant.share_link.as_deref().unwrap_or_default(),
+            &expires,
+            &ip,
+        ];
+        // Length prefixes keep one field from running into the next
+        for field in fields {
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant() -> StreamGrant {
+        StreamGrant {
+            artist: "Miles Davis".to_string(),
+            name: "So What".to_string(),
+            format: "mp3".to_string(),
+            user_id: "user123".to_string(),
+            share_link: None,
+            expires: 1_000,
+            ip: Some("192.0.2.7".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = StreamUrlSigner::new("test_secret_key_for_testing");
+        let signature = signer.sign(&grant());
+        assert_eq!(signer.verify(&grant(), &signature, 999), Ok(()));
+        assert_eq!(signer.verify(&grant(), &signature, 1_000), Err(SignatureError::Expired));
+        assert_eq!(StreamUrlSigner::new("another_secret").verify(&grant(), &signature, 0), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn test_signature_covers_every_field() {
+        let signer = StreamUrlSigner::new("test_secret_key_for_testing");
+        let signature = signer.sign(&grant());
+        let changed = [
+            StreamGrant { format: "flac".to_string(), ..grant() },
+            StreamGrant { name: "Freddie".to_string(), ..grant() },
+            StreamGrant { user_id: "user456".to_string(), ..grant() },
+            StreamGrant { share_link: Some("link".to_string()), ..grant() },
+            StreamGrant { expires: 2_000, ..grant() },
+            StreamGrant { ip: Some("192.0.2.8".parse().unwrap()), ..grant() },
+            StreamGrant { ip: None, ..grant() },
+            // Moving text between fields changes the signature too
+            StreamGrant { artist: "Miles DavisSo".to_string(), name: " What".to_string(), ..grant() },
+        ];
+        for grant in changed {
+            assert_eq!(signer.verify(&grant, &signature, 0), Err(SignatureError::Invalid), "{:?}", grant);
+        }
+        assert_eq!(signer.verify(&grant(), "not base64!", 0), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn test_query_string_round_trip() {
+        let signer = StreamUrlSigner::new("test_secret_key_for_testing");
+        let grant = StreamGrant { name: "So What & More".to_string(), ..grant() };
+        let signature = signer.sign(&grant);
+        let uri: axum::http::Uri = format!("/api/stream?{}", grant.query_string(&signature)).parse().unwrap();
+        let query = axum::extract::Query::<SignedStreamQuery>::try_from_uri(&uri).unwrap().0;
+
+        assert_eq!(query.grant(grant.ip), grant);
+        assert_eq!(signer.verify(&query.grant(grant.ip), &query.signature, 0), Ok(()));
+        // A bound URL used from elsewhere
+        let elsewhere = query.grant(Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(signer.verify(&elsewhere, &query.signature, 0), Err(SignatureError::Invalid));
+    }
+}
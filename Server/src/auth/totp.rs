@@ -0,0 +1,137 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Digits in a code
+pub const DIGITS: u32 = 6;
+/// Seconds a code is valid for
+pub const PERIOD: i64 = 30;
+/// Periods either side of now a code is still accepted in, for clocks that drift
+const SKEW: i64 = 1;
+/// Recovery codes given out at a time
+pub const RECOVERY_CODES: usize = 10;
+
+/// A new random secret, 160 bits in base32 as authenticator apps take it
+pub fn generate_secret() -> String {
+    BASE32_NOPAD.encode(&rand::random::<[u8; 20]>())
+}
+
+/// The `otpauth://` URI authenticator apps enroll from, usually shown as a QR code
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding::encode(issuer),
+        urlencoding::encode(account),
+        secret,
+        urlencoding::encode(issuer),
+        DIGITS,
+        PERIOD,
+    )
+}
+
+/// The code for a time step, as in RFC 6238 with HMAC-SHA1
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = HmacSha1::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The time step a code is for, when it's right for `now` give or take the
+/// allowed drift. Callers should refuse steps at or before the last one used,
+/// so each code only works once.
+pub fn verify(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+
+    let current = now.div_euclid(PERIOD);
+    (current - SKEW..=current + SKEW).find(|&step| code_at(&key, step) == code)
+}
+
+/// New one-time recovery codes, 80 random bits each, like `k4mz-q7tp-2xnd-h6wa`
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = BASE32_NOPAD.encode(&rand::random::<[u8; 10]>()).to_lowercase();
+            code.as_bytes().chunks(4)
+                .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// SHA-256 of a recovery code in hex, ignoring case, spaces and dashes. The
+/// codes are random enough that they don't need a slow hash.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(normalized.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret from RFC 6238, appendix B
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        // The RFC's codes have eight digits; these are their last six
+        for (time, code) in [(59, 287082), (1111111109, 81804), (1111111111, 50471), (1234567890, 5924), (2000000000, 279037)] {
+            assert_eq!(code_at(RFC_KEY, time / PERIOD), code, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn test_verify_allows_drift() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        let now = 1111111111;
+        let step = now / PERIOD;
+        let code = |step: i64| format!("{:06}", code_at(RFC_KEY, step));
+
+        assert_eq!(verify(&secret, &code(step), now), Some(step));
+        assert_eq!(verify(&secret, &code(step - 1), now), Some(step - 1));
+        assert_eq!(verify(&secret, &code(step + 1), now), Some(step + 1));
+        assert_eq!(verify(&secret, &code(step - 2), now), None);
+        assert_eq!(verify(&secret, &format!(" {} ", code(step)), now), Some(step));
+        assert_eq!(verify(&secret, "12345", now), None);
+        assert_eq!(verify(&secret, "abcdef", now), None);
+        assert_eq!(verify("not base32!", &code(step), now), None);
+    }
+
+    #[test]
+    fn test_secret_and_uri() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(BASE32_NOPAD.decode(secret.as_bytes()).unwrap().len(), 20);
+
+        let uri = provisioning_uri("JBSWY3DPEHPK3PXP", "Muse", "ada lovelace");
+        assert_eq!(uri, "otpauth://totp/Muse:ada%20lovelace?secret=JBSWY3DPEHPK3PXP&issuer=Muse&algorithm=SHA1&digits=6&period=30");
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(codes.iter().all(|code| code.len() == 19 && code.split('-').count() == 4));
+        assert_ne!(codes[0], codes[1]);
+
+        let hash = hash_recovery_code(&codes[0]);
+        assert_eq!(hash, hash_recovery_code(&codes[0].to_uppercase().replace('-', " ")));
+        assert_ne!(hash, hash_recovery_code(&codes[1]));
+    }
+}
@@ -0,0 +1,723 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+/// How long a browser has to answer a challenge
+const CEREMONY_TTL: Duration = Duration::minutes(5);
+/// Challenges outstanding at once, as anyone can ask for a login one
+const MAX_PENDING_CEREMONIES: usize = 10_000;
+/// Deepest CBOR nesting read; COSE keys and attestation objects need three
+const MAX_CBOR_DEPTH: usize = 8;
+
+/// COSE algorithms accepted, in order of preference
+pub const ES256: i64 = -7;
+pub const EDDSA: i64 = -8;
+pub const RS256: i64 = -257;
+const ALGORITHMS: [i64; 3] = [ES256, EDDSA, RS256];
+
+// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Debug, Error)]
+pub enum WebAuthnError {
+    #[error("Unknown or expired challenge")]
+    UnknownChallenge,
+
+    #[error("Too many passkey requests in progress, try again later")]
+    TooManyCeremonies,
+
+    #[error("Invalid client data: {0}")]
+    InvalidClientData(String),
+
+    #[error("Invalid authenticator data: {0}")]
+    InvalidAuthenticatorData(String),
+
+    #[error("Unsupported public key: {0}")]
+    UnsupportedKey(String),
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("Signature counter went backwards; the authenticator may have been cloned")]
+    CounterRegression,
+}
+
+/// The relying party passkeys are registered with: the site's domain, and the
+/// origin the client pages are served from
+#[derive(Debug, Clone)]
+pub struct WebAuthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub origin: String,
+}
+
+/// A credential from `navigator.credentials.create()`, as
+/// `PublicKeyCredential.toJSON()` gives it
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// A credential from `navigator.credentials.get()`, as
+/// `PublicKeyCredential.toJSON()` gives it
+#[derive(Debug, Deserialize)]
+pub struct LoginCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    /// The user ID given at registration, sent by discoverable credentials
+    #[serde(rename = "userHandle", default)]
+    pub user_handle: Option<String>,
+}
+
+/// A credential that passed registration, to store for the user
+#[derive(Debug)]
+pub struct NewCredential {
+    /// Credential ID, base64url
+    pub id: String,
+    /// COSE public key
+    pub public_key: Vec<u8>,
+    pub algorithm: i64,
+    pub sign_count: u32,
+}
+
+/// A login that passed verification
+#[derive(Debug)]
+pub struct VerifiedLogin {
+    pub sign_count: u32,
+    /// Whether the authenticator checked it was the user, with a PIN or biometric
+    pub user_verified: bool,
+}
+
+enum Ceremony {
+    Register { user_id: String },
+    Login,
+}
+
+struct PendingCeremony {
+    ceremony: Ceremony,
+    expires_at: OffsetDateTime,
+}
+
+/// Runs passkey registrations and logins, keeping the challenges it has handed
+/// out until they're answered or expire. Attestation isn't asked for, so any
+/// authenticator can be registered.
+pub struct WebAuthn {
+    config: WebAuthnConfig,
+    /// Challenges handed out, by their base64url value
+    pending: Mutex<HashMap<String, PendingCeremony>>,
+}
+
+impl WebAuthn {
+    pub fn new(config: WebAuthnConfig) -> Self {
+        Self {
+            config,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn new_challenge(&self, ceremony: Ceremony) -> Result<String, WebAuthnError> {
+        let challenge = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        let now = OffsetDateTime::now_utc();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, ceremony| ceremony.expires_at > now);
+        if pending.len() >= MAX_PENDING_CEREMONIES {
+            return Err(WebAuthnError::TooManyCeremonies);
+        }
+        pending.insert(challenge.clone(), PendingCeremony { ceremony, expires_at: now + CEREMONY_TTL });
+
+        Ok(challenge)
+    }
+
+    /// Check the client data is for a challenge this handed out, and take the
+    /// challenge so it only works once
+    fn take_challenge(&self, client_data_json: &[u8], expected_type: &str) -> Result<Ceremony, WebAuthnError> {
+        #[derive(Deserialize)]
+        struct ClientData {
+            #[serde(rename = "type")]
+            kind: String,
+            challenge: String,
+            origin: String,
+        }
+
+        let client_data: ClientData = serde_json::from_slice(client_data_json)
+            .map_err(|e| WebAuthnError::InvalidClientData(e.to_string()))?;
+        let pending = self.pending.lock().unwrap().remove(&client_data.challenge)
+            .filter(|pending| pending.expires_at > OffsetDateTime::now_utc())
+            .ok_or(WebAuthnError::UnknownChallenge)?;
+
+        if client_data.kind != expected_type {
+            return Err(WebAuthnError::InvalidClientData(format!("type is {}", client_data.kind)));
+        }
+        if client_data.origin != self.config.origin {
+            return Err(WebAuthnError::InvalidClientData(format!("origin {} isn't allowed", client_data.origin)));
+        }
+
+        Ok(pending.ceremony)
+    }
+
+    /// Start registering a passkey for a user, returning the options for
+    /// `navigator.credentials.create()` in their JSON form. `exclude` are the
+    /// IDs of the user's passkeys, which the authenticator won't make again.
+    pub fn start_registration(&self, user_id: &str, username: &str, exclude: &[String]) -> Result<Value, WebAuthnError> {
+        let challenge = self.new_challenge(Ceremony::Register { user_id: user_id.to_string() })?;
+
+        Ok(json!({
+            "challenge": challenge,
+            "rp": { "id": self.config.rp_id, "name": self.config.rp_name },
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(user_id),
+                "name": username,
+                "displayName": username,
+            },
+            "pubKeyCredParams": ALGORITHMS.iter()
+                .map(|alg| json!({ "type": "public-key", "alg": alg }))
+                .collect::<Vec<_>>(),
+            "timeout": CEREMONY_TTL.whole_milliseconds() as u64,
+            "excludeCredentials": exclude.iter()
+                .map(|id| json!({ "type": "public-key", "id": id }))
+                .collect::<Vec<_>>(),
+            "authenticatorSelection": { "residentKey": "preferred", "userVerification": "preferred" },
+            "attestation": "none",
+        }))
+    }
+
+    /// Finish registering the passkey a user's browser made
+    pub fn finish_registration(&self, user_id: &str, credential: &RegistrationCredential) -> Result<NewCredential, WebAuthnError> {
+        let client_data_json = decode_base64(&credential.response.client_data_json, "clientDataJSON")?;
+        match self.take_challenge(&client_data_json, "webauthn.create")? {
+            Ceremony::Register { user_id: for_user } if for_user == user_id => {}
+            _ => return Err(WebAuthnError::UnknownChallenge),
+        }
+
+        let attestation = decode_base64(&credential.response.attestation_object, "attestationObject")?;
+        let auth_data = match Cbor::decode(&attestation)? {
+            Cbor::Map(entries) => entries.into_iter()
+                .find(|(key, _)| *key == Cbor::Text("authData".to_string()))
+                .and_then(|(_, value)| match value {
+                    Cbor::Bytes(bytes) => Some(bytes),
+                    _ => None,
+                }),
+            _ => None,
+        }.ok_or_else(|| WebAuthnError::InvalidAuthenticatorData("no authData in attestation object".to_string()))?;
+
+        let auth = AuthenticatorData::parse(&auth_data)?;
+        self.check_authenticator_data(&auth)?;
+        let (credential_id, public_key) = auth.attested_credential
+            .ok_or_else(|| WebAuthnError::InvalidAuthenticatorData("no credential".to_string()))?;
+        let id = URL_SAFE_NO_PAD.encode(&credential_id);
+        if id != credential.id.trim_end_matches('=') {
+            return Err(WebAuthnError::InvalidAuthenticatorData("credential ID doesn't match".to_string()));
+        }
+        let algorithm = PublicKey::from_cose(&public_key)?.algorithm();
+
+        Ok(NewCredential { id, public_key, algorithm, sign_count: auth.sign_count })
+    }
+
+    /// Start a passkey login, returning the options for
+    /// `navigator.credentials.get()` in their JSON form. With no `allow`, the
+    /// browser offers the passkeys it has for the site.
+    pub fn start_login(&self, allow: &[String]) -> Result<Value, WebAuthnError> {
+        let challenge = self.new_challenge(Ceremony::Login)?;
+
+        Ok(json!({
+            "challenge": challenge,
+            "rpId": self.config.rp_id,
+            "timeout": CEREMONY_TTL.whole_milliseconds() as u64,
+            "allowCredentials": allow.iter()
+                .map(|id| json!({ "type": "public-key", "id": id }))
+                .collect::<Vec<_>>(),
+            "userVerification": "preferred",
+        }))
+    }
+
+    /// Verify a login with a stored passkey's COSE public key and the signature
+    /// counter last seen from it
+    pub fn finish_login(&self, credential: &LoginCredential, public_key: &[u8], stored_sign_count: u32) -> Result<VerifiedLogin, WebAuthnError> {
+        let client_data_json = decode_base64(&credential.response.client_data_json, "clientDataJSON")?;
+        match self.take_challenge(&client_data_json, "webauthn.get")? {
+            Ceremony::Login => {}
+            Ceremony::Register { .. } => return Err(WebAuthnError::UnknownChallenge),
+        }
+
+        let auth_data = decode_base64(&credential.response.authenticator_data, "authenticatorData")?;
+        let auth = AuthenticatorData::parse(&auth_data)?;
+        self.check_authenticator_data(&auth)?;
+
+        let signature = decode_base64(&credential.response.signature, "signature")?;
+        let mut message = auth_data;
+        message.extend_from_slice(&Sha256::digest(&client_data_json));
+        PublicKey::from_cose(public_key)?.verify(&message, &signature)?;
+
+        // Authenticators that count at all count up; synced passkeys stay at 0
+        if (auth.sign_count != 0 || stored_sign_count != 0) && auth.sign_count <= stored_sign_count {
+            return Err(WebAuthnError::CounterRegression);
+        }
+
+        Ok(VerifiedLogin {
+            sign_count: auth.sign_count,
+            user_verified: auth.flags & USER_VERIFIED != 0,
+        })
+    }
+
+    fn check_authenticator_data(&self, auth: &AuthenticatorData) -> Result<(), WebAuthnError> {
+        if auth.rp_id_hash[..] != Sha256::digest(self.config.rp_id.as_bytes())[..] {
+            return Err(WebAuthnError::InvalidAuthenticatorData("for another site".to_string()));
+        }
+        if auth.flags & USER_PRESENT == 0 {
+            return Err(WebAuthnError::InvalidAuthenticatorData("user wasn't present".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn decode_base64(value: &str, field: &str) -> Result<Vec<u8>, WebAuthnError> {
+    URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))
+        .map_err(|_| WebAuthnError::InvalidClientData(format!("{} isn't base64url", field)))
+}
+
+/// The parts of authenticator data that are checked
+struct AuthenticatorData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    sign_count: u32,
+    /// Credential ID and COSE public key, given at registration
+    attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl AuthenticatorData {
+    fn parse(data: &[u8]) -> Result<Self, WebAuthnError> {
+        let invalid = |reason: &str| WebAuthnError::InvalidAuthenticatorData(reason.to_string());
+        if data.len() < 37 {
+            return Err(invalid("too short"));
+        }
+
+        let rp_id_hash: [u8; 32] = data[..32].try_into().unwrap();
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes(data[33..37].try_into().unwrap());
+
+        let attested_credential = if flags & ATTESTED_CREDENTIAL != 0 {
+            // AAGUID, then the credential ID's length and the ID
+            let rest = data.get(37 + 16..).ok_or_else(|| invalid("credential data cut short"))?;
+            let id_len = u16::from_be_bytes(rest.get(..2).ok_or_else(|| invalid("credential data cut short"))?.try_into().unwrap()) as usize;
+            let id = rest.get(2..2 + id_len).ok_or_else(|| invalid("credential ID cut short"))?;
+            let key = &rest[2 + id_len..];
+            // Extensions may follow the key, so only its own bytes are kept
+            let key_len = Cbor::decode_prefix(key)?.1;
+            Some((id.to_vec(), key[..key_len].to_vec()))
+        } else {
+            None
+        };
+
+        Ok(Self { rp_id_hash, flags, sign_count, attested_credential })
+    }
+}
+
+/// A public key from a COSE key, for the algorithms offered at registration
+enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+    Rs256(rsa::RsaPublicKey),
+}
+
+impl PublicKey {
+    fn from_cose(cose: &[u8]) -> Result<Self, WebAuthnError> {
+        let Cbor::Map(entries) = Cbor::decode(cose)? else {
+            return Err(WebAuthnError::UnsupportedKey("not a COSE key".to_string()));
+        };
+        let get = |label: i64| entries.iter()
+            .find(|(key, _)| *key == Cbor::Int(label as i128))
+            .map(|(_, value)| value);
+        let int = |label: i64| match get(label) {
+            Some(Cbor::Int(value)) => Some(*value),
+            _ => None,
+        };
+        let bytes = |label: i64| match get(label) {
+            Some(Cbor::Bytes(value)) => Ok(value.as_slice()),
+            _ => Err(WebAuthnError::UnsupportedKey(format!("missing parameter {}", label))),
+        };
+
+        // Key type, algorithm and curve are labels 1, 3 and -1
+        match (int(1), int(3).map(|alg| alg as i64)) {
+            (Some(2), Some(ES256)) if int(-1) == Some(1) => {
+                let coordinate = |label: i64| bytes(label).and_then(|value| match value.len() {
+                    32 => Ok(p256::FieldBytes::clone_from_slice(value)),
+                    _ => Err(WebAuthnError::UnsupportedKey("bad P-256 coordinate".to_string())),
+                });
+                let point = p256::EncodedPoint::from_affine_coordinates(&coordinate(-2)?, &coordinate(-3)?, false);
+                p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map(Self::Es256)
+                    .map_err(|_| WebAuthnError::UnsupportedKey("not a P-256 point".to_string()))
+            }
+            (Some(1), Some(EDDSA)) if int(-1) == Some(6) => {
+                let x: &[u8; 32] = bytes(-2)?.try_into()
+                    .map_err(|_| WebAuthnError::UnsupportedKey("bad Ed25519 key".to_string()))?;
+                ed25519_dalek::VerifyingKey::from_bytes(x)
+                    .map(Self::Ed25519)
+                    .map_err(|_| WebAuthnError::UnsupportedKey("bad Ed25519 key".to_string()))
+            }
+            (Some(3), Some(RS256)) => {
+                // RSA keys use -1 and -2 for the modulus and exponent
+                rsa::RsaPublicKey::new(rsa::BigUint::from_bytes_be(bytes(-1)?), rsa::BigUint::from_bytes_be(bytes(-2)?))
+                    .map(Self::Rs256)
+                    .map_err(|e| WebAuthnError::UnsupportedKey(e.to_string()))
+            }
+            (kty, alg) => Err(WebAuthnError::UnsupportedKey(format!("key type {:?} with algorithm {:?}", kty, alg))),
+        }
+    }
+
+    fn algorithm(&self) -> i64 {
+        match self {
+            Self::Es256(_) => ES256,
+            Self::Ed25519(_) => EDDSA,
+            Self::Rs256(_) => RS256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), WebAuthnError> {
+        use p256::ecdsa::signature::Verifier;
+
+        let valid = match self {
+            // WebAuthn ECDSA signatures are DER, unlike JOSE's
+            Self::Es256(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            Self::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify_strict(message, &signature).is_ok()),
+            Self::Rs256(key) => key.verify(rsa::Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(message), signature).is_ok(),
+        };
+
+        if valid { Ok(()) } else { Err(WebAuthnError::InvalidSignature) }
+    }
+}
+
+/// The CBOR values authenticators send. Floats and indefinite lengths aren't
+/// allowed in CTAP2's encoding, so they're refused.
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Int(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Bool(bool),
+    Null,
+}
+
+impl Cbor {
+    /// Decode a value that makes up the whole input
+    fn decode(data: &[u8]) -> Result<Self, WebAuthnError> {
+        let (value, len) = Self::decode_prefix(data)?;
+        if len != data.len() {
+            return Err(WebAuthnError::InvalidAuthenticatorData("trailing CBOR data".to_string()));
+        }
+        Ok(value)
+    }
+
+    /// Decode the value at the start of the input, returning it and its length
+    fn decode_prefix(data: &[u8]) -> Result<(Self, usize), WebAuthnError> {
+        let mut pos = 0;
+        let value = Self::read(data, &mut pos, 0)?;
+        Ok((value, pos))
+    }
+
+    fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], WebAuthnError> {
+        let bytes = data.get(*pos..*pos + len)
+            .ok_or_else(|| WebAuthnError::InvalidAuthenticatorData("CBOR: cut short".to_string()))?;
+        *pos += len;
+        Ok(bytes)
+    }
+
+    fn read(data: &[u8], pos: &mut usize, depth: usize) -> Result<Self, WebAuthnError> {
+        let invalid = |reason: &str| WebAuthnError::InvalidAuthenticatorData(format!("CBOR: {}", reason));
+        if depth > MAX_CBOR_DEPTH {
+            return Err(invalid("nested too deep"));
+        }
+
+        let initial = *data.get(*pos).ok_or_else(|| invalid("cut short"))?;
+        *pos += 1;
+        let major = initial >> 5;
+        let info = initial & 0x1f;
+
+        let argument = match info {
+            0..=23 => info as u64,
+            24 => Self::take(data, pos, 1)?[0] as u64,
+            25 => u16::from_be_bytes(Self::take(data, pos, 2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(Self::take(data, pos, 4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(Self::take(data, pos, 8)?.try_into().unwrap()),
+            _ => return Err(invalid("indefinite lengths aren't allowed")),
+        };
+        // Lengths can't be more than what's left, which also bounds allocations
+        let remaining = data.len() - *pos;
+        let length = || -> Result<usize, WebAuthnError> {
+            usize::try_from(argument).ok()
+                .filter(|&len| len <= remaining)
+                .ok_or_else(|| invalid("length past the end"))
+        };
+
+        Ok(match major {
+            0 => Self::Int(argument as i128),
+            1 => Self::Int(-1 - argument as i128),
+            2 => Self::Bytes(Self::take(data, pos, length()?)?.to_vec()),
+            3 => Self::Text(String::from_utf8(Self::take(data, pos, length()?)?.to_vec())
+                .map_err(|_| invalid("text isn't UTF-8"))?),
+            4 => {
+                let len = length()?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(Self::read(data, pos, depth + 1)?);
+                }
+                Self::Array(items)
+            }
+            5 => {
+                let len = length()?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let key = Self::read(data, pos, depth + 1)?;
+                    let value = Self::read(data, pos, depth + 1)?;
+                    entries.push((key, value));
+                }
+                Self::Map(entries)
+            }
+            6 => Self::read(data, pos, depth + 1)?,
+            _ => match info {
+                20 => Self::Bool(false),
+                21 => Self::Bool(true),
+                22 => Self::Null,
+                _ => return Err(invalid("unsupported simple value")),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+
+    const ORIGIN: &str = "https://music.example.com";
+
+    fn webauthn() -> WebAuthn {
+        WebAuthn::new(WebAuthnConfig {
+            rp_id: "music.example.com".to_string(),
+            rp_name: "Muse".to_string(),
+            origin: ORIGIN.to_string(),
+        })
+    }
+
+    // Just enough CBOR encoding to play the authenticator
+    fn head(major: u8, value: usize) -> Vec<u8> {
+        match value {
+            0..=23 => vec![major << 5 | value as u8],
+            24..=255 => vec![major << 5 | 24, value as u8],
+            _ => [vec![major << 5 | 25], (value as u16).to_be_bytes().to_vec()].concat(),
+        }
+    }
+
+    fn int(value: i64) -> Vec<u8> {
+        if value >= 0 { head(0, value as usize) } else { head(1, (-1 - value) as usize) }
+    }
+
+    fn bytes(value: &[u8]) -> Vec<u8> {
+        [head(2, value.len()), value.to_vec()].concat()
+    }
+
+    fn text(value: &str) -> Vec<u8> {
+        [head(3, value.len()), value.as_bytes().to_vec()].concat()
+    }
+
+    fn map(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut out = head(5, entries.len());
+        for (key, value) in entries {
+            out.extend_from_slice(key);
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn es256_cose(key: &p256::ecdsa::SigningKey) -> Vec<u8> {
+        let point = key.verifying_key().to_encoded_point(false);
+        map(&[
+            (int(1), int(2)),
+            (int(3), int(ES256)),
+            (int(-1), int(1)),
+            (int(-2), bytes(point.x().unwrap())),
+            (int(-3), bytes(point.y().unwrap())),
+        ])
+    }
+
+    fn auth_data(flags: u8, sign_count: u32, credential: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = Sha256::digest(b"music.example.com").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        if let Some((id, key)) = credential {
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(id.len() as u16).to_be_bytes());
+            data.extend_from_slice(id);
+            data.extend_from_slice(key);
+        }
+        data
+    }
+
+    fn client_data(kind: &str, options: &Value, origin: &str) -> Vec<u8> {
+        json!({ "type": kind, "challenge": options["challenge"], "origin": origin }).to_string().into_bytes()
+    }
+
+    fn registration(options: &Value, id: &[u8], cose: &[u8], origin: &str) -> RegistrationCredential {
+        let attestation = map(&[
+            (text("fmt"), text("none")),
+            (text("attStmt"), map(&[])),
+            (text("authData"), bytes(&auth_data(USER_PRESENT | ATTESTED_CREDENTIAL, 0, Some((id, cose))))),
+        ]);
+        RegistrationCredential {
+            id: URL_SAFE_NO_PAD.encode(id),
+            response: AttestationResponse {
+                client_data_json: URL_SAFE_NO_PAD.encode(client_data("webauthn.create", options, origin)),
+                attestation_object: URL_SAFE_NO_PAD.encode(attestation),
+            },
+        }
+    }
+
+    fn login(options: &Value, id: &[u8], flags: u8, sign_count: u32, sign: impl Fn(&[u8]) -> Vec<u8>) -> LoginCredential {
+        let auth_data = auth_data(flags, sign_count, None);
+        let client_data = client_data("webauthn.get", options, ORIGIN);
+        let signature = sign(&[auth_data.clone(), Sha256::digest(&client_data).to_vec()].concat());
+        LoginCredential {
+            id: URL_SAFE_NO_PAD.encode(id),
+            response: AssertionResponse {
+                client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+                authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+                signature: URL_SAFE_NO_PAD.encode(signature),
+                user_handle: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_cbor_decoding() {
+        // {1: -7, "a": [h'0102', true, null]}
+        let data = [0xa2, 0x01, 0x26, 0x61, b'a', 0x83, 0x42, 0x01, 0x02, 0xf5, 0xf6];
+        assert_eq!(Cbor::decode(&data).unwrap(), Cbor::Map(vec![
+            (Cbor::Int(1), Cbor::Int(-7)),
+            (Cbor::Text("a".to_string()), Cbor::Array(vec![Cbor::Bytes(vec![1, 2]), Cbor::Bool(true), Cbor::Null])),
+        ]));
+
+        assert!(Cbor::decode(&[0x5a, 0xff, 0xff, 0xff, 0xff]).is_err(), "length past the end");
+        assert!(Cbor::decode(&[0x9f, 0xff]).is_err(), "indefinite length");
+        assert!(Cbor::decode(&[0x01, 0x02]).is_err(), "trailing data");
+        assert!(Cbor::decode(&[0x81; 32]).is_err(), "too deep");
+    }
+
+    #[test]
+    fn test_es256_registration_and_login() {
+        let webauthn = webauthn();
+        let key = p256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap();
+        let sign = |message: &[u8]| {
+            let signature: p256::ecdsa::Signature = key.sign(message);
+            signature.to_der().as_bytes().to_vec()
+        };
+
+        let options = webauthn.start_registration("user1", "ada", &[]).unwrap();
+        assert_eq!(options["rp"]["id"], "music.example.com");
+        assert_eq!(options["user"]["id"], URL_SAFE_NO_PAD.encode("user1"));
+        let credential = webauthn.finish_registration("user1", &registration(&options, b"cred1", &es256_cose(&key), ORIGIN)).unwrap();
+        assert_eq!(credential.id, URL_SAFE_NO_PAD.encode(b"cred1"));
+        assert_eq!(credential.algorithm, ES256);
+
+        let options = webauthn.start_login(&[]).unwrap();
+        let verified = webauthn.finish_login(&login(&options, b"cred1", USER_PRESENT | USER_VERIFIED, 1, sign), &credential.public_key, 0).unwrap();
+        assert_eq!(verified.sign_count, 1);
+        assert!(verified.user_verified);
+
+        // Each challenge works once
+        let replay = login(&options, b"cred1", USER_PRESENT, 2, sign);
+        assert!(matches!(webauthn.finish_login(&replay, &credential.public_key, 1), Err(WebAuthnError::UnknownChallenge)));
+
+        let options = webauthn.start_login(&[]).unwrap();
+        let mut forged = login(&options, b"cred1", USER_PRESENT, 2, sign);
+        forged.response.signature = URL_SAFE_NO_PAD.encode(sign(b"something else"));
+        assert!(matches!(webauthn.finish_login(&forged, &credential.public_key, 1), Err(WebAuthnError::InvalidSignature)));
+
+        let options = webauthn.start_login(&[]).unwrap();
+        let cloned = login(&options, b"cred1", USER_PRESENT, 1, sign);
+        assert!(matches!(webauthn.finish_login(&cloned, &credential.public_key, 1), Err(WebAuthnError::CounterRegression)));
+
+        let options = webauthn.start_login(&[]).unwrap();
+        let absent = login(&options, b"cred1", 0, 5, sign);
+        assert!(matches!(webauthn.finish_login(&absent, &credential.public_key, 1), Err(WebAuthnError::InvalidAuthenticatorData(_))));
+    }
+
+    #[test]
+    fn test_registration_checks_origin_and_user() {
+        let webauthn = webauthn();
+        let cose = es256_cose(&p256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap());
+
+        let options = webauthn.start_registration("user1", "ada", &[]).unwrap();
+        let result = webauthn.finish_registration("user1", &registration(&options, b"cred1", &cose, "https://evil.example.com"));
+        assert!(matches!(result, Err(WebAuthnError::InvalidClientData(_))));
+
+        let options = webauthn.start_registration("user1", "ada", &[]).unwrap();
+        let result = webauthn.finish_registration("user2", &registration(&options, b"cred1", &cose, ORIGIN));
+        assert!(matches!(result, Err(WebAuthnError::UnknownChallenge)));
+
+        // A login challenge can't be used to register
+        let options = webauthn.start_login(&[]).unwrap();
+        let result = webauthn.finish_registration("user1", &registration(&options, b"cred1", &cose, ORIGIN));
+        assert!(matches!(result, Err(WebAuthnError::UnknownChallenge)));
+
+        let short_key = map(&[
+            (int(1), int(2)),
+            (int(3), int(ES256)),
+            (int(-1), int(1)),
+            (int(-2), bytes(&[1; 31])),
+            (int(-3), bytes(&[1; 32])),
+        ]);
+        let options = webauthn.start_registration("user1", "ada", &[]).unwrap();
+        let result = webauthn.finish_registration("user1", &registration(&options, b"cred1", &short_key, ORIGIN));
+        assert!(matches!(result, Err(WebAuthnError::UnsupportedKey(_))));
+    }
+
+    #[test]
+    fn test_ed25519_login() {
+        use ed25519_dalek::Signer;
+
+        let webauthn = webauthn();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let cose = map(&[
+            (int(1), int(1)),
+            (int(3), int(EDDSA)),
+            (int(-1), int(6)),
+            (int(-2), bytes(key.verifying_key().as_bytes())),
+        ]);
+
+        let options = webauthn.start_login(&[URL_SAFE_NO_PAD.encode(b"cred2")]).unwrap();
+        assert_eq!(options["allowCredentials"][0]["id"], URL_SAFE_NO_PAD.encode(b"cred2"));
+        let credential = login(&options, b"cred2", USER_PRESENT, 0, |message| key.sign(message).to_bytes().to_vec());
+        let verified = webauthn.finish_login(&credential, &cose, 0).unwrap();
+        assert!(!verified.user_verified);
+    }
+}
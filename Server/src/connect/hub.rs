@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+
+use crate::connect::protocol::{ClientMessage, DeviceInfo, PlaybackState, ServerMessage};
+
+/// Messages waiting for a slow device before new ones are dropped
+const DEVICE_QUEUE_SIZE: usize = 64;
+
+struct Device {
+    name: String,
+    /// Tells a reconnect under the same id apart from the connection it replaced
+    connection: u64,
+    sender: mpsc::Sender<ServerMessage>,
+    state: Option<PlaybackState>,
+}
+
+/// A device's registration, holding the messages pushed to it
+pub struct DeviceSession {
+    pub device_id: String,
+    connection: u64,
+    pub messages: mpsc::Receiver<ServerMessage>,
+}
+
+/// Connected devices, grouped by user. Devices only ever see and control
+/// their own user's other devices.
+#[derive(Default)]
+pub struct ConnectHub {
+    users: Mutex<HashMap<String, HashMap<String, Device>>>,
+    next_connection: AtomicU64,
+}
+
+impl ConnectHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a device. A device already connected under the same id is
+    /// replaced and its session ends.
+    pub fn connect(&self, user_id: &str, device_id: &str, name: &str) -> DeviceSession {
+        let (sender, messages) = mpsc::channel(DEVICE_QUEUE_SIZE);
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+
+        let mut users = self.users.lock().unwrap();
+        let devices = users.entry(user_id.to_string()).or_default();
+        devices.insert(device_id.to_string(), Device {
+            name: name.to_string(),
+            connection,
+            sender,
+            state: None,
+        });
+
+        let device_list = device_list(devices);
+        send(&devices[device_id], ServerMessage::Welcome {
+            device_id: device_id.to_string(),
+            devices: device_list.clone(),
+        });
+        broadcast(devices, ServerMessage::Devices { devices: device_list }, Some(device_id));
+
+        DeviceSession {
+            device_id: device_id.to_string(),
+            connection,
+            messages,
+        }
+    }
+
+    /// Unregister a device when its socket closes, unless it has already
+    /// been replaced by a newer connection
+    pub fn disconnect(&self, user_id: &str, session: &DeviceSession) {
+        let mut users = self.users.lock().unwrap();
+        let Some(devices) = users.get_mut(user_id) else {
+            return;
+        };
+
+        if devices.get(&session.device_id).is_none_or(|device| device.connection != session.connection) {
+            return;
+        }
+        devices.remove(&session.device_id);
+
+        if devices.is_empty() {
+            users.remove(user_id);
+        } else {
+            broadcast(devices, ServerMessage::Devices { devices: device_list(devices) }, None);
+        }
+    }
+
+    /// Act on a message from one of a user's devices
+    pub fn handle(&self, user_id: &str, device_id: &str, message: ClientMessage) {
+        let mut users = self.users.lock().unwrap();
+        let Some(devices) = users.get_mut(user_id) else {
+            return;
+        };
+
+        match message {
+            ClientMessage::Command { target, command } => {
+                let error = match (command.validate(), devices.get(&target)) {
+                    (Err(message), _) => Some(message),
+                    (Ok(()), None) => Some(format!("No device {} is connected", target)),
+                    (Ok(()), Some(target)) => {
+                        send(target, ServerMessage::Command { from: device_id.to_string(), command });
+                        None
+                    }
+                };
+
+                if let (Some(message), Some(device)) = (error, devices.get(device_id)) {
+                    send(device, ServerMessage::Error { message });
+                }
+            }
+            ClientMessage::State { state } => {
+                if let Some(device) = devices.get_mut(device_id) {
+                    device.state = Some(state.clone());
+                }
+                broadcast(devices, ServerMessage::NowPlaying { device_id: device_id.to_string(), state }, Some(device_id));
+            }
+            // Handled by the socket
+            ClientMessage::Reauth { .. } => {}
+        }
+    }
+}
+
+fn device_list(devices: &HashMap<String, Device>) -> Vec<DeviceInfo> {
+    let mut list: Vec<DeviceInfo> = devices.iter()
+        .map(|(id, device)| DeviceInfo {
+            id: id.clone(),
+            name: device.name.clone(),
+            state: device.state.clone(),
+        })
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+    list
+}
+
+fn broadcast(devices: &HashMap<String, Device>, message: ServerMessage, except: Option<&str>) {
+    for (id, device) in devices {
+        if except != Some(id.as_str()) {
+            send(device, message.clone());
+        }
+    }
+}
+
+/// Queue a message without waiting. A device that has fallen this far
+/// behind misses it, and catches up from the next state push.
+fn send(device: &Device, message: ServerMessage) {
+    if device.sender.try_send(message).is_err() {
+        tracing::debug!("Dropped a Connect message for slow or closed device {}", device.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect::protocol::Command;
+
+    fn drain(session: &mut DeviceSession) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(message) = session.messages.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_connect_announces_devices() {
+        let hub = ConnectHub::new();
+        let mut laptop = hub.connect("u1", "laptop", "Laptop");
+        let mut tv = hub.connect("u1", "tv", "Living room");
+
+        let welcome = drain(&mut tv);
+        assert!(matches!(&welcome[0], ServerMessage::Welcome { device_id, devices } if device_id == "tv" && devices.len() == 2));
+
+        let laptop_messages = drain(&mut laptop);
+        assert!(matches!(laptop_messages.last(), Some(ServerMessage::Devices { devices }) if devices.len() == 2));
+    }
+
+    #[test]
+    fn test_command_reaches_target_only() {
+        let hub = ConnectHub::new();
+        let mut laptop = hub.connect("u1", "laptop", "Laptop");
+        let mut tv = hub.connect("u1", "tv", "Living room");
+        let mut other = hub.connect("u2", "phone", "Phone");
+        drain(&mut laptop);
+        drain(&mut tv);
+        drain(&mut other);
+
+        hub.handle("u1", "laptop", ClientMessage::Command { target: "tv".to_string(), command: Command::Pause });
+
+        assert_eq!(drain(&mut tv), vec![ServerMessage::Command { from: "laptop".to_string(), command: Command::Pause }]);
+        assert!(drain(&mut laptop).is_empty());
+        assert!(drain(&mut other).is_empty());
+    }
+
+    #[test]
+    fn test_command_errors_go_back_to_sender() {
+        let hub = ConnectHub::new();
+        let mut laptop = hub.connect("u1", "laptop", "Laptop");
+        hub.connect("u2", "tv", "Someone else's TV");
+        drain(&mut laptop);
+
+        hub.handle("u1", "laptop", ClientMessage::Command { target: "tv".to_string(), command: Command::Play });
+        hub.handle("u1", "laptop", ClientMessage::Command { target: "laptop".to_string(), command: Command::SetVolume { volume: 200 } });
+
+        let errors = drain(&mut laptop);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|message| matches!(message, ServerMessage::Error { .. })));
+    }
+
+    #[test]
+    fn test_state_is_pushed_and_kept() {
+        let hub = ConnectHub::new();
+        let mut laptop = hub.connect("u1", "laptop", "Laptop");
+        let mut tv = hub.connect("u1", "tv", "Living room");
+        drain(&mut laptop);
+        drain(&mut tv);
+
+        let state = PlaybackState { song_id: Some("s1".to_string()), is_playing: true, position_ms: 5000, volume: 60 };
+        hub.handle("u1", "tv", ClientMessage::State { state: state.clone() });
+
+        assert_eq!(drain(&mut laptop), vec![ServerMessage::NowPlaying { device_id: "tv".to_string(), state: state.clone() }]);
+        assert!(drain(&mut tv).is_empty());
+
+        let mut phone = hub.connect("u1", "phone", "Phone");
+        let ServerMessage::Welcome { devices, .. } = &drain(&mut phone)[0] else {
+            panic!("expected a welcome");
+        };
+        assert_eq!(devices.iter().find(|device| device.id == "tv").unwrap().state, Some(state));
+    }
+
+    #[test]
+    fn test_reconnect_replaces_old_session() {
+        let hub = ConnectHub::new();
+        let old = hub.connect("u1", "tv", "Living room");
+        let mut laptop = hub.connect("u1", "laptop", "Laptop");
+        let new = hub.connect("u1", "tv", "Living room");
+        drain(&mut laptop);
+
+        // The old socket closing must not unregister its replacement
+        hub.disconnect("u1", &old);
+        assert!(drain(&mut laptop).is_empty());
+
+        hub.disconnect("u1", &new);
+        assert!(matches!(drain(&mut laptop).as_slice(), [ServerMessage::Devices { devices }] if devices.len() == 1));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod hub;
+pub mod protocol;
+
+pub use hub::ConnectHub;
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+/// A playback command one device sends to another
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Command {
+    Play,
+    Pause,
+    Seek { position_ms: i64 },
+    Next,
+    Previous,
+    /// 0 to 100
+    SetVolume { volume: u8 },
+}
+
+impl Command {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Command::Seek { position_ms } if *position_ms < 0 => Err("position_ms can't be negative".to_string()),
+            Command::SetVolume { volume } if *volume > 100 => Err("volume goes from 0 to 100".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a device is playing, as it last reported
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackState {
+    pub song_id: Option<String>,
+    pub is_playing: bool,
+    pub position_ms: i64,
+    pub volume: u8,
+}
+
+/// Messages devices send over the socket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Ask another of the user's devices to do something
+    Command { target: String, command: Command },
+    /// Report this device's playback, pushed to every other device
+    State { state: PlaybackState },
+    /// A refreshed token for the same user, to stay connected past the
+    /// expiry of the one the socket was opened with
+    Reauth { token: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub state: Option<PlaybackState>,
+}
+
+/// Messages the server pushes to devices
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Sent once on connecting, with the id this device was registered under
+    Welcome { device_id: String, devices: Vec<DeviceInfo> },
+    /// The user's devices changed, one came or went
+    Devices { devices: Vec<DeviceInfo> },
+    /// A command for this device to carry out
+    Command { from: String, command: Command },
+    NowPlaying { device_id: String, state: PlaybackState },
+    /// A `reauth` was accepted, and the socket stays open until this time
+    Reauthenticated {
+        #[serde(with = "time::serde::rfc3339")]
+        expires_at: OffsetDateTime,
+    },
+    Error { message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type":"command","target":"tv","command":{"action":"seek","position_ms":30000}}"#
+        ).unwrap();
+
+        assert_eq!(message, ClientMessage::Command {
+            target: "tv".to_string(),
+            command: Command::Seek { position_ms: 30000 },
+        });
+    }
+
+    #[test]
+    fn test_parse_state() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type":"state","state":{"song_id":"s1","is_playing":true,"position_ms":1000,"volume":80}}"#
+        ).unwrap();
+
+        let ClientMessage::State { state } = message else {
+            panic!("expected a state message");
+        };
+        assert_eq!(state.song_id.as_deref(), Some("s1"));
+        assert!(state.is_playing);
+    }
+
+    #[test]
+    fn test_reauth_messages() {
+        let message: ClientMessage = serde_json::from_str(r#"{"type":"reauth","token":"abc"}"#).unwrap();
+        assert_eq!(message, ClientMessage::Reauth { token: "abc".to_string() });
+
+        let message = ServerMessage::Reauthenticated { expires_at: OffsetDateTime::from_unix_timestamp(0).unwrap() };
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"reauthenticated","expires_at":"1970-01-01T00:00:00Z"}"#
+        );
+    }
+
+    #[test]
+    fn test_validate_command() {
+        assert!(Command::SetVolume { volume: 100 }.validate().is_ok());
+        assert!(Command::SetVolume { volume: 101 }.validate().is_err());
+        assert!(Command::Seek { position_ms: -1 }.validate().is_err());
+        assert!(Command::Next.validate().is_ok());
+    }
+
+    #[test]
+    fn test_serialize_server_message() {
+        let message = ServerMessage::Command { from: "laptop".to_string(), command: Command::Pause };
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"command","from":"laptop","command":{"action":"pause"}}"#
+        );
+    }
+}
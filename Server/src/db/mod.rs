@@ -1,11 +1,19 @@
 pub mod models;
+pub mod paging;
+pub mod playlist_edit;
+pub mod smart_rules;
 pub mod sqlite;
 pub mod postgres;
 pub mod mongo;
 
-use crate::db::models::{Artist, Playlist, PlaylistShare, Song, User};
+use crate::auth::permissions::built_in_roles;
+use crate::db::models::{ApiKey, Artist, AuditEvent, AuditFilter, GrantKind, Library, LibraryFilter, LibraryGrant, Passkey, PlayQueue, Playlist, PlaylistActivity, PlaylistEntry, PlaylistShare, RefreshToken, Role, ShareLink, SharePermission, ShareTarget, Song, SongLyrics, TwoFactor, User, UserRating};
+use crate::db::paging::{Page, PageRequest};
+use crate::db::playlist_edit::{EditedEntry, PlaylistEdit};
+use crate::db::smart_rules::SmartRules;
 use async_trait::async_trait;
 use std::sync::Arc;
+use time::OffsetDateTime;
 
 #[derive(Debug, thiserror::Error)]
 pub enum DbError {
@@ -18,12 +26,45 @@ pub enum DbError {
     #[error("User already exists")]
     UserAlreadyExists,
     
+    #[error("Role not found")]
+    RoleNotFound,
+    
+    #[error("Refresh token not found")]
+    RefreshTokenNotFound,
+    
+    #[error("Identity not found")]
+    IdentityNotFound,
+    
+    #[error("Identity already linked")]
+    IdentityAlreadyLinked,
+    
+    #[error("Two-factor authentication not set up")]
+    TwoFactorNotFound,
+    
+    #[error("Passkey not found")]
+    PasskeyNotFound,
+    
+    #[error("API key not found")]
+    ApiKeyNotFound,
+    
+    #[error("Library not found")]
+    LibraryNotFound,
+    
+    #[error("Library grant not found")]
+    GrantNotFound,
+    
     #[error("Invalid credentials")]
     #[allow(dead_code)]
     InvalidCredentials,
     
     #[error("Configuration error: {0}")]
     ConfigError(String),
+    
+    #[error("Modified by another client since the expected version")]
+    VersionConflict,
+    
+    #[error("Invalid playlist position: {0}")]
+    InvalidPosition(usize),
 }
 
 /// Database abstraction trait for user operations
@@ -41,8 +82,8 @@ pub trait Database: Send + Sync {
     /// Get user by ID
     async fn get_user_by_id(&self, id: &str) -> Result<User, DbError>;
     
-    /// Update user's admin status
-    async fn update_user_admin_status(&self, id: &str, is_admin: bool) -> Result<(), DbError>;
+    /// Give a user another role
+    async fn update_user_role(&self, id: &str, role: &str) -> Result<(), DbError>;
     
     /// Check if username exists
     async fn username_exists(&self, username: &str) -> Result<bool, DbError>;
@@ -54,8 +95,8 @@ pub trait Database: Send + Sync {
     async fn initialize(&self) -> Result<(), DbError>;
     
     // Admin operations
-    /// Get all users with pagination
-    async fn get_all_users(&self, offset: usize, limit: usize) -> Result<Vec<User>, DbError>;
+    /// Get a page of all users
+    async fn get_all_users(&self, page: &PageRequest) -> Result<Page<User>, DbError>;
     
     /// Update user email
     async fn update_user_email(&self, username: &str, new_email: &str) -> Result<(), DbError>;
@@ -73,9 +114,135 @@ pub trait Database: Send + Sync {
     async fn delete_user_by_id(&self, user_id: &str) -> Result<(), DbError>;
     
     /// Get total user count
-    #[allow(dead_code)]
     async fn get_total_users(&self) -> Result<usize, DbError>;
     
+    // Single sign-on identity operations
+    /// Get the user an identity provider's subject is linked to
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, DbError>;
+    
+    /// Link a subject at an identity provider to a user. Each subject and each
+    /// user can only be linked once per provider.
+    async fn link_identity(&self, issuer: &str, subject: &str, user_id: &str) -> Result<(), DbError>;
+    
+    /// Unlink a user from an identity provider
+    async fn unlink_identity(&self, issuer: &str, user_id: &str) -> Result<(), DbError>;
+    
+    // Refresh token operations
+    /// Store a refresh token by the hash of its value
+    async fn create_refresh_token(&self, token_hash: &str, family_id: &str, user_id: &str, expires_at: OffsetDateTime) -> Result<RefreshToken, DbError>;
+    
+    /// Get a refresh token by the hash of its value
+    async fn get_refresh_token(&self, token_hash: &str) -> Result<RefreshToken, DbError>;
+    
+    /// Mark a refresh token used, returning false if it already was
+    async fn use_refresh_token(&self, token_hash: &str) -> Result<bool, DbError>;
+    
+    /// Delete every token in a family, ending that login
+    async fn delete_refresh_token_family(&self, family_id: &str) -> Result<(), DbError>;
+    
+    /// Delete a user's refresh tokens, ending their logins other than the one
+    /// in `keep_family`
+    async fn delete_user_refresh_tokens(&self, user_id: &str, keep_family: Option<&str>) -> Result<(), DbError>;
+    
+    /// Delete refresh tokens that have expired
+    async fn delete_expired_refresh_tokens(&self) -> Result<(), DbError>;
+    
+    // Two-factor authentication operations
+    /// Get a user's authenticator
+    async fn get_two_factor(&self, user_id: &str) -> Result<TwoFactor, DbError>;
+    
+    /// Create or replace a user's authenticator secret, keeping the last step used
+    async fn save_two_factor(&self, user_id: &str, secret: &str, enabled: bool) -> Result<(), DbError>;
+    
+    /// Record the time step of an accepted code, returning false if it isn't
+    /// later than the last one used
+    async fn use_totp_step(&self, user_id: &str, step: i64) -> Result<bool, DbError>;
+    
+    /// Delete a user's authenticator and recovery codes
+    async fn delete_two_factor(&self, user_id: &str) -> Result<(), DbError>;
+    
+    /// Replace a user's recovery codes with new ones, by the hash of each
+    async fn replace_recovery_codes(&self, user_id: &str, code_hashes: &[String]) -> Result<(), DbError>;
+    
+    /// Delete a recovery code by its hash, returning false if the user doesn't have it
+    async fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool, DbError>;
+    
+    /// Count a user's unused recovery codes
+    async fn count_recovery_codes(&self, user_id: &str) -> Result<usize, DbError>;
+    
+    // Passkey operations
+    /// Store a passkey a user registered
+    async fn create_passkey(&self, passkey: &Passkey) -> Result<(), DbError>;
+    
+    /// Get a passkey by its credential ID
+    async fn get_passkey(&self, id: &str) -> Result<Passkey, DbError>;
+    
+    /// Get a user's passkeys, oldest first
+    async fn get_user_passkeys(&self, user_id: &str) -> Result<Vec<Passkey>, DbError>;
+    
+    /// Record a login with a passkey and the signature counter it gave
+    async fn use_passkey(&self, id: &str, sign_count: i64) -> Result<(), DbError>;
+    
+    /// Delete one of a user's passkeys
+    async fn delete_passkey(&self, user_id: &str, id: &str) -> Result<(), DbError>;
+    
+    // API key operations
+    /// Store a key a user made
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<(), DbError>;
+    
+    /// Get a key by the hash of its value
+    async fn get_api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError>;
+    
+    /// Get a user's keys, oldest first
+    async fn get_user_api_keys(&self, user_id: &str) -> Result<Vec<ApiKey>, DbError>;
+    
+    /// Record that a key was used just now
+    async fn touch_api_key(&self, id: &str) -> Result<(), DbError>;
+    
+    /// Delete one of a user's keys
+    async fn delete_api_key(&self, user_id: &str, id: &str) -> Result<(), DbError>;
+    
+    // Role operations
+    /// Get every role, by name
+    async fn get_roles(&self) -> Result<Vec<Role>, DbError>;
+    
+    /// Get a role by name
+    async fn get_role(&self, name: &str) -> Result<Role, DbError>;
+    
+    /// Create a role, or replace the one with its name
+    async fn save_role(&self, role: &Role) -> Result<(), DbError>;
+    
+    /// Delete a role by name
+    async fn delete_role(&self, name: &str) -> Result<(), DbError>;
+    
+    /// Count the users with a role
+    async fn count_users_with_role(&self, role: &str) -> Result<usize, DbError>;
+    
+    // Library operations
+    /// Get every library, by name
+    async fn get_libraries(&self) -> Result<Vec<Library>, DbError>;
+    
+    /// Get a library by ID
+    async fn get_library(&self, id: &str) -> Result<Library, DbError>;
+    
+    /// Create a library, or replace the one with its ID
+    async fn save_library(&self, library: &Library) -> Result<(), DbError>;
+    
+    /// Delete a library and its grants. Its songs have to be removed first.
+    async fn delete_library(&self, id: &str) -> Result<(), DbError>;
+    
+    /// Get the grants of a library
+    async fn get_library_grants(&self, library_id: &str) -> Result<Vec<LibraryGrant>, DbError>;
+    
+    /// Grant a library to a user or role, doing nothing if it already is
+    async fn grant_library(&self, grant: &LibraryGrant) -> Result<(), DbError>;
+    
+    /// Remove a library grant
+    async fn revoke_library(&self, grant: &LibraryGrant) -> Result<(), DbError>;
+    
+    /// Get the IDs of the libraries granted to a user or their role
+    async fn get_accessible_libraries(&self, user_id: &str, role: &str) -> Result<Vec<String>, DbError>;
+    
     // Artist operations
     /// Create a new artist
     async fn create_artist(&self, name: &str) -> Result<Artist, DbError>;
@@ -86,11 +253,8 @@ pub trait Database: Send + Sync {
     /// Get artist by name
     async fn get_artist_by_name(&self, name: &str) -> Result<Artist, DbError>;
     
-    /// Get all artists with pagination
-    async fn get_artists(&self, offset: usize, limit: usize) -> Result<Vec<Artist>, DbError>;
-    
-    /// Get total artist count
-    async fn get_total_artists(&self) -> Result<usize, DbError>;
+    /// Get a page of the artists with songs in the given libraries
+    async fn get_artists(&self, page: &PageRequest, libraries: &LibraryFilter) -> Result<Page<Artist>, DbError>;
     
     /// Update artist cover image path
     #[allow(dead_code)]
@@ -100,30 +264,54 @@ pub trait Database: Send + Sync {
     async fn artist_exists(&self, name: &str) -> Result<bool, DbError>;
     
     // Song operations
-    /// Create a new song
-    async fn create_song(&self, title: &str, artist_id: &str, file_path: &str) -> Result<Song, DbError>;
+    /// Create a new song in a library
+    async fn create_song(&self, title: &str, artist_id: &str, file_path: &str, library_id: &str) -> Result<Song, DbError>;
     
     /// Get song by ID
     async fn get_song_by_id(&self, id: &str) -> Result<Song, DbError>;
     
-    /// Get songs by artist ID
-    async fn get_songs_by_artist(&self, artist_id: &str) -> Result<Vec<Song>, DbError>;
+    /// Get song by the path of its audio file
+    async fn get_song_by_file_path(&self, file_path: &str) -> Result<Song, DbError>;
     
-    /// Get all songs with pagination
-    async fn get_songs(&self, offset: usize, limit: usize) -> Result<Vec<Song>, DbError>;
+    /// Get every song whose audio is in a file, such as the tracks of a cue sheet
+    async fn get_songs_by_file_path(&self, file_path: &str) -> Result<Vec<Song>, DbError>;
     
-    /// Get total song count
-    async fn get_total_songs(&self) -> Result<usize, DbError>;
+    /// Get song by MusicBrainz recording ID
+    async fn get_song_by_musicbrainz_id(&self, musicbrainz_id: &str) -> Result<Song, DbError>;
+    
+    /// Get an artist's songs in the given libraries
+    async fn get_songs_by_artist(&self, artist_id: &str, libraries: &LibraryFilter) -> Result<Vec<Song>, DbError>;
+    
+    /// Get a page of the songs in the given libraries
+    async fn get_songs(&self, page: &PageRequest, libraries: &LibraryFilter) -> Result<Page<Song>, DbError>;
+    
+    /// Search the songs in the given libraries by title (case-insensitive substring match)
+    async fn search_songs(&self, query: &str, libraries: &LibraryFilter, offset: usize, limit: usize) -> Result<Vec<Song>, DbError>;
+    
+    /// Increment a song's play count
+    async fn increment_song_play_count(&self, id: &str) -> Result<(), DbError>;
     
     /// Update song metadata
     async fn update_song_metadata(&self, id: &str, album: Option<&str>, duration: Option<i32>, cover_path: Option<&str>) -> Result<(), DbError>;
     
+    /// Set a song's MusicBrainz recording ID, genre and year, keeping current values for `None`
+    async fn update_song_tags(&self, id: &str, musicbrainz_id: Option<&str>, genre: Option<&str>, year: Option<i32>) -> Result<(), DbError>;
+    
+    /// Set where a cue sheet track starts and ends in its file
+    async fn update_song_track_range(&self, id: &str, start_ms: Option<i64>, end_ms: Option<i64>) -> Result<(), DbError>;
+    
+    /// Set a song's encoder delay, padding and sample rate
+    async fn update_song_gapless(&self, id: &str, encoder_delay: Option<i32>, encoder_padding: Option<i32>, sample_rate: Option<i32>) -> Result<(), DbError>;
+    
     /// Delete a song by ID
     async fn delete_song_by_id(&self, id: &str) -> Result<(), DbError>;
     
     // Playlist operations
-    /// Create a new playlist
-    async fn create_playlist(&self, name: &str, owner_id: &str, is_public: bool) -> Result<Playlist, DbError>;
+    /// Create a new playlist, a smart playlist when `rules` are given
+    async fn create_playlist(&self, name: &str, owner_id: &str, is_public: bool, rules: Option<&SmartRules>) -> Result<Playlist, DbError>;
+    
+    /// Replace a smart playlist's rules and return the new version
+    async fn update_playlist_rules(&self, playlist_id: &str, rules: &SmartRules) -> Result<i64, DbError>;
     
     /// Get playlist by ID
     async fn get_playlist_by_id(&self, id: &str) -> Result<Playlist, DbError>;
@@ -131,11 +319,11 @@ pub trait Database: Send + Sync {
     /// Get playlist by name and owner
     async fn get_playlist_by_name_and_owner(&self, name: &str, owner_id: &str) -> Result<Playlist, DbError>;
     
-    /// Get user's private playlists with pagination
-    async fn get_user_playlists(&self, user_id: &str, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError>;
+    /// Get a page of a user's own playlists
+    async fn get_user_playlists(&self, user_id: &str, page: &PageRequest) -> Result<Page<Playlist>, DbError>;
     
-    /// Get public playlists with pagination
-    async fn get_public_playlists(&self, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError>;
+    /// Get a page of public playlists
+    async fn get_public_playlists(&self, page: &PageRequest) -> Result<Page<Playlist>, DbError>;
     
     /// Get playlists shared with a user
     async fn get_shared_playlists(&self, user_id: &str) -> Result<Vec<(Playlist, PlaylistShare)>, DbError>;
@@ -143,35 +331,96 @@ pub trait Database: Send + Sync {
     /// Delete a playlist
     async fn delete_playlist(&self, playlist_id: &str, owner_id: &str) -> Result<(), DbError>;
     
-    /// Add a song to a playlist
-    async fn add_song_to_playlist(&self, playlist_id: &str, song_id: &str) -> Result<(), DbError>;
+    /// Get the entries of a playlist in order, positioned from 0
+    async fn get_playlist_entries(&self, playlist_id: &str) -> Result<Vec<PlaylistEntry>, DbError>;
+    
+    /// Apply an edit to a playlist's entries and return the new version, with
+    /// the entries it touched as they were when the edit was applied.
+    /// Fails with `VersionConflict` when `expected_version` is stale.
+    async fn edit_playlist_entries(&self, playlist_id: &str, edit: &PlaylistEdit, expected_version: Option<i64>) -> Result<(i64, Vec<EditedEntry>), DbError>;
     
-    /// Remove a song from a playlist
-    async fn remove_song_from_playlist(&self, playlist_id: &str, song_id: &str) -> Result<(), DbError>;
+    /// Get songs in a playlist, in playlist order
+    async fn get_playlist_songs(&self, playlist_id: &str) -> Result<Vec<Song>, DbError> {
+        Ok(self.get_playlist_entries(playlist_id).await?
+            .into_iter()
+            .map(|entry| entry.song)
+            .collect())
+    }
     
-    /// Get songs in a playlist
-    async fn get_playlist_songs(&self, playlist_id: &str) -> Result<Vec<Song>, DbError>;
+    /// Evaluate smart playlist rules against the songs in the given libraries
+    async fn get_smart_playlist_songs(&self, rules: &SmartRules, libraries: &LibraryFilter) -> Result<Vec<Song>, DbError>;
     
     /// Check if a song is in a playlist
     #[allow(dead_code)]
     async fn is_song_in_playlist(&self, playlist_id: &str, song_id: &str) -> Result<bool, DbError>;
     
     /// Share a playlist with a user
-    async fn share_playlist(&self, playlist_id: &str, shared_with_user_id: &str, shared_by_user_id: &str) -> Result<PlaylistShare, DbError>;
+    async fn share_playlist(&self, playlist_id: &str, shared_with_user_id: &str, shared_by_user_id: &str, permission: SharePermission) -> Result<PlaylistShare, DbError>;
     
     /// Revoke playlist share
     async fn revoke_playlist_share(&self, playlist_id: &str, shared_with_user_id: &str) -> Result<(), DbError>;
     
-    /// Check if a playlist is shared with a user
-    #[allow(dead_code)]
-    async fn is_playlist_shared_with_user(&self, playlist_id: &str, user_id: &str) -> Result<bool, DbError>;
+    /// Get a user's share of a playlist, if it is shared with them
+    async fn get_playlist_share(&self, playlist_id: &str, user_id: &str) -> Result<Option<PlaylistShare>, DbError>;
+    
+    /// Get everyone a playlist is shared with
+    async fn get_playlist_shares(&self, playlist_id: &str) -> Result<Vec<PlaylistShare>, DbError>;
+    
+    /// Append to a playlist's activity log
+    async fn record_playlist_activity(&self, activity: &[PlaylistActivity]) -> Result<(), DbError>;
+    
+    /// Get a playlist's most recent activity, newest first
+    async fn get_playlist_activity(&self, playlist_id: &str, limit: usize) -> Result<Vec<PlaylistActivity>, DbError>;
+    
+    // Public share link operations
+    /// Create a public share link whose id is the given token
+    async fn create_share_link(&self, token: &str, owner_id: &str, target: &ShareTarget, password_hash: Option<&str>, expires_at: Option<OffsetDateTime>) -> Result<ShareLink, DbError>;
+    
+    /// Get a share link by its token
+    async fn get_share_link(&self, token: &str) -> Result<ShareLink, DbError>;
+    
+    /// Get the share links a user has created, newest first
+    async fn get_user_share_links(&self, owner_id: &str) -> Result<Vec<ShareLink>, DbError>;
+    
+    /// Revoke one of a user's share links
+    async fn delete_share_link(&self, token: &str, owner_id: &str) -> Result<(), DbError>;
+    
+    // Favourites and ratings
+    /// Save a user's favourite and rating for an item, removing the entry
+    /// once it is neither favourited nor rated
+    async fn save_user_rating(&self, rating: &UserRating) -> Result<(), DbError>;
+    
+    /// Get a user's favourites and ratings, most recently changed first,
+    /// optionally only for one kind of item (`song`, `album` or `artist`)
+    async fn get_user_ratings(&self, user_id: &str, kind: Option<&str>) -> Result<Vec<UserRating>, DbError>;
+    
+    /// Get a user's favourites and ratings for the given song or artist ids.
+    /// For albums the ids are artist ids, and every rated album of theirs is returned.
+    async fn get_user_ratings_for(&self, user_id: &str, kind: &str, item_ids: &[String]) -> Result<Vec<UserRating>, DbError>;
+    
+    // Play queue operations
+    /// Get a user's saved play queue, empty with version 0 if they never saved one
+    async fn get_play_queue(&self, user_id: &str) -> Result<PlayQueue, DbError>;
+    
+    /// Replace a user's play queue and return it with its new version.
+    /// Fails with `VersionConflict` when `expected_version` is stale.
+    async fn save_play_queue(&self, queue: &PlayQueue, expected_version: Option<i64>) -> Result<PlayQueue, DbError>;
+    
+    // Lyrics operations
+    /// Get a song's lyrics, if it has any
+    async fn get_song_lyrics(&self, song_id: &str) -> Result<Option<SongLyrics>, DbError>;
+    
+    /// Save a song's lyrics, replacing any it had
+    async fn save_song_lyrics(&self, lyrics: &SongLyrics) -> Result<(), DbError>;
+    
+    /// Remove a song's lyrics
+    async fn delete_song_lyrics(&self, song_id: &str) -> Result<(), DbError>;
     
     // Admin playlist operations
-    /// Get all playlists with pagination (admin)
-    async fn get_all_playlists(&self, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError>;
+    /// Get a page of all playlists (admin)
+    async fn get_all_playlists(&self, page: &PageRequest) -> Result<Page<Playlist>, DbError>;
     
     /// Get total playlist count (admin)
-    #[allow(dead_code)]
     async fn get_total_playlists(&self) -> Result<usize, DbError>;
     
     /// Update playlist name (admin)
@@ -182,6 +431,50 @@ pub trait Database: Send + Sync {
     
     /// Delete playlist by ID (admin - bypasses owner check)
     async fn delete_playlist_by_id(&self, playlist_id: &str) -> Result<(), DbError>;
+    
+    // Audit log operations
+    /// Append to the audit log
+    async fn record_audit_event(&self, event: &AuditEvent) -> Result<(), DbError>;
+    
+    /// Get a page of the audit log entries matching `filter`
+    async fn get_audit_events(&self, filter: &AuditFilter, page: &PageRequest) -> Result<Page<AuditEvent>, DbError>;
+}
+
+/// ID of the library made from `MUSIC_DIR` when there are none yet
+pub const DEFAULT_LIBRARY_ID: &str = "default";
+
+/// Turn `MUSIC_DIR` into the first library when there are none, granted to
+/// every role so upgraded servers keep showing everyone the same songs.
+/// Songs from before libraries already belong to it.
+pub async fn ensure_default_library(db: &dyn Database, music_dir: &str) -> Result<(), DbError> {
+    if !db.get_libraries().await?.is_empty() {
+        return Ok(());
+    }
+    
+    tracing::info!("Creating the default library for {}", music_dir);
+    db.save_library(&Library {
+        id: DEFAULT_LIBRARY_ID.to_string(),
+        name: "Music".to_string(),
+        root_path: music_dir.to_string(),
+        scan_on_startup: true,
+        recursive: false,
+        created_at: OffsetDateTime::now_utc(),
+    }).await?;
+    
+    for role in db.get_roles().await? {
+        db.grant_library(&LibraryGrant {
+            library_id: DEFAULT_LIBRARY_ID.to_string(),
+            kind: GrantKind::Role,
+            subject: role.name,
+        }).await?;
+    }
+    
+    Ok(())
+}
+
+/// Escape `%`, `_` and the escape character itself for a SQL LIKE pattern
+pub(crate) fn escape_like(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
 }
 
 /// Database backend type
@@ -223,5 +516,15 @@ pub async fn create_database(backend: DbBackend, connection_string: &str) -> Res
     // Initialize the database (create tables/collections)
     db.initialize().await?;
     
+    // Built-in roles always match this version's definitions
+    for (name, description, permissions) in built_in_roles() {
+        db.save_role(&Role {
+            name: name.to_string(),
+            description: description.to_string(),
+            permissions,
+            built_in: true,
+        }).await?;
+    }
+    
     Ok(db)
 }
@@ -1,5 +1,12 @@
+use std::net::IpAddr;
+
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::api_keys::ApiKeyScope;
+use crate::auth::permissions::Permission;
+use crate::db::smart_rules::SmartRules;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -7,11 +14,162 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password_hash: String,
-    pub is_admin: bool,
+    /// Name of the user's [`Role`]
+    pub role: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// A single-use refresh token, stored by the SHA-256 hash of its value. Each
+/// refresh exchanges it for a new one in the same family, which starts at login.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub token_hash: String,
+    pub family_id: String,
+    pub user_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+    /// When it was exchanged. Presenting it again means it was copied.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub used_at: Option<OffsetDateTime>,
     #[serde(with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
 }
 
+impl RefreshToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= OffsetDateTime::now_utc()
+    }
+}
+
+/// A user's TOTP authenticator. It's set up disabled and only enabled once the
+/// user proves their app has it by entering a code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactor {
+    pub user_id: String,
+    /// Base32 secret shared with the authenticator app
+    pub secret: String,
+    pub enabled: bool,
+    /// Time step of the last code accepted, so no code works twice
+    pub last_step: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// A passkey a user can log in with through WebAuthn
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Passkey {
+    /// Credential ID the authenticator made, base64url
+    pub id: String,
+    pub user_id: String,
+    /// What the user called it, like the device it's on
+    pub name: String,
+    /// COSE public key, base64url
+    pub public_key: String,
+    /// COSE algorithm of the key
+    pub algorithm: i64,
+    /// Signature counter last seen, to spot cloned authenticators
+    pub sign_count: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// A key a user made for scripts and other clients without a login, stored
+/// by the SHA-256 hash of its value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub user_id: String,
+    /// What the user called it, like the script it's for
+    pub name: String,
+    /// Start of the key, kept so the user can tell which one is which
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// A named set of permissions users are given
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<Permission>,
+    /// Built-in roles are set up on every start and can't be changed
+    pub built_in: bool,
+}
+
+/// A music folder of its own, which users see when it's granted to them or their role
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Library {
+    pub id: String,
+    pub name: String,
+    /// Folder the library's songs are scanned from
+    pub root_path: String,
+    /// Scan the library when the server starts
+    pub scan_on_startup: bool,
+    /// Scan subfolders too, not just the files in `root_path`
+    pub recursive: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// Who a library grant is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrantKind {
+    User,
+    Role,
+}
+
+impl GrantKind {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "user" => Some(GrantKind::User),
+            "role" => Some(GrantKind::Role),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GrantKind::User => "user",
+            GrantKind::Role => "role",
+        }
+    }
+}
+
+/// Access to a library for one user, by ID, or everyone with a role, by name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LibraryGrant {
+    pub library_id: String,
+    pub kind: GrantKind,
+    pub subject: String,
+}
+
+/// Libraries a song or artist query may return songs from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LibraryFilter {
+    /// Every library, for scans and for users who manage the library
+    All,
+    /// Only the libraries with these IDs
+    Only(Vec<String>),
+}
+
+impl LibraryFilter {
+    pub fn allows(&self, library_id: &str) -> bool {
+        match self {
+            LibraryFilter::All => true,
+            LibraryFilter::Only(ids) => ids.iter().any(|id| id == library_id),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Artist {
     pub id: String,
@@ -30,11 +188,62 @@ pub struct Song {
     pub album: Option<String>,
     pub duration: Option<i32>, // Duration in seconds
     pub file_path: String,
+    /// ID of the [`Library`] the song was scanned into
+    pub library_id: String,
     pub cover_image_path: Option<String>,
+    /// MusicBrainz recording ID read from the file's tags
+    pub musicbrainz_id: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    /// Where a cue sheet track starts in its file, in milliseconds
+    pub start_ms: Option<i64>,
+    /// Where a cue sheet track ends, `None` for the end of the file
+    pub end_ms: Option<i64>,
+    /// Samples of encoder priming and padding, for gapless playback
+    pub encoder_delay: Option<i32>,
+    pub encoder_padding: Option<i32>,
+    pub sample_rate: Option<i32>,
+    pub play_count: i64,
     #[serde(with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
 }
 
+impl Song {
+    /// Whether the song is one track of a cue sheet rather than a whole file
+    pub fn is_cue_track(&self) -> bool {
+        self.start_ms.is_some()
+    }
+}
+
+#[cfg(test)]
+impl Song {
+    /// A song with only a title, whose ID is the title too. Tests set the
+    /// other fields they need with struct update syntax.
+    pub fn for_test(title: &str) -> Self {
+        Song {
+            id: title.to_string(),
+            title: title.to_string(),
+            artist_id: "artist1".to_string(),
+            artist_name: "Artist".to_string(),
+            album: None,
+            duration: None,
+            file_path: format!("/music/{}.mp3", title),
+            library_id: "default".to_string(),
+            cover_image_path: None,
+            musicbrainz_id: None,
+            genre: None,
+            year: None,
+            start_ms: None,
+            end_ms: None,
+            encoder_delay: None,
+            encoder_padding: None,
+            sample_rate: None,
+            play_count: 0,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Playlist {
     pub id: String,
@@ -42,25 +251,534 @@ pub struct Playlist {
     pub owner_id: String,
     pub owner_username: String,
     pub is_public: bool,
+    /// Bumped on every change to the playlist's entries or rules
+    pub version: i64,
+    /// Smart playlists have no entries of their own;
+    /// their songs are generated from `rules` on every read
+    pub is_smart: bool,
+    pub rules: Option<SmartRules>,
     #[serde(with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
 }
 
+/// A song at a position in a playlist; the same song may appear more than once
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[allow(dead_code)]
-pub struct PlaylistSong {
-    pub playlist_id: String,
-    pub song_id: String,
+pub struct PlaylistEntry {
+    pub id: String,
+    pub position: i64,
+    pub song: Song,
     #[serde(with = "time::serde::rfc3339")]
     pub added_at: OffsetDateTime,
 }
 
+/// What a user a playlist is shared with may do, each level including the ones before it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SharePermission {
+    /// Read the playlist
+    #[default]
+    View,
+    /// Add songs
+    Add,
+    /// Add, move and remove songs, and change smart playlist rules
+    Edit,
+    /// Manage who the playlist is shared with
+    Admin,
+}
+
+impl SharePermission {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "view" => Some(SharePermission::View),
+            "add" => Some(SharePermission::Add),
+            "edit" => Some(SharePermission::Edit),
+            "admin" => Some(SharePermission::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SharePermission::View => "view",
+            SharePermission::Add => "add",
+            SharePermission::Edit => "edit",
+            SharePermission::Admin => "admin",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlaylistShare {
     pub id: String,
     pub playlist_id: String,
     pub shared_with_user_id: String,
     pub shared_by_user_id: String,
+    pub permission: SharePermission,
     #[serde(with = "time::serde::rfc3339")]
     pub shared_at: OffsetDateTime,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistAction {
+    Added,
+    Removed,
+    Moved,
+    RulesUpdated,
+}
+
+impl PlaylistAction {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s {
+            "added" => Some(PlaylistAction::Added),
+            "removed" => Some(PlaylistAction::Removed),
+            "moved" => Some(PlaylistAction::Moved),
+            "rules_updated" => Some(PlaylistAction::RulesUpdated),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaylistAction::Added => "added",
+            PlaylistAction::Removed => "removed",
+            PlaylistAction::Moved => "moved",
+            PlaylistAction::RulesUpdated => "rules_updated",
+        }
+    }
+}
+
+/// A change someone made to a playlist. The song's title and artist are
+/// copied so the log still reads sensibly after the song is deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistActivity {
+    pub id: String,
+    pub playlist_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub action: PlaylistAction,
+    pub song_id: Option<String>,
+    pub song_title: Option<String>,
+    pub artist_name: Option<String>,
+    /// Position the song was added at, removed from or moved to
+    pub position: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl PlaylistActivity {
+    pub fn new(playlist_id: &str, user_id: &str, username: &str, action: PlaylistAction) -> Self {
+        PlaylistActivity {
+            id: Uuid::new_v4().to_string(),
+            playlist_id: playlist_id.to_string(),
+            user_id: user_id.to_string(),
+            username: username.to_string(),
+            action,
+            song_id: None,
+            song_title: None,
+            artist_name: None,
+            position: None,
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    pub fn with_song(mut self, song: &Song, position: i64) -> Self {
+        self.song_id = Some(song.id.clone());
+        self.song_title = Some(song.title.clone());
+        self.artist_name = Some(song.artist_name.clone());
+        self.position = Some(position);
+        self
+    }
+}
+
+/// What a public share link gives access to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ShareTarget {
+    Playlist { playlist_id: String },
+    Album { artist_id: String, album: String },
+    Song { song_id: String },
+}
+
+impl ShareTarget {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ShareTarget::Playlist { .. } => "playlist",
+            ShareTarget::Album { .. } => "album",
+            ShareTarget::Song { .. } => "song",
+        }
+    }
+
+    /// Playlist, artist or song id the link points at
+    pub fn target_id(&self) -> &str {
+        match self {
+            ShareTarget::Playlist { playlist_id } => playlist_id,
+            ShareTarget::Album { artist_id, .. } => artist_id,
+            ShareTarget::Song { song_id } => song_id,
+        }
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        match self {
+            ShareTarget::Album { album, .. } => Some(album),
+            _ => None,
+        }
+    }
+
+    /// Rebuild a target from its stored kind, id and album name
+    pub fn from_parts(kind: &str, target_id: String, album: Option<String>) -> Option<Self> {
+        match kind {
+            "playlist" => Some(ShareTarget::Playlist { playlist_id: target_id }),
+            "album" => Some(ShareTarget::Album { artist_id: target_id, album: album? }),
+            "song" => Some(ShareTarget::Song { song_id: target_id }),
+            _ => None,
+        }
+    }
+}
+
+/// A public link that lets anyone holding its token stream a playlist,
+/// album or song without an account. The id is the unguessable token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: String,
+    pub owner_id: String,
+    pub target: ShareTarget,
+    pub password_hash: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl ShareLink {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    }
+}
+
+/// A song, album or artist a user can favourite or rate
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LibraryItem {
+    Song { song_id: String },
+    Album { artist_id: String, album: String },
+    Artist { artist_id: String },
+}
+
+impl LibraryItem {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LibraryItem::Song { .. } => "song",
+            LibraryItem::Album { .. } => "album",
+            LibraryItem::Artist { .. } => "artist",
+        }
+    }
+
+    /// Song or artist id, the artist's for albums
+    pub fn item_id(&self) -> &str {
+        match self {
+            LibraryItem::Song { song_id } => song_id,
+            LibraryItem::Album { artist_id, .. } | LibraryItem::Artist { artist_id } => artist_id,
+        }
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        match self {
+            LibraryItem::Album { album, .. } => Some(album),
+            _ => None,
+        }
+    }
+
+    /// Rebuild an item from its stored kind, id and album name
+    pub fn from_parts(kind: &str, item_id: String, album: Option<String>) -> Option<Self> {
+        match kind {
+            "song" => Some(LibraryItem::Song { song_id: item_id }),
+            "album" => Some(LibraryItem::Album { artist_id: item_id, album: album? }),
+            "artist" => Some(LibraryItem::Artist { artist_id: item_id }),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a user has favourited an item and the stars they gave it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRating {
+    pub user_id: String,
+    pub item: LibraryItem,
+    pub favorite: bool,
+    /// 1 to 5 stars
+    pub rating: Option<u8>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+impl UserRating {
+    pub fn new(user_id: &str, item: LibraryItem) -> Self {
+        UserRating {
+            user_id: user_id.to_string(),
+            item,
+            favorite: false,
+            rating: None,
+            updated_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    /// Neither favourited nor rated, so there is nothing to store
+    pub fn is_empty(&self) -> bool {
+        !self.favorite && self.rating.is_none()
+    }
+}
+
+/// Where a user is in their listening, saved by one device and restored by another
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayQueue {
+    pub user_id: String,
+    pub song_ids: Vec<String>,
+    /// Index into `song_ids` of the song playing
+    pub current_index: usize,
+    /// How far into the current song, in milliseconds
+    pub position_ms: i64,
+    /// Name of the device that saved the queue
+    pub device: Option<String>,
+    /// Bumped on every save, 0 before the first
+    pub version: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+impl PlayQueue {
+    /// The queue of a user who has never saved one
+    pub fn empty(user_id: &str) -> Self {
+        PlayQueue {
+            user_id: user_id.to_string(),
+            song_ids: Vec::new(),
+            current_index: 0,
+            position_ms: 0,
+            device: None,
+            version: 0,
+            updated_at: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+/// Where a song's lyrics came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LyricsSource {
+    /// Tags in the audio file
+    Embedded,
+    /// An `.lrc` file next to the audio file
+    Sidecar,
+    /// Looked up from the configured lyrics provider
+    Provider,
+    /// Entered by an admin
+    Manual,
+}
+
+impl LyricsSource {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s {
+            "embedded" => Some(LyricsSource::Embedded),
+            "sidecar" => Some(LyricsSource::Sidecar),
+            "provider" => Some(LyricsSource::Provider),
+            "manual" => Some(LyricsSource::Manual),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LyricsSource::Embedded => "embedded",
+            LyricsSource::Sidecar => "sidecar",
+            LyricsSource::Provider => "provider",
+            LyricsSource::Manual => "manual",
+        }
+    }
+
+    /// Whether a library scan may replace these lyrics with what it finds
+    pub fn is_scanned(&self) -> bool {
+        matches!(self, LyricsSource::Embedded | LyricsSource::Sidecar)
+    }
+}
+
+/// A song's lyrics, kept as plain text or LRC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SongLyrics {
+    pub song_id: String,
+    pub text: String,
+    /// Whether `text` is LRC with timestamps
+    pub synced: bool,
+    pub source: LyricsSource,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    PasswordChange,
+    AccountDelete,
+    TwoFactorEnable,
+    TwoFactorDisable,
+    ApiKeyCreate,
+    ApiKeyRevoke,
+    UserEdit,
+    UserDelete,
+    TwoFactorReset,
+    LoginUnlock,
+    RoleSave,
+    RoleDelete,
+    SongEdit,
+    SongDelete,
+    PlaylistEdit,
+    PlaylistDelete,
+    LibraryCreate,
+    LibraryEdit,
+    LibraryDelete,
+    LibraryGrant,
+    LibraryRevoke,
+    LyricsEdit,
+    LyricsDelete,
+    PasskeyRegister,
+    PasskeyDelete,
+    IdentityLink,
+    IdentityUnlink,
+    RefreshTokenReuse,
+}
+
+impl AuditAction {
+    pub const ALL: [AuditAction; 30] = [
+        AuditAction::Login,
+        AuditAction::LoginFailed,
+        AuditAction::PasswordChange,
+        AuditAction::AccountDelete,
+        AuditAction::TwoFactorEnable,
+        AuditAction::TwoFactorDisable,
+        AuditAction::ApiKeyCreate,
+        AuditAction::ApiKeyRevoke,
+        AuditAction::UserEdit,
+        AuditAction::UserDelete,
+        AuditAction::TwoFactorReset,
+        AuditAction::LoginUnlock,
+        AuditAction::RoleSave,
+        AuditAction::RoleDelete,
+        AuditAction::SongEdit,
+        AuditAction::SongDelete,
+        AuditAction::PlaylistEdit,
+        AuditAction::PlaylistDelete,
+        AuditAction::LibraryCreate,
+        AuditAction::LibraryEdit,
+        AuditAction::LibraryDelete,
+        AuditAction::LibraryGrant,
+        AuditAction::LibraryRevoke,
+        AuditAction::LyricsEdit,
+        AuditAction::LyricsDelete,
+        AuditAction::PasskeyRegister,
+        AuditAction::PasskeyDelete,
+        AuditAction::IdentityLink,
+        AuditAction::IdentityUnlink,
+        AuditAction::RefreshTokenReuse,
+    ];
+
+    pub fn from_string(s: &str) -> Option<Self> {
+        AuditAction::ALL.into_iter().find(|action| action.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::PasswordChange => "password_change",
+            AuditAction::AccountDelete => "account_delete",
+            AuditAction::TwoFactorEnable => "two_factor_enable",
+            AuditAction::TwoFactorDisable => "two_factor_disable",
+            AuditAction::ApiKeyCreate => "api_key_create",
+            AuditAction::ApiKeyRevoke => "api_key_revoke",
+            AuditAction::UserEdit => "user_edit",
+            AuditAction::UserDelete => "user_delete",
+            AuditAction::TwoFactorReset => "two_factor_reset",
+            AuditAction::LoginUnlock => "login_unlock",
+            AuditAction::RoleSave => "role_save",
+            AuditAction::RoleDelete => "role_delete",
+            AuditAction::SongEdit => "song_edit",
+            AuditAction::SongDelete => "song_delete",
+            AuditAction::PlaylistEdit => "playlist_edit",
+            AuditAction::PlaylistDelete => "playlist_delete",
+            AuditAction::LibraryCreate => "library_create",
+            AuditAction::LibraryEdit => "library_edit",
+            AuditAction::LibraryDelete => "library_delete",
+            AuditAction::LibraryGrant => "library_grant",
+            AuditAction::LibraryRevoke => "library_revoke",
+            AuditAction::LyricsEdit => "lyrics_edit",
+            AuditAction::LyricsDelete => "lyrics_delete",
+            AuditAction::PasskeyRegister => "passkey_register",
+            AuditAction::PasskeyDelete => "passkey_delete",
+            AuditAction::IdentityLink => "identity_link",
+            AuditAction::IdentityUnlink => "identity_unlink",
+            AuditAction::RefreshTokenReuse => "refresh_token_reuse",
+        }
+    }
+}
+
+/// A security-relevant or admin action, kept for review. The actor's name is
+/// copied so the entry still reads sensibly after the account is deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: String,
+    /// Account that acted, unless it's unknown, as for a login to a missing user
+    pub actor_id: Option<String>,
+    pub actor: String,
+    pub action: AuditAction,
+    /// What was acted on, like a username, role or playlist name
+    pub target: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    /// Address the request came from
+    pub ip: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl AuditEvent {
+    pub fn new(actor_id: Option<&str>, actor: &str, action: AuditAction) -> Self {
+        AuditEvent {
+            id: Uuid::new_v4().to_string(),
+            actor_id: actor_id.map(str::to_string),
+            actor: actor.to_string(),
+            action,
+            target: None,
+            before: None,
+            after: None,
+            ip: None,
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn with_change(mut self, before: Option<serde_json::Value>, after: Option<serde_json::Value>) -> Self {
+        self.before = before;
+        self.after = after;
+        self
+    }
+
+    pub fn with_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip.map(|ip| ip.to_string());
+        self
+    }
+}
+
+/// Which audit events to list. Every field that's set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>,
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
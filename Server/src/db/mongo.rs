@@ -1,11 +1,23 @@
 use async_trait::async_trait;
-use mongodb::{Client, Collection, bson::doc};
+use mongodb::{Client, Collection, bson::{doc, Bson, Document}};
 use uuid::Uuid;
 use time::OffsetDateTime;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::db::{Database, DbError};
-use crate::db::models::{User, Artist, Song, Playlist, PlaylistShare};
+use crate::auth::api_keys::{format_scopes, parse_scopes};
+use crate::auth::permissions::{format_scope, parse_scope, DEFAULT_ROLE};
+use crate::db::{Database, DbError, DEFAULT_LIBRARY_ID};
+use crate::db::models::{AuditAction, AuditEvent, AuditFilter, ApiKey, User, Role, Library, LibraryFilter, LibraryGrant, GrantKind, Artist, Song, Playlist, PlaylistAction, PlaylistActivity, PlaylistEntry, PlaylistShare, Passkey, RefreshToken, TwoFactor, ShareLink, SharePermission, ShareTarget, LibraryItem, UserRating, PlayQueue, SongLyrics, LyricsSource};
+use crate::db::paging::{Page, PageRequest, SortField, SortOrder};
+use crate::db::playlist_edit::{EditPlan, EditedEntry, EntrySlot, PlaylistEdit};
+use crate::db::smart_rules::{Condition, RuleExpr, RuleField, RuleOp, RuleOrder, RuleValue, SmartRules};
+
+/// Seconds an entry edit holds a playlist. Another edit may take over after
+/// that, in case the server making the edit went away.
+const PLAYLIST_EDIT_LEASE_SECONDS: i64 = 30;
+/// Times an edit without an expected version retries after another edit got in first
+const PLAYLIST_EDIT_ATTEMPTS: usize = 50;
+const PLAYLIST_EDIT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
 
 #[derive(Debug, Serialize, Deserialize)]
 struct MongoUser {
@@ -14,10 +26,15 @@ struct MongoUser {
     username: String,
     email: String,
     password_hash: String,
-    is_admin: bool,
+    #[serde(default = "default_role")]
+    role: String,
     created_at: i64,
 }
 
+fn default_role() -> String {
+    DEFAULT_ROLE.to_string()
+}
+
 impl From<MongoUser> for User {
     fn from(mongo_user: MongoUser) -> Self {
         let created_at = OffsetDateTime::from_unix_timestamp(mongo_user.created_at)
@@ -28,7 +45,79 @@ impl From<MongoUser> for User {
             username: mongo_user.username,
             email: mongo_user.email,
             password_hash: mongo_user.password_hash,
-            is_admin: mongo_user.is_admin,
+            role: mongo_user.role,
+            created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoRole {
+    #[serde(rename = "_id")]
+    name: String,
+    description: String,
+    /// Space-separated scopes, as in tokens
+    permissions: String,
+    built_in: bool,
+}
+
+impl From<&Role> for MongoRole {
+    fn from(role: &Role) -> Self {
+        MongoRole {
+            name: role.name.clone(),
+            description: role.description.clone(),
+            permissions: format_scope(&role.permissions),
+            built_in: role.built_in,
+        }
+    }
+}
+
+impl From<MongoRole> for Role {
+    fn from(mongo_role: MongoRole) -> Self {
+        Role {
+            name: mongo_role.name,
+            description: mongo_role.description,
+            permissions: parse_scope(&mongo_role.permissions),
+            built_in: mongo_role.built_in,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoLibrary {
+    #[serde(rename = "_id")]
+    id: String,
+    name: String,
+    root_path: String,
+    scan_on_startup: bool,
+    recursive: bool,
+    created_at: i64,
+}
+
+impl From<&Library> for MongoLibrary {
+    fn from(library: &Library) -> Self {
+        MongoLibrary {
+            id: library.id.clone(),
+            name: library.name.clone(),
+            root_path: library.root_path.clone(),
+            scan_on_startup: library.scan_on_startup,
+            recursive: library.recursive,
+            created_at: library.created_at.unix_timestamp(),
+        }
+    }
+}
+
+impl From<MongoLibrary> for Library {
+    fn from(mongo_library: MongoLibrary) -> Self {
+        let created_at = OffsetDateTime::from_unix_timestamp(mongo_library.created_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        Library {
+            id: mongo_library.id,
+            name: mongo_library.name,
+            root_path: mongo_library.root_path,
+            scan_on_startup: mongo_library.scan_on_startup,
+            recursive: mongo_library.recursive,
             created_at,
         }
     }
@@ -67,10 +156,34 @@ struct MongoSong {
     album: Option<String>,
     duration: Option<i32>,
     file_path: String,
+    #[serde(default = "default_library_id")]
+    library_id: String,
     cover_image_path: Option<String>,
+    #[serde(default)]
+    musicbrainz_id: Option<String>,
+    #[serde(default)]
+    genre: Option<String>,
+    #[serde(default)]
+    year: Option<i32>,
+    #[serde(default)]
+    start_ms: Option<i64>,
+    #[serde(default)]
+    end_ms: Option<i64>,
+    #[serde(default)]
+    encoder_delay: Option<i32>,
+    #[serde(default)]
+    encoder_padding: Option<i32>,
+    #[serde(default)]
+    sample_rate: Option<i32>,
+    #[serde(default)]
+    play_count: i64,
     created_at: i64,
 }
 
+fn default_library_id() -> String {
+    DEFAULT_LIBRARY_ID.to_string()
+}
+
 impl From<MongoSong> for Song {
     fn from(mongo_song: MongoSong) -> Self {
         let created_at = OffsetDateTime::from_unix_timestamp(mongo_song.created_at)
@@ -84,7 +197,17 @@ impl From<MongoSong> for Song {
             album: mongo_song.album,
             duration: mongo_song.duration,
             file_path: mongo_song.file_path,
+            library_id: mongo_song.library_id,
             cover_image_path: mongo_song.cover_image_path,
+            musicbrainz_id: mongo_song.musicbrainz_id,
+            genre: mongo_song.genre,
+            year: mongo_song.year,
+            start_ms: mongo_song.start_ms,
+            end_ms: mongo_song.end_ms,
+            encoder_delay: mongo_song.encoder_delay,
+            encoder_padding: mongo_song.encoder_padding,
+            sample_rate: mongo_song.sample_rate,
+            play_count: mongo_song.play_count,
             created_at,
         }
     }
@@ -98,7 +221,16 @@ struct MongoPlaylist {
     owner_id: String,
     owner_username: String,
     is_public: bool,
+    #[serde(default)]
+    version: i64,
+    #[serde(default)]
+    is_smart: bool,
+    #[serde(default)]
+    rules: Option<SmartRules>,
     created_at: i64,
+    /// Unix time until which an entry edit holds the playlist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edit_lease_until: Option<i64>,
 }
 
 impl From<MongoPlaylist> for Playlist {
@@ -112,15 +244,21 @@ impl From<MongoPlaylist> for Playlist {
             owner_id: mongo_playlist.owner_id,
             owner_username: mongo_playlist.owner_username,
             is_public: mongo_playlist.is_public,
+            version: mongo_playlist.version,
+            is_smart: mongo_playlist.is_smart,
+            rules: mongo_playlist.rules,
             created_at,
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct MongoPlaylistSong {
+struct MongoPlaylistEntry {
+    #[serde(rename = "_id")]
+    id: String,
     playlist_id: String,
     song_id: String,
+    position: i64,
     added_at: i64,
 }
 
@@ -131,6 +269,8 @@ struct MongoPlaylistShare {
     playlist_id: String,
     shared_with_user_id: String,
     shared_by_user_id: String,
+    #[serde(default)]
+    permission: SharePermission,
     shared_at: i64,
 }
 
@@ -144,18 +284,626 @@ impl From<MongoPlaylistShare> for PlaylistShare {
             playlist_id: mongo_share.playlist_id,
             shared_with_user_id: mongo_share.shared_with_user_id,
             shared_by_user_id: mongo_share.shared_by_user_id,
+            permission: mongo_share.permission,
             shared_at,
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoPlaylistActivity {
+    #[serde(rename = "_id")]
+    id: String,
+    playlist_id: String,
+    user_id: String,
+    username: String,
+    action: PlaylistAction,
+    song_id: Option<String>,
+    song_title: Option<String>,
+    artist_name: Option<String>,
+    position: Option<i64>,
+    created_at: i64,
+}
+
+impl From<&PlaylistActivity> for MongoPlaylistActivity {
+    fn from(activity: &PlaylistActivity) -> Self {
+        MongoPlaylistActivity {
+            id: activity.id.clone(),
+            playlist_id: activity.playlist_id.clone(),
+            user_id: activity.user_id.clone(),
+            username: activity.username.clone(),
+            action: activity.action,
+            song_id: activity.song_id.clone(),
+            song_title: activity.song_title.clone(),
+            artist_name: activity.artist_name.clone(),
+            position: activity.position,
+            created_at: activity.created_at.unix_timestamp(),
+        }
+    }
+}
+
+impl From<MongoPlaylistActivity> for PlaylistActivity {
+    fn from(mongo_activity: MongoPlaylistActivity) -> Self {
+        let created_at = OffsetDateTime::from_unix_timestamp(mongo_activity.created_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        PlaylistActivity {
+            id: mongo_activity.id,
+            playlist_id: mongo_activity.playlist_id,
+            user_id: mongo_activity.user_id,
+            username: mongo_activity.username,
+            action: mongo_activity.action,
+            song_id: mongo_activity.song_id,
+            song_title: mongo_activity.song_title,
+            artist_name: mongo_activity.artist_name,
+            position: mongo_activity.position,
+            created_at,
+        }
+    }
+}
+
+/// A user's subject at an identity provider
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoUserIdentity {
+    issuer: String,
+    subject: String,
+    user_id: String,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoTwoFactor {
+    #[serde(rename = "_id")]
+    user_id: String,
+    secret: String,
+    enabled: bool,
+    last_step: i64,
+    created_at: i64,
+}
+
+impl From<MongoTwoFactor> for TwoFactor {
+    fn from(mongo_two_factor: MongoTwoFactor) -> Self {
+        TwoFactor {
+            user_id: mongo_two_factor.user_id,
+            secret: mongo_two_factor.secret,
+            enabled: mongo_two_factor.enabled,
+            last_step: mongo_two_factor.last_step,
+            created_at: OffsetDateTime::from_unix_timestamp(mongo_two_factor.created_at)
+                .unwrap_or_else(|_| OffsetDateTime::now_utc()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoRecoveryCode {
+    user_id: String,
+    code_hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoPasskey {
+    #[serde(rename = "_id")]
+    id: String,
+    user_id: String,
+    name: String,
+    public_key: String,
+    algorithm: i64,
+    sign_count: i64,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+impl From<MongoPasskey> for Passkey {
+    fn from(mongo_passkey: MongoPasskey) -> Self {
+        Passkey {
+            id: mongo_passkey.id,
+            user_id: mongo_passkey.user_id,
+            name: mongo_passkey.name,
+            public_key: mongo_passkey.public_key,
+            algorithm: mongo_passkey.algorithm,
+            sign_count: mongo_passkey.sign_count,
+            created_at: OffsetDateTime::from_unix_timestamp(mongo_passkey.created_at)
+                .unwrap_or_else(|_| OffsetDateTime::now_utc()),
+            last_used_at: mongo_passkey.last_used_at.map(|last_used_at| {
+                OffsetDateTime::from_unix_timestamp(last_used_at).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoApiKey {
+    #[serde(rename = "_id")]
+    id: String,
+    user_id: String,
+    name: String,
+    prefix: String,
+    key_hash: String,
+    /// Space-separated scopes
+    scopes: String,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+impl From<MongoApiKey> for ApiKey {
+    fn from(mongo_api_key: MongoApiKey) -> Self {
+        ApiKey {
+            id: mongo_api_key.id,
+            user_id: mongo_api_key.user_id,
+            name: mongo_api_key.name,
+            prefix: mongo_api_key.prefix,
+            key_hash: mongo_api_key.key_hash,
+            scopes: parse_scopes(&mongo_api_key.scopes),
+            created_at: OffsetDateTime::from_unix_timestamp(mongo_api_key.created_at)
+                .unwrap_or_else(|_| OffsetDateTime::now_utc()),
+            last_used_at: mongo_api_key.last_used_at.map(|last_used_at| {
+                OffsetDateTime::from_unix_timestamp(last_used_at).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoAuditEvent {
+    #[serde(rename = "_id")]
+    id: String,
+    actor_id: Option<String>,
+    actor: String,
+    action: String,
+    target: Option<String>,
+    /// JSON text
+    before: Option<String>,
+    after: Option<String>,
+    ip: Option<String>,
+    created_at: i64,
+}
+
+impl From<&AuditEvent> for MongoAuditEvent {
+    fn from(event: &AuditEvent) -> Self {
+        MongoAuditEvent {
+            id: event.id.clone(),
+            actor_id: event.actor_id.clone(),
+            actor: event.actor.clone(),
+            action: event.action.as_str().to_string(),
+            target: event.target.clone(),
+            before: event.before.as_ref().map(|value| value.to_string()),
+            after: event.after.as_ref().map(|value| value.to_string()),
+            ip: event.ip.clone(),
+            created_at: event.created_at.unix_timestamp(),
+        }
+    }
+}
+
+impl TryFrom<MongoAuditEvent> for AuditEvent {
+    type Error = DbError;
+    
+    fn try_from(mongo_event: MongoAuditEvent) -> Result<Self, DbError> {
+        let json = |value: Option<String>| value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(|e| DbError::DatabaseError(format!("Invalid audit value: {}", e)));
+        
+        Ok(AuditEvent {
+            id: mongo_event.id,
+            actor_id: mongo_event.actor_id,
+            actor: mongo_event.actor,
+            action: AuditAction::from_string(&mongo_event.action)
+                .ok_or_else(|| DbError::DatabaseError(format!("Unknown audit action: {}", mongo_event.action)))?,
+            target: mongo_event.target,
+            before: json(mongo_event.before)?,
+            after: json(mongo_event.after)?,
+            ip: mongo_event.ip,
+            created_at: OffsetDateTime::from_unix_timestamp(mongo_event.created_at)
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoRefreshToken {
+    #[serde(rename = "_id")]
+    token_hash: String,
+    family_id: String,
+    user_id: String,
+    expires_at: i64,
+    used_at: Option<i64>,
+    created_at: i64,
+}
+
+impl From<MongoRefreshToken> for RefreshToken {
+    fn from(mongo_token: MongoRefreshToken) -> Self {
+        // An unreadable expiry is treated as already expired
+        let expires_at = OffsetDateTime::from_unix_timestamp(mongo_token.expires_at)
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        let created_at = OffsetDateTime::from_unix_timestamp(mongo_token.created_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        RefreshToken {
+            token_hash: mongo_token.token_hash,
+            family_id: mongo_token.family_id,
+            user_id: mongo_token.user_id,
+            expires_at,
+            used_at: mongo_token.used_at.map(|used_at| {
+                OffsetDateTime::from_unix_timestamp(used_at).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+            }),
+            created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoShareLink {
+    #[serde(rename = "_id")]
+    id: String,
+    owner_id: String,
+    target: ShareTarget,
+    password_hash: Option<String>,
+    expires_at: Option<i64>,
+    created_at: i64,
+}
+
+impl From<MongoShareLink> for ShareLink {
+    fn from(mongo_link: MongoShareLink) -> Self {
+        let created_at = OffsetDateTime::from_unix_timestamp(mongo_link.created_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        ShareLink {
+            id: mongo_link.id,
+            owner_id: mongo_link.owner_id,
+            target: mongo_link.target,
+            password_hash: mongo_link.password_hash,
+            // An unreadable expiry is treated as already expired rather than never
+            expires_at: mongo_link.expires_at.map(|expires_at| {
+                OffsetDateTime::from_unix_timestamp(expires_at).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+            }),
+            created_at,
+        }
+    }
+}
+
+/// Field to order by for a sort field
+fn sort_field(sort: SortField, name_field: &'static str) -> &'static str {
+    match sort {
+        SortField::Title => "title",
+        SortField::Artist => "artist_name",
+        SortField::Name => name_field,
+        SortField::Added => "created_at",
+        SortField::PlayCount => "play_count",
+    }
+}
+
+/// Query matching the audit log entries a filter keeps
+fn audit_filter(filter: &AuditFilter) -> Document {
+    let mut query = doc! {};
+    if let Some(actor) = &filter.actor {
+        query.insert("actor", actor.as_str());
+    }
+    if let Some(action) = filter.action {
+        query.insert("action", action.as_str());
+    }
+    if let Some(target) = &filter.target {
+        query.insert("target", target.as_str());
+    }
+    
+    let mut created_at = doc! {};
+    if let Some(since) = filter.since {
+        created_at.insert("$gte", since.unix_timestamp());
+    }
+    if let Some(until) = filter.until {
+        created_at.insert("$lt", until.unix_timestamp());
+    }
+    if !created_at.is_empty() {
+        query.insert("created_at", created_at);
+    }
+    
+    query
+}
+
+/// Fetch one page of a collection ordered by `(field, _id)`.
+/// One extra document is fetched so `Page::from_rows` can tell whether more remain.
+async fn find_page<T>(collection: &Collection<T>, filter: Document, field: &str, page: &PageRequest) -> Result<Vec<T>, DbError>
+where
+    T: DeserializeOwned + Send + Sync,
+{
+    use mongodb::options::FindOptions;
+    
+    let (op, direction) = match page.order {
+        SortOrder::Asc => ("$gt", 1),
+        SortOrder::Desc => ("$lt", -1),
+    };
+    
+    let filter = match &page.after {
+        Some(cursor) => {
+            let key = if cursor.sort.is_numeric() {
+                Bson::Int64(cursor.numeric_key())
+            } else {
+                Bson::String(cursor.key.clone())
+            };
+            
+            doc! {
+                "$and": [
+                    filter,
+                    { "$or": [
+                        { field: { op: key.clone() } },
+                        { field: key, "_id": { op: cursor.id.as_str() } },
+                    ] },
+                ]
+            }
+        }
+        None => filter,
+    };
+    
+    let options = FindOptions::builder()
+        .sort(doc! { field: direction, "_id": direction })
+        .limit((page.limit + 1) as i64)
+        .build();
+    
+    let mut cursor = collection
+        .find(filter)
+        .with_options(options)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+    
+    let mut items = Vec::new();
+    while cursor.advance().await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+        items.push(cursor.deserialize_current()
+            .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize document: {}", e)))?);
+    }
+    
+    Ok(items)
+}
+
+/// Condition keeping only songs in the given libraries, to merge into a song filter
+fn library_condition(libraries: &LibraryFilter) -> Document {
+    match libraries {
+        LibraryFilter::All => doc! {},
+        LibraryFilter::Only(ids) => doc! { "library_id": { "$in": ids.clone() } },
+    }
+}
+
+/// Song field a smart playlist rule tests
+fn rule_field(field: RuleField) -> &'static str {
+    match field {
+        RuleField::Title => "title",
+        RuleField::Artist => "artist_name",
+        RuleField::Album => "album",
+        RuleField::Genre => "genre",
+        RuleField::Year => "year",
+        RuleField::Duration => "duration",
+        RuleField::PlayCount => "play_count",
+        RuleField::Added => "created_at",
+    }
+}
+
+/// Query matching the songs a smart playlist filter keeps
+fn rule_filter(expr: &RuleExpr) -> Document {
+    match expr {
+        RuleExpr::Condition(condition) => rule_condition(condition),
+        // `$and` and `$or` refuse an empty list
+        RuleExpr::And(rules) if rules.is_empty() => doc! {},
+        RuleExpr::Or(rules) if rules.is_empty() => doc! { "$expr": false },
+        RuleExpr::And(rules) => doc! { "$and": rules.iter().map(rule_filter).collect::<Vec<_>>() },
+        RuleExpr::Or(rules) => doc! { "$or": rules.iter().map(rule_filter).collect::<Vec<_>>() },
+        RuleExpr::Not(rule) => doc! { "$nor": [rule_filter(rule)] },
+    }
+}
+
+/// Text compares case-insensitively, and a song without a value only matches `!=`
+fn rule_condition(condition: &Condition) -> Document {
+    let field = rule_field(condition.field);
+    let numeric = condition.field.is_numeric();
+    
+    match (&condition.value, condition.op) {
+        // A value of the wrong type only compares unequal to a missing value
+        (value, op) if numeric != matches!(value, RuleValue::Number(_)) => {
+            if op == RuleOp::Ne { doc! { field: Bson::Null } } else { doc! { "$expr": false } }
+        }
+        (RuleValue::Number(_), RuleOp::Contains) => doc! { "$expr": false },
+        (RuleValue::Number(n), op) => {
+            let comparison = match op {
+                RuleOp::Eq => "$eq",
+                RuleOp::Ne => "$ne",
+                RuleOp::Lt => "$lt",
+                RuleOp::Le => "$lte",
+                RuleOp::Gt => "$gt",
+                RuleOp::Ge => "$gte",
+                RuleOp::Contains => unreachable!(),
+            };
+            doc! { field: { comparison: *n } }
+        }
+        (RuleValue::Text(text), RuleOp::Eq) => {
+            doc! { field: { "$regex": format!("^{}$", escape_regex(text)), "$options": "i" } }
+        }
+        (RuleValue::Text(text), RuleOp::Ne) => {
+            doc! { field: { "$not": { "$regex": format!("^{}$", escape_regex(text)), "$options": "i" } } }
+        }
+        (RuleValue::Text(text), RuleOp::Contains) => {
+            doc! { field: { "$regex": escape_regex(text), "$options": "i" } }
+        }
+        (RuleValue::Text(text), op) => {
+            let comparison = match op {
+                RuleOp::Lt => "$lt",
+                RuleOp::Le => "$lte",
+                RuleOp::Gt => "$gt",
+                _ => "$gte",
+            };
+            let path = format!("${}", field);
+            doc! {
+                "$expr": {
+                    "$and": [
+                        { "$eq": [{ "$type": path.as_str() }, "string"] },
+                        { comparison: [{ "$toLower": path.as_str() }, text.to_lowercase()] },
+                    ]
+                }
+            }
+        }
+    }
+}
+
+/// Stages sorting songs by a smart playlist rule field. Songs without a value
+/// sort last either way, text sorts case-insensitively, and ties keep the
+/// order songs were added in.
+fn rule_order_stages(field: RuleField, descending: bool) -> Vec<Document> {
+    let path = format!("${}", rule_field(field));
+    let key = if field.is_numeric() {
+        Bson::String(path.clone())
+    } else {
+        Bson::Document(doc! { "$toLower": path.as_str() })
+    };
+    
+    vec![
+        doc! { "$addFields": {
+            "sort_missing": { "$in": [{ "$type": path.as_str() }, ["missing", "null"]] },
+            "sort_key": key,
+        } },
+        doc! { "$sort": {
+            "sort_missing": 1,
+            "sort_key": if descending { -1 } else { 1 },
+            "created_at": 1,
+            "_id": 1,
+        } },
+    ]
+}
+
+/// Escape regex metacharacters so user input matches literally
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoUserRating {
+    user_id: String,
+    item_type: String,
+    item_id: String,
+    /// Empty for anything but albums
+    album: String,
+    favorite: bool,
+    rating: Option<i32>,
+    updated_at: i64,
+}
+
+impl TryFrom<MongoUserRating> for UserRating {
+    type Error = DbError;
+    
+    fn try_from(mongo_rating: MongoUserRating) -> Result<Self, Self::Error> {
+        let album = Some(mongo_rating.album).filter(|album| !album.is_empty());
+        let item = LibraryItem::from_parts(&mongo_rating.item_type, mongo_rating.item_id, album)
+            .ok_or_else(|| DbError::DatabaseError(format!("Invalid rated item: {}", mongo_rating.item_type)))?;
+        let updated_at = OffsetDateTime::from_unix_timestamp(mongo_rating.updated_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        Ok(UserRating {
+            user_id: mongo_rating.user_id,
+            item,
+            favorite: mongo_rating.favorite,
+            rating: mongo_rating.rating.map(|rating| rating as u8),
+            updated_at,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoPlayQueue {
+    #[serde(rename = "_id")]
+    user_id: String,
+    song_ids: Vec<String>,
+    current_index: i64,
+    position_ms: i64,
+    device: Option<String>,
+    version: i64,
+    updated_at: i64,
+}
+
+impl From<MongoPlayQueue> for PlayQueue {
+    fn from(mongo_queue: MongoPlayQueue) -> Self {
+        let updated_at = OffsetDateTime::from_unix_timestamp(mongo_queue.updated_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        PlayQueue {
+            user_id: mongo_queue.user_id,
+            song_ids: mongo_queue.song_ids,
+            current_index: mongo_queue.current_index as usize,
+            position_ms: mongo_queue.position_ms,
+            device: mongo_queue.device,
+            version: mongo_queue.version,
+            updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoSongLyrics {
+    #[serde(rename = "_id")]
+    song_id: String,
+    text: String,
+    synced: bool,
+    source: String,
+    updated_at: i64,
+}
+
+impl From<&SongLyrics> for MongoSongLyrics {
+    fn from(lyrics: &SongLyrics) -> Self {
+        MongoSongLyrics {
+            song_id: lyrics.song_id.clone(),
+            text: lyrics.text.clone(),
+            synced: lyrics.synced,
+            source: lyrics.source.as_str().to_string(),
+            updated_at: lyrics.updated_at.unix_timestamp(),
+        }
+    }
+}
+
+impl TryFrom<MongoSongLyrics> for SongLyrics {
+    type Error = DbError;
+    
+    fn try_from(mongo_lyrics: MongoSongLyrics) -> Result<Self, DbError> {
+        let source = LyricsSource::from_string(&mongo_lyrics.source)
+            .ok_or_else(|| DbError::DatabaseError(format!("Invalid lyrics source: {}", mongo_lyrics.source)))?;
+        let updated_at = OffsetDateTime::from_unix_timestamp(mongo_lyrics.updated_at)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        
+        Ok(SongLyrics {
+            song_id: mongo_lyrics.song_id,
+            text: mongo_lyrics.text,
+            synced: mongo_lyrics.synced,
+            source,
+            updated_at,
+        })
+    }
+}
+
 pub struct MongoDatabase {
     users_collection: Collection<MongoUser>,
+    roles_collection: Collection<MongoRole>,
+    refresh_tokens_collection: Collection<MongoRefreshToken>,
+    user_identities_collection: Collection<MongoUserIdentity>,
+    two_factor_collection: Collection<MongoTwoFactor>,
+    recovery_codes_collection: Collection<MongoRecoveryCode>,
+    passkeys_collection: Collection<MongoPasskey>,
+    api_keys_collection: Collection<MongoApiKey>,
+    libraries_collection: Collection<MongoLibrary>,
+    library_grants_collection: Collection<LibraryGrant>,
     artists_collection: Collection<MongoArtist>,
     songs_collection: Collection<MongoSong>,
     playlists_collection: Collection<MongoPlaylist>,
-    playlist_songs_collection: Collection<MongoPlaylistSong>,
+    playlist_entries_collection: Collection<MongoPlaylistEntry>,
+    /// Unordered playlist songs from before entries had positions, only read to migrate them
+    legacy_playlist_songs_collection: Collection<Document>,
     playlist_shares_collection: Collection<MongoPlaylistShare>,
+    playlist_activity_collection: Collection<MongoPlaylistActivity>,
+    share_links_collection: Collection<MongoShareLink>,
+    user_ratings_collection: Collection<MongoUserRating>,
+    play_queues_collection: Collection<MongoPlayQueue>,
+    song_lyrics_collection: Collection<MongoSongLyrics>,
+    audit_log_collection: Collection<MongoAuditEvent>,
 }
 
 impl MongoDatabase {
@@ -167,45 +915,228 @@ impl MongoDatabase {
         // Default to "muse" database
         let database = client.database("muse");
         let users_collection = database.collection::<MongoUser>("users");
+        let roles_collection = database.collection::<MongoRole>("roles");
+        let refresh_tokens_collection = database.collection::<MongoRefreshToken>("refresh_tokens");
+        let user_identities_collection = database.collection::<MongoUserIdentity>("user_identities");
+        let two_factor_collection = database.collection::<MongoTwoFactor>("two_factor");
+        let recovery_codes_collection = database.collection::<MongoRecoveryCode>("recovery_codes");
+        let passkeys_collection = database.collection::<MongoPasskey>("passkeys");
+        let api_keys_collection = database.collection::<MongoApiKey>("api_keys");
+        let libraries_collection = database.collection::<MongoLibrary>("libraries");
+        let library_grants_collection = database.collection::<LibraryGrant>("library_grants");
         let artists_collection = database.collection::<MongoArtist>("artists");
         let songs_collection = database.collection::<MongoSong>("songs");
         let playlists_collection = database.collection::<MongoPlaylist>("playlists");
-        let playlist_songs_collection = database.collection::<MongoPlaylistSong>("playlist_songs");
+        let playlist_entries_collection = database.collection::<MongoPlaylistEntry>("playlist_entries");
+        let legacy_playlist_songs_collection = database.collection::<Document>("playlist_songs");
         let playlist_shares_collection = database.collection::<MongoPlaylistShare>("playlist_shares");
+        let playlist_activity_collection = database.collection::<MongoPlaylistActivity>("playlist_activity");
+        let share_links_collection = database.collection::<MongoShareLink>("share_links");
+        let user_ratings_collection = database.collection::<MongoUserRating>("user_ratings");
+        let play_queues_collection = database.collection::<MongoPlayQueue>("play_queues");
+        let song_lyrics_collection = database.collection::<MongoSongLyrics>("song_lyrics");
+        let audit_log_collection = database.collection::<MongoAuditEvent>("audit_log");
         
         Ok(Self { 
             users_collection,
+            roles_collection,
+            refresh_tokens_collection,
+            user_identities_collection,
+            two_factor_collection,
+            recovery_codes_collection,
+            passkeys_collection,
+            api_keys_collection,
+            libraries_collection,
+            library_grants_collection,
             artists_collection,
             songs_collection,
             playlists_collection,
-            playlist_songs_collection,
+            playlist_entries_collection,
+            legacy_playlist_songs_collection,
             playlist_shares_collection,
+            playlist_activity_collection,
+            share_links_collection,
+            user_ratings_collection,
+            play_queues_collection,
+            song_lyrics_collection,
+            audit_log_collection,
         })
     }
-}
-
-#[async_trait]
-impl Database for MongoDatabase {
-    async fn create_user(&self, username: &str, email: &str, password_hash: &str) -> Result<User, DbError> {
-        // Check if user already exists
-        if self.username_exists(username).await? {
-            return Err(DbError::UserAlreadyExists);
-        }
+    
+    async fn delete_user_passkeys(&self, user_id: &str) -> Result<(), DbError> {
+        self.passkeys_collection
+            .delete_many(doc! { "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete passkeys: {}", e)))?;
         
-        if self.email_exists(email).await? {
-            return Err(DbError::UserAlreadyExists);
+        Ok(())
+    }
+    
+    async fn delete_user_api_keys(&self, user_id: &str) -> Result<(), DbError> {
+        self.api_keys_collection
+            .delete_many(doc! { "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete API keys: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_user_identities(&self, user_id: &str) -> Result<(), DbError> {
+        self.user_identities_collection
+            .delete_many(doc! { "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete identities: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    /// Filter on the artists with songs in the given libraries
+    async fn artist_library_condition(&self, libraries: &LibraryFilter) -> Result<Document, DbError> {
+        if *libraries == LibraryFilter::All {
+            return Ok(doc! {});
         }
         
-        let id = Uuid::new_v4().to_string();
-        let created_at = OffsetDateTime::now_utc();
-        let created_at_timestamp = created_at.unix_timestamp();
+        let artist_ids = self.songs_collection
+            .distinct("artist_id", library_condition(libraries))
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mongo_user = MongoUser {
+        Ok(doc! { "_id": { "$in": artist_ids } })
+    }
+    
+    async fn find_user_ratings(&self, filter: Document, options: Option<mongodb::options::FindOptions>) -> Result<Vec<UserRating>, DbError> {
+        let mut cursor = self.user_ratings_collection
+            .find(filter)
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut ratings = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let rating: MongoUserRating = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize rating: {}", e)))?;
+            ratings.push(rating.try_into()?);
+        }
+        
+        Ok(ratings)
+    }
+    
+    /// Move documents from the old `playlist_songs` collection, which had no positions,
+    /// into `playlist_entries` in the order they were added, then drop it
+    async fn migrate_playlist_songs(&self) -> Result<(), DbError> {
+        use mongodb::options::FindOptions;
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "playlist_id": 1, "added_at": 1, "song_id": 1 })
+            .build();
+        
+        let mut cursor = self.legacy_playlist_songs_collection
+            .find(doc! {})
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut entries = Vec::new();
+        let mut last_playlist: Option<String> = None;
+        let mut position = 0;
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let legacy: Document = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist song: {}", e)))?;
+            
+            let playlist_id = legacy.get_str("playlist_id").unwrap_or_default().to_string();
+            if last_playlist.as_deref() != Some(playlist_id.as_str()) {
+                last_playlist = Some(playlist_id.clone());
+                position = 0;
+            }
+            
+            entries.push(MongoPlaylistEntry {
+                id: Uuid::new_v4().to_string(),
+                playlist_id,
+                song_id: legacy.get_str("song_id").unwrap_or_default().to_string(),
+                position,
+                added_at: legacy.get_i64("added_at").unwrap_or_default(),
+            });
+            position += 1;
+        }
+        
+        if entries.is_empty() {
+            return Ok(());
+        }
+        
+        self.playlist_entries_collection
+            .insert_many(&entries)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to migrate playlist songs: {}", e)))?;
+        
+        self.legacy_playlist_songs_collection
+            .drop()
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to drop playlist_songs collection: {}", e)))?;
+        
+        tracing::info!("Migrated {} playlist songs to ordered playlist entries", entries.len());
+        Ok(())
+    }
+    
+    /// Write the entries an edit plan removes, moves and adds
+    async fn write_playlist_entries(&self, playlist_id: &str, plan: &EditPlan) -> Result<(), DbError> {
+        if !plan.removed.is_empty() {
+            self.playlist_entries_collection
+                .delete_many(doc! { "_id": { "$in": &plan.removed } })
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to remove playlist entries: {}", e)))?;
+        }
+        
+        let added_at = OffsetDateTime::now_utc().unix_timestamp();
+        for (position, entry) in plan.changed() {
+            if entry.stored_position.is_some() {
+                self.playlist_entries_collection
+                    .update_one(doc! { "_id": &entry.id }, doc! { "$set": { "position": position } })
+                    .await
+                    .map_err(|e| DbError::DatabaseError(format!("Failed to write playlist entry: {}", e)))?;
+            } else {
+                let new_entry = MongoPlaylistEntry {
+                    id: entry.id.clone(),
+                    playlist_id: playlist_id.to_string(),
+                    song_id: entry.song_id.clone(),
+                    position,
+                    added_at,
+                };
+                
+                self.playlist_entries_collection
+                    .insert_one(&new_entry)
+                    .await
+                    .map_err(|e| DbError::DatabaseError(format!("Failed to write playlist entry: {}", e)))?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Database for MongoDatabase {
+    async fn create_user(&self, username: &str, email: &str, password_hash: &str) -> Result<User, DbError> {
+        // Check if user already exists
+        if self.username_exists(username).await? {
+            return Err(DbError::UserAlreadyExists);
+        }
+        
+        if self.email_exists(email).await? {
+            return Err(DbError::UserAlreadyExists);
+        }
+        
+        let id = Uuid::new_v4().to_string();
+        let created_at = OffsetDateTime::now_utc();
+        let created_at_timestamp = created_at.unix_timestamp();
+        
+        let mongo_user = MongoUser {
             id: id.clone(),
             username: username.to_string(),
             email: email.to_string(),
             password_hash: password_hash.to_string(),
-            is_admin: false,
+            role: DEFAULT_ROLE.to_string(),
             created_at: created_at_timestamp,
         };
         
@@ -219,7 +1150,7 @@ impl Database for MongoDatabase {
             username: username.to_string(),
             email: email.to_string(),
             password_hash: password_hash.to_string(),
-            is_admin: false,
+            role: DEFAULT_ROLE.to_string(),
             created_at,
         })
     }
@@ -260,15 +1191,19 @@ impl Database for MongoDatabase {
         Ok(mongo_user.into())
     }
 
-    async fn update_user_admin_status(&self, id: &str, is_admin: bool) -> Result<(), DbError> {
+    async fn update_user_role(&self, id: &str, role: &str) -> Result<(), DbError> {
         let filter = doc! { "_id": id };
-        let update = doc! { "$set": { "is_admin": is_admin } };
+        let update = doc! { "$set": { "role": role } };
         
-        self.users_collection
+        let result = self.users_collection
             .update_one(filter, update)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to update user: {}", e)))?;
         
+        if result.matched_count == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
         Ok(())
     }
 
@@ -319,154 +1254,918 @@ impl Database for MongoDatabase {
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to create email index: {}", e)))?;
         
+        let identity_subject_index = IndexModel::builder()
+            .keys(doc! { "issuer": 1, "subject": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        
+        self.user_identities_collection
+            .create_index(identity_subject_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create identity subject index: {}", e)))?;
+        
+        let identity_user_index = IndexModel::builder()
+            .keys(doc! { "issuer": 1, "user_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        
+        self.user_identities_collection
+            .create_index(identity_user_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create identity user index: {}", e)))?;
+        
+        let refresh_token_family_index = IndexModel::builder()
+            .keys(doc! { "family_id": 1 })
+            .build();
+        
+        self.refresh_tokens_collection
+            .create_index(refresh_token_family_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create refresh token family index: {}", e)))?;
+        
+        let refresh_token_user_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build();
+        
+        self.refresh_tokens_collection
+            .create_index(refresh_token_user_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create refresh token user index: {}", e)))?;
+        
+        let recovery_code_user_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build();
+        
+        self.recovery_codes_collection
+            .create_index(recovery_code_user_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create recovery code user index: {}", e)))?;
+        
+        let passkey_user_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build();
+        
+        self.passkeys_collection
+            .create_index(passkey_user_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create passkey user index: {}", e)))?;
+        
+        let api_key_hash_index = IndexModel::builder()
+            .keys(doc! { "key_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        
+        self.api_keys_collection
+            .create_index(api_key_hash_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create API key hash index: {}", e)))?;
+        
+        let api_key_user_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build();
+        
+        self.api_keys_collection
+            .create_index(api_key_user_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create API key user index: {}", e)))?;
+        
+        let audit_log_index = IndexModel::builder()
+            .keys(doc! { "created_at": -1 })
+            .build();
+        
+        self.audit_log_collection
+            .create_index(audit_log_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create audit log index: {}", e)))?;
+        
+        // Users from before roles were admins or not
+        self.users_collection
+            .update_many(doc! { "role": { "$exists": false }, "is_admin": true }, doc! { "$set": { "role": "admin" } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to migrate admins: {}", e)))?;
+        
+        self.users_collection
+            .update_many(doc! { "role": { "$exists": false } }, doc! { "$set": { "role": DEFAULT_ROLE } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to backfill roles: {}", e)))?;
+        
         // Create unique index for artist names
         let artist_name_index = IndexModel::builder()
             .keys(doc! { "name": 1 })
             .options(IndexOptions::builder().unique(true).build())
             .build();
         
-        self.artists_collection
-            .create_index(artist_name_index)
+        self.artists_collection
+            .create_index(artist_name_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create artist name index: {}", e)))?;
+        
+        // Create index for song artist_id
+        let song_artist_index = IndexModel::builder()
+            .keys(doc! { "artist_id": 1 })
+            .build();
+        
+        self.songs_collection
+            .create_index(song_artist_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create song artist index: {}", e)))?;
+        
+        // Indexes for resolving imported playlist entries by path or MusicBrainz ID
+        for key in ["file_path", "musicbrainz_id"] {
+            let song_index = IndexModel::builder()
+                .keys(doc! { key: 1 })
+                .build();
+            
+            self.songs_collection
+                .create_index(song_index)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to create song {} index: {}", key, e)))?;
+        }
+        
+        // Songs from before libraries are in the default one
+        self.songs_collection
+            .update_many(doc! { "library_id": { "$exists": false } }, doc! { "$set": { "library_id": DEFAULT_LIBRARY_ID } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to backfill song libraries: {}", e)))?;
+        
+        let song_library_index = IndexModel::builder()
+            .keys(doc! { "library_id": 1 })
+            .build();
+        
+        self.songs_collection
+            .create_index(song_library_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create song library index: {}", e)))?;
+        
+        let library_grant_index = IndexModel::builder()
+            .keys(doc! { "library_id": 1, "kind": 1, "subject": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        
+        self.library_grants_collection
+            .create_index(library_grant_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create library grant index: {}", e)))?;
+        
+        // Backfill play counts on songs stored before they were tracked
+        self.songs_collection
+            .update_many(doc! { "play_count": { "$exists": false } }, doc! { "$set": { "play_count": 0_i64 } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to backfill play counts: {}", e)))?;
+        
+        // Index playlist entries by position and bring over unordered playlist songs
+        let playlist_entry_index = IndexModel::builder()
+            .keys(doc! { "playlist_id": 1, "position": 1 })
+            .build();
+        
+        self.playlist_entries_collection
+            .create_index(playlist_entry_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist entry index: {}", e)))?;
+        
+        let playlist_activity_index = IndexModel::builder()
+            .keys(doc! { "playlist_id": 1, "created_at": -1 })
+            .build();
+        
+        self.playlist_activity_collection
+            .create_index(playlist_activity_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist activity index: {}", e)))?;
+        
+        let share_link_owner_index = IndexModel::builder()
+            .keys(doc! { "owner_id": 1 })
+            .build();
+        
+        self.share_links_collection
+            .create_index(share_link_owner_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create share link owner index: {}", e)))?;
+        
+        let user_rating_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1, "item_type": 1, "item_id": 1, "album": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        
+        self.user_ratings_collection
+            .create_index(user_rating_index)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create user rating index: {}", e)))?;
+        
+        self.playlists_collection
+            .update_many(doc! { "version": { "$exists": false } }, doc! { "$set": { "version": 0_i64 } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to backfill playlist versions: {}", e)))?;
+        
+        self.migrate_playlist_songs().await?;
+        
+        Ok(())
+    }
+    
+    async fn get_all_users(&self, page: &PageRequest) -> Result<Page<User>, DbError> {
+        let users = find_page(&self.users_collection, doc! {}, sort_field(page.sort, "username"), page).await?;
+        let total = self.get_total_users().await?;
+        
+        Ok(Page::from_rows(users.into_iter().map(Into::into).collect(), page, total))
+    }
+    
+    async fn update_user_email(&self, username: &str, new_email: &str) -> Result<(), DbError> {
+        // Check if new email already exists for another user
+        if self.email_exists(new_email).await? {
+            let existing_user = self.get_user_by_email(new_email).await?;
+            if existing_user.username != username {
+                return Err(DbError::UserAlreadyExists);
+            }
+        }
+        
+        let filter = doc! { "username": username };
+        let update = doc! { "$set": { "email": new_email } };
+        
+        let result = self.users_collection
+            .update_one(filter, update)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update user: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_username(&self, user_id: &str, new_username: &str) -> Result<(), DbError> {
+        // Check if new username already exists
+        if self.username_exists(new_username).await? {
+            return Err(DbError::UserAlreadyExists);
+        }
+        
+        let filter = doc! { "id": user_id };
+        let update = doc! { "$set": { "username": new_username } };
+        
+        let result = self.users_collection
+            .update_one(filter, update)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update username: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_user_password(&self, user_id: &str, new_password_hash: &str) -> Result<(), DbError> {
+        let filter = doc! { "id": user_id };
+        let update = doc! { "$set": { "password_hash": new_password_hash } };
+        
+        let result = self.users_collection
+            .update_one(filter, update)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update password: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn delete_user_by_username(&self, username: &str) -> Result<(), DbError> {
+        if let Ok(user) = self.get_user_by_username(username).await {
+            self.library_grants_collection
+                .delete_many(doc! { "kind": GrantKind::User.as_str(), "subject": &user.id })
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
+            self.delete_user_refresh_tokens(&user.id, None).await?;
+            self.delete_user_identities(&user.id).await?;
+            self.delete_user_passkeys(&user.id).await?;
+            self.delete_user_api_keys(&user.id).await?;
+            if let Err(e) = self.delete_two_factor(&user.id).await && !matches!(e, DbError::TwoFactorNotFound) {
+                return Err(e);
+            }
+        }
+        
+        let filter = doc! { "username": username };
+
+        let result = self.users_collection
+            .delete_one(filter)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
+
+        if result.deleted_count == 0 {
+            return Err(DbError::UserNotFound);
+        }
+
+        Ok(())
+    }
+    
+    async fn delete_user_by_id(&self, user_id: &str) -> Result<(), DbError> {
+        self.library_grants_collection
+            .delete_many(doc! { "kind": GrantKind::User.as_str(), "subject": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
+        
+        self.delete_user_refresh_tokens(user_id, None).await?;
+        self.delete_user_identities(user_id).await?;
+        self.delete_user_passkeys(user_id).await?;
+        self.delete_user_api_keys(user_id).await?;
+        if let Err(e) = self.delete_two_factor(user_id).await && !matches!(e, DbError::TwoFactorNotFound) {
+            return Err(e);
+        }
+        
+        let filter = doc! { "id": user_id };
+
+        let result = self.users_collection
+            .delete_one(filter)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
+
+        if result.deleted_count == 0 {
+            return Err(DbError::UserNotFound);
+        }
+
+        Ok(())
+    }
+    
+    async fn get_total_users(&self) -> Result<usize, DbError> {
+        let count = self.users_collection
+            .count_documents(doc! {})
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(count as usize)
+    }
+    
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, DbError> {
+        let identity = self.user_identities_collection
+            .find_one(doc! { "issuer": issuer, "subject": subject })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::UserNotFound)?;
+        
+        self.get_user_by_id(&identity.user_id).await
+    }
+    
+    async fn link_identity(&self, issuer: &str, subject: &str, user_id: &str) -> Result<(), DbError> {
+        let linked = self.user_identities_collection
+            .count_documents(doc! { "issuer": issuer, "$or": [{ "subject": subject }, { "user_id": user_id }] })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        if linked > 0 {
+            return Err(DbError::IdentityAlreadyLinked);
+        }
+        
+        let identity = MongoUserIdentity {
+            issuer: issuer.to_string(),
+            subject: subject.to_string(),
+            user_id: user_id.to_string(),
+            created_at: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        
+        self.user_identities_collection
+            .insert_one(&identity)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to link identity: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn unlink_identity(&self, issuer: &str, user_id: &str) -> Result<(), DbError> {
+        let result = self.user_identities_collection
+            .delete_one(doc! { "issuer": issuer, "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to unlink identity: {}", e)))?;
+        
+        if result.deleted_count == 0 {
+            return Err(DbError::IdentityNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn create_refresh_token(&self, token_hash: &str, family_id: &str, user_id: &str, expires_at: OffsetDateTime) -> Result<RefreshToken, DbError> {
+        let created_at = OffsetDateTime::now_utc();
+        
+        let mongo_token = MongoRefreshToken {
+            token_hash: token_hash.to_string(),
+            family_id: family_id.to_string(),
+            user_id: user_id.to_string(),
+            expires_at: expires_at.unix_timestamp(),
+            used_at: None,
+            created_at: created_at.unix_timestamp(),
+        };
+        
+        self.refresh_tokens_collection
+            .insert_one(&mongo_token)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create refresh token: {}", e)))?;
+        
+        Ok(RefreshToken {
+            token_hash: token_hash.to_string(),
+            family_id: family_id.to_string(),
+            user_id: user_id.to_string(),
+            expires_at,
+            used_at: None,
+            created_at,
+        })
+    }
+    
+    async fn get_refresh_token(&self, token_hash: &str) -> Result<RefreshToken, DbError> {
+        self.refresh_tokens_collection
+            .find_one(doc! { "_id": token_hash })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .map(Into::into)
+            .ok_or(DbError::RefreshTokenNotFound)
+    }
+    
+    async fn use_refresh_token(&self, token_hash: &str) -> Result<bool, DbError> {
+        let result = self.refresh_tokens_collection
+            .update_one(
+                doc! { "_id": token_hash, "used_at": Bson::Null },
+                doc! { "$set": { "used_at": OffsetDateTime::now_utc().unix_timestamp() } },
+            )
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to use refresh token: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+    
+    async fn delete_refresh_token_family(&self, family_id: &str) -> Result<(), DbError> {
+        self.refresh_tokens_collection
+            .delete_many(doc! { "family_id": family_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete refresh tokens: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_user_refresh_tokens(&self, user_id: &str, keep_family: Option<&str>) -> Result<(), DbError> {
+        self.refresh_tokens_collection
+            .delete_many(doc! { "user_id": user_id, "family_id": { "$ne": keep_family } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete refresh tokens: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_expired_refresh_tokens(&self) -> Result<(), DbError> {
+        self.refresh_tokens_collection
+            .delete_many(doc! { "expires_at": { "$lte": OffsetDateTime::now_utc().unix_timestamp() } })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete expired refresh tokens: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_two_factor(&self, user_id: &str) -> Result<TwoFactor, DbError> {
+        self.two_factor_collection
+            .find_one(doc! { "_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .map(Into::into)
+            .ok_or(DbError::TwoFactorNotFound)
+    }
+    
+    async fn save_two_factor(&self, user_id: &str, secret: &str, enabled: bool) -> Result<(), DbError> {
+        self.two_factor_collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! {
+                    "$set": { "secret": secret, "enabled": enabled, "created_at": OffsetDateTime::now_utc().unix_timestamp() },
+                    "$setOnInsert": { "last_step": 0i64 },
+                },
+            )
+            .upsert(true)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save two-factor authentication: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn use_totp_step(&self, user_id: &str, step: i64) -> Result<bool, DbError> {
+        let result = self.two_factor_collection
+            .update_one(
+                doc! { "_id": user_id, "last_step": { "$lt": step } },
+                doc! { "$set": { "last_step": step } },
+            )
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to use code: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+    
+    async fn delete_two_factor(&self, user_id: &str) -> Result<(), DbError> {
+        self.recovery_codes_collection
+            .delete_many(doc! { "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete recovery codes: {}", e)))?;
+        
+        let result = self.two_factor_collection
+            .delete_one(doc! { "_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete two-factor authentication: {}", e)))?;
+        
+        if result.deleted_count == 0 {
+            return Err(DbError::TwoFactorNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn replace_recovery_codes(&self, user_id: &str, code_hashes: &[String]) -> Result<(), DbError> {
+        self.recovery_codes_collection
+            .delete_many(doc! { "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete recovery codes: {}", e)))?;
+        
+        if code_hashes.is_empty() {
+            return Ok(());
+        }
+        
+        let codes = code_hashes.iter().map(|code_hash| MongoRecoveryCode {
+            user_id: user_id.to_string(),
+            code_hash: code_hash.clone(),
+        });
+        self.recovery_codes_collection
+            .insert_many(codes)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save recovery codes: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool, DbError> {
+        let result = self.recovery_codes_collection
+            .delete_one(doc! { "user_id": user_id, "code_hash": code_hash })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to use recovery code: {}", e)))?;
+        
+        Ok(result.deleted_count > 0)
+    }
+    
+    async fn count_recovery_codes(&self, user_id: &str) -> Result<usize, DbError> {
+        let count = self.recovery_codes_collection
+            .count_documents(doc! { "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(count as usize)
+    }
+    
+    async fn create_passkey(&self, passkey: &Passkey) -> Result<(), DbError> {
+        let mongo_passkey = MongoPasskey {
+            id: passkey.id.clone(),
+            user_id: passkey.user_id.clone(),
+            name: passkey.name.clone(),
+            public_key: passkey.public_key.clone(),
+            algorithm: passkey.algorithm,
+            sign_count: passkey.sign_count,
+            created_at: passkey.created_at.unix_timestamp(),
+            last_used_at: None,
+        };
+        
+        self.passkeys_collection
+            .insert_one(&mongo_passkey)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create passkey: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_passkey(&self, id: &str) -> Result<Passkey, DbError> {
+        self.passkeys_collection
+            .find_one(doc! { "_id": id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .map(Into::into)
+            .ok_or(DbError::PasskeyNotFound)
+    }
+    
+    async fn get_user_passkeys(&self, user_id: &str) -> Result<Vec<Passkey>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1, "_id": 1 })
+            .build();
+        
+        let mut cursor = self.passkeys_collection
+            .find(doc! { "user_id": user_id })
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut passkeys = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let passkey: MongoPasskey = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize passkey: {}", e)))?;
+            passkeys.push(passkey.into());
+        }
+        
+        Ok(passkeys)
+    }
+    
+    async fn use_passkey(&self, id: &str, sign_count: i64) -> Result<(), DbError> {
+        self.passkeys_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "sign_count": sign_count, "last_used_at": OffsetDateTime::now_utc().unix_timestamp() } },
+            )
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update passkey: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_passkey(&self, user_id: &str, id: &str) -> Result<(), DbError> {
+        let result = self.passkeys_collection
+            .delete_one(doc! { "_id": id, "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete passkey: {}", e)))?;
+        
+        if result.deleted_count == 0 {
+            return Err(DbError::PasskeyNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<(), DbError> {
+        let mongo_api_key = MongoApiKey {
+            id: api_key.id.clone(),
+            user_id: api_key.user_id.clone(),
+            name: api_key.name.clone(),
+            prefix: api_key.prefix.clone(),
+            key_hash: api_key.key_hash.clone(),
+            scopes: format_scopes(&api_key.scopes),
+            created_at: api_key.created_at.unix_timestamp(),
+            last_used_at: None,
+        };
+        
+        self.api_keys_collection
+            .insert_one(&mongo_api_key)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create API key: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError> {
+        self.api_keys_collection
+            .find_one(doc! { "key_hash": key_hash })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .map(Into::into)
+            .ok_or(DbError::ApiKeyNotFound)
+    }
+    
+    async fn get_user_api_keys(&self, user_id: &str) -> Result<Vec<ApiKey>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1, "_id": 1 })
+            .build();
+        
+        let mut cursor = self.api_keys_collection
+            .find(doc! { "user_id": user_id })
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut api_keys = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let api_key: MongoApiKey = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize API key: {}", e)))?;
+            api_keys.push(api_key.into());
+        }
+        
+        Ok(api_keys)
+    }
+    
+    async fn touch_api_key(&self, id: &str) -> Result<(), DbError> {
+        self.api_keys_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "last_used_at": OffsetDateTime::now_utc().unix_timestamp() } },
+            )
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update API key: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_api_key(&self, user_id: &str, id: &str) -> Result<(), DbError> {
+        let result = self.api_keys_collection
+            .delete_one(doc! { "_id": id, "user_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete API key: {}", e)))?;
+        
+        if result.deleted_count == 0 {
+            return Err(DbError::ApiKeyNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn get_roles(&self) -> Result<Vec<Role>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .build();
+        
+        let mut cursor = self.roles_collection
+            .find(doc! {})
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut roles = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let role: MongoRole = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize role: {}", e)))?;
+            roles.push(role.into());
+        }
+        
+        Ok(roles)
+    }
+    
+    async fn get_role(&self, name: &str) -> Result<Role, DbError> {
+        let role = self.roles_collection
+            .find_one(doc! { "_id": name })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::RoleNotFound)?;
+        
+        Ok(role.into())
+    }
+    
+    async fn save_role(&self, role: &Role) -> Result<(), DbError> {
+        self.roles_collection
+            .replace_one(doc! { "_id": &role.name }, MongoRole::from(role))
+            .upsert(true)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save role: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_role(&self, name: &str) -> Result<(), DbError> {
+        let result = self.roles_collection
+            .delete_one(doc! { "_id": name })
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to create artist name index: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete role: {}", e)))?;
         
-        // Create index for song artist_id
-        let song_artist_index = IndexModel::builder()
-            .keys(doc! { "artist_id": 1 })
-            .build();
+        if result.deleted_count == 0 {
+            return Err(DbError::RoleNotFound);
+        }
         
-        self.songs_collection
-            .create_index(song_artist_index)
+        // A role made later with the same name shouldn't inherit these
+        self.library_grants_collection
+            .delete_many(doc! { "kind": GrantKind::Role.as_str(), "subject": name })
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to create song artist index: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
         
         Ok(())
     }
     
-    async fn get_all_users(&self, offset: usize, limit: usize) -> Result<Vec<User>, DbError> {
+    async fn count_users_with_role(&self, role: &str) -> Result<usize, DbError> {
+        let count = self.users_collection
+            .count_documents(doc! { "role": role })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(count as usize)
+    }
+    
+    // Library operations
+    async fn get_libraries(&self) -> Result<Vec<Library>, DbError> {
         use mongodb::options::FindOptions;
         
         let options = FindOptions::builder()
-            .sort(doc! { "created_at": -1 })
-            .skip(offset as u64)
-            .limit(limit as i64)
+            .sort(doc! { "name": 1 })
             .build();
         
-        let mut cursor = self.users_collection
+        let mut cursor = self.libraries_collection
             .find(doc! {})
             .with_options(options)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut users = Vec::new();
+        let mut libraries = Vec::new();
         while cursor.advance().await
             .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
-            let mongo_user = cursor.deserialize_current()
-                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize user: {}", e)))?;
-            users.push(mongo_user.into());
+            let library: MongoLibrary = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize library: {}", e)))?;
+            libraries.push(library.into());
         }
         
-        Ok(users)
+        Ok(libraries)
     }
     
-    async fn update_user_email(&self, username: &str, new_email: &str) -> Result<(), DbError> {
-        // Check if new email already exists for another user
-        if self.email_exists(new_email).await? {
-            let existing_user = self.get_user_by_email(new_email).await?;
-            if existing_user.username != username {
-                return Err(DbError::UserAlreadyExists);
-            }
-        }
-        
-        let filter = doc! { "username": username };
-        let update = doc! { "$set": { "email": new_email } };
-        
-        let result = self.users_collection
-            .update_one(filter, update)
+    async fn get_library(&self, id: &str) -> Result<Library, DbError> {
+        let library = self.libraries_collection
+            .find_one(doc! { "_id": id })
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to update user: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::LibraryNotFound)?;
         
-        if result.matched_count == 0 {
-            return Err(DbError::UserNotFound);
-        }
+        Ok(library.into())
+    }
+    
+    async fn save_library(&self, library: &Library) -> Result<(), DbError> {
+        self.libraries_collection
+            .replace_one(doc! { "_id": &library.id }, MongoLibrary::from(library))
+            .upsert(true)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save library: {}", e)))?;
         
         Ok(())
     }
     
-    async fn update_username(&self, user_id: &str, new_username: &str) -> Result<(), DbError> {
-        // Check if new username already exists
-        if self.username_exists(new_username).await? {
-            return Err(DbError::UserAlreadyExists);
-        }
-        
-        let filter = doc! { "id": user_id };
-        let update = doc! { "$set": { "username": new_username } };
+    async fn delete_library(&self, id: &str) -> Result<(), DbError> {
+        self.library_grants_collection
+            .delete_many(doc! { "library_id": id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
         
-        let result = self.users_collection
-            .update_one(filter, update)
+        let result = self.libraries_collection
+            .delete_one(doc! { "_id": id })
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to update username: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library: {}", e)))?;
         
-        if result.matched_count == 0 {
-            return Err(DbError::UserNotFound);
+        if result.deleted_count == 0 {
+            return Err(DbError::LibraryNotFound);
         }
         
         Ok(())
     }
     
-    async fn update_user_password(&self, user_id: &str, new_password_hash: &str) -> Result<(), DbError> {
-        let filter = doc! { "id": user_id };
-        let update = doc! { "$set": { "password_hash": new_password_hash } };
+    async fn get_library_grants(&self, library_id: &str) -> Result<Vec<LibraryGrant>, DbError> {
+        use mongodb::options::FindOptions;
         
-        let result = self.users_collection
-            .update_one(filter, update)
+        let options = FindOptions::builder()
+            .sort(doc! { "kind": 1, "subject": 1 })
+            .build();
+        
+        let mut cursor = self.library_grants_collection
+            .find(doc! { "library_id": library_id })
+            .with_options(options)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to update password: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        if result.matched_count == 0 {
-            return Err(DbError::UserNotFound);
+        let mut grants = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            grants.push(cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize library grant: {}", e)))?);
         }
         
-        Ok(())
+        Ok(grants)
     }
     
-    async fn delete_user_by_username(&self, username: &str) -> Result<(), DbError> {
-        let filter = doc! { "username": username };
-
-        let result = self.users_collection
-            .delete_one(filter)
+    async fn grant_library(&self, grant: &LibraryGrant) -> Result<(), DbError> {
+        let filter = doc! { "library_id": &grant.library_id, "kind": grant.kind.as_str(), "subject": &grant.subject };
+        
+        self.library_grants_collection
+            .replace_one(filter, grant)
+            .upsert(true)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
-
-        if result.deleted_count == 0 {
-            return Err(DbError::UserNotFound);
-        }
-
+            .map_err(|e| DbError::DatabaseError(format!("Failed to grant library: {}", e)))?;
+        
         Ok(())
     }
     
-    async fn delete_user_by_id(&self, user_id: &str) -> Result<(), DbError> {
-        let filter = doc! { "id": user_id };
-
-        let result = self.users_collection
+    async fn revoke_library(&self, grant: &LibraryGrant) -> Result<(), DbError> {
+        let filter = doc! { "library_id": &grant.library_id, "kind": grant.kind.as_str(), "subject": &grant.subject };
+        
+        let result = self.library_grants_collection
             .delete_one(filter)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
-
+            .map_err(|e| DbError::DatabaseError(format!("Failed to revoke library: {}", e)))?;
+        
         if result.deleted_count == 0 {
-            return Err(DbError::UserNotFound);
+            return Err(DbError::GrantNotFound);
         }
-
+        
         Ok(())
     }
     
-    async fn get_total_users(&self) -> Result<usize, DbError> {
-        let count = self.users_collection
-            .count_documents(doc! {})
+    async fn get_accessible_libraries(&self, user_id: &str, role: &str) -> Result<Vec<String>, DbError> {
+        let filter = doc! { "$or": [
+            { "kind": GrantKind::User.as_str(), "subject": user_id },
+            { "kind": GrantKind::Role.as_str(), "subject": role },
+        ] };
+        
+        let ids = self.library_grants_collection
+            .distinct("library_id", filter)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(count as usize)
+        Ok(ids.into_iter().filter_map(|id| id.as_str().map(str::to_string)).collect())
     }
     
     // Artist operations
@@ -523,39 +2222,15 @@ impl Database for MongoDatabase {
         Ok(mongo_artist.into())
     }
     
-    async fn get_artists(&self, offset: usize, limit: usize) -> Result<Vec<Artist>, DbError> {
-        use mongodb::options::FindOptions;
-        
-        let options = FindOptions::builder()
-            .sort(doc! { "name": 1 })
-            .skip(offset as u64)
-            .limit(limit as i64)
-            .build();
-        
-        let mut cursor = self.artists_collection
-            .find(doc! {})
-            .with_options(options)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
-        
-        let mut artists = Vec::new();
-        while cursor.advance().await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
-            let mongo_artist = cursor.deserialize_current()
-                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize artist: {}", e)))?;
-            artists.push(mongo_artist.into());
-        }
-        
-        Ok(artists)
-    }
-    
-    async fn get_total_artists(&self) -> Result<usize, DbError> {
-        let count = self.artists_collection
-            .count_documents(doc! {})
+    async fn get_artists(&self, page: &PageRequest, libraries: &LibraryFilter) -> Result<Page<Artist>, DbError> {
+        let filter = self.artist_library_condition(libraries).await?;
+        let artists = find_page(&self.artists_collection, filter.clone(), sort_field(page.sort, "name"), page).await?;
+        let total = self.artists_collection
+            .count_documents(filter)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))? as usize;
         
-        Ok(count as usize)
+        Ok(Page::from_rows(artists.into_iter().map(Into::into).collect(), page, total))
     }
     
     async fn update_artist_cover(&self, id: &str, cover_path: &str) -> Result<(), DbError> {
@@ -586,7 +2261,7 @@ impl Database for MongoDatabase {
     }
     
     // Song operations
-    async fn create_song(&self, title: &str, artist_id: &str, file_path: &str) -> Result<Song, DbError> {
+    async fn create_song(&self, title: &str, artist_id: &str, file_path: &str, library_id: &str) -> Result<Song, DbError> {
         let artist = self.get_artist_by_id(artist_id).await?;
         
         let id = Uuid::new_v4().to_string();
@@ -601,7 +2276,17 @@ impl Database for MongoDatabase {
             album: None,
             duration: None,
             file_path: file_path.to_string(),
+            library_id: library_id.to_string(),
             cover_image_path: None,
+            musicbrainz_id: None,
+            genre: None,
+            year: None,
+            start_ms: None,
+            end_ms: None,
+            encoder_delay: None,
+            encoder_padding: None,
+            sample_rate: None,
+            play_count: 0,
             created_at: created_at_timestamp,
         };
         
@@ -618,7 +2303,17 @@ impl Database for MongoDatabase {
             album: None,
             duration: None,
             file_path: file_path.to_string(),
+            library_id: library_id.to_string(),
             cover_image_path: None,
+            musicbrainz_id: None,
+            genre: None,
+            year: None,
+            start_ms: None,
+            end_ms: None,
+            encoder_delay: None,
+            encoder_padding: None,
+            sample_rate: None,
+            play_count: 0,
             created_at,
         })
     }
@@ -635,10 +2330,60 @@ impl Database for MongoDatabase {
         Ok(mongo_song.into())
     }
     
-    async fn get_songs_by_artist(&self, artist_id: &str) -> Result<Vec<Song>, DbError> {
+    async fn get_song_by_file_path(&self, file_path: &str) -> Result<Song, DbError> {
+        let filter = doc! { "file_path": file_path };
+        
+        let mongo_song = self.songs_collection
+            .find_one(filter)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::DatabaseError("Song not found".to_string()))?;
+        
+        Ok(mongo_song.into())
+    }
+    
+    async fn get_songs_by_file_path(&self, file_path: &str) -> Result<Vec<Song>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let filter = doc! { "file_path": file_path };
+        let options = FindOptions::builder()
+            .sort(doc! { "start_ms": 1 })
+            .build();
+        
+        let mut cursor = self.songs_collection
+            .find(filter)
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut songs = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let mongo_song = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize song: {}", e)))?;
+            songs.push(mongo_song.into());
+        }
+        
+        Ok(songs)
+    }
+    
+    async fn get_song_by_musicbrainz_id(&self, musicbrainz_id: &str) -> Result<Song, DbError> {
+        let filter = doc! { "musicbrainz_id": musicbrainz_id };
+        
+        let mongo_song = self.songs_collection
+            .find_one(filter)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::DatabaseError("Song not found".to_string()))?;
+        
+        Ok(mongo_song.into())
+    }
+    
+    async fn get_songs_by_artist(&self, artist_id: &str, libraries: &LibraryFilter) -> Result<Vec<Song>, DbError> {
         use mongodb::options::FindOptions;
         
-        let filter = doc! { "artist_id": artist_id };
+        let mut filter = doc! { "artist_id": artist_id };
+        filter.extend(library_condition(libraries));
         let options = FindOptions::builder()
             .sort(doc! { "title": 1 })
             .build();
@@ -660,17 +2405,30 @@ impl Database for MongoDatabase {
         Ok(songs)
     }
     
-    async fn get_songs(&self, offset: usize, limit: usize) -> Result<Vec<Song>, DbError> {
+    async fn get_songs(&self, page: &PageRequest, libraries: &LibraryFilter) -> Result<Page<Song>, DbError> {
+        let filter = library_condition(libraries);
+        let songs = find_page(&self.songs_collection, filter.clone(), sort_field(page.sort, "title"), page).await?;
+        let total = self.songs_collection
+            .count_documents(filter)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))? as usize;
+        
+        Ok(Page::from_rows(songs.into_iter().map(Into::into).collect(), page, total))
+    }
+    
+    async fn search_songs(&self, query: &str, libraries: &LibraryFilter, offset: usize, limit: usize) -> Result<Vec<Song>, DbError> {
         use mongodb::options::FindOptions;
         
+        let mut filter = doc! { "title": { "$regex": escape_regex(query), "$options": "i" } };
+        filter.extend(library_condition(libraries));
         let options = FindOptions::builder()
-            .sort(doc! { "created_at": -1 })
+            .sort(doc! { "title": 1 })
             .skip(offset as u64)
             .limit(limit as i64)
             .build();
         
         let mut cursor = self.songs_collection
-            .find(doc! {})
+            .find(filter)
             .with_options(options)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
@@ -686,30 +2444,102 @@ impl Database for MongoDatabase {
         Ok(songs)
     }
     
-    async fn get_total_songs(&self) -> Result<usize, DbError> {
-        let count = self.songs_collection
-            .count_documents(doc! {})
+    async fn increment_song_play_count(&self, id: &str) -> Result<(), DbError> {
+        let filter = doc! { "_id": id };
+        let update = doc! { "$inc": { "play_count": 1_i64 } };
+        
+        let result = self.songs_collection
+            .update_one(filter, update)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_song_metadata(&self, id: &str, album: Option<&str>, duration: Option<i32>, cover_path: Option<&str>) -> Result<(), DbError> {
+        let filter = doc! { "_id": id };
+        let mut updates = doc! {};
+        
+        if let Some(album_val) = album {
+            updates.insert("album", album_val);
+        }
+        if let Some(duration_val) = duration {
+            updates.insert("duration", duration_val);
+        }
+        if let Some(cover_val) = cover_path {
+            updates.insert("cover_image_path", cover_val);
+        }
+        
+        let update = doc! { "$set": updates };
+        
+        let result = self.songs_collection
+            .update_one(filter, update)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_song_tags(&self, id: &str, musicbrainz_id: Option<&str>, genre: Option<&str>, year: Option<i32>) -> Result<(), DbError> {
+        let filter = doc! { "_id": id };
+        let mut set = Document::new();
+        if let Some(musicbrainz_id) = musicbrainz_id {
+            set.insert("musicbrainz_id", musicbrainz_id);
+        }
+        if let Some(genre) = genre {
+            set.insert("genre", genre);
+        }
+        if let Some(year) = year {
+            set.insert("year", year);
+        }
+        if set.is_empty() {
+            return self.get_song_by_id(id).await.map(|_| ());
+        }
+        let update = doc! { "$set": set };
+        
+        let result = self.songs_collection
+            .update_one(filter, update)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_song_track_range(&self, id: &str, start_ms: Option<i64>, end_ms: Option<i64>) -> Result<(), DbError> {
+        let filter = doc! { "_id": id };
+        let update = doc! { "$set": { "start_ms": start_ms, "end_ms": end_ms } };
+        
+        let result = self.songs_collection
+            .update_one(filter, update)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
         
-        Ok(count as usize)
+        Ok(())
     }
     
-    async fn update_song_metadata(&self, id: &str, album: Option<&str>, duration: Option<i32>, cover_path: Option<&str>) -> Result<(), DbError> {
+    async fn update_song_gapless(&self, id: &str, encoder_delay: Option<i32>, encoder_padding: Option<i32>, sample_rate: Option<i32>) -> Result<(), DbError> {
         let filter = doc! { "_id": id };
-        let mut updates = doc! {};
-        
-        if let Some(album_val) = album {
-            updates.insert("album", album_val);
-        }
-        if let Some(duration_val) = duration {
-            updates.insert("duration", duration_val);
-        }
-        if let Some(cover_val) = cover_path {
-            updates.insert("cover_image_path", cover_val);
-        }
-        
-        let update = doc! { "$set": updates };
+        let update = doc! { "$set": {
+            "encoder_delay": encoder_delay,
+            "encoder_padding": encoder_padding,
+            "sample_rate": sample_rate,
+        } };
         
         let result = self.songs_collection
             .update_one(filter, update)
@@ -735,11 +2565,16 @@ impl Database for MongoDatabase {
             return Err(DbError::DatabaseError("Song not found".to_string()));
         }
         
+        // Drop the song from playlists, as the SQL backends do by cascade
+        let _ = self.playlist_entries_collection.delete_many(doc! { "song_id": id }).await;
+        let _ = self.user_ratings_collection.delete_many(doc! { "item_type": "song", "item_id": id }).await;
+        let _ = self.song_lyrics_collection.delete_one(doc! { "_id": id }).await;
+        
         Ok(())
     }
     
     // Playlist operations
-    async fn create_playlist(&self, name: &str, owner_id: &str, is_public: bool) -> Result<Playlist, DbError> {
+    async fn create_playlist(&self, name: &str, owner_id: &str, is_public: bool, rules: Option<&SmartRules>) -> Result<Playlist, DbError> {
         let owner = self.get_user_by_id(owner_id).await?;
         
         let id = Uuid::new_v4().to_string();
@@ -752,7 +2587,11 @@ impl Database for MongoDatabase {
             owner_id: owner_id.to_string(),
             owner_username: owner.username.clone(),
             is_public,
+            version: 0,
+            is_smart: rules.is_some(),
+            rules: rules.cloned(),
             created_at: created_at_timestamp,
+            edit_lease_until: None,
         };
         
         self.playlists_collection
@@ -766,6 +2605,9 @@ impl Database for MongoDatabase {
             owner_id: owner_id.to_string(),
             owner_username: owner.username,
             is_public,
+            version: 0,
+            is_smart: rules.is_some(),
+            rules: rules.cloned(),
             created_at,
         })
     }
@@ -794,58 +2636,28 @@ impl Database for MongoDatabase {
         Ok(mongo_playlist.into())
     }
     
-    async fn get_user_playlists(&self, user_id: &str, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError> {
-        use mongodb::options::FindOptions;
-        
+    async fn get_user_playlists(&self, user_id: &str, page: &PageRequest) -> Result<Page<Playlist>, DbError> {
         let filter = doc! { "owner_id": user_id };
-        let options = FindOptions::builder()
-            .sort(doc! { "created_at": -1 })
-            .skip(offset as u64)
-            .limit(limit as i64)
-            .build();
+        let playlists = find_page(&self.playlists_collection, filter.clone(), sort_field(page.sort, "name"), page).await?;
         
-        let mut cursor = self.playlists_collection
-            .find(filter)
-            .with_options(options)
+        let total = self.playlists_collection
+            .count_documents(filter)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut playlists = Vec::new();
-        while cursor.advance().await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
-            let mongo_playlist = cursor.deserialize_current()
-                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist: {}", e)))?;
-            playlists.push(mongo_playlist.into());
-        }
-        
-        Ok(playlists)
+        Ok(Page::from_rows(playlists.into_iter().map(Into::into).collect(), page, total as usize))
     }
     
-    async fn get_public_playlists(&self, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError> {
-        use mongodb::options::FindOptions;
-        
+    async fn get_public_playlists(&self, page: &PageRequest) -> Result<Page<Playlist>, DbError> {
         let filter = doc! { "is_public": true };
-        let options = FindOptions::builder()
-            .sort(doc! { "created_at": -1 })
-            .skip(offset as u64)
-            .limit(limit as i64)
-            .build();
+        let playlists = find_page(&self.playlists_collection, filter.clone(), sort_field(page.sort, "name"), page).await?;
         
-        let mut cursor = self.playlists_collection
-            .find(filter)
-            .with_options(options)
+        let total = self.playlists_collection
+            .count_documents(filter)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut playlists = Vec::new();
-        while cursor.advance().await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
-            let mongo_playlist = cursor.deserialize_current()
-                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist: {}", e)))?;
-            playlists.push(mongo_playlist.into());
-        }
-        
-        Ok(playlists)
+        Ok(Page::from_rows(playlists.into_iter().map(Into::into).collect(), page, total as usize))
     }
     
     async fn get_shared_playlists(&self, user_id: &str) -> Result<Vec<(Playlist, PlaylistShare)>, DbError> {
@@ -885,72 +2697,173 @@ impl Database for MongoDatabase {
         
         // Also delete associated playlist songs and shares
         let playlist_filter = doc! { "playlist_id": playlist_id };
-        let _ = self.playlist_songs_collection.delete_many(playlist_filter.clone()).await;
-        let _ = self.playlist_shares_collection.delete_many(playlist_filter).await;
+        let _ = self.playlist_entries_collection.delete_many(playlist_filter.clone()).await;
+        let _ = self.playlist_shares_collection.delete_many(playlist_filter.clone()).await;
+        let _ = self.playlist_activity_collection.delete_many(playlist_filter).await;
         
         Ok(())
     }
     
-    async fn add_song_to_playlist(&self, playlist_id: &str, song_id: &str) -> Result<(), DbError> {
-        // Verify song and playlist exist
-        let _ = self.get_song_by_id(song_id).await?;
-        let _ = self.get_playlist_by_id(playlist_id).await?;
+    async fn get_playlist_entries(&self, playlist_id: &str) -> Result<Vec<PlaylistEntry>, DbError> {
+        use mongodb::options::FindOptions;
         
-        let added_at = OffsetDateTime::now_utc();
-        let added_at_timestamp = added_at.unix_timestamp();
+        let filter = doc! { "playlist_id": playlist_id };
+        let options = FindOptions::builder()
+            .sort(doc! { "position": 1, "_id": 1 })
+            .build();
         
-        let mongo_playlist_song = MongoPlaylistSong {
-            playlist_id: playlist_id.to_string(),
-            song_id: song_id.to_string(),
-            added_at: added_at_timestamp,
-        };
+        let mut cursor = self.playlist_entries_collection
+            .find(filter)
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        // Try to insert, ignore if already exists
-        let _ = self.playlist_songs_collection
-            .insert_one(&mongo_playlist_song)
-            .await;
+        let mut entries = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let entry: MongoPlaylistEntry = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist entry: {}", e)))?;
+            
+            if let Ok(song) = self.get_song_by_id(&entry.song_id).await {
+                entries.push(PlaylistEntry {
+                    id: entry.id,
+                    position: entries.len() as i64,
+                    song,
+                    added_at: OffsetDateTime::from_unix_timestamp(entry.added_at)
+                        .unwrap_or_else(|_| OffsetDateTime::now_utc()),
+                });
+            }
+        }
         
-        Ok(())
+        Ok(entries)
     }
     
-    async fn remove_song_from_playlist(&self, playlist_id: &str, song_id: &str) -> Result<(), DbError> {
-        let filter = doc! { "playlist_id": playlist_id, "song_id": song_id };
+    async fn edit_playlist_entries(&self, playlist_id: &str, edit: &PlaylistEdit, expected_version: Option<i64>) -> Result<(i64, Vec<EditedEntry>), DbError> {
+        use mongodb::options::FindOptions;
         
-        let result = self.playlist_songs_collection
-            .delete_one(filter)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to remove song from playlist: {}", e)))?;
+        // Check songs being added exist
+        for song_id in edit.inserted_song_ids() {
+            let _ = self.get_song_by_id(song_id).await?;
+        }
         
-        if result.deleted_count == 0 {
-            return Err(DbError::DatabaseError("Song not in playlist".to_string()));
+        // Without transactions, an edit claims the next version only while no
+        // other edit holds the playlist, and releases it once its writes are done
+        for _ in 0..PLAYLIST_EDIT_ATTEMPTS {
+            let playlist = self.playlists_collection
+                .find_one(doc! { "_id": playlist_id })
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+                .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
+            
+            if expected_version.is_some_and(|expected| expected != playlist.version) {
+                return Err(DbError::VersionConflict);
+            }
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            if playlist.edit_lease_until.is_some_and(|until| until > now) {
+                tokio::time::sleep(PLAYLIST_EDIT_RETRY_DELAY).await;
+                continue;
+            }
+            
+            let options = FindOptions::builder()
+                .sort(doc! { "position": 1, "_id": 1 })
+                .build();
+            
+            let mut cursor = self.playlist_entries_collection
+                .find(doc! { "playlist_id": playlist_id })
+                .with_options(options)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+            
+            let mut entries = Vec::new();
+            while cursor.advance().await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+                let entry: MongoPlaylistEntry = cursor.deserialize_current()
+                    .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist entry: {}", e)))?;
+                entries.push(EntrySlot::stored(entry.id, entry.song_id, entry.position));
+            }
+            
+            // A rejected edit leaves the version alone
+            let plan = edit.apply(entries)?;
+            
+            // Only claims the version if no other edit has since the entries were read
+            let claim = self.playlists_collection
+                .update_one(
+                    doc! { "_id": playlist_id, "version": playlist.version, "edit_lease_until": { "$not": { "$gt": now } } },
+                    doc! { "$inc": { "version": 1_i64 }, "$set": { "edit_lease_until": now + PLAYLIST_EDIT_LEASE_SECONDS } },
+                )
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to update playlist version: {}", e)))?;
+            
+            if claim.matched_count == 0 {
+                if expected_version.is_some() {
+                    return Err(DbError::VersionConflict);
+                }
+                continue;
+            }
+            
+            let version = playlist.version + 1;
+            let written = self.write_playlist_entries(playlist_id, &plan).await;
+            
+            self.playlists_collection
+                .update_one(
+                    doc! { "_id": playlist_id, "version": version },
+                    doc! { "$unset": { "edit_lease_until": "" } },
+                )
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to release playlist: {}", e)))?;
+            
+            written?;
+            return Ok((version, plan.edited));
         }
         
-        Ok(())
+        Err(DbError::VersionConflict)
     }
     
-    async fn get_playlist_songs(&self, playlist_id: &str) -> Result<Vec<Song>, DbError> {
-        use mongodb::options::FindOptions;
+    async fn get_smart_playlist_songs(&self, rules: &SmartRules, libraries: &LibraryFilter) -> Result<Vec<Song>, DbError> {
+        let mut filter = library_condition(libraries);
+        if let Some(rule) = &rules.filter {
+            filter = doc! { "$and": [filter, rule_filter(rule)] };
+        }
         
-        let filter = doc! { "playlist_id": playlist_id };
-        let options = FindOptions::builder()
-            .sort(doc! { "added_at": 1 })
-            .build();
+        let mut pipeline = vec![doc! { "$match": filter.clone() }];
+        match &rules.order {
+            Some(RuleOrder::Random) => {
+                // `$sample` needs a size, so without a limit it draws every match
+                let size = match rules.limit {
+                    Some(limit) => limit as i64,
+                    None => self.songs_collection
+                        .count_documents(filter)
+                        .await
+                        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))? as i64,
+                };
+                if size == 0 {
+                    return Ok(Vec::new());
+                }
+                pipeline.push(doc! { "$sample": { "size": size } });
+            }
+            Some(RuleOrder::Field { field, descending }) => {
+                pipeline.extend(rule_order_stages(*field, *descending));
+                pipeline.extend(rules.limit.map(|limit| doc! { "$limit": limit as i64 }));
+                pipeline.push(doc! { "$project": { "sort_missing": 0, "sort_key": 0 } });
+            }
+            None => {
+                pipeline.push(doc! { "$sort": { "created_at": 1, "_id": 1 } });
+                pipeline.extend(rules.limit.map(|limit| doc! { "$limit": limit as i64 }));
+            }
+        }
         
-        let mut cursor = self.playlist_songs_collection
-            .find(filter)
-            .with_options(options)
+        let mut cursor = self.songs_collection
+            .aggregate(pipeline)
+            .with_type::<MongoSong>()
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
         let mut songs = Vec::new();
         while cursor.advance().await
             .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
-            let playlist_song: MongoPlaylistSong = cursor.deserialize_current()
-                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist song: {}", e)))?;
-            
-            if let Ok(song) = self.get_song_by_id(&playlist_song.song_id).await {
-                songs.push(song);
-            }
+            let mongo_song = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize song: {}", e)))?;
+            songs.push(mongo_song.into());
         }
         
         Ok(songs)
@@ -959,7 +2872,7 @@ impl Database for MongoDatabase {
     async fn is_song_in_playlist(&self, playlist_id: &str, song_id: &str) -> Result<bool, DbError> {
         let filter = doc! { "playlist_id": playlist_id, "song_id": song_id };
         
-        let count = self.playlist_songs_collection
+        let count = self.playlist_entries_collection
             .count_documents(filter)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
@@ -967,7 +2880,7 @@ impl Database for MongoDatabase {
         Ok(count > 0)
     }
     
-    async fn share_playlist(&self, playlist_id: &str, shared_with_user_id: &str, shared_by_user_id: &str) -> Result<PlaylistShare, DbError> {
+    async fn share_playlist(&self, playlist_id: &str, shared_with_user_id: &str, shared_by_user_id: &str, permission: SharePermission) -> Result<PlaylistShare, DbError> {
         // Verify playlist and users exist
         let _ = self.get_playlist_by_id(playlist_id).await?;
         let _ = self.get_user_by_id(shared_with_user_id).await?;
@@ -982,6 +2895,7 @@ impl Database for MongoDatabase {
             playlist_id: playlist_id.to_string(),
             shared_with_user_id: shared_with_user_id.to_string(),
             shared_by_user_id: shared_by_user_id.to_string(),
+            permission,
             shared_at: shared_at_timestamp,
         };
         
@@ -1000,6 +2914,7 @@ impl Database for MongoDatabase {
             playlist_id: playlist_id.to_string(),
             shared_with_user_id: shared_with_user_id.to_string(),
             shared_by_user_id: shared_by_user_id.to_string(),
+            permission,
             shared_at,
         })
     }
@@ -1019,41 +2934,306 @@ impl Database for MongoDatabase {
         Ok(())
     }
     
-    async fn is_playlist_shared_with_user(&self, playlist_id: &str, user_id: &str) -> Result<bool, DbError> {
+    async fn get_playlist_share(&self, playlist_id: &str, user_id: &str) -> Result<Option<PlaylistShare>, DbError> {
         let filter = doc! { "playlist_id": playlist_id, "shared_with_user_id": user_id };
 
-        let count = self.playlist_shares_collection
-            .count_documents(filter)
+        let share = self.playlist_shares_collection
+            .find_one(filter)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
 
-        Ok(count > 0)
+        Ok(share.map(Into::into))
     }
     
-    // Admin playlist operations
-    async fn get_all_playlists(&self, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError> {
+    async fn get_playlist_shares(&self, playlist_id: &str) -> Result<Vec<PlaylistShare>, DbError> {
         use mongodb::options::FindOptions;
         
         let options = FindOptions::builder()
-            .skip(offset as u64)
+            .sort(doc! { "shared_at": 1 })
+            .build();
+        
+        let mut cursor = self.playlist_shares_collection
+            .find(doc! { "playlist_id": playlist_id })
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut shares = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let share: MongoPlaylistShare = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize share: {}", e)))?;
+            shares.push(share.into());
+        }
+        
+        Ok(shares)
+    }
+    
+    async fn record_playlist_activity(&self, activity: &[PlaylistActivity]) -> Result<(), DbError> {
+        if activity.is_empty() {
+            return Ok(());
+        }
+        
+        let documents: Vec<MongoPlaylistActivity> = activity.iter().map(Into::into).collect();
+        self.playlist_activity_collection
+            .insert_many(documents)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to record playlist activity: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_playlist_activity(&self, playlist_id: &str, limit: usize) -> Result<Vec<PlaylistActivity>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1, "_id": -1 })
             .limit(limit as i64)
+            .build();
+        
+        let mut cursor = self.playlist_activity_collection
+            .find(doc! { "playlist_id": playlist_id })
+            .with_options(options)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let mut activity = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let item: MongoPlaylistActivity = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist activity: {}", e)))?;
+            activity.push(item.into());
+        }
+        
+        Ok(activity)
+    }
+    
+    async fn create_share_link(&self, token: &str, owner_id: &str, target: &ShareTarget, password_hash: Option<&str>, expires_at: Option<OffsetDateTime>) -> Result<ShareLink, DbError> {
+        let created_at = OffsetDateTime::now_utc();
+        
+        let mongo_link = MongoShareLink {
+            id: token.to_string(),
+            owner_id: owner_id.to_string(),
+            target: target.clone(),
+            password_hash: password_hash.map(str::to_string),
+            expires_at: expires_at.map(|expires_at| expires_at.unix_timestamp()),
+            created_at: created_at.unix_timestamp(),
+        };
+        
+        self.share_links_collection
+            .insert_one(&mongo_link)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create share link: {}", e)))?;
+        
+        Ok(ShareLink {
+            id: token.to_string(),
+            owner_id: owner_id.to_string(),
+            target: target.clone(),
+            password_hash: password_hash.map(str::to_string),
+            expires_at,
+            created_at,
+        })
+    }
+    
+    async fn get_share_link(&self, token: &str) -> Result<ShareLink, DbError> {
+        self.share_links_collection
+            .find_one(doc! { "_id": token })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .map(Into::into)
+            .ok_or(DbError::DatabaseError("Share link not found".to_string()))
+    }
+    
+    async fn get_user_share_links(&self, owner_id: &str) -> Result<Vec<ShareLink>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let options = FindOptions::builder()
             .sort(doc! { "created_at": -1 })
             .build();
-
-        let mut cursor = self.playlists_collection
-            .find(doc! {})
+        
+        let mut cursor = self.share_links_collection
+            .find(doc! { "owner_id": owner_id })
             .with_options(options)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
-
-        let mut playlists = Vec::new();
-        while cursor.advance().await.map_err(|e| DbError::DatabaseError(format!("Cursor error: {}", e)))? {
-            let mongo_playlist: MongoPlaylist = cursor.deserialize_current()
-                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize playlist: {}", e)))?;
-            playlists.push(mongo_playlist.into());
+        
+        let mut links = Vec::new();
+        while cursor.advance().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to iterate cursor: {}", e)))? {
+            let link: MongoShareLink = cursor.deserialize_current()
+                .map_err(|e| DbError::DatabaseError(format!("Failed to deserialize share link: {}", e)))?;
+            links.push(link.into());
         }
-
-        Ok(playlists)
+        
+        Ok(links)
+    }
+    
+    async fn delete_share_link(&self, token: &str, owner_id: &str) -> Result<(), DbError> {
+        let result = self.share_links_collection
+            .delete_one(doc! { "_id": token, "owner_id": owner_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete share link: {}", e)))?;
+        
+        if result.deleted_count == 0 {
+            return Err(DbError::DatabaseError("Share link not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    // Favourites and ratings
+    async fn save_user_rating(&self, rating: &UserRating) -> Result<(), DbError> {
+        let filter = doc! {
+            "user_id": &rating.user_id,
+            "item_type": rating.item.kind(),
+            "item_id": rating.item.item_id(),
+            "album": rating.item.album().unwrap_or_default(),
+        };
+        
+        if rating.is_empty() {
+            self.user_ratings_collection
+                .delete_one(filter)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to delete rating: {}", e)))?;
+            
+            return Ok(());
+        }
+        
+        let mongo_rating = MongoUserRating {
+            user_id: rating.user_id.clone(),
+            item_type: rating.item.kind().to_string(),
+            item_id: rating.item.item_id().to_string(),
+            album: rating.item.album().unwrap_or_default().to_string(),
+            favorite: rating.favorite,
+            rating: rating.rating.map(i32::from),
+            updated_at: rating.updated_at.unix_timestamp(),
+        };
+        
+        self.user_ratings_collection
+            .replace_one(filter, &mongo_rating)
+            .upsert(true)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save rating: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_user_ratings(&self, user_id: &str, kind: Option<&str>) -> Result<Vec<UserRating>, DbError> {
+        use mongodb::options::FindOptions;
+        
+        let mut filter = doc! { "user_id": user_id };
+        if let Some(kind) = kind {
+            filter.insert("item_type", kind);
+        }
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "updated_at": -1 })
+            .build();
+        
+        self.find_user_ratings(filter, Some(options)).await
+    }
+    
+    async fn get_user_ratings_for(&self, user_id: &str, kind: &str, item_ids: &[String]) -> Result<Vec<UserRating>, DbError> {
+        if item_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        self.find_user_ratings(doc! { "user_id": user_id, "item_type": kind, "item_id": { "$in": item_ids } }, None).await
+    }
+    
+    // Play queue operations
+    async fn get_play_queue(&self, user_id: &str) -> Result<PlayQueue, DbError> {
+        let queue = self.play_queues_collection
+            .find_one(doc! { "_id": user_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(queue.map(Into::into).unwrap_or_else(|| PlayQueue::empty(user_id)))
+    }
+    
+    async fn save_play_queue(&self, queue: &PlayQueue, expected_version: Option<i64>) -> Result<PlayQueue, DbError> {
+        let fields = doc! {
+            "song_ids": &queue.song_ids,
+            "current_index": queue.current_index as i64,
+            "position_ms": queue.position_ms,
+            "device": &queue.device,
+            "updated_at": OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        
+        let saved = match expected_version {
+            // Only a queue still at the expected version is replaced
+            Some(version) if version > 0 => {
+                self.play_queues_collection
+                    .update_one(doc! { "_id": &queue.user_id, "version": version }, doc! { "$set": fields, "$inc": { "version": 1_i64 } })
+                    .await
+                    .map_err(|e| DbError::DatabaseError(format!("Failed to save play queue: {}", e)))?
+                    .matched_count > 0
+            }
+            // Version 0 means the client expects no queue to exist yet
+            Some(_) => {
+                let mut fields = fields;
+                fields.insert("version", 1_i64);
+                
+                self.play_queues_collection
+                    .update_one(doc! { "_id": &queue.user_id }, doc! { "$setOnInsert": fields })
+                    .upsert(true)
+                    .await
+                    .map_err(|e| DbError::DatabaseError(format!("Failed to save play queue: {}", e)))?
+                    .upserted_id
+                    .is_some()
+            }
+            None => {
+                self.play_queues_collection
+                    .update_one(doc! { "_id": &queue.user_id }, doc! { "$set": fields, "$inc": { "version": 1_i64 } })
+                    .upsert(true)
+                    .await
+                    .map_err(|e| DbError::DatabaseError(format!("Failed to save play queue: {}", e)))?;
+                true
+            }
+        };
+        
+        if !saved {
+            return Err(DbError::VersionConflict);
+        }
+        
+        self.get_play_queue(&queue.user_id).await
+    }
+    
+    // Lyrics operations
+    async fn get_song_lyrics(&self, song_id: &str) -> Result<Option<SongLyrics>, DbError> {
+        let lyrics = self.song_lyrics_collection
+            .find_one(doc! { "_id": song_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        lyrics.map(TryInto::try_into).transpose()
+    }
+    
+    async fn save_song_lyrics(&self, lyrics: &SongLyrics) -> Result<(), DbError> {
+        self.song_lyrics_collection
+            .replace_one(doc! { "_id": &lyrics.song_id }, MongoSongLyrics::from(lyrics))
+            .upsert(true)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save lyrics: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_song_lyrics(&self, song_id: &str) -> Result<(), DbError> {
+        self.song_lyrics_collection
+            .delete_one(doc! { "_id": song_id })
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete lyrics: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    // Admin playlist operations
+    async fn get_all_playlists(&self, page: &PageRequest) -> Result<Page<Playlist>, DbError> {
+        let playlists = find_page(&self.playlists_collection, doc! {}, sort_field(page.sort, "name"), page).await?;
+        let total = self.get_total_playlists().await?;
+        
+        Ok(Page::from_rows(playlists.into_iter().map(Into::into).collect(), page, total))
     }
     
     async fn get_total_playlists(&self) -> Result<usize, DbError> {
@@ -1066,7 +3246,7 @@ impl Database for MongoDatabase {
     }
     
     async fn update_playlist_name(&self, playlist_id: &str, new_name: &str) -> Result<(), DbError> {
-        let filter = doc! { "id": playlist_id };
+        let filter = doc! { "_id": playlist_id };
         let update = doc! { "$set": { "name": new_name } };
 
         let result = self.playlists_collection
@@ -1082,7 +3262,7 @@ impl Database for MongoDatabase {
     }
     
     async fn update_playlist_visibility(&self, playlist_id: &str, is_public: bool) -> Result<(), DbError> {
-        let filter = doc! { "id": playlist_id };
+        let filter = doc! { "_id": playlist_id };
         let update = doc! { "$set": { "is_public": is_public } };
 
         let result = self.playlists_collection
@@ -1097,8 +3277,27 @@ impl Database for MongoDatabase {
         Ok(())
     }
     
+    async fn update_playlist_rules(&self, playlist_id: &str, rules: &SmartRules) -> Result<i64, DbError> {
+        use mongodb::options::ReturnDocument;
+        
+        let rules = mongodb::bson::to_bson(rules)
+            .map_err(|e| DbError::DatabaseError(format!("Failed to encode smart playlist rules: {}", e)))?;
+        
+        let playlist = self.playlists_collection
+            .find_one_and_update(
+                doc! { "_id": playlist_id, "is_smart": true },
+                doc! { "$set": { "rules": rules }, "$inc": { "version": 1_i64 } },
+            )
+            .return_document(ReturnDocument::After)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update playlist rules: {}", e)))?
+            .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
+        
+        Ok(playlist.version)
+    }
+    
     async fn delete_playlist_by_id(&self, playlist_id: &str) -> Result<(), DbError> {
-        let filter = doc! { "id": playlist_id };
+        let filter = doc! { "_id": playlist_id };
 
         let result = self.playlists_collection
             .delete_one(filter)
@@ -1109,6 +3308,35 @@ impl Database for MongoDatabase {
             return Err(DbError::DatabaseError("Playlist not found".to_string()));
         }
 
+        let playlist_filter = doc! { "playlist_id": playlist_id };
+        let _ = self.playlist_entries_collection.delete_many(playlist_filter.clone()).await;
+        let _ = self.playlist_shares_collection.delete_many(playlist_filter.clone()).await;
+        let _ = self.playlist_activity_collection.delete_many(playlist_filter).await;
+
+        Ok(())
+    }
+    
+    async fn record_audit_event(&self, event: &AuditEvent) -> Result<(), DbError> {
+        self.audit_log_collection
+            .insert_one(MongoAuditEvent::from(event))
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to record audit event: {}", e)))?;
+        
         Ok(())
     }
+    
+    async fn get_audit_events(&self, filter: &AuditFilter, page: &PageRequest) -> Result<Page<AuditEvent>, DbError> {
+        let query = audit_filter(filter);
+        let events = find_page(&self.audit_log_collection, query.clone(), "created_at", page).await?
+            .into_iter()
+            .map(AuditEvent::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        let total = self.audit_log_collection
+            .count_documents(query)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(Page::from_rows(events, page, total as usize))
+    }
 }
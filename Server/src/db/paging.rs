@@ -0,0 +1,290 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::db::models::{Artist, AuditEvent, Playlist, Song, User};
+
+/// Field a listing can be ordered by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    Title,
+    Artist,
+    Name,
+    Added,
+    PlayCount,
+}
+
+impl SortField {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "title" => Some(SortField::Title),
+            "artist" => Some(SortField::Artist),
+            "name" => Some(SortField::Name),
+            "added" | "created_at" => Some(SortField::Added),
+            "play_count" | "plays" => Some(SortField::PlayCount),
+            _ => None,
+        }
+    }
+
+    /// Numeric sort keys are compared as integers, everything else as text
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, SortField::Added | SortField::PlayCount)
+    }
+
+    /// Order used when a client picks a sort field without an order:
+    /// newest/most played first, text alphabetically
+    pub fn default_order(&self) -> SortOrder {
+        if self.is_numeric() {
+            SortOrder::Desc
+        } else {
+            SortOrder::Asc
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "asc" => Some(SortOrder::Asc),
+            "desc" => Some(SortOrder::Desc),
+            _ => None,
+        }
+    }
+
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Position of the last item returned, used to resume a listing.
+/// The sort field and order are embedded so a cursor can't be replayed
+/// against a differently ordered listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    #[serde(rename = "s")]
+    pub sort: SortField,
+    #[serde(rename = "o")]
+    pub order: SortOrder,
+    #[serde(rename = "k")]
+    pub key: String,
+    pub id: String,
+}
+
+impl Cursor {
+    /// Encode the cursor as an opaque URL-safe token
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    /// Decode a token produced by `encode`
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    /// Sort key as an integer, for numeric sort fields
+    pub fn numeric_key(&self) -> i64 {
+        self.key.parse().unwrap_or(0)
+    }
+}
+
+/// A request for one page of a listing
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub limit: usize,
+    pub after: Option<Cursor>,
+}
+
+impl PageRequest {
+    pub fn new(sort: SortField, order: SortOrder, limit: usize) -> Self {
+        Self {
+            sort,
+            order,
+            limit,
+            after: None,
+        }
+    }
+
+    /// Request for the page following `cursor`
+    pub fn next(&self, cursor: Cursor) -> Self {
+        Self {
+            after: Some(cursor),
+            ..self.clone()
+        }
+    }
+}
+
+/// One page of a listing with the cursor for the following page
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+    pub total: usize,
+}
+
+impl<T: Keyed> Page<T> {
+    /// Build a page from rows fetched with `LIMIT request.limit + 1`.
+    /// The extra row only tells us whether another page exists.
+    pub fn from_rows(mut rows: Vec<T>, request: &PageRequest, total: usize) -> Self {
+        let has_more = rows.len() > request.limit;
+        rows.truncate(request.limit);
+
+        let next_cursor = if has_more {
+            rows.last().map(|last| Cursor {
+                sort: request.sort,
+                order: request.order,
+                key: last.sort_key(request.sort),
+                id: last.key_id().to_string(),
+            })
+        } else {
+            None
+        };
+
+        Self {
+            items: rows,
+            next_cursor,
+            total,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            total: self.total,
+        }
+    }
+}
+
+/// Models that can be listed with keyset pagination
+pub trait Keyed {
+    fn key_id(&self) -> &str;
+    fn sort_key(&self, field: SortField) -> String;
+}
+
+impl Keyed for Song {
+    fn key_id(&self) -> &str {
+        &self.id
+    }
+
+    fn sort_key(&self, field: SortField) -> String {
+        match field {
+            SortField::Title | SortField::Name => self.title.clone(),
+            SortField::Artist => self.artist_name.clone(),
+            SortField::Added => self.created_at.unix_timestamp().to_string(),
+            SortField::PlayCount => self.play_count.to_string(),
+        }
+    }
+}
+
+impl Keyed for Artist {
+    fn key_id(&self) -> &str {
+        &self.id
+    }
+
+    fn sort_key(&self, field: SortField) -> String {
+        match field {
+            SortField::Added => self.created_at.unix_timestamp().to_string(),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+impl Keyed for Playlist {
+    fn key_id(&self) -> &str {
+        &self.id
+    }
+
+    fn sort_key(&self, field: SortField) -> String {
+        match field {
+            SortField::Added => self.created_at.unix_timestamp().to_string(),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+impl Keyed for User {
+    fn key_id(&self) -> &str {
+        &self.id
+    }
+
+    fn sort_key(&self, field: SortField) -> String {
+        match field {
+            SortField::Added => self.created_at.unix_timestamp().to_string(),
+            _ => self.username.clone(),
+        }
+    }
+}
+
+impl Keyed for AuditEvent {
+    fn key_id(&self) -> &str {
+        &self.id
+    }
+
+    /// The log is only ever listed by time
+    fn sort_key(&self, _field: SortField) -> String {
+        self.created_at.unix_timestamp().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    fn artist(id: &str, name: &str) -> Artist {
+        Artist {
+            id: id.to_string(),
+            name: name.to_string(),
+            cover_image_path: None,
+            created_at: OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor {
+            sort: SortField::Title,
+            order: SortOrder::Desc,
+            key: "Blue in Green".to_string(),
+            id: "abc-123".to_string(),
+        };
+
+        let token = cursor.encode();
+        assert!(!token.contains('='));
+        assert_eq!(Cursor::decode(&token), Some(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), None);
+    }
+
+    #[test]
+    fn test_page_from_rows() {
+        let request = PageRequest::new(SortField::Name, SortOrder::Asc, 2);
+        let rows = vec![artist("1", "A"), artist("2", "B"), artist("3", "C")];
+
+        let page = Page::from_rows(rows, &request, 3);
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.total, 3);
+
+        let cursor = page.next_cursor.unwrap();
+        assert_eq!(cursor.key, "B");
+        assert_eq!(cursor.id, "2");
+
+        let last = Page::from_rows(vec![artist("3", "C")], &request.next(cursor), 3);
+        assert!(last.next_cursor.is_none());
+    }
+}
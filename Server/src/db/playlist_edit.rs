@@ -0,0 +1,198 @@
+use uuid::Uuid;
+
+use crate::db::DbError;
+
+/// A change to the ordered entries of a playlist.
+/// Positions are 0-based indexes into the playlist as currently ordered.
+#[derive(Debug, Clone)]
+pub enum PlaylistEdit {
+    /// Insert songs at a position, or append when `position` is `None`
+    Insert { song_ids: Vec<String>, position: Option<usize> },
+    /// Move the entry at `from` so it ends up at `to`
+    Move { from: usize, to: usize },
+    /// Remove the entries at the given positions
+    Remove { positions: Vec<usize> },
+}
+
+/// A playlist entry as stored, or one an edit is about to insert
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntrySlot {
+    pub id: String,
+    pub song_id: String,
+    /// Position currently stored for the entry, `None` for new entries
+    pub stored_position: Option<i64>,
+}
+
+impl EntrySlot {
+    pub fn stored(id: String, song_id: String, position: i64) -> Self {
+        Self {
+            id,
+            song_id,
+            stored_position: Some(position),
+        }
+    }
+}
+
+/// An entry an edit added, moved or removed, with the position it was
+/// added or moved to, or removed from
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditedEntry {
+    pub position: usize,
+    pub song_id: String,
+}
+
+/// Result of applying an edit: the entries in their new order,
+/// the ids of entries that were removed and the entries the edit touched
+#[derive(Debug)]
+pub struct EditPlan {
+    pub entries: Vec<EntrySlot>,
+    pub removed: Vec<String>,
+    pub edited: Vec<EditedEntry>,
+}
+
+impl EditPlan {
+    /// Entries whose stored position no longer matches their index,
+    /// including new entries, paired with the position to write
+    pub fn changed(&self) -> impl Iterator<Item = (i64, &EntrySlot)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| (index as i64, slot))
+            .filter(|(index, slot)| slot.stored_position != Some(*index))
+    }
+}
+
+impl PlaylistEdit {
+    /// Song ids an edit adds to the playlist
+    pub fn inserted_song_ids(&self) -> &[String] {
+        match self {
+            PlaylistEdit::Insert { song_ids, .. } => song_ids,
+            _ => &[],
+        }
+    }
+
+    /// Apply the edit to the current entries, ordered by stored position
+    pub fn apply(&self, mut entries: Vec<EntrySlot>) -> Result<EditPlan, DbError> {
+        let len = entries.len();
+        let mut removed = Vec::new();
+        let edited;
+
+        match self {
+            PlaylistEdit::Insert { song_ids, position } => {
+                let at = position.unwrap_or(len);
+                if at > len {
+                    return Err(DbError::InvalidPosition(at));
+                }
+
+                edited = song_ids.iter().enumerate()
+                    .map(|(i, song_id)| EditedEntry { position: at + i, song_id: song_id.clone() })
+                    .collect();
+                let new_entries = song_ids.iter().map(|song_id| EntrySlot {
+                    id: Uuid::new_v4().to_string(),
+                    song_id: song_id.clone(),
+                    stored_position: None,
+                });
+                entries.splice(at..at, new_entries);
+            }
+            PlaylistEdit::Move { from, to } => {
+                if *from >= len {
+                    return Err(DbError::InvalidPosition(*from));
+                }
+                if *to >= len {
+                    return Err(DbError::InvalidPosition(*to));
+                }
+
+                let entry = entries.remove(*from);
+                edited = vec![EditedEntry { position: *to, song_id: entry.song_id.clone() }];
+                entries.insert(*to, entry);
+            }
+            PlaylistEdit::Remove { positions } => {
+                let mut positions = positions.clone();
+                positions.sort_unstable();
+                positions.dedup();
+
+                if let Some(&last) = positions.last()
+                    && last >= len
+                {
+                    return Err(DbError::InvalidPosition(last));
+                }
+
+                edited = positions.iter()
+                    .map(|&position| EditedEntry { position, song_id: entries[position].song_id.clone() })
+                    .collect();
+                for &position in positions.iter().rev() {
+                    removed.push(entries.remove(position).id);
+                }
+            }
+        }
+
+        Ok(EditPlan { entries, removed, edited })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(songs: &[&str]) -> Vec<EntrySlot> {
+        songs
+            .iter()
+            .enumerate()
+            .map(|(i, song)| EntrySlot::stored(format!("e{}", i), song.to_string(), i as i64))
+            .collect()
+    }
+
+    fn song_order(plan: &EditPlan) -> Vec<&str> {
+        plan.entries.iter().map(|e| e.song_id.as_str()).collect()
+    }
+
+    fn edited(plan: &EditPlan) -> Vec<(usize, &str)> {
+        plan.edited.iter().map(|e| (e.position, e.song_id.as_str())).collect()
+    }
+
+    #[test]
+    fn test_insert_allows_duplicates() {
+        let edit = PlaylistEdit::Insert {
+            song_ids: vec!["a".to_string(), "x".to_string()],
+            position: Some(1),
+        };
+        let plan = edit.apply(playlist(&["a", "b"])).unwrap();
+
+        assert_eq!(song_order(&plan), vec!["a", "a", "x", "b"]);
+        assert_eq!(edited(&plan), vec![(1, "a"), (2, "x")]);
+        // Two new entries plus "b" shifting from 1 to 3
+        assert_eq!(plan.changed().count(), 3);
+
+        let append = PlaylistEdit::Insert { song_ids: vec!["c".to_string()], position: None };
+        assert_eq!(song_order(&append.apply(playlist(&["a"])).unwrap()), vec!["a", "c"]);
+
+        let past_end = PlaylistEdit::Insert { song_ids: vec!["c".to_string()], position: Some(3) };
+        assert!(matches!(past_end.apply(playlist(&["a"])), Err(DbError::InvalidPosition(3))));
+    }
+
+    #[test]
+    fn test_move() {
+        let plan = PlaylistEdit::Move { from: 0, to: 2 }.apply(playlist(&["a", "b", "c", "d"])).unwrap();
+        assert_eq!(song_order(&plan), vec!["b", "c", "a", "d"]);
+        assert_eq!(edited(&plan), vec![(2, "a")]);
+        assert!(plan.changed().all(|(_, e)| e.song_id != "d"));
+
+        let plan = PlaylistEdit::Move { from: 3, to: 1 }.apply(playlist(&["a", "b", "c", "d"])).unwrap();
+        assert_eq!(song_order(&plan), vec!["a", "d", "b", "c"]);
+
+        assert!(PlaylistEdit::Move { from: 0, to: 4 }.apply(playlist(&["a", "b"])).is_err());
+    }
+
+    #[test]
+    fn test_remove_positions() {
+        let edit = PlaylistEdit::Remove { positions: vec![2, 0, 2] };
+        let plan = edit.apply(playlist(&["a", "b", "c", "d"])).unwrap();
+
+        assert_eq!(song_order(&plan), vec!["b", "d"]);
+        assert_eq!(plan.removed, vec!["e2".to_string(), "e0".to_string()]);
+        assert_eq!(edited(&plan), vec![(0, "a"), (2, "c")]);
+
+        let edit = PlaylistEdit::Remove { positions: vec![4] };
+        assert!(edit.apply(playlist(&["a"])).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Rules a smart playlist is generated from, e.g.
+/// `genre = Jazz AND year < 1970 AND duration > 300 ORDER BY random LIMIT 50`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SmartRules {
+    /// Songs must match this to be included, every song matches when absent
+    pub filter: Option<RuleExpr>,
+    pub order: Option<RuleOrder>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleExpr {
+    Condition(Condition),
+    And(Vec<RuleExpr>),
+    Or(Vec<RuleExpr>),
+    Not(Box<RuleExpr>),
+}
+
+/// Text compares case-insensitively. A song without a value for the field
+/// (e.g. untagged genre) only matches `!=`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    pub field: RuleField,
+    pub op: RuleOp,
+    pub value: RuleValue,
+}
+
+/// Song attribute a rule can test or order by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    /// Duration in seconds
+    Duration,
+    PlayCount,
+    Added,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RuleValue {
+    Number(i64),
+    Text(String),
+}
+
+/// Songs without a value for the field sort last in either direction, and
+/// ties keep the order songs were added in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleOrder {
+    Random,
+    Field { field: RuleField, descending: bool },
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{message} at position {position}")]
+pub struct RuleParseError {
+    pub message: String,
+    /// Character offset into the rule text
+    pub position: usize,
+}
+
+impl RuleField {
+    fn from_string(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "title" | "name" => Some(RuleField::Title),
+            "artist" => Some(RuleField::Artist),
+            "album" => Some(RuleField::Album),
+            "genre" => Some(RuleField::Genre),
+            "year" => Some(RuleField::Year),
+            "duration" => Some(RuleField::Duration),
+            "play_count" | "plays" => Some(RuleField::PlayCount),
+            "added" | "created_at" => Some(RuleField::Added),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            RuleField::Title => "title",
+            RuleField::Artist => "artist",
+            RuleField::Album => "album",
+            RuleField::Genre => "genre",
+            RuleField::Year => "year",
+            RuleField::Duration => "duration",
+            RuleField::PlayCount => "play_count",
+            RuleField::Added => "added",
+        }
+    }
+
+    pub(crate) fn is_numeric(&self) -> bool {
+        matches!(self, RuleField::Year | RuleField::Duration | RuleField::PlayCount | RuleField::Added)
+    }
+}
+
+impl RuleOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RuleOp::Eq => "=",
+            RuleOp::Ne => "!=",
+            RuleOp::Lt => "<",
+            RuleOp::Le => "<=",
+            RuleOp::Gt => ">",
+            RuleOp::Ge => ">=",
+            RuleOp::Contains => "CONTAINS",
+        }
+    }
+}
+
+impl SmartRules {
+    pub fn parse(input: &str) -> Result<Self, RuleParseError> {
+        Parser::new(input)?.parse_rules()
+    }
+}
+
+// ============================================================================
+// Display, the inverse of parsing
+// ============================================================================
+
+impl fmt::Display for SmartRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(filter) = &self.filter {
+            parts.push(filter.to_string());
+        }
+        match &self.order {
+            Some(RuleOrder::Random) => parts.push("ORDER BY random".to_string()),
+            Some(RuleOrder::Field { field, descending }) => parts.push(format!(
+                "ORDER BY {} {}",
+                field.as_str(),
+                if *descending { "DESC" } else { "ASC" }
+            )),
+            None => {}
+        }
+        if let Some(limit) = self.limit {
+            parts.push(format!("LIMIT {}", limit));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for RuleExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |rules: &[RuleExpr], keyword: &str| {
+            rules.iter()
+                .map(|rule| match rule {
+                    RuleExpr::And(_) | RuleExpr::Or(_) => format!("({})", rule),
+                    _ => rule.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(keyword)
+        };
+
+        match self {
+            RuleExpr::Condition(condition) => write!(f, "{}", condition),
+            RuleExpr::And(rules) => write!(f, "{}", join(rules, " AND ")),
+            RuleExpr::Or(rules) => write!(f, "{}", join(rules, " OR ")),
+            RuleExpr::Not(rule) => match rule.as_ref() {
+                RuleExpr::Condition(_) | RuleExpr::Not(_) => write!(f, "NOT {}", rule),
+                _ => write!(f, "NOT ({})", rule),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            RuleValue::Number(n) => write!(f, "{} {} {}", self.field.as_str(), self.op.as_str(), n),
+            RuleValue::Text(text) => write!(
+                f,
+                "{} {} \"{}\"",
+                self.field.as_str(),
+                self.op.as_str(),
+                text.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        }
+    }
+}
+
+// ============================================================================
+// Parser
+// ============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(i64),
+    Text(String),
+    Op(RuleOp),
+    Open,
+    Close,
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn new(input: &str) -> Result<Self, RuleParseError> {
+        Ok(Self {
+            tokens: tokenize(input)?,
+            pos: 0,
+            end: input.chars().count(),
+        })
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, RuleParseError> {
+        Err(RuleParseError {
+            message: message.into(),
+            position: self.tokens.get(self.pos).map(|(at, _)| *at).unwrap_or(self.end),
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Consume the next token if it is the given keyword
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_rules(&mut self) -> Result<SmartRules, RuleParseError> {
+        let filter = match self.peek() {
+            None => None,
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("order") || word.eq_ignore_ascii_case("limit") => None,
+            Some(_) => Some(self.parse_or()?),
+        };
+
+        let order = if self.keyword("order") {
+            if !self.keyword("by") {
+                return self.error("Expected BY after ORDER");
+            }
+            Some(self.parse_order()?)
+        } else {
+            None
+        };
+
+        let limit = if self.keyword("limit") {
+            match self.next() {
+                Some(Token::Number(n)) if n > 0 => Some(n as usize),
+                _ => {
+                    self.pos -= 1;
+                    return self.error("Expected a positive number after LIMIT");
+                }
+            }
+        } else {
+            None
+        };
+
+        if self.peek().is_some() {
+            return self.error("Unexpected input");
+        }
+
+        Ok(SmartRules { filter, order, limit })
+    }
+
+    fn parse_order(&mut self) -> Result<RuleOrder, RuleParseError> {
+        let Some(Token::Word(word)) = self.peek().cloned() else {
+            return self.error("Expected a field or random after ORDER BY");
+        };
+        self.pos += 1;
+
+        if word.eq_ignore_ascii_case("random") {
+            return Ok(RuleOrder::Random);
+        }
+
+        let Some(field) = RuleField::from_string(&word) else {
+            self.pos -= 1;
+            return self.error(format!("Unknown field: {}", word));
+        };
+
+        let descending = if self.keyword("desc") {
+            true
+        } else {
+            self.keyword("asc");
+            false
+        };
+
+        Ok(RuleOrder::Field { field, descending })
+    }
+
+    fn parse_or(&mut self) -> Result<RuleExpr, RuleParseError> {
+        let mut rules = vec![self.parse_and()?];
+        while self.keyword("or") {
+            rules.push(self.parse_and()?);
+        }
+        Ok(if rules.len() == 1 { rules.remove(0) } else { RuleExpr::Or(rules) })
+    }
+
+    fn parse_and(&mut self) -> Result<RuleExpr, RuleParseError> {
+        let mut rules = vec![self.parse_not()?];
+        while self.keyword("and") {
+            rules.push(self.parse_not()?);
+        }
+        Ok(if rules.len() == 1 { rules.remove(0) } else { RuleExpr::And(rules) })
+    }
+
+    fn parse_not(&mut self) -> Result<RuleExpr, RuleParseError> {
+        if self.keyword("not") {
+            return Ok(RuleExpr::Not(Box::new(self.parse_not()?)));
+        }
+
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let rule = self.parse_or()?;
+            if self.next() != Some(Token::Close) {
+                self.pos -= 1;
+                return self.error("Expected )");
+            }
+            return Ok(rule);
+        }
+
+        self.parse_condition()
+    }
+
+    fn parse_condition(&mut self) -> Result<RuleExpr, RuleParseError> {
+        let field = match self.peek() {
+            Some(Token::Word(word)) => match RuleField::from_string(word) {
+                Some(field) => field,
+                None => return self.error(format!("Unknown field: {}", word)),
+            },
+            _ => return self.error("Expected a field name"),
+        };
+        self.pos += 1;
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("contains") => RuleOp::Contains,
+            _ => return self.error("Expected a comparison operator"),
+        };
+        self.pos += 1;
+
+        let value = match self.peek() {
+            Some(Token::Number(n)) => RuleValue::Number(*n),
+            Some(Token::Text(text)) | Some(Token::Word(text)) => RuleValue::Text(text.clone()),
+            _ => return self.error("Expected a value"),
+        };
+
+        if field.is_numeric() != matches!(value, RuleValue::Number(_)) {
+            return self.error(format!(
+                "{} needs a {} value",
+                field.as_str(),
+                if field.is_numeric() { "numeric" } else { "text" }
+            ));
+        }
+        if op == RuleOp::Contains && field.is_numeric() {
+            return self.error(format!("CONTAINS can't be used with {}", field.as_str()));
+        }
+        self.pos += 1;
+
+        Ok(RuleExpr::Condition(Condition { field, op, value }))
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, RuleParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = match c {
+            '(' => { i += 1; Token::Open }
+            ')' => { i += 1; Token::Close }
+            '=' => { i += 1; Token::Op(RuleOp::Eq) }
+            '~' => { i += 1; Token::Op(RuleOp::Contains) }
+            '!' if chars.get(i + 1) == Some(&'=') => { i += 2; Token::Op(RuleOp::Ne) }
+            '<' if chars.get(i + 1) == Some(&'>') => { i += 2; Token::Op(RuleOp::Ne) }
+            '<' if chars.get(i + 1) == Some(&'=') => { i += 2; Token::Op(RuleOp::Le) }
+            '>' if chars.get(i + 1) == Some(&'=') => { i += 2; Token::Op(RuleOp::Ge) }
+            '<' => { i += 1; Token::Op(RuleOp::Lt) }
+            '>' => { i += 1; Token::Op(RuleOp::Gt) }
+            '"' | '\'' => {
+                let quote = c;
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(RuleParseError { message: "Unterminated string".to_string(), position: start }),
+                        Some('\\') if i + 1 < chars.len() => {
+                            text.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&ch) if ch == quote => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&ch) => {
+                            text.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                Token::Text(text)
+            }
+            c if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) => {
+                i += 1;
+                while chars.get(i).is_some_and(|d| d.is_ascii_digit()) {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                match number.parse() {
+                    Ok(n) => Token::Number(n),
+                    Err(_) => return Err(RuleParseError { message: "Number out of range".to_string(), position: start }),
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                while chars.get(i).is_some_and(|d| d.is_alphanumeric() || *d == '_' || *d == '-') {
+                    i += 1;
+                }
+                Token::Word(chars[start..i].iter().collect())
+            }
+            _ => return Err(RuleParseError { message: format!("Unexpected character '{}'", c), position: start }),
+        };
+
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let rules = SmartRules::parse("genre = Jazz AND year < 1970 AND duration > 300 ORDER BY random LIMIT 50").unwrap();
+        assert_eq!(rules.limit, Some(50));
+        assert_eq!(rules.order, Some(RuleOrder::Random));
+        assert!(matches!(&rules.filter, Some(RuleExpr::And(conditions)) if conditions.len() == 3));
+
+        let text = "(genre = \"Hip \\\"Hop\\\"\" OR NOT artist CONTAINS davis) AND plays >= 3 ORDER BY year DESC";
+        let rules = SmartRules::parse(text).unwrap();
+        assert_eq!(SmartRules::parse(&rules.to_string()).unwrap(), rules);
+
+        let json = serde_json::to_string(&rules).unwrap();
+        assert_eq!(serde_json::from_str::<SmartRules>(&json).unwrap(), rules);
+
+        assert_eq!(SmartRules::parse("").unwrap().filter, None);
+        assert_eq!(SmartRules::parse("LIMIT 5").unwrap().limit, Some(5));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (input, position) in [
+            ("mood = happy", 0),
+            ("year = old", 7),
+            ("genre > ", 8),
+            ("genre = Jazz LIMIT 0", 19),
+            ("(genre = Jazz", 13),
+            ("genre = 'Jazz", 8),
+            ("year CONTAINS 19", 14),
+        ] {
+            let error = SmartRules::parse(input).unwrap_err();
+            assert_eq!(error.position, position, "{}: {}", input, error);
+        }
+    }
+}
@@ -1,10 +1,19 @@
 use async_trait::async_trait;
-use sqlx::{Row, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{QueryBuilder, Row, Sqlite, SqlitePool};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::db::models::{Artist, Playlist, PlaylistShare, Song, User};
-use crate::db::{Database, DbError};
+use crate::auth::api_keys::{format_scopes, parse_scopes};
+use crate::auth::permissions::{format_scope, parse_scope, DEFAULT_ROLE};
+use crate::db::models::{ApiKey, Artist, AuditAction, AuditEvent, AuditFilter, GrantKind, Library, LibraryFilter, LibraryGrant, LibraryItem, Passkey, PlayQueue, Playlist, PlaylistAction, PlaylistActivity, PlaylistEntry, PlaylistShare, RefreshToken, Role, ShareLink, SharePermission, ShareTarget, Song, SongLyrics, LyricsSource, TwoFactor, User, UserRating};
+use crate::db::paging::{Cursor, Page, PageRequest, SortField, SortOrder};
+use crate::db::playlist_edit::{EditedEntry, EntrySlot, PlaylistEdit};
+use crate::db::smart_rules::{Condition, RuleExpr, RuleField, RuleOp, RuleOrder, RuleValue, SmartRules};
+use crate::db::{escape_like, Database, DbError};
+
+const SONG_COLUMNS: &str = "id, title, artist_id, artist_name, album, duration, file_path, library_id, cover_image_path, musicbrainz_id, genre, year, start_ms, end_ms, encoder_delay, encoder_padding, sample_rate, play_count, created_at";
+const PLAYLIST_COLUMNS: &str = "id, name, owner_id, owner_username, is_public, version, is_smart, rules, created_at";
 
 pub struct SqliteDatabase {
     pool: SqlitePool,
@@ -28,6 +37,594 @@ impl SqliteDatabase {
         
         Ok(Self { pool })
     }
+    
+    /// Add a column to an existing table if an older schema lacks it
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<bool, DbError> {
+        let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to read {} schema: {}", table, e)))?;
+        
+        if columns.iter().any(|row| row.get::<String, _>("name") == column) {
+            return Ok(false);
+        }
+        
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to add {}.{}: {}", table, column, e)))?;
+        
+        Ok(true)
+    }
+    
+    /// Move entries from the old `playlist_songs` table, which had no positions,
+    /// into `playlist_entries` in the order they were added, then drop it
+    async fn migrate_playlist_songs(&self) -> Result<(), DbError> {
+        let exists: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'playlist_songs'"
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        if exists == 0 {
+            return Ok(());
+        }
+        
+        let mut tx = self.pool.begin()
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+        
+        sqlx::query(
+            r#"
+            INSERT INTO playlist_entries (id, playlist_id, song_id, position, added_at)
+            SELECT lower(hex(randomblob(16))), playlist_id, song_id,
+                ROW_NUMBER() OVER (PARTITION BY playlist_id ORDER BY CAST(added_at AS INTEGER), song_id) - 1,
+                added_at
+            FROM playlist_songs
+            WHERE playlist_id IN (SELECT id FROM playlists) AND song_id IN (SELECT id FROM songs)
+            "#
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to migrate playlist songs: {}", e)))?;
+        
+        sqlx::query("DROP TABLE playlist_songs")
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to drop playlist_songs table: {}", e)))?;
+        
+        tx.commit()
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        
+        tracing::info!("Migrated playlist songs to ordered playlist entries");
+        Ok(())
+    }
+}
+
+/// Read a unix timestamp. Timestamps are bound as text into TEXT columns,
+/// so they come back as text and have to be parsed.
+fn timestamp_from_row(row: &SqliteRow, column: &str) -> Result<OffsetDateTime, DbError> {
+    let timestamp = match row.try_get::<i64, _>(column) {
+        Ok(timestamp) => timestamp,
+        Err(_) => row.try_get::<String, _>(column)
+            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?
+            .parse::<i64>()
+            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?,
+    };
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))
+}
+
+fn user_from_row(row: &SqliteRow) -> Result<User, DbError> {
+    Ok(User {
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        password_hash: row.get("password_hash"),
+        role: row.get("role"),
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+fn role_from_row(row: &SqliteRow) -> Role {
+    Role {
+        name: row.get("name"),
+        description: row.get("description"),
+        permissions: parse_scope(&row.get::<String, _>("permissions")),
+        built_in: row.get::<i32, _>("built_in") != 0,
+    }
+}
+
+fn library_from_row(row: &SqliteRow) -> Result<Library, DbError> {
+    Ok(Library {
+        id: row.get("id"),
+        name: row.get("name"),
+        root_path: row.get("root_path"),
+        scan_on_startup: row.get::<i32, _>("scan_on_startup") != 0,
+        recursive: row.get::<i32, _>("recursive") != 0,
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+fn grant_from_row(row: &SqliteRow) -> Result<LibraryGrant, DbError> {
+    let kind: String = row.get("kind");
+    Ok(LibraryGrant {
+        library_id: row.get("library_id"),
+        kind: GrantKind::from_string(&kind)
+            .ok_or_else(|| DbError::DatabaseError(format!("Invalid grant kind: {}", kind)))?,
+        subject: row.get("subject"),
+    })
+}
+
+fn artist_from_row(row: &SqliteRow) -> Result<Artist, DbError> {
+    Ok(Artist {
+        id: row.get("id"),
+        name: row.get("name"),
+        cover_image_path: row.get("cover_image_path"),
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+fn song_from_row(row: &SqliteRow) -> Result<Song, DbError> {
+    Ok(Song {
+        id: row.get("id"),
+        title: row.get("title"),
+        artist_id: row.get("artist_id"),
+        artist_name: row.get("artist_name"),
+        album: row.get("album"),
+        duration: row.get("duration"),
+        file_path: row.get("file_path"),
+        library_id: row.get("library_id"),
+        cover_image_path: row.get("cover_image_path"),
+        musicbrainz_id: row.get("musicbrainz_id"),
+        genre: row.get("genre"),
+        year: row.get("year"),
+        start_ms: row.get("start_ms"),
+        end_ms: row.get("end_ms"),
+        encoder_delay: row.get("encoder_delay"),
+        encoder_padding: row.get("encoder_padding"),
+        sample_rate: row.get("sample_rate"),
+        play_count: row.get("play_count"),
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+const SHARE_COLUMNS: &str = "id, playlist_id, shared_with_user_id, shared_by_user_id, permission, shared_at";
+
+/// Build a PlaylistShare from a row selecting SHARE_COLUMNS, or with the id aliased as `share_id`
+fn share_from_row(row: &SqliteRow, id_column: &str) -> Result<PlaylistShare, DbError> {
+    Ok(PlaylistShare {
+        id: row.get(id_column),
+        playlist_id: row.get("playlist_id"),
+        shared_with_user_id: row.get("shared_with_user_id"),
+        shared_by_user_id: row.get("shared_by_user_id"),
+        permission: SharePermission::from_string(row.get("permission")).unwrap_or_default(),
+        shared_at: timestamp_from_row(row, "shared_at")?,
+    })
+}
+
+fn activity_from_row(row: &SqliteRow) -> Result<PlaylistActivity, DbError> {
+    let action: String = row.get("action");
+    
+    Ok(PlaylistActivity {
+        id: row.get("id"),
+        playlist_id: row.get("playlist_id"),
+        user_id: row.get("user_id"),
+        username: row.get("username"),
+        action: PlaylistAction::from_string(&action)
+            .ok_or_else(|| DbError::DatabaseError(format!("Unknown playlist action: {}", action)))?,
+        song_id: row.get("song_id"),
+        song_title: row.get("song_title"),
+        artist_name: row.get("artist_name"),
+        position: row.get("position"),
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+const REFRESH_TOKEN_COLUMNS: &str = "token_hash, family_id, user_id, expires_at, used_at, created_at";
+
+fn refresh_token_from_row(row: &SqliteRow) -> Result<RefreshToken, DbError> {
+    let used_at = match row.get::<Option<String>, _>("used_at") {
+        Some(_) => Some(timestamp_from_row(row, "used_at")?),
+        None => None,
+    };
+    
+    Ok(RefreshToken {
+        token_hash: row.get("token_hash"),
+        family_id: row.get("family_id"),
+        user_id: row.get("user_id"),
+        expires_at: timestamp_from_row(row, "expires_at")?,
+        used_at,
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+const PASSKEY_COLUMNS: &str = "id, user_id, name, public_key, algorithm, sign_count, created_at, last_used_at";
+
+fn passkey_from_row(row: &SqliteRow) -> Result<Passkey, DbError> {
+    let last_used_at = match row.get::<Option<String>, _>("last_used_at") {
+        Some(_) => Some(timestamp_from_row(row, "last_used_at")?),
+        None => None,
+    };
+    
+    Ok(Passkey {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        name: row.get("name"),
+        public_key: row.get("public_key"),
+        algorithm: row.get("algorithm"),
+        sign_count: row.get("sign_count"),
+        created_at: timestamp_from_row(row, "created_at")?,
+        last_used_at,
+    })
+}
+
+const API_KEY_COLUMNS: &str = "id, user_id, name, prefix, key_hash, scopes, created_at, last_used_at";
+
+fn api_key_from_row(row: &SqliteRow) -> Result<ApiKey, DbError> {
+    let last_used_at = match row.get::<Option<String>, _>("last_used_at") {
+        Some(_) => Some(timestamp_from_row(row, "last_used_at")?),
+        None => None,
+    };
+    
+    Ok(ApiKey {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        name: row.get("name"),
+        prefix: row.get("prefix"),
+        key_hash: row.get("key_hash"),
+        scopes: parse_scopes(&row.get::<String, _>("scopes")),
+        created_at: timestamp_from_row(row, "created_at")?,
+        last_used_at,
+    })
+}
+
+const AUDIT_COLUMNS: &str = "id, actor_id, actor, action, target, before_value, after_value, ip, created_at";
+
+fn audit_event_from_row(row: &SqliteRow) -> Result<AuditEvent, DbError> {
+    let action: String = row.get("action");
+    let json = |column: &str| -> Result<Option<serde_json::Value>, DbError> {
+        row.get::<Option<String>, _>(column)
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(|e| DbError::DatabaseError(format!("Invalid audit value: {}", e)))
+    };
+    
+    Ok(AuditEvent {
+        id: row.get("id"),
+        actor_id: row.get("actor_id"),
+        actor: row.get("actor"),
+        action: AuditAction::from_string(&action)
+            .ok_or_else(|| DbError::DatabaseError(format!("Unknown audit action: {}", action)))?,
+        target: row.get("target"),
+        before: json("before_value")?,
+        after: json("after_value")?,
+        ip: row.get("ip"),
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+const SHARE_LINK_COLUMNS: &str = "id, owner_id, target_type, target_id, album, password_hash, expires_at, created_at";
+
+fn share_link_from_row(row: &SqliteRow) -> Result<ShareLink, DbError> {
+    let target_type: String = row.get("target_type");
+    let target = ShareTarget::from_parts(&target_type, row.get("target_id"), row.get("album"))
+        .ok_or_else(|| DbError::DatabaseError(format!("Invalid share link target: {}", target_type)))?;
+    let expires_at = match row.get::<Option<String>, _>("expires_at") {
+        Some(_) => Some(timestamp_from_row(row, "expires_at")?),
+        None => None,
+    };
+    
+    Ok(ShareLink {
+        id: row.get("id"),
+        owner_id: row.get("owner_id"),
+        target,
+        password_hash: row.get("password_hash"),
+        expires_at,
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+const RATING_COLUMNS: &str = "user_id, item_type, item_id, album, favorite, rating, updated_at";
+
+/// Albums are stored with their name, other items with an empty one
+fn rating_from_row(row: &SqliteRow) -> Result<UserRating, DbError> {
+    let item_type: String = row.get("item_type");
+    let album: String = row.get("album");
+    let item = LibraryItem::from_parts(&item_type, row.get("item_id"), Some(album).filter(|album| !album.is_empty()))
+        .ok_or_else(|| DbError::DatabaseError(format!("Invalid rated item: {}", item_type)))?;
+    
+    Ok(UserRating {
+        user_id: row.get("user_id"),
+        item,
+        favorite: row.get::<i32, _>("favorite") != 0,
+        rating: row.get::<Option<i32>, _>("rating").map(|rating| rating as u8),
+        updated_at: timestamp_from_row(row, "updated_at")?,
+    })
+}
+
+const PLAY_QUEUE_COLUMNS: &str = "user_id, song_ids, current_index, position_ms, device, version, updated_at";
+
+/// The song ids of a play queue are stored as a JSON array
+fn play_queue_from_row(row: &SqliteRow) -> Result<PlayQueue, DbError> {
+    let song_ids = serde_json::from_str(&row.get::<String, _>("song_ids"))
+        .map_err(|e| DbError::DatabaseError(format!("Invalid play queue: {}", e)))?;
+    
+    Ok(PlayQueue {
+        user_id: row.get("user_id"),
+        song_ids,
+        current_index: row.get::<i64, _>("current_index") as usize,
+        position_ms: row.get("position_ms"),
+        device: row.get("device"),
+        version: row.get("version"),
+        updated_at: timestamp_from_row(row, "updated_at")?,
+    })
+}
+
+fn lyrics_from_row(row: &SqliteRow) -> Result<SongLyrics, DbError> {
+    let source = row.get::<String, _>("source");
+    
+    Ok(SongLyrics {
+        song_id: row.get("song_id"),
+        text: row.get("text"),
+        synced: row.get::<i64, _>("synced") != 0,
+        source: LyricsSource::from_string(&source)
+            .ok_or_else(|| DbError::DatabaseError(format!("Invalid lyrics source: {}", source)))?,
+        updated_at: timestamp_from_row(row, "updated_at")?,
+    })
+}
+
+/// Smart playlist rules are stored as JSON
+fn rules_from_row(row: &SqliteRow) -> Result<Option<SmartRules>, DbError> {
+    row.get::<Option<String>, _>("rules")
+        .map(|rules| serde_json::from_str(&rules)
+            .map_err(|e| DbError::DatabaseError(format!("Invalid smart playlist rules: {}", e))))
+        .transpose()
+}
+
+fn rules_to_json(rules: &SmartRules) -> Result<String, DbError> {
+    serde_json::to_string(rules)
+        .map_err(|e| DbError::DatabaseError(format!("Failed to encode smart playlist rules: {}", e)))
+}
+
+fn playlist_from_row(row: &SqliteRow) -> Result<Playlist, DbError> {
+    Ok(Playlist {
+        id: row.get("id"),
+        name: row.get("name"),
+        owner_id: row.get("owner_id"),
+        owner_username: row.get("owner_username"),
+        is_public: row.get::<i32, _>("is_public") != 0,
+        version: row.get("version"),
+        is_smart: row.get::<i32, _>("is_smart") != 0,
+        rules: rules_from_row(row)?,
+        created_at: timestamp_from_row(row, "created_at")?,
+    })
+}
+
+/// Column expression to order by for a sort field.
+/// `created_at` is stored as text so it is cast for numeric ordering.
+fn sort_column(sort: SortField, name_column: &'static str) -> &'static str {
+    match sort {
+        SortField::Title => "title",
+        SortField::Artist => "artist_name",
+        SortField::Name => name_column,
+        SortField::Added => "CAST(created_at AS INTEGER)",
+        SortField::PlayCount => "play_count",
+    }
+}
+
+/// Append the keyset condition, ordering and limit for a paged listing.
+/// Rows are ordered by `(column, id)` so ties on the sort key stay stable.
+fn push_page_clause(builder: &mut QueryBuilder<'_, Sqlite>, column: &str, page: &PageRequest, has_where: bool) {
+    if let Some(cursor) = &page.after {
+        let op = match page.order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        
+        builder.push(if has_where { " AND (" } else { " WHERE (" });
+        builder.push(format!("{} {} ", column, op));
+        push_cursor_key(builder, cursor);
+        builder.push(format!(" OR ({} = ", column));
+        push_cursor_key(builder, cursor);
+        builder.push(format!(" AND id {} ", op));
+        builder.push_bind(cursor.id.clone());
+        builder.push("))");
+    }
+    
+    let direction = page.order.as_sql();
+    builder.push(format!(" ORDER BY {} {}, id {} LIMIT ", column, direction, direction));
+    builder.push_bind((page.limit + 1) as i64);
+}
+
+/// Append the conditions of an audit log filter. Returns whether the query has a WHERE clause.
+fn push_audit_filter(builder: &mut QueryBuilder<'_, Sqlite>, filter: &AuditFilter) -> bool {
+    let mut has_where = false;
+    let mut next = |builder: &mut QueryBuilder<'_, Sqlite>| {
+        builder.push(if has_where { " AND " } else { " WHERE " });
+        has_where = true;
+    };
+    
+    if let Some(actor) = &filter.actor {
+        next(builder);
+        builder.push("actor = ").push_bind(actor.clone());
+    }
+    if let Some(action) = filter.action {
+        next(builder);
+        builder.push("action = ").push_bind(action.as_str());
+    }
+    if let Some(target) = &filter.target {
+        next(builder);
+        builder.push("target = ").push_bind(target.clone());
+    }
+    if let Some(since) = filter.since {
+        next(builder);
+        builder.push("CAST(created_at AS INTEGER) >= ").push_bind(since.unix_timestamp());
+    }
+    if let Some(until) = filter.until {
+        next(builder);
+        builder.push("CAST(created_at AS INTEGER) < ").push_bind(until.unix_timestamp());
+    }
+    
+    has_where
+}
+
+/// Append a condition keeping only songs in the given libraries, after a
+/// WHERE clause when `has_where` is set. Returns whether the query has one now.
+fn push_library_clause(builder: &mut QueryBuilder<'_, Sqlite>, column: &str, libraries: &LibraryFilter, has_where: bool) -> bool {
+    let LibraryFilter::Only(ids) = libraries else {
+        return has_where;
+    };
+    
+    builder.push(if has_where { " AND " } else { " WHERE " });
+    if ids.is_empty() {
+        builder.push("0 = 1");
+        return true;
+    }
+    
+    builder.push(format!("{} IN (", column));
+    let mut separated = builder.separated(", ");
+    for id in ids {
+        separated.push_bind(id.clone());
+    }
+    separated.push_unseparated(")");
+    true
+}
+
+/// Append a condition keeping only artists with songs in the given libraries
+fn push_artist_library_clause(builder: &mut QueryBuilder<'_, Sqlite>, libraries: &LibraryFilter) -> bool {
+    if *libraries == LibraryFilter::All {
+        return false;
+    }
+    
+    builder.push(" WHERE EXISTS (SELECT 1 FROM songs WHERE songs.artist_id = artists.id");
+    push_library_clause(builder, "songs.library_id", libraries, true);
+    builder.push(")");
+    true
+}
+
+/// Column a smart playlist rule tests. `created_at` is cast as for sorting.
+fn rule_column(field: RuleField) -> &'static str {
+    match field {
+        RuleField::Title => "title",
+        RuleField::Artist => "artist_name",
+        RuleField::Album => "album",
+        RuleField::Genre => "genre",
+        RuleField::Year => "year",
+        RuleField::Duration => "duration",
+        RuleField::PlayCount => "play_count",
+        RuleField::Added => "CAST(created_at AS INTEGER)",
+    }
+}
+
+/// Append a smart playlist filter. Every condition is true or false, never
+/// NULL, so NOT of a condition on a missing value still matches.
+fn push_rule_expr(builder: &mut QueryBuilder<'_, Sqlite>, expr: &RuleExpr) {
+    let (rules, keyword, empty) = match expr {
+        RuleExpr::Condition(condition) => return push_rule_condition(builder, condition),
+        RuleExpr::Not(rule) => {
+            builder.push("NOT (");
+            push_rule_expr(builder, rule);
+            builder.push(")");
+            return;
+        }
+        RuleExpr::And(rules) => (rules, " AND ", "1 = 1"),
+        RuleExpr::Or(rules) => (rules, " OR ", "1 = 0"),
+    };
+    
+    if rules.is_empty() {
+        builder.push(empty);
+        return;
+    }
+    builder.push("(");
+    for (i, rule) in rules.iter().enumerate() {
+        if i > 0 {
+            builder.push(keyword);
+        }
+        push_rule_expr(builder, rule);
+    }
+    builder.push(")");
+}
+
+/// Text compares case-insensitively, and a song without a value only matches `!=`.
+/// SQLite's `LOWER` only folds ASCII letters, as with `LIKE` in song search.
+fn push_rule_condition(builder: &mut QueryBuilder<'_, Sqlite>, condition: &Condition) {
+    let column = rule_column(condition.field);
+    let numeric = condition.field.is_numeric();
+    let subject = if numeric { column.to_string() } else { format!("LOWER({})", column) };
+    
+    match (&condition.value, condition.op) {
+        // A value of the wrong type only compares unequal to a missing value
+        (value, op) if numeric != matches!(value, RuleValue::Number(_)) => {
+            builder.push(if op == RuleOp::Ne { format!("{} IS NULL", column) } else { "1 = 0".to_string() });
+        }
+        (RuleValue::Text(text), RuleOp::Contains) => {
+            builder.push(format!("({} IS NOT NULL AND {} LIKE LOWER(", column, subject));
+            builder.push_bind(format!("%{}%", escape_like(text)));
+            builder.push(") ESCAPE '\\')");
+        }
+        (_, RuleOp::Contains) => {
+            builder.push("1 = 0");
+        }
+        (value, op) => {
+            let comparison = match op {
+                RuleOp::Eq => "=",
+                RuleOp::Ne => "<>",
+                RuleOp::Lt => "<",
+                RuleOp::Le => "<=",
+                RuleOp::Gt => ">",
+                RuleOp::Ge => ">=",
+                RuleOp::Contains => unreachable!(),
+            };
+            builder.push(if op == RuleOp::Ne {
+                format!("({} IS NULL OR {} {} ", column, subject, comparison)
+            } else {
+                format!("({} IS NOT NULL AND {} {} ", column, subject, comparison)
+            });
+            match value {
+                RuleValue::Number(n) => builder.push_bind(*n),
+                RuleValue::Text(text) => builder.push("LOWER(").push_bind(text.clone()).push(")"),
+            };
+            builder.push(")");
+        }
+    }
+}
+
+/// Append the ordering and limit of smart playlist rules. Songs without a
+/// value sort last either way, and ties keep the order songs were added in.
+fn push_rule_order(builder: &mut QueryBuilder<'_, Sqlite>, rules: &SmartRules) {
+    builder.push(" ORDER BY ");
+    match &rules.order {
+        Some(RuleOrder::Random) => {
+            builder.push("RANDOM(), ");
+        }
+        Some(RuleOrder::Field { field, descending }) => {
+            let column = rule_column(*field);
+            let direction = if *descending { "DESC" } else { "ASC" };
+            if field.is_numeric() {
+                builder.push(format!("{} IS NULL, {} {}, ", column, column, direction));
+            } else {
+                builder.push(format!("{} IS NULL, LOWER({}) {}, ", column, column, direction));
+            }
+        }
+        None => {}
+    }
+    builder.push("CAST(created_at AS INTEGER) ASC, id ASC");
+    
+    if let Some(limit) = rules.limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+}
+
+fn push_cursor_key(builder: &mut QueryBuilder<'_, Sqlite>, cursor: &Cursor) {
+    if cursor.sort.is_numeric() {
+        builder.push_bind(cursor.numeric_key());
+    } else {
+        builder.push_bind(cursor.key.clone());
+    }
 }
 
 #[async_trait]
@@ -47,13 +644,13 @@ impl Database for SqliteDatabase {
         let created_at_str = created_at.unix_timestamp().to_string();
         
         sqlx::query(
-            "INSERT INTO users (id, username, email, password_hash, is_admin, created_at) VALUES (?, ?, ?, ?, ?, ?)"
+            "INSERT INTO users (id, username, email, password_hash, role, created_at) VALUES (?, ?, ?, ?, ?, ?)"
         )
         .bind(&id)
         .bind(username)
         .bind(email)
         .bind(password_hash)
-        .bind(0) // is_admin = false
+        .bind(DEFAULT_ROLE)
         .bind(&created_at_str)
         .execute(&self.pool)
         .await
@@ -64,14 +661,14 @@ impl Database for SqliteDatabase {
             username: username.to_string(),
             email: email.to_string(),
             password_hash: password_hash.to_string(),
-            is_admin: false,
+            role: DEFAULT_ROLE.to_string(),
             created_at,
         })
     }
 
     async fn get_user_by_username(&self, username: &str) -> Result<User, DbError> {
         let row = sqlx::query(
-            "SELECT id, username, email, password_hash, is_admin, created_at FROM users WHERE username = ?"
+            "SELECT id, username, email, password_hash, role, created_at FROM users WHERE username = ?"
         )
         .bind(username)
         .fetch_optional(&self.pool)
@@ -79,23 +676,12 @@ impl Database for SqliteDatabase {
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
         .ok_or(DbError::UserNotFound)?;
         
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-        
-        Ok(User {
-            id: row.get("id"),
-            username: row.get("username"),
-            email: row.get("email"),
-            password_hash: row.get("password_hash"),
-            is_admin: row.get::<i32, _>("is_admin") != 0,
-            created_at,
-        })
+        user_from_row(&row)
     }
 
     async fn get_user_by_email(&self, email: &str) -> Result<User, DbError> {
         let row = sqlx::query(
-            "SELECT id, username, email, password_hash, is_admin, created_at FROM users WHERE email = ?"
+            "SELECT id, username, email, password_hash, role, created_at FROM users WHERE email = ?"
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -103,23 +689,12 @@ impl Database for SqliteDatabase {
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
         .ok_or(DbError::UserNotFound)?;
         
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-        
-        Ok(User {
-            id: row.get("id"),
-            username: row.get("username"),
-            email: row.get("email"),
-            password_hash: row.get("password_hash"),
-            is_admin: row.get::<i32, _>("is_admin") != 0,
-            created_at,
-        })
+        user_from_row(&row)
     }
 
     async fn get_user_by_id(&self, id: &str) -> Result<User, DbError> {
         let row = sqlx::query(
-            "SELECT id, username, email, password_hash, is_admin, created_at FROM users WHERE id = ?"
+            "SELECT id, username, email, password_hash, role, created_at FROM users WHERE id = ?"
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -127,30 +702,21 @@ impl Database for SqliteDatabase {
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
         .ok_or(DbError::UserNotFound)?;
         
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-        
-        Ok(User {
-            id: row.get("id"),
-            username: row.get("username"),
-            email: row.get("email"),
-            password_hash: row.get("password_hash"),
-            is_admin: row.get::<i32, _>("is_admin") != 0,
-            created_at,
-        })
+        user_from_row(&row)
     }
 
-    async fn update_user_admin_status(&self, id: &str, is_admin: bool) -> Result<(), DbError> {
-        let is_admin_int = if is_admin { 1 } else { 0 };
-        
-        sqlx::query("UPDATE users SET is_admin = ? WHERE id = ?")
-            .bind(is_admin_int)
+    async fn update_user_role(&self, id: &str, role: &str) -> Result<(), DbError> {
+        let result = sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+            .bind(role)
             .bind(id)
             .execute(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to update user: {}", e)))?;
         
+        if result.rows_affected() == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
         Ok(())
     }
 
@@ -183,7 +749,7 @@ impl Database for SqliteDatabase {
                 username TEXT NOT NULL UNIQUE,
                 email TEXT NOT NULL UNIQUE,
                 password_hash TEXT NOT NULL,
-                is_admin INTEGER NOT NULL DEFAULT 0,
+                role TEXT NOT NULL DEFAULT 'listener',
                 created_at TEXT NOT NULL
             )
             "#
@@ -192,264 +758,1201 @@ impl Database for SqliteDatabase {
         .await
         .map_err(|e| DbError::DatabaseError(format!("Failed to create users table: {}", e)))?;
         
-        // Create artists table
+        // Users from before roles were admins or not
+        if self.add_column_if_missing("users", "role", "TEXT NOT NULL DEFAULT 'listener'").await? {
+            sqlx::query("UPDATE users SET role = 'admin' WHERE is_admin != 0")
+                .execute(&self.pool)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to migrate admins: {}", e)))?;
+        }
+        
+        // Create roles table
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS artists (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE,
-                cover_image_path TEXT,
-                created_at TEXT NOT NULL
+            CREATE TABLE IF NOT EXISTS roles (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL DEFAULT '',
+                permissions TEXT NOT NULL,
+                built_in INTEGER NOT NULL DEFAULT 0
             )
             "#
         )
         .execute(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Failed to create artists table: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create roles table: {}", e)))?;
         
-        // Create songs table
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS songs (
-                id TEXT PRIMARY KEY,
-                title TEXT NOT NULL,
-                artist_id TEXT NOT NULL,
-                artist_name TEXT NOT NULL,
-                album TEXT,
-                duration INTEGER,
-                file_path TEXT NOT NULL,
-                cover_image_path TEXT,
+            CREATE TABLE IF NOT EXISTS user_identities (
+                issuer TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                user_id TEXT NOT NULL,
                 created_at TEXT NOT NULL,
-                FOREIGN KEY (artist_id) REFERENCES artists(id) ON DELETE CASCADE
+                PRIMARY KEY (issuer, subject),
+                UNIQUE (issuer, user_id),
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
             )
             "#
         )
         .execute(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Failed to create songs table: {}", e)))?;
-        
-        // Create indices for faster lookups
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_username ON users(username)")
-            .execute(&self.pool)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create user_identities table: {}", e)))?;
         
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_email ON users(email)")
-            .execute(&self.pool)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
-        
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_artists_name ON artists(name)")
-            .execute(&self.pool)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS refresh_tokens (
+                token_hash TEXT PRIMARY KEY,
+                family_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                used_at TEXT,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create refresh_tokens table: {}", e)))?;
         
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_artist_id ON songs(artist_id)")
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family_id ON refresh_tokens(family_id)")
             .execute(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
         
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_title ON songs(title)")
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_refresh_tokens_user_id ON refresh_tokens(user_id)")
             .execute(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
         
-        // Create playlists table
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS playlists (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                owner_id TEXT NOT NULL,
-                owner_username TEXT NOT NULL,
-                is_public INTEGER NOT NULL DEFAULT 0,
+            CREATE TABLE IF NOT EXISTS two_factor (
+                user_id TEXT PRIMARY KEY,
+                secret TEXT NOT NULL,
+                enabled INTEGER NOT NULL DEFAULT 0,
+                last_step INTEGER NOT NULL DEFAULT 0,
                 created_at TEXT NOT NULL,
-                FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
             )
             "#
         )
         .execute(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlists table: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create two_factor table: {}", e)))?;
         
-        // Create playlist_songs table
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS playlist_songs (
-                playlist_id TEXT NOT NULL,
-                song_id TEXT NOT NULL,
-                added_at TEXT NOT NULL,
-                PRIMARY KEY (playlist_id, song_id),
-                FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
-                FOREIGN KEY (song_id) REFERENCES songs(id) ON DELETE CASCADE
+            CREATE TABLE IF NOT EXISTS recovery_codes (
+                user_id TEXT NOT NULL,
+                code_hash TEXT NOT NULL,
+                PRIMARY KEY (user_id, code_hash),
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
             )
             "#
         )
         .execute(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist_songs table: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create recovery_codes table: {}", e)))?;
         
-        // Create playlist_shares table
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS playlist_shares (
+            CREATE TABLE IF NOT EXISTS passkeys (
                 id TEXT PRIMARY KEY,
-                playlist_id TEXT NOT NULL,
-                shared_with_user_id TEXT NOT NULL,
-                shared_by_user_id TEXT NOT NULL,
-                shared_at TEXT NOT NULL,
-                FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
-                FOREIGN KEY (shared_with_user_id) REFERENCES users(id) ON DELETE CASCADE,
-                FOREIGN KEY (shared_by_user_id) REFERENCES users(id) ON DELETE CASCADE,
-                UNIQUE(playlist_id, shared_with_user_id)
+                user_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                algorithm INTEGER NOT NULL,
+                sign_count BIGINT NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL,
+                last_used_at TEXT,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
             )
             "#
         )
         .execute(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist_shares table: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create passkeys table: {}", e)))?;
         
-        // Create indices for playlists
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlists_owner_id ON playlists(owner_id)")
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys(user_id)")
             .execute(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
         
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlists_is_public ON playlists(is_public)")
-            .execute(&self.pool)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS api_keys (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                prefix TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                scopes TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                last_used_at TEXT,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create api_keys table: {}", e)))?;
         
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlist_shares_shared_with ON playlist_shares(shared_with_user_id)")
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_api_keys_user_id ON api_keys(user_id)")
             .execute(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
         
-        Ok(())
+        // No foreign keys, so entries outlive the accounts they mention
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id TEXT PRIMARY KEY,
+                actor_id TEXT,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target TEXT,
+                before_value TEXT,
+                after_value TEXT,
+                ip TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create audit_log table: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_audit_log_created_at ON audit_log(CAST(created_at AS INTEGER))")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        // Create artists table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS artists (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                cover_image_path TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create artists table: {}", e)))?;
+        
+        // Create songs table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS songs (
+                id TEXT PRIMARY KEY,
+                title TEXT NOT NULL,
+                artist_id TEXT NOT NULL,
+                artist_name TEXT NOT NULL,
+                album TEXT,
+                duration INTEGER,
+                file_path TEXT NOT NULL,
+                library_id TEXT NOT NULL DEFAULT 'default',
+                cover_image_path TEXT,
+                musicbrainz_id TEXT,
+                genre TEXT,
+                year INTEGER,
+                start_ms INTEGER,
+                end_ms INTEGER,
+                encoder_delay INTEGER,
+                encoder_padding INTEGER,
+                sample_rate INTEGER,
+                play_count INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (artist_id) REFERENCES artists(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create songs table: {}", e)))?;
+        
+        self.add_column_if_missing("songs", "play_count", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.add_column_if_missing("songs", "musicbrainz_id", "TEXT").await?;
+        self.add_column_if_missing("songs", "genre", "TEXT").await?;
+        self.add_column_if_missing("songs", "year", "INTEGER").await?;
+        self.add_column_if_missing("songs", "start_ms", "INTEGER").await?;
+        self.add_column_if_missing("songs", "end_ms", "INTEGER").await?;
+        self.add_column_if_missing("songs", "encoder_delay", "INTEGER").await?;
+        self.add_column_if_missing("songs", "encoder_padding", "INTEGER").await?;
+        self.add_column_if_missing("songs", "sample_rate", "INTEGER").await?;
+        // Songs from before libraries are in the default one
+        self.add_column_if_missing("songs", "library_id", "TEXT NOT NULL DEFAULT 'default'").await?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS libraries (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                root_path TEXT NOT NULL,
+                scan_on_startup INTEGER NOT NULL DEFAULT 1,
+                recursive INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create libraries table: {}", e)))?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS library_grants (
+                library_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                PRIMARY KEY (library_id, kind, subject),
+                FOREIGN KEY (library_id) REFERENCES libraries(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create library_grants table: {}", e)))?;
+        
+        // Create indices for faster lookups
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_username ON users(username)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_email ON users(email)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_artists_name ON artists(name)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_artist_id ON songs(artist_id)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_title ON songs(title)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_artist_name ON songs(artist_name)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_play_count ON songs(play_count)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_file_path ON songs(file_path)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_musicbrainz_id ON songs(musicbrainz_id)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_songs_library_id ON songs(library_id)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_library_grants_subject ON library_grants(kind, subject)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        // Create playlists table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS playlists (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                owner_username TEXT NOT NULL,
+                is_public INTEGER NOT NULL DEFAULT 0,
+                version INTEGER NOT NULL DEFAULT 0,
+                is_smart INTEGER NOT NULL DEFAULT 0,
+                rules TEXT,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlists table: {}", e)))?;
+        
+        self.add_column_if_missing("playlists", "version", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.add_column_if_missing("playlists", "is_smart", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.add_column_if_missing("playlists", "rules", "TEXT").await?;
+        
+        // Create playlist_entries table. Entries are ordered by position
+        // and the same song may appear more than once.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS playlist_entries (
+                id TEXT PRIMARY KEY,
+                playlist_id TEXT NOT NULL,
+                song_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                added_at TEXT NOT NULL,
+                FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
+                FOREIGN KEY (song_id) REFERENCES songs(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist_entries table: {}", e)))?;
+        
+        self.migrate_playlist_songs().await?;
+        
+        // Create playlist_shares table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS playlist_shares (
+                id TEXT PRIMARY KEY,
+                playlist_id TEXT NOT NULL,
+                shared_with_user_id TEXT NOT NULL,
+                shared_by_user_id TEXT NOT NULL,
+                permission TEXT NOT NULL DEFAULT 'view',
+                shared_at TEXT NOT NULL,
+                FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
+                FOREIGN KEY (shared_with_user_id) REFERENCES users(id) ON DELETE CASCADE,
+                FOREIGN KEY (shared_by_user_id) REFERENCES users(id) ON DELETE CASCADE,
+                UNIQUE(playlist_id, shared_with_user_id)
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist_shares table: {}", e)))?;
+        
+        self.add_column_if_missing("playlist_shares", "permission", "TEXT NOT NULL DEFAULT 'view'").await?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS playlist_activity (
+                id TEXT PRIMARY KEY,
+                playlist_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                username TEXT NOT NULL,
+                action TEXT NOT NULL,
+                song_id TEXT,
+                song_title TEXT,
+                artist_name TEXT,
+                position INTEGER,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create playlist_activity table: {}", e)))?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS share_links (
+                id TEXT PRIMARY KEY,
+                owner_id TEXT NOT NULL,
+                target_type TEXT NOT NULL,
+                target_id TEXT NOT NULL,
+                album TEXT,
+                password_hash TEXT,
+                expires_at TEXT,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create share_links table: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_share_links_owner_id ON share_links(owner_id)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS user_ratings (
+                user_id TEXT NOT NULL,
+                item_type TEXT NOT NULL,
+                item_id TEXT NOT NULL,
+                album TEXT NOT NULL DEFAULT '',
+                favorite INTEGER NOT NULL DEFAULT 0,
+                rating INTEGER,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (user_id, item_type, item_id, album),
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create user_ratings table: {}", e)))?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS play_queues (
+                user_id TEXT PRIMARY KEY,
+                song_ids TEXT NOT NULL,
+                current_index INTEGER NOT NULL DEFAULT 0,
+                position_ms INTEGER NOT NULL DEFAULT 0,
+                device TEXT,
+                version INTEGER NOT NULL DEFAULT 1,
+                updated_at TEXT NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create play_queues table: {}", e)))?;
+        
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS song_lyrics (
+                song_id TEXT PRIMARY KEY,
+                text TEXT NOT NULL,
+                synced INTEGER NOT NULL DEFAULT 0,
+                source TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                FOREIGN KEY (song_id) REFERENCES songs(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create song_lyrics table: {}", e)))?;
+        
+        // Create indices for playlists
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlists_owner_id ON playlists(owner_id)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlists_is_public ON playlists(is_public)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlist_shares_shared_with ON playlist_shares(shared_with_user_id)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlist_entries_position ON playlist_entries(playlist_id, position)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_playlist_activity_playlist ON playlist_activity(playlist_id, created_at)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to create index: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_all_users(&self, page: &PageRequest) -> Result<Page<User>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT id, username, email, password_hash, role, created_at FROM users"
+        );
+        push_page_clause(&mut builder, sort_column(page.sort, "username"), page, false);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let users = rows.iter().map(user_from_row).collect::<Result<Vec<_>, _>>()?;
+        let total = self.get_total_users().await?;
+        
+        Ok(Page::from_rows(users, page, total))
+    }
+    
+    async fn update_user_email(&self, username: &str, new_email: &str) -> Result<(), DbError> {
+        // Check if new email already exists for another user
+        if self.email_exists(new_email).await? {
+            let existing_user = self.get_user_by_email(new_email).await?;
+            if existing_user.username != username {
+                return Err(DbError::UserAlreadyExists);
+            }
+        }
+        
+        let result = sqlx::query("UPDATE users SET email = ? WHERE username = ?")
+            .bind(new_email)
+            .bind(username)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update user: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_username(&self, user_id: &str, new_username: &str) -> Result<(), DbError> {
+        // Check if new username already exists
+        if self.username_exists(new_username).await? {
+            return Err(DbError::UserAlreadyExists);
+        }
+        
+        let result = sqlx::query("UPDATE users SET username = ? WHERE id = ?")
+            .bind(new_username)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update username: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_user_password(&self, user_id: &str, new_password_hash: &str) -> Result<(), DbError> {
+        let result = sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+            .bind(new_password_hash)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update password: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn delete_user_by_username(&self, username: &str) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM library_grants WHERE kind = 'user' AND subject IN (SELECT id FROM users WHERE username = ?)")
+            .bind(username)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
+        
+        let result = sqlx::query("DELETE FROM users WHERE username = ?")
+            .bind(username)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn delete_user_by_id(&self, user_id: &str) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM library_grants WHERE kind = 'user' AND subject = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
+        
+        let result = sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::UserNotFound);
+        }
+        
+        Ok(())
     }
     
-    async fn get_all_users(&self, offset: usize, limit: usize) -> Result<Vec<User>, DbError> {
-        let rows = sqlx::query(
-            "SELECT id, username, email, password_hash, is_admin, created_at FROM users ORDER BY created_at DESC LIMIT ? OFFSET ?"
+    async fn get_total_users(&self) -> Result<usize, DbError> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(count as usize)
+    }
+    
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, DbError> {
+        let user_id: String = sqlx::query_scalar("SELECT user_id FROM user_identities WHERE issuer = ? AND subject = ?")
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::UserNotFound)?;
+        
+        self.get_user_by_id(&user_id).await
+    }
+    
+    async fn link_identity(&self, issuer: &str, subject: &str, user_id: &str) -> Result<(), DbError> {
+        let linked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_identities WHERE issuer = ? AND (subject = ? OR user_id = ?)")
+            .bind(issuer)
+            .bind(subject)
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        if linked > 0 {
+            return Err(DbError::IdentityAlreadyLinked);
+        }
+        
+        sqlx::query("INSERT INTO user_identities (issuer, subject, user_id, created_at) VALUES (?, ?, ?, ?)")
+            .bind(issuer)
+            .bind(subject)
+            .bind(user_id)
+            .bind(OffsetDateTime::now_utc().unix_timestamp().to_string())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to link identity: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn unlink_identity(&self, issuer: &str, user_id: &str) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM user_identities WHERE issuer = ? AND user_id = ?")
+            .bind(issuer)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to unlink identity: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::IdentityNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn create_refresh_token(&self, token_hash: &str, family_id: &str, user_id: &str, expires_at: OffsetDateTime) -> Result<RefreshToken, DbError> {
+        let created_at = OffsetDateTime::now_utc();
+        
+        sqlx::query(
+            "INSERT INTO refresh_tokens (token_hash, family_id, user_id, expires_at, created_at) VALUES (?, ?, ?, ?, ?)"
         )
-        .bind(limit as i64)
-        .bind(offset as i64)
-        .fetch_all(&self.pool)
+        .bind(token_hash)
+        .bind(family_id)
+        .bind(user_id)
+        .bind(expires_at.unix_timestamp().to_string())
+        .bind(created_at.unix_timestamp().to_string())
+        .execute(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create refresh token: {}", e)))?;
+        
+        Ok(RefreshToken {
+            token_hash: token_hash.to_string(),
+            family_id: family_id.to_string(),
+            user_id: user_id.to_string(),
+            expires_at,
+            used_at: None,
+            created_at,
+        })
+    }
+    
+    async fn get_refresh_token(&self, token_hash: &str) -> Result<RefreshToken, DbError> {
+        let row = sqlx::query(&format!("SELECT {} FROM refresh_tokens WHERE token_hash = ?", REFRESH_TOKEN_COLUMNS))
+            .bind(token_hash)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::RefreshTokenNotFound)?;
         
-        let mut users = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            users.push(User {
-                id: row.get("id"),
-                username: row.get("username"),
-                email: row.get("email"),
-                password_hash: row.get("password_hash"),
-                is_admin: row.get::<i32, _>("is_admin") != 0,
-                created_at,
-            });
+        refresh_token_from_row(&row)
+    }
+    
+    async fn use_refresh_token(&self, token_hash: &str) -> Result<bool, DbError> {
+        let result = sqlx::query("UPDATE refresh_tokens SET used_at = ? WHERE token_hash = ? AND used_at IS NULL")
+            .bind(OffsetDateTime::now_utc().unix_timestamp().to_string())
+            .bind(token_hash)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to use refresh token: {}", e)))?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+    
+    async fn delete_refresh_token_family(&self, family_id: &str) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE family_id = ?")
+            .bind(family_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete refresh tokens: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_user_refresh_tokens(&self, user_id: &str, keep_family: Option<&str>) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE user_id = ? AND family_id IS NOT ?")
+            .bind(user_id)
+            .bind(keep_family)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete refresh tokens: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_expired_refresh_tokens(&self) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE CAST(expires_at AS INTEGER) <= ?")
+            .bind(OffsetDateTime::now_utc().unix_timestamp())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete expired refresh tokens: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_two_factor(&self, user_id: &str) -> Result<TwoFactor, DbError> {
+        let row = sqlx::query("SELECT user_id, secret, enabled, last_step, created_at FROM two_factor WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::TwoFactorNotFound)?;
+        
+        Ok(TwoFactor {
+            user_id: row.get("user_id"),
+            secret: row.get("secret"),
+            enabled: row.get::<i64, _>("enabled") != 0,
+            last_step: row.get("last_step"),
+            created_at: timestamp_from_row(&row, "created_at")?,
+        })
+    }
+    
+    async fn save_two_factor(&self, user_id: &str, secret: &str, enabled: bool) -> Result<(), DbError> {
+        sqlx::query(
+            r#"
+            INSERT INTO two_factor (user_id, secret, enabled, created_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, enabled = excluded.enabled, created_at = excluded.created_at
+            "#
+        )
+        .bind(user_id)
+        .bind(secret)
+        .bind(enabled as i64)
+        .bind(OffsetDateTime::now_utc().unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to save two-factor authentication: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn use_totp_step(&self, user_id: &str, step: i64) -> Result<bool, DbError> {
+        let result = sqlx::query("UPDATE two_factor SET last_step = ? WHERE user_id = ? AND last_step < ?")
+            .bind(step)
+            .bind(user_id)
+            .bind(step)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to use code: {}", e)))?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+    
+    async fn delete_two_factor(&self, user_id: &str) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete recovery codes: {}", e)))?;
+        
+        let result = sqlx::query("DELETE FROM two_factor WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete two-factor authentication: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::TwoFactorNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn replace_recovery_codes(&self, user_id: &str, code_hashes: &[String]) -> Result<(), DbError> {
+        let mut tx = self.pool.begin().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+        
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete recovery codes: {}", e)))?;
+        
+        for code_hash in code_hashes {
+            sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+                .bind(user_id)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to save recovery code: {}", e)))?;
         }
         
-        Ok(users)
+        tx.commit().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        
+        Ok(())
     }
     
-    async fn update_user_email(&self, username: &str, new_email: &str) -> Result<(), DbError> {
-        // Check if new email already exists for another user
-        if self.email_exists(new_email).await? {
-            let existing_user = self.get_user_by_email(new_email).await?;
-            if existing_user.username != username {
-                return Err(DbError::UserAlreadyExists);
-            }
+    async fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool, DbError> {
+        let result = sqlx::query("DELETE FROM recovery_codes WHERE user_id = ? AND code_hash = ?")
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to use recovery code: {}", e)))?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+    
+    async fn count_recovery_codes(&self, user_id: &str) -> Result<usize, DbError> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(count as usize)
+    }
+    
+    async fn create_passkey(&self, passkey: &Passkey) -> Result<(), DbError> {
+        sqlx::query(
+            "INSERT INTO passkeys (id, user_id, name, public_key, algorithm, sign_count, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&passkey.id)
+        .bind(&passkey.user_id)
+        .bind(&passkey.name)
+        .bind(&passkey.public_key)
+        .bind(passkey.algorithm)
+        .bind(passkey.sign_count)
+        .bind(passkey.created_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create passkey: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_passkey(&self, id: &str) -> Result<Passkey, DbError> {
+        let row = sqlx::query(&format!("SELECT {} FROM passkeys WHERE id = ?", PASSKEY_COLUMNS))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::PasskeyNotFound)?;
+        
+        passkey_from_row(&row)
+    }
+    
+    async fn get_user_passkeys(&self, user_id: &str) -> Result<Vec<Passkey>, DbError> {
+        let rows = sqlx::query(&format!("SELECT {} FROM passkeys WHERE user_id = ? ORDER BY CAST(created_at AS INTEGER), id", PASSKEY_COLUMNS))
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(passkey_from_row).collect()
+    }
+    
+    async fn use_passkey(&self, id: &str, sign_count: i64) -> Result<(), DbError> {
+        sqlx::query("UPDATE passkeys SET sign_count = ?, last_used_at = ? WHERE id = ?")
+            .bind(sign_count)
+            .bind(OffsetDateTime::now_utc().unix_timestamp().to_string())
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update passkey: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_passkey(&self, user_id: &str, id: &str) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM passkeys WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete passkey: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::PasskeyNotFound);
         }
         
-        let result = sqlx::query("UPDATE users SET email = ? WHERE username = ?")
-            .bind(new_email)
-            .bind(username)
+        Ok(())
+    }
+    
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<(), DbError> {
+        sqlx::query(
+            "INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&api_key.id)
+        .bind(&api_key.user_id)
+        .bind(&api_key.name)
+        .bind(&api_key.prefix)
+        .bind(&api_key.key_hash)
+        .bind(format_scopes(&api_key.scopes))
+        .bind(api_key.created_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create API key: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_api_key_by_hash(&self, key_hash: &str) -> Result<ApiKey, DbError> {
+        let row = sqlx::query(&format!("SELECT {} FROM api_keys WHERE key_hash = ?", API_KEY_COLUMNS))
+            .bind(key_hash)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::ApiKeyNotFound)?;
+        
+        api_key_from_row(&row)
+    }
+    
+    async fn get_user_api_keys(&self, user_id: &str) -> Result<Vec<ApiKey>, DbError> {
+        let rows = sqlx::query(&format!("SELECT {} FROM api_keys WHERE user_id = ? ORDER BY CAST(created_at AS INTEGER), id", API_KEY_COLUMNS))
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(api_key_from_row).collect()
+    }
+    
+    async fn touch_api_key(&self, id: &str) -> Result<(), DbError> {
+        sqlx::query("UPDATE api_keys SET last_used_at = ? WHERE id = ?")
+            .bind(OffsetDateTime::now_utc().unix_timestamp().to_string())
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update API key: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_api_key(&self, user_id: &str, id: &str) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM api_keys WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete API key: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::ApiKeyNotFound);
+        }
+        
+        Ok(())
+    }
+    
+    async fn get_roles(&self) -> Result<Vec<Role>, DbError> {
+        let rows = sqlx::query("SELECT name, description, permissions, built_in FROM roles ORDER BY name")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(rows.iter().map(role_from_row).collect())
+    }
+    
+    async fn get_role(&self, name: &str) -> Result<Role, DbError> {
+        let row = sqlx::query("SELECT name, description, permissions, built_in FROM roles WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::RoleNotFound)?;
+        
+        Ok(role_from_row(&row))
+    }
+    
+    async fn save_role(&self, role: &Role) -> Result<(), DbError> {
+        sqlx::query(
+            r#"
+            INSERT INTO roles (name, description, permissions, built_in)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                description = excluded.description,
+                permissions = excluded.permissions,
+                built_in = excluded.built_in
+            "#
+        )
+        .bind(&role.name)
+        .bind(&role.description)
+        .bind(format_scope(&role.permissions))
+        .bind(if role.built_in { 1 } else { 0 })
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to save role: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_role(&self, name: &str) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM roles WHERE name = ?")
+            .bind(name)
             .execute(&self.pool)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to update user: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete role: {}", e)))?;
         
         if result.rows_affected() == 0 {
-            return Err(DbError::UserNotFound);
+            return Err(DbError::RoleNotFound);
         }
         
+        // A role made later with the same name shouldn't inherit these
+        sqlx::query("DELETE FROM library_grants WHERE kind = 'role' AND subject = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
+        
         Ok(())
     }
     
-    async fn update_username(&self, user_id: &str, new_username: &str) -> Result<(), DbError> {
-        // Check if new username already exists
-        if self.username_exists(new_username).await? {
-            return Err(DbError::UserAlreadyExists);
-        }
+    async fn count_users_with_role(&self, role: &str) -> Result<usize, DbError> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE role = ?")
+            .bind(role)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let result = sqlx::query("UPDATE users SET username = ? WHERE id = ?")
-            .bind(new_username)
-            .bind(user_id)
-            .execute(&self.pool)
+        Ok(count as usize)
+    }
+    
+    // Library operations
+    async fn get_libraries(&self) -> Result<Vec<Library>, DbError> {
+        let rows = sqlx::query("SELECT id, name, root_path, scan_on_startup, recursive, created_at FROM libraries ORDER BY name")
+            .fetch_all(&self.pool)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to update username: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        if result.rows_affected() == 0 {
-            return Err(DbError::UserNotFound);
-        }
+        rows.iter().map(library_from_row).collect()
+    }
+    
+    async fn get_library(&self, id: &str) -> Result<Library, DbError> {
+        let row = sqlx::query("SELECT id, name, root_path, scan_on_startup, recursive, created_at FROM libraries WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::LibraryNotFound)?;
+        
+        library_from_row(&row)
+    }
+    
+    async fn save_library(&self, library: &Library) -> Result<(), DbError> {
+        sqlx::query(
+            r#"
+            INSERT INTO libraries (id, name, root_path, scan_on_startup, recursive, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (id) DO UPDATE SET
+                name = excluded.name,
+                root_path = excluded.root_path,
+                scan_on_startup = excluded.scan_on_startup,
+                recursive = excluded.recursive
+            "#
+        )
+        .bind(&library.id)
+        .bind(&library.name)
+        .bind(&library.root_path)
+        .bind(if library.scan_on_startup { 1 } else { 0 })
+        .bind(if library.recursive { 1 } else { 0 })
+        .bind(library.created_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to save library: {}", e)))?;
         
         Ok(())
     }
     
-    async fn update_user_password(&self, user_id: &str, new_password_hash: &str) -> Result<(), DbError> {
-        let result = sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
-            .bind(new_password_hash)
-            .bind(user_id)
+    async fn delete_library(&self, id: &str) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM library_grants WHERE library_id = ?")
+            .bind(id)
             .execute(&self.pool)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to update password: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library grants: {}", e)))?;
+        
+        let result = sqlx::query("DELETE FROM libraries WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete library: {}", e)))?;
         
         if result.rows_affected() == 0 {
-            return Err(DbError::UserNotFound);
+            return Err(DbError::LibraryNotFound);
         }
         
         Ok(())
     }
     
-    async fn delete_user_by_username(&self, username: &str) -> Result<(), DbError> {
-        let result = sqlx::query("DELETE FROM users WHERE username = ?")
-            .bind(username)
-            .execute(&self.pool)
+    async fn get_library_grants(&self, library_id: &str) -> Result<Vec<LibraryGrant>, DbError> {
+        let rows = sqlx::query("SELECT library_id, kind, subject FROM library_grants WHERE library_id = ? ORDER BY kind, subject")
+            .bind(library_id)
+            .fetch_all(&self.pool)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        if result.rows_affected() == 0 {
-            return Err(DbError::UserNotFound);
-        }
+        rows.iter().map(grant_from_row).collect()
+    }
+    
+    async fn grant_library(&self, grant: &LibraryGrant) -> Result<(), DbError> {
+        sqlx::query("INSERT OR IGNORE INTO library_grants (library_id, kind, subject) VALUES (?, ?, ?)")
+            .bind(&grant.library_id)
+            .bind(grant.kind.as_str())
+            .bind(&grant.subject)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to grant library: {}", e)))?;
         
         Ok(())
     }
     
-    async fn delete_user_by_id(&self, user_id: &str) -> Result<(), DbError> {
-        let result = sqlx::query("DELETE FROM users WHERE id = ?")
-            .bind(user_id)
+    async fn revoke_library(&self, grant: &LibraryGrant) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM library_grants WHERE library_id = ? AND kind = ? AND subject = ?")
+            .bind(&grant.library_id)
+            .bind(grant.kind.as_str())
+            .bind(&grant.subject)
             .execute(&self.pool)
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to delete user: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to revoke library: {}", e)))?;
         
         if result.rows_affected() == 0 {
-            return Err(DbError::UserNotFound);
+            return Err(DbError::GrantNotFound);
         }
         
         Ok(())
     }
     
-    async fn get_total_users(&self) -> Result<usize, DbError> {
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
-            .fetch_one(&self.pool)
-            .await
-            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
-        
-        Ok(count as usize)
+    async fn get_accessible_libraries(&self, user_id: &str, role: &str) -> Result<Vec<String>, DbError> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT library_id FROM library_grants WHERE (kind = 'user' AND subject = ?) OR (kind = 'role' AND subject = ?)"
+        )
+        .bind(user_id)
+        .bind(role)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))
     }
     
     // Artist operations
@@ -486,16 +1989,7 @@ impl Database for SqliteDatabase {
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
             .ok_or(DbError::DatabaseError("Artist not found".to_string()))?;
         
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-        
-        Ok(Artist {
-            id: row.get("id"),
-            name: row.get("name"),
-            cover_image_path: row.get("cover_image_path"),
-            created_at,
-        })
+        artist_from_row(&row)
     }
     
     async fn get_artist_by_name(&self, name: &str) -> Result<Artist, DbError> {
@@ -506,52 +2000,32 @@ impl Database for SqliteDatabase {
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
             .ok_or(DbError::DatabaseError("Artist not found".to_string()))?;
         
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-        
-        Ok(Artist {
-            id: row.get("id"),
-            name: row.get("name"),
-            cover_image_path: row.get("cover_image_path"),
-            created_at,
-        })
+        artist_from_row(&row)
     }
     
-    async fn get_artists(&self, offset: usize, limit: usize) -> Result<Vec<Artist>, DbError> {
-        let rows = sqlx::query(
-            "SELECT id, name, cover_image_path, created_at FROM artists ORDER BY name ASC LIMIT ? OFFSET ?"
-        )
-        .bind(limit as i64)
-        .bind(offset as i64)
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+    async fn get_artists(&self, page: &PageRequest, libraries: &LibraryFilter) -> Result<Page<Artist>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT id, name, cover_image_path, created_at FROM artists"
+        );
+        let has_where = push_artist_library_clause(&mut builder, libraries);
+        push_page_clause(&mut builder, sort_column(page.sort, "name"), page, has_where);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut artists = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            artists.push(Artist {
-                id: row.get("id"),
-                name: row.get("name"),
-                cover_image_path: row.get("cover_image_path"),
-                created_at,
-            });
-        }
+        let artists = rows.iter().map(artist_from_row).collect::<Result<Vec<_>, _>>()?;
         
-        Ok(artists)
-    }
-    
-    async fn get_total_artists(&self) -> Result<usize, DbError> {
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM artists")
+        let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM artists");
+        push_artist_library_clause(&mut count, libraries);
+        let total: i64 = count.build_query_scalar()
             .fetch_one(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        let total = total as usize;
         
-        Ok(count as usize)
+        Ok(Page::from_rows(artists, page, total))
     }
     
     async fn update_artist_cover(&self, id: &str, cover_path: &str) -> Result<(), DbError> {
@@ -580,7 +2054,7 @@ impl Database for SqliteDatabase {
     }
     
     // Song operations
-    async fn create_song(&self, title: &str, artist_id: &str, file_path: &str) -> Result<Song, DbError> {
+    async fn create_song(&self, title: &str, artist_id: &str, file_path: &str, library_id: &str) -> Result<Song, DbError> {
         // Get artist to get the artist name
         let artist = self.get_artist_by_id(artist_id).await?;
         
@@ -589,13 +2063,14 @@ impl Database for SqliteDatabase {
         let created_at_str = created_at.unix_timestamp().to_string();
         
         sqlx::query(
-            "INSERT INTO songs (id, title, artist_id, artist_name, file_path, created_at) VALUES (?, ?, ?, ?, ?, ?)"
+            "INSERT INTO songs (id, title, artist_id, artist_name, file_path, library_id, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
         )
         .bind(&id)
         .bind(title)
         .bind(artist_id)
         .bind(&artist.name)
         .bind(file_path)
+        .bind(library_id)
         .bind(&created_at_str)
         .execute(&self.pool)
         .await
@@ -609,14 +2084,24 @@ impl Database for SqliteDatabase {
             album: None,
             duration: None,
             file_path: file_path.to_string(),
+            library_id: library_id.to_string(),
             cover_image_path: None,
+            musicbrainz_id: None,
+            genre: None,
+            year: None,
+            start_ms: None,
+            end_ms: None,
+            encoder_delay: None,
+            encoder_padding: None,
+            sample_rate: None,
+            play_count: 0,
             created_at,
         })
     }
     
     async fn get_song_by_id(&self, id: &str) -> Result<Song, DbError> {
         let row = sqlx::query(
-            "SELECT id, title, artist_id, artist_name, album, duration, file_path, cover_image_path, created_at FROM songs WHERE id = ?"
+            &format!("SELECT {} FROM songs WHERE id = ?", SONG_COLUMNS)
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -624,93 +2109,116 @@ impl Database for SqliteDatabase {
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
         .ok_or(DbError::DatabaseError("Song not found".to_string()))?;
         
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
+        song_from_row(&row)
+    }
+    
+    async fn get_song_by_file_path(&self, file_path: &str) -> Result<Song, DbError> {
+        let row = sqlx::query(
+            &format!("SELECT {} FROM songs WHERE file_path = ?", SONG_COLUMNS)
+        )
+        .bind(file_path)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+        .ok_or(DbError::DatabaseError("Song not found".to_string()))?;
         
-        Ok(Song {
-            id: row.get("id"),
-            title: row.get("title"),
-            artist_id: row.get("artist_id"),
-            artist_name: row.get("artist_name"),
-            album: row.get("album"),
-            duration: row.get("duration"),
-            file_path: row.get("file_path"),
-            cover_image_path: row.get("cover_image_path"),
-            created_at,
-        })
+        song_from_row(&row)
     }
     
-    async fn get_songs_by_artist(&self, artist_id: &str) -> Result<Vec<Song>, DbError> {
+    async fn get_songs_by_file_path(&self, file_path: &str) -> Result<Vec<Song>, DbError> {
         let rows = sqlx::query(
-            "SELECT id, title, artist_id, artist_name, album, duration, file_path, cover_image_path, created_at FROM songs WHERE artist_id = ? ORDER BY title ASC"
+            &format!("SELECT {} FROM songs WHERE file_path = ? ORDER BY start_ms ASC", SONG_COLUMNS)
         )
-        .bind(artist_id)
+        .bind(file_path)
         .fetch_all(&self.pool)
         .await
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut songs = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            songs.push(Song {
-                id: row.get("id"),
-                title: row.get("title"),
-                artist_id: row.get("artist_id"),
-                artist_name: row.get("artist_name"),
-                album: row.get("album"),
-                duration: row.get("duration"),
-                file_path: row.get("file_path"),
-                cover_image_path: row.get("cover_image_path"),
-                created_at,
-            });
-        }
-        
-        Ok(songs)
+        rows.iter().map(song_from_row).collect()
     }
     
-    async fn get_songs(&self, offset: usize, limit: usize) -> Result<Vec<Song>, DbError> {
-        let rows = sqlx::query(
-            "SELECT id, title, artist_id, artist_name, album, duration, file_path, cover_image_path, created_at FROM songs ORDER BY created_at DESC LIMIT ? OFFSET ?"
+    async fn get_song_by_musicbrainz_id(&self, musicbrainz_id: &str) -> Result<Song, DbError> {
+        let row = sqlx::query(
+            &format!("SELECT {} FROM songs WHERE musicbrainz_id = ?", SONG_COLUMNS)
         )
-        .bind(limit as i64)
-        .bind(offset as i64)
-        .fetch_all(&self.pool)
+        .bind(musicbrainz_id)
+        .fetch_optional(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+        .ok_or(DbError::DatabaseError("Song not found".to_string()))?;
         
-        let mut songs = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            songs.push(Song {
-                id: row.get("id"),
-                title: row.get("title"),
-                artist_id: row.get("artist_id"),
-                artist_name: row.get("artist_name"),
-                album: row.get("album"),
-                duration: row.get("duration"),
-                file_path: row.get("file_path"),
-                cover_image_path: row.get("cover_image_path"),
-                created_at,
-            });
-        }
+        song_from_row(&row)
+    }
+    
+    async fn get_songs_by_artist(&self, artist_id: &str, libraries: &LibraryFilter) -> Result<Vec<Song>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM songs WHERE artist_id = ", SONG_COLUMNS));
+        builder.push_bind(artist_id);
+        push_library_clause(&mut builder, "library_id", libraries, true);
+        builder.push(" ORDER BY title ASC");
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(songs)
+        rows.iter().map(song_from_row).collect()
     }
     
-    async fn get_total_songs(&self) -> Result<usize, DbError> {
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM songs")
+    async fn get_songs(&self, page: &PageRequest, libraries: &LibraryFilter) -> Result<Page<Song>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM songs", SONG_COLUMNS));
+        let has_where = push_library_clause(&mut builder, "library_id", libraries, false);
+        push_page_clause(&mut builder, sort_column(page.sort, "title"), page, has_where);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let songs = rows.iter().map(song_from_row).collect::<Result<Vec<_>, _>>()?;
+        
+        let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM songs");
+        push_library_clause(&mut count, "library_id", libraries, false);
+        let total: i64 = count.build_query_scalar()
             .fetch_one(&self.pool)
             .await
             .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        let total = total as usize;
         
-        Ok(count as usize)
+        Ok(Page::from_rows(songs, page, total))
+    }
+    
+    async fn search_songs(&self, query: &str, libraries: &LibraryFilter, offset: usize, limit: usize) -> Result<Vec<Song>, DbError> {
+        let pattern = format!("%{}%", escape_like(query));
+        
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM songs WHERE title LIKE ", SONG_COLUMNS));
+        builder.push_bind(pattern);
+        builder.push(" ESCAPE '\\'");
+        push_library_clause(&mut builder, "library_id", libraries, true);
+        builder.push(" ORDER BY title ASC LIMIT ");
+        builder.push_bind(limit as i64);
+        builder.push(" OFFSET ");
+        builder.push_bind(offset as i64);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(song_from_row).collect()
+    }
+    
+    async fn increment_song_play_count(&self, id: &str) -> Result<(), DbError> {
+        let result = sqlx::query("UPDATE songs SET play_count = play_count + 1 WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
     }
     
     async fn update_song_metadata(&self, id: &str, album: Option<&str>, duration: Option<i32>, cover_path: Option<&str>) -> Result<(), DbError> {
@@ -732,6 +2240,60 @@ impl Database for SqliteDatabase {
         Ok(())
     }
    
+    async fn update_song_tags(&self, id: &str, musicbrainz_id: Option<&str>, genre: Option<&str>, year: Option<i32>) -> Result<(), DbError> {
+        let result = sqlx::query(
+            "UPDATE songs SET musicbrainz_id = COALESCE(?, musicbrainz_id), genre = COALESCE(?, genre), year = COALESCE(?, year) WHERE id = ?"
+        )
+            .bind(musicbrainz_id)
+            .bind(genre)
+            .bind(year)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_song_track_range(&self, id: &str, start_ms: Option<i64>, end_ms: Option<i64>) -> Result<(), DbError> {
+        let result = sqlx::query("UPDATE songs SET start_ms = ?, end_ms = ? WHERE id = ?")
+            .bind(start_ms)
+            .bind(end_ms)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    async fn update_song_gapless(&self, id: &str, encoder_delay: Option<i32>, encoder_padding: Option<i32>, sample_rate: Option<i32>) -> Result<(), DbError> {
+        let result = sqlx::query(
+            "UPDATE songs SET encoder_delay = ?, encoder_padding = ?, sample_rate = ? WHERE id = ?"
+        )
+            .bind(encoder_delay)
+            .bind(encoder_padding)
+            .bind(sample_rate)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to update song: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::DatabaseError("Song not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
     async fn delete_song_by_id(&self, id: &str) -> Result<(), DbError> {
         let result = sqlx::query("DELETE FROM songs WHERE id = ?")
             .bind(id)
@@ -743,11 +2305,18 @@ impl Database for SqliteDatabase {
             return Err(DbError::DatabaseError("Song not found".to_string()));
         }
         
-        Ok(())
+        sqlx::query("DELETE FROM user_ratings WHERE item_type = 'song' AND item_id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete song ratings: {}", e)))?;
+        
+        self.delete_song_lyrics(id).await
+
     }
     
     // Playlist operations
-    async fn create_playlist(&self, name: &str, owner_id: &str, is_public: bool) -> Result<Playlist, DbError> {
+    async fn create_playlist(&self, name: &str, owner_id: &str, is_public: bool, rules: Option<&SmartRules>) -> Result<Playlist, DbError> {
         // Get owner username
         let owner = self.get_user_by_id(owner_id).await?;
         
@@ -755,15 +2324,19 @@ impl Database for SqliteDatabase {
         let created_at = OffsetDateTime::now_utc();
         let created_at_str = created_at.unix_timestamp().to_string();
         let is_public_int = if is_public { 1 } else { 0 };
+        let is_smart_int = if rules.is_some() { 1 } else { 0 };
+        let rules_json = rules.map(rules_to_json).transpose()?;
         
         sqlx::query(
-            "INSERT INTO playlists (id, name, owner_id, owner_username, is_public, created_at) VALUES (?, ?, ?, ?, ?, ?)"
+            "INSERT INTO playlists (id, name, owner_id, owner_username, is_public, is_smart, rules, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
         )
         .bind(&id)
         .bind(name)
         .bind(owner_id)
         .bind(&owner.username)
         .bind(is_public_int)
+        .bind(is_smart_int)
+        .bind(&rules_json)
         .bind(&created_at_str)
         .execute(&self.pool)
         .await
@@ -775,124 +2348,86 @@ impl Database for SqliteDatabase {
             owner_id: owner_id.to_string(),
             owner_username: owner.username,
             is_public,
+            version: 0,
+            is_smart: rules.is_some(),
+            rules: rules.cloned(),
             created_at,
         })
     }
     
     async fn get_playlist_by_id(&self, id: &str) -> Result<Playlist, DbError> {
-        let row = sqlx::query(
-            "SELECT id, name, owner_id, owner_username, is_public, created_at FROM playlists WHERE id = ?"
-        )
-        .bind(id)
-        .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
-        .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
-        
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
+        let row = sqlx::query(&format!("SELECT {} FROM playlists WHERE id = ?", PLAYLIST_COLUMNS))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
         
-        Ok(Playlist {
-            id: row.get("id"),
-            name: row.get("name"),
-            owner_id: row.get("owner_id"),
-            owner_username: row.get("owner_username"),
-            is_public: row.get::<i32, _>("is_public") != 0,
-            created_at,
-        })
+        playlist_from_row(&row)
     }
     
     async fn get_playlist_by_name_and_owner(&self, name: &str, owner_id: &str) -> Result<Playlist, DbError> {
-        let row = sqlx::query(
-            "SELECT id, name, owner_id, owner_username, is_public, created_at FROM playlists WHERE name = ? AND owner_id = ?"
-        )
-        .bind(name)
-        .bind(owner_id)
-        .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
-        .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
-        
-        let timestamp: i64 = row.get("created_at");
-        let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-            .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
+        let row = sqlx::query(&format!("SELECT {} FROM playlists WHERE name = ? AND owner_id = ?", PLAYLIST_COLUMNS))
+            .bind(name)
+            .bind(owner_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
         
-        Ok(Playlist {
-            id: row.get("id"),
-            name: row.get("name"),
-            owner_id: row.get("owner_id"),
-            owner_username: row.get("owner_username"),
-            is_public: row.get::<i32, _>("is_public") != 0,
-            created_at,
-        })
+        playlist_from_row(&row)
     }
     
-    async fn get_user_playlists(&self, user_id: &str, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError> {
-        let rows = sqlx::query(
-            "SELECT id, name, owner_id, owner_username, is_public, created_at FROM playlists WHERE owner_id = ? ORDER BY created_at DESC LIMIT ? OFFSET ?"
-        )
-        .bind(user_id)
-        .bind(limit as i64)
-        .bind(offset as i64)
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+    async fn get_user_playlists(&self, user_id: &str, page: &PageRequest) -> Result<Page<Playlist>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            format!("SELECT {} FROM playlists WHERE owner_id = ", PLAYLIST_COLUMNS)
+        );
+        builder.push_bind(user_id.to_string());
+        push_page_clause(&mut builder, sort_column(page.sort, "name"), page, true);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut playlists = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            playlists.push(Playlist {
-                id: row.get("id"),
-                name: row.get("name"),
-                owner_id: row.get("owner_id"),
-                owner_username: row.get("owner_username"),
-                is_public: row.get::<i32, _>("is_public") != 0,
-                created_at,
-            });
-        }
+        let playlists = rows.iter().map(playlist_from_row).collect::<Result<Vec<_>, _>>()?;
+        
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM playlists WHERE owner_id = ?")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(playlists)
+        Ok(Page::from_rows(playlists, page, total as usize))
     }
     
-    async fn get_public_playlists(&self, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError> {
-        let rows = sqlx::query(
-            "SELECT id, name, owner_id, owner_username, is_public, created_at FROM playlists WHERE is_public = 1 ORDER BY created_at DESC LIMIT ? OFFSET ?"
-        )
-        .bind(limit as i64)
-        .bind(offset as i64)
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+    async fn get_public_playlists(&self, page: &PageRequest) -> Result<Page<Playlist>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            format!("SELECT {} FROM playlists WHERE is_public = 1", PLAYLIST_COLUMNS)
+        );
+        push_page_clause(&mut builder, sort_column(page.sort, "name"), page, true);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut playlists = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            playlists.push(Playlist {
-                id: row.get("id"),
-                name: row.get("name"),
-                owner_id: row.get("owner_id"),
-                owner_username: row.get("owner_username"),
-                is_public: row.get::<i32, _>("is_public") != 0,
-                created_at,
-            });
-        }
+        let playlists = rows.iter().map(playlist_from_row).collect::<Result<Vec<_>, _>>()?;
+        
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM playlists WHERE is_public = 1")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(playlists)
+        Ok(Page::from_rows(playlists, page, total as usize))
     }
     
     async fn get_shared_playlists(&self, user_id: &str) -> Result<Vec<(Playlist, PlaylistShare)>, DbError> {
         let rows = sqlx::query(
             r#"
             SELECT 
-                p.id, p.name, p.owner_id, p.owner_username, p.is_public, p.created_at,
-                ps.id as share_id, ps.playlist_id, ps.shared_with_user_id, ps.shared_by_user_id, ps.shared_at
+                p.id, p.name, p.owner_id, p.owner_username, p.is_public, p.version, p.is_smart, p.rules, p.created_at,
+                ps.id as share_id, ps.playlist_id, ps.shared_with_user_id, ps.shared_by_user_id, ps.permission, ps.shared_at
             FROM playlists p
             INNER JOIN playlist_shares ps ON p.id = ps.playlist_id
             WHERE ps.shared_with_user_id = ?
@@ -906,30 +2441,9 @@ impl Database for SqliteDatabase {
         
         let mut results = Vec::new();
         for row in rows {
-            let playlist_timestamp: i64 = row.get("created_at");
-            let playlist_created_at = OffsetDateTime::from_unix_timestamp(playlist_timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            let share_timestamp: i64 = row.get("shared_at");
-            let shared_at = OffsetDateTime::from_unix_timestamp(share_timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
-            
-            let playlist = Playlist {
-                id: row.get("id"),
-                name: row.get("name"),
-                owner_id: row.get("owner_id"),
-                owner_username: row.get("owner_username"),
-                is_public: row.get::<i32, _>("is_public") != 0,
-                created_at: playlist_created_at,
-            };
+            let playlist = playlist_from_row(&row)?;
             
-            let share = PlaylistShare {
-                id: row.get("share_id"),
-                playlist_id: row.get("playlist_id"),
-                shared_with_user_id: row.get("shared_with_user_id"),
-                shared_by_user_id: row.get("shared_by_user_id"),
-                shared_at,
-            };
+            let share = share_from_row(&row, "share_id")?;
             
             results.push((playlist, share));
         }
@@ -952,84 +2466,129 @@ impl Database for SqliteDatabase {
         Ok(())
     }
     
-    async fn add_song_to_playlist(&self, playlist_id: &str, song_id: &str) -> Result<(), DbError> {
-        // Check if song exists
-        let _ = self.get_song_by_id(song_id).await?;
-        
-        // Check if playlist exists
-        let _ = self.get_playlist_by_id(playlist_id).await?;
-        
-        let added_at = OffsetDateTime::now_utc();
-        let added_at_str = added_at.unix_timestamp().to_string();
-        
-        sqlx::query(
-            "INSERT OR IGNORE INTO playlist_songs (playlist_id, song_id, added_at) VALUES (?, ?, ?)"
+    async fn get_playlist_entries(&self, playlist_id: &str) -> Result<Vec<PlaylistEntry>, DbError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT e.id AS entry_id, e.added_at AS entry_added_at,
+                s.id, s.title, s.artist_id, s.artist_name, s.album, s.duration, s.file_path, s.library_id, s.cover_image_path, s.musicbrainz_id, s.genre, s.year, s.start_ms, s.end_ms, s.encoder_delay, s.encoder_padding, s.sample_rate, s.play_count, s.created_at
+            FROM playlist_entries e
+            INNER JOIN songs s ON s.id = e.song_id
+            WHERE e.playlist_id = ?
+            ORDER BY e.position ASC, e.id ASC
+            "#
         )
         .bind(playlist_id)
-        .bind(song_id)
-        .bind(&added_at_str)
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
         .await
-        .map_err(|e| DbError::DatabaseError(format!("Failed to add song to playlist: {}", e)))?;
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(())
+        rows.iter().enumerate().map(|(position, row)| {
+            Ok(PlaylistEntry {
+                id: row.get("entry_id"),
+                position: position as i64,
+                song: song_from_row(row)?,
+                added_at: timestamp_from_row(row, "entry_added_at")?,
+            })
+        }).collect()
     }
     
-    async fn remove_song_from_playlist(&self, playlist_id: &str, song_id: &str) -> Result<(), DbError> {
-        let result = sqlx::query("DELETE FROM playlist_songs WHERE playlist_id = ? AND song_id = ?")
-            .bind(playlist_id)
-            .bind(song_id)
-            .execute(&self.pool)
+    async fn edit_playlist_entries(&self, playlist_id: &str, edit: &PlaylistEdit, expected_version: Option<i64>) -> Result<(i64, Vec<EditedEntry>), DbError> {
+        // Check songs being added exist
+        for song_id in edit.inserted_song_ids() {
+            let _ = self.get_song_by_id(song_id).await?;
+        }
+        
+        let mut tx = self.pool.begin()
             .await
-            .map_err(|e| DbError::DatabaseError(format!("Failed to remove song from playlist: {}", e)))?;
+            .map_err(|e| DbError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
         
-        if result.rows_affected() == 0 {
-            return Err(DbError::DatabaseError("Song not in playlist".to_string()));
-        }
+        // Bumping the version first takes the write lock, so concurrent edits are serialised
+        let version: Option<i64> = sqlx::query_scalar(
+            "UPDATE playlists SET version = version + 1 WHERE id = ? AND (? IS NULL OR version = ?) RETURNING version"
+        )
+        .bind(playlist_id)
+        .bind(expected_version)
+        .bind(expected_version)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to update playlist version: {}", e)))?;
+        
+        let Some(version) = version else {
+            drop(tx);
+            self.get_playlist_by_id(playlist_id).await?;
+            return Err(DbError::VersionConflict);
+        };
         
-        Ok(())
-    }
-    
-    async fn get_playlist_songs(&self, playlist_id: &str) -> Result<Vec<Song>, DbError> {
         let rows = sqlx::query(
-            r#"
-            SELECT s.id, s.title, s.artist_id, s.artist_name, s.album, s.duration, s.file_path, s.cover_image_path, s.created_at
-            FROM songs s
-            INNER JOIN playlist_songs ps ON s.id = ps.song_id
-            WHERE ps.playlist_id = ?
-            ORDER BY ps.added_at ASC
-            "#
+            "SELECT id, song_id, position FROM playlist_entries WHERE playlist_id = ? ORDER BY position ASC, id ASC"
         )
         .bind(playlist_id)
-        .fetch_all(&self.pool)
+        .fetch_all(&mut *tx)
         .await
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut songs = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
+        let entries = rows.iter()
+            .map(|row| EntrySlot::stored(row.get("id"), row.get("song_id"), row.get("position")))
+            .collect();
+        let plan = edit.apply(entries)?;
+        
+        for id in &plan.removed {
+            sqlx::query("DELETE FROM playlist_entries WHERE id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to remove playlist entry: {}", e)))?;
+        }
+        
+        let added_at_str = OffsetDateTime::now_utc().unix_timestamp().to_string();
+        for (position, entry) in plan.changed() {
+            let query = if entry.stored_position.is_some() {
+                sqlx::query("UPDATE playlist_entries SET position = ? WHERE id = ?")
+                    .bind(position)
+                    .bind(&entry.id)
+            } else {
+                sqlx::query(
+                    "INSERT INTO playlist_entries (id, playlist_id, song_id, position, added_at) VALUES (?, ?, ?, ?, ?)"
+                )
+                .bind(&entry.id)
+                .bind(playlist_id)
+                .bind(&entry.song_id)
+                .bind(position)
+                .bind(&added_at_str)
+            };
             
-            songs.push(Song {
-                id: row.get("id"),
-                title: row.get("title"),
-                artist_id: row.get("artist_id"),
-                artist_name: row.get("artist_name"),
-                album: row.get("album"),
-                duration: row.get("duration"),
-                file_path: row.get("file_path"),
-                cover_image_path: row.get("cover_image_path"),
-                created_at,
-            });
+            query.execute(&mut *tx)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to write playlist entry: {}", e)))?;
+        }
+        
+        tx.commit()
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        
+        Ok((version, plan.edited))
+    }
+    
+    async fn get_smart_playlist_songs(&self, rules: &SmartRules, libraries: &LibraryFilter) -> Result<Vec<Song>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM songs", SONG_COLUMNS));
+        let has_where = push_library_clause(&mut builder, "library_id", libraries, false);
+        if let Some(filter) = &rules.filter {
+            builder.push(if has_where { " AND " } else { " WHERE " });
+            push_rule_expr(&mut builder, filter);
         }
+        push_rule_order(&mut builder, rules);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(songs)
+        rows.iter().map(song_from_row).collect()
     }
     
     async fn is_song_in_playlist(&self, playlist_id: &str, song_id: &str) -> Result<bool, DbError> {
         let count: i64 = sqlx::query_scalar(
-            "SELECT COUNT(*) FROM playlist_songs WHERE playlist_id = ? AND song_id = ?"
+            "SELECT COUNT(*) FROM playlist_entries WHERE playlist_id = ? AND song_id = ?"
         )
         .bind(playlist_id)
         .bind(song_id)
@@ -1040,7 +2599,7 @@ impl Database for SqliteDatabase {
         Ok(count > 0)
     }
     
-    async fn share_playlist(&self, playlist_id: &str, shared_with_user_id: &str, shared_by_user_id: &str) -> Result<PlaylistShare, DbError> {
+    async fn share_playlist(&self, playlist_id: &str, shared_with_user_id: &str, shared_by_user_id: &str, permission: SharePermission) -> Result<PlaylistShare, DbError> {
         // Check if playlist exists
         let _ = self.get_playlist_by_id(playlist_id).await?;
         
@@ -1053,12 +2612,13 @@ impl Database for SqliteDatabase {
         let shared_at_str = shared_at.unix_timestamp().to_string();
         
         sqlx::query(
-            "INSERT OR REPLACE INTO playlist_shares (id, playlist_id, shared_with_user_id, shared_by_user_id, shared_at) VALUES (?, ?, ?, ?, ?)"
+            "INSERT OR REPLACE INTO playlist_shares (id, playlist_id, shared_with_user_id, shared_by_user_id, permission, shared_at) VALUES (?, ?, ?, ?, ?, ?)"
         )
         .bind(&id)
         .bind(playlist_id)
         .bind(shared_with_user_id)
         .bind(shared_by_user_id)
+        .bind(permission.as_str())
         .bind(&shared_at_str)
         .execute(&self.pool)
         .await
@@ -1069,6 +2629,7 @@ impl Database for SqliteDatabase {
             playlist_id: playlist_id.to_string(),
             shared_with_user_id: shared_with_user_id.to_string(),
             shared_by_user_id: shared_by_user_id.to_string(),
+            permission,
             shared_at,
         })
     }
@@ -1090,47 +2651,341 @@ impl Database for SqliteDatabase {
         Ok(())
     }
     
-    async fn is_playlist_shared_with_user(&self, playlist_id: &str, user_id: &str) -> Result<bool, DbError> {
-        let count: i64 = sqlx::query_scalar(
-            "SELECT COUNT(*) FROM playlist_shares WHERE playlist_id = ? AND shared_with_user_id = ?"
+    async fn get_playlist_share(&self, playlist_id: &str, user_id: &str) -> Result<Option<PlaylistShare>, DbError> {
+        let row = sqlx::query(
+            &format!("SELECT {} FROM playlist_shares WHERE playlist_id = ? AND shared_with_user_id = ?", SHARE_COLUMNS)
         )
         .bind(playlist_id)
         .bind(user_id)
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        Ok(count > 0)
+        row.map(|row| share_from_row(&row, "id")).transpose()
     }
     
-    // Admin playlist operations
-    async fn get_all_playlists(&self, offset: usize, limit: usize) -> Result<Vec<Playlist>, DbError> {
+    async fn get_playlist_shares(&self, playlist_id: &str) -> Result<Vec<PlaylistShare>, DbError> {
+        let rows = sqlx::query(
+            &format!("SELECT {} FROM playlist_shares WHERE playlist_id = ? ORDER BY CAST(shared_at AS INTEGER)", SHARE_COLUMNS)
+        )
+        .bind(playlist_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(|row| share_from_row(row, "id")).collect()
+    }
+    
+    async fn record_playlist_activity(&self, activity: &[PlaylistActivity]) -> Result<(), DbError> {
+        let mut tx = self.pool.begin().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+        
+        for item in activity {
+            sqlx::query(
+                "INSERT INTO playlist_activity (id, playlist_id, user_id, username, action, song_id, song_title, artist_name, position, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(&item.id)
+            .bind(&item.playlist_id)
+            .bind(&item.user_id)
+            .bind(&item.username)
+            .bind(item.action.as_str())
+            .bind(&item.song_id)
+            .bind(&item.song_title)
+            .bind(&item.artist_name)
+            .bind(item.position)
+            .bind(item.created_at.unix_timestamp().to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to record playlist activity: {}", e)))?;
+        }
+        
+        tx.commit().await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to commit transaction: {}", e)))
+    }
+    
+    async fn get_playlist_activity(&self, playlist_id: &str, limit: usize) -> Result<Vec<PlaylistActivity>, DbError> {
         let rows = sqlx::query(
-            "SELECT id, name, owner_id, owner_username, is_public, created_at FROM playlists ORDER BY created_at DESC LIMIT ? OFFSET ?"
+            "SELECT id, playlist_id, user_id, username, action, song_id, song_title, artist_name, position, created_at FROM playlist_activity WHERE playlist_id = ? ORDER BY CAST(created_at AS INTEGER) DESC, rowid DESC LIMIT ?"
         )
+        .bind(playlist_id)
         .bind(limit as i64)
-        .bind(offset as i64)
         .fetch_all(&self.pool)
         .await
         .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
         
-        let mut playlists = Vec::new();
-        for row in rows {
-            let timestamp: i64 = row.get("created_at");
-            let created_at = OffsetDateTime::from_unix_timestamp(timestamp)
-                .map_err(|e| DbError::DatabaseError(format!("Invalid timestamp: {}", e)))?;
+        rows.iter().map(activity_from_row).collect()
+    }
+    
+    async fn create_share_link(&self, token: &str, owner_id: &str, target: &ShareTarget, password_hash: Option<&str>, expires_at: Option<OffsetDateTime>) -> Result<ShareLink, DbError> {
+        let created_at = OffsetDateTime::now_utc();
+        
+        sqlx::query(
+            "INSERT INTO share_links (id, owner_id, target_type, target_id, album, password_hash, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(token)
+        .bind(owner_id)
+        .bind(target.kind())
+        .bind(target.target_id())
+        .bind(target.album())
+        .bind(password_hash)
+        .bind(expires_at.map(|expires_at| expires_at.unix_timestamp().to_string()))
+        .bind(created_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to create share link: {}", e)))?;
+        
+        Ok(ShareLink {
+            id: token.to_string(),
+            owner_id: owner_id.to_string(),
+            target: target.clone(),
+            password_hash: password_hash.map(str::to_string),
+            expires_at,
+            created_at,
+        })
+    }
+    
+    async fn get_share_link(&self, token: &str) -> Result<ShareLink, DbError> {
+        let row = sqlx::query(&format!("SELECT {} FROM share_links WHERE id = ?", SHARE_LINK_COLUMNS))
+            .bind(token)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?
+            .ok_or(DbError::DatabaseError("Share link not found".to_string()))?;
+        
+        share_link_from_row(&row)
+    }
+    
+    async fn get_user_share_links(&self, owner_id: &str) -> Result<Vec<ShareLink>, DbError> {
+        let rows = sqlx::query(&format!("SELECT {} FROM share_links WHERE owner_id = ? ORDER BY CAST(created_at AS INTEGER) DESC", SHARE_LINK_COLUMNS))
+            .bind(owner_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(share_link_from_row).collect()
+    }
+    
+    async fn delete_share_link(&self, token: &str, owner_id: &str) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM share_links WHERE id = ? AND owner_id = ?")
+            .bind(token)
+            .bind(owner_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete share link: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::DatabaseError("Share link not found".to_string()));
+        }
+        
+        Ok(())
+    }
+    
+    // Favourites and ratings
+    async fn save_user_rating(&self, rating: &UserRating) -> Result<(), DbError> {
+        let album = rating.item.album().unwrap_or_default();
+        
+        if rating.is_empty() {
+            sqlx::query("DELETE FROM user_ratings WHERE user_id = ? AND item_type = ? AND item_id = ? AND album = ?")
+                .bind(&rating.user_id)
+                .bind(rating.item.kind())
+                .bind(rating.item.item_id())
+                .bind(album)
+                .execute(&self.pool)
+                .await
+                .map_err(|e| DbError::DatabaseError(format!("Failed to delete rating: {}", e)))?;
             
-            playlists.push(Playlist {
-                id: row.get("id"),
-                name: row.get("name"),
-                owner_id: row.get("owner_id"),
-                owner_username: row.get("owner_username"),
-                is_public: row.get::<i32, _>("is_public") != 0,
-                created_at,
-            });
+            return Ok(());
+        }
+        
+        sqlx::query(
+            r#"
+            INSERT INTO user_ratings (user_id, item_type, item_id, album, favorite, rating, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (user_id, item_type, item_id, album)
+            DO UPDATE SET favorite = excluded.favorite, rating = excluded.rating, updated_at = excluded.updated_at
+            "#
+        )
+        .bind(&rating.user_id)
+        .bind(rating.item.kind())
+        .bind(rating.item.item_id())
+        .bind(album)
+        .bind(if rating.favorite { 1 } else { 0 })
+        .bind(rating.rating.map(i32::from))
+        .bind(rating.updated_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to save rating: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_user_ratings(&self, user_id: &str, kind: Option<&str>) -> Result<Vec<UserRating>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM user_ratings WHERE user_id = ", RATING_COLUMNS));
+        builder.push_bind(user_id);
+        
+        if let Some(kind) = kind {
+            builder.push(" AND item_type = ").push_bind(kind);
+        }
+        
+        builder.push(" ORDER BY CAST(updated_at AS INTEGER) DESC, rowid DESC");
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(rating_from_row).collect()
+    }
+    
+    async fn get_user_ratings_for(&self, user_id: &str, kind: &str, item_ids: &[String]) -> Result<Vec<UserRating>, DbError> {
+        if item_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM user_ratings WHERE user_id = ", RATING_COLUMNS));
+        builder.push_bind(user_id);
+        builder.push(" AND item_type = ").push_bind(kind);
+        builder.push(" AND item_id IN (");
+        
+        let mut ids = builder.separated(", ");
+        for item_id in item_ids {
+            ids.push_bind(item_id);
+        }
+        ids.push_unseparated(")");
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        rows.iter().map(rating_from_row).collect()
+    }
+    
+    // Play queue operations
+    async fn get_play_queue(&self, user_id: &str) -> Result<PlayQueue, DbError> {
+        let row = sqlx::query(&format!("SELECT {} FROM play_queues WHERE user_id = ?", PLAY_QUEUE_COLUMNS))
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        match row {
+            Some(row) => play_queue_from_row(&row),
+            None => Ok(PlayQueue::empty(user_id)),
+        }
+    }
+    
+    async fn save_play_queue(&self, queue: &PlayQueue, expected_version: Option<i64>) -> Result<PlayQueue, DbError> {
+        let song_ids = serde_json::to_string(&queue.song_ids)
+            .map_err(|e| DbError::DatabaseError(format!("Failed to encode play queue: {}", e)))?;
+        let updated_at = OffsetDateTime::now_utc();
+        
+        let query = match expected_version {
+            // Only a queue still at the expected version is replaced
+            Some(version) if version > 0 => sqlx::query(
+                r#"
+                UPDATE play_queues
+                SET song_ids = ?2, current_index = ?3, position_ms = ?4, device = ?5, version = version + 1, updated_at = ?6
+                WHERE user_id = ?1 AND version = ?7
+                "#
+            ),
+            // Version 0 means the client expects no queue to exist yet
+            _ => sqlx::query(
+                r#"
+                INSERT INTO play_queues (user_id, song_ids, current_index, position_ms, device, version, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    song_ids = excluded.song_ids,
+                    current_index = excluded.current_index,
+                    position_ms = excluded.position_ms,
+                    device = excluded.device,
+                    version = play_queues.version + 1,
+                    updated_at = excluded.updated_at
+                WHERE ?7 IS NULL
+                "#
+            ),
+        };
+        
+        let result = query
+            .bind(&queue.user_id)
+            .bind(song_ids)
+            .bind(queue.current_index as i64)
+            .bind(queue.position_ms)
+            .bind(&queue.device)
+            .bind(updated_at.unix_timestamp().to_string())
+            .bind(expected_version)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to save play queue: {}", e)))?;
+        
+        if result.rows_affected() == 0 {
+            return Err(DbError::VersionConflict);
         }
         
-        Ok(playlists)
+        self.get_play_queue(&queue.user_id).await
+    }
+    
+    // Lyrics operations
+    async fn get_song_lyrics(&self, song_id: &str) -> Result<Option<SongLyrics>, DbError> {
+        let row = sqlx::query("SELECT song_id, text, synced, source, updated_at FROM song_lyrics WHERE song_id = ?")
+            .bind(song_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        row.as_ref().map(lyrics_from_row).transpose()
+    }
+    
+    async fn save_song_lyrics(&self, lyrics: &SongLyrics) -> Result<(), DbError> {
+        sqlx::query(
+            r#"
+            INSERT INTO song_lyrics (song_id, text, synced, source, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (song_id) DO UPDATE SET
+                text = excluded.text,
+                synced = excluded.synced,
+                source = excluded.source,
+                updated_at = excluded.updated_at
+            "#
+        )
+        .bind(&lyrics.song_id)
+        .bind(&lyrics.text)
+        .bind(if lyrics.synced { 1 } else { 0 })
+        .bind(lyrics.source.as_str())
+        .bind(lyrics.updated_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to save lyrics: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn delete_song_lyrics(&self, song_id: &str) -> Result<(), DbError> {
+        sqlx::query("DELETE FROM song_lyrics WHERE song_id = ?")
+            .bind(song_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Failed to delete lyrics: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    // Admin playlist operations
+    async fn get_all_playlists(&self, page: &PageRequest) -> Result<Page<Playlist>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            format!("SELECT {} FROM playlists", PLAYLIST_COLUMNS)
+        );
+        push_page_clause(&mut builder, sort_column(page.sort, "name"), page, false);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let playlists = rows.iter().map(playlist_from_row).collect::<Result<Vec<_>, _>>()?;
+        let total = self.get_total_playlists().await?;
+        
+        Ok(Page::from_rows(playlists, page, total))
     }
     
     async fn get_total_playlists(&self) -> Result<usize, DbError> {
@@ -1174,6 +3029,22 @@ impl Database for SqliteDatabase {
         Ok(())
     }
     
+    async fn update_playlist_rules(&self, playlist_id: &str, rules: &SmartRules) -> Result<i64, DbError> {
+        let rules_json = rules_to_json(rules)?;
+        
+        let row = sqlx::query(
+            "UPDATE playlists SET rules = ?, version = version + 1 WHERE id = ? AND is_smart != 0 RETURNING version"
+        )
+        .bind(&rules_json)
+        .bind(playlist_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to update playlist rules: {}", e)))?
+        .ok_or(DbError::DatabaseError("Playlist not found".to_string()))?;
+        
+        Ok(row.get("version"))
+    }
+    
     async fn delete_playlist_by_id(&self, playlist_id: &str) -> Result<(), DbError> {
         let result = sqlx::query("DELETE FROM playlists WHERE id = ?")
             .bind(playlist_id)
@@ -1187,4 +3058,144 @@ impl Database for SqliteDatabase {
         
         Ok(())
     }
+    
+    async fn record_audit_event(&self, event: &AuditEvent) -> Result<(), DbError> {
+        sqlx::query(
+            "INSERT INTO audit_log (id, actor_id, actor, action, target, before_value, after_value, ip, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&event.id)
+        .bind(&event.actor_id)
+        .bind(&event.actor)
+        .bind(event.action.as_str())
+        .bind(&event.target)
+        .bind(event.before.as_ref().map(|value| value.to_string()))
+        .bind(event.after.as_ref().map(|value| value.to_string()))
+        .bind(&event.ip)
+        .bind(event.created_at.unix_timestamp().to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::DatabaseError(format!("Failed to record audit event: {}", e)))?;
+        
+        Ok(())
+    }
+    
+    async fn get_audit_events(&self, filter: &AuditFilter, page: &PageRequest) -> Result<Page<AuditEvent>, DbError> {
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM audit_log", AUDIT_COLUMNS));
+        let has_where = push_audit_filter(&mut builder, filter);
+        push_page_clause(&mut builder, sort_column(SortField::Added, "created_at"), page, has_where);
+        
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        let events = rows.iter().map(audit_event_from_row).collect::<Result<Vec<_>, _>>()?;
+        
+        let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM audit_log");
+        push_audit_filter(&mut count, filter);
+        let total: i64 = count.build_query_scalar()
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| DbError::DatabaseError(format!("Database query failed: {}", e)))?;
+        
+        Ok(Page::from_rows(events, page, total as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::db::{create_database, DbBackend, DEFAULT_LIBRARY_ID};
+    use super::*;
+
+    /// (title, artist, album, genre, year, duration, plays)
+    type Tags = (&'static str, &'static str, Option<&'static str>, Option<&'static str>, Option<i32>, Option<i32>, usize);
+
+    const SONGS: [Tags; 6] = [
+        ("So What", "Miles Davis", Some("Kind of Blue"), Some("jazz"), Some(1959), Some(562), 0),
+        ("Short", "Miles Davis", None, Some("Jazz"), Some(1959), Some(120), 1),
+        ("Modern", "Robert Glasper", Some("black radio"), Some("Jazz"), Some(2012), Some(400), 2),
+        ("Untagged", "Unknown", None, None, None, None, 3),
+        ("Blue in Green", "Miles Davis", Some("Kind of Blue (Legacy)"), Some("Jazz"), Some(1959), Some(337), 0),
+        ("100% Pure", "Band", Some("a_side"), Some("Rock"), Some(1970), Some(10), 5),
+    ];
+
+    async fn library() -> Arc<dyn Database> {
+        let path = std::env::temp_dir().join(format!("muse-smart-{}.db", Uuid::new_v4()));
+        let db = create_database(DbBackend::SQLite, &format!("sqlite:{}?mode=rwc", path.display())).await.unwrap();
+
+        for (title, artist, album, genre, year, duration, plays) in SONGS {
+            let artist = match db.get_artist_by_name(artist).await {
+                Ok(artist) => artist,
+                Err(_) => db.create_artist(artist).await.unwrap(),
+            };
+            let song = db.create_song(title, &artist.id, &format!("/music/{}.flac", title), DEFAULT_LIBRARY_ID).await.unwrap();
+            db.update_song_metadata(&song.id, album, duration, None).await.unwrap();
+            db.update_song_tags(&song.id, None, genre, year).await.unwrap();
+            for _ in 0..plays {
+                db.increment_song_play_count(&song.id).await.unwrap();
+            }
+        }
+        db
+    }
+
+    async fn titles(db: &Arc<dyn Database>, rules: &str) -> Vec<String> {
+        let rules = SmartRules::parse(rules).unwrap();
+        db.get_smart_playlist_songs(&rules, &LibraryFilter::All).await.unwrap()
+            .into_iter()
+            .map(|song| song.title)
+            .collect()
+    }
+
+    async fn sorted_titles(db: &Arc<dyn Database>, rules: &str) -> Vec<String> {
+        let mut titles = titles(db, rules).await;
+        titles.sort();
+        titles
+    }
+
+    #[tokio::test]
+    async fn test_smart_playlist_filter() {
+        let db = library().await;
+
+        assert_eq!(
+            titles(&db, "genre = Jazz AND year < 1970 AND duration > 300 ORDER BY duration LIMIT 5").await,
+            vec!["Blue in Green", "So What"],
+        );
+        // Untagged songs only match `!=`, and NOT of a condition on them
+        assert_eq!(sorted_titles(&db, "genre != Jazz").await, vec!["100% Pure", "Untagged"]);
+        assert_eq!(sorted_titles(&db, "NOT genre = jazz").await, vec!["100% Pure", "Untagged"]);
+        assert_eq!(sorted_titles(&db, "genre < k").await, vec!["Blue in Green", "Modern", "Short", "So What"]);
+        // LIKE wildcards in the value match literally
+        assert_eq!(titles(&db, "album CONTAINS _").await, vec!["100% Pure"]);
+        assert_eq!(titles(&db, "title ~ \"%\"").await, vec!["100% Pure"]);
+        assert_eq!(
+            sorted_titles(&db, "(artist ~ davis OR plays >= 5) AND NOT album ~ legacy").await,
+            vec!["100% Pure", "Short", "So What"],
+        );
+        assert_eq!(titles(&db, "added > 0 AND duration = 400").await, vec!["Modern"]);
+
+        let rules = SmartRules::parse("genre = jazz").unwrap();
+        let none = db.get_smart_playlist_songs(&rules, &LibraryFilter::Only(Vec::new())).await.unwrap();
+        assert!(none.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_smart_playlist_order_and_limit() {
+        let db = library().await;
+
+        // Text orders case-insensitively, and songs without a value come last either way
+        assert_eq!(titles(&db, "ORDER BY album LIMIT 4").await, vec!["100% Pure", "Modern", "So What", "Blue in Green"]);
+        assert_eq!(titles(&db, "ORDER BY album DESC LIMIT 4").await, vec!["Blue in Green", "So What", "Modern", "100% Pure"]);
+        assert_eq!(titles(&db, "ORDER BY year DESC LIMIT 2").await, vec!["Modern", "100% Pure"]);
+        assert_eq!(titles(&db, "plays >= 2 ORDER BY plays DESC").await, vec!["100% Pure", "Untagged", "Modern"]);
+        assert_eq!(titles(&db, "LIMIT 2").await.len(), 2);
+
+        assert_eq!(sorted_titles(&db, "genre = jazz ORDER BY random").await, vec!["Blue in Green", "Modern", "Short", "So What"]);
+        let mut random = titles(&db, "ORDER BY random LIMIT 3").await;
+        assert_eq!(random.len(), 3);
+        random.sort();
+        random.dedup();
+        assert_eq!(random.len(), 3);
+    }
 }
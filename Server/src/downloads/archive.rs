@@ -0,0 +1,382 @@
+//! ZIP and tar writers for download bundles. Audio is already compressed,
+//! so files are stored as they are, which also keeps the archive's bytes the
+//! same every time it's served and lets downloads resume with ranges.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+const ZIP_LOCAL_HEADER: u32 = 0x04034b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+/// Version 1.0, as stored entries need nothing newer
+const ZIP_VERSION: u16 = 10;
+/// Names are UTF-8
+const ZIP_FLAGS: u16 = 1 << 11;
+
+const TAR_BLOCK: usize = 512;
+/// Sizes are 11 octal digits
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar" => Some(ArchiveFormat::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("The download is too large for a ZIP file, use tar instead")]
+    TooLarge,
+
+    #[error("File name too long for the archive: {0}")]
+    NameTooLong(String),
+}
+
+/// A file added to an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedFile {
+    pub size: u64,
+    /// Hex SHA-256 of the file's contents
+    pub sha256: String,
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes an uncompressed ZIP or ustar archive. Each file's header is
+/// written ahead of it and filled in once the file has been copied, so
+/// files of unknown length are read only once.
+pub struct ArchiveWriter<W: Write + Seek> {
+    out: W,
+    format: ArchiveFormat,
+    position: u64,
+    modified: OffsetDateTime,
+    zip_entries: Vec<ZipEntry>,
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    /// Every file is given the modification time `modified`
+    pub fn new(out: W, format: ArchiveFormat, modified: OffsetDateTime) -> Self {
+        Self { out, format, position: 0, modified, zip_entries: Vec::new() }
+    }
+
+    /// Copy everything `data` reads into the archive as a file called `name`
+    pub fn add(&mut self, name: &str, data: &mut impl Read) -> Result<ArchivedFile, ArchiveError> {
+        match self.format {
+            ArchiveFormat::Zip => self.add_zip(name, data),
+            ArchiveFormat::Tar => self.add_tar(name, data),
+        }
+    }
+
+    /// Write the end of the archive, returning what it was written to
+    pub fn finish(mut self) -> Result<W, ArchiveError> {
+        match self.format {
+            ArchiveFormat::Zip => self.finish_zip()?,
+            ArchiveFormat::Tar => self.out.write_all(&[0u8; TAR_BLOCK * 2])?,
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn add_zip(&mut self, name: &str, data: &mut impl Read) -> Result<ArchivedFile, ArchiveError> {
+        let offset = u32::try_from(self.position).map_err(|_| ArchiveError::TooLarge)?;
+        if self.zip_entries.len() >= u16::MAX as usize {
+            return Err(ArchiveError::TooLarge);
+        }
+        let name_len = u16::try_from(name.len()).map_err(|_| ArchiveError::NameTooLong(name.to_string()))?;
+        let (time, date) = dos_time(self.modified);
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(ZIP_LOCAL_HEADER.to_le_bytes());
+        header.extend(ZIP_VERSION.to_le_bytes());
+        header.extend(ZIP_FLAGS.to_le_bytes());
+        // Stored
+        header.extend(0u16.to_le_bytes());
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        // CRC and sizes, filled in after the data
+        header.extend([0u8; 12]);
+        header.extend(name_len.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.out.write_all(&header)?;
+
+        let (file, crc) = copy_hashed(data, &mut self.out)?;
+        let size = u32::try_from(file.size).map_err(|_| ArchiveError::TooLarge)?;
+        self.position += header.len() as u64 + file.size;
+
+        let mut sizes = Vec::with_capacity(12);
+        sizes.extend(crc.to_le_bytes());
+        sizes.extend(size.to_le_bytes());
+        sizes.extend(size.to_le_bytes());
+        self.patch(offset as u64 + 14, &sizes)?;
+
+        self.zip_entries.push(ZipEntry { name: name.to_string(), crc, size, offset });
+        Ok(file)
+    }
+
+    fn finish_zip(&mut self) -> Result<(), ArchiveError> {
+        let directory_offset = u32::try_from(self.position).map_err(|_| ArchiveError::TooLarge)?;
+        let (time, date) = dos_time(self.modified);
+
+        let mut directory = Vec::new();
+        for entry in &self.zip_entries {
+            directory.extend(ZIP_CENTRAL_HEADER.to_le_bytes());
+            // Made by and needed to extract
+            directory.extend(ZIP_VERSION.to_le_bytes());
+            directory.extend(ZIP_VERSION.to_le_bytes());
+            directory.extend(ZIP_FLAGS.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(time.to_le_bytes());
+            directory.extend(date.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            // Extra field, comment, disk number, internal and external attributes
+            directory.extend([0u8; 12]);
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let directory_len = u32::try_from(directory.len()).map_err(|_| ArchiveError::TooLarge)?;
+        if directory_offset.checked_add(directory_len).is_none() {
+            return Err(ArchiveError::TooLarge);
+        }
+
+        let count = self.zip_entries.len() as u16;
+        directory.extend(ZIP_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        // This disk and the disk the directory starts on
+        directory.extend([0u8; 4]);
+        directory.extend(count.to_le_bytes());
+        directory.extend(count.to_le_bytes());
+        directory.extend(directory_len.to_le_bytes());
+        directory.extend(directory_offset.to_le_bytes());
+        // Comment length
+        directory.extend(0u16.to_le_bytes());
+
+        self.out.write_all(&directory)?;
+        self.position += directory.len() as u64;
+        Ok(())
+    }
+
+    fn add_tar(&mut self, name: &str, data: &mut impl Read) -> Result<ArchivedFile, ArchiveError> {
+        let start = self.position;
+        // Checked before anything is written, and written again with the size
+        let header = tar_header(name, 0, self.modified)?;
+        self.out.write_all(&header)?;
+
+        let (file, _) = copy_hashed(data, &mut self.out)?;
+        if file.size > TAR_MAX_SIZE {
+            return Err(ArchiveError::TooLarge);
+        }
+        let padding = (TAR_BLOCK - file.size as usize % TAR_BLOCK) % TAR_BLOCK;
+        self.out.write_all(&[0u8; TAR_BLOCK][..padding])?;
+        self.position += (TAR_BLOCK + padding) as u64 + file.size;
+
+        self.patch(start, &tar_header(name, file.size, self.modified)?)?;
+        Ok(file)
+    }
+
+    /// Overwrite bytes already written, then carry on at the end
+    fn patch(&mut self, at: u64, bytes: &[u8]) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(at))?;
+        self.out.write_all(bytes)?;
+        self.out.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+}
+
+/// Copy `data` to `out`, hashing it with SHA-256 and CRC-32 on the way
+fn copy_hashed(data: &mut impl Read, out: &mut impl Write) -> io::Result<(ArchivedFile, u32)> {
+    let mut sha256 = Sha256::new();
+    let mut crc = crc32fast::Hasher::new();
+    let mut size = 0u64;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        sha256.update(&buf[..read]);
+        crc.update(&buf[..read]);
+        out.write_all(&buf[..read])?;
+        size += read as u64;
+    }
+    let sha256 = sha256.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    Ok((ArchivedFile { size, sha256 }, crc.finalize()))
+}
+
+/// MS-DOS time and date, which can't go before 1980
+fn dos_time(at: OffsetDateTime) -> (u16, u16) {
+    if at.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = ((at.hour() as u16) << 11) | ((at.minute() as u16) << 5) | (at.second() as u16 / 2);
+    let date = (((at.year() - 1980).min(127) as u16) << 9) | ((at.month() as u16) << 5) | at.day() as u16;
+    (time, date)
+}
+
+/// A ustar header. Names longer than 100 bytes are split at a `/` into the
+/// prefix field.
+fn tar_header(name: &str, size: u64, modified: OffsetDateTime) -> Result<[u8; TAR_BLOCK], ArchiveError> {
+    let (prefix, name) = split_tar_name(name).ok_or_else(|| ArchiveError::NameTooLong(name.to_string()))?;
+
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    // Owner and group
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size.min(TAR_MAX_SIZE)).as_bytes());
+    let mtime = modified.unix_timestamp().clamp(0, 0o77777777777);
+    header[136..148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+    // A regular file
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is summed with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+fn split_tar_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    name.match_indices('/')
+        .map(|(at, _)| (&name[..at], &name[at + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && !rest.is_empty() && rest.len() <= 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// 2024-03-05 14:30:20 UTC
+    const MODIFIED: i64 = 1709649020;
+
+    fn modified() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(MODIFIED).unwrap()
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_zip_layout() {
+        let mut zip = ArchiveWriter::new(Cursor::new(Vec::new()), ArchiveFormat::Zip, modified());
+        let hello = zip.add("music/hello.txt", &mut "hello".as_bytes()).unwrap();
+        zip.add("empty", &mut io::empty()).unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+
+        assert_eq!(hello.size, 5);
+        assert_eq!(hello.sha256, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+
+        // The local header, filled in after the data
+        assert_eq!(u32_at(&bytes, 0), ZIP_LOCAL_HEADER);
+        assert_eq!(u32_at(&bytes, 14), 0x3610a686);
+        assert_eq!(u32_at(&bytes, 18), 5);
+        assert_eq!(u32_at(&bytes, 22), 5);
+        assert_eq!(&bytes[30..45], b"music/hello.txt");
+        assert_eq!(&bytes[45..50], b"hello");
+        assert_eq!(u16_at(&bytes, 10), (14 << 11) | (30 << 5) | 10);
+        assert_eq!(u16_at(&bytes, 12), (44 << 9) | (3 << 5) | 5);
+
+        // The end of central directory record points back at both entries
+        let end = bytes.len() - 22;
+        assert_eq!(u32_at(&bytes, end), ZIP_END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u16_at(&bytes, end + 10), 2);
+        let directory = u32_at(&bytes, end + 16) as usize;
+        assert_eq!(directory + u32_at(&bytes, end + 12) as usize, end);
+        assert_eq!(u32_at(&bytes, directory), ZIP_CENTRAL_HEADER);
+        assert_eq!(u32_at(&bytes, directory + 16), 0x3610a686);
+        assert_eq!(u32_at(&bytes, directory + 42), 0);
+        let second = directory + 46 + 15;
+        assert_eq!(u32_at(&bytes, second + 42), 50);
+        assert_eq!(u32_at(&bytes, 50), ZIP_LOCAL_HEADER);
+    }
+
+    #[test]
+    fn test_tar_layout() {
+        let mut tar = ArchiveWriter::new(Cursor::new(Vec::new()), ArchiveFormat::Tar, modified());
+        let data = vec![7u8; 600];
+        tar.add("music/a.flac", &mut data.as_slice()).unwrap();
+        tar.add("manifest.json", &mut "{}".as_bytes()).unwrap();
+        let bytes = tar.finish().unwrap().into_inner();
+
+        // Header, two blocks of data, header, one block, and two empty blocks
+        assert_eq!(bytes.len(), 512 * 7);
+        assert_eq!(&bytes[..12], b"music/a.flac");
+        assert_eq!(&bytes[124..136], b"00000001130\0");
+        assert_eq!(&bytes[257..262], b"ustar");
+        assert_eq!(&bytes[512..1112], data.as_slice());
+        assert!(bytes[1112..1536].iter().all(|&b| b == 0));
+        assert_eq!(&bytes[1536..1549], b"manifest.json");
+        assert!(bytes[512 * 5..].iter().all(|&b| b == 0));
+
+        let checksum = std::str::from_utf8(&bytes[148..154]).unwrap();
+        let mut summed = bytes[..512].to_vec();
+        summed[148..156].copy_from_slice(b"        ");
+        assert_eq!(u32::from_str_radix(checksum, 8).unwrap(), summed.iter().map(|&b| b as u32).sum::<u32>());
+    }
+
+    #[test]
+    fn test_long_tar_names() {
+        let dir = "d".repeat(120);
+        let name = format!("{}/song.mp3", dir);
+        assert_eq!(split_tar_name(&name), Some((dir.as_str(), "song.mp3")));
+        assert_eq!(split_tar_name(&"n".repeat(101)), None);
+
+        let header = tar_header(&name, 0, modified()).unwrap();
+        assert_eq!(&header[..8], b"song.mp3");
+        assert_eq!(&header[345..465], dir.as_bytes());
+
+        let mut tar = ArchiveWriter::new(Cursor::new(Vec::new()), ArchiveFormat::Tar, modified());
+        assert!(matches!(tar.add(&"n".repeat(300), &mut io::empty()), Err(ArchiveError::NameTooLong(_))));
+    }
+}
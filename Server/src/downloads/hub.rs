@@ -0,0 +1,373 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::db::models::Song;
+use crate::downloads::archive::{ArchiveError, ArchiveFormat, ArchiveWriter, ArchivedFile};
+use crate::downloads::manifest::{
+    cover_path, sanitize_name, song_path, song_revision, Manifest, ManifestSong, MANIFEST_NAME, MANIFEST_VERSION,
+};
+use crate::downloads::transcode::{prepare, source_extension, Prepared, TranscodeProfile};
+use crate::music::seek::file_stamp;
+use crate::music::slicing::Slice;
+
+const DOWNLOAD_CACHE_DIR: &str = "runtime/cache/downloads";
+/// How long a download is kept once asked for
+const DOWNLOAD_TTL: Duration = Duration::hours(24);
+/// Downloads built at once, as each can keep an encoder busy
+const MAX_CONCURRENT_BUILDS: usize = 2;
+/// Downloads a user can have at a time
+const MAX_DOWNLOADS_PER_USER: usize = 10;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DownloadError {
+    #[error("Download not found")]
+    NotFound,
+
+    #[error("The download is not ready yet")]
+    NotReady,
+
+    #[error("Too many downloads, delete one first")]
+    TooMany,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadStatus {
+    /// Waiting for another download to finish building
+    Pending,
+    Building,
+    Ready,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadInfo {
+    pub id: String,
+    /// What the download was made from: `playlist`, `album` or `songs`
+    pub source: String,
+    pub name: String,
+    pub profile: String,
+    pub format: String,
+    pub status: DownloadStatus,
+    pub songs: usize,
+    /// Songs added so far
+    pub completed: usize,
+    /// Size of the archive once ready
+    pub size: Option<u64>,
+    pub error: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+}
+
+impl DownloadInfo {
+    /// The archive's name when saved
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", sanitize_name(&self.name), self.format)
+    }
+}
+
+/// What goes into a download
+pub struct DownloadRequest {
+    pub source: String,
+    pub name: String,
+    pub songs: Vec<Song>,
+    pub profile: TranscodeProfile,
+    pub format: ArchiveFormat,
+}
+
+struct Job {
+    user_id: String,
+    info: DownloadInfo,
+}
+
+type Writer = ArchiveWriter<BufWriter<File>>;
+
+/// Where a file in a download is read from
+enum Source {
+    /// A region of a file after a new header
+    Slice(PathBuf, Slice),
+    File(PathBuf),
+}
+
+impl Source {
+    fn open(&self) -> io::Result<Box<dyn Read>> {
+        match self {
+            Source::Slice(path, slice) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(slice.offset))?;
+                Ok(Box::new(Cursor::new(slice.header.clone()).chain(file.take(slice.len))))
+            }
+            Source::File(path) => Ok(Box::new(File::open(path)?)),
+        }
+    }
+}
+
+/// Builds download archives in the background. Downloads are kept in memory
+/// and on disk for a day, and only their owner can see them.
+pub struct DownloadHub {
+    ffmpeg: String,
+    downloads: Mutex<HashMap<String, Job>>,
+    builds: Semaphore,
+}
+
+impl DownloadHub {
+    pub fn new(ffmpeg: impl Into<String>) -> Self {
+        // Archives from a previous run can't be reached any more
+        let _ = std::fs::remove_dir_all(DOWNLOAD_CACHE_DIR);
+        Self {
+            ffmpeg: ffmpeg.into(),
+            downloads: Mutex::new(HashMap::new()),
+            builds: Semaphore::new(MAX_CONCURRENT_BUILDS),
+        }
+    }
+
+    /// Start building a download, which is pending until a build slot frees up
+    pub fn create(self: &Arc<Self>, user_id: &str, request: DownloadRequest) -> Result<DownloadInfo, DownloadError> {
+        self.prune();
+        let now = OffsetDateTime::now_utc();
+        let info = DownloadInfo {
+            id: Uuid::new_v4().to_string(),
+            source: request.source.clone(),
+            name: request.name.clone(),
+            profile: request.profile.to_string(),
+            format: request.format.extension().to_string(),
+            status: DownloadStatus::Pending,
+            songs: request.songs.len(),
+            completed: 0,
+            size: None,
+            error: None,
+            created_at: now,
+            expires_at: now + DOWNLOAD_TTL,
+        };
+
+        {
+            let mut downloads = self.downloads.lock().unwrap();
+            if downloads.values().filter(|job| job.user_id == user_id).count() >= MAX_DOWNLOADS_PER_USER {
+                return Err(DownloadError::TooMany);
+            }
+            downloads.insert(info.id.clone(), Job { user_id: user_id.to_string(), info: info.clone() });
+        }
+
+        tokio::spawn(self.clone().build(info.id.clone(), request));
+        Ok(info)
+    }
+
+    /// A user's downloads, newest first
+    pub fn list(&self, user_id: &str) -> Vec<DownloadInfo> {
+        self.prune();
+        let mut downloads: Vec<DownloadInfo> = self.downloads.lock().unwrap().values()
+            .filter(|job| job.user_id == user_id)
+            .map(|job| job.info.clone())
+            .collect();
+        downloads.sort_by_key(|download| std::cmp::Reverse(download.created_at));
+        downloads
+    }
+
+    pub fn get(&self, user_id: &str, id: &str) -> Result<DownloadInfo, DownloadError> {
+        self.prune();
+        self.downloads.lock().unwrap().get(id)
+            .filter(|job| job.user_id == user_id)
+            .map(|job| job.info.clone())
+            .ok_or(DownloadError::NotFound)
+    }
+
+    /// Where a finished download's archive is
+    pub fn archive(&self, user_id: &str, id: &str) -> Result<(PathBuf, DownloadInfo), DownloadError> {
+        let info = self.get(user_id, id)?;
+        if info.status != DownloadStatus::Ready {
+            return Err(DownloadError::NotReady);
+        }
+        Ok((archive_path(&info), info))
+    }
+
+    /// Delete a download, stopping its build if it's still going
+    pub fn remove(&self, user_id: &str, id: &str) -> Result<(), DownloadError> {
+        let mut downloads = self.downloads.lock().unwrap();
+        if downloads.get(id).is_none_or(|job| job.user_id != user_id) {
+            return Err(DownloadError::NotFound);
+        }
+        let job = downloads.remove(id).expect("download was just found");
+        // Unfinished builds clean up after themselves when they notice
+        if job.info.status == DownloadStatus::Ready {
+            let _ = std::fs::remove_file(archive_path(&job.info));
+        }
+        Ok(())
+    }
+
+    /// Forget finished downloads that have expired
+    fn prune(&self) {
+        let now = OffsetDateTime::now_utc();
+        let mut downloads = self.downloads.lock().unwrap();
+        downloads.retain(|_, job| {
+            let finished = matches!(job.info.status, DownloadStatus::Ready | DownloadStatus::Failed);
+            if finished && job.info.expires_at <= now {
+                let _ = std::fs::remove_file(archive_path(&job.info));
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Change a download, returning false when it has been deleted
+    fn update(&self, id: &str, change: impl FnOnce(&mut DownloadInfo)) -> bool {
+        match self.downloads.lock().unwrap().get_mut(id) {
+            Some(job) => {
+                change(&mut job.info);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn build(self: Arc<Self>, id: String, request: DownloadRequest) {
+        let _permit = self.builds.acquire().await;
+        if !self.update(&id, |info| info.status = DownloadStatus::Building) {
+            return;
+        }
+        tracing::info!("Building {} download {} of {} songs", request.profile, id, request.songs.len());
+
+        let dir = Path::new(DOWNLOAD_CACHE_DIR);
+        let partial = dir.join(format!("{}.partial", id));
+        let work_dir = dir.join(format!("{}.work", id));
+        let result = self.write_archive(&id, &request, &partial, &work_dir).await;
+        let _ = tokio::fs::remove_dir_all(&work_dir).await;
+
+        let finished = match result {
+            Ok(Some(size)) => {
+                let path = dir.join(format!("{}.{}", id, request.format.extension()));
+                match tokio::fs::rename(&partial, &path).await {
+                    Ok(()) => Ok(size),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            // Deleted while building
+            Ok(None) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return;
+            }
+            Err(e) => Err(e.to_string()),
+        };
+
+        let still_wanted = self.update(&id, |info| match &finished {
+            Ok(size) => {
+                info.status = DownloadStatus::Ready;
+                info.size = Some(*size);
+            }
+            Err(e) => {
+                info.status = DownloadStatus::Failed;
+                info.error = Some(e.clone());
+            }
+        });
+        match finished {
+            Ok(_) if !still_wanted => {
+                let _ = tokio::fs::remove_file(dir.join(format!("{}.{}", id, request.format.extension()))).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("Failed to build download {}: {}", id, e);
+                let _ = tokio::fs::remove_file(&partial).await;
+            }
+        }
+    }
+
+    /// Write every song, its cover and the manifest into an archive at
+    /// `path`, returning its size, or `None` if the download was deleted
+    async fn write_archive(&self, id: &str, request: &DownloadRequest, path: &Path, work_dir: &Path) -> Result<Option<u64>, ArchiveError> {
+        tokio::fs::create_dir_all(work_dir).await?;
+        let created_at = OffsetDateTime::now_utc();
+        let file = File::create(path)?;
+        let mut writer = ArchiveWriter::new(BufWriter::new(file), request.format, created_at);
+
+        let mut songs = Vec::with_capacity(request.songs.len());
+        let mut covers: HashMap<String, String> = HashMap::new();
+        for (index, song) in request.songs.iter().enumerate() {
+            let stamp = file_stamp(Path::new(&song.file_path))?;
+            let extension = request.profile.extension(&source_extension(song)).to_string();
+
+            let (source, encoded) = match prepare(&self.ffmpeg, song, request.profile, work_dir).await? {
+                Prepared::Slice(slice) => (Source::Slice(PathBuf::from(&song.file_path), slice), None),
+                Prepared::Encoded(path) => (Source::File(path.clone()), Some(path)),
+            };
+            let name = song_path(index, song, &extension);
+            let (next, file) = add_file(writer, name.clone(), source).await?;
+            writer = next;
+            if let Some(encoded) = encoded {
+                let _ = tokio::fs::remove_file(encoded).await;
+            }
+
+            let cover = match &song.cover_image_path {
+                Some(cover) if covers.contains_key(cover) => covers.get(cover).cloned(),
+                Some(cover) if Path::new(cover).is_file() => {
+                    let extension = Path::new(cover).extension().and_then(|ext| ext.to_str()).unwrap_or("img");
+                    let cover_name = cover_path(song, extension);
+                    let (next, _) = add_file(writer, cover_name.clone(), Source::File(PathBuf::from(cover))).await?;
+                    writer = next;
+                    covers.insert(cover.clone(), cover_name.clone());
+                    Some(cover_name)
+                }
+                _ => None,
+            };
+
+            songs.push(ManifestSong {
+                id: song.id.clone(),
+                title: song.title.clone(),
+                artist: song.artist_name.clone(),
+                album: song.album.clone(),
+                genre: song.genre.clone(),
+                year: song.year,
+                duration: song.duration,
+                revision: song_revision(song, stamp, request.profile),
+                path: name,
+                size: file.size,
+                sha256: file.sha256,
+                cover,
+            });
+            if !self.update(id, |info| info.completed = index + 1) {
+                return Ok(None);
+            }
+        }
+
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            download_id: id.to_string(),
+            source: request.source.clone(),
+            name: request.name.clone(),
+            profile: request.profile.to_string(),
+            created_at: created_at.format(&Rfc3339).unwrap_or_default(),
+            songs,
+        };
+        let json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::other)?;
+        let manifest_path = work_dir.join(MANIFEST_NAME);
+        tokio::fs::write(&manifest_path, json).await?;
+        let (writer, _) = add_file(writer, MANIFEST_NAME.to_string(), Source::File(manifest_path)).await?;
+
+        let size = tokio::task::spawn_blocking(move || -> Result<u64, ArchiveError> {
+            let mut out = writer.finish()?;
+            Ok(out.stream_position()?)
+        }).await.map_err(io::Error::other)??;
+        Ok(Some(size))
+    }
+}
+
+/// Add a file to an archive on a blocking thread, handing the writer back
+async fn add_file(mut writer: Writer, name: String, source: Source) -> Result<(Writer, ArchivedFile), ArchiveError> {
+    tokio::task::spawn_blocking(move || {
+        let file = writer.add(&name, &mut source.open()?)?;
+        Ok((writer, file))
+    }).await.map_err(io::Error::other)?
+}
+
+fn archive_path(info: &DownloadInfo) -> PathBuf {
+    Path::new(DOWNLOAD_CACHE_DIR).join(format!("{}.{}", info.id, info.format))
+}
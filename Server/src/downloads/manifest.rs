@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::models::Song;
+use crate::downloads::transcode::TranscodeProfile;
+
+/// Where the manifest is in a download, after every file it describes
+pub const MANIFEST_NAME: &str = "manifest.json";
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Longest a file or folder name made from tags can be, in bytes, so paths
+/// fit any archive format and filesystem
+const MAX_NAME_LEN: usize = 80;
+
+/// Describes everything in a download, so a client can check the files and
+/// later ask what changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub download_id: String,
+    /// What the download was made from, such as `album`
+    pub source: String,
+    pub name: String,
+    pub profile: String,
+    /// RFC 3339
+    pub created_at: String,
+    pub songs: Vec<ManifestSong>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestSong {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub duration: Option<i32>,
+    /// Changes whenever the file in the download would, see [`song_revision`]
+    pub revision: String,
+    /// Path of the audio file in the archive
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Path of the cover in the archive
+    pub cover: Option<String>,
+}
+
+/// What a client with some revisions of a set of songs needs to do to match
+/// the set now
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Delta {
+    /// Songs it doesn't have
+    pub add: Vec<String>,
+    /// Songs it has an outdated file of
+    pub update: Vec<String>,
+    /// Songs no longer in the set
+    pub remove: Vec<String>,
+    pub unchanged: usize,
+}
+
+/// A short hash of everything that goes into a song's file in a download:
+/// the source file's size and modification time, the track's times within
+/// it, its tags and the profile it's converted with
+pub fn song_revision(song: &Song, file_stamp: (u64, u64), profile: TranscodeProfile) -> String {
+    let mut hash = Sha256::new();
+    let fields = [
+        file_stamp.0.to_string(),
+        file_stamp.1.to_string(),
+        format!("{:?} {:?}", song.start_ms, song.end_ms),
+        song.title.clone(),
+        song.artist_name.clone(),
+        song.album.clone().unwrap_or_default(),
+        song.genre.clone().unwrap_or_default(),
+        format!("{:?}", song.year),
+        profile.to_string(),
+    ];
+    for field in fields {
+        hash.update((field.len() as u64).to_be_bytes());
+        hash.update(field.as_bytes());
+    }
+    hash.finalize()[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compare the revisions a client `have`s against the `current` revision of
+/// each song in a set, in the set's order
+pub fn compute_delta(current: &[(String, String)], have: &HashMap<String, String>) -> Delta {
+    let mut delta = Delta::default();
+    let mut seen = HashSet::new();
+    for (id, revision) in current {
+        if !seen.insert(id.as_str()) {
+            continue;
+        }
+        match have.get(id) {
+            None => delta.add.push(id.clone()),
+            Some(have) if have != revision => delta.update.push(id.clone()),
+            Some(_) => delta.unchanged += 1,
+        }
+    }
+    let mut remove: Vec<String> = have.keys()
+        .filter(|id| !seen.contains(id.as_str()))
+        .cloned()
+        .collect();
+    remove.sort();
+    delta.remove = remove;
+    delta
+}
+
+/// Where the `index`th song of a download goes in the archive, such as
+/// `music/001 - Miles Davis - So What.mp3`
+pub fn song_path(index: usize, song: &Song, extension: &str) -> String {
+    let name = sanitize_name(&format!("{:03} - {} - {}", index + 1, song.artist_name, song.title));
+    format!("music/{}.{}", name, sanitize_name(extension))
+}
+
+/// Where a song's cover goes in the archive
+pub fn cover_path(song: &Song, extension: &str) -> String {
+    format!("covers/{}.{}", sanitize_name(&song.id), sanitize_name(extension))
+}
+
+/// Make a tag safe as a file name on any system: no separators, reserved or
+/// control characters, leading dots or trailing dots and spaces
+pub fn sanitize_name(name: &str) -> String {
+    let cleaned: String = name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let mut cleaned = cleaned.trim().trim_start_matches('.').to_string();
+    if cleaned.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !cleaned.is_char_boundary(end) {
+            end -= 1;
+        }
+        cleaned.truncate(end);
+    }
+    let cleaned = cleaned.trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() { "_".to_string() } else { cleaned.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str) -> Song {
+        Song {
+            id: "song1".to_string(),
+            artist_name: "Miles Davis".to_string(),
+            album: Some("Kind of Blue".to_string()),
+            duration: Some(545),
+            file_path: "/music/so_what.flac".to_string(),
+            genre: Some("Jazz".to_string()),
+            year: Some(1959),
+            ..Song::for_test(title)
+        }
+    }
+
+    #[test]
+    fn test_song_revision_changes_with_what_goes_into_the_file() {
+        let revision = song_revision(&song("So What"), (100, 5), TranscodeProfile::Original);
+        assert_eq!(revision.len(), 16);
+        assert_eq!(revision, song_revision(&song("So What"), (100, 5), TranscodeProfile::Original));
+
+        let changed = [
+            song_revision(&song("So What (Remastered)"), (100, 5), TranscodeProfile::Original),
+            song_revision(&song("So What"), (101, 5), TranscodeProfile::Original),
+            song_revision(&song("So What"), (100, 6), TranscodeProfile::Original),
+            song_revision(&song("So What"), (100, 5), TranscodeProfile::Mp3 { kbps: 320 }),
+            song_revision(&Song { start_ms: Some(0), ..song("So What") }, (100, 5), TranscodeProfile::Original),
+        ];
+        for other in changed {
+            assert_ne!(other, revision);
+        }
+    }
+
+    #[test]
+    fn test_compute_delta() {
+        let current = vec![
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "2".to_string()),
+            ("c".to_string(), "3".to_string()),
+            ("a".to_string(), "1".to_string()),
+        ];
+        let have = HashMap::from([
+            ("b".to_string(), "2".to_string()),
+            ("c".to_string(), "old".to_string()),
+            ("z".to_string(), "9".to_string()),
+            ("y".to_string(), "9".to_string()),
+        ]);
+        assert_eq!(compute_delta(&current, &have), Delta {
+            add: vec!["a".to_string()],
+            update: vec!["c".to_string()],
+            remove: vec!["y".to_string(), "z".to_string()],
+            unchanged: 1,
+        });
+        assert_eq!(compute_delta(&[], &HashMap::new()), Delta::default());
+    }
+
+    #[test]
+    fn test_paths_are_safe() {
+        assert_eq!(song_path(0, &song("So What"), "flac"), "music/001 - Miles Davis - So What.flac");
+        assert_eq!(song_path(11, &song("A/B: C?"), "mp3"), "music/012 - Miles Davis - A_B_ C_.mp3");
+        assert_eq!(sanitize_name("../.."), "_");
+        assert_eq!(sanitize_name("  ..hidden. "), "hidden");
+        assert_eq!(sanitize_name("tab\there"), "tab_here");
+
+        let long = song_path(0, &song(&"é".repeat(100)), "flac");
+        let name = long.strip_prefix("music/").unwrap().strip_suffix(".flac").unwrap();
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.ends_with('é'));
+        assert_eq!(cover_path(&song("So What"), "avif"), "covers/song1.avif");
+    }
+}
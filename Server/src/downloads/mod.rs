@@ -0,0 +1,6 @@
+pub mod archive;
+pub mod hub;
+pub mod manifest;
+pub mod transcode;
+
+pub use hub::DownloadHub;
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::db::models::Song;
+use crate::music::seek::load_seek_table;
+use crate::music::slicing::{slice_file, Slice};
+
+/// What songs are converted to for a download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeProfile {
+    /// The file as it is, with cue sheet tracks cut out of theirs
+    Original,
+    Mp3 { kbps: u32 },
+    Aac { kbps: u32 },
+    Opus { kbps: u32 },
+}
+
+impl TranscodeProfile {
+    /// `original`, or a codec and bitrate such as `mp3-320`, `aac-256` or `opus-96`
+    pub fn parse(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("original") {
+            return Some(TranscodeProfile::Original);
+        }
+        let (codec, kbps) = name.split_once('-')?;
+        let kbps = kbps.trim_end_matches(['k', 'K']).parse().ok().filter(|kbps| (8..=512).contains(kbps))?;
+        match codec.to_ascii_lowercase().as_str() {
+            "mp3" if kbps <= 320 => Some(TranscodeProfile::Mp3 { kbps }),
+            "aac" => Some(TranscodeProfile::Aac { kbps }),
+            "opus" => Some(TranscodeProfile::Opus { kbps }),
+            _ => None,
+        }
+    }
+
+    /// Extension of the files this profile makes from one with `source_extension`
+    pub fn extension<'a>(&self, source_extension: &'a str) -> &'a str {
+        match self {
+            TranscodeProfile::Original => source_extension,
+            TranscodeProfile::Mp3 { .. } => "mp3",
+            TranscodeProfile::Aac { .. } => "m4a",
+            TranscodeProfile::Opus { .. } => "opus",
+        }
+    }
+
+    fn encoder_args(&self) -> Vec<String> {
+        let (codec, kbps) = match *self {
+            TranscodeProfile::Original => return vec!["-c:a".to_string(), "copy".to_string()],
+            TranscodeProfile::Mp3 { kbps } => ("libmp3lame", kbps),
+            TranscodeProfile::Aac { kbps } => ("aac", kbps),
+            TranscodeProfile::Opus { kbps } => ("libopus", kbps),
+        };
+        vec!["-c:a".to_string(), codec.to_string(), "-b:a".to_string(), format!("{}k", kbps)]
+    }
+}
+
+impl fmt::Display for TranscodeProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranscodeProfile::Original => write!(f, "original"),
+            TranscodeProfile::Mp3 { kbps } => write!(f, "mp3-{}", kbps),
+            TranscodeProfile::Aac { kbps } => write!(f, "aac-{}", kbps),
+            TranscodeProfile::Opus { kbps } => write!(f, "opus-{}", kbps),
+        }
+    }
+}
+
+/// The extension of a song's file, lowercased
+pub fn source_extension(song: &Song) -> String {
+    Path::new(&song.file_path).extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_else(|| "bin".to_string())
+}
+
+/// Where a song's audio for a download comes from
+pub enum Prepared {
+    /// A region of the song's file after a new header, read straight from it
+    Slice(Slice),
+    /// A file made for the download, to be deleted once added
+    Encoded(PathBuf),
+}
+
+/// Get a song ready to add to a download. Whole files and cue sheet tracks
+/// of formats that can be cut are used as they are for `original`; anything
+/// else is encoded into `work_dir` with ffmpeg.
+pub async fn prepare(ffmpeg: &str, song: &Song, profile: TranscodeProfile, work_dir: &Path) -> io::Result<Prepared> {
+    if profile == TranscodeProfile::Original {
+        let path = PathBuf::from(&song.file_path);
+        let size = tokio::fs::metadata(&path).await?.len();
+        let Some(start_ms) = song.start_ms else {
+            return Ok(Prepared::Slice(Slice::whole(size)));
+        };
+        let end_ms = song.end_ms;
+        let cut = tokio::task::spawn_blocking(move || {
+            let table = load_seek_table(&path);
+            slice_file(&path, start_ms, end_ms, table.as_ref())
+        }).await
+            .map_err(io::Error::other)?
+            .map_err(io::Error::other)?;
+        if let Some(slice) = cut {
+            return Ok(Prepared::Slice(slice));
+        }
+    }
+
+    let output = work_dir.join(format!("{}.{}", song.id, profile.extension(&source_extension(song))));
+    let mut command = Command::new(ffmpeg);
+    command.args(["-hide_banner", "-loglevel", "error", "-nostdin", "-y"]);
+    if let Some(start_ms) = song.start_ms {
+        command.arg("-ss").arg(format!("{:.3}", start_ms as f64 / 1000.0));
+        if let Some(end_ms) = song.end_ms {
+            command.arg("-t").arg(format!("{:.3}", (end_ms - start_ms) as f64 / 1000.0));
+        }
+    }
+    command.arg("-i").arg(&song.file_path)
+        .args(["-map", "0:a:0", "-vn", "-map_metadata", "0"])
+        .args(profile.encoder_args())
+        .arg(&output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let result = command.output().await
+        .map_err(|e| io::Error::other(format!("Failed to run {}: {}", ffmpeg, e)))?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(&output).await;
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(io::Error::other(format!("Encoder failed: {}", stderr.lines().last().unwrap_or("no output"))));
+    }
+    Ok(Prepared::Encoded(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        assert_eq!(TranscodeProfile::parse("original"), Some(TranscodeProfile::Original));
+        assert_eq!(TranscodeProfile::parse("mp3-320"), Some(TranscodeProfile::Mp3 { kbps: 320 }));
+        assert_eq!(TranscodeProfile::parse("AAC-256k"), Some(TranscodeProfile::Aac { kbps: 256 }));
+        assert_eq!(TranscodeProfile::parse("opus-96"), Some(TranscodeProfile::Opus { kbps: 96 }));
+        for name in ["mp3", "mp3-", "mp3-400", "aac-0", "flac-900", "wav-128"] {
+            assert_eq!(TranscodeProfile::parse(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_profile_names_round_trip() {
+        for name in ["original", "mp3-192", "aac-128", "opus-64"] {
+            assert_eq!(TranscodeProfile::parse(name).unwrap().to_string(), name);
+        }
+        assert_eq!(TranscodeProfile::Original.extension("flac"), "flac");
+        assert_eq!(TranscodeProfile::Aac { kbps: 128 }.extension("flac"), "m4a");
+    }
+}
@@ -1,14 +1,32 @@
+// Handlers build error messages with `&format!`, which `ApiError::new` takes
+// as readily as a `String`
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 mod api;
 mod db;
 mod auth;
 mod music;
+mod connect;
+mod rooms;
+mod downloads;
 
 use std::sync::Arc;
 
 use crate::api::auth::AppState;
+use crate::api::pagination::PageLimits;
 use crate::auth::{JwtService, PasswordService};
-use crate::db::{create_database, DbBackend};
+use crate::auth::oidc::{OidcClient, OidcConfig, RoleMapping};
+use crate::auth::permissions::DEFAULT_ROLE;
+use crate::auth::rate_limit::{FailurePolicy, RateLimitConfig, RateLimiter};
+use crate::auth::stream_url::StreamUrlSigner;
+use crate::auth::webauthn::{WebAuthn, WebAuthnConfig};
+use crate::connect::ConnectHub;
+use crate::db::{create_database, ensure_default_library, DbBackend};
+use crate::downloads::DownloadHub;
 use crate::music::MusicScanner;
+use crate::music::hls::HlsPackager;
+use crate::music::lyrics_provider::{HttpLyricsProvider, LyricsProvider};
+use crate::rooms::RoomHub;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -43,12 +61,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap_or_else(|_| "sqlite:runtime/cache/users.db".to_string());
     let jwt_secret = std::env::var("JWT_SECRET")
         .unwrap_or_else(|_| "change_this_to_a_secure_random_secret_key".to_string());
-    let jwt_expiration_hours = std::env::var("JWT_EXPIRATION_HOURS")
-        .unwrap_or_else(|_| "24".to_string())
+    let access_token_minutes = std::env::var("ACCESS_TOKEN_MINUTES")
+        .unwrap_or_else(|_| "15".to_string())
+        .parse::<i64>()
+        .unwrap_or(15);
+    let refresh_token_days = std::env::var("REFRESH_TOKEN_DAYS")
+        .unwrap_or_else(|_| "30".to_string())
         .parse::<i64>()
-        .unwrap_or(24);
+        .unwrap_or(30);
     let server_bind = std::env::var("SERVER_BIND")
         .unwrap_or_else(|_| "127.0.0.1:8000".to_string());
+    let max_page_size = std::env::var("MAX_PAGE_SIZE")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse::<usize>()
+        .unwrap_or(100)
+        .max(1);
+    let default_page_size = std::env::var("DEFAULT_PAGE_SIZE")
+        .unwrap_or_else(|_| "50".to_string())
+        .parse::<usize>()
+        .unwrap_or(50)
+        .clamp(1, max_page_size);
+    
+    let website_url = std::env::var("WEBSITE_URL")
+        .unwrap_or_else(|_| server_bind.clone());
+    let website_url = if website_url.contains("://") {
+        website_url.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", website_url.trim_end_matches('/'))
+    };
     
     tracing::info!("Using database backend: {}", db_backend);
     tracing::info!("Database URL: {}", db_url);
@@ -59,39 +99,185 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let db = create_database(backend, &db_url).await?;
     tracing::info!("Database initialized successfully");
     
-    // Perform initial music library scan
+    // MUSIC_DIR becomes the first library, then every library asking for it is scanned
     let music_dir = std::env::var("MUSIC_DIR")
         .unwrap_or_else(|_| "runtime/music".to_string());
+    ensure_default_library(db.as_ref(), &music_dir).await?;
     
-    tracing::info!("Scanning music directory: {}", music_dir);
-    let scanner = MusicScanner::new(db.clone(), &music_dir);
-    
-    match scanner.scan_and_register().await {
-        Ok(result) => {
-            tracing::info!(
-                "Music scan complete - Total: {}, Registered: {}, Updated: {}, Skipped: {}, Removed: {}, Errors: {}",
-                result.total_files,
-                result.registered,
-                result.updated,
-                result.skipped,
-                result.removed,
-                result.errors
-            );
+    for library in db.get_libraries().await? {
+        if !library.scan_on_startup {
+            continue;
         }
-        Err(e) => {
-            tracing::warn!("Music scan failed: {}. Server will continue without music library.", e);
+        
+        let name = library.name.clone();
+        tracing::info!("Scanning library '{}': {}", name, library.root_path);
+        let scanner = MusicScanner::new(db.clone(), library);
+        
+        match scanner.scan_and_register().await {
+            Ok(result) => {
+                tracing::info!(
+                    "Scan of '{}' complete - Total: {}, Registered: {}, Updated: {}, Skipped: {}, Removed: {}, Errors: {}",
+                    name,
+                    result.total_files,
+                    result.registered,
+                    result.updated,
+                    result.skipped,
+                    result.removed,
+                    result.errors
+                );
+            }
+            Err(e) => {
+                tracing::warn!("Scan of '{}' failed: {}. Server will continue without its songs.", name, e);
+            }
         }
     }
     
     // Create services
-    let jwt_service = Arc::new(JwtService::new(&jwt_secret, jwt_expiration_hours));
+    let jwt_service = Arc::new(JwtService::new(&jwt_secret, access_token_minutes, refresh_token_days));
     let password_service = Arc::new(PasswordService::new());
+    let stream_signer = Arc::new(StreamUrlSigner::new(&jwt_secret));
+    
+    let lyrics_provider = std::env::var("LYRICS_PROVIDER_URL").ok()
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            tracing::info!("Looking up missing lyrics from {}", url);
+            Arc::new(HttpLyricsProvider::new(url)) as Arc<dyn LyricsProvider>
+        });
+    
+    // Single sign-on is on when an identity provider is configured
+    let oidc = match (
+        std::env::var("OIDC_ISSUER_URL").ok().filter(|url| !url.is_empty()),
+        std::env::var("OIDC_CLIENT_ID").ok().filter(|id| !id.is_empty()),
+        std::env::var("OIDC_REDIRECT_URL").ok().filter(|url| !url.is_empty()),
+    ) {
+        (Some(issuer), Some(client_id), Some(redirect_url)) => {
+            let flag = |name: &str, default: bool| std::env::var(name).ok()
+                .and_then(|value| value.parse::<bool>().ok())
+                .unwrap_or(default);
+            // Roles come from the provider only when a claim to map is named
+            let roles = std::env::var("OIDC_ROLE_CLAIM").ok()
+                .filter(|claim| !claim.is_empty())
+                .map(|claim| RoleMapping::parse(
+                    &claim,
+                    &std::env::var("OIDC_ROLE_MAP").unwrap_or_default(),
+                    &std::env::var("OIDC_DEFAULT_ROLE").unwrap_or_else(|_| DEFAULT_ROLE.to_string()),
+                ));
+            tracing::info!("Single sign-on with {}", issuer);
+            Some(Arc::new(OidcClient::new(OidcConfig {
+                issuer,
+                client_id,
+                client_secret: std::env::var("OIDC_CLIENT_SECRET").ok().filter(|secret| !secret.is_empty()),
+                redirect_url,
+                scopes: std::env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid profile email".to_string()),
+                roles,
+                auto_provision: flag("OIDC_AUTO_PROVISION", true),
+                link_by_email: flag("OIDC_LINK_BY_EMAIL", false),
+            })))
+        }
+        (Some(_), _, _) => {
+            tracing::warn!("OIDC_ISSUER_URL needs OIDC_CLIENT_ID and OIDC_REDIRECT_URL; single sign-on is off");
+            None
+        }
+        _ => None,
+    };
+    
+    // Accounts that can manage users can be made to use two-factor authentication
+    let require_admin_two_factor = std::env::var("REQUIRE_ADMIN_2FA").ok()
+        .and_then(|value| value.parse::<bool>().ok())
+        .unwrap_or(false);
+    
+    // Logins back off after a few failures in a row and then lock out, per
+    // account and per address
+    let limits = RateLimitConfig::default();
+    let limit = |name: &str, default: u32| std::env::var(name).ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(default);
+    let lockout_seconds = i64::from(limit("LOGIN_LOCKOUT_MINUTES", (limits.account.lockout_seconds / 60) as u32).max(1)) * 60;
+    let rate_limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+        requests_per_minute: limit("LOGIN_RATE_LIMIT", limits.requests_per_minute),
+        account: FailurePolicy {
+            free_failures: limit("LOGIN_ACCOUNT_BACKOFF_AFTER", limits.account.free_failures),
+            lockout_failures: limit("LOGIN_ACCOUNT_LOCKOUT_AFTER", limits.account.lockout_failures).max(1),
+            lockout_seconds,
+        },
+        address: FailurePolicy {
+            free_failures: limit("LOGIN_ADDRESS_BACKOFF_AFTER", limits.address.free_failures),
+            lockout_failures: limit("LOGIN_ADDRESS_LOCKOUT_AFTER", limits.address.lockout_failures).max(1),
+            lockout_seconds,
+        },
+    }));
+    
+    // Passkeys belong to the site's domain and are only used from its pages,
+    // which are served from WEBSITE_URL unless the client is hosted elsewhere
+    let webauthn_origin = std::env::var("WEBAUTHN_ORIGIN").ok()
+        .filter(|origin| !origin.is_empty())
+        .unwrap_or_else(|| website_url.clone())
+        .trim_end_matches('/')
+        .to_string();
+    let webauthn_rp_id = std::env::var("WEBAUTHN_RP_ID").ok()
+        .filter(|rp_id| !rp_id.is_empty())
+        .or_else(|| reqwest::Url::parse(&webauthn_origin).ok()?.host_str().map(str::to_string))
+        .unwrap_or_else(|| "localhost".to_string());
+    let webauthn = Arc::new(WebAuthn::new(WebAuthnConfig {
+        rp_id: webauthn_rp_id,
+        rp_name: "Muse".to_string(),
+        origin: webauthn_origin,
+    }));
+    
+    // Adaptive streaming encodes with ffmpeg into a ladder of AAC bitrates
+    let ffmpeg = std::env::var("FFMPEG_PATH")
+        .unwrap_or_else(|_| "ffmpeg".to_string());
+    let hls_bitrates: Vec<u32> = std::env::var("HLS_BITRATES")
+        .unwrap_or_else(|_| "64,128,256".to_string())
+        .split(',')
+        .filter_map(|kbps| kbps.trim().parse().ok())
+        .filter(|&kbps| kbps > 0)
+        .collect();
+    let hls_bitrates = if hls_bitrates.is_empty() { vec![64, 128, 256] } else { hls_bitrates };
+    let hls_segment_seconds = std::env::var("HLS_SEGMENT_SECONDS")
+        .unwrap_or_else(|_| "6".to_string())
+        .parse::<u32>()
+        .unwrap_or(6);
+    let hls = Arc::new(HlsPackager::new(ffmpeg.clone(), hls_bitrates, hls_segment_seconds));
+
+    // Download bundles are transcoded with the same ffmpeg
+    let downloads = Arc::new(DownloadHub::new(ffmpeg));
+    
+    // Listening rooms live in memory, and are saved to ROOMS_FILE when it is set
+    let rooms = match std::env::var("ROOMS_FILE").ok().filter(|path| !path.is_empty()) {
+        Some(path) => {
+            let path = std::path::PathBuf::from(path);
+            let rooms = RoomHub::with_persistence(path.clone());
+            match rooms.restore(&path, db.as_ref()).await {
+                Ok(restored) => tracing::info!("Restored {} listening rooms from {}", restored, path.display()),
+                Err(e) => tracing::warn!("Failed to restore listening rooms from {}: {}", path.display(), e),
+            }
+            Arc::new(rooms)
+        }
+        None => Arc::new(RoomHub::new()),
+    };
+    tokio::spawn(rooms.clone().run_clock());
     
     // Create application state
     let app_state = AppState {
         db: db.clone(),
         jwt_service: jwt_service.clone(),
+        stream_signer,
         password_service,
+        page_limits: PageLimits {
+            default_size: default_page_size,
+            max_size: max_page_size,
+        },
+        website_url,
+        connect: Arc::new(ConnectHub::new()),
+        rooms,
+        lyrics_provider,
+        hls,
+        downloads,
+        oidc,
+        require_admin_two_factor,
+        webauthn,
+        rate_limiter,
     };
     
     // Create the main API router using the defined api module
@@ -102,7 +288,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing::info!("Server listening on {}", server_bind);
     tracing::info!("API routes available at http://{}/api/*", server_bind);
     
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await?;
     
     Ok(())
 }
@@ -0,0 +1,209 @@
+use std::path::Path;
+
+/// CD frames per second, the unit of cue sheet times
+const FRAMES_PER_SECOND: i64 = 75;
+
+/// A cue sheet, which splits one or more large audio files into tracks
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueSheet {
+    /// Album title
+    pub title: Option<String>,
+    /// Album artist
+    pub performer: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueFile {
+    /// Path of the audio file, relative to the cue sheet
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Where the track starts in its file (`INDEX 01`)
+    pub start_ms: i64,
+    /// Where the next track in the same file starts, `None` for the last
+    pub end_ms: Option<i64>,
+}
+
+/// Read a cue sheet, which may be UTF-8 or Latin-1
+pub fn read_cue(path: &Path) -> std::io::Result<CueSheet> {
+    let bytes = std::fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    Ok(parse_cue(&text))
+}
+
+/// Parse a cue sheet. Unknown commands and non-audio tracks are skipped, as
+/// are tracks without an `INDEX 01`.
+pub fn parse_cue(text: &str) -> CueSheet {
+    let mut sheet = CueSheet::default();
+    let mut track: Option<PendingTrack> = None;
+
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let (command, rest) = split_word(line.trim());
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                finish_track(&mut sheet, track.take());
+                sheet.files.push(CueFile { name: unquote(rest), tracks: Vec::new() });
+            }
+            "TRACK" => {
+                finish_track(&mut sheet, track.take());
+                let (number, kind) = split_word(rest);
+                if kind.eq_ignore_ascii_case("AUDIO") && let Ok(number) = number.parse() {
+                    track = Some(PendingTrack { number, ..PendingTrack::default() });
+                }
+            }
+            "TITLE" => match &mut track {
+                Some(track) => track.title = Some(unquote(rest)),
+                None => sheet.title = Some(unquote(rest)),
+            },
+            "PERFORMER" => match &mut track {
+                Some(track) => track.performer = Some(unquote(rest)),
+                None => sheet.performer = Some(unquote(rest)),
+            },
+            "INDEX" => {
+                let (index, time) = split_word(rest);
+                if let Some(track) = &mut track && index == "01" {
+                    track.start_ms = parse_time(time);
+                }
+            }
+            "REM" => {
+                let (field, value) = split_word(rest);
+                match field.to_ascii_uppercase().as_str() {
+                    "GENRE" => sheet.genre = Some(unquote(value)),
+                    "DATE" => sheet.year = unquote(value).get(..4).and_then(|year| year.parse().ok()),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_track(&mut sheet, track);
+
+    // Each track runs until the next one in the same file starts
+    for file in &mut sheet.files {
+        file.tracks.sort_by_key(|track| track.start_ms);
+        let starts: Vec<i64> = file.tracks.iter().map(|track| track.start_ms).collect();
+        for (track, next_start) in file.tracks.iter_mut().zip(starts.into_iter().skip(1)) {
+            track.end_ms = Some(next_start);
+        }
+    }
+    sheet.files.retain(|file| !file.tracks.is_empty());
+    sheet
+}
+
+#[derive(Default)]
+struct PendingTrack {
+    number: u32,
+    title: Option<String>,
+    performer: Option<String>,
+    start_ms: Option<i64>,
+}
+
+fn finish_track(sheet: &mut CueSheet, track: Option<PendingTrack>) {
+    let Some(track) = track else { return };
+    let (Some(start_ms), Some(file)) = (track.start_ms, sheet.files.last_mut()) else { return };
+
+    file.tracks.push(CueTrack {
+        number: track.number,
+        title: track.title.filter(|title| !title.is_empty()),
+        performer: track.performer.filter(|performer| !performer.is_empty()),
+        start_ms,
+        end_ms: None,
+    });
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+/// The quoted value of a command, or its first word when unquoted
+fn unquote(text: &str) -> String {
+    match text.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default().to_string(),
+        None => split_word(text).0.to_string(),
+    }
+}
+
+/// `mm:ss:ff`, where minutes may exceed 59
+fn parse_time(text: &str) -> Option<i64> {
+    let mut parts = text.split(':').map(|part| part.parse::<i64>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return None;
+    };
+    Some((minutes * 60 + seconds) * 1000 + frames * 1000 / FRAMES_PER_SECOND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE "Electronic"
+REM DATE 2019-06-01
+PERFORMER "DJ Example"
+TITLE "Live at the Pier"
+FILE "Live at the Pier.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Opening"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second Wind"
+    PERFORMER "Guest"
+    INDEX 00 04:58:00
+    INDEX 01 05:00:37
+  TRACK 03 AUDIO
+    TITLE "Closing"
+    INDEX 01 118:30:74
+"#;
+
+    #[test]
+    fn test_parse_cue() {
+        let sheet = parse_cue(SHEET);
+        assert_eq!(sheet.title.as_deref(), Some("Live at the Pier"));
+        assert_eq!(sheet.performer.as_deref(), Some("DJ Example"));
+        assert_eq!(sheet.genre.as_deref(), Some("Electronic"));
+        assert_eq!(sheet.year, Some(2019));
+
+        let file = &sheet.files[0];
+        assert_eq!(file.name, "Live at the Pier.flac");
+        assert_eq!(file.tracks.len(), 3);
+        assert_eq!(file.tracks[1], CueTrack {
+            number: 2,
+            title: Some("Second Wind".to_string()),
+            performer: Some("Guest".to_string()),
+            start_ms: 300_493,
+            end_ms: Some(7_110_986),
+        });
+        assert_eq!(file.tracks[0].end_ms, Some(300_493));
+        assert_eq!(file.tracks[2].end_ms, None);
+    }
+
+    #[test]
+    fn test_tracks_belong_to_their_file() {
+        let sheet = parse_cue("FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 00:00:00\nTRACK 2 AUDIO\nINDEX 01 01:00:00\nFILE \"b.wav\" WAVE\nTRACK 3 AUDIO\nINDEX 01 00:00:00\n");
+        assert_eq!(sheet.files.len(), 2);
+        assert_eq!(sheet.files[0].name, "a.wav");
+        assert_eq!(sheet.files[0].tracks[1].end_ms, None);
+        assert_eq!(sheet.files[1].tracks[0].number, 3);
+    }
+
+    #[test]
+    fn test_skips_data_tracks_and_missing_indexes() {
+        let sheet = parse_cue("FILE a.bin BINARY\nTRACK 01 MODE1/2352\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nTITLE \"No index\"\n");
+        assert!(sheet.files.is_empty());
+    }
+}
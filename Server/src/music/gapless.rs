@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use lofty::file::{AudioFile, TaggedFile, TaggedFileExt};
+use lofty::tag::{ItemKey, ItemValue};
+use serde::Serialize;
+
+use crate::db::models::Song;
+
+/// What a player needs to stitch consecutive tracks together without a gap:
+/// the silent samples the encoder added before and after the audio
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct GaplessInfo {
+    /// Samples of encoder priming at the start
+    pub encoder_delay: Option<i32>,
+    /// Samples of padding at the end
+    pub encoder_padding: Option<i32>,
+    pub sample_rate: Option<i32>,
+}
+
+impl GaplessInfo {
+    pub fn of(song: &Song) -> Self {
+        GaplessInfo {
+            encoder_delay: song.encoder_delay,
+            encoder_padding: song.encoder_padding,
+            sample_rate: song.sample_rate,
+        }
+    }
+}
+
+/// Read gapless info from an iTunSMPB tag, an MP3's LAME header or an Opus
+/// pre-skip, in that order. Lossless formats have neither delay nor padding.
+pub fn read_gapless(path: &Path, tagged_file: &TaggedFile) -> GaplessInfo {
+    let mut info = GaplessInfo {
+        sample_rate: tagged_file.properties().sample_rate().map(|rate| rate as i32),
+        ..GaplessInfo::default()
+    };
+
+    let found = itunes_smpb(tagged_file).or_else(|| {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+            Some("mp3") => read_head(path, 64 * 1024).and_then(|head| lame_header(&head)),
+            Some("opus") => read_head(path, 4096).and_then(|head| opus_pre_skip(&head)).map(|skip| (skip, 0)),
+            _ => None,
+        }
+    });
+    if let Some((delay, padding)) = found {
+        info.encoder_delay = Some(delay);
+        info.encoder_padding = Some(padding);
+    }
+    info
+}
+
+/// iTunes stores gapless info as hex fields: ` 00000000 <delay> <padding> <samples> ...`.
+/// It is a freeform atom in MP4 and a described comment in ID3v2.
+fn itunes_smpb(tagged_file: &TaggedFile) -> Option<(i32, i32)> {
+    let value = tagged_file.tags().iter()
+        .flat_map(|tag| tag.items())
+        .find(|item| match item.key() {
+            ItemKey::Unknown(key) => key.to_ascii_lowercase().ends_with("itunsmpb"),
+            ItemKey::Comment => item.description().eq_ignore_ascii_case("iTunSMPB"),
+            _ => false,
+        })
+        .and_then(|item| match item.value() {
+            ItemValue::Text(text) => Some(text.clone()),
+            _ => None,
+        })?;
+
+    let mut fields = value.split_whitespace().skip(1).map(|field| i32::from_str_radix(field, 16).ok());
+    match (fields.next(), fields.next()) {
+        (Some(Some(delay)), Some(Some(padding))) => Some((delay, padding)),
+        _ => None,
+    }
+}
+
+fn read_head(path: &Path, max: u64) -> Option<Vec<u8>> {
+    let mut file = File::open(path).ok()?;
+    let mut head = Vec::new();
+    file.by_ref().take(max).read_to_end(&mut head).ok()?;
+
+    // Skip an ID3v2 tag, whose size is synchsafe and excludes the 10-byte header
+    if head.starts_with(b"ID3") && head.len() >= 10 {
+        let size = head[6..10].iter().fold(0u64, |size, &b| (size << 7) | (b & 0x7f) as u64) + 10;
+        file.seek(SeekFrom::Start(size)).ok()?;
+        head.clear();
+        file.take(max).read_to_end(&mut head).ok()?;
+    }
+    Some(head)
+}
+
+/// The LAME extension of the Xing/Info header in an MP3's first frame
+fn lame_header(head: &[u8]) -> Option<(i32, i32)> {
+    let frame = head.windows(2).position(|w| w[0] == 0xff && w[1] & 0xe0 == 0xe0)?;
+    let header = head.get(frame..frame + 4)?;
+
+    let mpeg1 = header[1] & 0x18 == 0x18;
+    let mono = header[3] >> 6 == 3;
+    let side_info = match (mpeg1, mono) {
+        (true, true) => 17,
+        (true, false) => 32,
+        (false, true) => 9,
+        (false, false) => 17,
+    };
+
+    let xing = frame + 4 + side_info;
+    let tag = head.get(xing..xing + 4)?;
+    if tag != b"Xing" && tag != b"Info" {
+        return None;
+    }
+
+    // Frame count, byte count, TOC and quality follow the flags when present
+    let flags = head.get(xing + 4..xing + 8)?[3];
+    let mut lame = xing + 8;
+    for (flag, len) in [(1, 4), (2, 4), (4, 100), (8, 4)] {
+        if flags & flag != 0 {
+            lame += len;
+        }
+    }
+
+    let ext = head.get(lame..lame + 24)?;
+    if !(ext.starts_with(b"LAME") || ext.starts_with(b"Lavc") || ext.starts_with(b"Lavf")) {
+        return None;
+    }
+    let delay = ((ext[21] as i32) << 4) | (ext[22] as i32 >> 4);
+    let padding = ((ext[22] as i32 & 0x0f) << 8) | ext[23] as i32;
+    Some((delay, padding))
+}
+
+/// The pre-skip from the `OpusHead` packet in the first Ogg page
+fn opus_pre_skip(head: &[u8]) -> Option<i32> {
+    let start = head.windows(8).position(|w| w == b"OpusHead")?;
+    let skip = head.get(start + 10..start + 12)?;
+    Some(u16::from_le_bytes([skip[0], skip[1]]) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp3_frame(flags: u8, fields: usize) -> Vec<u8> {
+        // MPEG-1 layer III, 128 kbps, 44.1 kHz, joint stereo
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x40];
+        frame.extend([0; 32]);
+        frame.extend(b"Info");
+        frame.extend([0, 0, 0, flags]);
+        frame.extend(vec![0; fields]);
+        frame.extend(b"LAME3.100");
+        frame.extend([0; 12]);
+        // 576 samples of delay, 1234 of padding
+        frame.extend([0x24, 0x04, 0xd2]);
+        frame.extend([0; 20]);
+        frame
+    }
+
+    #[test]
+    fn test_lame_header() {
+        assert_eq!(lame_header(&mp3_frame(0, 0)), Some((576, 1234)));
+        // Frame count and TOC present
+        assert_eq!(lame_header(&mp3_frame(5, 104)), Some((576, 1234)));
+    }
+
+    #[test]
+    fn test_lame_header_needs_info_tag() {
+        let mut frame = mp3_frame(0, 0);
+        frame[36..40].copy_from_slice(b"None");
+        assert_eq!(lame_header(&frame), None);
+    }
+
+    #[test]
+    fn test_opus_pre_skip() {
+        let mut page = b"OggS".to_vec();
+        page.extend([0; 24]);
+        page.extend(b"OpusHead");
+        page.extend([1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0]);
+        assert_eq!(opus_pre_skip(&page), Some(312));
+    }
+}
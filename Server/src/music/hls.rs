@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::db::models::Song;
+use crate::music::hls_playlist::{parse_media_playlist, rendition_name, Segment};
+use crate::music::seek::file_stamp;
+
+const HLS_CACHE_DIR: &str = "runtime/cache/hls";
+
+/// The media playlist the encoder writes into each rendition
+const ENCODER_PLAYLIST: &str = "index.m3u8";
+
+/// What the rendition was encoded from, so a changed file is re-encoded
+const SOURCE_FILE: &str = "source";
+
+#[derive(Debug, thiserror::Error)]
+pub enum HlsError {
+    #[error("No {0} kbps rendition is offered")]
+    UnknownBitrate(u32),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Encoder failed: {0}")]
+    Encoder(String),
+}
+
+/// Encodes songs into segmented AAC at each bitrate of a ladder for HLS and
+/// DASH, with ffmpeg. Renditions are encoded the first time they're asked
+/// for and kept on disk until their file changes or the song is removed.
+pub struct HlsPackager {
+    ffmpeg: String,
+    bitrates: Vec<u32>,
+    segment_seconds: u32,
+    /// One lock per rendition, so concurrent requests encode it once
+    encoding: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl HlsPackager {
+    pub fn new(ffmpeg: impl Into<String>, mut bitrates: Vec<u32>, segment_seconds: u32) -> Self {
+        bitrates.sort_unstable();
+        bitrates.dedup();
+        Self {
+            ffmpeg: ffmpeg.into(),
+            bitrates,
+            segment_seconds: segment_seconds.max(1),
+            encoding: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bitrates offered, in kbps, lowest first
+    pub fn bitrates(&self) -> &[u32] {
+        &self.bitrates
+    }
+
+    /// The segments of a song at a bitrate, encoding them if needed
+    pub async fn rendition(&self, song: &Song, kbps: u32) -> Result<Vec<Segment>, HlsError> {
+        if !self.bitrates.contains(&kbps) {
+            return Err(HlsError::UnknownBitrate(kbps));
+        }
+        let dir = rendition_dir(&song.id, kbps);
+        let source = source_stamp(song)?;
+
+        let lock = self.encoding.lock().unwrap().entry(dir.clone()).or_default().clone();
+        let _encoding = lock.lock().await;
+
+        if fs::read_to_string(dir.join(SOURCE_FILE)).await.ok().as_deref() != Some(source.as_str()) {
+            self.encode(song, kbps, &dir, &source).await?;
+        }
+        let playlist = fs::read_to_string(dir.join(ENCODER_PLAYLIST)).await?;
+        Ok(parse_media_playlist(&playlist))
+    }
+
+    /// Encode into a scratch directory that replaces the rendition once
+    /// finished, so an interrupted encode never leaves a partial one behind
+    async fn encode(&self, song: &Song, kbps: u32, dir: &Path, source: &str) -> Result<(), HlsError> {
+        tracing::info!("Encoding {} kbps HLS rendition of {} - {}", kbps, song.artist_name, song.title);
+        let scratch = dir.with_extension("partial");
+        let _ = fs::remove_dir_all(&scratch).await;
+        fs::create_dir_all(&scratch).await?;
+
+        let mut command = Command::new(&self.ffmpeg);
+        command.args(["-hide_banner", "-loglevel", "error", "-nostdin", "-y"]);
+        // Cue sheet tracks are cut out of their file
+        if let Some(start_ms) = song.start_ms {
+            command.arg("-ss").arg(format!("{:.3}", start_ms as f64 / 1000.0));
+            if let Some(end_ms) = song.end_ms {
+                command.arg("-t").arg(format!("{:.3}", (end_ms - start_ms) as f64 / 1000.0));
+            }
+        }
+        command.arg("-i").arg(&song.file_path)
+            .args(["-map", "0:a:0", "-vn", "-c:a", "aac", "-b:a"])
+            .arg(format!("{}k", kbps))
+            .args(["-f", "hls", "-hls_playlist_type", "vod", "-hls_segment_type", "fmp4"])
+            .args(["-hls_fmp4_init_filename", "init.mp4", "-hls_time"])
+            .arg(self.segment_seconds.to_string())
+            .arg("-hls_segment_filename").arg(scratch.join("seg_%05d.m4s"))
+            .arg(scratch.join(ENCODER_PLAYLIST))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        let output = command.output().await
+            .map_err(|e| HlsError::Encoder(format!("Failed to run {}: {}", self.ffmpeg, e)))?;
+        if !output.status.success() {
+            let _ = fs::remove_dir_all(&scratch).await;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(HlsError::Encoder(stderr.lines().last().unwrap_or("no output").to_string()));
+        }
+
+        fs::write(scratch.join(SOURCE_FILE), source).await?;
+        let _ = fs::remove_dir_all(dir).await;
+        fs::rename(&scratch, dir).await?;
+        Ok(())
+    }
+}
+
+/// Where an encoded file of a rendition is, for names the playlists use
+pub fn segment_path(song_id: &str, kbps: u32, name: &str) -> Option<PathBuf> {
+    let is_segment = name.strip_prefix("seg_")
+        .and_then(|rest| rest.strip_suffix(".m4s"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()));
+    (name == "init.mp4" || is_segment).then(|| rendition_dir(song_id, kbps).join(name))
+}
+
+/// Remove every rendition of a song
+pub async fn remove_renditions(song_id: &str) {
+    let _ = fs::remove_dir_all(Path::new(HLS_CACHE_DIR).join(song_id)).await;
+}
+
+fn rendition_dir(song_id: &str, kbps: u32) -> PathBuf {
+    Path::new(HLS_CACHE_DIR).join(song_id).join(rendition_name(kbps))
+}
+
+/// The file's size and modification time, and the track's times within it
+fn source_stamp(song: &Song) -> std::io::Result<String> {
+    let (size, modified_ms) = file_stamp(Path::new(&song.file_path))?;
+    Ok(format!("{} {} {:?} {:?}", size, modified_ms, song.start_ms, song.end_ms))
+}
@@ -0,0 +1,188 @@
+use std::fmt::Write;
+
+/// AAC-LC, which every rung is encoded as
+const CODECS: &str = "mp4a.40.2";
+
+/// The container adds to the audio bitrate; players pick rungs by this
+const CONTAINER_OVERHEAD_PERCENT: u64 = 10;
+
+/// One media segment of a rendition
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// File name, relative to the rendition
+    pub uri: String,
+    /// Seconds
+    pub duration: f64,
+}
+
+/// A bitrate rung and its segments, as it appears in a master playlist or
+/// DASH manifest
+pub struct Rendition<'a> {
+    pub kbps: u32,
+    pub segments: &'a [Segment],
+}
+
+/// Name of a rendition's directory in URLs, e.g. `128k`
+pub fn rendition_name(kbps: u32) -> String {
+    format!("{}k", kbps)
+}
+
+/// The bitrate of a rendition named by [`rendition_name`]
+pub fn parse_rendition_name(name: &str) -> Option<u32> {
+    name.strip_suffix('k')?.parse().ok()
+}
+
+/// The segments of a media playlist written by the encoder
+pub fn parse_media_playlist(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut duration = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            duration = info.split(',').next().and_then(|seconds| seconds.trim().parse().ok());
+        } else if !line.is_empty() && !line.starts_with('#') && let Some(duration) = duration.take() {
+            segments.push(Segment { uri: line.to_string(), duration });
+        }
+    }
+    segments
+}
+
+/// `?token=...` to add to each URI, or nothing
+fn query(token: Option<&str>) -> String {
+    token.map(|token| format!("?token={}", urlencoding::encode(token))).unwrap_or_default()
+}
+
+fn bandwidth(kbps: u32) -> u64 {
+    kbps as u64 * 1000 * (100 + CONTAINER_OVERHEAD_PERCENT) / 100
+}
+
+/// An HLS master playlist listing a media playlist for each bitrate
+pub fn master_playlist(bitrates: &[u32], token: Option<&str>) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    for &kbps in bitrates {
+        let _ = writeln!(playlist, "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"", bandwidth(kbps), CODECS);
+        let _ = writeln!(playlist, "{}/index.m3u8{}", rendition_name(kbps), query(token));
+    }
+    playlist
+}
+
+/// An HLS media playlist of fragmented MP4 segments, which start with the
+/// rendition's `init.mp4`
+pub fn media_playlist(segments: &[Segment], token: Option<&str>) -> String {
+    let query = query(token);
+    let target = segments.iter().map(|segment| segment.duration.ceil() as u64).max().unwrap_or(1);
+
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:7\n");
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target);
+    playlist.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"init.mp4{}\"", query);
+    for segment in segments {
+        let _ = writeln!(playlist, "#EXTINF:{:.6},", segment.duration);
+        let _ = writeln!(playlist, "{}{}", segment.uri, query);
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// A static DASH manifest with a representation for each rendition, using
+/// the same segments as the HLS playlists
+pub fn dash_manifest(renditions: &[Rendition], token: Option<&str>) -> String {
+    let query = xml_escape(&query(token));
+    let duration = renditions.iter()
+        .map(|rendition| rendition.segments.iter().map(|segment| segment.duration).sum::<f64>())
+        .fold(0.0, f64::max);
+
+    let mut mpd = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        mpd,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"static\" mediaPresentationDuration=\"PT{:.3}S\" minBufferTime=\"PT2S\">",
+        duration,
+    );
+    mpd.push_str("  <Period id=\"0\" start=\"PT0S\">\n");
+    let _ = writeln!(mpd, "    <AdaptationSet contentType=\"audio\" mimeType=\"audio/mp4\" codecs=\"{}\" segmentAlignment=\"true\">", CODECS);
+    for rendition in renditions {
+        let name = rendition_name(rendition.kbps);
+        let _ = writeln!(mpd, "      <Representation id=\"{}\" bandwidth=\"{}\">", name, bandwidth(rendition.kbps));
+        let _ = writeln!(mpd, "        <BaseURL>{}/</BaseURL>", name);
+        mpd.push_str("        <SegmentList timescale=\"1000\">\n");
+        let _ = writeln!(mpd, "          <Initialization sourceURL=\"init.mp4{}\"/>", query);
+        mpd.push_str("          <SegmentTimeline>\n");
+        for segment in rendition.segments {
+            let _ = writeln!(mpd, "            <S d=\"{}\"/>", (segment.duration * 1000.0).round() as u64);
+        }
+        mpd.push_str("          </SegmentTimeline>\n");
+        for segment in rendition.segments {
+            let _ = writeln!(mpd, "          <SegmentURL media=\"{}{}\"/>", xml_escape(&segment.uri), query);
+        }
+        mpd.push_str("        </SegmentList>\n      </Representation>\n");
+    }
+    mpd.push_str("    </AdaptationSet>\n  </Period>\n</MPD>\n");
+    mpd
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODER_PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:6.013968,
+seg_00000.m4s
+#EXTINF:5.990748,
+seg_00001.m4s
+#EXTINF:1.500000,
+seg_00002.m4s
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn test_parse_media_playlist() {
+        let segments = parse_media_playlist(ENCODER_PLAYLIST);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0], Segment { uri: "seg_00000.m4s".to_string(), duration: 6.013968 });
+        assert_eq!(segments[2].duration, 1.5);
+    }
+
+    #[test]
+    fn test_master_playlist() {
+        let playlist = master_playlist(&[64, 128], Some("a.b.c"));
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        assert!(playlist.contains("#EXT-X-STREAM-INF:BANDWIDTH=70400,CODECS=\"mp4a.40.2\"\n64k/index.m3u8?token=a.b.c\n"));
+        assert!(playlist.contains("128k/index.m3u8?token=a.b.c\n"));
+    }
+
+    #[test]
+    fn test_media_playlist() {
+        let playlist = media_playlist(&parse_media_playlist(ENCODER_PLAYLIST), None);
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:7\n"));
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"init.mp4\"\n"));
+        assert!(playlist.contains("#EXTINF:5.990748,\nseg_00001.m4s\n"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    }
+
+    #[test]
+    fn test_dash_manifest() {
+        let segments = parse_media_playlist(ENCODER_PLAYLIST);
+        let mpd = dash_manifest(&[Rendition { kbps: 64, segments: &segments }], Some("t"));
+        assert!(mpd.contains("mediaPresentationDuration=\"PT13.505S\""));
+        assert!(mpd.contains("<Representation id=\"64k\" bandwidth=\"70400\">"));
+        assert!(mpd.contains("<Initialization sourceURL=\"init.mp4?token=t\"/>"));
+        assert!(mpd.contains("<S d=\"6014\"/>"));
+        assert!(mpd.contains("<SegmentURL media=\"seg_00002.m4s?token=t\"/>"));
+    }
+
+    #[test]
+    fn test_rendition_names() {
+        assert_eq!(rendition_name(128), "128k");
+        assert_eq!(parse_rendition_name("128k"), Some(128));
+        assert_eq!(parse_rendition_name("128"), None);
+        assert_eq!(parse_rendition_name("../k"), None);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::path::Path;
+use lofty::config::ParseOptions;
+use lofty::file::AudioFile;
+use lofty::id3::v2::{Frame, SyncTextContentType, SynchronizedTextFrame, TimestampFormat};
+use lofty::mpeg::MpegFile;
+use serde::Serialize;
+
+use crate::db::models::LyricsSource;
+
+/// One line of synced lyrics
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LyricLine {
+    /// When the line starts, in milliseconds from the start of the song
+    pub time_ms: i64,
+    pub text: String,
+}
+
+/// Lyrics found in or next to an audio file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoundLyrics {
+    pub text: String,
+    pub synced: bool,
+    pub source: LyricsSource,
+}
+
+impl FoundLyrics {
+    /// Lyrics from text that may or may not be LRC
+    pub fn from_text(text: &str, source: LyricsSource) -> Option<Self> {
+        let text = text.trim_start_matches('\u{feff}').trim();
+        if text.is_empty() {
+            return None;
+        }
+
+        Some(FoundLyrics {
+            text: text.replace("\r\n", "\n"),
+            synced: !parse_lrc(text).is_empty(),
+            source,
+        })
+    }
+}
+
+/// Find an audio file's lyrics. An `.lrc` file next to it comes first, then
+/// synced lyrics in an ID3v2 SYLT frame, then the lyrics tag read with the
+/// rest of its metadata (USLT, Vorbis `LYRICS`, MP4 `©lyr`).
+pub fn read_lyrics(path: &Path, embedded: Option<&str>) -> Option<FoundLyrics> {
+    read_sidecar(path)
+        .or_else(|| read_sylt(path))
+        .or_else(|| embedded.and_then(|text| FoundLyrics::from_text(text, LyricsSource::Embedded)))
+}
+
+fn read_sidecar(path: &Path) -> Option<FoundLyrics> {
+    let bytes = std::fs::read(path.with_extension("lrc")).ok()?;
+    FoundLyrics::from_text(&String::from_utf8_lossy(&bytes), LyricsSource::Sidecar)
+}
+
+/// Synced lyrics from an MP3's SYLT frame, as LRC. Only millisecond
+/// timestamps are read, as MPEG frame timestamps need the frame rate.
+fn read_sylt(path: &Path) -> Option<FoundLyrics> {
+    let is_mp3 = path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mp3"));
+    if !is_mp3 {
+        return None;
+    }
+
+    let mut file = File::open(path).ok()?;
+    let mpeg = MpegFile::read_from(&mut file, ParseOptions::new().read_properties(false)).ok()?;
+
+    mpeg.id3v2()?.into_iter()
+        .filter_map(|frame| match frame {
+            Frame::Binary(binary) if binary.id().as_str() == "SYLT" => {
+                SynchronizedTextFrame::parse(&binary.data, binary.flags()).ok()
+            }
+            _ => None,
+        })
+        .find(|sylt| sylt.content_type == SyncTextContentType::Lyrics && sylt.timestamp_format == TimestampFormat::MS)
+        .and_then(|sylt| {
+            let lines: Vec<LyricLine> = sylt.content.into_iter()
+                .map(|(time_ms, text)| LyricLine { time_ms: i64::from(time_ms), text: text.trim().to_string() })
+                .collect();
+            FoundLyrics::from_text(&to_lrc(&lines), LyricsSource::Embedded)
+        })
+}
+
+/// Parse the timed lines of LRC text, in order. Metadata tags such as `[ar:]`
+/// are skipped, apart from `[offset:]`, and word timings are removed. Empty
+/// when the text has no timestamps.
+pub fn parse_lrc(text: &str) -> Vec<LyricLine> {
+    let mut offset_ms = 0;
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+
+        while let Some(tag_end) = rest.strip_prefix('[').and_then(|tag| tag.find(']')) {
+            let tag = &rest[1..=tag_end];
+            if let Some(time_ms) = parse_timestamp(tag) {
+                times.push(time_ms);
+            } else if let Some(offset) = tag.strip_prefix("offset:") {
+                offset_ms = offset.trim().parse().unwrap_or(0);
+            }
+            rest = rest[tag_end + 2..].trim_start();
+        }
+
+        let text = strip_word_timings(rest);
+        // A positive offset shows lyrics sooner
+        lines.extend(times.into_iter().map(|time_ms| LyricLine {
+            time_ms: (time_ms - offset_ms).max(0),
+            text: text.clone(),
+        }));
+    }
+
+    lines.sort_by_key(|line| line.time_ms);
+    lines
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss.xxx` (also with `:` before the fraction)
+fn parse_timestamp(tag: &str) -> Option<i64> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: i64 = minutes.parse().ok()?;
+
+    let (seconds, fraction) = match seconds.find(['.', ':']) {
+        Some(split) => (&seconds[..split], &seconds[split + 1..]),
+        None => (seconds, ""),
+    };
+    if seconds.len() != 2 || !fraction.chars().all(|c| c.is_ascii_digit()) || fraction.len() > 3 {
+        return None;
+    }
+    let seconds: i64 = seconds.parse().ok()?;
+    let fraction_ms = match fraction.len() {
+        0 => 0,
+        1 => fraction.parse::<i64>().ok()? * 100,
+        2 => fraction.parse::<i64>().ok()? * 10,
+        _ => fraction.parse::<i64>().ok()?,
+    };
+
+    Some(minutes * 60_000 + seconds * 1000 + fraction_ms)
+}
+
+/// Remove enhanced LRC word timings like `<00:12.34>`
+fn strip_word_timings(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_timestamp(&rest[start + 1..start + end]).is_some() => {
+                plain.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            _ => {
+                plain.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    plain.push_str(rest);
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Write lines as LRC
+pub fn to_lrc(lines: &[LyricLine]) -> String {
+    lines.iter()
+        .map(|line| {
+            let centis = line.time_ms / 10;
+            format!("[{:02}:{:02}.{:02}]{}", centis / 6000, centis / 100 % 60, centis % 100, line.text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Lyrics without timestamps or LRC tags
+pub fn plain_text(text: &str, synced: bool) -> String {
+    if synced {
+        parse_lrc(text).into_iter().map(|line| line.text).collect::<Vec<_>>().join("\n")
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lrc() {
+        let lines = parse_lrc("[ar:Miles Davis]\n[ti:So What]\n[00:12.50]First line\n[01:02.123][00:05]Repeated\n\nUntimed\n");
+        assert_eq!(lines, vec![
+            LyricLine { time_ms: 5_000, text: "Repeated".to_string() },
+            LyricLine { time_ms: 12_500, text: "First line".to_string() },
+            LyricLine { time_ms: 62_123, text: "Repeated".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_lrc_offset_and_word_timings() {
+        let lines = parse_lrc("[offset:+500]\n[00:10.00]<00:10.00>Hello <00:10.50>world\n[00:00.20]Early");
+        assert_eq!(lines[0], LyricLine { time_ms: 0, text: "Early".to_string() });
+        assert_eq!(lines[1], LyricLine { time_ms: 9_500, text: "Hello world".to_string() });
+    }
+
+    #[test]
+    fn test_plain_text_is_not_lrc() {
+        assert!(parse_lrc("Just words\n[Chorus]\nMore words").is_empty());
+
+        let found = FoundLyrics::from_text("\u{feff}Just words\r\nMore words\n", LyricsSource::Embedded).unwrap();
+        assert!(!found.synced);
+        assert_eq!(found.text, "Just words\nMore words");
+        assert_eq!(FoundLyrics::from_text("  \n", LyricsSource::Embedded), None);
+    }
+
+    #[test]
+    fn test_lrc_round_trip() {
+        let lines = vec![
+            LyricLine { time_ms: 1_230, text: "One".to_string() },
+            LyricLine { time_ms: 61_000, text: "Two".to_string() },
+        ];
+        let lrc = to_lrc(&lines);
+        assert_eq!(lrc, "[00:01.23]One\n[01:01.00]Two");
+        assert_eq!(parse_lrc(&lrc), lines);
+        assert_eq!(plain_text(&lrc, true), "One\nTwo");
+    }
+}
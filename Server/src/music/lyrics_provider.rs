@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::db::models::Song;
+
+/// Somewhere to look up lyrics for songs that have none
+#[async_trait]
+pub trait LyricsProvider: Send + Sync {
+    /// Lyrics for a song as plain text or LRC, `None` when the provider has none
+    async fn lookup(&self, song: &Song) -> Result<Option<String>, LyricsProviderError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LyricsProviderError {
+    #[error("Lyrics provider request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Lyrics provider returned HTTP {0}")]
+    Status(StatusCode),
+}
+
+/// Looks lyrics up over HTTP from an LRCLIB-style `get` endpoint, which takes
+/// `artist_name`, `track_name`, `album_name` and `duration` query parameters.
+/// Pointing it at a local stub is enough to try lookups out.
+pub struct HttpLyricsProvider {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProviderResponse {
+    synced_lyrics: Option<String>,
+    plain_lyrics: Option<String>,
+}
+
+impl HttpLyricsProvider {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .user_agent("Muse-Server/0.1.0")
+                .build()
+                .unwrap_or_default(),
+            url: url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl LyricsProvider for HttpLyricsProvider {
+    async fn lookup(&self, song: &Song) -> Result<Option<String>, LyricsProviderError> {
+        let mut query = vec![
+            ("artist_name", song.artist_name.clone()),
+            ("track_name", song.title.clone()),
+        ];
+        if let Some(album) = &song.album {
+            query.push(("album_name", album.clone()));
+        }
+        if let Some(duration) = song.duration {
+            query.push(("duration", duration.to_string()));
+        }
+
+        let response = self.client.get(&self.url).query(&query).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(LyricsProviderError::Status(response.status()));
+        }
+
+        // Synced lyrics are preferred when the provider has both
+        let lyrics: ProviderResponse = response.json().await?;
+        Ok(lyrics.synced_lyrics
+            .filter(|text| !text.trim().is_empty())
+            .or(lyrics.plain_lyrics)
+            .filter(|text| !text.trim().is_empty()))
+    }
+}
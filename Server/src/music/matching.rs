@@ -0,0 +1,269 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::db::Database;
+use crate::db::models::{LibraryFilter, Song};
+use crate::music::playlist_file::PlaylistTrack;
+
+/// Minimum title similarity for a fuzzy match
+const TITLE_THRESHOLD: f64 = 0.85;
+/// Minimum artist similarity for a fuzzy match, when the entry names an artist
+const ARTIST_THRESHOLD: f64 = 0.8;
+/// Candidates fetched per title search
+const SEARCH_LIMIT: usize = 50;
+
+/// How an imported playlist entry was resolved to a library song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMethod {
+    /// File path or stream URL
+    Location,
+    MusicbrainzId,
+    Fuzzy,
+}
+
+/// Resolves playlist file entries against the libraries a user can see
+pub struct LibraryMatcher {
+    db: Arc<dyn Database>,
+    /// Folders of the libraries, for bare file names and relative paths
+    roots: Vec<PathBuf>,
+    libraries: LibraryFilter,
+}
+
+impl LibraryMatcher {
+    pub fn new(db: Arc<dyn Database>, roots: Vec<PathBuf>, libraries: LibraryFilter) -> Self {
+        Self { db, roots, libraries }
+    }
+
+    /// Find the library song for an entry, trying its location first,
+    /// then its MusicBrainz ID, then its artist and title
+    pub async fn resolve(&self, track: &PlaylistTrack) -> Option<(Song, MatchMethod)> {
+        if let Some(location) = &track.location
+            && let Some(song) = self.resolve_location(location).await
+        {
+            return Some((song, MatchMethod::Location));
+        }
+
+        if let Some(musicbrainz_id) = &track.musicbrainz_id
+            && let Ok(song) = self.db.get_song_by_musicbrainz_id(musicbrainz_id).await
+            && self.libraries.allows(&song.library_id)
+        {
+            return Some((song, MatchMethod::MusicbrainzId));
+        }
+
+        if let Some(title) = &track.title
+            && let Some(song) = self.resolve_fuzzy(title, track.artist.as_deref()).await
+        {
+            return Some((song, MatchMethod::Fuzzy));
+        }
+
+        None
+    }
+
+    async fn resolve_location(&self, location: &str) -> Option<Song> {
+        if let Some((artist, title)) = stream_url_song(location) {
+            let songs = self.db.search_songs(&title, &self.libraries, 0, SEARCH_LIMIT).await.ok()?;
+            return songs.into_iter().find(|s| {
+                s.artist_name.eq_ignore_ascii_case(&artist) && s.title.eq_ignore_ascii_case(&title)
+            });
+        }
+
+        for root in &self.roots {
+            for path in candidate_paths(location, root) {
+                if let Some(path) = path.to_str()
+                    && let Ok(song) = self.db.get_song_by_file_path(path).await
+                    && self.libraries.allows(&song.library_id)
+                {
+                    return Some(song);
+                }
+            }
+        }
+
+        None
+    }
+
+    async fn resolve_fuzzy(&self, title: &str, artist: Option<&str>) -> Option<Song> {
+        let mut candidates = self.db.search_songs(title, &self.libraries, 0, SEARCH_LIMIT).await.unwrap_or_default();
+
+        // "Song (Remastered)" won't substring-match "Song", so search the bare title too
+        let bare_title = strip_bracketed(title);
+        if bare_title != title && !bare_title.is_empty() {
+            candidates.extend(self.db.search_songs(&bare_title, &self.libraries, 0, SEARCH_LIMIT).await.unwrap_or_default());
+        }
+
+        if let Some(artist) = artist
+            && let Ok(artist) = self.db.get_artist_by_name(artist).await
+        {
+            candidates.extend(self.db.get_songs_by_artist(&artist.id, &self.libraries).await.unwrap_or_default());
+        }
+
+        candidates.into_iter()
+            .filter_map(|song| match_score(title, artist, &song).map(|score| (score, song)))
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, song)| song)
+    }
+}
+
+/// Score how well a song matches an entry's title and artist,
+/// `None` when it's below the match thresholds
+pub fn match_score(title: &str, artist: Option<&str>, song: &Song) -> Option<f64> {
+    let title_score = similarity(title, &song.title);
+    if title_score < TITLE_THRESHOLD {
+        return None;
+    }
+
+    match artist {
+        Some(artist) => {
+            let artist_score = similarity(artist, &song.artist_name);
+            (artist_score >= ARTIST_THRESHOLD).then_some(title_score * 0.7 + artist_score * 0.3)
+        }
+        None => Some(title_score * 0.7),
+    }
+}
+
+/// Similarity of two names between 0 and 1, ignoring case, punctuation,
+/// bracketed suffixes, featured artists and a leading "The"
+pub fn similarity(a: &str, b: &str) -> f64 {
+    strsim::normalized_levenshtein(&normalize(a), &normalize(b))
+}
+
+fn normalize(value: &str) -> String {
+    let value = strip_bracketed(&value.to_lowercase());
+    let value = [" feat. ", " feat ", " ft. ", " featuring "]
+        .iter()
+        .fold(value, |value, marker| match value.find(marker) {
+            Some(index) => value[..index].to_string(),
+            None => value,
+        });
+
+    let words: Vec<String> = value
+        .replace('&', " and ")
+        .split_whitespace()
+        .map(|word| word.chars().filter(|c| c.is_alphanumeric()).collect::<String>())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    match words.split_first() {
+        Some((first, rest)) if first == "the" && !rest.is_empty() => rest.join(" "),
+        _ => words.join(" "),
+    }
+}
+
+/// Remove "(...)" and "[...]" parts, e.g. "(Remastered 2009)"
+fn strip_bracketed(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut depth = 0usize;
+    for c in value.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Artist and title from one of our own `/api/stream?artist=..&name=..` URLs
+fn stream_url_song(location: &str) -> Option<(String, String)> {
+    if !location.starts_with("http://") && !location.starts_with("https://") {
+        return None;
+    }
+
+    let (path, query) = location.split_once('?')?;
+    if !path.ends_with("/api/stream") {
+        return None;
+    }
+
+    let mut artist = None;
+    let mut name = None;
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = urlencoding::decode(&value.replace('+', " ")).ok()?.into_owned();
+        match key {
+            "artist" => artist = Some(value),
+            "name" => name = Some(value),
+            _ => {}
+        }
+    }
+
+    Some((artist?, name?))
+}
+
+/// File paths an entry's location could refer to: the path as given
+/// (relative to the music directory when not absolute), and the file
+/// name within the music directory, since playlists made on another
+/// machine rarely share our directory layout
+fn candidate_paths(location: &str, music_dir: &Path) -> Vec<PathBuf> {
+    let location = match location.strip_prefix("file://") {
+        Some(path) => match urlencoding::decode(path) {
+            Ok(path) => path.into_owned(),
+            Err(_) => return Vec::new(),
+        },
+        None if location.contains("://") => return Vec::new(),
+        None => location.to_string(),
+    };
+    let location = location.replace('\\', "/");
+
+    let path = Path::new(&location);
+    let mut candidates = vec![if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        music_dir.join(path)
+    }];
+
+    if let Some(file_name) = path.file_name() {
+        let in_music_dir = music_dir.join(file_name);
+        if !candidates.contains(&in_music_dir) {
+            candidates.push(in_music_dir);
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str, artist: &str) -> Song {
+        Song { artist_name: artist.to_string(), ..Song::for_test(title) }
+    }
+
+    #[test]
+    fn test_fuzzy_scores() {
+        let song = song("So What", "Miles Davis");
+
+        assert_eq!(similarity("So What (Remastered 2009)", "so what!"), 1.0);
+        assert_eq!(similarity("The Beatles", "Beatles"), 1.0);
+        assert_eq!(similarity("Song feat. Someone", "Song"), 1.0);
+        assert!(match_score("So What", Some("Miles Davis"), &song).is_some());
+        assert!(match_score("So Wht", Some("miles davis"), &song).is_some());
+        assert!(match_score("So What", Some("John Coltrane"), &song).is_none());
+        assert!(match_score("Freddie Freeloader", None, &song).is_none());
+    }
+
+    #[test]
+    fn test_locations() {
+        let music_dir = Path::new("/srv/music");
+
+        assert_eq!(
+            candidate_paths("file:///home/me/Music/So%20What.flac", music_dir),
+            vec![PathBuf::from("/home/me/Music/So What.flac"), PathBuf::from("/srv/music/So What.flac")]
+        );
+        assert_eq!(candidate_paths("So What.flac", music_dir), vec![PathBuf::from("/srv/music/So What.flac")]);
+        assert_eq!(
+            candidate_paths("..\\other\\a.mp3", music_dir)[1],
+            PathBuf::from("/srv/music/a.mp3")
+        );
+        assert!(candidate_paths("https://example.com/a.mp3", music_dir).is_empty());
+
+        assert_eq!(
+            stream_url_song("http://host:8000/api/stream?artist=Miles%20Davis&name=So+What&format=flac"),
+            Some(("Miles Davis".to_string(), "So What".to_string()))
+        );
+        assert_eq!(stream_url_song("http://host/other?artist=a&name=b"), None);
+    }
+}
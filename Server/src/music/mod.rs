@@ -1,3 +1,13 @@
 pub mod scanner;
+pub mod playlist_file;
+pub mod matching;
+pub mod lyrics;
+pub mod lyrics_provider;
+pub mod cue;
+pub mod gapless;
+pub mod slicing;
+pub mod seek;
+pub mod hls;
+pub mod hls_playlist;
 
 pub use scanner::MusicScanner;
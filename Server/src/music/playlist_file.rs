@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+
+const XSPF_NAMESPACE: &str = "http://xspf.org/ns/0/";
+const MUSICBRAINZ_RECORDING_URL: &str = "https://musicbrainz.org/recording/";
+
+/// Playlist file formats supported for import and export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u8,
+    Xspf,
+    Jspf,
+}
+
+impl PlaylistFormat {
+    pub fn from_string(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "m3u8" | "m3u" => Some(PlaylistFormat::M3u8),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            "jspf" => Some(PlaylistFormat::Jspf),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of an uploaded file from its first characters
+    pub fn detect(content: &str) -> Self {
+        match content.trim_start_matches('\u{feff}').trim_start().chars().next() {
+            Some('<') => PlaylistFormat::Xspf,
+            Some('{') => PlaylistFormat::Jspf,
+            _ => PlaylistFormat::M3u8,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            PlaylistFormat::Xspf => "application/xspf+xml",
+            PlaylistFormat::Jspf => "application/jspf+json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u8 => "m3u8",
+            PlaylistFormat::Xspf => "xspf",
+            PlaylistFormat::Jspf => "jspf",
+        }
+    }
+}
+
+/// A playlist as read from or written to a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlaylistFile {
+    pub title: Option<String>,
+    pub tracks: Vec<PlaylistTrack>,
+}
+
+/// One entry of a playlist file. Every field is optional since
+/// formats and the tools producing them vary in what they include.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlaylistTrack {
+    /// File path or URL
+    pub location: Option<String>,
+    pub musicbrainz_id: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Duration in seconds
+    pub duration: Option<i32>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlaylistFileError {
+    #[error("Invalid XSPF playlist: {0}")]
+    Xspf(String),
+
+    #[error("Invalid JSPF playlist: {0}")]
+    Jspf(String),
+}
+
+impl PlaylistFile {
+    pub fn parse(format: PlaylistFormat, content: &str) -> Result<Self, PlaylistFileError> {
+        let content = content.trim_start_matches('\u{feff}');
+        match format {
+            PlaylistFormat::M3u8 => Ok(parse_m3u8(content)),
+            PlaylistFormat::Xspf => parse_xspf(content),
+            PlaylistFormat::Jspf => parse_jspf(content),
+        }
+    }
+
+    pub fn write(&self, format: PlaylistFormat) -> String {
+        match format {
+            PlaylistFormat::M3u8 => write_m3u8(self),
+            PlaylistFormat::Xspf => write_xspf(self),
+            PlaylistFormat::Jspf => write_jspf(self),
+        }
+    }
+}
+
+/// Extract a MusicBrainz recording ID from a recording URL or a bare ID
+pub fn musicbrainz_id_from_identifier(identifier: &str) -> Option<String> {
+    let id = identifier
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()?;
+
+    uuid::Uuid::parse_str(id).ok().map(|uuid| uuid.to_string())
+}
+
+// ============================================================================
+// M3U8
+// ============================================================================
+
+fn parse_m3u8(content: &str) -> PlaylistFile {
+    let mut playlist = PlaylistFile::default();
+    let mut pending = PlaylistTrack::default();
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(title) = line.strip_prefix("#PLAYLIST:") {
+            playlist.title = Some(title.trim().to_string());
+        } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>[ key="value"...],<artist> - <title>
+            let (duration, display) = info.split_once(',').unwrap_or((info, ""));
+            pending.duration = duration
+                .split_whitespace()
+                .next()
+                .and_then(|secs| secs.parse::<f64>().ok())
+                .filter(|secs| *secs >= 0.0)
+                .map(|secs| secs.round() as i32);
+
+            match display.split_once(" - ") {
+                Some((artist, title)) => {
+                    pending.artist = Some(artist.trim().to_string());
+                    pending.title = Some(title.trim().to_string());
+                }
+                None if !display.trim().is_empty() => {
+                    pending.title = Some(display.trim().to_string());
+                }
+                None => {}
+            }
+        } else if let Some(album) = line.strip_prefix("#EXTALB:") {
+            pending.album = Some(album.trim().to_string());
+        } else if !line.starts_with('#') {
+            pending.location = Some(line.to_string());
+            playlist.tracks.push(std::mem::take(&mut pending));
+        }
+    }
+
+    playlist
+}
+
+fn write_m3u8(playlist: &PlaylistFile) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    if let Some(title) = &playlist.title {
+        out.push_str(&format!("#PLAYLIST:{}\n", single_line(title)));
+    }
+
+    for track in &playlist.tracks {
+        let Some(location) = &track.location else {
+            continue;
+        };
+
+        let display = match (&track.artist, &track.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => String::new(),
+        };
+        out.push_str(&format!("#EXTINF:{},{}\n", track.duration.unwrap_or(-1), single_line(&display)));
+        if let Some(album) = &track.album {
+            out.push_str(&format!("#EXTALB:{}\n", single_line(album)));
+        }
+        out.push_str(location);
+        out.push('\n');
+    }
+
+    out
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+// ============================================================================
+// XSPF
+// ============================================================================
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "playlist")]
+struct Xspf {
+    #[serde(rename = "@version", default)]
+    version: String,
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(rename = "trackList", default)]
+    track_list: XspfTrackList,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct XspfTrackList {
+    #[serde(default)]
+    track: Vec<XspfTrack>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct XspfTrack {
+    #[serde(default)]
+    location: Vec<String>,
+    #[serde(default)]
+    identifier: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    /// Duration in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+}
+
+fn parse_xspf(content: &str) -> Result<PlaylistFile, PlaylistFileError> {
+    let xspf: Xspf = quick_xml::de::from_str(content)
+        .map_err(|e| PlaylistFileError::Xspf(e.to_string()))?;
+
+    Ok(PlaylistFile {
+        title: xspf.title,
+        tracks: xspf.track_list.track.into_iter().map(|track| PlaylistTrack {
+            location: track.location.into_iter().next(),
+            musicbrainz_id: track.identifier.iter().find_map(|id| musicbrainz_id_from_identifier(id)),
+            title: track.title,
+            artist: track.creator,
+            album: track.album,
+            duration: track.duration.map(millis_to_seconds),
+        }).collect(),
+    })
+}
+
+fn write_xspf(playlist: &PlaylistFile) -> String {
+    let xspf = Xspf {
+        version: "1".to_string(),
+        xmlns: XSPF_NAMESPACE.to_string(),
+        title: playlist.title.clone(),
+        track_list: XspfTrackList {
+            track: playlist.tracks.iter().map(|track| XspfTrack {
+                location: track.location.iter().cloned().collect(),
+                identifier: track.musicbrainz_id.iter().map(|id| format!("{}{}", MUSICBRAINZ_RECORDING_URL, id)).collect(),
+                title: track.title.clone(),
+                creator: track.artist.clone(),
+                album: track.album.clone(),
+                duration: track.duration.map(seconds_to_millis),
+            }).collect(),
+        },
+    };
+
+    let body = quick_xml::se::to_string(&xspf).unwrap_or_default();
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}\n", body)
+}
+
+// ============================================================================
+// JSPF
+// ============================================================================
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Jspf {
+    playlist: JspfPlaylist,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JspfPlaylist {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default)]
+    track: Vec<JspfTrack>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JspfTrack {
+    #[serde(default, skip_serializing_if = "OneOrMany::is_empty")]
+    location: OneOrMany,
+    #[serde(default, skip_serializing_if = "OneOrMany::is_empty")]
+    identifier: OneOrMany,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    /// Duration in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+}
+
+/// JSPF lists locations and identifiers as arrays, but older
+/// writers emit a single string
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for OneOrMany {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl OneOrMany {
+    fn is_empty(&self) -> bool {
+        matches!(self, OneOrMany::Many(values) if values.is_empty())
+    }
+
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+fn parse_jspf(content: &str) -> Result<PlaylistFile, PlaylistFileError> {
+    let jspf: Jspf = serde_json::from_str(content)
+        .map_err(|e| PlaylistFileError::Jspf(e.to_string()))?;
+
+    Ok(PlaylistFile {
+        title: jspf.playlist.title,
+        tracks: jspf.playlist.track.into_iter().map(|track| PlaylistTrack {
+            location: track.location.into_vec().into_iter().next(),
+            musicbrainz_id: track.identifier.into_vec().iter().find_map(|id| musicbrainz_id_from_identifier(id)),
+            title: track.title,
+            artist: track.creator,
+            album: track.album,
+            duration: track.duration.map(millis_to_seconds),
+        }).collect(),
+    })
+}
+
+fn write_jspf(playlist: &PlaylistFile) -> String {
+    let jspf = Jspf {
+        playlist: JspfPlaylist {
+            title: playlist.title.clone(),
+            track: playlist.tracks.iter().map(|track| JspfTrack {
+                location: OneOrMany::Many(track.location.iter().cloned().collect()),
+                identifier: OneOrMany::Many(track.musicbrainz_id.iter().map(|id| format!("{}{}", MUSICBRAINZ_RECORDING_URL, id)).collect()),
+                title: track.title.clone(),
+                creator: track.artist.clone(),
+                album: track.album.clone(),
+                duration: track.duration.map(seconds_to_millis),
+            }).collect(),
+        },
+    };
+
+    serde_json::to_string_pretty(&jspf).unwrap_or_default()
+}
+
+fn millis_to_seconds(millis: u64) -> i32 {
+    ((millis + 500) / 1000) as i32
+}
+
+fn seconds_to_millis(seconds: i32) -> u64 {
+    seconds.max(0) as u64 * 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> PlaylistFile {
+        PlaylistFile {
+            title: Some("Kind of Blue & more".to_string()),
+            tracks: vec![
+                PlaylistTrack {
+                    location: Some("So What.flac".to_string()),
+                    musicbrainz_id: Some("2f2a2b8e-3c4d-4e5f-8a9b-0c1d2e3f4a5b".to_string()),
+                    title: Some("So What".to_string()),
+                    artist: Some("Miles Davis".to_string()),
+                    album: Some("Kind of Blue".to_string()),
+                    duration: Some(562),
+                },
+                PlaylistTrack {
+                    location: Some("https://example.com/api/stream?artist=Bill%20Evans&name=Peace%20Piece".to_string()),
+                    title: Some("Peace Piece".to_string()),
+                    artist: Some("Bill Evans".to_string()),
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip_all_formats() {
+        let playlist = sample();
+        for format in [PlaylistFormat::Xspf, PlaylistFormat::Jspf] {
+            let written = playlist.write(format);
+            assert_eq!(PlaylistFormat::detect(&written), format);
+            assert_eq!(PlaylistFile::parse(format, &written).unwrap(), playlist, "{:?}", format);
+        }
+
+        // M3U8 has no place for MusicBrainz IDs
+        let written = playlist.write(PlaylistFormat::M3u8);
+        let parsed = PlaylistFile::parse(PlaylistFormat::M3u8, &written).unwrap();
+        assert_eq!(parsed.title, playlist.title);
+        assert_eq!(parsed.tracks[0].title.as_deref(), Some("So What"));
+        assert_eq!(parsed.tracks[0].album.as_deref(), Some("Kind of Blue"));
+        assert_eq!(parsed.tracks[0].musicbrainz_id, None);
+        assert_eq!(parsed.tracks[1].duration, None);
+        assert_eq!(parsed.tracks[1].location, playlist.tracks[1].location);
+    }
+
+    #[test]
+    fn test_parse_m3u_variants() {
+        let content = "\u{feff}#EXTM3U\r\n#EXTINF:123 tvg-id=\"x\",Artist - Title - Live\r\n/music/a.mp3\r\n\r\nplain/b.mp3\r\n#EXTINF:5,Only Title\r\nc.ogg\r\n";
+        let parsed = PlaylistFile::parse(PlaylistFormat::M3u8, content).unwrap();
+
+        assert_eq!(parsed.tracks.len(), 3);
+        assert_eq!(parsed.tracks[0].artist.as_deref(), Some("Artist"));
+        assert_eq!(parsed.tracks[0].title.as_deref(), Some("Title - Live"));
+        assert_eq!(parsed.tracks[0].duration, Some(123));
+        assert_eq!(parsed.tracks[1], PlaylistTrack { location: Some("plain/b.mp3".to_string()), ..Default::default() });
+        assert_eq!(parsed.tracks[2].artist, None);
+        assert_eq!(parsed.tracks[2].title.as_deref(), Some("Only Title"));
+    }
+
+    #[test]
+    fn test_parse_jspf_single_strings() {
+        let content = r#"{"playlist":{"track":[{"location":"a.mp3","identifier":"https://musicbrainz.org/recording/2F2A2B8E-3C4D-4E5F-8A9B-0C1D2E3F4A5B/","duration":1499}]}}"#;
+        let parsed = PlaylistFile::parse(PlaylistFormat::Jspf, content).unwrap();
+
+        assert_eq!(parsed.tracks[0].location.as_deref(), Some("a.mp3"));
+        assert_eq!(parsed.tracks[0].musicbrainz_id.as_deref(), Some("2f2a2b8e-3c4d-4e5f-8a9b-0c1d2e3f4a5b"));
+        assert_eq!(parsed.tracks[0].duration, Some(1));
+        assert!(PlaylistFile::parse(PlaylistFormat::Jspf, "{}").is_err());
+    }
+}
@@ -1,24 +1,36 @@
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
+use time::OffsetDateTime;
 use lofty::prelude::*;
 use lofty::probe::Probe;
 
 use crate::db::{Database, DbError};
+use crate::db::models::{Artist, Library, LibraryFilter, Song, SongLyrics};
+use crate::music::cue::{read_cue, CueFile, CueSheet, CueTrack};
+use crate::music::gapless::{read_gapless, GaplessInfo};
+use crate::music::lyrics::{read_lyrics, FoundLyrics};
+use crate::music::hls::remove_renditions;
+use crate::music::seek::{remove_seek_table, update_seek_table};
+use crate::db::paging::{PageRequest, SortField, SortOrder};
 
 const COVER_CACHE_DIR: &str = "runtime/cache/covers";
 
+/// Scans one library's folder into the database
 pub struct MusicScanner {
     db: Arc<dyn Database>,
+    library: Library,
     music_dir: PathBuf,
     use_spotify: bool,
 }
 
 impl MusicScanner {
-    pub fn new(db: Arc<dyn Database>, music_dir: impl Into<PathBuf>) -> Self {
+    pub fn new(db: Arc<dyn Database>, library: Library) -> Self {
         Self {
             db,
-            music_dir: music_dir.into(),
+            music_dir: PathBuf::from(&library.root_path),
+            library,
             use_spotify: std::env::var("USE_SPOTIFY_API")
                 .unwrap_or_else(|_| "false".to_string())
                 .parse()
@@ -26,9 +38,9 @@ impl MusicScanner {
         }
     }
 
-    /// Scan the music directory and register all audio files
+    /// Scan the library's folder and register all audio files
     pub async fn scan_and_register(&self) -> Result<ScanResult, ScanError> {
-        tracing::info!("Starting music directory scan: {:?}", self.music_dir);
+        tracing::info!("Starting scan of library '{}': {:?}", self.library.name, self.music_dir);
         
         // Check if directory exists
         if !self.music_dir.exists() {
@@ -48,46 +60,54 @@ impl MusicScanner {
         result.removed = self.cleanup_removed_songs().await?;
 
         // Step 2: Read directory entries
-        let mut entries = match fs::read_dir(&self.music_dir).await {
-            Ok(entries) => entries,
-            Err(e) => return Err(ScanError::IoError(e)),
-        };
+        let files = collect_files(&self.music_dir, self.library.recursive).await?;
+        result.total_files = files.len();
+
+        // Step 3: Register the tracks of cue sheets. The audio files they
+        // split up are not registered as songs of their own.
+        let mut covered = HashSet::new();
+        for cue_path in files.iter().filter(|path| is_cue_sheet(path)) {
+            match read_cue(cue_path) {
+                Ok(sheet) => covered.extend(self.register_cue_sheet(cue_path, &sheet, &mut result).await),
+                Err(e) => {
+                    result.errors += 1;
+                    tracing::error!("Failed to read cue sheet {:?}: {}", cue_path.file_name(), e);
+                }
+            }
+        }
 
-        // Step 3: Process each file
-        while let Some(entry) = entries.next_entry().await.map_err(ScanError::IoError)? {
-            let path = entry.path();
-            
-            // Skip if not a file
-            if !path.is_file() {
+        // Step 4: Process each file
+        for path in &files {
+            if is_cue_sheet(path) || covered.contains(path) {
                 continue;
             }
 
-            result.total_files += 1;
-
             // Check if it's an audio file by extension
-            if !is_audio_file(&path) {
+            if !is_audio_file(path) {
                 result.skipped += 1;
                 continue;
             }
 
+            // Tracks of a cue sheet that has since been removed
+            match self.remove_cue_tracks(path).await {
+                Ok(removed) => result.removed += removed,
+                Err(e) => tracing::error!("Failed to remove cue tracks of {:?}: {}", path.file_name(), e),
+            }
+
             // Register or update the song
-            match self.register_or_update_song(&path).await {
-                Ok(SongAction::Registered) => {
-                    result.registered += 1;
-                    tracing::info!("Registered song: {:?}", path.file_name());
-                }
-                Ok(SongAction::Updated) => {
-                    result.updated += 1;
-                    tracing::info!("Updated song: {:?}", path.file_name());
-                }
-                Ok(SongAction::Skipped) => {
-                    result.skipped += 1;
-                    tracing::debug!("Skipped song (already exists): {:?}", path.file_name());
-                }
-                Err(e) => {
-                    result.errors += 1;
-                    tracing::error!("Failed to register {:?}: {}", path.file_name(), e);
-                }
+            let action = self.register_or_update_song(path).await;
+            result.record(action, &path.file_name().unwrap_or_default().to_string_lossy());
+        }
+
+        // Step 5: Build seek tables for streams that start at a time, for
+        // whole files and cue sheet files alike
+        for path in files.iter().filter(|path| is_audio_file(path)) {
+            let seek_path = path.clone();
+            match tokio::task::spawn_blocking(move || update_seek_table(&seek_path)).await {
+                Ok(Ok(true)) => tracing::debug!("Built seek table for {:?}", path.file_name()),
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => tracing::warn!("Failed to build seek table for {:?}: {}", path.file_name(), e),
+                Err(e) => tracing::warn!("Failed to build seek table for {:?}: {}", path.file_name(), e),
             }
         }
 
@@ -95,49 +115,262 @@ impl MusicScanner {
         Ok(result)
     }
 
-    /// Clean up songs whose files no longer exist
+    /// Clean up songs whose files no longer exist, or that a scan wouldn't
+    /// find any more because the library's folder moved or stopped being recursive
     async fn cleanup_removed_songs(&self) -> Result<usize, ScanError> {
-        let all_songs = self.db.get_songs(0, 10000).await
-            .map_err(ScanError::DatabaseError)?;
+        let removed_count = self.remove_songs(|file_path| !file_path.exists() || !self.is_scanned(file_path)).await?;
         
+        if removed_count > 0 {
+            tracing::info!("Removed {} songs whose files are gone or no longer scanned", removed_count);
+        }
+        
+        Ok(removed_count)
+    }
+
+    /// Remove every song of the library, for when it is deleted. The files are left alone.
+    pub async fn remove_all_songs(&self) -> Result<usize, ScanError> {
+        self.remove_songs(|_| true).await
+    }
+
+    /// Remove the library's songs whose file paths match
+    async fn remove_songs(&self, matches: impl Fn(&Path) -> bool) -> Result<usize, ScanError> {
+        // Keyset paging is unaffected by deleting rows already passed
+        let mut request = PageRequest::new(SortField::Added, SortOrder::Asc, 500);
+        let libraries = self.library_filter();
         let mut removed_count = 0;
         
-        for song in all_songs {
-            let file_path = PathBuf::from(&song.file_path);
+        loop {
+            let page = self.db.get_songs(&request, &libraries).await
+                .map_err(ScanError::DatabaseError)?;
             
-            // Check if file still exists
-            if !file_path.exists() {
-                tracing::info!("Removing song with missing file: {} ({})", song.title, song.file_path);
+            for song in &page.items {
+                let file_path = PathBuf::from(&song.file_path);
                 
-                if let Err(e) = self.db.delete_song_by_id(&song.id).await {
-                    tracing::error!("Failed to remove song {}: {}", song.id, e);
-                } else {
-                    removed_count += 1;
+                if matches(&file_path) {
+                    tracing::info!("Removing song: {} ({})", song.title, song.file_path);
+                    remove_seek_table(&file_path);
+                    remove_renditions(&song.id).await;
+                    
+                    if let Err(e) = self.db.delete_song_by_id(&song.id).await {
+                        tracing::error!("Failed to remove song {}: {}", song.id, e);
+                    } else {
+                        removed_count += 1;
+                    }
                 }
             }
-        }
-        
-        if removed_count > 0 {
-            tracing::info!("Removed {} songs with missing files", removed_count);
+            
+            match page.next_cursor {
+                Some(cursor) => request = request.next(cursor),
+                None => break,
+            }
         }
         
         Ok(removed_count)
     }
 
+    /// Whether a scan of the library looks at this file
+    fn is_scanned(&self, file_path: &Path) -> bool {
+        if self.library.recursive {
+            file_path.starts_with(&self.music_dir)
+        } else {
+            file_path.parent() == Some(self.music_dir.as_path())
+        }
+    }
+
+    /// Only this library, so the same song can be in more than one
+    fn library_filter(&self) -> LibraryFilter {
+        LibraryFilter::Only(vec![self.library.id.clone()])
+    }
+
+    /// Register the tracks of a cue sheet, returning the audio files it covers.
+    /// A file is covered even when its tracks fail to register, so that it
+    /// isn't registered whole instead.
+    async fn register_cue_sheet(&self, cue_path: &Path, sheet: &CueSheet, result: &mut ScanResult) -> Vec<PathBuf> {
+        let dir = cue_path.parent().unwrap_or(&self.music_dir);
+        let mut covered = Vec::new();
+
+        for file in &sheet.files {
+            let Some(audio_path) = find_cue_audio(dir, &file.name) else {
+                result.errors += 1;
+                tracing::error!("Audio file '{}' of cue sheet {:?} not found", file.name, cue_path.file_name());
+                continue;
+            };
+
+            if let Err(e) = self.register_cue_file(sheet, file, &audio_path, result).await {
+                result.errors += 1;
+                tracing::error!("Failed to register the tracks of {:?}: {}", audio_path.file_name(), e);
+            }
+            covered.push(audio_path);
+        }
+        covered
+    }
+
+    /// Register each track of one audio file of a cue sheet, with metadata
+    /// from the cue sheet and the file's own tags where it has none
+    async fn register_cue_file(&self, sheet: &CueSheet, file: &CueFile, path: &Path, result: &mut ScanResult) -> Result<(), ScanError> {
+        let file_path = path
+            .to_str()
+            .ok_or_else(|| ScanError::InvalidFileName(path.to_path_buf()))?;
+
+        let tagged_file = Probe::open(path)
+            .map_err(|e| ScanError::MetadataError(format!("Failed to open file: {}", e)))?
+            .read()
+            .map_err(|e| ScanError::MetadataError(format!("Failed to read metadata: {}", e)))?;
+        let tag = tagged_file.primary_tag();
+        let file_ms = tagged_file.properties().duration().as_millis() as i64;
+        let gapless = read_gapless(path, &tagged_file);
+
+        // A song for the whole file, registered before the cue sheet was added
+        let existing = self.db.get_songs_by_file_path(file_path).await
+            .map_err(ScanError::DatabaseError)?;
+        for song in existing.iter().filter(|song| !song.is_cue_track()) {
+            self.db.delete_song_by_id(&song.id).await.map_err(ScanError::DatabaseError)?;
+            result.removed += 1;
+        }
+
+        let last = file.tracks.len() - 1;
+        for (i, track) in file.tracks.iter().enumerate() {
+            let metadata = SongMetadata {
+                title: track.title.clone().unwrap_or_default(),
+                artist: track.performer.clone()
+                    .or_else(|| sheet.performer.clone())
+                    .or_else(|| tag.and_then(|tag| tag.artist()).map(|artist| artist.to_string()))
+                    .unwrap_or_default(),
+                album: sheet.title.clone().or_else(|| tag.and_then(|tag| tag.album()).map(|album| album.to_string())),
+                duration: Some(((track.end_ms.unwrap_or(file_ms) - track.start_ms).max(0) / 1000) as i32),
+                cover_url: None,
+                musicbrainz_id: None,
+                genre: sheet.genre.clone().or_else(|| tag.and_then(|tag| tag.genre()).map(|genre| genre.to_string())),
+                year: sheet.year.or_else(|| tag.and_then(|tag| tag.year()).and_then(|year| i32::try_from(year).ok())),
+                lyrics: None,
+                // Priming comes before the first track and padding after the last
+                gapless: GaplessInfo {
+                    encoder_delay: if i == 0 { gapless.encoder_delay } else { None },
+                    encoder_padding: if i == last { gapless.encoder_padding } else { None },
+                    sample_rate: gapless.sample_rate,
+                },
+            };
+
+            let name = format!("{} (track {} of {:?})", metadata.title, track.number, path.file_name().unwrap_or_default());
+            let action = self.register_cue_track(file_path, track, metadata, &existing).await;
+            result.record(action, &name);
+        }
+
+        // Tracks taken out of the cue sheet
+        for song in existing.iter().filter(|song| {
+            song.is_cue_track() && !file.tracks.iter().any(|track| song.start_ms == Some(track.start_ms))
+        }) {
+            self.db.delete_song_by_id(&song.id).await.map_err(ScanError::DatabaseError)?;
+            result.removed += 1;
+        }
+        Ok(())
+    }
+
+    /// Register or update one cue sheet track, identified by its file and start
+    async fn register_cue_track(&self, file_path: &str, track: &CueTrack, metadata: SongMetadata, existing: &[Song]) -> Result<SongAction, ScanError> {
+        if metadata.title.is_empty() || metadata.artist.is_empty() {
+            return Err(ScanError::MissingMetadata(
+                PathBuf::from(file_path),
+                format!("Cue sheet track {} has no title or performer", track.number),
+            ));
+        }
+
+        if let Some(song) = existing.iter().find(|song| song.start_ms == Some(track.start_ms)) {
+            if song.title == metadata.title && song.artist_name == metadata.artist {
+                let mut updated = self.update_gapless(song, metadata.gapless).await?;
+                if song.end_ms != track.end_ms {
+                    self.db.update_song_track_range(&song.id, song.start_ms, track.end_ms).await
+                        .map_err(ScanError::DatabaseError)?;
+                    updated = true;
+                }
+                if song.album != metadata.album || song.duration != metadata.duration {
+                    self.db.update_song_metadata(&song.id, metadata.album.as_deref(), metadata.duration, song.cover_image_path.as_deref()).await
+                        .map_err(ScanError::DatabaseError)?;
+                    updated = true;
+                }
+                if metadata.has_new_tags(song) {
+                    self.db.update_song_tags(&song.id, None, metadata.genre.as_deref(), metadata.year).await
+                        .map_err(ScanError::DatabaseError)?;
+                    updated = true;
+                }
+                return Ok(if updated { SongAction::Updated } else { SongAction::Skipped });
+            }
+
+            // The cue sheet was edited, so the track is registered afresh
+            self.db.delete_song_by_id(&song.id).await.map_err(ScanError::DatabaseError)?;
+        }
+
+        let artist = self.artist_named(&metadata.artist).await?;
+        let existing_songs = self.db.get_songs_by_artist(&artist.id, &self.library_filter()).await
+            .map_err(ScanError::DatabaseError)?;
+        if let Some(duplicate) = existing_songs.iter().find(|s| s.title.eq_ignore_ascii_case(&metadata.title)) {
+            tracing::warn!(
+                "Song '{}' by '{}' already exists with file: {}. Skipping cue sheet track {} of {}",
+                metadata.title, metadata.artist, duplicate.file_path, track.number, file_path
+            );
+            return Ok(SongAction::Skipped);
+        }
+
+        let song = self.db.create_song(&metadata.title, &artist.id, file_path, &self.library.id).await
+            .map_err(ScanError::DatabaseError)?;
+        self.db.update_song_track_range(&song.id, Some(track.start_ms), track.end_ms).await
+            .map_err(ScanError::DatabaseError)?;
+        self.db.update_song_metadata(&song.id, metadata.album.as_deref(), metadata.duration, None).await
+            .map_err(ScanError::DatabaseError)?;
+        if metadata.has_new_tags(&song) {
+            self.db.update_song_tags(&song.id, None, metadata.genre.as_deref(), metadata.year).await
+                .map_err(ScanError::DatabaseError)?;
+        }
+        self.update_gapless(&song, metadata.gapless).await?;
+
+        tracing::debug!("Created cue sheet track: {} by {} (ID: {})", metadata.title, metadata.artist, song.id);
+        Ok(SongAction::Registered)
+    }
+
+    /// Remove the cue sheet tracks cut from a file, returning how many there were
+    async fn remove_cue_tracks(&self, path: &Path) -> Result<usize, ScanError> {
+        let Some(file_path) = path.to_str() else {
+            return Ok(0);
+        };
+
+        let mut removed = 0;
+        for song in self.db.get_songs_by_file_path(file_path).await.map_err(ScanError::DatabaseError)? {
+            if song.is_cue_track() {
+                self.db.delete_song_by_id(&song.id).await.map_err(ScanError::DatabaseError)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Get or create the artist with a name
+    async fn artist_named(&self, name: &str) -> Result<Artist, ScanError> {
+        match self.db.get_artist_by_name(name).await {
+            Ok(artist) => Ok(artist),
+            // Artist doesn't exist, create it
+            Err(_) => self.db.create_artist(name).await.map_err(ScanError::DatabaseError),
+        }
+    }
+
+    /// Store a song's gapless info when it has changed, returning whether it did
+    async fn update_gapless(&self, song: &Song, gapless: GaplessInfo) -> Result<bool, ScanError> {
+        if GaplessInfo::of(song) == gapless {
+            return Ok(false);
+        }
+
+        self.db.update_song_gapless(&song.id, gapless.encoder_delay, gapless.encoder_padding, gapless.sample_rate).await
+            .map_err(ScanError::DatabaseError)?;
+        Ok(true)
+    }
+
     /// Register a new song or update existing one
     async fn register_or_update_song(&self, path: &Path) -> Result<SongAction, ScanError> {
         // Extract metadata from the audio file
         let metadata = self.extract_metadata(path).await?;
+        let lyrics = read_lyrics(path, metadata.lyrics.as_deref());
         
         // Get or create the artist
-        let artist = match self.db.get_artist_by_name(&metadata.artist).await {
-            Ok(artist) => artist,
-            Err(_) => {
-                // Artist doesn't exist, create it
-                self.db.create_artist(&metadata.artist).await
-                    .map_err(ScanError::DatabaseError)?
-            }
-        };
+        let artist = self.artist_named(&metadata.artist).await?;
 
         // Convert path to string for storage
         let file_path = path
@@ -145,8 +378,8 @@ impl MusicScanner {
             .ok_or_else(|| ScanError::InvalidFileName(path.to_path_buf()))?
             .to_string();
 
-        // Check if a song with this title and artist already exists
-        let existing_songs = self.db.get_songs_by_artist(&artist.id).await
+        // Check if a song with this title and artist is already in the library
+        let existing_songs = self.db.get_songs_by_artist(&artist.id, &self.library_filter()).await
             .map_err(ScanError::DatabaseError)?;
         
         // Find if there's a song with the same title (case-insensitive)
@@ -155,6 +388,9 @@ impl MusicScanner {
         {
             // Song exists - check if it's the same file or different format
             if existing_song.file_path == file_path {
+                let lyrics_changed = self.update_lyrics(&existing_song.id, lyrics).await?;
+                let gapless_changed = self.update_gapless(existing_song, metadata.gapless).await?;
+                
                 // Same file, check if we need to update metadata
                 if metadata.album.is_some() && existing_song.album.is_none() ||
                    metadata.duration.is_some() && existing_song.duration.is_none() {
@@ -168,6 +404,19 @@ impl MusicScanner {
                     
                     return Ok(SongAction::Updated);
                 }
+                if metadata.has_new_tags(existing_song) {
+                    self.db.update_song_tags(
+                        &existing_song.id,
+                        metadata.musicbrainz_id.as_deref(),
+                        metadata.genre.as_deref(),
+                        metadata.year,
+                    ).await.map_err(ScanError::DatabaseError)?;
+                    
+                    return Ok(SongAction::Updated);
+                }
+                if lyrics_changed || gapless_changed {
+                    return Ok(SongAction::Updated);
+                }
                 return Ok(SongAction::Skipped);
             } else {
                 // Different file path - this is a duplicate in different format
@@ -181,7 +430,7 @@ impl MusicScanner {
         }
 
         // Song doesn't exist, create it
-        let song = self.db.create_song(&metadata.title, &artist.id, &file_path).await
+        let song = self.db.create_song(&metadata.title, &artist.id, &file_path, &self.library.id).await
             .map_err(ScanError::DatabaseError)?;
 
         // Download and cache cover image if available
@@ -210,10 +459,51 @@ impl MusicScanner {
             ).await.map_err(ScanError::DatabaseError)?;
         }
 
+        if metadata.has_new_tags(&song) {
+            self.db.update_song_tags(
+                &song.id,
+                metadata.musicbrainz_id.as_deref(),
+                metadata.genre.as_deref(),
+                metadata.year,
+            ).await.map_err(ScanError::DatabaseError)?;
+        }
+
+        self.update_lyrics(&song.id, lyrics).await?;
+        self.update_gapless(&song, metadata.gapless).await?;
+
         tracing::debug!("Created song: {} by {} (ID: {})", metadata.title, metadata.artist, song.id);
         Ok(SongAction::Registered)
     }
 
+    /// Store the lyrics found for a song's file, returning whether they changed.
+    /// Lyrics entered by an admin or looked up from a provider are left alone.
+    async fn update_lyrics(&self, song_id: &str, found: Option<FoundLyrics>) -> Result<bool, ScanError> {
+        let existing = self.db.get_song_lyrics(song_id).await
+            .map_err(ScanError::DatabaseError)?;
+
+        match (existing, found) {
+            (Some(existing), _) if !existing.source.is_scanned() => Ok(false),
+            (Some(existing), Some(found)) if existing.text == found.text && existing.source == found.source => Ok(false),
+            (_, Some(found)) => {
+                self.db.save_song_lyrics(&SongLyrics {
+                    song_id: song_id.to_string(),
+                    text: found.text,
+                    synced: found.synced,
+                    source: found.source,
+                    updated_at: OffsetDateTime::now_utc(),
+                }).await.map_err(ScanError::DatabaseError)?;
+                Ok(true)
+            }
+            // The lyrics were taken out of the file
+            (Some(_), None) => {
+                self.db.delete_song_lyrics(song_id).await
+                    .map_err(ScanError::DatabaseError)?;
+                Ok(true)
+            }
+            (None, None) => Ok(false),
+        }
+    }
+
     /// Extract metadata from an audio file
     async fn extract_metadata(&self, path: &Path) -> Result<SongMetadata, ScanError> {
         // Try to extract metadata using lofty
@@ -228,6 +518,11 @@ impl MusicScanner {
             album: None,
             duration: None,
             cover_url: None,
+            musicbrainz_id: None,
+            genre: None,
+            year: None,
+            lyrics: None,
+            gapless: read_gapless(path, &tagged_file),
         };
 
         // Extract duration
@@ -248,6 +543,16 @@ impl MusicScanner {
 
             // Extract album
             metadata.album = tag.album().map(|a| a.to_string());
+
+            metadata.musicbrainz_id = tag.get_string(&ItemKey::MusicBrainzRecordingId)
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty());
+
+            metadata.genre = tag.genre()
+                .map(|genre| genre.trim().to_string())
+                .filter(|genre| !genre.is_empty());
+            metadata.year = tag.year().and_then(|year| i32::try_from(year).ok()).filter(|year| *year > 0);
+            metadata.lyrics = tag.get_string(&ItemKey::Lyrics).map(|lyrics| lyrics.to_string());
         }
 
         // Validate that we have both title and artist
@@ -264,8 +569,8 @@ impl MusicScanner {
         }
 
         // Try to enrich with MusicBrainz for additional info (album, cover art)
-        if metadata.album.is_none() || metadata.cover_url.is_none() {
-            if let Ok(enriched) = self.enrich_from_musicbrainz(&metadata.title).await {
+        if (metadata.album.is_none() || metadata.cover_url.is_none())
+            && let Ok(enriched) = self.enrich_from_musicbrainz(&metadata.title).await {
                 if metadata.album.is_none() {
                     metadata.album = enriched.album;
                 }
@@ -273,11 +578,10 @@ impl MusicScanner {
                     metadata.cover_url = enriched.cover_url;
                 }
             }
-        }
 
         // If Spotify is enabled, try to enrich for additional info (album, cover art)
-        if self.use_spotify && (metadata.album.is_none() || metadata.cover_url.is_none()) {
-            if let Ok(enriched) = self.enrich_from_spotify(&metadata.title, &metadata.artist).await {
+        if self.use_spotify && (metadata.album.is_none() || metadata.cover_url.is_none())
+            && let Ok(enriched) = self.enrich_from_spotify(&metadata.title, &metadata.artist).await {
                 if metadata.album.is_none() {
                     metadata.album = enriched.album;
                 }
@@ -285,7 +589,6 @@ impl MusicScanner {
                     metadata.cover_url = enriched.cover_url;
                 }
             }
-        }
 
         Ok(metadata)
     }
@@ -361,8 +664,8 @@ impl MusicScanner {
             .map_err(|e| ScanError::MetadataError(format!("Failed to parse response: {}", e)))?;
 
         // Extract first recording if available
-        if let Some(recordings) = data["recordings"].as_array() {
-            if let Some(recording) = recordings.first() {
+        if let Some(recordings) = data["recordings"].as_array()
+            && let Some(recording) = recordings.first() {
                 let artist = recording["artist-credit"]
                     .as_array()
                     .and_then(|arr| arr.first())
@@ -397,9 +700,13 @@ impl MusicScanner {
                     album,
                     duration: None,
                     cover_url,
+                    musicbrainz_id: None,
+                    genre: None,
+                    year: None,
+                    lyrics: None,
+                    gapless: GaplessInfo::default(),
                 });
             }
-        }
 
         Err(ScanError::MetadataError("No results from MusicBrainz".to_string()))
     }
@@ -424,18 +731,16 @@ impl MusicScanner {
         // Get the front cover image URL
         if let Some(images) = data["images"].as_array() {
             for image in images {
-                if image["front"].as_bool().unwrap_or(false) {
-                    if let Some(url) = image["image"].as_str() {
+                if image["front"].as_bool().unwrap_or(false)
+                    && let Some(url) = image["image"].as_str() {
                         return Ok(url.to_string());
                     }
-                }
             }
             // If no front cover, use first available image
-            if let Some(first_image) = images.first() {
-                if let Some(url) = first_image["image"].as_str() {
+            if let Some(first_image) = images.first()
+                && let Some(url) = first_image["image"].as_str() {
                     return Ok(url.to_string());
                 }
-            }
         }
 
         Err(ScanError::MetadataError("No cover art images available".to_string()))
@@ -501,8 +806,8 @@ impl MusicScanner {
             .map_err(|e| ScanError::MetadataError(format!("Failed to parse Spotify response: {}", e)))?;
 
         // Extract first track if available
-        if let Some(tracks) = data["tracks"]["items"].as_array() {
-            if let Some(track) = tracks.first() {
+        if let Some(tracks) = data["tracks"]["items"].as_array()
+            && let Some(track) = tracks.first() {
                 let spotify_artist = track["artists"]
                     .as_array()
                     .and_then(|arr| arr.first())
@@ -527,14 +832,65 @@ impl MusicScanner {
                     album: spotify_album,
                     duration: None,
                     cover_url,
+                    musicbrainz_id: None,
+                    genre: None,
+                    year: None,
+                    lyrics: None,
+                    gapless: GaplessInfo::default(),
                 });
             }
-        }
 
         Err(ScanError::MetadataError("No results from Spotify".to_string()))
     }
 }
 
+/// Every file in a folder, and in its subfolders when `recursive` is set.
+/// Symlinked folders aren't followed, so links can't make it loop.
+async fn collect_files(root: &Path, recursive: bool) -> Result<Vec<PathBuf>, ScanError> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await.map_err(ScanError::IoError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(ScanError::IoError)? {
+            let path = entry.path();
+            let file_type = entry.file_type().await.map_err(ScanError::IoError)?;
+
+            if file_type.is_dir() {
+                if recursive {
+                    dirs.push(path);
+                }
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn is_cue_sheet(path: &Path) -> bool {
+    path.extension().and_then(|s| s.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// The audio file a cue sheet names. Sheets are often written for a WAV rip
+/// that was then encoded, so a file with the same stem and another audio
+/// extension will do.
+fn find_cue_audio(dir: &Path, name: &str) -> Option<PathBuf> {
+    // Only the file name counts, as the scan doesn't descend into folders
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let path = dir.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+
+    let stem = Path::new(name).file_stem()?;
+    ["flac", "wav", "mp3", "ogg", "opus", "m4a", "wma", "alac", "aac"].into_iter()
+        .map(|ext| dir.join(stem).with_extension(ext))
+        .find(|path| path.is_file())
+}
+
 /// Check if a file is an audio file based on extension
 fn is_audio_file(path: &Path) -> bool {
     if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
@@ -554,6 +910,21 @@ pub struct SongMetadata {
     pub album: Option<String>,
     pub duration: Option<i32>,
     pub cover_url: Option<String>,
+    pub musicbrainz_id: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    /// Unsynced lyrics tag, which may hold LRC
+    pub lyrics: Option<String>,
+    pub gapless: GaplessInfo,
+}
+
+impl SongMetadata {
+    /// Whether the file has a MusicBrainz ID, genre or year the stored song lacks
+    fn has_new_tags(&self, song: &Song) -> bool {
+        (self.musicbrainz_id.is_some() && self.musicbrainz_id != song.musicbrainz_id)
+            || (self.genre.is_some() && self.genre != song.genre)
+            || (self.year.is_some() && self.year != song.year)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -566,6 +937,29 @@ pub struct ScanResult {
     pub errors: usize,
 }
 
+impl ScanResult {
+    fn record(&mut self, action: Result<SongAction, ScanError>, name: &str) {
+        match action {
+            Ok(SongAction::Registered) => {
+                self.registered += 1;
+                tracing::info!("Registered song: {}", name);
+            }
+            Ok(SongAction::Updated) => {
+                self.updated += 1;
+                tracing::info!("Updated song: {}", name);
+            }
+            Ok(SongAction::Skipped) => {
+                self.skipped += 1;
+                tracing::debug!("Skipped song (already exists): {}", name);
+            }
+            Err(e) => {
+                self.errors += 1;
+                tracing::error!("Failed to register {}: {}", name, e);
+            }
+        }
+    }
+}
+
 enum SongAction {
     Registered,
     Updated,
@@ -0,0 +1,217 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::music::slicing::{self, SliceError};
+
+const SEEK_CACHE_DIR: &str = "runtime/cache/seek";
+
+/// How far apart the points of a seek table are
+const POINT_INTERVAL_MS: u64 = 1000;
+
+/// How much of a FLAC file is scanned at a time
+const CHUNK: u64 = 256 * 1024;
+
+/// The longest a FLAC frame header can be
+const FLAC_HEADER_MAX: usize = 16;
+
+/// Where frames start in an audio file, about once a second, so a stream can
+/// start at any time by walking at most a second's worth of frames. Built
+/// while scanning and kept beside the cover cache, one file per audio file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeekTable {
+    /// Size and modification time of the file when the table was built
+    pub file_size: u64,
+    pub modified_ms: u64,
+    /// First sample and byte offset of a frame, in order
+    pub points: Vec<(u64, u64)>,
+}
+
+impl SeekTable {
+    /// The last point at or before `sample`
+    pub fn point_before(&self, sample: u64) -> Option<(u64, u64)> {
+        let after = self.points.partition_point(|&(point, _)| point <= sample);
+        after.checked_sub(1).map(|index| self.points[index])
+    }
+}
+
+/// Build a seek table by reading every frame of a file, or `None` for
+/// formats without one. WAV needs none as its times map straight to bytes.
+pub fn build_seek_table(path: &Path) -> Result<Option<SeekTable>, SliceError> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+    let mut file = File::open(path)?;
+    let points = match extension.as_deref() {
+        Some("flac") => flac_points(&mut file)?,
+        Some("mp3") => mp3_points(&mut file)?,
+        _ => return Ok(None),
+    };
+    let (file_size, modified_ms) = file_stamp(path)?;
+    Ok(Some(SeekTable { file_size, modified_ms, points }))
+}
+
+/// The cached seek table of a file, unless the file changed since it was built
+pub fn load_seek_table(path: &Path) -> Option<SeekTable> {
+    let table: SeekTable = serde_json::from_slice(&fs::read(cache_path(path)).ok()?).ok()?;
+    let (file_size, modified_ms) = file_stamp(path).ok()?;
+    (table.file_size == file_size && table.modified_ms == modified_ms).then_some(table)
+}
+
+/// Build and cache the seek table of a file unless an up-to-date one is
+/// cached already. Returns whether a table was built.
+pub fn update_seek_table(path: &Path) -> Result<bool, SliceError> {
+    if load_seek_table(path).is_some() {
+        return Ok(false);
+    }
+    let Some(table) = build_seek_table(path)? else {
+        return Ok(false);
+    };
+    fs::create_dir_all(SEEK_CACHE_DIR)?;
+    let json = serde_json::to_vec(&table).map_err(std::io::Error::other)?;
+    fs::write(cache_path(path), json)?;
+    Ok(true)
+}
+
+pub fn remove_seek_table(path: &Path) {
+    let _ = fs::remove_file(cache_path(path));
+}
+
+/// Cache files are named by an FNV-1a hash of the audio file's path
+fn cache_path(path: &Path) -> PathBuf {
+    let hash = path.to_string_lossy().bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    Path::new(SEEK_CACHE_DIR).join(format!("{:016x}.json", hash))
+}
+
+/// Size and modification time of a file, to tell when it has changed
+pub fn file_stamp(path: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified_ms = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    Ok((metadata.len(), modified_ms))
+}
+
+/// Keeps the first frame of each interval
+struct Points {
+    interval: u64,
+    next: u64,
+    points: Vec<(u64, u64)>,
+}
+
+impl Points {
+    fn new(sample_rate: u64) -> Self {
+        Points { interval: (sample_rate * POINT_INTERVAL_MS / 1000).max(1), next: 0, points: Vec::new() }
+    }
+
+    fn frame(&mut self, sample: u64, offset: u64) {
+        if sample >= self.next {
+            self.points.push((sample, offset));
+            self.next = (sample / self.interval + 1) * self.interval;
+        }
+    }
+}
+
+/// Frame headers carry their first sample, so FLAC files are scanned for
+/// them. A header only counts when it follows on from the previous frame,
+/// which rules out sync codes that happen to appear in the audio.
+fn flac_points<R: Read + Seek>(file: &mut R) -> Result<Vec<(u64, u64)>, SliceError> {
+    let info = slicing::read_stream_info(file)?;
+    let mut points = Points::new(info.sample_rate);
+    let mut last: Option<u64> = None;
+
+    file.seek(SeekFrom::Start(info.audio_start))?;
+    let mut buf = Vec::new();
+    let mut base = info.audio_start;
+    loop {
+        let read = file.by_ref().take(CHUNK).read_to_end(&mut buf)? as u64;
+        let end = read < CHUNK;
+        // Headers straddling the chunk are picked up with the next one
+        let scan_to = if end { buf.len() } else { buf.len().saturating_sub(FLAC_HEADER_MAX) };
+
+        for i in 0..scan_to {
+            let Some(sample) = slicing::flac_frame_sample(&buf[i..], &info) else { continue };
+            let follows = match last {
+                None => sample == 0,
+                Some(last) => sample > last && sample <= last + info.max_block_size,
+            };
+            if follows {
+                points.frame(sample, base + i as u64);
+                last = Some(sample);
+            }
+        }
+
+        if end {
+            break;
+        }
+        buf.drain(..scan_to);
+        base += scan_to as u64;
+    }
+    Ok(points.points)
+}
+
+/// MP3 frames are walked by their lengths, resyncing past any junk
+fn mp3_points<R: Read + Seek>(file: &mut R) -> Result<Vec<(u64, u64)>, SliceError> {
+    let audio = slicing::mp3_audio(file)?;
+    let mut points = Points::new(audio.first.sample_rate);
+
+    let mut reader = BufReader::new(file);
+    let mut offset = reader.seek(SeekFrom::Start(audio.start))?;
+    let mut sample = 0;
+    let mut header = [0u8; 4];
+    while offset + 4 <= audio.end {
+        reader.read_exact(&mut header)?;
+        match slicing::mp3_frame(&header) {
+            Some(frame) => {
+                points.frame(sample, offset);
+                sample += frame.samples;
+                offset += frame.len;
+                reader.seek_relative(frame.len as i64 - 4)?;
+            }
+            None => match slicing::next_mp3_frame(&mut reader, offset + 1, audio.end)? {
+                Some((next, _)) => offset = reader.seek(SeekFrom::Start(next))?,
+                None => break,
+            },
+        }
+    }
+    Ok(points.points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music::slicing::tests::{flac, mp3, FLAC_AUDIO_START};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_flac_points() {
+        let points = flac_points(&mut Cursor::new(flac(200))).unwrap();
+        // 4096-sample frames, so each second starts in frame 11, 22, 33...
+        assert_eq!(points[0], (0, FLAC_AUDIO_START));
+        assert_eq!(points[1], (11 * 4096, FLAC_AUDIO_START + 11 * 1000));
+        assert_eq!(points[2], (22 * 4096, FLAC_AUDIO_START + 22 * 1000));
+        assert_eq!(points.len(), 19);
+    }
+
+    #[test]
+    fn test_mp3_points_skip_junk() {
+        let mut file = mp3(10);
+        file.extend([0xff; 7]);
+        file.extend(mp3(100));
+        let points = mp3_points(&mut Cursor::new(file)).unwrap();
+        // 1152-sample frames, so the second second starts in frame 39
+        assert_eq!(points[1], (39 * 1152, 39 * 417 + 7));
+    }
+
+    #[test]
+    fn test_point_before() {
+        let table = SeekTable { file_size: 0, modified_ms: 0, points: vec![(0, 10), (100, 20), (200, 30)] };
+        assert_eq!(table.point_before(0), Some((0, 10)));
+        assert_eq!(table.point_before(150), Some((100, 20)));
+        assert_eq!(table.point_before(500), Some((200, 30)));
+        assert_eq!(SeekTable { points: Vec::new(), ..table }.point_before(5), None);
+    }
+}
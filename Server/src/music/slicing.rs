@@ -0,0 +1,662 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::music::seek::SeekTable;
+
+/// How much of a file is read at a time while looking for a frame
+const WINDOW: usize = 16 * 1024;
+
+/// Longest WAV `fmt ` chunk accepted; `WAVE_FORMAT_EXTENSIBLE` needs 40 bytes
+const MAX_WAV_FMT_LEN: u64 = 64;
+
+/// A cut of an audio file that plays on its own: a rewritten header followed
+/// by one contiguous region of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slice {
+    pub header: Vec<u8>,
+    pub offset: u64,
+    pub len: u64,
+    /// Where in the file the cut starts, at or before the time asked for
+    pub start_ms: u64,
+}
+
+impl Slice {
+    /// The whole file, untouched
+    pub fn whole(file_size: u64) -> Self {
+        Slice { header: Vec::new(), offset: 0, len: file_size, start_ms: 0 }
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.header.len() as u64 + self.len
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SliceError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid audio file: {0}")]
+    Invalid(&'static str),
+
+    #[error("There is no audio between the given times")]
+    Empty,
+}
+
+/// Cut the audio between two times out of a file, or `None` for formats that
+/// can't be cut. FLAC and MP3 cuts start on the frame holding the start time
+/// and end on the one holding the end time, so consecutive tracks join up
+/// exactly; WAV cuts are exact. A seek table from the scan finds the frames
+/// directly; without one, FLAC files are bisected and MP3 positions estimated.
+pub fn slice_file(path: &Path, start_ms: i64, end_ms: Option<i64>, table: Option<&SeekTable>) -> Result<Option<Slice>, SliceError> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+    let cut = match extension.as_deref() {
+        Some("flac") => slice_flac,
+        Some("mp3") => slice_mp3,
+        Some("wav") => |file: &mut File, start_ms, end_ms, _: Option<&SeekTable>| slice_wav(file, start_ms, end_ms),
+        _ => return Ok(None),
+    };
+    cut(&mut File::open(path)?, start_ms.max(0) as u64, end_ms.map(|end| end.max(0) as u64), table)
+}
+
+fn ms_to_samples(ms: u64, sample_rate: u64) -> u64 {
+    ms * sample_rate / 1000
+}
+
+/// Read up to `len` bytes at `offset`, fewer at the end of the file
+fn read_at<R: Read + Seek>(file: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(offset))?;
+    // Lengths come from headers in the file, so never reserve more than it holds
+    let mut buf = Vec::with_capacity(len.min(file_size.saturating_sub(offset) as usize));
+    file.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+// ============================================================================
+// FLAC
+// ============================================================================
+
+pub(super) struct StreamInfo {
+    block: [u8; 34],
+    min_block_size: u64,
+    pub(super) max_block_size: u64,
+    pub(super) sample_rate: u64,
+    total_samples: u64,
+    pub(super) audio_start: u64,
+}
+
+/// A FLAC cut is `fLaC`, the STREAMINFO block with its sample count patched
+/// and MD5 cleared, then the frames. Other metadata blocks are left out.
+fn slice_flac<R: Read + Seek>(file: &mut R, start_ms: u64, end_ms: Option<u64>, table: Option<&SeekTable>) -> Result<Option<Slice>, SliceError> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    let info = read_stream_info(file)?;
+
+    let start_target = ms_to_samples(start_ms, info.sample_rate);
+    if info.total_samples > 0 && start_target >= info.total_samples {
+        return Err(SliceError::Empty);
+    }
+    let (start, start_sample) = flac_frame_before(file, &info, file_size, start_target, table)?;
+    let (end, end_sample) = match end_ms.map(|ms| ms_to_samples(ms, info.sample_rate)) {
+        Some(target) if info.total_samples == 0 || target < info.total_samples => {
+            flac_frame_before(file, &info, file_size, target, table)?
+        }
+        _ => (file_size, info.total_samples),
+    };
+    if end <= start {
+        return Err(SliceError::Empty);
+    }
+
+    let mut block = info.block;
+    let samples = end_sample.saturating_sub(start_sample);
+    // Total samples are the low 36 bits of bytes 13..18
+    block[13] = (block[13] & 0xf0) | ((samples >> 32) as u8 & 0x0f);
+    block[14..18].copy_from_slice(&(samples as u32).to_be_bytes());
+    block[18..34].fill(0);
+
+    let mut header = b"fLaC".to_vec();
+    // Last metadata block, type 0, 34 bytes
+    header.extend([0x80, 0, 0, 34]);
+    header.extend(block);
+    Ok(Some(Slice {
+        header,
+        offset: start,
+        len: end - start,
+        start_ms: start_sample * 1000 / info.sample_rate,
+    }))
+}
+
+pub(super) fn read_stream_info<R: Read + Seek>(file: &mut R) -> Result<StreamInfo, SliceError> {
+    let head = read_at(file, 0, 8)?;
+    if head.len() < 8 || &head[..4] != b"fLaC" || head[4] & 0x7f != 0 {
+        return Err(SliceError::Invalid("Not a FLAC file"));
+    }
+    let mut block = [0u8; 34];
+    file.read_exact(&mut block)?;
+
+    // Walk the remaining metadata blocks to find where the frames start
+    let mut audio_start = 8 + u32::from_be_bytes([0, head[5], head[6], head[7]]) as u64;
+    let mut last = head[4] & 0x80 != 0;
+    while !last {
+        let block_header = read_at(file, audio_start, 4)?;
+        if block_header.len() < 4 {
+            return Err(SliceError::Invalid("Truncated FLAC metadata"));
+        }
+        last = block_header[0] & 0x80 != 0;
+        audio_start += 4 + u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]) as u64;
+    }
+
+    let info = StreamInfo {
+        min_block_size: u16::from_be_bytes([block[0], block[1]]) as u64,
+        max_block_size: u16::from_be_bytes([block[2], block[3]]) as u64,
+        sample_rate: ((block[10] as u64) << 12) | ((block[11] as u64) << 4) | (block[12] as u64 >> 4),
+        total_samples: ((block[13] as u64 & 0x0f) << 32) | u32::from_be_bytes([block[14], block[15], block[16], block[17]]) as u64,
+        block,
+        audio_start,
+    };
+    if info.sample_rate == 0 {
+        return Err(SliceError::Invalid("FLAC stream has no sample rate"));
+    }
+    Ok(info)
+}
+
+/// The offset and first sample of the frame holding `target`. The search
+/// starts from the seek table's nearest point, or from bisecting on byte
+/// offsets and reading the sample number of the frame found there.
+fn flac_frame_before<R: Read + Seek>(file: &mut R, info: &StreamInfo, file_size: u64, target: u64, table: Option<&SeekTable>) -> Result<(u64, u64), SliceError> {
+    let mut best = match table.and_then(|table| table.point_before(target)) {
+        Some((sample, offset)) => (offset, sample),
+        None => bisect_flac(file, info, file_size, target)?,
+    };
+
+    // Close enough to walk frame by frame
+    while let Some(next) = next_flac_frame(file, info, best.0 + 1, file_size)? {
+        if next.1 > target {
+            break;
+        }
+        best = next;
+    }
+    Ok(best)
+}
+
+fn bisect_flac<R: Read + Seek>(file: &mut R, info: &StreamInfo, file_size: u64, target: u64) -> io::Result<(u64, u64)> {
+    let mut best = (info.audio_start, 0);
+    let mut high = file_size;
+    while high - best.0 > WINDOW as u64 {
+        let middle = best.0 + (high - best.0) / 2;
+        match next_flac_frame(file, info, middle, file_size)? {
+            Some((offset, sample)) if sample <= target => best = (offset, sample),
+            _ => high = middle,
+        }
+    }
+    Ok(best)
+}
+
+fn next_flac_frame<R: Read + Seek>(file: &mut R, info: &StreamInfo, from: u64, file_size: u64) -> io::Result<Option<(u64, u64)>> {
+    let mut offset = from;
+    while offset < file_size {
+        let buf = read_at(file, offset, WINDOW + 16)?;
+        let searchable = buf.len().min(WINDOW);
+        for i in 0..searchable {
+            if let Some(sample) = flac_frame_sample(&buf[i..], info) {
+                return Ok(Some((offset + i as u64, sample)));
+            }
+        }
+        offset += searchable as u64;
+    }
+    Ok(None)
+}
+
+/// The first sample of the frame whose header starts `buf`, if it is a valid one
+pub(super) fn flac_frame_sample(buf: &[u8], info: &StreamInfo) -> Option<u64> {
+    if *buf.first()? != 0xff || buf.get(1)? & 0xfe != 0xf8 {
+        return None;
+    }
+    let variable = buf[1] & 1 == 1;
+    let (block_code, rate_code) = (buf.get(2)? >> 4, buf[2] & 0x0f);
+    let (channels, sample_size) = (buf.get(3)? >> 4, (buf[3] >> 1) & 0x07);
+    if block_code == 0 || rate_code == 0x0f || channels > 10 || sample_size == 3 || buf[3] & 1 != 0 {
+        return None;
+    }
+
+    // The frame or sample number is coded like UTF-8, in up to 7 bytes
+    let first = *buf.get(4)?;
+    let extra = match first.leading_ones() {
+        0 => 0,
+        1 | 8 => return None,
+        n => n as usize - 1,
+    };
+    let mut number = (first & (0x7f >> extra)) as u64;
+    for &b in buf.get(5..5 + extra)? {
+        if b & 0xc0 != 0x80 {
+            return None;
+        }
+        number = (number << 6) | (b & 0x3f) as u64;
+    }
+
+    let mut len = 5 + extra;
+    len += match block_code { 6 => 1, 7 => 2, _ => 0 };
+    len += match rate_code { 12 => 1, 13 | 14 => 2, _ => 0 };
+    if crc8(buf.get(..len)?) != *buf.get(len)? {
+        return None;
+    }
+
+    let sample = if variable { number } else { number * info.min_block_size };
+    (info.total_samples == 0 || sample < info.total_samples).then_some(sample)
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &b| {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+// ============================================================================
+// MP3
+// ============================================================================
+
+pub(super) struct Mp3Frame {
+    pub(super) len: u64,
+    pub(super) samples: u64,
+    pub(super) sample_rate: u64,
+    bitrate: u64,
+}
+
+pub(super) fn mp3_frame(header: &[u8]) -> Option<Mp3Frame> {
+    if header.len() < 4 || header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate_index = (header[2] >> 4) as usize;
+    let rate_index = ((header[2] >> 2) & 0x03) as usize;
+    // Layer III only, and no reserved or free-format values
+    if version == 1 || layer != 1 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+        return None;
+    }
+
+    const MPEG1_BITRATES: [u64; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const MPEG2_BITRATES: [u64; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    let mpeg1 = version == 3;
+    let bitrate = (if mpeg1 { MPEG1_BITRATES } else { MPEG2_BITRATES })[bitrate_index] * 1000;
+    let sample_rate = [44100, 48000, 32000][rate_index] >> match version { 3 => 0, 2 => 1, _ => 2 };
+    let samples = if mpeg1 { 1152 } else { 576 };
+    let padding = ((header[2] >> 1) & 1) as u64;
+
+    Some(Mp3Frame {
+        len: samples / 8 * bitrate / sample_rate + padding,
+        samples,
+        sample_rate,
+        bitrate,
+    })
+}
+
+/// Where an MP3's audio frames are
+pub(super) struct Mp3Audio {
+    /// The first frame with audio, after any Xing or Info frame
+    pub(super) start: u64,
+    /// Before any ID3v1 tag
+    pub(super) end: u64,
+    pub(super) first: Mp3Frame,
+    xing: Option<Xing>,
+}
+
+pub(super) fn mp3_audio<R: Read + Seek>(file: &mut R) -> Result<Mp3Audio, SliceError> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+
+    let mut start = 0;
+    let head = read_at(file, 0, 10)?;
+    if head.starts_with(b"ID3") && head.len() == 10 {
+        start = head[6..10].iter().fold(0u64, |size, &b| (size << 7) | (b & 0x7f) as u64) + 10;
+    }
+    let mut end = file_size;
+    if file_size >= 128 && read_at(file, file_size - 128, 3)? == b"TAG" {
+        end -= 128;
+    }
+
+    let Some((start, first)) = next_mp3_frame(file, start, end)? else {
+        return Err(SliceError::Invalid("No MP3 frames found"));
+    };
+
+    // A Xing or Info frame holds no audio; it is dropped so the frame count
+    // of the whole file doesn't make players misjudge a cut's length
+    let xing = find_xing(&read_at(file, start, first.len as usize)?);
+    let start = if xing.is_some() { start + first.len } else { start };
+    Ok(Mp3Audio { start, end, first, xing })
+}
+
+/// An MP3 cut needs no header: it is the frames between the two times
+fn slice_mp3<R: Read + Seek>(file: &mut R, start_ms: u64, end_ms: Option<u64>, table: Option<&SeekTable>) -> Result<Option<Slice>, SliceError> {
+    let audio = mp3_audio(file)?;
+    let sample_rate = audio.first.sample_rate;
+
+    let (start, start_ms, end) = match table {
+        Some(table) => {
+            let (start, start_sample) = mp3_frame_before(file, &audio, table, ms_to_samples(start_ms, sample_rate))?;
+            let end = match end_ms {
+                Some(ms) => mp3_frame_before(file, &audio, table, ms_to_samples(ms, sample_rate))?.0,
+                None => audio.end,
+            };
+            (start, start_sample * 1000 / sample_rate, end)
+        }
+        None => estimate_mp3_cut(file, &audio, start_ms, end_ms)?,
+    };
+
+    if start >= end {
+        return Err(SliceError::Empty);
+    }
+    Ok(Some(Slice { header: Vec::new(), offset: start, len: end - start, start_ms }))
+}
+
+/// The offset and first sample of the frame holding `target`, walking from
+/// the seek table's nearest point, or the end of the audio when it's past it
+fn mp3_frame_before<R: Read + Seek>(file: &mut R, audio: &Mp3Audio, table: &SeekTable, target: u64) -> io::Result<(u64, u64)> {
+    let (mut sample, mut offset) = table.point_before(target).unwrap_or((0, audio.start));
+    while offset < audio.end {
+        let Some(frame) = mp3_frame(&read_at(file, offset, 4)?) else {
+            break;
+        };
+        if sample + frame.samples > target {
+            return Ok((offset, sample));
+        }
+        offset += frame.len;
+        sample += frame.samples;
+    }
+    Ok((audio.end, sample))
+}
+
+/// Positions from the Xing table of contents when there is one, and
+/// proportional to the time otherwise, which is exact for constant bitrates
+fn estimate_mp3_cut<R: Read + Seek>(file: &mut R, audio: &Mp3Audio, start_ms: u64, end_ms: Option<u64>) -> Result<(u64, u64, u64), SliceError> {
+    let audio_len = audio.end.saturating_sub(audio.start);
+    let duration_ms = match audio.xing.as_ref().and_then(|xing| xing.frames) {
+        Some(frames) if frames > 0 => frames * audio.first.samples * 1000 / audio.first.sample_rate,
+        _ => audio_len * 8 * 1000 / audio.first.bitrate,
+    };
+    if duration_ms == 0 {
+        return Err(SliceError::Invalid("MP3 has no audio"));
+    }
+
+    let position = |ms: u64| -> u64 {
+        let ms = ms.min(duration_ms);
+        match audio.xing.as_ref().and_then(|xing| xing.toc.as_ref()) {
+            Some(toc) => {
+                let percent = (ms * 100 / duration_ms).min(99) as usize;
+                audio.start + toc[percent] as u64 * audio_len / 256
+            }
+            None => audio.start + audio_len * ms / duration_ms,
+        }
+    };
+
+    let start = match start_ms {
+        0 => audio.start,
+        ms if ms >= duration_ms => return Err(SliceError::Empty),
+        ms => next_mp3_frame(file, position(ms), audio.end)?.map_or(audio.end, |(offset, _)| offset),
+    };
+    let end = match end_ms {
+        Some(ms) if ms < duration_ms => next_mp3_frame(file, position(ms), audio.end)?.map_or(audio.end, |(offset, _)| offset),
+        _ => audio.end,
+    };
+    let start_ms = (start - audio.start) * duration_ms / audio_len.max(1);
+    Ok((start, start_ms, end))
+}
+
+/// The next frame header at or after `from` that is followed by another one
+pub(super) fn next_mp3_frame<R: Read + Seek>(file: &mut R, from: u64, end: u64) -> io::Result<Option<(u64, Mp3Frame)>> {
+    let mut offset = from;
+    while offset < end {
+        let buf = read_at(file, offset, WINDOW + 4)?;
+        let searchable = buf.len().min(WINDOW);
+        for i in 0..searchable {
+            let Some(frame) = mp3_frame(&buf[i..]) else { continue };
+            let next = offset + i as u64 + frame.len;
+            if next >= end || mp3_frame(&read_at(file, next, 4)?).is_some() {
+                return Ok(Some((offset + i as u64, frame)));
+            }
+        }
+        offset += searchable as u64;
+    }
+    Ok(None)
+}
+
+struct Xing {
+    frames: Option<u64>,
+    toc: Option<Vec<u8>>,
+}
+
+fn find_xing(frame: &[u8]) -> Option<Xing> {
+    let start = [36, 21, 13].into_iter()
+        .find(|&at| frame.get(at..at + 4).is_some_and(|tag| tag == b"Xing" || tag == b"Info"))?;
+    let flags = *frame.get(start + 7)?;
+
+    let mut at = start + 8;
+    let mut xing = Xing { frames: None, toc: None };
+    if flags & 1 != 0 {
+        let count = frame.get(at..at + 4)?;
+        xing.frames = Some(u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as u64);
+        at += 4;
+    }
+    if flags & 2 != 0 {
+        at += 4;
+    }
+    if flags & 4 != 0 {
+        xing.toc = frame.get(at..at + 100).map(<[u8]>::to_vec);
+    }
+    Some(xing)
+}
+
+// ============================================================================
+// WAV
+// ============================================================================
+
+/// A WAV cut is a fresh RIFF header with the original `fmt ` chunk, then the
+/// PCM between the two times, cut on whole sample frames
+fn slice_wav<R: Read + Seek>(file: &mut R, start_ms: u64, end_ms: Option<u64>) -> Result<Option<Slice>, SliceError> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    let head = read_at(file, 0, 12)?;
+    if head.len() < 12 || &head[..4] != b"RIFF" || &head[8..12] != b"WAVE" {
+        return Err(SliceError::Invalid("Not a WAV file"));
+    }
+
+    let mut fmt = None;
+    let mut data = None;
+    let mut offset = 12;
+    while offset + 8 <= file_size && data.is_none() {
+        let chunk = read_at(file, offset, 8)?;
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+        match &chunk[..4] {
+            b"fmt " if size > MAX_WAV_FMT_LEN => return Err(SliceError::Invalid("WAV fmt chunk is too long")),
+            b"fmt " => fmt = Some(read_at(file, offset + 8, size as usize)?),
+            b"data" => data = Some((offset + 8, size.min(file_size - offset - 8))),
+            _ => {}
+        }
+        offset += 8 + size + (size & 1);
+    }
+
+    let (Some(fmt), Some((data_start, data_len))) = (fmt, data) else {
+        return Err(SliceError::Invalid("WAV file has no fmt or data chunk"));
+    };
+    if fmt.len() < 16 {
+        return Err(SliceError::Invalid("WAV fmt chunk is too short"));
+    }
+    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]) as u64;
+    let block_align = u16::from_le_bytes([fmt[12], fmt[13]]) as u64;
+    if sample_rate == 0 || block_align == 0 {
+        return Err(SliceError::Invalid("WAV fmt chunk is invalid"));
+    }
+
+    let whole_frames = data_len / block_align * block_align;
+    let byte_at = |ms: u64| (ms_to_samples(ms, sample_rate) * block_align).min(whole_frames);
+    let start = byte_at(start_ms);
+    let end = end_ms.map_or(whole_frames, byte_at);
+    if end <= start {
+        return Err(SliceError::Empty);
+    }
+    let len = end - start;
+
+    let fmt_len = fmt.len() as u64 + (fmt.len() as u64 & 1);
+    let mut header = b"RIFF".to_vec();
+    header.extend(((4 + 8 + fmt_len + 8 + len) as u32).to_le_bytes());
+    header.extend(b"WAVEfmt ");
+    header.extend((fmt.len() as u32).to_le_bytes());
+    header.extend(&fmt);
+    if fmt.len() % 2 == 1 {
+        header.push(0);
+    }
+    header.extend(b"data");
+    header.extend((len as u32).to_le_bytes());
+    Ok(Some(Slice {
+        header,
+        offset: data_start + start,
+        len,
+        start_ms: start / block_align * 1000 / sample_rate,
+    }))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// 44.1 kHz 16-bit stereo PCM, `seconds` long, after a `LIST` chunk
+    fn wav(seconds: u64) -> Vec<u8> {
+        let data_len = 44100 * 4 * seconds;
+        let mut wav = b"RIFF".to_vec();
+        wav.extend(((4 + 24 + 12 + 8 + data_len) as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend([1, 0, 2, 0]);
+        wav.extend(44100u32.to_le_bytes());
+        wav.extend((44100u32 * 4).to_le_bytes());
+        wav.extend([4, 0, 16, 0]);
+        wav.extend(b"LIST");
+        wav.extend(4u32.to_le_bytes());
+        wav.extend(b"INFO");
+        wav.extend(b"data");
+        wav.extend((data_len as u32).to_le_bytes());
+        wav.extend(vec![0; data_len as usize]);
+        wav
+    }
+
+    #[test]
+    fn test_slice_wav() {
+        let slice = slice_wav(&mut Cursor::new(wav(3)), 1000, Some(2500)).unwrap().unwrap();
+        // 12 + 24 for fmt + 12 for LIST + 8 for the data header
+        assert_eq!(slice.offset, 56 + 44100 * 4);
+        assert_eq!(slice.len, 66150 * 4);
+        assert_eq!(slice.start_ms, 1000);
+        assert_eq!(slice.header.len(), 44);
+        assert_eq!(&slice.header[40..44], &(66150u32 * 4).to_le_bytes());
+
+        let last = slice_wav(&mut Cursor::new(wav(3)), 2500, None).unwrap().unwrap();
+        assert_eq!(last.offset + last.len, 56 + 44100 * 4 * 3);
+        assert!(matches!(slice_wav(&mut Cursor::new(wav(3)), 3000, None), Err(SliceError::Empty)));
+    }
+
+    #[test]
+    fn test_wav_fmt_chunk_too_long() {
+        let mut file = wav(1);
+        file[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(slice_wav(&mut Cursor::new(file), 0, None), Err(SliceError::Invalid(_))));
+    }
+
+    /// Where the frames of [`flac`] start
+    pub(in crate::music) const FLAC_AUDIO_START: u64 = 8 + 34 + 4 + 10;
+
+    /// A FLAC stream of 4096-sample frames at 44.1 kHz, each 1000 bytes long
+    pub(in crate::music) fn flac(frames: u64) -> Vec<u8> {
+        let mut flac = b"fLaC".to_vec();
+        let mut info = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+        // 44.1 kHz, 2 channels, 16 bits
+        info.extend([0x0a, 0xc4, 0x42, 0xf0]);
+        info.extend(((frames * 4096) as u32).to_be_bytes());
+        info.extend([0xaa; 16]);
+        flac.extend([0x00, 0, 0, 34]);
+        flac.extend(info);
+        // A padding block as the last metadata block
+        flac.extend([0x81, 0, 0, 10]);
+        flac.extend([0; 10]);
+
+        for number in 0..frames {
+            let mut frame = vec![0xff, 0xf8, 0xc0, 0x18];
+            frame.extend(encode_number(number));
+            frame.push(crc8(&frame));
+            frame.resize(1000, 0);
+            flac.extend(frame);
+        }
+        flac
+    }
+
+    fn encode_number(number: u64) -> Vec<u8> {
+        if number < 0x80 {
+            vec![number as u8]
+        } else {
+            vec![0xc0 | (number >> 6) as u8, 0x80 | (number & 0x3f) as u8]
+        }
+    }
+
+    /// Constant bitrate MP3 frames: 128 kbps at 44.1 kHz without padding is
+    /// 417 bytes and 1152 samples per frame
+    pub(in crate::music) fn mp3(frames: usize) -> Vec<u8> {
+        let mut file = Vec::new();
+        for _ in 0..frames {
+            file.extend([0xff, 0xfb, 0x90, 0x00]);
+            file.extend([0; 413]);
+        }
+        file
+    }
+
+    #[test]
+    fn test_slice_flac() {
+        let file = flac(200);
+
+        // 1 s is sample 44100, in frame 10 (40960..45056)
+        let slice = slice_flac(&mut Cursor::new(file.clone()), 1000, Some(15000), None).unwrap().unwrap();
+        assert_eq!(slice.offset, FLAC_AUDIO_START + 10 * 1000);
+        assert_eq!(slice.start_ms, 40960 * 1000 / 44100);
+        // 15 s is sample 661500, in frame 161
+        assert_eq!(slice.len, 151 * 1000);
+        assert_eq!(&slice.header[..8], &[b'f', b'L', b'a', b'C', 0x80, 0, 0, 34]);
+        assert_eq!(&slice.header[22..26], &((151 * 4096) as u32).to_be_bytes());
+        assert!(slice.header[26..].iter().all(|&b| b == 0));
+
+        let last = slice_flac(&mut Cursor::new(file.clone()), 15000, None, None).unwrap().unwrap();
+        assert_eq!(last.offset, slice.offset + slice.len);
+        assert_eq!(last.offset + last.len, file.len() as u64);
+    }
+
+    #[test]
+    fn test_slice_mp3() {
+        let slice = slice_mp3(&mut Cursor::new(mp3(100)), 1000, Some(2000), None).unwrap().unwrap();
+        assert!(slice.header.is_empty());
+        assert_eq!(slice.offset % 417, 0);
+        assert_eq!(slice.len % 417, 0);
+        assert_eq!(slice.offset, 39 * 417);
+        assert_eq!(slice.offset + slice.len, 77 * 417);
+    }
+
+    #[test]
+    fn test_slice_with_seek_table() {
+        let table = SeekTable { file_size: 0, modified_ms: 0, points: vec![(0, 0), (39 * 1152, 39 * 417)] };
+        // 1 s is sample 44100, in frame 38 (43776..44928); 2 s is in frame 76
+        let slice = slice_mp3(&mut Cursor::new(mp3(100)), 1000, Some(2000), Some(&table)).unwrap().unwrap();
+        assert_eq!(slice.offset, 38 * 417);
+        assert_eq!(slice.offset + slice.len, 76 * 417);
+        assert_eq!(slice.start_ms, 43776 * 1000 / 44100);
+        assert!(matches!(slice_mp3(&mut Cursor::new(mp3(100)), 5000, None, Some(&table)), Err(SliceError::Empty)));
+
+        let table = SeekTable { file_size: 0, modified_ms: 0, points: vec![(0, FLAC_AUDIO_START), (11 * 4096, FLAC_AUDIO_START + 11 * 1000)] };
+        let with_table = slice_flac(&mut Cursor::new(flac(200)), 1000, Some(15000), Some(&table)).unwrap();
+        assert_eq!(with_table, slice_flac(&mut Cursor::new(flac(200)), 1000, Some(15000), None).unwrap());
+    }
+
+    #[test]
+    fn test_unsupported_format() {
+        assert_eq!(slice_file(Path::new("mix.ogg"), 0, None, None).unwrap(), None);
+    }
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+/// Shared playback clock of a room. Rather than ticking, it remembers the
+/// position at a moment in server time, so any client can work out where
+/// playback is now from its own estimate of the server's clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomClock {
+    pub playing: bool,
+    /// Position in the current song at `server_time_ms`
+    pub position_ms: i64,
+    /// Server time, in Unix milliseconds, the position was taken at
+    pub server_time_ms: i64,
+}
+
+impl RoomClock {
+    /// Stopped at the start of a song
+    pub fn stopped(now_ms: i64) -> Self {
+        RoomClock { playing: false, position_ms: 0, server_time_ms: now_ms }
+    }
+
+    pub fn position_at(&self, now_ms: i64) -> i64 {
+        if self.playing {
+            self.position_ms + (now_ms - self.server_time_ms).max(0)
+        } else {
+            self.position_ms
+        }
+    }
+
+    /// The same clock, re-anchored at `now_ms`
+    pub fn at(&self, now_ms: i64) -> Self {
+        RoomClock { playing: self.playing, position_ms: self.position_at(now_ms), server_time_ms: now_ms }
+    }
+
+    pub fn play(&self, now_ms: i64) -> Self {
+        RoomClock { playing: true, ..self.at(now_ms) }
+    }
+
+    pub fn pause(&self, now_ms: i64) -> Self {
+        RoomClock { playing: false, ..self.at(now_ms) }
+    }
+
+    pub fn seek(&self, position_ms: i64, now_ms: i64) -> Self {
+        RoomClock { playing: self.playing, position_ms: position_ms.max(0), server_time_ms: now_ms }
+    }
+
+    /// Whether a song of the given length has played to its end
+    pub fn finished(&self, duration_ms: i64, now_ms: i64) -> bool {
+        self.playing && self.position_at(now_ms) >= duration_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playing_clock_advances() {
+        let clock = RoomClock::stopped(1_000).play(1_000);
+        assert_eq!(clock.position_at(1_000), 0);
+        assert_eq!(clock.position_at(4_500), 3_500);
+    }
+
+    #[test]
+    fn test_paused_clock_holds() {
+        let clock = RoomClock::stopped(0).play(0).pause(2_000);
+        assert_eq!(clock.position_at(2_000), 2_000);
+        assert_eq!(clock.position_at(60_000), 2_000);
+
+        let resumed = clock.play(10_000);
+        assert_eq!(resumed.position_at(11_000), 3_000);
+    }
+
+    #[test]
+    fn test_seek_keeps_play_state() {
+        let clock = RoomClock::stopped(0).play(0).seek(30_000, 5_000);
+        assert!(clock.playing);
+        assert_eq!(clock.position_at(6_000), 31_000);
+        assert_eq!(clock.seek(-5, 6_000).position_ms, 0);
+    }
+
+    #[test]
+    fn test_finished() {
+        let clock = RoomClock::stopped(0).play(0);
+        assert!(!clock.finished(180_000, 179_999));
+        assert!(clock.finished(180_000, 180_000));
+        assert!(!clock.pause(170_000).finished(180_000, 400_000));
+    }
+
+    #[test]
+    fn test_clock_ignores_times_before_anchor() {
+        let clock = RoomClock::stopped(0).play(10_000);
+        assert_eq!(clock.position_at(9_000), 0);
+    }
+}
@@ -0,0 +1,666 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+use crate::db::Database;
+use crate::db::models::Song;
+use crate::rooms::clock::RoomClock;
+use crate::rooms::protocol::{ClientMessage, RoomSong, RoomState, ServerMessage, SuggestionInfo};
+
+/// Messages waiting for a slow member before new ones are dropped
+const MEMBER_QUEUE_SIZE: usize = 64;
+/// Rooms nobody has been in for this long are closed
+const EMPTY_ROOM_TTL_MS: i64 = 10 * 60 * 1000;
+/// How often playing rooms resend their clock, to correct drift
+const RESYNC_INTERVAL_MS: i64 = 5_000;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RoomError {
+    #[error("Room not found")]
+    NotFound,
+
+    #[error("Only the host can do that")]
+    NotHost,
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+struct Member {
+    user_id: String,
+    username: String,
+    /// Tells a reconnect apart from the connection it replaced
+    connection: u64,
+    sender: mpsc::Sender<ServerMessage>,
+}
+
+struct Suggestion {
+    id: String,
+    song: Song,
+    username: String,
+}
+
+struct Room {
+    id: String,
+    name: String,
+    host_id: String,
+    host_name: String,
+    /// In the order they joined, so the longest present takes over as host
+    members: Vec<Member>,
+    queue: Vec<Song>,
+    current: Option<usize>,
+    clock: RoomClock,
+    suggestions: Vec<Suggestion>,
+    skip_votes: HashSet<String>,
+    empty_since: Option<i64>,
+    last_sync_ms: i64,
+}
+
+impl Room {
+    fn state(&self) -> RoomState {
+        RoomState {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            host: self.host_name.clone(),
+            members: self.members.iter().map(|member| member.username.clone()).collect(),
+            queue: self.queue.iter().map(RoomSong::from).collect(),
+            current_index: self.current,
+            clock: self.clock,
+            suggestions: self.suggestions.iter()
+                .map(|suggestion| SuggestionInfo {
+                    id: suggestion.id.clone(),
+                    song: RoomSong::from(&suggestion.song),
+                    suggested_by: suggestion.username.clone(),
+                })
+                .collect(),
+            skip_votes: self.skip_votes.len(),
+            skip_votes_needed: self.skip_votes_needed(),
+        }
+    }
+
+    fn summary(&self) -> RoomSummary {
+        RoomSummary {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            host: self.host_name.clone(),
+            members: self.members.len(),
+            now_playing: self.current.and_then(|index| self.queue.get(index)).map(RoomSong::from),
+        }
+    }
+
+    fn snapshot(&self) -> RoomSnapshot {
+        RoomSnapshot {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            host_id: self.host_id.clone(),
+            host_name: self.host_name.clone(),
+            song_ids: self.queue.iter().map(|song| song.id.clone()).collect(),
+            current_index: self.current,
+            clock: self.clock,
+        }
+    }
+
+    /// More than half of the members present
+    fn skip_votes_needed(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn broadcast(&self, message: ServerMessage) {
+        for member in &self.members {
+            send(member, message.clone());
+        }
+    }
+
+    fn broadcast_state(&self) {
+        self.broadcast(ServerMessage::Room { room: self.state() });
+    }
+
+    fn ensure_host(&self, user_id: &str) -> Result<(), RoomError> {
+        if self.host_id == user_id {
+            Ok(())
+        } else {
+            Err(RoomError::NotHost)
+        }
+    }
+
+    /// Move on to the next song, keeping the room playing if it was
+    fn advance(&mut self, now_ms: i64) {
+        let playing = self.clock.playing;
+        self.current = self.current
+            .map(|index| index + 1)
+            .filter(|index| *index < self.queue.len());
+        self.clock = RoomClock::stopped(now_ms);
+        if playing && self.current.is_some() {
+            self.clock = self.clock.play(now_ms);
+        }
+        self.skip_votes.clear();
+        self.last_sync_ms = now_ms;
+    }
+
+    fn apply(&mut self, user_id: &str, message: ClientMessage, now_ms: i64) -> Result<(), RoomError> {
+        match message {
+            ClientMessage::Play => {
+                self.ensure_host(user_id)?;
+                if self.current.is_none() {
+                    return Err(RoomError::Invalid("Nothing left in the queue".to_string()));
+                }
+                self.clock = self.clock.play(now_ms);
+            }
+            ClientMessage::Pause => {
+                self.ensure_host(user_id)?;
+                self.clock = self.clock.pause(now_ms);
+            }
+            ClientMessage::Seek { position_ms } => {
+                self.ensure_host(user_id)?;
+                self.clock = self.clock.seek(position_ms, now_ms);
+            }
+            ClientMessage::Skip => {
+                self.ensure_host(user_id)?;
+                self.advance(now_ms);
+            }
+            ClientMessage::VoteSkip => {
+                if self.current.is_none() {
+                    return Err(RoomError::Invalid("Nothing is playing".to_string()));
+                }
+                self.skip_votes.insert(user_id.to_string());
+                if self.skip_votes.len() >= self.skip_votes_needed() {
+                    self.advance(now_ms);
+                }
+            }
+            ClientMessage::AcceptSuggestion { suggestion_id } => {
+                self.ensure_host(user_id)?;
+                let position = self.suggestions.iter().position(|suggestion| suggestion.id == suggestion_id)
+                    .ok_or_else(|| RoomError::Invalid("Suggestion not found".to_string()))?;
+                let suggestion = self.suggestions.remove(position);
+                self.enqueue(suggestion.song, now_ms);
+            }
+            ClientMessage::RejectSuggestion { suggestion_id } => {
+                self.ensure_host(user_id)?;
+                let before = self.suggestions.len();
+                self.suggestions.retain(|suggestion| suggestion.id != suggestion_id);
+                if self.suggestions.len() == before {
+                    return Err(RoomError::Invalid("Suggestion not found".to_string()));
+                }
+            }
+            // Suggestions need their song looked up first, and the rest only concern the sender
+            ClientMessage::Suggest { .. } | ClientMessage::Ping { .. } | ClientMessage::Reauth { .. } | ClientMessage::Leave => {}
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, song: Song, now_ms: i64) {
+        self.queue.push(song);
+        // A queue that had run out picks up from the new song
+        if self.current.is_none() {
+            self.current = Some(self.queue.len() - 1);
+            self.clock = RoomClock::stopped(now_ms);
+        }
+    }
+}
+
+fn send(member: &Member, message: ServerMessage) {
+    if member.sender.try_send(message).is_err() {
+        tracing::debug!("Dropped a room message for slow or closed member {}", member.username);
+    }
+}
+
+/// A room as listed to people who might join
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomSummary {
+    pub id: String,
+    pub name: String,
+    pub host: String,
+    pub members: usize,
+    pub now_playing: Option<RoomSong>,
+}
+
+/// What is kept of a room across restarts. Members reconnect on their own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub id: String,
+    pub name: String,
+    pub host_id: String,
+    pub host_name: String,
+    pub song_ids: Vec<String>,
+    pub current_index: Option<usize>,
+    pub clock: RoomClock,
+}
+
+/// A member's connection to a room, holding the messages pushed to it
+pub struct RoomSession {
+    pub room_id: String,
+    connection: u64,
+    pub messages: mpsc::Receiver<ServerMessage>,
+}
+
+/// Listening rooms, kept in memory
+#[derive(Default)]
+pub struct RoomHub {
+    rooms: Mutex<HashMap<String, Room>>,
+    next_connection: AtomicU64,
+    /// Latest snapshot of every room, when rooms are persisted
+    snapshots: Option<watch::Sender<Vec<RoomSnapshot>>>,
+}
+
+impl RoomHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rooms that are also written to a JSON file whenever they change
+    pub fn with_persistence(path: PathBuf) -> Self {
+        let (sender, receiver) = watch::channel(Vec::new());
+        tokio::spawn(write_snapshots(path, receiver));
+
+        RoomHub {
+            snapshots: Some(sender),
+            ..Self::default()
+        }
+    }
+
+    /// Bring back rooms saved by a previous run, paused until someone joins
+    pub async fn restore(&self, path: &PathBuf, db: &dyn Database) -> Result<usize, String> {
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.to_string()),
+        };
+        let snapshots: Vec<RoomSnapshot> = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+        let now_ms = now_ms();
+
+        let mut rooms = Vec::with_capacity(snapshots.len());
+        for snapshot in snapshots {
+            let mut queue = Vec::with_capacity(snapshot.song_ids.len());
+            let mut current = snapshot.current_index;
+            for (index, song_id) in snapshot.song_ids.iter().enumerate() {
+                match db.get_song_by_id(song_id).await {
+                    Ok(song) => queue.push(song),
+                    // Keep pointing at the same song when earlier ones are gone
+                    Err(_) => current = current.map(|current| if index < current { current - 1 } else { current }),
+                }
+            }
+
+            rooms.push(Room {
+                id: snapshot.id,
+                name: snapshot.name,
+                host_id: snapshot.host_id,
+                host_name: snapshot.host_name,
+                members: Vec::new(),
+                current: current.filter(|index| *index < queue.len()),
+                queue,
+                clock: snapshot.clock.pause(snapshot.clock.server_time_ms).at(now_ms),
+                suggestions: Vec::new(),
+                skip_votes: HashSet::new(),
+                empty_since: Some(now_ms),
+                last_sync_ms: now_ms,
+            });
+        }
+
+        let restored = rooms.len();
+        let mut all_rooms = self.rooms.lock().unwrap();
+        for room in rooms {
+            all_rooms.insert(room.id.clone(), room);
+        }
+        Ok(restored)
+    }
+
+    pub fn create(&self, name: &str, host_id: &str, host_name: &str, queue: Vec<Song>) -> RoomState {
+        let now_ms = now_ms();
+        let room = Room {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            host_id: host_id.to_string(),
+            host_name: host_name.to_string(),
+            members: Vec::new(),
+            current: if queue.is_empty() { None } else { Some(0) },
+            queue,
+            clock: RoomClock::stopped(now_ms),
+            suggestions: Vec::new(),
+            skip_votes: HashSet::new(),
+            empty_since: Some(now_ms),
+            last_sync_ms: now_ms,
+        };
+        let state = room.state();
+
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.insert(room.id.clone(), room);
+        self.persist(&rooms);
+        state
+    }
+
+    /// Open rooms, largest first
+    pub fn list(&self) -> Vec<RoomSummary> {
+        let rooms = self.rooms.lock().unwrap();
+        let mut summaries: Vec<RoomSummary> = rooms.values().map(Room::summary).collect();
+        summaries.sort_by(|a, b| b.members.cmp(&a.members).then_with(|| a.name.cmp(&b.name)));
+        summaries
+    }
+
+    pub fn contains(&self, room_id: &str) -> bool {
+        self.rooms.lock().unwrap().contains_key(room_id)
+    }
+
+    /// Close a room, for its host or a user who may close any room
+    pub fn close(&self, room_id: &str, user_id: &str, close_any: bool) -> Result<(), RoomError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get(room_id).ok_or(RoomError::NotFound)?;
+        if !close_any {
+            room.ensure_host(user_id)?;
+        }
+
+        room.broadcast(ServerMessage::Closed);
+        // Dropping the members' senders ends their sessions
+        rooms.remove(room_id);
+        self.persist(&rooms);
+        Ok(())
+    }
+
+    /// Join a room. A second connection from the same user replaces the first.
+    pub fn join(&self, room_id: &str, user_id: &str, username: &str) -> Result<RoomSession, RoomError> {
+        let (sender, messages) = mpsc::channel(MEMBER_QUEUE_SIZE);
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(room_id).ok_or(RoomError::NotFound)?;
+
+        room.members.retain(|member| member.user_id != user_id);
+        room.members.push(Member {
+            user_id: user_id.to_string(),
+            username: username.to_string(),
+            connection,
+            sender,
+        });
+        room.empty_since = None;
+        room.broadcast_state();
+
+        Ok(RoomSession {
+            room_id: room_id.to_string(),
+            connection,
+            messages,
+        })
+    }
+
+    /// Leave a room when the socket closes, unless the member already
+    /// reconnected. A leaving host hands over to whoever has been there longest.
+    pub fn leave(&self, user_id: &str, session: &RoomSession) {
+        let now_ms = now_ms();
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(&session.room_id) else {
+            return;
+        };
+
+        let before = room.members.len();
+        room.members.retain(|member| member.user_id != user_id || member.connection != session.connection);
+        if room.members.len() == before {
+            return;
+        }
+        room.skip_votes.remove(user_id);
+
+        if room.host_id == user_id && let Some(next_host) = room.members.first() {
+            room.host_id = next_host.user_id.clone();
+            room.host_name = next_host.username.clone();
+        }
+
+        if room.members.is_empty() {
+            // Nobody is listening, so hold the place until someone is back
+            room.clock = room.clock.pause(now_ms);
+            room.empty_since = Some(now_ms);
+        } else {
+            room.broadcast_state();
+        }
+        self.persist(&rooms);
+    }
+
+    /// Act on a message from a member. Mistakes are sent back to them only.
+    pub fn handle(&self, user_id: &str, session: &RoomSession, message: ClientMessage) {
+        let now_ms = now_ms();
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(&session.room_id) else {
+            return;
+        };
+
+        if let ClientMessage::Ping { client_time_ms } = message {
+            if let Some(member) = room.members.iter().find(|member| member.connection == session.connection) {
+                send(member, ServerMessage::Pong { client_time_ms, server_time_ms: now_ms });
+            }
+            return;
+        }
+
+        let playback_only = matches!(message, ClientMessage::Play | ClientMessage::Pause | ClientMessage::Seek { .. });
+        match room.apply(user_id, message, now_ms) {
+            Ok(()) if playback_only => {
+                room.last_sync_ms = now_ms;
+                room.broadcast(ServerMessage::Playback { current_index: room.current, clock: room.clock });
+            }
+            Ok(()) => room.broadcast_state(),
+            Err(e) => {
+                if let Some(member) = room.members.iter().find(|member| member.connection == session.connection) {
+                    send(member, ServerMessage::Error { message: e.to_string() });
+                }
+                return;
+            }
+        }
+        self.persist(&rooms);
+    }
+
+    /// Suggest a song. The host's suggestions go straight into the queue.
+    pub fn suggest(&self, user_id: &str, session: &RoomSession, song: Song) {
+        let now_ms = now_ms();
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(&session.room_id) else {
+            return;
+        };
+        let Some(member) = room.members.iter().find(|member| member.connection == session.connection) else {
+            return;
+        };
+
+        if room.host_id == user_id {
+            room.enqueue(song, now_ms);
+        } else {
+            let username = member.username.clone();
+            room.suggestions.push(Suggestion { id: Uuid::new_v4().to_string(), song, username });
+        }
+        room.broadcast_state();
+        self.persist(&rooms);
+    }
+
+    /// Move rooms on to their next song as songs end, resync playing rooms
+    /// now and then, and close rooms left empty
+    pub fn tick(&self, now_ms: i64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut changed = false;
+
+        rooms.retain(|_, room| room.empty_since.is_none_or(|since| now_ms - since < EMPTY_ROOM_TTL_MS));
+
+        for room in rooms.values_mut() {
+            let duration_ms = room.current
+                .and_then(|index| room.queue.get(index))
+                .and_then(|song| song.duration)
+                .map(|duration| i64::from(duration) * 1000);
+
+            if duration_ms.is_some_and(|duration_ms| room.clock.finished(duration_ms, now_ms)) {
+                room.advance(now_ms);
+                room.broadcast_state();
+                changed = true;
+            } else if room.clock.playing && now_ms - room.last_sync_ms >= RESYNC_INTERVAL_MS {
+                room.last_sync_ms = now_ms;
+                room.broadcast(ServerMessage::Playback { current_index: room.current, clock: room.clock.at(now_ms) });
+            }
+        }
+
+        if changed {
+            self.persist(&rooms);
+        }
+    }
+
+    /// Tick every second for as long as the server runs
+    pub async fn run_clock(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.tick(now_ms());
+        }
+    }
+
+    fn persist(&self, rooms: &HashMap<String, Room>) {
+        if let Some(snapshots) = &self.snapshots {
+            snapshots.send_replace(rooms.values().map(Room::snapshot).collect());
+        }
+    }
+}
+
+/// Write the latest snapshot each time rooms change. Snapshots that come in
+/// while a write is underway are folded into the next one.
+async fn write_snapshots(path: PathBuf, mut snapshots: watch::Receiver<Vec<RoomSnapshot>>) {
+    while snapshots.changed().await.is_ok() {
+        let json = serde_json::to_vec(&*snapshots.borrow_and_update());
+        match json {
+            Ok(json) => {
+                if let Err(e) = tokio::fs::write(&path, json).await {
+                    tracing::warn!("Failed to save listening rooms to {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => tracing::warn!("Failed to encode listening rooms: {}", e),
+        }
+    }
+}
+
+pub fn now_ms() -> i64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, duration: i32) -> Song {
+        Song { duration: Some(duration), ..Song::for_test(id) }
+    }
+
+    fn drain(session: &mut RoomSession) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(message) = session.messages.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn last_room(messages: &[ServerMessage]) -> RoomState {
+        messages.iter().rev()
+            .find_map(|message| match message {
+                ServerMessage::Room { room } => Some(room.clone()),
+                _ => None,
+            })
+            .expect("a room update")
+    }
+
+    #[test]
+    fn test_only_host_controls_playback() {
+        let hub = RoomHub::new();
+        let room = hub.create("Office", "host", "Host", vec![song("s1", 180)]);
+        let mut host = hub.join(&room.id, "host", "Host").unwrap();
+        let mut guest = hub.join(&room.id, "guest", "Guest").unwrap();
+        drain(&mut host);
+        drain(&mut guest);
+
+        hub.handle("guest", &guest, ClientMessage::Play);
+        assert!(matches!(drain(&mut guest).as_slice(), [ServerMessage::Error { .. }]));
+        assert!(drain(&mut host).is_empty());
+
+        hub.handle("host", &host, ClientMessage::Play);
+        assert!(matches!(drain(&mut guest).as_slice(), [ServerMessage::Playback { clock, .. }] if clock.playing));
+    }
+
+    #[test]
+    fn test_vote_skip_needs_majority() {
+        let hub = RoomHub::new();
+        let room = hub.create("Office", "a", "A", vec![song("s1", 180), song("s2", 180)]);
+        let a = hub.join(&room.id, "a", "A").unwrap();
+        let b = hub.join(&room.id, "b", "B").unwrap();
+        let mut c = hub.join(&room.id, "c", "C").unwrap();
+
+        hub.handle("b", &b, ClientMessage::VoteSkip);
+        hub.handle("b", &b, ClientMessage::VoteSkip);
+        let state = last_room(&drain(&mut c));
+        assert_eq!((state.current_index, state.skip_votes, state.skip_votes_needed), (Some(0), 1, 2));
+
+        hub.handle("c", &c, ClientMessage::VoteSkip);
+        let state = last_room(&drain(&mut c));
+        assert_eq!((state.current_index, state.skip_votes), (Some(1), 0));
+        drop(a);
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let hub = RoomHub::new();
+        let room = hub.create("Office", "host", "Host", Vec::new());
+        let host = hub.join(&room.id, "host", "Host").unwrap();
+        let mut guest = hub.join(&room.id, "guest", "Guest").unwrap();
+
+        hub.suggest("guest", &guest, song("s1", 180));
+        let state = last_room(&drain(&mut guest));
+        assert!(state.queue.is_empty());
+        assert_eq!(state.suggestions[0].suggested_by, "Guest");
+
+        hub.handle("host", &host, ClientMessage::AcceptSuggestion { suggestion_id: state.suggestions[0].id.clone() });
+        let state = last_room(&drain(&mut guest));
+        assert_eq!(state.queue.len(), 1);
+        assert_eq!(state.current_index, Some(0));
+        assert!(state.suggestions.is_empty());
+
+        hub.suggest("host", &host, song("s2", 180));
+        assert_eq!(last_room(&drain(&mut guest)).queue.len(), 2);
+    }
+
+    #[test]
+    fn test_tick_advances_finished_songs() {
+        let hub = RoomHub::new();
+        let room = hub.create("Office", "host", "Host", vec![song("s1", 1), song("s2", 180)]);
+        let mut host = hub.join(&room.id, "host", "Host").unwrap();
+        hub.handle("host", &host, ClientMessage::Play);
+        drain(&mut host);
+
+        hub.tick(now_ms() + 1_500);
+        let state = last_room(&drain(&mut host));
+        assert_eq!(state.current_index, Some(1));
+        assert!(state.clock.playing);
+    }
+
+    #[test]
+    fn test_host_hands_over_and_empty_rooms_close() {
+        let hub = RoomHub::new();
+        let room = hub.create("Office", "host", "Host", Vec::new());
+        let host = hub.join(&room.id, "host", "Host").unwrap();
+        let guest = hub.join(&room.id, "guest", "Guest").unwrap();
+
+        hub.leave("host", &host);
+        assert_eq!(hub.list()[0].host, "Guest");
+
+        hub.leave("guest", &guest);
+        hub.tick(now_ms() + EMPTY_ROOM_TTL_MS - 1_000);
+        assert_eq!(hub.list().len(), 1);
+        hub.tick(now_ms() + EMPTY_ROOM_TTL_MS + 1_000);
+        assert!(hub.list().is_empty());
+    }
+
+    #[test]
+    fn test_rejoin_replaces_connection() {
+        let hub = RoomHub::new();
+        let room = hub.create("Office", "host", "Host", Vec::new());
+        let old = hub.join(&room.id, "host", "Host").unwrap();
+        let new = hub.join(&room.id, "host", "Host").unwrap();
+
+        hub.leave("host", &old);
+        assert_eq!(hub.list()[0].members, 1);
+        hub.leave("host", &new);
+        assert_eq!(hub.list()[0].members, 0);
+    }
+}
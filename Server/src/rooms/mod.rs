@@ -0,0 +1,5 @@
+pub mod clock;
+pub mod hub;
+pub mod protocol;
+
+pub use hub::RoomHub;
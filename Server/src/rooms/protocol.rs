@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::db::models::Song;
+use crate::rooms::clock::RoomClock;
+
+/// Messages members send over the room socket
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Host only
+    Play,
+    Pause,
+    Seek { position_ms: i64 },
+    Skip,
+    AcceptSuggestion { suggestion_id: String },
+    RejectSuggestion { suggestion_id: String },
+
+    // Anyone
+    /// Goes straight into the queue when the host suggests it
+    Suggest { song_id: String },
+    VoteSkip,
+    /// Ask for the server time, to estimate the offset of the local clock
+    Ping { client_time_ms: i64 },
+    /// A refreshed token for the same user, to stay in the room past the
+    /// expiry of the one the socket was opened with
+    Reauth { token: String },
+    Leave,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomSong {
+    pub id: String,
+    pub name: String,
+    pub artist_name: String,
+    pub duration: Option<i32>,
+}
+
+impl From<&Song> for RoomSong {
+    fn from(song: &Song) -> Self {
+        RoomSong {
+            id: song.id.clone(),
+            name: song.title.clone(),
+            artist_name: song.artist_name.clone(),
+            duration: song.duration,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SuggestionInfo {
+    pub id: String,
+    pub song: RoomSong,
+    pub suggested_by: String,
+}
+
+/// Everything a member needs to render the room
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomState {
+    pub id: String,
+    pub name: String,
+    pub host: String,
+    pub members: Vec<String>,
+    pub queue: Vec<RoomSong>,
+    /// Index into `queue` of the song playing, none once the queue has run out
+    pub current_index: Option<usize>,
+    pub clock: RoomClock,
+    pub suggestions: Vec<SuggestionInfo>,
+    pub skip_votes: usize,
+    pub skip_votes_needed: usize,
+}
+
+/// Messages the server pushes to members
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The whole room, on joining and whenever the queue, members or votes change
+    Room { room: RoomState },
+    /// Playback moved, or a periodic resync of the clock
+    Playback { current_index: Option<usize>, clock: RoomClock },
+    Pong { client_time_ms: i64, server_time_ms: i64 },
+    /// A `reauth` was accepted, and the socket stays open until this time
+    Reauthenticated {
+        #[serde(with = "time::serde::rfc3339")]
+        expires_at: OffsetDateTime,
+    },
+    Error { message: String },
+    /// The room was closed by its host
+    Closed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_messages() {
+        let message: ClientMessage = serde_json::from_str(r#"{"type":"seek","position_ms":1500}"#).unwrap();
+        assert_eq!(message, ClientMessage::Seek { position_ms: 1500 });
+
+        let message: ClientMessage = serde_json::from_str(r#"{"type":"vote_skip"}"#).unwrap();
+        assert_eq!(message, ClientMessage::VoteSkip);
+
+        let message: ClientMessage = serde_json::from_str(r#"{"type":"suggest","song_id":"s1"}"#).unwrap();
+        assert_eq!(message, ClientMessage::Suggest { song_id: "s1".to_string() });
+    }
+
+    #[test]
+    fn test_serialize_playback() {
+        let message = ServerMessage::Playback {
+            current_index: Some(2),
+            clock: RoomClock { playing: true, position_ms: 1000, server_time_ms: 5000 },
+        };
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"playback","current_index":2,"clock":{"playing":true,"position_ms":1000,"server_time_ms":5000}}"#
+        );
+    }
+}